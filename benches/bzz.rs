@@ -0,0 +1,29 @@
+//! BZZ block compression (BWT + MTF + ZP).
+
+mod common;
+
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use djvu_encoder::iff::bs_byte_stream::bzz_compress;
+
+fn bench_bzz(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bzz_compress");
+    group.sample_size(20);
+
+    for &len in &[64 * 1024usize, 1024 * 1024] {
+        let data = common::mixed_bytes(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        // 100k blocks are what the page encoder uses for TXTz/ANTz;
+        // 4096k is the maximum the format allows.
+        for &block_k in &[100usize, 4096] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("block_{block_k}k"), len),
+                &data,
+                |b, data| b.iter(|| black_box(bzz_compress(data, block_k).unwrap())),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_bzz);
+criterion_main!(benches);
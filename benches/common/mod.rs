@@ -0,0 +1,203 @@
+//! Deterministic synthetic corpus shared by the codec benchmarks.
+//!
+//! Real scans would be better, but checking them in bloats the repository.
+//! These generators produce inputs with similar statistics (text-like
+//! glyph runs for JB2, smooth gradients plus noise for IW44) and are
+//! stable across runs, so numbers are comparable between commits.
+
+#![allow(dead_code)]
+
+use criterion::Throughput;
+use criterion::measurement::{Measurement, ValueFormatter};
+use djvu_encoder::encode::jb2::BitImage;
+use djvu_encoder::image::image_formats::{Bitmap, GrayPixel, Pixel, Pixmap};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The system allocator, counting allocations so a benchmark can report
+/// allocator traffic next to its timings. Install it with
+/// `#[global_allocator]` and measure with [`Allocations`].
+pub struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+/// A criterion measurement of allocations and reallocations instead of
+/// time, for groups configured with
+/// `Criterion::default().with_measurement(Allocations)` while
+/// [`CountingAlloc`] is the global allocator.
+pub struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = usize;
+    type Value = usize;
+
+    fn start(&self) -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: usize) -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, v1: &usize, v2: &usize) -> usize {
+        v1 + v2
+    }
+
+    fn zero(&self) -> usize {
+        0
+    }
+
+    fn to_f64(&self, value: &usize) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationFormatter
+    }
+}
+
+/// Reports [`Allocations`] as plain counts.
+struct AllocationFormatter;
+
+impl ValueFormatter for AllocationFormatter {
+    fn scale_values(&self, _typical: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        let (n, unit) = match *throughput {
+            Throughput::Bytes(n) | Throughput::BytesDecimal(n) => (n, "allocs/byte"),
+            Throughput::Elements(n) => (n, "allocs/elem"),
+        };
+        values.iter_mut().for_each(|v| *v /= n as f64);
+        unit
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+/// Small xorshift PRNG so the corpus does not depend on `rand`.
+pub struct XorShift(u32);
+
+impl XorShift {
+    pub fn new(seed: u32) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+}
+
+/// Bytes that look like a mix of text and binary structure, for BZZ.
+pub fn mixed_bytes(len: usize) -> Vec<u8> {
+    let words: &[&[u8]] = &[
+        b"djvu ",
+        b"page ",
+        b"the ",
+        b"of ",
+        b"(maparea ",
+        b"\x00\x01\x02",
+    ];
+    let mut rng = XorShift::new(0x2545_f491);
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        let w = words[(rng.next_u32() as usize) % words.len()];
+        out.extend_from_slice(w);
+    }
+    out.truncate(len);
+    out
+}
+
+/// A page of pseudo-text: rows of small glyph-sized blobs drawn from a
+/// limited alphabet, so the JB2 dictionary gets real matches.
+pub fn text_page(width: u32, height: u32) -> BitImage {
+    let mut page = BitImage::new(width, height).unwrap();
+    let mut rng = XorShift::new(0x9e37_79b9);
+    let glyphs: Vec<Vec<(usize, usize)>> = (0..40)
+        .map(|_| {
+            (0..60)
+                .map(|_| {
+                    (
+                        (rng.next_u32() % 12) as usize,
+                        (rng.next_u32() % 18) as usize,
+                    )
+                })
+                .collect()
+        })
+        .collect();
+
+    let (w, h) = (width as usize, height as usize);
+    let mut y = 40;
+    while y + 24 < h {
+        let mut x = 40;
+        while x + 16 < w {
+            if !rng.next_u32().is_multiple_of(6) {
+                let glyph = &glyphs[(rng.next_u32() as usize) % glyphs.len()];
+                for &(gx, gy) in glyph {
+                    page.set_usize(x + gx, y + gy, true);
+                }
+            }
+            x += 14;
+        }
+        y += 30;
+    }
+    page
+}
+
+/// A smooth color gradient with mild noise, for IW44.
+pub fn photo_rgb(width: u32, height: u32) -> Pixmap {
+    let mut rng = XorShift::new(0x1234_5678);
+    Pixmap::from_fn(width, height, |x, y| {
+        let n = (rng.next_u32() % 16) as u8;
+        Pixel::new(
+            ((x * 255 / width.max(1)) as u8).saturating_add(n),
+            ((y * 255 / height.max(1)) as u8).saturating_add(n),
+            (((x + y) * 127 / (width + height).max(1)) as u8).saturating_add(n),
+        )
+    })
+}
+
+/// Grayscale counterpart of [`photo_rgb`].
+pub fn photo_gray(width: u32, height: u32) -> Bitmap {
+    let rgb = photo_rgb(width, height);
+    let data = rgb
+        .pixels()
+        .iter()
+        .map(|p| GrayPixel::new(((p.r as u32 + p.g as u32 + p.b as u32) / 3) as u8))
+        .collect();
+    Bitmap::from_vec(width, height, data)
+}
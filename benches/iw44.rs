@@ -0,0 +1,53 @@
+//! IW44 wavelet transform and slice encoding, reported per pixel.
+
+mod common;
+
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use djvu_encoder::encode::iw44::encoder::{EncoderParams, IWEncoder};
+
+/// Runs the encoder to completion the way the page encoder does.
+fn encode_all(mut encoder: IWEncoder) -> usize {
+    let mut total = 0;
+    loop {
+        let (chunk, more) = encoder.encode_chunk(10).unwrap();
+        total += chunk.len();
+        if !more {
+            break;
+        }
+    }
+    total
+}
+
+fn bench_iw44(c: &mut Criterion) {
+    let mut group = c.benchmark_group("iw44");
+    group.sample_size(10);
+
+    // One megapixel and an A4 page at 150 dpi.
+    for &(w, h) in &[(1024u32, 1024u32), (1240, 1754)] {
+        let pixels = (w as u64) * (h as u64);
+        group.throughput(Throughput::Elements(pixels));
+        let rgb = common::photo_rgb(w, h);
+        let gray = common::photo_gray(w, h);
+        let label = format!("{w}x{h}");
+
+        group.bench_with_input(BenchmarkId::new("transform_rgb", &label), &rgb, |b, img| {
+            b.iter(|| black_box(IWEncoder::from_rgb(img, None, EncoderParams::default()).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("encode_rgb", &label), &rgb, |b, img| {
+            b.iter(|| {
+                let enc = IWEncoder::from_rgb(img, None, EncoderParams::default()).unwrap();
+                black_box(encode_all(enc))
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("encode_gray", &label), &gray, |b, img| {
+            b.iter(|| {
+                let enc = IWEncoder::from_gray(img, None, EncoderParams::default()).unwrap();
+                black_box(encode_all(enc))
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_iw44);
+criterion_main!(benches);
@@ -0,0 +1,180 @@
+//! JB2 page encoding: connected-component analysis plus the arithmetic
+//! coding of the resulting symbols, and clustering symbols across pages.
+//! The `jb2_words` group compares reading shapes stored in the page arena
+//! with reading them from their own buffers, and `jb2_allocations` counts
+//! allocations instead of time.
+
+mod common;
+
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use djvu_encoder::encode::jb2::{
+    BitImage, ClusterParams, Connectivity, JB2Encoder, analyze_page, cluster_symbols,
+    find_connected_components_with, shapes_to_encoder_format,
+};
+
+#[global_allocator]
+static ALLOC: common::CountingAlloc = common::CountingAlloc;
+
+fn bench_jb2(c: &mut Criterion) {
+    let mut group = c.benchmark_group("jb2");
+    group.sample_size(10);
+
+    // Letter-size pages at 300 and 600 dpi.
+    for &(w, h) in &[(2550u32, 3300u32), (5100, 6600)] {
+        let page = common::text_page(w, h);
+        group.throughput(Throughput::Elements((w as u64) * (h as u64)));
+        let label = format!("{w}x{h}");
+
+        group.bench_with_input(BenchmarkId::new("analyze", &label), &page, |b, page| {
+            b.iter(|| black_box(analyze_page(page, 300, 1).extract_shapes().len()))
+        });
+
+        for (name, conn) in [
+            ("label_cc4", Connectivity::Four),
+            ("label_cc8", Connectivity::Eight),
+        ] {
+            group.bench_with_input(BenchmarkId::new(name, &label), &page, |b, page| {
+                b.iter(|| black_box(find_connected_components_with(page, 1, conn).len()))
+            });
+        }
+
+        group.bench_with_input(BenchmarkId::new("encode_page", &label), &page, |b, page| {
+            b.iter(|| {
+                let shapes = analyze_page(page, 300, 1).extract_shapes();
+                let (bitmaps, parents, blits) = shapes_to_encoder_format(shapes, h as i32);
+                let mut encoder = JB2Encoder::new(Vec::new());
+                black_box(
+                    encoder
+                        .encode_page_with_shapes(w, h, &bitmaps, &parents, &blits, 0, None)
+                        .unwrap(),
+                )
+            })
+        });
+    }
+
+    // A 20-page book set in the same font: every page has the same symbols.
+    let page = common::text_page(2550, 3300);
+    let shapes: Vec<_> = analyze_page(&page, 300, 1)
+        .extract_shapes()
+        .into_iter()
+        .map(|(shape, _)| shape)
+        .collect();
+    let pages = vec![shapes; 20];
+    group.bench_function("cluster_20_pages", |b| {
+        b.iter(|| {
+            black_box(
+                cluster_symbols(&pages, &ClusterParams::default())
+                    .dict
+                    .shape_count(),
+            )
+        })
+    });
+    group.finish();
+}
+
+/// Every pixel of `shapes`, read the way the encoder reads them.
+fn count_pixels(shapes: &[BitImage]) -> usize {
+    shapes
+        .iter()
+        .map(|shape| {
+            (0..shape.height)
+                .flat_map(|y| (0..shape.width).map(move |x| (x, y)))
+                .filter(|&(x, y)| shape.get_pixel_unchecked(x, y))
+                .count()
+        })
+        .sum()
+}
+
+fn bench_jb2_words(c: &mut Criterion) {
+    let mut group = c.benchmark_group("jb2_words");
+    group.sample_size(10);
+
+    // The same shapes three ways: as extracted (small ones inline, the rest
+    // in the page arena), copied out of the arena into their own buffers,
+    // and as bare word vectors, which is what a bitmap held before it could
+    // be inline or shared.
+    let (w, h) = (2550u32, 3300u32);
+    let page = common::text_page(w, h);
+    let extracted = analyze_page(&page, 300, 1).extract_shapes();
+    let (arena, parents, blits) = shapes_to_encoder_format(extracted, h as i32);
+    let owned: Vec<BitImage> = arena
+        .iter()
+        .map(|shape| {
+            let mut shape = shape.clone();
+            shape.unshare();
+            shape
+        })
+        .collect();
+    let vecs: Vec<(usize, usize, Vec<u32>)> = arena
+        .iter()
+        .map(|s| (s.width, s.height, s.to_packed_words().to_vec()))
+        .collect();
+    group.throughput(Throughput::Elements(arena.len() as u64));
+
+    group.bench_function("pixels/arena", |b| {
+        b.iter(|| black_box(count_pixels(&arena)))
+    });
+    group.bench_function("pixels/owned", |b| {
+        b.iter(|| black_box(count_pixels(&owned)))
+    });
+    // The arithmetic of `get_pixel_unchecked`, without the match on the
+    // storage.
+    group.bench_function("pixels/vec", |b| {
+        b.iter(|| {
+            let count: usize = vecs
+                .iter()
+                .map(|(width, height, words)| {
+                    (0..*height)
+                        .flat_map(|y| (0..*width).map(move |x| (x, y)))
+                        .filter(|&(x, y)| {
+                            words[y * width.div_ceil(32) + x / 32] & (0x8000_0000 >> (x % 32)) != 0
+                        })
+                        .count()
+                })
+                .sum();
+            black_box(count)
+        })
+    });
+
+    for (name, shapes) in [("arena", &arena), ("owned", &owned)] {
+        group.bench_function(format!("encode_page/{name}"), |b| {
+            b.iter(|| {
+                let mut encoder = JB2Encoder::new(Vec::new());
+                black_box(
+                    encoder
+                        .encode_page_with_shapes(w, h, shapes, &parents, &blits, 0, None)
+                        .unwrap(),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_jb2_allocations(c: &mut Criterion<common::Allocations>) {
+    let mut group = c.benchmark_group("jb2_allocations");
+    group.sample_size(10);
+
+    for &(w, h) in &[(2550u32, 3300u32), (5100, 6600)] {
+        let page = common::text_page(w, h);
+        let label = format!("{w}x{h}");
+        group.bench_with_input(BenchmarkId::new("analyze", &label), &page, |b, page| {
+            b.iter(|| black_box(analyze_page(page, 300, 1)))
+        });
+        let ccs = analyze_page(&page, 300, 1);
+        group.bench_with_input(
+            BenchmarkId::new("extract_shapes", &label),
+            &ccs,
+            |b, ccs| b.iter(|| black_box(ccs.extract_shapes())),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_jb2, bench_jb2_words);
+criterion_group! {
+    name = allocations;
+    config = Criterion::default().with_measurement(common::Allocations);
+    targets = bench_jb2_allocations
+}
+criterion_main!(benches, allocations);
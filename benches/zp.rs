@@ -0,0 +1,48 @@
+//! ZP arithmetic coder throughput.
+
+mod common;
+
+use common::XorShift;
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use djvu_encoder::encode::zc::{ZEncoder, ZpContext};
+
+const BITS: usize = 1 << 20;
+
+fn bench_zp(c: &mut Criterion) {
+    let mut group = c.benchmark_group("zp_encode");
+    group.throughput(Throughput::Elements(BITS as u64));
+
+    // Skewed (mostly-zero) and balanced bit streams exercise different
+    // renormalization rates.
+    for &(name, one_in) in &[("skewed", 16u32), ("balanced", 2u32)] {
+        let mut rng = XorShift::new(7);
+        let bits: Vec<bool> = (0..BITS)
+            .map(|_| rng.next_u32().is_multiple_of(one_in))
+            .collect();
+
+        group.bench_with_input(BenchmarkId::new("adaptive", name), &bits, |b, bits| {
+            b.iter(|| {
+                let mut zp = ZEncoder::new(Vec::with_capacity(BITS / 8), true).unwrap();
+                let mut ctx = [ZpContext::new(); 4];
+                for (i, &bit) in bits.iter().enumerate() {
+                    zp.encode(bit, &mut ctx[i & 3]).unwrap();
+                }
+                black_box(zp.finish().unwrap())
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("raw", name), &bits, |b, bits| {
+            b.iter(|| {
+                let mut zp = ZEncoder::new(Vec::with_capacity(BITS / 8), true).unwrap();
+                for &bit in bits {
+                    zp.encode_raw(bit).unwrap();
+                }
+                black_box(zp.finish().unwrap())
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_zp);
+criterion_main!(benches);
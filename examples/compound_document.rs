@@ -9,7 +9,7 @@
 
 use djvu_encoder::doc::page_encoder::{PageComponents, PageEncodeParams};
 use djvu_encoder::encode::jb2::symbol_dict::BitImage;
-use djvu_encoder::image::image_formats::{Pixel, Pixmap};
+use djvu_encoder::image::image_formats::{Pixel, Pixmap, write_pbm};
 use std::fs;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -70,32 +70,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // --- DEBUG: Export mask to PBM to verify content ---
     {
         println!("DEBUG: Exporting mask to /tmp/compound_debug_mask.pbm...");
-        let header = format!("P4\n{} {}\n", width, height);
         let mut pbm_data = Vec::new();
-        pbm_data.extend_from_slice(header.as_bytes());
-
-        // BitImage bits are packed MSB0. P4 expects packed bits, row-aligned.
-        // Since 800 is divisible by 8, no padding needed per row.
-        // We can access the underlying storage if we can get it, or iterate manually.
-        // BitVec storage access is tricky if private.
-        // We'll reconstruct bytes manually to be safe and independent of internal storage.
-
-        for y in 0..height {
-            let mut byte = 0u8;
-            for x in 0..width {
-                if mask.get_pixel_unchecked(x as usize, y as usize) {
-                    byte |= 1 << (7 - (x % 8));
-                }
-                if x % 8 == 7 {
-                    pbm_data.push(byte);
-                    byte = 0;
-                }
-            }
-            // If width not multiple of 8, pad last byte (not needed for 800, but good practice)
-            if width % 8 != 0 {
-                pbm_data.push(byte);
-            }
-        }
+        write_pbm(&mut pbm_data, &mask)?;
         fs::write("/tmp/compound_debug_mask.pbm", &pbm_data)?;
     }
 
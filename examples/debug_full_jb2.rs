@@ -2,40 +2,15 @@
 
 use djvu_encoder::doc::page_encoder::{PageComponents, PageEncodeParams};
 use djvu_encoder::encode::jb2::symbol_dict::BitImage;
-use djvu_encoder::image::image_formats::{Pixel, Pixmap};
+use djvu_encoder::image::image_formats::{Pixel, Pixmap, write_pbm};
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::BufWriter;
 use std::process::Command;
 
-fn save_bitimage_as_pbm(img: &BitImage, path: &str) -> std::io::Result<()> {
-    let width = img.width as u32;
-    let height = img.height as u32;
-
-    let mut file = File::create(path)?;
-    writeln!(file, "P4")?;
-    writeln!(file, "{} {}", width, height)?;
-
-    for y in 0..height {
-        let mut byte = 0u8;
-        let mut bit_pos = 7i32;
-        for x in 0..width {
-            if img.get_pixel_unchecked(x as usize, y as usize) {
-                byte |= 1 << bit_pos;
-            }
-            if bit_pos == 0 {
-                file.write_all(&[byte])?;
-                byte = 0;
-                bit_pos = 7;
-            } else {
-                bit_pos -= 1;
-            }
-        }
-        if width % 8 != 0 {
-            file.write_all(&[byte])?;
-        }
-    }
-    Ok(())
+fn save_bitimage_as_pbm(img: &BitImage, path: &str) -> djvu_encoder::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_pbm(&mut file, img)
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
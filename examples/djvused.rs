@@ -0,0 +1,40 @@
+//! Example: run djvused scripts without DjVuLibre
+//!
+//! Takes the same arguments as djvused for the commands `doc::script`
+//! supports:
+//!
+//! ```text
+//! cargo run --example djvused -- book.djvu -e 'select 1; remove-txt' -s
+//! cargo run --example djvused -- book.djvu -f retext.dsed
+//! ```
+//!
+//! `-s` saves the document in place once the script has run.
+
+use djvu_encoder::doc::{DjVuDocEditor, Script};
+use std::fs;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = None;
+    let mut script = String::new();
+    let mut save = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" => script.push_str(&args.next().ok_or("-e needs a script")?),
+            "-f" => script.push_str(&fs::read_to_string(args.next().ok_or("-f needs a file")?)?),
+            "-s" => save = true,
+            _ if file.is_none() => file = Some(arg),
+            _ => return Err(format!("unexpected argument {arg:?}").into()),
+        }
+        script.push('\n');
+    }
+    let file = file.ok_or("usage: djvused <file.djvu> [-e script] [-f script-file] [-s]")?;
+
+    let script = Script::parse(&script)?;
+    let mut editor = DjVuDocEditor::from_bytes(&fs::read(&file)?)?;
+    script.run(&mut editor)?;
+    if save {
+        fs::write(&file, editor.to_bytes()?)?;
+    }
+    Ok(())
+}
@@ -1,5 +1,7 @@
 // src/annotations.rs
 
+use crate::annotations::hidden_text::BoundingBox;
+use crate::utils::string::sanitize;
 use std::fmt;
 use std::io::Write;
 use thiserror::Error;
@@ -37,6 +39,32 @@ impl fmt::Display for AnnotationShape {
     }
 }
 
+impl AnnotationShape {
+    /// The box the shape covers.
+    pub fn bounds(&self) -> BoundingBox {
+        match self {
+            Self::Rect { x, y, w, h } | Self::Oval { x, y, w, h } => box_of(*x, *y, *w, *h),
+            Self::Polygon { points } => {
+                let xs = points.iter().map(|p| p.0);
+                let ys = points.iter().map(|p| p.1);
+                let (x0, x1) = (xs.clone().min().unwrap_or(0), xs.max().unwrap_or(0));
+                let (y0, y1) = (ys.clone().min().unwrap_or(0), ys.max().unwrap_or(0));
+                box_of(x0, y0, x1 - x0 + 1, y1 - y0 + 1)
+            }
+        }
+    }
+}
+
+fn box_of(x: u32, y: u32, w: u32, h: u32) -> BoundingBox {
+    let clamp = |v: u32| v.min(u16::MAX as u32) as u16;
+    BoundingBox {
+        x: clamp(x),
+        y: clamp(y),
+        w: clamp(w),
+        h: clamp(h),
+    }
+}
+
 /// Represents a single hyperlink or clickable map area.
 #[derive(Debug, Clone)]
 pub struct Hyperlink {
@@ -47,6 +75,14 @@ pub struct Hyperlink {
     // Note: Border and highlight options are omitted for simplicity but can be added here.
 }
 
+impl Hyperlink {
+    /// The page a link within the document points to: the part of a
+    /// `#page` URL after the `#` (a page ID, title or number).
+    pub fn page_target(&self) -> Option<&str> {
+        self.url.strip_prefix('#')
+    }
+}
+
 /// Represents the full set of annotations for a page.
 #[derive(Default, Debug, Clone)]
 pub struct Annotations {
@@ -59,6 +95,20 @@ impl Annotations {
         Default::default()
     }
 
+    /// Whether any hyperlink points to a page of the document.
+    pub fn has_page_links(&self) -> bool {
+        self.hyperlinks.iter().any(|l| l.page_target().is_some())
+    }
+
+    /// Removes the hyperlinks whose area touches `area`, returning how many
+    /// there were.
+    pub fn redact(&mut self, area: &BoundingBox) -> usize {
+        let before = self.hyperlinks.len();
+        self.hyperlinks
+            .retain(|link| !link.shape.bounds().intersects(area));
+        before - self.hyperlinks.len()
+    }
+
     /// Encodes the annotations into the LISP-like format required for an ANTa/ANTz chunk.
     /// The output of this function should be compressed (e.g., with bzip2) before
     /// being stored in a final DjVu file as an 'ANTz' chunk.
@@ -93,7 +143,273 @@ impl Annotations {
     }
 }
 
-/// Escapes a string for use inside the LISP-like annotation format.
+/// Removes the `maparea` expressions whose shape touches `area` from
+/// annotation text as stored in an `ANTa` chunk (or a decompressed `ANTz`),
+/// returning the remaining text and how many were removed. Everything else
+/// is kept byte for byte.
+pub fn remove_mapareas(text: &[u8], area: &BoundingBox) -> (Vec<u8>, usize) {
+    let mut out = Vec::with_capacity(text.len());
+    let mut removed = 0;
+    let mut pos = 0;
+    while pos < text.len() {
+        if text[pos] != b'(' {
+            out.push(text[pos]);
+            pos += 1;
+            continue;
+        }
+        let end = expression_end(text, pos);
+        let expr = &text[pos..end];
+        let touches = expr
+            .strip_prefix(b"(maparea")
+            .and_then(maparea_bounds)
+            .is_some_and(|bounds| bounds.intersects(area));
+        if touches {
+            removed += 1;
+        } else {
+            out.extend_from_slice(expr);
+        }
+        pos = end;
+    }
+    (out, removed)
+}
+
+/// Replaces the `metadata` expression of annotation text as stored in an
+/// `ANTa` chunk (or a decompressed `ANTz`) by one holding `metadata`, or
+/// removes it when `metadata` is empty. Everything else is kept byte for
+/// byte.
+pub fn replace_metadata(text: &[u8], metadata: &[(String, String)]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut pos = 0;
+    while pos < text.len() {
+        if text[pos] != b'(' {
+            out.push(text[pos]);
+            pos += 1;
+            continue;
+        }
+        let end = expression_end(text, pos);
+        let keyword = text[pos + 1..end]
+            .split(|&c| c.is_ascii_whitespace() || c == b'(' || c == b')')
+            .next();
+        if keyword != Some(b"metadata") {
+            out.extend_from_slice(&text[pos..end]);
+        }
+        pos = end;
+    }
+    if !metadata.is_empty() {
+        let annotations = Annotations {
+            metadata: metadata.to_vec(),
+            ..Annotations::default()
+        };
+        // Writing to a Vec cannot fail.
+        let _ = annotations.encode(&mut out);
+    }
+    out
+}
+
+/// Rewrites the URLs of the `maparea` expressions of annotation text as
+/// stored in an `ANTa` chunk (or a decompressed `ANTz`). `retarget` gets
+/// each URL and returns the one to use, or `None` to remove the `maparea`.
+/// Returns the new text and whether anything changed; everything else is
+/// kept byte for byte.
+pub fn retarget_mapareas(
+    text: &[u8],
+    mut retarget: impl FnMut(&str) -> Option<String>,
+) -> (Vec<u8>, bool) {
+    let mut out = Vec::with_capacity(text.len());
+    let mut changed = false;
+    let mut pos = 0;
+    while pos < text.len() {
+        if text[pos] != b'(' {
+            out.push(text[pos]);
+            pos += 1;
+            continue;
+        }
+        let end = expression_end(text, pos);
+        let expr = &text[pos..end];
+        let url = expr
+            .strip_prefix(b"(maparea")
+            .and_then(maparea_url)
+            .map(|(start, stop)| (start + 8, stop + 8));
+        match url {
+            Some((start, stop)) => {
+                let old = unescape(&expr[start + 1..stop - 1]);
+                match retarget(&old) {
+                    Some(new) if new == old => out.extend_from_slice(expr),
+                    Some(new) => {
+                        out.extend_from_slice(&expr[..start]);
+                        out.extend_from_slice(format!("\"{}\"", escape_str(&new)).as_bytes());
+                        out.extend_from_slice(&expr[stop..]);
+                        changed = true;
+                    }
+                    None => changed = true,
+                }
+            }
+            None => out.extend_from_slice(expr),
+        }
+        pos = end;
+    }
+    (out, changed)
+}
+
+/// Span of the quoted URL of a `maparea`, given the text after its
+/// keyword; the URL is either the first string or in a `(url ...)` form.
+fn maparea_url(body: &[u8]) -> Option<(usize, usize)> {
+    let skip_space = |mut pos: usize| {
+        while body.get(pos).is_some_and(u8::is_ascii_whitespace) {
+            pos += 1;
+        }
+        pos
+    };
+    let mut pos = skip_space(0);
+    if body[pos..].starts_with(b"(url") {
+        pos = skip_space(pos + 4);
+    }
+    if body.get(pos) != Some(&b'"') {
+        return None;
+    }
+    let mut end = pos + 1;
+    while end < body.len() && body[end] != b'"' {
+        end += 1 + (body[end] == b'\\') as usize;
+    }
+    (end < body.len()).then_some((pos, end + 1))
+}
+
+/// The contents of a quoted string, with its escapes resolved.
+fn unescape(quoted: &[u8]) -> String {
+    let mut out = Vec::with_capacity(quoted.len());
+    let mut bytes = quoted.iter();
+    while let Some(&b) = bytes.next() {
+        out.push(if b == b'\\' {
+            bytes.next().copied().unwrap_or(b)
+        } else {
+            b
+        });
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// End of the parenthesized expression starting at `start`, skipping
+/// quoted strings; the end of `text` if it is not closed.
+fn expression_end(text: &[u8], start: usize) -> usize {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut pos = start;
+    while pos < text.len() {
+        match (text[pos], in_string) {
+            (b'\\', true) => pos += 1,
+            (b'"', _) => in_string = !in_string,
+            (b'(', false) => depth += 1,
+            (b')', false) => {
+                depth -= 1;
+                if depth == 0 {
+                    return pos + 1;
+                }
+            }
+            _ => {}
+        }
+        pos += 1;
+    }
+    text.len()
+}
+
+/// Bounds of the shape of a `maparea`, given the text after its keyword.
+fn maparea_bounds(body: &[u8]) -> Option<BoundingBox> {
+    let mut pos = 0;
+    while pos < body.len() {
+        match body[pos] {
+            b'"' => {
+                // Skip the URL and comment strings.
+                pos += 1;
+                while pos < body.len() && body[pos] != b'"' {
+                    pos += 1 + (body[pos] == b'\\') as usize;
+                }
+                pos += 1;
+            }
+            b'(' => {
+                let end = expression_end(body, pos);
+                let inner = std::str::from_utf8(&body[pos + 1..end.saturating_sub(1)]).ok()?;
+                let mut words = inner.split_whitespace();
+                let keyword = words.next()?;
+                let numbers: Vec<u32> = words.map_while(|w| w.parse().ok()).collect();
+                match (keyword, numbers.as_slice()) {
+                    ("rect" | "oval" | "text", &[x, y, w, h, ..]) => {
+                        return Some(box_of(x, y, w, h));
+                    }
+                    ("poly" | "line", points) if points.len() >= 4 => {
+                        let points = points.chunks_exact(2).map(|p| (p[0], p[1])).collect();
+                        return Some(AnnotationShape::Polygon { points }.bounds());
+                    }
+                    _ => pos = end,
+                }
+            }
+            _ => pos += 1,
+        }
+    }
+    None
+}
+
+/// Escapes a string for use inside the LISP-like annotation format, after
+/// [`sanitize`]-ing it.
 fn escape_str(s: &str) -> String {
-    s.replace('\\', "\\\\").replace('"', "\\\"")
+    sanitize(s).replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_metadata() {
+        let text = br#"(background #ffffff) (metadata (Author "A. N. Other"))"#;
+        let metadata = [("Title".to_string(), "Kant \"Kritik\"".to_string())];
+        assert_eq!(
+            replace_metadata(text, &metadata),
+            br#"(background #ffffff) (metadata (Title "Kant \"Kritik\""))"#
+        );
+        assert_eq!(replace_metadata(text, &[]), b"(background #ffffff) ");
+    }
+
+    #[test]
+    fn test_remove_mapareas() {
+        let text = br##"(background #ffffff) (maparea "#2" "see (rect 1 1 1 1)" (rect 10 10 20 20) (none)) (maparea "x" "" (poly 100 100 120 100 110 130) (xor))"##;
+        let area = BoundingBox {
+            x: 25,
+            y: 0,
+            w: 10,
+            h: 15,
+        };
+        let (kept, removed) = remove_mapareas(text, &area);
+        assert_eq!(removed, 1);
+        assert_eq!(
+            kept,
+            br##"(background #ffffff)  (maparea "x" "" (poly 100 100 120 100 110 130) (xor))"##
+        );
+
+        let far = BoundingBox {
+            x: 500,
+            y: 500,
+            w: 1,
+            h: 1,
+        };
+        assert_eq!(remove_mapareas(text, &far), (text.to_vec(), 0));
+    }
+
+    #[test]
+    fn test_retarget_mapareas() {
+        let text = br##"(maparea "#2" "" (rect 1 1 1 1)) (maparea (url "#gone" "_self") "" (rect 2 2 2 2)) (maparea "http://x" "" (oval 3 3 3 3))"##;
+        let (out, changed) = retarget_mapareas(text, |url| match url {
+            "#2" => Some("#1".to_string()),
+            "#gone" => None,
+            _ => Some(url.to_string()),
+        });
+        assert!(changed);
+        assert_eq!(
+            out,
+            br##"(maparea "#1" "" (rect 1 1 1 1))  (maparea "http://x" "" (oval 3 3 3 3))"##
+        );
+        assert_eq!(
+            retarget_mapareas(text, |url| Some(url.to_string())),
+            (text.to_vec(), false)
+        );
+    }
 }
@@ -8,6 +8,9 @@
 // IMPORTANT: DjVu uses a bottom-left coordinate origin. Input coordinates from hOCR
 // (which uses top-left origin) must be converted before encoding.
 
+use crate::doc::page_encoder::PageRotation;
+use crate::encode::jb2::symbol_dict::BitImage;
+use crate::utils::string::sanitize;
 use std::io::Write;
 use thiserror::Error;
 
@@ -17,6 +20,8 @@ pub enum HiddenTextError {
     Io(#[from] std::io::Error),
     #[error("Coordinate value {0} out of range for 16-bit encoding")]
     CoordinateOutOfRange(i32),
+    #[error("Malformed hidden text: {0}")]
+    Malformed(String),
 }
 
 /// The type of a zone in the document hierarchy.
@@ -62,6 +67,49 @@ impl BoundingBox {
     pub fn ymax(&self) -> u16 {
         self.y.saturating_add(self.h)
     }
+
+    /// The smallest box containing both.
+    pub fn union(&self, other: &BoundingBox) -> Self {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        Self {
+            x,
+            y,
+            w: self.xmax().max(other.xmax()) - x,
+            h: self.ymax().max(other.ymax()) - y,
+        }
+    }
+
+    /// Whether the two boxes share at least one pixel.
+    pub fn intersects(&self, other: &BoundingBox) -> bool {
+        self.x < other.xmax()
+            && other.x < self.xmax()
+            && self.y < other.ymax()
+            && other.y < self.ymax()
+    }
+
+    /// Maps a box on the displayed page to the stored image of a `width` x
+    /// `height` page shown with `rotation`.
+    pub fn unrotated(&self, rotation: PageRotation, width: u16, height: u16) -> Self {
+        let (x, y, w, h) = (self.x as i32, self.y as i32, self.w, self.h);
+        let (width, height) = (width as i32, height as i32);
+        let (x, y, w, h) = match rotation {
+            PageRotation::Upright => return *self,
+            // The displayed x axis runs up the stored image, its y axis
+            // leftwards.
+            PageRotation::Ccw90 => (y, height - (x + w as i32), h, w),
+            PageRotation::Rotate180 => (width - (x + w as i32), height - (y + h as i32), w, h),
+            // The displayed x axis runs down the stored image, its y axis
+            // rightwards.
+            PageRotation::Cw90 => (width - (y + h as i32), x, h, w),
+        };
+        let clamp = |v: i32| v.clamp(0, u16::MAX as i32) as u16;
+        Self {
+            x: clamp(x),
+            y: clamp(y),
+            w,
+            h,
+        }
+    }
 }
 
 /// A node in the hierarchical text structure.
@@ -103,6 +151,136 @@ impl Zone {
     }
 }
 
+/// The order in which the words of a line are read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WordOrder {
+    /// Latin, Cyrillic, CJK set horizontally, ...
+    #[default]
+    LeftToRight,
+    /// Arabic, Hebrew, ...
+    RightToLeft,
+}
+
+/// The direction in which lines run.
+///
+/// Vertical lines are read top to bottom, and follow one another in the
+/// page's [`WordOrder`]: right to left for Japanese and Chinese, left to
+/// right for Mongolian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineOrientation {
+    #[default]
+    Horizontal,
+    Vertical,
+}
+
+/// Sorts the words of a line into reading order.
+pub fn order_words(words: &mut [Zone], order: WordOrder, orientation: LineOrientation) {
+    match (orientation, order) {
+        (LineOrientation::Horizontal, WordOrder::LeftToRight) => {
+            words.sort_by_key(|w| w.bbox.x);
+        }
+        (LineOrientation::Horizontal, WordOrder::RightToLeft) => {
+            words.sort_by_key(|w| std::cmp::Reverse(w.bbox.xmax()));
+        }
+        // Rows count upwards, so the top word has the largest ymax.
+        (LineOrientation::Vertical, _) => {
+            words.sort_by_key(|w| std::cmp::Reverse(w.bbox.ymax()));
+        }
+    }
+}
+
+/// Sorts the lines of a page into reading order: horizontal lines top to
+/// bottom, vertical ones across the page in the direction of `order`.
+pub fn order_lines(lines: &mut [Zone], order: WordOrder, orientation: LineOrientation) {
+    match (orientation, order) {
+        (LineOrientation::Horizontal, _) => {
+            lines.sort_by_key(|l| std::cmp::Reverse(l.bbox.ymax()));
+        }
+        (LineOrientation::Vertical, WordOrder::LeftToRight) => {
+            lines.sort_by_key(|l| l.bbox.x);
+        }
+        (LineOrientation::Vertical, WordOrder::RightToLeft) => {
+            lines.sort_by_key(|l| std::cmp::Reverse(l.bbox.xmax()));
+        }
+    }
+}
+
+/// Builds a page of line and word zones in reading order.
+///
+/// Words go in with boxes in DjVu coordinates and in any order; each line
+/// is sorted by its word order (the builder's, unless given) and the lines
+/// by the builder's orientation, so viewers select and search the text the
+/// way it is read. A line can override the word order, for an English
+/// quote in an Arabic page, say.
+///
+/// ```ignore
+/// let mut builder = HiddenTextBuilder::new(1000, 1400)
+///     .with_word_order(WordOrder::RightToLeft)
+///     .with_orientation(LineOrientation::Vertical);
+/// builder.add_line(column_words);
+/// let text = builder.build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct HiddenTextBuilder {
+    page: BoundingBox,
+    word_order: WordOrder,
+    orientation: LineOrientation,
+    lines: Vec<Zone>,
+}
+
+impl HiddenTextBuilder {
+    /// An empty, horizontal, left-to-right page.
+    pub fn new(page_width: u16, page_height: u16) -> Self {
+        Self {
+            page: BoundingBox {
+                x: 0,
+                y: 0,
+                w: page_width,
+                h: page_height,
+            },
+            word_order: WordOrder::default(),
+            orientation: LineOrientation::default(),
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn with_word_order(mut self, order: WordOrder) -> Self {
+        self.word_order = order;
+        self
+    }
+
+    pub fn with_orientation(mut self, orientation: LineOrientation) -> Self {
+        self.orientation = orientation;
+        self
+    }
+
+    /// Adds a line read in the builder's word order. Empty lines are
+    /// skipped.
+    pub fn add_line(&mut self, words: Vec<Zone>) -> &mut Self {
+        self.add_line_with_order(words, self.word_order)
+    }
+
+    /// Adds a line whose words are read in `order`.
+    pub fn add_line_with_order(&mut self, mut words: Vec<Zone>, order: WordOrder) -> &mut Self {
+        let Some(bbox) = words.iter().map(|w| w.bbox).reduce(|a, b| a.union(&b)) else {
+            return self;
+        };
+        order_words(&mut words, order, self.orientation);
+        let mut line = Zone::new(ZoneKind::Line, bbox);
+        line.children = words;
+        self.lines.push(line);
+        self
+    }
+
+    /// The page, with its lines in reading order.
+    pub fn build(mut self) -> HiddenText {
+        order_lines(&mut self.lines, self.word_order, self.orientation);
+        let mut root_zone = Zone::new(ZoneKind::Page, self.page);
+        root_zone.children = self.lines;
+        HiddenText { root_zone }
+    }
+}
+
 /// Represents the complete hidden text structure for a page.
 #[derive(Debug, Clone)]
 pub struct HiddenText {
@@ -167,6 +345,254 @@ impl HiddenText {
         Self { root_zone: root }
     }
 
+    /// Reads the first page of an hOCR document, as written by Tesseract
+    /// and other OCR engines.
+    ///
+    /// Content areas become column zones, `ocr_par` paragraphs, `ocr_line`
+    /// (and captions, headers and floating text) lines and `ocrx_word`
+    /// words, with their `bbox` moved to DjVu's bottom-left origin. Other
+    /// markup is ignored; words without text and zones without words are
+    /// dropped.
+    pub fn from_hocr(hocr: &str) -> Result<Self, HiddenTextError> {
+        struct Open {
+            tag: String,
+            zone: Option<Zone>,
+        }
+        let mut stack: Vec<Open> = Vec::new();
+        let mut page: Option<Zone> = None;
+        let mut rest = hocr;
+        while let Some(start) = rest.find('<') {
+            if let Some(word) = stack.iter_mut().rev().find_map(|o| o.zone.as_mut())
+                && word.kind == ZoneKind::Word
+            {
+                word.text
+                    .get_or_insert_with(String::new)
+                    .push_str(&decode_entities(&rest[..start]));
+            }
+            rest = &rest[start..];
+            let end_marker = if rest.starts_with("<!--") { "-->" } else { ">" };
+            let Some(end) = rest.find(end_marker) else {
+                return Err(HiddenTextError::Malformed("unterminated hOCR tag".into()));
+            };
+            let tag = &rest[1..end];
+            rest = &rest[end + end_marker.len()..];
+            if tag.starts_with(['!', '?']) {
+                continue;
+            }
+
+            if let Some(name) = tag.strip_prefix('/') {
+                let name = name.trim().to_ascii_lowercase();
+                let Some(at) = stack.iter().rposition(|o| o.tag == name) else {
+                    continue;
+                };
+                while stack.len() > at {
+                    let Some(zone) = stack.pop().and_then(|o| o.zone) else {
+                        continue;
+                    };
+                    let keep = match zone.kind {
+                        ZoneKind::Word => zone.text.as_ref().is_some_and(|t| !t.trim().is_empty()),
+                        _ => !zone.children.is_empty(),
+                    };
+                    match stack.iter_mut().rev().find_map(|o| o.zone.as_mut()) {
+                        Some(parent) if keep => parent.children.push(zone),
+                        Some(_) => {}
+                        None if zone.kind == ZoneKind::Page => {
+                            page = Some(zone);
+                            break;
+                        }
+                        None => {}
+                    }
+                }
+                if page.is_some() {
+                    break;
+                }
+                continue;
+            }
+
+            let name: String = tag
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            if tag.ends_with('/') || VOID_ELEMENTS.contains(&name.as_str()) {
+                continue;
+            }
+            let kind = match hocr_attribute(tag, "class") {
+                Some("ocr_page") => Some(ZoneKind::Page),
+                Some("ocr_carea") => Some(ZoneKind::Column),
+                Some("ocr_par") => Some(ZoneKind::Paragraph),
+                Some("ocr_line" | "ocr_caption" | "ocr_header" | "ocr_textfloat") => {
+                    Some(ZoneKind::Line)
+                }
+                Some("ocrx_word") => Some(ZoneKind::Word),
+                _ => None,
+            };
+            // Zones outside a page, and pages after the first, are skipped.
+            let in_page = stack.iter().any(|o| o.zone.is_some());
+            let zone = match kind {
+                Some(ZoneKind::Page) if !in_page => {
+                    let (_, _, x1, y1) = hocr_bbox(tag)?;
+                    Some(Zone::new(ZoneKind::Page, page_box(x1, y1)))
+                }
+                Some(ZoneKind::Page) | None => None,
+                Some(kind) if in_page => {
+                    // The page is the outermost zone.
+                    let height = stack
+                        .iter()
+                        .find_map(|o| o.zone.as_ref())
+                        .map_or(0, |p| p.bbox.h);
+                    let (x0, y0, x1, y1) = hocr_bbox(tag)?;
+                    let bbox = BoundingBox {
+                        x: x0,
+                        y: height.saturating_sub(y1),
+                        w: x1.saturating_sub(x0),
+                        h: y1.saturating_sub(y0),
+                    };
+                    Some(Zone::new(kind, bbox))
+                }
+                Some(_) => None,
+            };
+            stack.push(Open { tag: name, zone });
+        }
+
+        let mut root =
+            page.ok_or_else(|| HiddenTextError::Malformed("no ocr_page in hOCR".into()))?;
+        trim_words(&mut root);
+        Ok(Self { root_zone: root })
+    }
+
+    /// Best-effort layer for pages that have text but no OCR coordinates,
+    /// so they are at least searchable.
+    ///
+    /// Paragraphs of `text` are separated by blank lines, and each of its
+    /// other lines becomes a line zone. The lines are spread evenly down
+    /// the page, inside a margin of a tenth of it, with a blank line between
+    /// paragraphs; words get widths in proportion to their length. Boxes do
+    /// not match the picture, so selections will be off.
+    pub fn from_plain_text(page_width: u16, page_height: u16, text: &str) -> Self {
+        let mut root = Zone::new(ZoneKind::Page, page_box(page_width, page_height));
+        let paragraphs = plain_paragraphs(text);
+        let line_count: usize = paragraphs.iter().map(Vec::len).sum();
+        if line_count == 0 {
+            return Self { root_zone: root };
+        }
+
+        let (margin_x, margin_y) = (page_width as f64 / 10.0, page_height as f64 / 10.0);
+        let width = page_width as f64 - 2.0 * margin_x;
+        let pitch =
+            (page_height as f64 - 2.0 * margin_y) / (line_count + paragraphs.len() - 1) as f64;
+        let longest = paragraphs
+            .iter()
+            .flatten()
+            .map(|l| char_count(l))
+            .max()
+            .unwrap_or(1);
+        let mut top = margin_y;
+        for paragraph in paragraphs {
+            let lines: Vec<Zone> = paragraph
+                .iter()
+                .map(|words| {
+                    let line_width = width * char_count(words) as f64 / longest as f64;
+                    let line = place_line(
+                        words,
+                        (margin_x, top),
+                        (line_width, pitch * 0.75),
+                        page_height,
+                    );
+                    top += pitch;
+                    line
+                })
+                .collect();
+            top += pitch;
+            let mut zone = Zone::new(ZoneKind::Paragraph, union_of(&lines));
+            zone.children = lines;
+            root.children.push(zone);
+        }
+        Self { root_zone: root }
+    }
+
+    /// Like [`from_plain_text`](Self::from_plain_text), but sets the words
+    /// on `lines`, boxes in DjVu coordinates in reading order, such as the
+    /// text lines [`find_text_lines`] finds on the page's mask. The words
+    /// flow across the boxes, each taking a share of the text in proportion
+    /// to its width, so searches land near the right line; paragraph breaks
+    /// are not kept. Without boxes, the text is spread evenly.
+    pub fn from_plain_text_on_lines(
+        page_width: u16,
+        page_height: u16,
+        text: &str,
+        lines: &[BoundingBox],
+    ) -> Self {
+        let total_width: f64 = lines.iter().map(|l| l.w as f64).sum();
+        if total_width == 0.0 {
+            return Self::from_plain_text(page_width, page_height, text);
+        }
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let chars = char_count(&words) as f64;
+
+        // A word goes on the first box whose share of the text ends past
+        // its middle.
+        let mut line_words = vec![Vec::new(); lines.len()];
+        let share = |line: &BoundingBox| chars * line.w as f64 / total_width;
+        let (mut offset, mut line, mut share_end) = (0.0, 0, share(&lines[0]));
+        for word in words {
+            let len = word.chars().count() as f64;
+            while offset + len / 2.0 > share_end && line + 1 < lines.len() {
+                line += 1;
+                share_end += share(&lines[line]);
+            }
+            line_words[line].push(word);
+            offset += len + 1.0;
+        }
+
+        let mut root = Zone::new(ZoneKind::Page, page_box(page_width, page_height));
+        for (bbox, words) in lines.iter().zip(line_words) {
+            if !words.is_empty() {
+                let top = page_height.saturating_sub(bbox.ymax()) as f64;
+                let (x, w, h) = (bbox.x as f64, bbox.w as f64, bbox.h as f64);
+                root.children
+                    .push(place_line(&words, (x, top), (w, h), page_height));
+            }
+        }
+        Self { root_zone: root }
+    }
+
+    /// Copy of the text with every zone mapped from the displayed page to
+    /// the stored image of a `width` x `height` page shown with `rotation`,
+    /// the coordinates the spec requires.
+    pub fn unrotated(&self, rotation: PageRotation, width: u16, height: u16) -> Self {
+        fn map(zone: &mut Zone, rotation: PageRotation, width: u16, height: u16) {
+            zone.bbox = zone.bbox.unrotated(rotation, width, height);
+            for child in &mut zone.children {
+                map(child, rotation, width, height);
+            }
+        }
+        let mut text = self.clone();
+        if rotation != PageRotation::Upright {
+            map(&mut text.root_zone, rotation, width, height);
+        }
+        text
+    }
+
+    /// Removes the text of every leaf zone touching `area`, along with the
+    /// zones left empty, and returns how many leaf zones were removed.
+    pub fn redact(&mut self, area: &BoundingBox) -> usize {
+        fn prune(zone: &mut Zone, area: &BoundingBox) -> usize {
+            let mut removed = 0;
+            zone.children.retain_mut(|child| {
+                if child.children.is_empty() {
+                    let hit = child.bbox.intersects(area);
+                    removed += hit as usize;
+                    return !hit;
+                }
+                removed += prune(child, area);
+                !child.children.is_empty()
+            });
+            removed
+        }
+        prune(&mut self.root_zone, area)
+    }
+
     /// Encodes the hidden text structure into the binary format for a TXTa/TXTz chunk.
     ///
     /// **Note**: The output of this function should be compressed with BZZ (not bzip2!)
@@ -192,8 +618,10 @@ impl HiddenText {
     /// Recursively walks the tree, collecting text and assigning text offsets.
     fn flatten_text_recursive(zone: &mut Zone, full_text: &mut String) {
         if let Some(text) = &zone.text {
+            // Control characters in a word would read as zone separators.
+            let text = sanitize(text);
             zone.text_start = full_text.len();
-            full_text.push_str(text);
+            full_text.push_str(&text);
             zone.text_len = text.len();
         } else {
             zone.text_start = full_text.len();
@@ -297,6 +725,531 @@ impl HiddenText {
     }
 }
 
+impl HiddenText {
+    /// Reads back the data of a `TXTa` chunk, or of a `TXTz` chunk once
+    /// BZZ-decompressed.
+    ///
+    /// Zones without children get their text, less the separator that ends
+    /// it; the others get none, as when built by hand.
+    pub fn decode(data: &[u8]) -> Result<Self, HiddenTextError> {
+        let mut reader = Reader { data, pos: 0 };
+        let len = reader.u24()? as usize;
+        let text = reader.take(len)?;
+        let text = std::str::from_utf8(text)
+            .map_err(|_| HiddenTextError::Malformed("text is not UTF-8".to_string()))?;
+        let _version = reader.take(1)?;
+        let root_zone = Self::decode_zone(&mut reader, text, None, None)?;
+        Ok(Self { root_zone })
+    }
+
+    /// Reads hidden text in the format of djvused's `print-txt` and
+    /// `set-txt`: nested `(kind xmin ymin xmax ymax ...)` expressions, from
+    /// `page` down to `char`, with the text of each leaf zone as a quoted
+    /// string after its box. Coordinates have DjVu's bottom-left origin.
+    pub fn from_djvused(text: &str) -> Result<Self, HiddenTextError> {
+        let mut tokens = SexprTokens {
+            data: text.as_bytes(),
+            pos: 0,
+        };
+        let root_zone = Self::parse_zone(&mut tokens)?;
+        if root_zone.kind != ZoneKind::Page {
+            return Err(HiddenTextError::Malformed(
+                "hidden text must start with a page zone".to_string(),
+            ));
+        }
+        match tokens.token()? {
+            None => Ok(Self { root_zone }),
+            Some(_) => Err(HiddenTextError::Malformed(
+                "text after the page zone".to_string(),
+            )),
+        }
+    }
+
+    fn parse_zone(tokens: &mut SexprTokens) -> Result<Zone, HiddenTextError> {
+        let malformed = |what: &str| HiddenTextError::Malformed(what.to_string());
+        if tokens.token()? != Some(Sexpr::Open) {
+            return Err(malformed("expected a zone"));
+        }
+        let kind = match tokens.token()? {
+            Some(Sexpr::Atom(name)) => match name.as_str() {
+                "page" => ZoneKind::Page,
+                "column" => ZoneKind::Column,
+                "region" => ZoneKind::Region,
+                "para" => ZoneKind::Paragraph,
+                "line" => ZoneKind::Line,
+                "word" => ZoneKind::Word,
+                "char" => ZoneKind::Character,
+                other => return Err(malformed(&format!("unknown zone type {other}"))),
+            },
+            _ => return Err(malformed("expected a zone type")),
+        };
+        let mut coords = [0u16; 4];
+        for coord in &mut coords {
+            *coord = match tokens.token()? {
+                Some(Sexpr::Atom(number)) => number
+                    .parse()
+                    .map_err(|_| malformed(&format!("bad coordinate {number}")))?,
+                _ => return Err(malformed("expected four coordinates")),
+            };
+        }
+        let [xmin, ymin, xmax, ymax] = coords;
+        if xmax < xmin || ymax < ymin {
+            return Err(malformed("zone box is inverted"));
+        }
+        let mut zone = Zone::new(
+            kind,
+            BoundingBox {
+                x: xmin,
+                y: ymin,
+                w: xmax - xmin,
+                h: ymax - ymin,
+            },
+        );
+        loop {
+            match tokens.peek()? {
+                Some(Sexpr::Close) => {
+                    tokens.token()?;
+                    return Ok(zone);
+                }
+                Some(Sexpr::Open) if zone.text.is_none() => {
+                    zone.children.push(Self::parse_zone(tokens)?)
+                }
+                Some(Sexpr::Str(_)) if zone.children.is_empty() && zone.text.is_none() => {
+                    if let Some(Sexpr::Str(text)) = tokens.token()? {
+                        zone.text = Some(text);
+                    }
+                }
+                Some(_) => return Err(malformed("unexpected item in a zone")),
+                None => return Err(malformed("unclosed zone")),
+            }
+        }
+    }
+
+    /// Inverse of [`HiddenText::encode_zone_recursive`].
+    fn decode_zone(
+        reader: &mut Reader,
+        text: &str,
+        parent: Option<&Zone>,
+        prev_sibling: Option<&Zone>,
+    ) -> Result<Zone, HiddenTextError> {
+        let kind = match reader.take(1)?[0] {
+            1 => ZoneKind::Page,
+            2 => ZoneKind::Column,
+            3 => ZoneKind::Region,
+            4 => ZoneKind::Paragraph,
+            5 => ZoneKind::Line,
+            6 => ZoneKind::Word,
+            7 => ZoneKind::Character,
+            other => {
+                return Err(HiddenTextError::Malformed(format!(
+                    "unknown zone type {other}"
+                )));
+            }
+        };
+        let (mut x, mut y) = (reader.i16()?, reader.i16()?);
+        let (width, height) = (reader.i16()?, reader.i16()?);
+        let mut text_start = reader.i16()?;
+        let text_len = reader.u24()? as usize;
+
+        if let Some(prev) = prev_sibling {
+            match kind {
+                ZoneKind::Page | ZoneKind::Paragraph | ZoneKind::Line => {
+                    x += prev.bbox.x as i32;
+                    y = prev.bbox.y as i32 - (y + height);
+                }
+                _ => {
+                    x += prev.bbox.xmax() as i32;
+                    y += prev.bbox.y as i32;
+                }
+            }
+            text_start += (prev.text_start + prev.text_len) as i32;
+        } else if let Some(p) = parent {
+            x += p.bbox.x as i32;
+            y = p.bbox.ymax() as i32 - (y + height);
+            text_start += p.text_start as i32;
+        }
+
+        let clamp = |v: i32| v.clamp(0, u16::MAX as i32) as u16;
+        let mut zone = Zone::new(
+            kind,
+            BoundingBox {
+                x: clamp(x),
+                y: clamp(y),
+                w: clamp(width),
+                h: clamp(height),
+            },
+        );
+        zone.text_start = text_start.max(0) as usize;
+        zone.text_len = text_len;
+
+        let children = reader.u24()?;
+        let mut prev_child: Option<Zone> = None;
+        for _ in 0..children {
+            let child = Self::decode_zone(reader, text, Some(&zone), prev_child.as_ref())?;
+            if let Some(prev) = prev_child.replace(child) {
+                zone.children.push(prev);
+            }
+        }
+        zone.children.extend(prev_child);
+
+        if zone.children.is_empty() {
+            let own = text
+                .get(zone.text_start..zone.text_start + zone.text_len)
+                .ok_or_else(|| HiddenTextError::Malformed("zone text out of range".to_string()))?;
+            zone.text = Some(own.trim_end_matches(SEPARATORS).to_string());
+        }
+        Ok(zone)
+    }
+}
+
+/// An item of djvused's text format.
+#[derive(Debug, PartialEq)]
+enum Sexpr {
+    Open,
+    Close,
+    Atom(String),
+    Str(String),
+}
+
+struct SexprTokens<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl SexprTokens<'_> {
+    fn peek(&mut self) -> Result<Option<Sexpr>, HiddenTextError> {
+        let pos = self.pos;
+        let token = self.token();
+        self.pos = pos;
+        token
+    }
+
+    fn token(&mut self) -> Result<Option<Sexpr>, HiddenTextError> {
+        let data = self.data;
+        while self.pos < data.len() && data[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        let Some(&first) = data.get(self.pos) else {
+            return Ok(None);
+        };
+        self.pos += 1;
+        match first {
+            b'(' => Ok(Some(Sexpr::Open)),
+            b')' => Ok(Some(Sexpr::Close)),
+            b'"' => {
+                // C escapes; djvused writes bytes outside ASCII as octal.
+                let mut bytes = Vec::new();
+                loop {
+                    let Some(&c) = data.get(self.pos) else {
+                        return Err(HiddenTextError::Malformed(
+                            "unterminated string".to_string(),
+                        ));
+                    };
+                    self.pos += 1;
+                    match c {
+                        b'"' => break,
+                        b'\\' => {
+                            let escaped = data.get(self.pos).copied().unwrap_or(b'\\');
+                            self.pos += 1;
+                            match escaped {
+                                b'n' => bytes.push(b'\n'),
+                                b't' => bytes.push(b'\t'),
+                                b'0'..=b'7' => {
+                                    let mut value = (escaped - b'0') as u32;
+                                    for _ in 0..2 {
+                                        match data.get(self.pos) {
+                                            Some(&d @ b'0'..=b'7') => {
+                                                value = value * 8 + (d - b'0') as u32;
+                                                self.pos += 1;
+                                            }
+                                            _ => break,
+                                        }
+                                    }
+                                    bytes.push(value as u8);
+                                }
+                                other => bytes.push(other),
+                            }
+                        }
+                        c => bytes.push(c),
+                    }
+                }
+                let text = String::from_utf8(bytes)
+                    .map_err(|_| HiddenTextError::Malformed("string is not UTF-8".to_string()))?;
+                Ok(Some(Sexpr::Str(text)))
+            }
+            _ => {
+                let start = self.pos - 1;
+                while self.pos < data.len()
+                    && !data[self.pos].is_ascii_whitespace()
+                    && !matches!(data[self.pos], b'(' | b')' | b'"')
+                {
+                    self.pos += 1;
+                }
+                let atom = String::from_utf8_lossy(&data[start..self.pos]).into_owned();
+                Ok(Some(Sexpr::Atom(atom)))
+            }
+        }
+    }
+}
+
+/// Characters the text layer puts after zones to separate them.
+const SEPARATORS: &[char] = &['\x0B', '\x1D', '\x1F', '\n', ' '];
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], HiddenTextError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| HiddenTextError::Malformed("unexpected end of data".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u24(&mut self) -> Result<u32, HiddenTextError> {
+        let b = self.take(3)?;
+        Ok((b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32)
+    }
+
+    fn i16(&mut self) -> Result<i32, HiddenTextError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]) as i32 - 0x8000)
+    }
+}
+
+/// Finds the lines of text of a bilevel page, as boxes in DjVu
+/// coordinates, top to bottom.
+///
+/// Lines are bands of rows holding black pixels, so the page should be
+/// straight (see [`estimate_skew`]) and single-column. Bands under half the
+/// median line height, such as the dots of a line of i's or stray specks,
+/// join the nearest line if it is close and are dropped otherwise.
+///
+/// [`estimate_skew`]: crate::image::deskew::estimate_skew
+pub fn find_text_lines(image: &BitImage) -> Vec<BoundingBox> {
+    // (top, bottom, left, right), inclusive, in image rows.
+    let mut bands: Vec<(usize, usize, usize, usize)> = Vec::new();
+    let mut open = false;
+    for y in 0..image.height {
+        let runs = image.row_runs(y);
+        let (Some(first), Some(last)) = (runs.first(), runs.last()) else {
+            open = false;
+            continue;
+        };
+        match bands.last_mut() {
+            Some(band) if open => {
+                band.1 = y;
+                band.2 = band.2.min(first.0);
+                band.3 = band.3.max(last.1);
+            }
+            _ => bands.push((y, y, first.0, last.1)),
+        }
+        open = true;
+    }
+
+    let mut heights: Vec<usize> = bands.iter().map(|b| b.1 - b.0 + 1).collect();
+    heights.sort_unstable();
+    let small = heights
+        .get(heights.len() / 2)
+        .map_or(0, |median| median / 2);
+    let (mut lines, marks): (Vec<_>, Vec<_>) =
+        bands.into_iter().partition(|b| b.1 - b.0 + 1 >= small);
+    for mark in marks {
+        let gap = |line: &(usize, usize, usize, usize)| {
+            line.0
+                .saturating_sub(mark.1)
+                .max(mark.0.saturating_sub(line.1))
+        };
+        if let Some(line) = lines.iter_mut().min_by_key(|line| gap(line))
+            && gap(line) <= small
+        {
+            *line = (
+                line.0.min(mark.0),
+                line.1.max(mark.1),
+                line.2.min(mark.2),
+                line.3.max(mark.3),
+            );
+        }
+    }
+
+    let to_u16 = |v: usize| v.min(u16::MAX as usize) as u16;
+    lines
+        .into_iter()
+        .map(|(top, bottom, left, right)| BoundingBox {
+            x: to_u16(left),
+            y: to_u16(image.height - 1 - bottom),
+            w: to_u16(right - left + 1),
+            h: to_u16(bottom - top + 1),
+        })
+        .collect()
+}
+
+/// HTML elements without a closing tag.
+const VOID_ELEMENTS: [&str; 6] = ["br", "hr", "img", "input", "link", "meta"];
+
+/// The value of attribute `name` of the `tag` source.
+fn hocr_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(at) = rest.find(name) {
+        let before = rest[..at].chars().next_back();
+        let after = rest[at + name.len()..].trim_start();
+        rest = &rest[at + name.len()..];
+        if !before.is_some_and(char::is_whitespace) {
+            continue;
+        }
+        let Some(value) = after.strip_prefix('=').map(str::trim_start) else {
+            continue;
+        };
+        let quote = value.chars().next().filter(|q| *q == '"' || *q == '\'')?;
+        let value = &value[1..];
+        return value.find(quote).map(|end| &value[..end]);
+    }
+    None
+}
+
+/// The `bbox x0 y0 x1 y1` property of the `title` of an hOCR element.
+fn hocr_bbox(tag: &str) -> Result<(u16, u16, u16, u16), HiddenTextError> {
+    let bad = || HiddenTextError::Malformed(format!("no valid bbox in <{tag}>"));
+    let title = hocr_attribute(tag, "title").ok_or_else(bad)?;
+    let bbox = title
+        .split(';')
+        .find_map(|property| property.trim().strip_prefix("bbox "))
+        .ok_or_else(bad)?;
+    let coords: Vec<u16> = bbox
+        .split_whitespace()
+        .map(|c| c.parse().map_err(|_| bad()))
+        .collect::<Result<_, _>>()?;
+    match coords[..] {
+        [x0, y0, x1, y1] if x0 <= x1 && y0 <= y1 => Ok((x0, y0, x1, y1)),
+        _ => Err(bad()),
+    }
+}
+
+/// Replaces the character references of HTML text.
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('&') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let decoded = rest.find(';').and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                "nbsp" => ' ',
+                num => {
+                    let code = match num.strip_prefix("#x").or_else(|| num.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => num.strip_prefix('#')?.parse().ok()?,
+                    };
+                    char::from_u32(code)?
+                }
+            };
+            Some((c, end + 1))
+        });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Strips the whitespace around the text of every word.
+fn trim_words(zone: &mut Zone) {
+    if let Some(text) = &mut zone.text {
+        *text = text.trim().to_string();
+    }
+    zone.children.iter_mut().for_each(trim_words);
+}
+
+fn page_box(width: u16, height: u16) -> BoundingBox {
+    BoundingBox {
+        x: 0,
+        y: 0,
+        w: width,
+        h: height,
+    }
+}
+
+/// The non-empty lines of each paragraph of `text`, split into words.
+fn plain_paragraphs(text: &str) -> Vec<Vec<Vec<&str>>> {
+    let mut paragraphs = vec![Vec::new()];
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match paragraphs.last_mut() {
+            Some(paragraph) if words.is_empty() => {
+                if !paragraph.is_empty() {
+                    paragraphs.push(Vec::new());
+                }
+            }
+            Some(paragraph) => paragraph.push(words),
+            None => unreachable!("starts with a paragraph"),
+        }
+    }
+    paragraphs.retain(|p| !p.is_empty());
+    paragraphs
+}
+
+/// Characters of `words` set with single spaces between them.
+fn char_count(words: &[&str]) -> usize {
+    let letters: usize = words.iter().map(|w| w.chars().count()).sum();
+    (letters + words.len()).saturating_sub(1).max(1)
+}
+
+/// A line zone of `words` filling the box at `top_left` (top-left origin)
+/// of size `size`, each word as wide as its share of the characters.
+fn place_line(
+    words: &[&str],
+    (x, top): (f64, f64),
+    (width, height): (f64, f64),
+    page_height: u16,
+) -> Zone {
+    let scale = width / char_count(words) as f64;
+    let to_u16 = |v: f64| v.round().clamp(0.0, u16::MAX as f64) as u16;
+    let y = to_u16(page_height as f64 - top - height);
+    let mut offset = 0.0;
+    let children: Vec<Zone> = words
+        .iter()
+        .map(|word| {
+            let len = word.chars().count() as f64;
+            let bbox = BoundingBox {
+                x: to_u16(x + offset * scale),
+                y,
+                w: to_u16(len * scale).max(1),
+                h: to_u16(height).max(1),
+            };
+            offset += len + 1.0;
+            Zone::word(word.to_string(), bbox)
+        })
+        .collect();
+    let mut line = Zone::new(ZoneKind::Line, union_of(&children));
+    line.children = children;
+    line
+}
+
+fn union_of(zones: &[Zone]) -> BoundingBox {
+    zones
+        .iter()
+        .map(|z| z.bbox)
+        .reduce(|a, b| a.union(&b))
+        .unwrap_or_default()
+}
+
 // Helper functions for writing multi-byte integers in DjVu's format.
 
 /// Writes a 24-bit unsigned integer in big-endian format
@@ -309,3 +1262,339 @@ fn write_i16(writer: &mut impl Write, val: i32) -> Result<(), std::io::Error> {
     let val_u16 = (val + 0x8000) as u16;
     writer.write_all(&val_u16.to_be_bytes())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bbox(x: u16, y: u16, w: u16, h: u16) -> BoundingBox {
+        BoundingBox { x, y, w, h }
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        let text = HiddenText::from_word_boxes(
+            200,
+            100,
+            vec![
+                ("Hello".to_string(), 10, 20, 50, 12),
+                ("wörld".to_string(), 70, 22, 60, 10),
+                ("again".to_string(), 10, 60, 40, 12),
+            ],
+        );
+        let mut data = Vec::new();
+        text.encode(&mut data).unwrap();
+
+        let decoded = HiddenText::decode(&data).unwrap();
+        let page = decoded.root_zone.bbox;
+        assert_eq!((page.x, page.y, page.w, page.h), (0, 0, 200, 100));
+        for (ours, theirs) in decoded
+            .root_zone
+            .children
+            .iter()
+            .zip(&text.root_zone.children)
+        {
+            assert_eq!(ours.kind, ZoneKind::Word);
+            assert_eq!(ours.text, theirs.text);
+            let (a, b) = (ours.bbox, theirs.bbox);
+            assert_eq!((a.x, a.y, a.w, a.h), (b.x, b.y, b.w, b.h));
+        }
+        assert_eq!(decoded.root_zone.children.len(), 3);
+
+        let mut again = Vec::new();
+        decoded.encode(&mut again).unwrap();
+        assert_eq!(again, data);
+        assert!(HiddenText::decode(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_unrotate_all_orientations() {
+        // A 100x40 stored page with a word in its bottom-left corner, and
+        // where that word appears once the page is turned for display.
+        let (width, height) = (100, 40);
+        for (rotation, shown) in [
+            (PageRotation::Upright, bbox(0, 0, 10, 5)),
+            (PageRotation::Ccw90, bbox(35, 0, 5, 10)),
+            (PageRotation::Rotate180, bbox(90, 35, 10, 5)),
+            (PageRotation::Cw90, bbox(0, 90, 5, 10)),
+        ] {
+            assert_eq!(
+                PageRotation::from_info_flags(rotation.info_flags()),
+                rotation
+            );
+            let (shown_w, shown_h) = rotation.displayed_size(width, height);
+            let mut text = HiddenText::new(bbox(0, 0, shown_w as u16, shown_h as u16));
+            text.root_zone
+                .children
+                .push(Zone::word("corner".to_string(), shown));
+
+            let stored = text.unrotated(rotation, width as u16, height as u16);
+            let page = stored.root_zone.bbox;
+            assert_eq!((page.x, page.y, page.w, page.h), (0, 0, 100, 40));
+            let word = stored.root_zone.children[0].bbox;
+            assert_eq!(
+                (word.x, word.y, word.w, word.h),
+                (0, 0, 10, 5),
+                "{rotation:?}"
+            );
+        }
+    }
+
+    fn words(boxes: &[(&str, BoundingBox)]) -> Vec<Zone> {
+        boxes
+            .iter()
+            .map(|(t, b)| Zone::word(t.to_string(), *b))
+            .collect()
+    }
+
+    fn texts(zone: &Zone) -> Vec<&str> {
+        zone.children
+            .iter()
+            .filter_map(|w| w.text.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn test_mixed_direction_lines() {
+        // An Arabic page (right to left) with an English line in the
+        // middle. Words go in scrambled.
+        let mut builder = HiddenTextBuilder::new(300, 100).with_word_order(WordOrder::RightToLeft);
+        builder.add_line(words(&[
+            ("ثانية", bbox(100, 20, 60, 10)),
+            ("سطر", bbox(200, 20, 60, 10)),
+        ]));
+        builder.add_line(words(&[
+            ("مرحبا", bbox(200, 80, 60, 10)),
+            ("بالعالم", bbox(100, 80, 80, 10)),
+        ]));
+        builder.add_line_with_order(
+            words(&[
+                ("world", bbox(90, 50, 50, 10)),
+                ("hello", bbox(20, 50, 50, 10)),
+            ]),
+            WordOrder::LeftToRight,
+        );
+        builder.add_line(Vec::new());
+        let text = builder.build();
+
+        let lines = &text.root_zone.children;
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|l| l.kind == ZoneKind::Line));
+        assert_eq!(texts(&lines[0]), ["مرحبا", "بالعالم"]);
+        assert_eq!(texts(&lines[1]), ["hello", "world"]);
+        assert_eq!(texts(&lines[2]), ["سطر", "ثانية"]);
+        let b = lines[0].bbox;
+        assert_eq!((b.x, b.y, b.w, b.h), (100, 80, 160, 10));
+
+        // Leftward steps between words survive the delta coding.
+        let mut data = Vec::new();
+        text.encode(&mut data).unwrap();
+        let decoded = HiddenText::decode(&data).unwrap();
+        let line = &decoded.root_zone.children[0];
+        assert_eq!(texts(line), ["مرحبا", "بالعالم"]);
+        assert_eq!(line.children[1].bbox.x, 100);
+    }
+
+    #[test]
+    fn test_vertical_lines() {
+        // Two Japanese columns, read top to bottom, right column first.
+        let mut builder = HiddenTextBuilder::new(200, 300)
+            .with_word_order(WordOrder::RightToLeft)
+            .with_orientation(LineOrientation::Vertical);
+        builder.add_line(words(&[
+            ("世界", bbox(40, 120, 20, 60)),
+            ("こんにちは", bbox(40, 200, 20, 90)),
+        ]));
+        builder.add_line(words(&[
+            ("です", bbox(140, 100, 20, 40)),
+            ("縦書き", bbox(140, 160, 20, 60)),
+        ]));
+        let text = builder.build();
+        let lines = &text.root_zone.children;
+        assert_eq!(texts(&lines[0]), ["縦書き", "です"]);
+        assert_eq!(texts(&lines[1]), ["こんにちは", "世界"]);
+
+        let mut lines = lines.clone();
+        order_lines(
+            &mut lines,
+            WordOrder::LeftToRight,
+            LineOrientation::Vertical,
+        );
+        assert_eq!(texts(&lines[0]), ["こんにちは", "世界"]);
+    }
+
+    #[test]
+    fn test_from_hocr() {
+        let hocr = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html><head><meta name='ocr-system' content='tesseract 5.3.0' /></head>
+<body>
+  <div class='ocr_page' id='page_1' title='image "page.ppm"; bbox 0 0 400 300; ppageno 0'>
+   <div class='ocr_carea' id='block_1_1' title="bbox 20 30 380 120">
+    <p class='ocr_par' id='par_1_1' lang='eng' title="bbox 20 30 380 120">
+     <span class='ocr_line' id='line_1_1' title="bbox 20 30 380 60; baseline 0 -5">
+      <span class='ocrx_word' id='word_1_1' title='bbox 20 30 120 60; x_wconf 96'>Fish<!-- a comment -->&amp;Chips</span>
+      <span class='ocrx_word' id='word_1_2' title='bbox 140 32 380 60; x_wconf 91'><strong>caf&#233;</strong></span>
+     </span>
+     <span class='ocr_line' id='line_1_2' title="bbox 20 90 200 120">
+      <span class='ocrx_word' id='word_1_3' title='bbox 20 90 200 120'> </span>
+     </span>
+    </p>
+   </div>
+  </div>
+  <div class='ocr_page' title='bbox 0 0 10 10'></div>
+</body></html>"#;
+        let text = HiddenText::from_hocr(hocr).unwrap();
+        let page = text.root_zone.bbox;
+        assert_eq!((page.w, page.h), (400, 300));
+        let column = &text.root_zone.children[0];
+        assert_eq!(column.kind, ZoneKind::Column);
+        let paragraph = &column.children[0];
+        assert_eq!(paragraph.kind, ZoneKind::Paragraph);
+        // The line without words is dropped.
+        assert_eq!(paragraph.children.len(), 1);
+        let line = &paragraph.children[0];
+        assert_eq!(line.kind, ZoneKind::Line);
+        assert_eq!(texts(line), ["Fish&Chips", "café"]);
+        let b = line.children[1].bbox;
+        assert_eq!((b.x, b.y, b.w, b.h), (140, 240, 240, 28));
+
+        let mut data = Vec::new();
+        text.encode(&mut data).unwrap();
+        assert!(HiddenText::decode(&data).is_ok());
+        assert!(HiddenText::from_hocr("<html><body>no page</body></html>").is_err());
+        assert!(HiddenText::from_hocr("<div class='ocr_page' title='bbox 0 0'>").is_err());
+    }
+
+    #[test]
+    fn test_from_tesseract_hocr() {
+        // The layout Tesseract 5 writes with `hocr_char_boxes=1`: an XHTML
+        // prologue, a header line, a photo and a separator block, escaped
+        // quotes and per-character spans inside the words.
+        let hocr = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Transitional//EN"
+    "http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml" xml:lang="en" lang="en">
+ <head>
+  <title></title>
+  <meta http-equiv="Content-Type" content="text/html;charset=utf-8"/>
+  <meta name='ocr-system' content='tesseract 5.3.0' />
+  <meta name='ocr-capabilities' content='ocr_page ocr_carea ocr_par ocr_line ocrx_word ocrp_wconf'/>
+ </head>
+ <body>
+  <div class='ocr_page' id='page_1' title='image "/tmp/.tmpQx1/page.ppm"; bbox 0 0 1275 1650; ppageno 0; scan_res 300 300'>
+   <div class='ocr_carea' id='block_1_1' title="bbox 150 140 520 190">
+    <p class='ocr_par' id='par_1_1' lang='eng' title="bbox 150 140 520 190">
+     <span class='ocr_header' id='line_1_1' title="bbox 150 140 520 190; baseline 0 -10; x_size 50; x_descenders 10; x_ascenders 12">
+      <span class='ocrx_word' id='word_1_1' title='bbox 150 140 390 190; x_wconf 96'><span class='ocrx_cinfo' title='x_bboxes 150 140 190 180; x_conf 99.2'>C</span><span class='ocrx_cinfo' title='x_bboxes 192 140 230 180; x_conf 98.7'>h</span><span class='ocrx_cinfo' title='x_bboxes 232 152 270 180; x_conf 98.9'>a</span><span class='ocrx_cinfo' title='x_bboxes 272 152 310 190; x_conf 97.5'>p</span><span class='ocrx_cinfo' title='x_bboxes 312 146 340 180; x_conf 99.0'>t</span><span class='ocrx_cinfo' title='x_bboxes 342 152 365 180; x_conf 98.1'>e</span><span class='ocrx_cinfo' title='x_bboxes 367 152 390 180; x_conf 98.4'>r</span></span>
+      <span class='ocrx_word' id='word_1_2' title='bbox 480 140 520 180; x_wconf 95'><span class='ocrx_cinfo' title='x_bboxes 480 140 520 180; x_conf 95.3'>1</span></span>
+     </span>
+    </p>
+   </div>
+   <div class='ocr_photo' id='block_1_2' title="bbox 150 260 1125 900"></div>
+   <div class='ocr_separator' id='block_1_3' title="bbox 150 950 1125 954"></div>
+   <div class='ocr_carea' id='block_1_4' title="bbox 150 1000 1100 1100">
+    <p class='ocr_par' id='par_1_2' lang='eng' title="bbox 150 1000 1100 1100">
+     <span class='ocr_line' id='line_1_2' title="bbox 150 1000 1100 1040; baseline 0.001 -9; x_size 40; x_descenders 9; x_ascenders 10">
+      <span class='ocrx_word' id='word_1_3' title='bbox 150 1000 250 1040; x_wconf 93'>It&#39;s</span>
+      <span class='ocrx_word' id='word_1_4' title='bbox 270 1000 420 1040; x_wconf 89'>&quot;R&amp;D&quot;</span>
+      <span class='ocrx_word' id='word_1_5' title='bbox 440 1000 600 1040; x_wconf 91'><em>“naïve”</em></span>
+     </span>
+     <span class='ocr_line' id='line_1_3' title="bbox 150 1060 700 1100; baseline 0 -9; x_size 40; x_descenders 9; x_ascenders 10">
+      <span class='ocrx_word' id='word_1_6' title='bbox 150 1060 300 1100; x_wconf 0'> </span>
+      <span class='ocrx_word' id='word_1_7' title='bbox 320 1060 700 1100; x_wconf 90'>x&lt;y</span>
+     </span>
+    </p>
+   </div>
+  </div>
+ </body>
+</html>
+"#;
+        let text = HiddenText::from_hocr(hocr).unwrap();
+        let page = text.root_zone.bbox;
+        assert_eq!((page.w, page.h), (1275, 1650));
+        // The photo and separator blocks hold no words.
+        let columns = &text.root_zone.children;
+        assert_eq!(columns.len(), 2);
+
+        let header = &columns[0].children[0].children[0];
+        assert_eq!(header.kind, ZoneKind::Line);
+        assert_eq!(texts(header), ["Chapter", "1"]);
+        let b = header.children[0].bbox;
+        assert_eq!((b.x, b.y, b.w, b.h), (150, 1460, 240, 50));
+
+        let lines = &columns[1].children[0].children;
+        assert_eq!(texts(&lines[0]), ["It's", "\"R&D\"", "“naïve”"]);
+        // The blank word is dropped, its line kept for the other one.
+        assert_eq!(texts(&lines[1]), ["x<y"]);
+        let b = lines[1].bbox;
+        assert_eq!((b.x, b.y, b.w, b.h), (150, 550, 550, 40));
+    }
+
+    #[test]
+    fn test_plain_text_spread_evenly() {
+        let text = HiddenText::from_plain_text(
+            1000,
+            1000,
+            "Call me Ishmael.\nSome years ago\n\n\nnever mind",
+        );
+        let paragraphs = &text.root_zone.children;
+        assert_eq!(paragraphs.len(), 2);
+        assert!(paragraphs.iter().all(|p| p.kind == ZoneKind::Paragraph));
+        let lines: Vec<&Zone> = paragraphs.iter().flat_map(|p| &p.children).collect();
+        assert_eq!(texts(lines[0]), ["Call", "me", "Ishmael."]);
+        assert_eq!(texts(lines[2]), ["never", "mind"]);
+
+        // Inside the margins, top to bottom, the longest line full width.
+        let first = lines[0].bbox;
+        assert_eq!((first.x, first.xmax(), first.ymax()), (100, 900, 900));
+        assert!(lines.windows(2).all(|l| l[0].bbox.y > l[1].bbox.ymax()));
+        assert_eq!(lines[2].bbox.y, 150);
+        let words = &lines[0].children;
+        assert!(words.windows(2).all(|w| w[0].bbox.xmax() < w[1].bbox.x));
+
+        let mut data = Vec::new();
+        text.encode(&mut data).unwrap();
+        assert_eq!(
+            HiddenText::decode(&data).unwrap().root_zone.children.len(),
+            2
+        );
+        assert!(
+            HiddenText::from_plain_text(100, 100, " \n\n")
+                .root_zone
+                .children
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_plain_text_on_found_lines() {
+        // Two lines of text on a 200x100 page, the second twice as wide,
+        // with a dot above the first and a speck far below the second.
+        let mut page = BitImage::new(200, 100).unwrap();
+        for y in 20..30 {
+            page.fill_run(y, 10, 89);
+        }
+        page.fill_run(17, 30, 31);
+        for y in 50..60 {
+            page.fill_run(y, 10, 169);
+        }
+        page.set_usize(5, 90, true);
+        let lines = find_text_lines(&page);
+        assert_eq!(lines.len(), 2);
+        let (a, b) = (lines[0], lines[1]);
+        assert_eq!((a.x, a.y, a.w, a.h), (10, 70, 80, 13));
+        assert_eq!((b.x, b.y, b.w, b.h), (10, 40, 160, 10));
+
+        let text =
+            HiddenText::from_plain_text_on_lines(200, 100, "one two\nthree four five six", &lines);
+        let placed = &text.root_zone.children;
+        // The first line holds a third of the 27 characters.
+        assert_eq!(texts(&placed[0]), ["one", "two"]);
+        assert_eq!(texts(&placed[1]), ["three", "four", "five", "six"]);
+        assert!(placed[0].bbox.xmax() <= 90 && placed[1].bbox.y == 40);
+
+        let even = HiddenText::from_plain_text_on_lines(200, 100, "one", &[]);
+        assert_eq!(even.root_zone.children[0].kind, ZoneKind::Paragraph);
+    }
+}
@@ -1,9 +0,0 @@
-// src/lib.rs
-
-pub mod annotations;
-pub mod hidden_text;
-
-// Existing modules...
-pub mod iw44;
-pub mod jb2; // Assuming you have this from the previous step
-pub mod zp_codec;
\ No newline at end of file
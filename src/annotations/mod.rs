@@ -3,4 +3,4 @@ pub mod hidden_text;
 pub mod string;
 
 pub use annotations::{AnnotationShape, Annotations, Hyperlink};
-pub use hidden_text::HiddenText;
+pub use hidden_text::{HiddenText, HiddenTextBuilder};
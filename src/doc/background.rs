@@ -0,0 +1,113 @@
+//! Codecs for the background layer of a page.
+//!
+//! A page's background picture goes through a [`BackgroundCodec`], which
+//! turns it into the payloads of one or more chunks. [`Iw44Background`],
+//! writing progressive `BG44` chunks, is the default; a page can take
+//! another one with [`PageComponents::with_background_codec`], for instance
+//! to try JPEG 2000 (`BG2k`) or JPEG (`BGjp`) backgrounds while keeping the
+//! segmentation, masking and document assembly of this crate.
+//!
+//! Chunk IDs other than `BG44` are only understood by some viewers.
+//!
+//! [`PageComponents::with_background_codec`]: crate::doc::PageComponents::with_background_codec
+
+use crate::Result;
+use crate::doc::page_encoder::{PageEncodeParams, iw44_layer_chunks};
+use crate::iff::chunk_id::ChunkId;
+use crate::image::fill::{HoleFill, fill_holes};
+use crate::image::image_formats::{Bitmap, Pixmap};
+
+/// Encodes the background picture of a page.
+pub trait BackgroundCodec: Send + Sync {
+    /// ID of the chunks the background is written as, such as `BG44`.
+    fn chunk_id(&self) -> ChunkId;
+
+    /// Whether the background may take several chunks, each refining the
+    /// picture of the ones before, like IW44 does. A codec that is not
+    /// progressive returns exactly one chunk.
+    fn is_progressive(&self) -> bool;
+
+    /// Bytes written at the start of the first chunk, ahead of what
+    /// [`encode`](Self::encode) returns, given the reduced size of the
+    /// picture. [`Iw44Background`] has none, since IW44 writes its own.
+    fn header(&self, width: u32, height: u32) -> Option<Vec<u8>> {
+        let _ = (width, height);
+        None
+    }
+
+    /// Encodes `image`, which has the size of the page, reduced by
+    /// `subsample` (1-12), and returns the chunk payloads in order. `mask`
+    /// is at the reduced size and 1 where the foreground hides the picture,
+    /// so those pixels may be coded any way.
+    fn encode(
+        &self,
+        image: &Pixmap,
+        mask: Option<&Bitmap>,
+        subsample: u32,
+        params: &PageEncodeParams,
+    ) -> Result<Vec<Vec<u8>>>;
+
+    /// The picture [`encode`](Self::encode) codes for the same arguments, at
+    /// the reduced size, as [`PageEncodeParams::debug_dump`] writes it. By
+    /// default `image` reduced by `subsample`.
+    fn prepared(
+        &self,
+        image: &Pixmap,
+        mask: Option<&Bitmap>,
+        subsample: u32,
+        params: &PageEncodeParams,
+    ) -> Pixmap {
+        let _ = (mask, params);
+        image.subsample(subsample)
+    }
+}
+
+/// The IW44 wavelet codec of DjVu, writing `BG44` chunks under the IW44
+/// settings of the [`PageEncodeParams`]. The pixels the foreground hides
+/// are filled as [`PageEncodeParams::hole_fill`] says.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Iw44Background;
+
+impl BackgroundCodec for Iw44Background {
+    fn chunk_id(&self) -> ChunkId {
+        ChunkId::BG44
+    }
+
+    fn is_progressive(&self) -> bool {
+        true
+    }
+
+    fn encode(
+        &self,
+        image: &Pixmap,
+        mask: Option<&Bitmap>,
+        subsample: u32,
+        params: &PageEncodeParams,
+    ) -> Result<Vec<Vec<u8>>> {
+        match mask {
+            Some(_) if params.hole_fill != HoleFill::Wavelet => {
+                let filled = self.prepared(image, mask, subsample, params);
+                iw44_layer_chunks(&filled, None, 1, params)
+            }
+            _ => iw44_layer_chunks(image, mask, subsample, params),
+        }
+    }
+
+    /// Fills the hidden pixels unless [`HoleFill::Wavelet`] leaves them to
+    /// the wavelet coder, in which case they come out as they are.
+    fn prepared(
+        &self,
+        image: &Pixmap,
+        mask: Option<&Bitmap>,
+        subsample: u32,
+        params: &PageEncodeParams,
+    ) -> Pixmap {
+        let mut reduced = image.subsample(subsample);
+        if let Some(mask) = mask
+            && params.hole_fill != HoleFill::Wavelet
+        {
+            fill_holes(&mut reduced, mask, params.hole_fill);
+        }
+        reduced
+    }
+}
@@ -0,0 +1,455 @@
+//! Whole-directory conversion.
+//!
+//! [`encode_directory`] turns a folder of scans into one document: it expands
+//! a file-name pattern such as `scans/page*.pbm`, orders the matches the way
+//! a person would (`page2` before `page10`), loads each file, and picks JB2
+//! for black-and-white pages and IW44 for everything else.
+//! [`encode_each_file`] does the same but writes one document per input.
+//!
+//! PNM files are always supported; PNG and (multi-page) TIFF need the `png`
+//! and `tiff` features.
+
+use crate::doc::builder::{DjvuBuilder, DjvuDocument, PageBuilder};
+use crate::doc::import::classify;
+use crate::doc::profile::Profile;
+use crate::doc::recovery::ErrorRecoveryAction;
+use crate::image::classify::{ClassifyParams, PageClass, classify_page};
+use crate::image::image_formats::{Bitmap, GrayPixel, LoadedImage, load_pnm};
+use crate::utils::error::IoContext;
+use crate::{DjvuError, Result};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Settings for a batch conversion.
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// Encoder preset applied to every document.
+    pub profile: Option<Profile>,
+    /// Resolution of the scans; the global default when unset.
+    pub dpi: Option<u32>,
+    /// Encode gray or color files that only contain pure black and white
+    /// as bitonal pages (default: true).
+    pub detect_bitonal: bool,
+    /// Classify pages from their statistics with [`classify_page`] instead
+    /// of requiring exact black and white or exact gray, so noisy scans of
+    /// text still take the JB2 path and tinted grayscale scans lose their
+    /// tint. Unset by default.
+    pub classify: Option<ClassifyParams>,
+    /// What to do with pages that fail to encode.
+    pub error_recovery: ErrorRecoveryAction,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            profile: None,
+            dpi: None,
+            detect_bitonal: true,
+            classify: None,
+            error_recovery: ErrorRecoveryAction::default(),
+        }
+    }
+}
+
+impl BatchOptions {
+    fn builder(&self, total_pages: usize) -> DjvuBuilder {
+        let mut builder = DjvuBuilder::new(total_pages).with_error_recovery(self.error_recovery);
+        if let Some(dpi) = self.dpi {
+            builder = builder.with_dpi(dpi);
+        }
+        if let Some(profile) = self.profile {
+            builder = builder.with_profile(profile);
+        }
+        builder
+    }
+
+    /// Decides how a page is encoded.
+    fn page_class(&self, image: &LoadedImage) -> PageClass {
+        match &self.classify {
+            Some(params) => match classify_page(image, params) {
+                PageClass::Bitonal if !self.detect_bitonal => PageClass::Grayscale,
+                class => class,
+            },
+            None => classify(image, self.detect_bitonal),
+        }
+    }
+}
+
+/// Encodes every file matching `pattern` into one document, in natural
+/// sort order.
+///
+/// Call [`DjvuDocument::finalize`] or [`DjvuDocument::write_to`] on the
+/// result to get the bytes.
+pub fn encode_directory(pattern: &str, options: &BatchOptions) -> Result<DjvuDocument> {
+    let inputs = find_inputs(pattern)?;
+    if inputs.is_empty() {
+        return Err(DjvuError::InvalidArg(format!(
+            "No input files match {pattern:?}"
+        )));
+    }
+    let counts: Vec<_> = inputs.iter().map(|path| page_count(path)).collect();
+
+    let doc = options.builder(counts.iter().sum()).build();
+    let mut page_num = 0;
+    for (path, count) in inputs.iter().zip(counts) {
+        add_file(&doc, path, page_num, count, options)?;
+        page_num += count;
+    }
+    Ok(doc)
+}
+
+/// Encodes each file matching `pattern` into its own document, written to
+/// `out_dir` as `<file stem>.djvu`. Returns the paths written.
+///
+/// When two inputs share a stem (`scan.pbm` and `scan.png`), the later one
+/// is written as `scan-2.djvu`, and so on. A file whose pages were all
+/// skipped by [`ErrorRecoveryAction::SkipPage`] writes no document.
+pub fn encode_each_file(
+    pattern: &str,
+    out_dir: impl AsRef<Path>,
+    options: &BatchOptions,
+) -> Result<Vec<PathBuf>> {
+    let out_dir = out_dir.as_ref();
+    std::fs::create_dir_all(out_dir).context(out_dir.display())?;
+
+    let mut written = Vec::new();
+    let mut names = HashSet::new();
+    for path in find_inputs(pattern)? {
+        let count = page_count(&path);
+        let doc = options.builder(count).build();
+        add_file(&doc, &path, 0, count, options)?;
+        if doc.encode_stats()?.failures.len() == count
+            && options.error_recovery == ErrorRecoveryAction::SkipPage
+        {
+            continue;
+        }
+
+        let stem = path
+            .file_stem()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy();
+        let name = (1..)
+            .map(|n| match n {
+                1 => format!("{stem}.djvu"),
+                n => format!("{stem}-{n}.djvu"),
+            })
+            .find(|name| !names.contains(name))
+            .unwrap_or_default();
+        let out = out_dir.join(&name);
+        names.insert(name);
+        std::fs::write(&out, doc.finalize()?).context(out.display())?;
+        written.push(out);
+    }
+    Ok(written)
+}
+
+/// Adds the pages of `path` to `doc` as pages `first_page..first_page +
+/// count`. A file that fails to load, or a page that fails to build, goes
+/// through the document's error recovery like any other failed page.
+fn add_file(
+    doc: &DjvuDocument,
+    path: &Path,
+    first_page: usize,
+    count: usize,
+    options: &BatchOptions,
+) -> Result<()> {
+    let images = match load_pages(path) {
+        Ok(images) => images,
+        Err(e) => {
+            let message = e.to_string();
+            let mut error = Some(e);
+            for page_num in first_page..first_page + count {
+                let e = error
+                    .take()
+                    .unwrap_or_else(|| DjvuError::Custom(message.clone()));
+                doc.recover_failed_page(page_num, doc.neighbour_dimensions(page_num), e)?;
+            }
+            return Ok(());
+        }
+    };
+    for (page_num, image) in (first_page..).zip(images) {
+        let dimensions = image.dimensions();
+        let page =
+            page_builder(page_num, options.page_class(&image), image).and_then(PageBuilder::build);
+        match page {
+            Ok(page) => doc.add_page(page)?,
+            Err(e) => doc.recover_failed_page(page_num, dimensions, e)?,
+        }
+    }
+    Ok(())
+}
+
+/// Expands `pattern` and returns the matching files in natural order.
+///
+/// Only the file-name part may contain wildcards: `*` matches any run of
+/// characters and `?` a single one.
+pub fn find_inputs(pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern = Path::new(pattern);
+    let name = pattern
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| DjvuError::InvalidArg(format!("Invalid input pattern {pattern:?}")))?;
+    let dir = match pattern.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    let mut inputs = Vec::new();
+    for entry in std::fs::read_dir(dir).context(dir.display())? {
+        let path = entry.context(dir.display())?.path();
+        let matches = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| wildcard_match(name, n));
+        if matches && path.is_file() {
+            inputs.push(path);
+        }
+    }
+    inputs.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    Ok(inputs)
+}
+
+/// Compares strings so that runs of digits are ordered by value
+/// (`"scan9"` < `"scan10"`).
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(ca), Some(cb)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if ca.is_ascii_digit() && cb.is_ascii_digit() {
+            let split = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            let (na, ra) = a.split_at(split(a));
+            let (nb, rb) = b.split_at(split(b));
+            let (ta, tb) = (na.trim_start_matches('0'), nb.trim_start_matches('0'));
+            let ord = ta.len().cmp(&tb.len()).then_with(|| ta.cmp(tb));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            (a, b) = (ra, rb);
+        } else {
+            if ca != cb {
+                return ca.cmp(&cb);
+            }
+            (a, b) = (&a[ca.len_utf8()..], &b[cb.len_utf8()..]);
+        }
+    }
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    // Greedy match with backtracking to the last `*`.
+    let (mut pi, mut ni) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        match p.get(pi) {
+            Some('*') => {
+                star = Some((pi, ni));
+                pi += 1;
+            }
+            Some(&c) if c == '?' || c == n[ni] => {
+                pi += 1;
+                ni += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    pi = sp + 1;
+                    ni = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase()
+}
+
+/// Number of pages in `path`; a file that cannot be read counts as one
+/// page, which fails when it is loaded.
+fn page_count(path: &Path) -> usize {
+    match extension(path).as_str() {
+        #[cfg(feature = "tiff")]
+        "tif" | "tiff" => crate::image::image_formats::TiffPages::open(path)
+            .map(|pages| pages.count().max(1))
+            .unwrap_or(1),
+        _ => 1,
+    }
+}
+
+pub(crate) fn load_pages(path: &Path) -> Result<Vec<LoadedImage>> {
+    match extension(path).as_str() {
+        "pbm" | "pgm" | "ppm" | "pnm" => Ok(vec![load_pnm(path)?]),
+        #[cfg(feature = "png")]
+        "png" => Ok(vec![crate::image::image_formats::load_png(path)?]),
+        #[cfg(feature = "tiff")]
+        "tif" | "tiff" => crate::image::image_formats::TiffPages::open(path)?.collect(),
+        _ => Err(DjvuError::InvalidArg(format!(
+            "Unsupported input file {}",
+            path.display()
+        ))),
+    }
+}
+
+/// Starts a page from a loaded image of class `class`: bitonal pages become
+/// a JB2 foreground (thresholded at mid-gray), anything else an IW44
+/// background. Color images of grayscale pages are converted to gray first.
+pub(crate) fn page_builder(
+    page_num: usize,
+    class: PageClass,
+    image: LoadedImage,
+) -> Result<PageBuilder> {
+    let (width, height) = image.dimensions();
+    let builder = PageBuilder::new(page_num, width, height);
+    let bitonal = |black: &dyn Fn(u32, u32) -> bool| {
+        Bitmap::from_vec(
+            width,
+            height,
+            (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| GrayPixel::new(if black(x, y) { 0 } else { 255 }))
+                .collect(),
+        )
+    };
+
+    Ok(match (class, image) {
+        (PageClass::Bitonal, LoadedImage::Bilevel(bits)) => builder.with_foreground(
+            bitonal(&|x, y| bits.get_pixel_unchecked(x as usize, y as usize)),
+            0,
+            0,
+        ),
+        (PageClass::Bitonal, LoadedImage::Gray(gray)) => {
+            builder.with_foreground(bitonal(&|x, y| gray.get_pixel(x, y).y < 128), 0, 0)
+        }
+        (PageClass::Bitonal, LoadedImage::Rgb(rgb)) => {
+            let gray = rgb.to_bitmap();
+            builder.with_foreground(bitonal(&|x, y| gray.get_pixel(x, y).y < 128), 0, 0)
+        }
+        (PageClass::Grayscale, LoadedImage::Rgb(rgb)) => {
+            builder.with_background(LoadedImage::Gray(rgb.to_bitmap()).into_pixmap())?
+        }
+        (_, image) => builder.with_background(image.into_pixmap())?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_natural_order_and_wildcards() {
+        let mut names = vec!["scan10.pbm", "scan2.pbm", "scan1.pbm", "scan02a.pbm"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            ["scan1.pbm", "scan2.pbm", "scan02a.pbm", "scan10.pbm"]
+        );
+
+        assert!(wildcard_match("scan*.p?m", "scan12.pgm"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("scan*.pbm", "scan1.pgm"));
+        assert!(!wildcard_match("a?", "a"));
+    }
+
+    #[test]
+    fn test_encode_directory() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // A bitonal PBM, a black-and-white PGM and a color PPM.
+        std::fs::write(dir.path().join("p10.pbm"), b"P1\n2 2\n1 0\n0 1\n")?;
+        std::fs::write(dir.path().join("p2.pgm"), b"P2\n2 2\n255\n0 255\n255 0\n")?;
+        std::fs::write(
+            dir.path().join("p1.ppm"),
+            b"P3\n2 2\n255\n255 0 0  0 255 0\n0 0 255  9 9 9\n",
+        )?;
+        std::fs::write(dir.path().join("notes.txt"), b"skip me")?;
+
+        let pattern = dir.path().join("p*.p?m");
+        let pattern = pattern.to_str().unwrap();
+        let inputs = find_inputs(pattern)?;
+        let names: Vec<_> = inputs
+            .iter()
+            .map(|p| p.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["p1.ppm", "p2.pgm", "p10.pbm"]);
+
+        let doc = encode_directory(pattern, &BatchOptions::default())?;
+        assert!(doc.is_complete());
+        let data = doc.finalize()?;
+        assert_eq!(&data[12..16], b"DJVM");
+
+        let out = dir.path().join("out");
+        let written = encode_each_file(pattern, &out, &BatchOptions::default())?;
+        assert_eq!(written.len(), 3);
+        assert_eq!(written[2], out.join("p10.djvu"));
+        assert_eq!(&std::fs::read(&written[2])?[12..16], b"DJVU");
+
+        assert!(
+            encode_directory(
+                dir.path().join("*.tga").to_str().unwrap(),
+                &BatchOptions::default()
+            )
+            .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_files_and_shared_stems() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("a.pbm"), b"P1\n2 2\n1 0\n0 1\n")?;
+        std::fs::write(dir.path().join("a.pgm"), b"P2\n2 2\n255\n0 255\n255 0\n")?;
+        std::fs::write(dir.path().join("b.pgm"), b"P2\n2 2\nnot a number")?;
+        let pattern = dir.path().join("*.p?m");
+        let pattern = pattern.to_str().unwrap();
+
+        assert!(encode_directory(pattern, &BatchOptions::default()).is_err());
+        for action in [
+            ErrorRecoveryAction::SkipPage,
+            ErrorRecoveryAction::Placeholder,
+        ] {
+            let options = BatchOptions {
+                error_recovery: action,
+                ..BatchOptions::default()
+            };
+            let doc = encode_directory(pattern, &options)?;
+            assert!(doc.finalize().is_ok());
+            let failures = doc.encode_stats()?.failures;
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].page_num, 2);
+        }
+
+        let out = dir.path().join("out");
+        let options = BatchOptions {
+            error_recovery: ErrorRecoveryAction::SkipPage,
+            ..BatchOptions::default()
+        };
+        let written = encode_each_file(pattern, &out, &options)?;
+        assert_eq!(written, [out.join("a.djvu"), out.join("a-2.djvu")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_statistical_classification() {
+        let noisy = LoadedImage::Gray(Bitmap::from_vec(
+            2,
+            2,
+            [10, 250, 240, 0].map(GrayPixel::new).to_vec(),
+        ));
+        let exact = BatchOptions::default();
+        assert_eq!(exact.page_class(&noisy), PageClass::Grayscale);
+        let statistical = BatchOptions {
+            classify: Some(ClassifyParams::default()),
+            ..BatchOptions::default()
+        };
+        assert_eq!(statistical.page_class(&noisy), PageClass::Bitonal);
+        let page = page_builder(0, PageClass::Bitonal, noisy).unwrap();
+        assert!(page.build().is_ok());
+    }
+}
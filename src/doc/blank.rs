@@ -0,0 +1,199 @@
+//! Detecting blank pages.
+//!
+//! Duplex scans and double feeds leave empty sheets in a batch, and each one
+//! still costs a page of IW44 paper texture. [`ink_coverage`] paints every
+//! layer of a page into one bilevel image (background pixels count as ink
+//! when darker than a threshold), drops the specks that dust and scanner
+//! noise leave, and returns the share of the page still inked. Pages at or
+//! under [`BlankPageParams::max_coverage`] are blank; what happens to them is
+//! set with [`DjvuBuilder::with_blank_pages`].
+//!
+//! [`DjvuBuilder::with_blank_pages`]: crate::doc::DjvuBuilder::with_blank_pages
+
+use crate::doc::page_encoder::{PageComponents, PageLayer};
+use crate::encode::jb2::CCImage;
+use crate::encode::jb2::symbol_dict::BitImage;
+use crate::image::image_formats::Pixmap;
+
+/// What to do with pages found to be blank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlankPageAction {
+    /// Don't look for blank pages.
+    #[default]
+    Keep,
+    /// Encode them normally, but list them in
+    /// [`EncodeStats::blank_pages`](crate::doc::EncodeStats::blank_pages).
+    Flag,
+    /// Leave them out of the document.
+    Drop,
+    /// Replace them with an empty page of the same size, which is only a
+    /// few dozen bytes.
+    Placeholder,
+}
+
+/// Thresholds for [`ink_coverage`] and [`is_blank`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlankPageParams {
+    /// Background pixels with a luminance below this count as ink.
+    pub ink_threshold: u8,
+    /// Ink blobs with fewer pixels than this are specks and not counted.
+    pub despeckle: usize,
+    /// Pages with at most this fraction of inked pixels are blank.
+    pub max_coverage: f32,
+}
+
+impl Default for BlankPageParams {
+    fn default() -> Self {
+        Self {
+            ink_threshold: 128,
+            despeckle: 16,
+            max_coverage: 0.0005,
+        }
+    }
+}
+
+/// The fraction of the page covered by ink, after despeckling.
+pub fn ink_coverage(components: &PageComponents, params: &BlankPageParams) -> f32 {
+    let (w, h) = components.dimensions();
+    if w == 0 || h == 0 {
+        return 0.0;
+    }
+    let Ok(mut ink) = BitImage::new(w, h) else {
+        return 0.0;
+    };
+
+    for bits in [&components.mask, &components.foreground]
+        .into_iter()
+        .flatten()
+    {
+        paint_bits(&mut ink, bits, 0, 0);
+    }
+    if let (Some(shapes), Some(blits)) = (&components.jb2_shapes, &components.jb2_blits) {
+        for &(left, bottom, index) in blits {
+            if let Some(shape) = shapes.get(index) {
+                let top = h as i32 - bottom - shape.height as i32;
+                paint_bits(&mut ink, shape, left, top);
+            }
+        }
+    }
+    if let Some(background) = &components.background {
+        paint_dark(&mut ink, background, 0, 0, w, h, params.ink_threshold);
+    }
+    for layer in &components.layers {
+        match layer {
+            PageLayer::IW44Background { image, rect } => paint_dark(
+                &mut ink,
+                image,
+                rect.x,
+                rect.y,
+                rect.width,
+                rect.height,
+                params.ink_threshold,
+            ),
+            PageLayer::JB2Foreground { image, rect } | PageLayer::JB2Mask { image, rect } => {
+                paint_bits(&mut ink, image, rect.x as i32, rect.y as i32)
+            }
+        }
+    }
+
+    let mut ccs = CCImage::new(w as i32, h as i32, 300);
+    ccs.add_bitmap_runs(&ink);
+    ccs.make_ccids_by_analysis();
+    ccs.make_ccs_from_ccids();
+    let inked: usize = ccs
+        .ccs
+        .iter()
+        .map(|cc| cc.npix.max(0) as usize)
+        .filter(|&npix| npix >= params.despeckle)
+        .sum();
+    inked as f32 / (w as f32 * h as f32)
+}
+
+/// Whether the page is blank by `params`.
+pub fn is_blank(components: &PageComponents, params: &BlankPageParams) -> bool {
+    ink_coverage(components, params) <= params.max_coverage
+}
+
+/// Sets the black pixels of `bits` in `ink`, with its top-left corner at
+/// (`left`, `top`).
+fn paint_bits(ink: &mut BitImage, bits: &BitImage, left: i32, top: i32) {
+    for y in 0..bits.height {
+        let py = top + y as i32;
+        if py < 0 || py >= ink.height as i32 {
+            continue;
+        }
+        for (start, end) in bits.row_runs(y) {
+            for x in start..=end {
+                let px = left + x as i32;
+                if px >= 0 && px < ink.width as i32 {
+                    ink.set_usize(px as usize, py as usize, true);
+                }
+            }
+        }
+    }
+}
+
+/// Sets the pixels of `ink` in the given rectangle where `image`, stretched
+/// over it, is darker than `threshold`.
+fn paint_dark(
+    ink: &mut BitImage,
+    image: &Pixmap,
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+    threshold: u8,
+) {
+    let (iw, ih) = image.dimensions();
+    if iw == 0 || ih == 0 {
+        return;
+    }
+    for y in 0..height.min((ink.height as u32).saturating_sub(top)) {
+        let iy = (y as u64 * ih as u64 / height as u64) as u32;
+        for x in 0..width.min((ink.width as u32).saturating_sub(left)) {
+            let ix = (x as u64 * iw as u64 / width as u64) as u32;
+            let p = image.get_pixel(ix, iy);
+            let luma = (299 * p.r as u32 + 587 * p.g as u32 + 114 * p.b as u32) / 1000;
+            if luma < threshold as u32 {
+                ink.set_usize((left + x) as usize, (top + y) as usize, true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::image_formats::Pixel;
+
+    #[test]
+    fn test_blank_page_detection() {
+        let params = BlankPageParams::default();
+        let mut paper = Pixmap::from_pixel(200, 200, Pixel::new(240, 236, 228));
+        // Dust: a few isolated dark pixels.
+        for i in 0..20 {
+            paper.put_pixel(i * 9, i * 7, Pixel::black());
+        }
+        let page = PageComponents::new()
+            .with_background(paper.clone())
+            .unwrap();
+        assert_eq!(ink_coverage(&page, &params), 0.0);
+        assert!(is_blank(&page, &params));
+
+        // A short line of text.
+        for y in 100..108 {
+            for x in 20..180 {
+                if x % 6 < 3 {
+                    paper.put_pixel(x, y, Pixel::new(20, 20, 20));
+                }
+            }
+        }
+        let page = PageComponents::new().with_background(paper).unwrap();
+        assert!(!is_blank(&page, &params));
+
+        let mut mask = BitImage::new(200, 200).unwrap();
+        mask.fill_run(50, 10, 60);
+        let page = PageComponents::new().with_mask(mask).unwrap();
+        assert!(ink_coverage(&page, &params) > 0.001);
+    }
+}
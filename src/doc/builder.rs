@@ -28,15 +28,37 @@
 //! std::fs::write("output.djvu", djvu_bytes)?;
 //! ```
 
-use crate::annotations::{Annotations, hidden_text::HiddenText};
-use crate::doc::encoder::DocumentEncoder;
+use crate::annotations::{
+    Annotations,
+    hidden_text::{BoundingBox, HiddenText, find_text_lines},
+};
+use crate::doc::blank::{self, BlankPageAction, BlankPageParams};
+use crate::doc::checkpoint::{self, Checkpoint, Fingerprint, PageState};
+use crate::doc::djvu_dir::{DirectoryFormat, DjVmNav};
+use crate::doc::encoder::{self, DocumentEncoder, PageFiles, PageLabel};
+use crate::doc::include_file::{IncludeFile, IncludeFileBuilder};
+use crate::doc::integrity;
+use crate::doc::links::{self, PageDirectory};
+#[cfg(feature = "ocr-tesseract")]
+use crate::doc::ocr::TesseractOcr;
+use crate::doc::output::{Identity, OutputTransform};
 use crate::doc::page_collection::PageCollection;
 use crate::doc::page_encoder::PageEncodeParams;
 use crate::doc::page_encoder::{EncodedPage, PageComponents, Rect};
-use crate::encode::symbol_dict::BitImage;
+use crate::doc::profile::Profile;
+use crate::doc::recovery::{EncodeStats, ErrorRecoveryAction, PageFailure};
+use crate::encode::jb2::symbol_dict::{BitImage, SharedDict};
 use crate::image::image_formats::{Bitmap, Pixmap};
+use crate::image::palette::Palette;
+use crate::utils::error::IoContext;
+use crate::utils::log::{target, warn};
+use crate::utils::string::sanitize;
+use crate::utils::{djvu_global, memory};
 use crate::{DjvuError, Result};
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 // ============================================================================
 // Image Layers
@@ -150,7 +172,12 @@ pub struct PageBuilder {
     height: u32,
     layers: Vec<ImageLayer>,
     text_layer: Option<HiddenText>,
+    plain_text: Option<String>,
     annotations: Option<Annotations>,
+    includes: Vec<String>,
+    fg_colors: Option<Pixmap>,
+    fg_palette: Option<(Palette, Vec<u16>)>,
+    fg_palette_image: Option<(Palette, Pixmap)>,
 }
 
 impl PageBuilder {
@@ -166,7 +193,12 @@ impl PageBuilder {
             height,
             layers: Vec::new(),
             text_layer: None,
+            plain_text: None,
             annotations: None,
+            includes: Vec::new(),
+            fg_colors: None,
+            fg_palette: None,
+            fg_palette_image: None,
         }
     }
 
@@ -200,6 +232,52 @@ impl PageBuilder {
         self.add_layer(ImageLayer::mask(data, x, y))
     }
 
+    /// Colors the foreground with a page-sized picture (written as `FG44`)
+    /// instead of plain black
+    pub fn with_foreground_colors(mut self, data: Pixmap) -> Result<Self> {
+        if data.dimensions() != (self.width, self.height) {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Foreground colors size {}x{} doesn't match page size {}x{}",
+                data.width(),
+                data.height(),
+                self.width,
+                self.height
+            )));
+        }
+        self.fg_colors = Some(data);
+        Ok(self)
+    }
+
+    /// Colors the foreground from a palette (written as `FGbz`); see
+    /// [`PageComponents::with_foreground_palette`]
+    pub fn with_foreground_palette(mut self, palette: Palette, index_map: Vec<u16>) -> Self {
+        self.fg_palette = Some((palette, index_map));
+        self.fg_palette_image = None;
+        self
+    }
+
+    /// Colors the foreground from a palette (written as `FGbz`), each shape
+    /// taking the color of `image` under it; see
+    /// [`PageComponents::with_foreground_palette_image`]
+    pub fn with_foreground_palette_image(
+        mut self,
+        palette: Palette,
+        image: Pixmap,
+    ) -> Result<Self> {
+        if image.dimensions() != (self.width, self.height) {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Foreground palette image size {}x{} doesn't match page size {}x{}",
+                image.width(),
+                image.height(),
+                self.width,
+                self.height
+            )));
+        }
+        self.fg_palette_image = Some((palette, image));
+        self.fg_palette = None;
+        Ok(self)
+    }
+
     /// Returns the configured page dimensions
     pub fn dimensions(&self) -> (u32, u32) {
         (self.width, self.height)
@@ -246,6 +324,7 @@ impl PageBuilder {
     ///     .build()?;
     /// ```
     pub fn with_ocr_words(mut self, words: Vec<(String, u16, u16, u16, u16)>) -> Self {
+        self.plain_text = None;
         self.text_layer = Some(HiddenText::from_word_boxes(
             self.width as u16,
             self.height as u16,
@@ -256,10 +335,23 @@ impl PageBuilder {
 
     /// Adds a custom HiddenText layer (for advanced hierarchical text structures)
     pub fn with_hidden_text(mut self, text: HiddenText) -> Self {
+        self.plain_text = None;
         self.text_layer = Some(text);
         self
     }
 
+    /// Adds a hidden text layer from text without coordinates, paragraphs
+    /// separated by blank lines, so the page is at least searchable
+    ///
+    /// When the page is built, the words are set on the text lines found on
+    /// its foreground and mask layers, or spread evenly over the page if it
+    /// has none; see [`HiddenText::from_plain_text_on_lines`].
+    pub fn with_plain_text(mut self, text: impl Into<String>) -> Self {
+        self.text_layer = None;
+        self.plain_text = Some(text.into());
+        self
+    }
+
     /// Adds a rectangular hyperlink to the page
     ///
     /// # Arguments
@@ -305,6 +397,15 @@ impl PageBuilder {
         self
     }
 
+    /// References a shared include file by its directory ID
+    ///
+    /// The file itself is added once per document with
+    /// [`DjvuDocument::add_include_file`].
+    pub fn with_include(mut self, id: impl Into<String>) -> Self {
+        self.includes.push(id.into());
+        self
+    }
+
     /// Consumes the builder and returns the constructed page
     pub fn build(self) -> Result<Page> {
         if self.layers.is_empty() {
@@ -323,15 +424,51 @@ impl PageBuilder {
             }
         }
 
+        let text_layer = match &self.plain_text {
+            Some(text) => Some(self.place_plain_text(text)?),
+            None => self.text_layer,
+        };
+
         Ok(Page {
             page_num: self.page_num,
             width: self.width,
             height: self.height,
             layers: self.layers,
-            text_layer: self.text_layer,
+            text_layer,
             annotations: self.annotations,
+            includes: self.includes,
+            fg_colors: self.fg_colors,
+            fg_palette: self.fg_palette,
+            fg_palette_image: self.fg_palette_image,
         })
     }
+
+    /// Lays `text` out on the text lines of the bilevel layers.
+    fn place_plain_text(&self, text: &str) -> Result<HiddenText> {
+        let mut lines = Vec::new();
+        for layer in &self.layers {
+            if let LayerData::Foreground(bitmap) | LayerData::Mask(bitmap) = &layer.data {
+                // Line boxes are relative to the layer, bottom-up.
+                let bottom = self.height - (layer.y + layer.height);
+                lines.extend(
+                    find_text_lines(&bitmap_to_bitimage(bitmap)?)
+                        .into_iter()
+                        .map(|line| BoundingBox {
+                            x: line.x + layer.x as u16,
+                            y: line.y + bottom as u16,
+                            ..line
+                        }),
+                );
+            }
+        }
+        lines.sort_by_key(|line| (std::cmp::Reverse(line.ymax()), line.x));
+        Ok(HiddenText::from_plain_text_on_lines(
+            self.width as u16,
+            self.height as u16,
+            text,
+            &lines,
+        ))
+    }
 }
 
 /// A fully constructed page ready for encoding
@@ -343,6 +480,10 @@ pub struct Page {
     layers: Vec<ImageLayer>,
     text_layer: Option<HiddenText>,
     annotations: Option<Annotations>,
+    includes: Vec<String>,
+    fg_colors: Option<Pixmap>,
+    fg_palette: Option<(Palette, Vec<u16>)>,
+    fg_palette_image: Option<(Palette, Pixmap)>,
 }
 
 impl Page {
@@ -388,11 +529,32 @@ impl Page {
         if let Some(ref annot) = self.annotations {
             components.annotations = Some(annot.clone());
         }
+        components.includes = self.includes.clone();
+        if let Some(ref colors) = self.fg_colors {
+            components = components.with_foreground_colors(colors.clone())?;
+        }
+        if let Some((palette, index_map)) = &self.fg_palette {
+            components = components.with_foreground_palette(palette.clone(), index_map.clone())?;
+        }
+        if let Some((palette, image)) = &self.fg_palette_image {
+            components =
+                components.with_foreground_palette_image(palette.clone(), image.clone())?;
+        }
 
         Ok(components)
     }
 }
 
+/// Helper: DIRM strings are NUL-terminated, so they cannot contain NUL
+fn check_dir_string(what: &str, value: &str) -> Result<()> {
+    if value.contains('\0') {
+        return Err(DjvuError::InvalidArg(format!(
+            "{what} must not contain NUL characters"
+        )));
+    }
+    Ok(())
+}
+
 /// Helper: convert Bitmap to BitImage
 fn bitmap_to_bitimage(bitmap: &Bitmap) -> Result<BitImage> {
     let (width, height) = bitmap.dimensions();
@@ -411,6 +573,69 @@ fn bitmap_to_bitimage(bitmap: &Bitmap) -> Result<BitImage> {
     Ok(bit_image)
 }
 
+/// How pages without an explicit ID are named in a multi-page document's
+/// directory
+///
+/// IDs are what links and bookmarks (`#id`) point at, and the file names of
+/// indirect output. Numbered IDs shift when a page is inserted in front;
+/// [`DjvuDocument::add_page_from_file`] or a formatter keyed on something
+/// stable keeps them fixed.
+#[derive(Clone, Default)]
+pub enum PageIdScheme {
+    /// `p0001.djvu`, `p0002.djvu`, ... (the default)
+    #[default]
+    Numbered,
+    /// Called with the page's position in the finished document, from 0.
+    /// Must return distinct, non-empty IDs.
+    Custom(Arc<dyn Fn(usize) -> String + Send + Sync>),
+}
+
+impl PageIdScheme {
+    /// A scheme that names page `i` by `format(i)`.
+    pub fn custom(format: impl Fn(usize) -> String + Send + Sync + 'static) -> Self {
+        Self::Custom(Arc::new(format))
+    }
+
+    /// The ID of the page at `index`.
+    pub fn id(&self, index: usize) -> String {
+        match self {
+            Self::Numbered => format!("p{:04}.djvu", index + 1),
+            Self::Custom(format) => format(index),
+        }
+    }
+}
+
+impl std::fmt::Debug for PageIdScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Numbered => f.write_str("Numbered"),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// A page ID made from the name of the file a page came from:
+/// `scans/Chapter 1 - 003.tif` becomes `Chapter_1_-_003.djvu`.
+///
+/// Letters, digits, `-`, `_` and `.` are kept (non-ASCII letters included);
+/// anything else becomes `_`. Returns `None` for paths without a file name.
+pub fn page_id_from_path(path: impl AsRef<Path>) -> Option<String> {
+    let stem = path.as_ref().file_stem()?.to_string_lossy();
+    let stem = sanitize(&stem);
+    let id: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let id = id.trim_matches('.');
+    (!id.is_empty()).then(|| format!("{id}.djvu"))
+}
+
 // ============================================================================
 // Document Builder
 // ============================================================================
@@ -423,28 +648,80 @@ pub struct DjvuBuilder {
     params: PageEncodeParams,
     dpi: u32,
     gamma: Option<f32>,
+    integrity_checksums: bool,
+    output_transform: Arc<dyn OutputTransform>,
+    directory_format: DirectoryFormat,
+    error_recovery: ErrorRecoveryAction,
+    blank_pages: BlankPageAction,
+    blank_params: BlankPageParams,
+    validate_pages: bool,
+    canonical_links: bool,
+    page_ids: PageIdScheme,
+    symbol_dict: Option<(Arc<SharedDict>, IncludeFile)>,
+    #[cfg(feature = "ocr-tesseract")]
+    ocr: Option<TesseractOcr>,
 }
 
+/// Directory ID of the include file holding the dictionary set with
+/// [`DjvuBuilder::with_symbol_dictionary`].
+pub const SYMBOL_DICT_ID: &str = "symbols.iff";
+
 impl DjvuBuilder {
     /// Creates a new document builder
     ///
     /// # Arguments
     /// * `total_pages` - Total number of pages (numbered 0..total_pages-1)
+    ///
+    /// DPI and gamma start from the process-wide defaults in
+    /// [`djvu_global::config`](crate::utils::djvu_global::config).
     pub fn new(total_pages: usize) -> Self {
+        let global = djvu_global::config();
+        let params = PageEncodeParams {
+            dpi: global.default_dpi(),
+            ..PageEncodeParams::default()
+        };
         Self {
             collection: Arc::new(PageCollection::new(total_pages)),
-            params: PageEncodeParams::default(),
-            dpi: 300,
-            gamma: Some(2.2),
+            params,
+            dpi: global.default_dpi(),
+            gamma: Some(global.default_gamma()),
+            integrity_checksums: false,
+            output_transform: Arc::new(Identity),
+            directory_format: DirectoryFormat::default(),
+            error_recovery: ErrorRecoveryAction::default(),
+            blank_pages: BlankPageAction::default(),
+            blank_params: BlankPageParams::default(),
+            validate_pages: true,
+            page_ids: PageIdScheme::default(),
+            canonical_links: false,
+            symbol_dict: None,
+            #[cfg(feature = "ocr-tesseract")]
+            ocr: None,
         }
     }
 
+    /// Sets the number of pages, for when it is only known once the input
+    /// has been read
+    pub fn with_total_pages(mut self, total_pages: usize) -> Self {
+        self.collection = Arc::new(PageCollection::new(total_pages));
+        self
+    }
+
     /// Sets encoding parameters
     pub fn with_params(mut self, params: PageEncodeParams) -> Self {
         self.params = params;
         self
     }
 
+    /// Applies an encoder preset (see [`Profile`])
+    ///
+    /// Only the settings the profile covers are changed, so call this before
+    /// fine-tuning individual parameters.
+    pub fn with_profile(mut self, profile: Profile) -> Self {
+        profile.apply(&mut self.params);
+        self
+    }
+
     /// Sets DPI (dots per inch)
     pub fn with_dpi(mut self, dpi: u32) -> Self {
         self.dpi = dpi;
@@ -477,17 +754,147 @@ impl DjvuBuilder {
         self
     }
 
+    /// Appends a CRC-32 checksum chunk (`CIDa`) to every page
+    ///
+    /// Use [`integrity::verify_document`] to check the finished file.
+    pub fn with_integrity_checksums(mut self, enabled: bool) -> Self {
+        self.integrity_checksums = enabled;
+        self
+    }
+
+    /// Sets the transform applied by [`DjvuDocument::write_to`]
+    /// (compression, encryption; see [`crate::doc::output`])
+    pub fn with_output_transform(mut self, transform: impl OutputTransform + 'static) -> Self {
+        self.output_transform = Arc::new(transform);
+        self
+    }
+
+    /// Selects the directory chunk written for multi-page documents
+    ///
+    /// The default `DIRM` version 1 is what every current viewer expects;
+    /// the older layouts are for compatibility with legacy readers only.
+    pub fn with_directory_format(mut self, format: DirectoryFormat) -> Self {
+        self.directory_format = format;
+        self
+    }
+
+    /// Sets what [`DjvuDocument::add_page`] does when a page fails to encode
+    ///
+    /// Recovered failures are listed in [`DjvuDocument::encode_stats`].
+    pub fn with_error_recovery(mut self, action: ErrorRecoveryAction) -> Self {
+        self.error_recovery = action;
+        self
+    }
+
+    /// Sets what [`DjvuDocument::add_page`] does with pages that
+    /// [`blank::is_blank`] finds blank by `params` (kept, unchecked, by
+    /// default)
+    ///
+    /// Blank pages are listed in [`EncodeStats::blank_pages`].
+    pub fn with_blank_pages(mut self, action: BlankPageAction, params: BlankPageParams) -> Self {
+        self.blank_pages = action;
+        self.blank_params = params;
+        self
+    }
+
+    /// Sets whether pages are checked with [`PageComponents::validate`]
+    /// before encoding (on by default)
+    ///
+    /// Turning it off saves a pass over each page's layers when the input is
+    /// known to be well-formed.
+    pub fn with_validation(mut self, enabled: bool) -> Self {
+        self.validate_pages = enabled;
+        self
+    }
+
+    /// Sets whether links that name a page by its title are rewritten to
+    /// use the page's ID when the document is assembled (off by default)
+    ///
+    /// Links are checked either way; see [`EncodeStats::broken_links`].
+    pub fn with_canonical_links(mut self, enabled: bool) -> Self {
+        self.canonical_links = enabled;
+        self
+    }
+
+    /// Sets how pages added without an ID are named (see [`PageIdScheme`])
+    pub fn with_page_ids(mut self, scheme: PageIdScheme) -> Self {
+        self.page_ids = scheme;
+        self
+    }
+
+    /// Seeds the document with a JB2 shape dictionary, such as one saved
+    /// from an earlier document in the same typeface with
+    /// [`SharedDict::save`]
+    ///
+    /// The dictionary is stored once, as include file [`SYMBOL_DICT_ID`].
+    /// Every page with a foreground or mask and no JB2 shapes of its own
+    /// blits the shapes it finds there from the dictionary instead of
+    /// coding them again; [`EncodeStats::symbols`] tells how many did.
+    pub fn with_symbol_dictionary(mut self, dict: SharedDict) -> Result<Self> {
+        let file = IncludeFileBuilder::new(SYMBOL_DICT_ID)
+            .with_shared_dict(&dict)?
+            .build()?;
+        self.symbol_dict = Some((Arc::new(dict), file));
+        Ok(self)
+    }
+
+    /// Runs OCR on every page added without a text layer and stores what it
+    /// reads as the page's hidden text (see [`crate::doc::ocr`])
+    ///
+    /// A page whose OCR fails is encoded without text, with a warning.
+    #[cfg(feature = "ocr-tesseract")]
+    pub fn with_ocr(mut self, ocr: TesseractOcr) -> Self {
+        self.ocr = Some(ocr);
+        self
+    }
+
     /// Consumes the builder and returns the document
     pub fn build(self) -> DjvuDocument {
+        let labels = Mutex::new(vec![PageLabel::default(); self.collection.len()]);
+        let (symbol_dict, includes) = match self.symbol_dict {
+            Some((dict, file)) => (Some(dict), vec![file]),
+            None => (None, Vec::new()),
+        };
         DjvuDocument {
+            labels,
+            page_annotations: Mutex::new(vec![None; self.collection.len()]),
+            includes: Mutex::new(includes),
+            symbol_dict,
+            navigation: Mutex::new(None),
+            deferred: Mutex::new(BTreeMap::new()),
             collection: self.collection,
             params: self.params,
             dpi: self.dpi,
             gamma: self.gamma,
+            integrity_checksums: self.integrity_checksums,
+            output_transform: self.output_transform,
+            directory_format: self.directory_format,
+            error_recovery: self.error_recovery,
+            blank_pages: self.blank_pages,
+            blank_params: self.blank_params,
+            validate_pages: self.validate_pages,
+            canonical_links: self.canonical_links,
+            page_ids: self.page_ids,
+            #[cfg(feature = "ocr-tesseract")]
+            ocr: self.ocr,
+            stats: Mutex::new(EncodeStats::default()),
+            checkpoint_log: Mutex::new(None),
         }
     }
 }
 
+/// Makes the components of a page added with
+/// [`DjvuDocument::add_page_lazy`], each time the document is written
+type PageSource = Arc<dyn Fn() -> Result<PageComponents> + Send + Sync>;
+
+/// A page added with [`DjvuDocument::add_page_lazy`]
+struct DeferredPage {
+    source: PageSource,
+    /// Length of the encoded page without its `AT&T` prefix, once it has
+    /// been measured
+    len: Option<usize>,
+}
+
 /// A DjVu document under construction
 ///
 /// Thread-safe, supports out-of-order page insertion.
@@ -496,6 +903,108 @@ pub struct DjvuDocument {
     params: PageEncodeParams,
     dpi: u32,
     gamma: Option<f32>,
+    integrity_checksums: bool,
+    output_transform: Arc<dyn OutputTransform>,
+    directory_format: DirectoryFormat,
+    labels: Mutex<Vec<PageLabel>>,
+    /// Annotations of pages with page links, checked by `finalize`
+    page_annotations: Mutex<Vec<Option<Annotations>>>,
+    includes: Mutex<Vec<IncludeFile>>,
+    /// Dictionary pages blit their shapes from, in include file
+    /// [`SYMBOL_DICT_ID`]
+    symbol_dict: Option<Arc<SharedDict>>,
+    navigation: Mutex<Option<DjVmNav>>,
+    /// Pages added with [`DjvuDocument::add_page_lazy`], by page number
+    deferred: Mutex<BTreeMap<usize, DeferredPage>>,
+    error_recovery: ErrorRecoveryAction,
+    blank_pages: BlankPageAction,
+    blank_params: BlankPageParams,
+    validate_pages: bool,
+    canonical_links: bool,
+    page_ids: PageIdScheme,
+    #[cfg(feature = "ocr-tesseract")]
+    ocr: Option<TesseractOcr>,
+    stats: Mutex<EncodeStats>,
+    /// What the last [`DjvuDocument::save_checkpoint`] wrote, so the next
+    /// one only appends what changed since
+    checkpoint_log: Mutex<Option<CheckpointLog>>,
+}
+
+/// The contents of a checkpoint file, as far as this document wrote it.
+struct CheckpointLog {
+    path: PathBuf,
+    saved_pages: Vec<bool>,
+    labels: Vec<PageLabel>,
+    includes: usize,
+}
+
+/// What a complete [`DjvuDocument`] is assembled from.
+struct FinishedParts<'a> {
+    pages: StoredPages<'a>,
+    labels: Vec<PageLabel>,
+    includes: Vec<IncludeFile>,
+    navigation: Option<DjVmNav>,
+}
+
+/// The pages of a complete [`DjvuDocument`], read from its page collection
+/// one at a time as they are written.
+struct StoredPages<'a> {
+    doc: &'a DjvuDocument,
+    /// Page numbers of the pages not skipped, in order
+    page_nums: Vec<usize>,
+    /// Link targets to rewrite on each page, indexed like `page_nums`
+    rewrites: Vec<Vec<(String, String)>>,
+}
+
+impl PageFiles for StoredPages<'_> {
+    fn count(&self) -> usize {
+        self.page_nums.len()
+    }
+
+    /// Takes the length of a deferred page from when it was measured,
+    /// unless its links are rewritten
+    fn size(&mut self, index: usize) -> Result<usize> {
+        let page_num = self.page_nums[index];
+        let measured = self
+            .doc
+            .lock_deferred()?
+            .get(&page_num)
+            .and_then(|page| page.len);
+        match measured {
+            Some(len) if self.rewrites[index].is_empty() => Ok(len),
+            _ => {
+                let mut size = 0;
+                self.with_page(index, &mut |page| {
+                    size = encoder::strip(page).len();
+                    Ok(())
+                })?;
+                Ok(size)
+            }
+        }
+    }
+
+    fn with_page(&mut self, index: usize, f: &mut dyn FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        let page_num = self.page_nums[index];
+        let data = match self.doc.encode_deferred(page_num)? {
+            Some(data) => data,
+            None => self.doc.collection.get_page(page_num)?.ok_or_else(|| {
+                DjvuError::InvalidOperation(format!("Page {page_num} is not ready"))
+            })?,
+        };
+        let rewrites = &self.rewrites[index];
+        if rewrites.is_empty() {
+            return f(&data);
+        }
+        let retarget = |url: &str| {
+            let new = rewrites.iter().find(|(old, _)| old == url);
+            Some(new.map_or(url, |(_, new)| new).to_string())
+        };
+        f(&links::retarget_links(
+            &data,
+            retarget,
+            &self.doc.params.bzz,
+        )?)
+    }
 }
 
 impl DjvuDocument {
@@ -519,23 +1028,95 @@ impl DjvuDocument {
         self.collection.is_complete()
     }
 
+    /// Approximate bytes currently held by encoded pages in memory
+    pub fn memory_used(&self) -> usize {
+        self.collection.memory().used()
+    }
+
+    /// Number of encoded pages moved to the temporary spill file
+    pub fn spilled_pages(&self) -> usize {
+        self.collection.spilled_count()
+    }
+
     /// Encode a page into its compressed byte representation.
     ///
     /// CPU-heavy (runs IW44 / JB2). Touches no shared mutable state, so it is
     /// safe to call from a worker thread or rayon iterator. Pair with
     /// [`Self::add_encoded_page`] to insert the result into the document.
     pub fn encode_page(&self, page: Page) -> Result<EncodedPage> {
-        let page_num = page.page_number();
-        let components = page.to_components()?;
-        EncodedPage::from_components(page_num, components, &self.params, self.dpi, self.gamma)
+        self.encode_components(page.page_number(), page.to_components()?)
+    }
+
+    /// Encode a page given as [`PageComponents`], with the document's
+    /// settings. Like [`Self::encode_page`], safe to call from worker threads.
+    pub fn encode_components(
+        &self,
+        page_num: usize,
+        components: PageComponents,
+    ) -> Result<EncodedPage> {
+        // Account for the codec working set while this page is in flight.
+        let (w, h) = components.dimensions();
+        let _working_set = self.collection.memory().scoped(
+            memory::iw44_encoder_bytes(w, h, self.params.color) + memory::jb2_encoder_bytes(w, h),
+        );
+        if self.validate_pages {
+            components.validate(components.dpi.unwrap_or(self.dpi))?;
+        }
+        #[cfg(feature = "ocr-tesseract")]
+        let components = self.recognize_text(components);
+        let components = self.with_symbol_dict(components);
+        let mut encoded =
+            EncodedPage::from_components(page_num, components, &self.params, self.dpi, self.gamma)?;
+        if self.integrity_checksums {
+            integrity::append_checksum(Arc::make_mut(&mut encoded.data))?;
+        }
+        Ok(encoded)
+    }
+
+    /// Lets a page that extracts its JB2 shapes use the document's symbol
+    /// dictionary
+    fn with_symbol_dict(&self, mut components: PageComponents) -> PageComponents {
+        if let Some(dict) = &self.symbol_dict
+            && components.shared_dict.is_none()
+            && components.jb2_shapes.is_none()
+            && (components.foreground.is_some() || components.mask.is_some())
+        {
+            components.shared_dict = Some(dict.clone());
+            if !components.includes.iter().any(|id| id == SYMBOL_DICT_ID) {
+                components.includes.push(SYMBOL_DICT_ID.to_string());
+            }
+        }
+        components
+    }
+
+    /// Gives a page without hidden text the text OCR finds on it
+    #[cfg(feature = "ocr-tesseract")]
+    fn recognize_text(&self, mut components: PageComponents) -> PageComponents {
+        if let Some(ocr) = &self.ocr
+            && components.text_layer.is_none()
+        {
+            match ocr.recognize(&components, components.dpi.unwrap_or(self.dpi)) {
+                Ok(text) => components.text_layer = Some(text),
+                Err(e) => warn!(target: target::DOC, "OCR failed, page left without text: {e}"),
+            }
+        }
+        components
     }
 
     /// Insert an already-encoded page into the document (thread-safe, out-of-order).
     ///
     /// Cheap. The expensive work belongs in [`Self::encode_page`].
-    pub fn add_encoded_page(&self, encoded: EncodedPage) -> Result<()> {
+    pub fn add_encoded_page(&self, mut encoded: EncodedPage) -> Result<()> {
         let page_num = encoded.page_num;
-        self.collection.insert_page(page_num, encoded)
+        self.check_not_deferred(page_num)?;
+        let annotations = encoded.annotations.take();
+        let symbols = encoded.symbols;
+        self.collection.insert_page(page_num, encoded)?;
+        self.lock_stats()?.symbols += symbols;
+        if let Some(slot) = self.lock_page_annotations()?.get_mut(page_num) {
+            *slot = annotations;
+        }
+        Ok(())
     }
 
     /// Add a page (thread-safe, out-of-order).
@@ -544,13 +1125,762 @@ impl DjvuDocument {
     /// [`Self::add_encoded_page`]. For parallel encoding pipelines, call those
     /// two directly so the encode runs off-thread and only the cheap insert
     /// runs on the assembler.
+    ///
+    /// A page that fails to encode is handled by the configured
+    /// [`ErrorRecoveryAction`], and blank pages by the configured
+    /// [`BlankPageAction`].
     pub fn add_page(&self, page: Page) -> Result<()> {
-        let encoded = self.encode_page(page)?;
-        self.add_encoded_page(encoded)
+        let page_num = page.page_number();
+        let dimensions = page.dimensions();
+        self.check_not_deferred(page_num)?;
+        match page.to_components() {
+            Ok(components) => self.add_components(page_num, components),
+            Err(e) => self.recover_failed_page(page_num, dimensions, e),
+        }
+    }
+
+    /// Add a page whose components are only made when the document is
+    /// written
+    ///
+    /// `source` runs in [`Self::finalize`], [`Self::write_to`] or
+    /// [`Self::write_indirect_zip`], and each deferred page is encoded
+    /// before the source of the next one runs, so only one page's pictures
+    /// are loaded at a time. Setting up a large batch then costs a closure
+    /// per page instead of its scans. The page is not ready until then.
+    ///
+    /// The directory at the start of a document gives the size of every
+    /// page, so the source runs twice: once to measure the page before the
+    /// directory is written, and again to encode it as it is written. The
+    /// encoded page is never kept. The source must give the same page each
+    /// time; writing fails if the page comes out a different size. Writing
+    /// the document again runs it once more.
+    ///
+    /// The error recovery and blank page policies apply as for
+    /// [`Self::add_components`] when the page is measured. A placeholder for
+    /// a page whose source fails takes the size of the nearest page before
+    /// it, or after it.
+    pub fn add_page_lazy(
+        &self,
+        page_num: usize,
+        source: impl Fn() -> Result<PageComponents> + Send + Sync + 'static,
+    ) -> Result<()> {
+        if page_num >= self.total_pages() {
+            return Err(DjvuError::InvalidArg(format!(
+                "Page number {page_num} out of range (total {})",
+                self.total_pages()
+            )));
+        }
+        let mut deferred = self.lock_deferred()?;
+        if self.is_page_ready(page_num) || deferred.contains_key(&page_num) {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Page {page_num} already exists"
+            )));
+        }
+        deferred.insert(
+            page_num,
+            DeferredPage {
+                source: Arc::new(source),
+                len: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Loads and encodes the pages added with [`Self::add_page_lazy`] that
+    /// have not been measured yet, in page order, one at a time, keeping
+    /// only their length
+    ///
+    /// Pages not reached when a page fails are left for the next call.
+    fn measure_deferred(&self) -> Result<()> {
+        loop {
+            let next = {
+                let mut deferred = self.lock_deferred()?;
+                let page_num = deferred
+                    .iter()
+                    .find_map(|(&page_num, page)| page.len.is_none().then_some(page_num));
+                page_num.and_then(|page_num| deferred.remove_entry(&page_num))
+            };
+            let Some((page_num, page)) = next else {
+                return Ok(());
+            };
+            match (page.source)() {
+                Ok(components) => self.place_components(page_num, components, Some(page.source))?,
+                Err(e) => {
+                    self.recover_failed_page(page_num, self.neighbour_dimensions(page_num), e)?
+                }
+            }
+        }
+    }
+
+    /// Encodes a measured page added with [`Self::add_page_lazy`] again, to
+    /// be written, or `None` for any other page
+    fn encode_deferred(&self, page_num: usize) -> Result<Option<Arc<Vec<u8>>>> {
+        let Some((source, len)) = self
+            .lock_deferred()?
+            .get(&page_num)
+            .map(|page| (Arc::clone(&page.source), page.len))
+        else {
+            return Ok(None);
+        };
+        let encoded = self.encode_components(page_num, source()?)?;
+        let encoded_len = encoder::strip(&encoded.data).len();
+        if Some(encoded_len) != len {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Page {page_num} came out {encoded_len} bytes long, not the {} bytes it \
+                 measured; its source must give the same page each time",
+                len.unwrap_or_default()
+            )));
+        }
+        Ok(Some(encoded.data))
+    }
+
+    /// Records a page encoded from `source` by its length alone, for
+    /// [`Self::encode_deferred`] to encode again as it is written
+    fn add_measured_page(&self, mut encoded: EncodedPage, source: PageSource) -> Result<()> {
+        let page_num = encoded.page_num;
+        let annotations = encoded.annotations.take();
+        self.collection
+            .insert_deferred(page_num, encoded.width, encoded.height)?;
+        self.lock_deferred()?.insert(
+            page_num,
+            DeferredPage {
+                source,
+                len: Some(encoder::strip(&encoded.data).len()),
+            },
+        );
+        self.lock_stats()?.symbols += encoded.symbols;
+        if let Some(slot) = self.lock_page_annotations()?.get_mut(page_num) {
+            *slot = annotations;
+        }
+        Ok(())
+    }
+
+    /// Size of the nearest page already added, for a placeholder of a page
+    /// whose own size is unknown because it failed to load
+    pub(crate) fn neighbour_dimensions(&self, page_num: usize) -> (u32, u32) {
+        (0..page_num)
+            .rev()
+            .chain(page_num + 1..self.total_pages())
+            .find_map(|i| self.collection.get_metadata(i))
+            .unwrap_or((0, 0))
+    }
+
+    /// Fails if `page_num` waits in [`Self::add_page_lazy`], so that a page
+    /// is not added twice.
+    fn check_not_deferred(&self, page_num: usize) -> Result<()> {
+        if self.lock_deferred()?.contains_key(&page_num) {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Page {page_num} already exists"
+            )));
+        }
+        Ok(())
+    }
+
+    fn lock_deferred(&self) -> Result<std::sync::MutexGuard<'_, BTreeMap<usize, DeferredPage>>> {
+        self.deferred
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Deferred page lock poisoned".to_string()))
+    }
+
+    /// Encode and insert a page given as [`PageComponents`], applying the
+    /// error recovery and blank page policies like [`Self::add_page`]
+    pub fn add_components(&self, page_num: usize, components: PageComponents) -> Result<()> {
+        self.check_not_deferred(page_num)?;
+        self.place_components(page_num, components, None)
+    }
+
+    /// [`Self::add_components`], keeping only the length of the encoded
+    /// page when it comes from the `source` of a deferred page
+    fn place_components(
+        &self,
+        page_num: usize,
+        components: PageComponents,
+        source: Option<PageSource>,
+    ) -> Result<()> {
+        let dimensions = components.dimensions();
+        if self.blank_pages != BlankPageAction::Keep
+            && blank::is_blank(&components, &self.blank_params)
+        {
+            self.lock_stats()?.blank_pages.push(page_num);
+            match self.blank_pages {
+                BlankPageAction::Drop => return self.collection.skip_page(page_num),
+                BlankPageAction::Placeholder => {
+                    return self.add_encoded_page(self.placeholder_page(page_num, dimensions)?);
+                }
+                BlankPageAction::Keep | BlankPageAction::Flag => {}
+            }
+        }
+        match self.encode_components(page_num, components) {
+            Ok(encoded) => {
+                match source {
+                    Some(source) => self.add_measured_page(encoded, source)?,
+                    None => self.add_encoded_page(encoded)?,
+                }
+                self.lock_stats()?.pages_encoded += 1;
+                Ok(())
+            }
+            Err(e) => self.recover_failed_page(page_num, dimensions, e),
+        }
+    }
+
+    /// An empty page of the given size
+    fn placeholder_page(&self, page_num: usize, (w, h): (u32, u32)) -> Result<EncodedPage> {
+        EncodedPage::from_components(
+            page_num,
+            PageComponents::new_with_dimensions(w, h),
+            &self.params,
+            self.dpi,
+            self.gamma,
+        )
+    }
+
+    /// Apply the error recovery policy to a page whose encoding failed
+    ///
+    /// [`Self::add_page`] calls this itself; pipelines that run
+    /// [`Self::encode_page`] on worker threads call it with the error instead
+    /// of aborting. `dimensions` sizes the placeholder page. With
+    /// [`ErrorRecoveryAction::Abort`] the error is returned unchanged.
+    pub fn recover_failed_page(
+        &self,
+        page_num: usize,
+        dimensions: (u32, u32),
+        error: DjvuError,
+    ) -> Result<()> {
+        match self.error_recovery {
+            ErrorRecoveryAction::Abort => return Err(error),
+            ErrorRecoveryAction::SkipPage => self.collection.skip_page(page_num)?,
+            ErrorRecoveryAction::Placeholder => {
+                self.add_encoded_page(self.placeholder_page(page_num, dimensions)?)?;
+            }
+        }
+        warn!(
+            target: target::DOC,
+            "Page {} failed to encode ({:?}): {}", page_num, self.error_recovery, error
+        );
+        self.lock_stats()?.failures.push(PageFailure {
+            page_num,
+            action: self.error_recovery,
+            error: error.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Pages encoded so far and the failures that were recovered from
+    pub fn encode_stats(&self) -> Result<EncodeStats> {
+        Ok(self.lock_stats()?.clone())
+    }
+
+    fn lock_stats(&self) -> Result<std::sync::MutexGuard<'_, EncodeStats>> {
+        self.stats
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Encode stats lock poisoned".to_string()))
+    }
+
+    /// Add a page under a custom directory ID and title.
+    ///
+    /// The ID replaces the default `pNNNN.djvu` and is what links and
+    /// bookmarks (`#id`) refer to; the title is the label viewers show for the
+    /// page. Both are stored in the `DIRM` of multi-page documents.
+    ///
+    /// Fails if the ID is taken by another page or an include file, or reads
+    /// as a page number in links (`12`, `+1`), or if a link on the page can
+    /// never resolve (an empty `#`, or a page number past the end of the
+    /// document). Links to IDs and titles are checked when the document is
+    /// assembled, once all pages are in.
+    pub fn add_page_with_id(
+        &self,
+        page: Page,
+        id: impl Into<String>,
+        title: impl Into<String>,
+    ) -> Result<()> {
+        let index = page.page_number();
+        let id = id.into();
+        let title = sanitize(&title.into()).into_owned();
+        check_dir_string("Page ID", &id)?;
+        check_dir_string("Page title", &title)?;
+        if id.is_empty() {
+            return Err(DjvuError::InvalidArg(
+                "Page ID must not be empty".to_string(),
+            ));
+        }
+        if links::page_number(&id, index, self.total_pages()).is_some() {
+            return Err(DjvuError::InvalidArg(format!(
+                "Page ID '{id}' would be read as a page number in links"
+            )));
+        }
+        let hyperlinks = page.annotations.iter().flat_map(|a| &a.hyperlinks);
+        for target in hyperlinks.filter_map(|link| link.page_target()) {
+            let page_number = links::page_number(target, index, self.total_pages());
+            if target.is_empty() || page_number == Some(None) {
+                return Err(DjvuError::InvalidArg(format!(
+                    "Link to '#{target}' on page {index} leads outside the document"
+                )));
+            }
+        }
+        let included = self
+            .includes
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Include list lock poisoned".to_string()))?
+            .iter()
+            .any(|file| file.id() == id);
+        if included {
+            return Err(DjvuError::InvalidArg(format!(
+                "Page ID '{id}' is already used by an include file"
+            )));
+        }
+        {
+            let labels = self.lock_labels()?;
+            if index >= labels.len() {
+                return Err(DjvuError::InvalidArg(format!(
+                    "Page number {} out of range (total {})",
+                    index,
+                    labels.len()
+                )));
+            }
+            let skipped = self.collection.skipped_pages();
+            let taken = self
+                .resolve_page_ids(&labels, &skipped)
+                .iter()
+                .enumerate()
+                .any(|(i, other)| i != index && other.as_deref() == Some(id.as_str()));
+            if taken {
+                return Err(DjvuError::InvalidArg(format!(
+                    "Page ID '{id}' is already used by another page"
+                )));
+            }
+        }
+
+        self.add_page(page)?;
+        let mut labels = self.lock_labels()?;
+        labels[index].id = Some(id);
+        labels[index].title = (!title.is_empty()).then_some(title);
+        Ok(())
+    }
+
+    /// Add a page under an ID made from the name of the file it came from
+    /// (see [`page_id_from_path`]), so the ID survives pages being inserted
+    /// before it
+    ///
+    /// Two sources with the same file name give the same ID, which is an
+    /// error; use [`Self::add_page_with_id`] to tell them apart.
+    pub fn add_page_from_file(&self, page: Page, source: impl AsRef<Path>) -> Result<()> {
+        let source = source.as_ref();
+        let id = page_id_from_path(source).ok_or_else(|| {
+            DjvuError::InvalidArg(format!("No page ID in file name {}", source.display()))
+        })?;
+        self.add_page_with_id(page, id, "")
+    }
+
+    /// Set the title viewers show for a page (an empty title clears it).
+    pub fn set_page_title(&self, index: usize, title: impl Into<String>) -> Result<()> {
+        let title = sanitize(&title.into()).into_owned();
+        check_dir_string("Page title", &title)?;
+        let mut labels = self.lock_labels()?;
+        let total = labels.len();
+        let label = labels.get_mut(index).ok_or_else(|| {
+            DjvuError::InvalidArg(format!("Page number {index} out of range (total {total})"))
+        })?;
+        label.title = (!title.is_empty()).then_some(title);
+        Ok(())
+    }
+
+    /// Add a shared include file (`FORM:DJVI`) to the document
+    ///
+    /// Include files are stored ahead of the pages and listed in the
+    /// directory; pages refer to them with [`PageBuilder::with_include`].
+    /// A document with include files is always written as a bundled `DJVM`,
+    /// even when it has a single page.
+    pub fn add_include_file(&self, file: IncludeFile) -> Result<()> {
+        let skipped = self.collection.skipped_pages();
+        let taken_by_page = self
+            .resolve_page_ids(&self.lock_labels()?, &skipped)
+            .iter()
+            .any(|id| id.as_deref() == Some(file.id()));
+        let mut includes = self
+            .includes
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Include list lock poisoned".to_string()))?;
+        if taken_by_page || includes.iter().any(|f| f.id() == file.id()) {
+            return Err(DjvuError::InvalidArg(format!(
+                "File ID '{}' is already used in this document",
+                file.id()
+            )));
+        }
+        includes.push(file);
+        Ok(())
+    }
+
+    /// Set the document outline (`NAVM` chunk)
+    ///
+    /// Bookmark destinations are page IDs prefixed with `#` (e.g.
+    /// `#p0001.djvu`, or a custom ID from [`Self::add_page_with_id`]). A
+    /// document with bookmarks is always written as a bundled `DJVM`.
+    pub fn set_bookmarks(&self, navigation: DjVmNav) -> Result<()> {
+        *self
+            .navigation
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Navigation lock poisoned".to_string()))? =
+            Some(navigation);
+        Ok(())
+    }
+
+    /// Saves the document's state so far to `path` (see
+    /// [`crate::doc::checkpoint`])
+    ///
+    /// The first save writes the whole checkpoint to a temporary file next
+    /// to `path` and renames it over any previous one. Later saves to the
+    /// same path append only the pages, titles and include files added
+    /// since, so saving after every page stays cheap; a save cut short
+    /// leaves a partial record at the end, which restoring ignores.
+    pub fn save_checkpoint(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut log = self
+            .checkpoint_log
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Checkpoint lock poisoned".to_string()))?;
+        if let Some(saved) = log.as_mut().filter(|log| log.path == path && path.exists()) {
+            let appended = self.append_checkpoint(saved);
+            if appended.is_err() {
+                // The file may end in a torn record; start afresh next time.
+                *log = None;
+            }
+            return appended;
+        }
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let file = std::fs::File::create(&tmp).context(tmp.display())?;
+        let mut writer = BufWriter::new(file);
+        let snapshot = self.checkpoint()?;
+        snapshot.write(&mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .context(tmp.display())?;
+        std::fs::rename(&tmp, path).context(path.display())?;
+        *log = Some(CheckpointLog {
+            path: path.to_path_buf(),
+            saved_pages: snapshot
+                .pages
+                .iter()
+                .map(|page| !matches!(page, PageState::Pending))
+                .collect(),
+            labels: snapshot.labels,
+            includes: snapshot.includes.len(),
+        });
+        Ok(())
+    }
+
+    /// Appends the changes since `log` was written to its checkpoint file.
+    fn append_checkpoint(&self, log: &mut CheckpointLog) -> Result<()> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&log.path)
+            .context(log.path.display())?;
+        let mut writer = BufWriter::new(file);
+
+        let skipped = self.collection.skipped_pages();
+        let annotations = self.lock_page_annotations()?.clone();
+        for page_num in 0..self.total_pages() {
+            if log.saved_pages[page_num] {
+                continue;
+            }
+            let page = self.page_state(page_num, &skipped)?;
+            if !matches!(page, PageState::Pending) {
+                let annotations = annotations.get(page_num).and_then(Option::as_ref);
+                checkpoint::write_page(&mut writer, page_num, &page, annotations)?;
+                log.saved_pages[page_num] = true;
+            }
+        }
+        let labels = self.lock_labels()?.clone();
+        for (page_num, (label, saved)) in labels.iter().zip(&log.labels).enumerate() {
+            if label != saved {
+                checkpoint::write_label(&mut writer, page_num, label)?;
+            }
+        }
+        log.labels = labels;
+        let includes = self
+            .includes
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Include list lock poisoned".to_string()))?
+            .clone();
+        for file in includes.iter().skip(log.includes) {
+            checkpoint::write_include(&mut writer, file)?;
+        }
+        log.includes = includes.len();
+        let navigation = self
+            .navigation
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Navigation lock poisoned".to_string()))?
+            .clone();
+        checkpoint::write_state(&mut writer, navigation.as_ref(), &self.encode_stats()?)?;
+
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .context(log.path.display())?;
+        Ok(())
+    }
+
+    /// Writes the document's state so far, like [`Self::save_checkpoint`]
+    pub fn write_checkpoint<W: Write>(&self, writer: W) -> Result<()> {
+        self.checkpoint()?.write(writer)
+    }
+
+    /// Everything a checkpoint of the document holds, as it stands now.
+    fn checkpoint(&self) -> Result<Checkpoint> {
+        let skipped = self.collection.skipped_pages();
+        let pages = (0..self.total_pages())
+            .map(|page_num| self.page_state(page_num, &skipped))
+            .collect::<Result<_>>()?;
+        Ok(Checkpoint {
+            settings: self.settings_fingerprint(),
+            pages,
+            labels: self.lock_labels()?.clone(),
+            annotations: self.lock_page_annotations()?.clone(),
+            includes: self
+                .includes
+                .lock()
+                .map_err(|_| DjvuError::InvalidOperation("Include list lock poisoned".to_string()))?
+                .clone(),
+            navigation: self
+                .navigation
+                .lock()
+                .map_err(|_| DjvuError::InvalidOperation("Navigation lock poisoned".to_string()))?
+                .clone(),
+            stats: self.encode_stats()?,
+        })
+    }
+
+    fn page_state(&self, page_num: usize, skipped: &[usize]) -> Result<PageState> {
+        Ok(match self.collection.get_page(page_num)? {
+            _ if skipped.contains(&page_num) => PageState::Skipped,
+            Some(data) => {
+                let (width, height) = self.collection.get_metadata(page_num).unwrap_or((0, 0));
+                PageState::Ready {
+                    data,
+                    width,
+                    height,
+                }
+            }
+            None => PageState::Pending,
+        })
+    }
+
+    /// Restores the state saved by [`Self::save_checkpoint`] into this
+    /// document, returning how many pages it filled in
+    ///
+    /// The document must have the same page count and encoder settings as
+    /// the one the checkpoint was saved from, and no pages of its own yet.
+    /// Include files and bookmarks in the checkpoint replace any already set.
+    pub fn restore_checkpoint<R: Read>(&self, reader: R) -> Result<usize> {
+        let checkpoint = Checkpoint::read(reader)?;
+        if checkpoint.pages.len() != self.total_pages() {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Checkpoint has {} pages, the document {}",
+                checkpoint.pages.len(),
+                self.total_pages()
+            )));
+        }
+        if checkpoint.settings != self.settings_fingerprint() {
+            return Err(DjvuError::InvalidOperation(
+                "Checkpoint was saved with different encoder settings".to_string(),
+            ));
+        }
+        if self.pages_ready() > 0 {
+            return Err(DjvuError::InvalidOperation(
+                "Checkpoints can only be restored into a document without pages".to_string(),
+            ));
+        }
+
+        let mut restored = 0;
+        let annotations = checkpoint
+            .annotations
+            .into_iter()
+            .chain(std::iter::repeat(None));
+        for (page_num, (page, annotations)) in
+            checkpoint.pages.into_iter().zip(annotations).enumerate()
+        {
+            match page {
+                PageState::Pending => continue,
+                PageState::Ready {
+                    data,
+                    width,
+                    height,
+                } => {
+                    let mut encoded = EncodedPage::new(page_num, Vec::new(), width, height);
+                    encoded.data = data;
+                    encoded.annotations = annotations;
+                    self.add_encoded_page(encoded)?;
+                }
+                PageState::Skipped => self.collection.skip_page(page_num)?,
+            }
+            restored += 1;
+        }
+        for (label, saved) in self.lock_labels()?.iter_mut().zip(checkpoint.labels) {
+            *label = saved;
+        }
+        *self
+            .includes
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Include list lock poisoned".to_string()))? =
+            checkpoint.includes;
+        *self
+            .navigation
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Navigation lock poisoned".to_string()))? =
+            checkpoint.navigation;
+        *self.lock_stats()? = checkpoint.stats;
+        Ok(restored)
+    }
+
+    /// The settings that shape encoded pages, for telling whether a
+    /// checkpoint's pages match this document
+    fn settings_fingerprint(&self) -> u64 {
+        let mut f = Fingerprint::new();
+        self.params.fingerprint(&mut f);
+        f.u64(u64::from(self.dpi));
+        f.opt(self.gamma, Fingerprint::f32);
+        f.bytes(&[self.integrity_checksums as u8]);
+        f.opt(self.symbol_dict.as_deref(), |f, dict| {
+            f.u64(dict.shape_count() as u64);
+            for shape in dict.shapes() {
+                f.u64(shape.width as u64);
+                f.u64(shape.height as u64);
+                for word in shape.to_packed_words() {
+                    f.bytes(&word.to_be_bytes());
+                }
+            }
+        });
+        f.finish()
+    }
+
+    fn lock_labels(&self) -> Result<std::sync::MutexGuard<'_, Vec<PageLabel>>> {
+        self.labels
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Page label lock poisoned".to_string()))
+    }
+
+    fn lock_page_annotations(&self) -> Result<std::sync::MutexGuard<'_, Vec<Option<Annotations>>>> {
+        self.page_annotations
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Annotation list lock poisoned".to_string()))
+    }
+
+    /// The ID each page ends up with, indexed like `labels`: its own, or the
+    /// scheme's for its position among the pages not in `skipped`. Skipped
+    /// pages have none.
+    fn resolve_page_ids(&self, labels: &[PageLabel], skipped: &[usize]) -> Vec<Option<String>> {
+        let mut position = 0;
+        labels
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                if skipped.contains(&i) {
+                    return None;
+                }
+                position += 1;
+                Some(
+                    label
+                        .id
+                        .clone()
+                        .unwrap_or_else(|| self.page_ids.id(position - 1)),
+                )
+            })
+            .collect()
+    }
+
+    /// Resolves the page links of every page against the final page list,
+    /// recording broken ones in the stats. Returns, for each page not in
+    /// `skipped`, the title targets to rewrite with canonical links.
+    fn check_links(
+        &self,
+        labels: &[PageLabel],
+        skipped: &[usize],
+    ) -> Result<Vec<Vec<(String, String)>>> {
+        let annotations: Vec<Option<Annotations>> = self
+            .lock_page_annotations()?
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !skipped.contains(i))
+            .map(|(_, a)| a.clone())
+            .collect();
+        // `finished_parts` has given every page its ID.
+        let ids: Vec<String> = labels
+            .iter()
+            .map(|label| label.id.clone().unwrap_or_default())
+            .collect();
+        let titles: Vec<Option<String>> = labels.iter().map(|l| l.title.clone()).collect();
+        let directory = PageDirectory {
+            ids: &ids,
+            titles: &titles,
+        };
+
+        let mut broken = Vec::new();
+        let rewrites = annotations
+            .iter()
+            .enumerate()
+            .map(|(i, annotations)| match annotations {
+                Some(annotations) => {
+                    directory.check(annotations, i, self.canonical_links, &mut broken)
+                }
+                None => Vec::new(),
+            })
+            .collect();
+        for link in &broken {
+            warn!(
+                target: target::DOC,
+                "Page {} links to missing page {}", link.page_num, link.url
+            );
+        }
+        self.lock_stats()?.broken_links = broken;
+        Ok(rewrites)
     }
 
     /// Finalize and return DjVu file bytes
     pub fn finalize(&self) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.write_document(&mut output)?;
+        Ok(output)
+    }
+
+    /// Writes the document to `writer`, reading the pages one at a time
+    fn write_document(&self, writer: &mut dyn Write) -> Result<()> {
+        let mut parts = self.finished_parts()?;
+        DocumentEncoder::write_to(
+            &mut parts.pages,
+            &parts.includes,
+            &parts.labels,
+            parts.navigation.as_ref(),
+            self.directory_format,
+            &self.params.bzz,
+            writer,
+        )
+    }
+
+    /// Writes the document in indirect form, as a ZIP archive of the index
+    /// file `index.djvu` and one file per page and include file, named by
+    /// its ID. The output transform is not applied.
+    pub fn write_indirect_zip<W: Write>(&self, writer: W) -> Result<()> {
+        let mut parts = self.finished_parts()?;
+        DocumentEncoder::write_indirect_zip(
+            &mut parts.pages,
+            &parts.includes,
+            &parts.labels,
+            parts.navigation.as_ref(),
+            self.directory_format,
+            &self.params.bzz,
+            writer,
+        )
+    }
+
+    /// Takes the encoded pages of a complete document, with what else goes
+    /// into the finished file.
+    fn finished_parts(&self) -> Result<FinishedParts<'_>> {
+        self.measure_deferred()?;
         if !self.is_complete() {
             return Err(DjvuError::InvalidOperation(format!(
                 "Document incomplete: {} of {} pages ready",
@@ -559,12 +1889,73 @@ impl DjvuDocument {
             )));
         }
 
-        let pages = self
-            .collection
-            .take_all()
-            .ok_or_else(|| DjvuError::InvalidOperation("Failed to collect pages".to_string()))?;
+        let skipped = self.collection.skipped_pages();
+        if skipped.len() == self.total_pages() && !skipped.is_empty() {
+            return Err(DjvuError::InvalidOperation(
+                "Every page of the document was skipped".to_string(),
+            ));
+        }
+        let labels: Vec<PageLabel> = {
+            let labels = self.lock_labels()?;
+            let ids = self.resolve_page_ids(&labels, &skipped);
+            labels
+                .iter()
+                .zip(ids)
+                .filter_map(|(label, id)| {
+                    id.map(|id| PageLabel {
+                        id: Some(id),
+                        title: label.title.clone(),
+                    })
+                })
+                .collect()
+        };
+        // Skipping a page renumbers those after it, which may give one the
+        // explicit ID of another.
+        for (i, label) in labels.iter().enumerate() {
+            if labels[..i].iter().any(|other| other.id == label.id) {
+                return Err(DjvuError::InvalidOperation(format!(
+                    "Page ID '{}' is used by two pages",
+                    label.id.as_deref().unwrap_or_default()
+                )));
+            }
+        }
+        let rewrites = self.check_links(&labels, &skipped)?;
+        let pages = StoredPages {
+            doc: self,
+            page_nums: (0..self.total_pages())
+                .filter(|i| !skipped.contains(i))
+                .collect(),
+            rewrites,
+        };
+        let includes = self
+            .includes
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Include list lock poisoned".to_string()))?
+            .clone();
+        let navigation = self
+            .navigation
+            .lock()
+            .map_err(|_| DjvuError::InvalidOperation("Navigation lock poisoned".to_string()))?
+            .clone();
 
-        // Use internal encoder to assemble the document
-        DocumentEncoder::assemble_pages(&pages)
+        Ok(FinishedParts {
+            pages,
+            labels,
+            includes,
+            navigation,
+        })
+    }
+
+    /// Finalize and write the document through the configured output transform
+    ///
+    /// The directory goes out first, then each page as it is read from the
+    /// page collection, so the document is never held in memory as a whole,
+    /// and wrapping (e.g. gzip or encryption) happens as it is written.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        let mut out = self.output_transform.wrap(&mut writer)?;
+        self.write_document(&mut out)?;
+        out.finish()?;
+        writer.flush()?;
+        Ok(())
     }
 }
@@ -0,0 +1,613 @@
+//! Checkpoints of documents under construction.
+//!
+//! Encoding a large book can take hours. [`DjvuDocument::save_checkpoint`]
+//! writes everything the document holds so far (the encoded pages, skipped
+//! pages, page IDs and titles, include files, bookmarks and the encode
+//! stats) to a file; after a crash, a document built with the same settings
+//! picks up from there with [`DjvuDocument::restore_checkpoint`], and only
+//! the pages that are not [`ready`](DjvuDocument::is_page_ready) yet need
+//! encoding.
+//!
+//! Saving again to the same file appends only what changed since the last
+//! save, so checkpointing after every page costs one page of I/O.
+//!
+//! The encoder settings are not restored from the checkpoint, since the
+//! builder already has them. A fingerprint of the settings that shape the
+//! encoded pages is stored instead, and restoring into a document built
+//! differently fails rather than mixing pages encoded two ways.
+//!
+//! ```ignore
+//! let doc = DjvuBuilder::new(pages.len()).with_quality(80).build();
+//! if let Ok(file) = std::fs::File::open("book.ckpt") {
+//!     doc.restore_checkpoint(file)?;
+//! }
+//! for (i, page) in pages.into_iter().enumerate() {
+//!     if !doc.is_page_ready(i) {
+//!         doc.add_page(page)?;
+//!         doc.save_checkpoint("book.ckpt")?;
+//!     }
+//! }
+//! ```
+//!
+//! [`DjvuDocument::save_checkpoint`]: crate::doc::DjvuDocument::save_checkpoint
+//! [`DjvuDocument::restore_checkpoint`]: crate::doc::DjvuDocument::restore_checkpoint
+//! [`DjvuDocument::is_page_ready`]: crate::doc::DjvuDocument::is_page_ready
+
+use crate::annotations::{AnnotationShape, Annotations, Hyperlink};
+use crate::doc::djvu_dir::DjVmNav;
+use crate::doc::encoder::PageLabel;
+use crate::doc::include_file::IncludeFile;
+use crate::doc::recovery::{EncodeStats, ErrorRecoveryAction, PageFailure};
+use crate::encode::jb2::DictStats;
+use crate::{DjvuError, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+/// First bytes of a checkpoint file.
+pub const CHECKPOINT_MAGIC: &[u8; 8] = b"DJVUCKPT";
+const CHECKPOINT_VERSION: u8 = 4;
+
+/// Record tags. A checkpoint is a header followed by records, each a tag,
+/// a length and a payload; later records override earlier ones, so saving
+/// again only appends what changed.
+const PAGE_RECORD: u8 = 1;
+const LABEL_RECORD: u8 = 2;
+const INCLUDE_RECORD: u8 = 3;
+const STATE_RECORD: u8 = 4;
+
+/// What a checkpoint holds for one page.
+#[derive(Debug, Clone)]
+pub(crate) enum PageState {
+    Pending,
+    Ready {
+        data: Arc<Vec<u8>>,
+        width: u32,
+        height: u32,
+    },
+    Skipped,
+}
+
+/// The state of a [`DjvuDocument`](crate::doc::DjvuDocument), as saved.
+#[derive(Debug, Clone)]
+pub(crate) struct Checkpoint {
+    /// Fingerprint of the settings the pages were encoded with.
+    pub settings: u64,
+    pub pages: Vec<PageState>,
+    pub labels: Vec<PageLabel>,
+    pub annotations: Vec<Option<Annotations>>,
+    pub includes: Vec<IncludeFile>,
+    pub navigation: Option<DjVmNav>,
+    pub stats: EncodeStats,
+}
+
+impl Checkpoint {
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        write_header(&mut w, self.settings, self.pages.len())?;
+        for (i, page) in self.pages.iter().enumerate() {
+            if !matches!(page, PageState::Pending) {
+                let annotations = self.annotations.get(i).and_then(Option::as_ref);
+                write_page(&mut w, i, page, annotations)?;
+            }
+        }
+        for (i, label) in self.labels.iter().enumerate() {
+            if label.id.is_some() || label.title.is_some() {
+                write_label(&mut w, i, label)?;
+            }
+        }
+        for file in &self.includes {
+            write_include(&mut w, file)?;
+        }
+        write_state(&mut w, self.navigation.as_ref(), &self.stats)
+    }
+
+    /// Reads a checkpoint, replaying its records in order
+    ///
+    /// A record cut short at the end of the file, left by an interrupted
+    /// save, is dropped along with everything it would have changed.
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC {
+            return Err(DjvuError::InvalidArg("Not a checkpoint file".to_string()));
+        }
+        let version = r.read_u8()?;
+        if version != CHECKPOINT_VERSION {
+            return Err(DjvuError::InvalidArg(format!(
+                "Unsupported checkpoint version {version}"
+            )));
+        }
+        let settings = r.read_u64::<LittleEndian>()?;
+        let count = r.read_u32::<LittleEndian>()? as usize;
+        let mut checkpoint = Self {
+            settings,
+            pages: vec![PageState::Pending; count],
+            labels: vec![PageLabel::default(); count],
+            annotations: vec![None; count],
+            includes: Vec::new(),
+            navigation: None,
+            stats: EncodeStats::default(),
+        };
+
+        while let Some((tag, payload)) = read_record(&mut r)? {
+            let mut p = payload.as_slice();
+            match tag {
+                PAGE_RECORD => {
+                    let page_num = checkpoint.page_num(&mut p)?;
+                    checkpoint.pages[page_num] = match p.read_u8()? {
+                        1 => {
+                            let width = p.read_u32::<LittleEndian>()?;
+                            let height = p.read_u32::<LittleEndian>()?;
+                            let data = Arc::new(read_bytes(&mut p)?);
+                            PageState::Ready {
+                                data,
+                                width,
+                                height,
+                            }
+                        }
+                        2 => PageState::Skipped,
+                        other => return Err(invalid(format!("unknown page state {other}"))),
+                    };
+                    checkpoint.annotations[page_num] = match p.read_u8()? {
+                        0 => None,
+                        _ => Some(read_annotations(&mut p)?),
+                    };
+                }
+                LABEL_RECORD => {
+                    let page_num = checkpoint.page_num(&mut p)?;
+                    checkpoint.labels[page_num] = PageLabel {
+                        id: read_opt_str(&mut p)?,
+                        title: read_opt_str(&mut p)?,
+                    };
+                }
+                INCLUDE_RECORD => {
+                    let id = read_str(&mut p)?;
+                    let file = IncludeFile::from_parts(id, read_bytes(&mut p)?);
+                    checkpoint.includes.retain(|f| f.id() != file.id());
+                    checkpoint.includes.push(file);
+                }
+                STATE_RECORD => {
+                    checkpoint.navigation = read_opt_str(&mut p)?
+                        .map(|outline| DjVmNav::from_outline(&outline))
+                        .transpose()?;
+                    checkpoint.stats = read_stats(&mut p)?;
+                }
+                other => return Err(invalid(format!("unknown record {other}"))),
+            }
+        }
+        Ok(checkpoint)
+    }
+
+    fn page_num(&self, p: &mut &[u8]) -> Result<usize> {
+        let page_num = p.read_u32::<LittleEndian>()? as usize;
+        if page_num >= self.pages.len() {
+            return Err(invalid(format!("page {page_num} out of range")));
+        }
+        Ok(page_num)
+    }
+}
+
+pub(crate) fn write_header<W: Write>(w: &mut W, settings: u64, pages: usize) -> Result<()> {
+    w.write_all(CHECKPOINT_MAGIC)?;
+    w.write_u8(CHECKPOINT_VERSION)?;
+    w.write_u64::<LittleEndian>(settings)?;
+    w.write_u32::<LittleEndian>(pages as u32)?;
+    Ok(())
+}
+
+/// Writes a ready or skipped page and its page-link annotations.
+pub(crate) fn write_page<W: Write>(
+    w: &mut W,
+    page_num: usize,
+    page: &PageState,
+    annotations: Option<&Annotations>,
+) -> Result<()> {
+    let mut p = Vec::new();
+    p.write_u32::<LittleEndian>(page_num as u32)?;
+    match page {
+        PageState::Pending => {
+            return Err(DjvuError::InvalidOperation(
+                "Pending pages are not saved".to_string(),
+            ));
+        }
+        PageState::Ready {
+            data,
+            width,
+            height,
+        } => {
+            p.write_u8(1)?;
+            p.write_u32::<LittleEndian>(*width)?;
+            p.write_u32::<LittleEndian>(*height)?;
+            write_bytes(&mut p, data)?;
+        }
+        PageState::Skipped => p.write_u8(2)?,
+    }
+    match annotations {
+        Some(annotations) => {
+            p.write_u8(1)?;
+            write_annotations(&mut p, annotations)?;
+        }
+        None => p.write_u8(0)?,
+    }
+    write_record(w, PAGE_RECORD, &p)
+}
+
+pub(crate) fn write_label<W: Write>(w: &mut W, page_num: usize, label: &PageLabel) -> Result<()> {
+    let mut p = Vec::new();
+    p.write_u32::<LittleEndian>(page_num as u32)?;
+    write_opt_str(&mut p, label.id.as_deref())?;
+    write_opt_str(&mut p, label.title.as_deref())?;
+    write_record(w, LABEL_RECORD, &p)
+}
+
+pub(crate) fn write_include<W: Write>(w: &mut W, file: &IncludeFile) -> Result<()> {
+    let mut p = Vec::new();
+    write_str(&mut p, file.id())?;
+    write_bytes(&mut p, file.data())?;
+    write_record(w, INCLUDE_RECORD, &p)
+}
+
+/// Writes the bookmarks and encode stats, which every save rewrites.
+pub(crate) fn write_state<W: Write>(
+    w: &mut W,
+    navigation: Option<&DjVmNav>,
+    stats: &EncodeStats,
+) -> Result<()> {
+    let mut p = Vec::new();
+    let outline = navigation.map(DjVmNav::to_outline);
+    write_opt_str(&mut p, outline.as_deref())?;
+
+    p.write_u32::<LittleEndian>(stats.pages_encoded as u32)?;
+    p.write_u32::<LittleEndian>(stats.failures.len() as u32)?;
+    for failure in &stats.failures {
+        p.write_u32::<LittleEndian>(failure.page_num as u32)?;
+        p.write_u8(match failure.action {
+            ErrorRecoveryAction::Abort => 0,
+            ErrorRecoveryAction::SkipPage => 1,
+            ErrorRecoveryAction::Placeholder => 2,
+        })?;
+        write_str(&mut p, &failure.error)?;
+    }
+    p.write_u32::<LittleEndian>(stats.blank_pages.len() as u32)?;
+    for &page_num in &stats.blank_pages {
+        p.write_u32::<LittleEndian>(page_num as u32)?;
+    }
+    let symbols = &stats.symbols;
+    for count in [
+        symbols.symbols,
+        symbols.hits,
+        symbols.shared,
+        symbols.new_shapes,
+    ] {
+        p.write_u32::<LittleEndian>(count as u32)?;
+    }
+    write_record(w, STATE_RECORD, &p)
+}
+
+fn read_stats<R: Read>(r: &mut R) -> Result<EncodeStats> {
+    let pages_encoded = r.read_u32::<LittleEndian>()? as usize;
+    let count = r.read_u32::<LittleEndian>()? as usize;
+    let mut failures = Vec::with_capacity(count.min(1 << 10));
+    for _ in 0..count {
+        let page_num = r.read_u32::<LittleEndian>()? as usize;
+        let action = match r.read_u8()? {
+            0 => ErrorRecoveryAction::Abort,
+            1 => ErrorRecoveryAction::SkipPage,
+            2 => ErrorRecoveryAction::Placeholder,
+            other => return Err(invalid(format!("unknown recovery action {other}"))),
+        };
+        failures.push(PageFailure {
+            page_num,
+            action,
+            error: read_str(r)?,
+        });
+    }
+    let count = r.read_u32::<LittleEndian>()? as usize;
+    let mut blank_pages = Vec::with_capacity(count.min(1 << 16));
+    for _ in 0..count {
+        blank_pages.push(r.read_u32::<LittleEndian>()? as usize);
+    }
+    let mut counts = [0usize; 4];
+    for count in &mut counts {
+        *count = r.read_u32::<LittleEndian>()? as usize;
+    }
+    let [symbols, hits, shared, new_shapes] = counts;
+    Ok(EncodeStats {
+        pages_encoded,
+        failures,
+        broken_links: Vec::new(),
+        blank_pages,
+        symbols: DictStats {
+            symbols,
+            hits,
+            shared,
+            new_shapes,
+        },
+    })
+}
+
+fn write_record<W: Write>(w: &mut W, tag: u8, payload: &[u8]) -> Result<()> {
+    w.write_u8(tag)?;
+    write_bytes(w, payload)
+}
+
+/// The next record, or `None` at the end of the file or a torn last record.
+fn read_record<R: Read>(r: &mut R) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut head = [0u8; 5];
+    let mut filled = 0;
+    while filled < head.len() {
+        match r.read(&mut head[filled..])? {
+            0 => return Ok(None),
+            n => filled += n,
+        }
+    }
+    let len = u32::from_le_bytes([head[1], head[2], head[3], head[4]]) as usize;
+    let mut payload = Vec::new();
+    r.take(len as u64).read_to_end(&mut payload)?;
+    Ok((payload.len() == len).then_some((head[0], payload)))
+}
+
+/// FNV-1a hash of the settings a checkpoint's pages were encoded with.
+pub(crate) struct Fingerprint(u64);
+
+impl Fingerprint {
+    pub fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.bytes(&v.to_bits().to_le_bytes());
+    }
+
+    /// Hashes whether `v` is set, then its value.
+    pub fn opt<T>(&mut self, v: Option<T>, hash: impl FnOnce(&mut Self, T)) {
+        self.bytes(&[v.is_some() as u8]);
+        if let Some(v) = v {
+            hash(self, v);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn invalid(what: String) -> DjvuError {
+    DjvuError::ValidationError(format!("Corrupt checkpoint: {what}"))
+}
+
+fn write_bytes<W: Write>(w: &mut W, data: &[u8]) -> Result<()> {
+    let len = u32::try_from(data.len())
+        .map_err(|_| DjvuError::InvalidArg("Checkpoint entry exceeds 4 GiB".to_string()))?;
+    w.write_u32::<LittleEndian>(len)?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    let mut data = Vec::new();
+    r.take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len {
+        return Err(invalid("truncated entry".to_string()));
+    }
+    Ok(data)
+}
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> Result<()> {
+    write_bytes(w, s.as_bytes())
+}
+
+fn read_str<R: Read>(r: &mut R) -> Result<String> {
+    String::from_utf8(read_bytes(r)?).map_err(|_| invalid("string is not UTF-8".to_string()))
+}
+
+fn write_opt_str<W: Write>(w: &mut W, s: Option<&str>) -> Result<()> {
+    match s {
+        Some(s) => {
+            w.write_u8(1)?;
+            write_str(w, s)
+        }
+        None => Ok(w.write_u8(0)?),
+    }
+}
+
+fn read_opt_str<R: Read>(r: &mut R) -> Result<Option<String>> {
+    match r.read_u8()? {
+        0 => Ok(None),
+        _ => read_str(r).map(Some),
+    }
+}
+
+fn write_annotations<W: Write>(w: &mut W, annotations: &Annotations) -> Result<()> {
+    w.write_u32::<LittleEndian>(annotations.hyperlinks.len() as u32)?;
+    for link in &annotations.hyperlinks {
+        match &link.shape {
+            AnnotationShape::Rect { x, y, w: sw, h } | AnnotationShape::Oval { x, y, w: sw, h } => {
+                w.write_u8(matches!(link.shape, AnnotationShape::Oval { .. }) as u8)?;
+                for v in [x, y, sw, h] {
+                    w.write_u32::<LittleEndian>(*v)?;
+                }
+            }
+            AnnotationShape::Polygon { points } => {
+                w.write_u8(2)?;
+                w.write_u32::<LittleEndian>(points.len() as u32)?;
+                for (x, y) in points {
+                    w.write_u32::<LittleEndian>(*x)?;
+                    w.write_u32::<LittleEndian>(*y)?;
+                }
+            }
+        }
+        write_str(w, &link.url)?;
+        write_str(w, &link.comment)?;
+        write_str(w, &link.target)?;
+    }
+    w.write_u32::<LittleEndian>(annotations.metadata.len() as u32)?;
+    for (key, value) in &annotations.metadata {
+        write_str(w, key)?;
+        write_str(w, value)?;
+    }
+    Ok(())
+}
+
+fn read_annotations<R: Read>(r: &mut R) -> Result<Annotations> {
+    let mut annotations = Annotations::new();
+    for _ in 0..r.read_u32::<LittleEndian>()? {
+        let tag = r.read_u8()?;
+        let shape = match tag {
+            0 | 1 => {
+                let mut v = [0u32; 4];
+                for v in &mut v {
+                    *v = r.read_u32::<LittleEndian>()?;
+                }
+                let [x, y, w, h] = v;
+                if tag == 0 {
+                    AnnotationShape::Rect { x, y, w, h }
+                } else {
+                    AnnotationShape::Oval { x, y, w, h }
+                }
+            }
+            2 => {
+                let count = r.read_u32::<LittleEndian>()? as usize;
+                let mut points = Vec::with_capacity(count.min(1 << 10));
+                for _ in 0..count {
+                    points.push((r.read_u32::<LittleEndian>()?, r.read_u32::<LittleEndian>()?));
+                }
+                AnnotationShape::Polygon { points }
+            }
+            other => return Err(invalid(format!("unknown shape {other}"))),
+        };
+        annotations.hyperlinks.push(Hyperlink {
+            shape,
+            url: read_str(r)?,
+            comment: read_str(r)?,
+            target: read_str(r)?,
+        });
+    }
+    for _ in 0..r.read_u32::<LittleEndian>()? {
+        annotations.metadata.push((read_str(r)?, read_str(r)?));
+    }
+    Ok(annotations)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::doc::{DjVmNav, DjvuBuilder, DjvuDocument, Page, PageBuilder};
+    use crate::image::image_formats::{Pixel, Pixmap};
+    use crate::{DjvuError, Result};
+
+    fn page(page_num: usize) -> Result<Page> {
+        PageBuilder::new(page_num, 16, 16)
+            .with_background(Pixmap::from_pixel(16, 16, Pixel::new(0, 0, 200)))?
+            .with_hyperlink("#Last", 0, 0, 4, 4, "")
+            .build()
+    }
+
+    fn document() -> DjvuDocument {
+        DjvuBuilder::new(3).with_quality(70).build()
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("book.ckpt");
+        let nav = DjVmNav::from_outline("(bookmarks (\"Start\" \"#cover\"))")?;
+
+        let doc = document();
+        doc.add_page_with_id(page(0)?, "cover", "")?;
+        doc.add_page_with_id(page(2)?, "last", "Last")?;
+        doc.set_bookmarks(nav.clone())?;
+        doc.save_checkpoint(&path)?;
+        assert!(!dir.path().join("book.ckpt.tmp").exists());
+
+        let resumed = document();
+        assert_eq!(resumed.restore_checkpoint(std::fs::File::open(&path)?)?, 2);
+        assert_eq!(
+            (0..3).map(|i| resumed.is_page_ready(i)).collect::<Vec<_>>(),
+            [true, false, true]
+        );
+        assert_eq!(resumed.encode_stats()?.pages_encoded, 2);
+        resumed.add_page(page(1)?)?;
+
+        doc.add_page(page(1)?)?;
+        assert_eq!(resumed.finalize()?, doc.finalize()?);
+        assert!(resumed.encode_stats()?.broken_links.is_empty());
+
+        // Pages encoded with other settings are not mixed in.
+        let other = DjvuBuilder::new(3).with_quality(90).build();
+        let err = other.restore_checkpoint(std::fs::File::open(&path)?);
+        assert!(matches!(err, Err(DjvuError::InvalidOperation(_))));
+        assert_eq!(other.pages_ready(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_tells_symbol_dictionaries_apart() -> Result<()> {
+        use crate::encode::jb2::symbol_dict::{BitImage, SharedDict};
+
+        // Two dictionaries of one 8x8 shape, one pixel apart.
+        let dict = |x: usize| {
+            let mut shape = BitImage::new(8, 8).unwrap();
+            shape.set_usize(x, 4, true);
+            SharedDict::new(vec![shape])
+        };
+        let seeded = |x: usize| -> Result<DjvuDocument> {
+            Ok(DjvuBuilder::new(3)
+                .with_quality(70)
+                .with_symbol_dictionary(dict(x))?
+                .build())
+        };
+
+        let doc = seeded(2)?;
+        doc.add_page(page(0)?)?;
+        let mut saved = Vec::new();
+        doc.write_checkpoint(&mut saved)?;
+        assert_eq!(seeded(2)?.restore_checkpoint(saved.as_slice())?, 1);
+        let err = seeded(3)?.restore_checkpoint(saved.as_slice());
+        assert!(matches!(err, Err(DjvuError::InvalidOperation(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_appends_changes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("book.ckpt");
+
+        let doc = document();
+        doc.add_page(page(0)?)?;
+        doc.save_checkpoint(&path)?;
+        let first = std::fs::read(&path)?;
+        doc.add_page(page(1)?)?;
+        doc.set_page_title(0, "Cover")?;
+        doc.save_checkpoint(&path)?;
+        let second = std::fs::read(&path)?;
+        assert!(second.starts_with(&first));
+        doc.add_page_with_id(page(2)?, "last", "Last")?;
+        doc.save_checkpoint(&path)?;
+        let third = std::fs::read(&path)?;
+        assert!(third.starts_with(&second));
+        // Only the new page is written again, not the ones before it.
+        assert!(third.len() - second.len() < 2 * (second.len() - first.len()));
+
+        let resumed = document();
+        assert_eq!(resumed.restore_checkpoint(third.as_slice())?, 3);
+        assert_eq!(resumed.finalize()?, doc.finalize()?);
+
+        // A save interrupted halfway leaves a torn record, which is ignored.
+        let torn = &third[..third.len() - 3];
+        let resumed = document();
+        assert_eq!(resumed.restore_checkpoint(torn)?, 3);
+        assert_eq!(resumed.encode_stats()?.pages_encoded, 2);
+        Ok(())
+    }
+}
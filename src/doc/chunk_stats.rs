@@ -0,0 +1,534 @@
+//! Chunk statistics of encoded documents.
+//!
+//! [`DocumentStats::from_reader`] walks the IFF structure of a single-page
+//! `FORM:DJVU` or a bundled or indirect `FORM:DJVM` and records, for every
+//! form, its chunks with their offsets and sizes, the page `INFO` fields and
+//! the headers of IW44 chunks, and for the document its `DIRM` directory.
+//! Nothing is decoded: only chunk headers, `INFO`, the first bytes of IW44
+//! chunks and the `DIRM` are read, and everything else is seeked over, so
+//! inventory tools can go through large archives quickly.
+//!
+//! [`FormStats::check_iw44_chain`] goes through the `BG44` (or other IW44)
+//! chunks of a page as a viewer refining the image would, and reports
+//! broken serial numbers, empty chunks and first-chunk headers that do not
+//! fit the page. Viewers skip a refinement they cannot place without
+//! saying so, leaving a page blurrier than it was encoded.
+//!
+//! ```ignore
+//! let stats = DocumentStats::from_reader(std::fs::File::open("book.djvu")?)?;
+//! for page in stats.pages() {
+//!     println!("{:?} {} bytes, text: {}", page.id, page.size, page.has_hidden_text());
+//! }
+//! ```
+
+use crate::doc::djvu_dir::{DjVmDir, FileType};
+pub use crate::doc::page_encoder::PageInfo;
+use crate::encode::iw44::Iw44ChunkHeader;
+use crate::{DjvuError, Result};
+use std::fmt;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+/// Chunks that start with an IW44 header.
+const IW44_CHUNKS: [&[u8; 4]; 4] = [b"BG44", b"FG44", b"BM44", b"PM44"];
+
+/// One leaf chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkStats {
+    pub id: [u8; 4],
+    /// Offset of the chunk header from the start of the file.
+    pub offset: u64,
+    /// Payload size, without the header and padding.
+    pub size: u32,
+    /// Header of an IW44 chunk, if it could be parsed.
+    pub iw44: Option<Iw44ChunkHeader>,
+}
+
+/// A `FORM` holding a page (`DJVU`), an include file (`DJVI`) or
+/// thumbnails (`THUM`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormStats {
+    pub secondary_id: [u8; 4],
+    /// ID of the file in the `DIRM`, for forms of bundled documents.
+    pub id: Option<String>,
+    /// Offset of the `FORM` header from the start of the file.
+    pub offset: u64,
+    /// Size of the form with its header.
+    pub size: u32,
+    /// The `INFO` chunk of a page.
+    pub info: Option<PageInfo>,
+    pub chunks: Vec<ChunkStats>,
+}
+
+impl FormStats {
+    pub fn is_page(&self) -> bool {
+        self.secondary_id == *b"DJVU"
+    }
+
+    /// Whether the form has a `TXTa` or `TXTz` chunk.
+    pub fn has_hidden_text(&self) -> bool {
+        self.chunks
+            .iter()
+            .any(|c| c.id == *b"TXTa" || c.id == *b"TXTz")
+    }
+
+    /// Total payload size of the chunks with ID `id`.
+    pub fn chunk_bytes(&self, id: [u8; 4]) -> u64 {
+        self.chunks
+            .iter()
+            .filter(|c| c.id == id)
+            .map(|c| c.size as u64)
+            .sum()
+    }
+
+    /// Slices coded in the IW44 chunks with ID `id` (e.g. `BG44`).
+    pub fn iw44_slices(&self, id: [u8; 4]) -> u32 {
+        self.chunks
+            .iter()
+            .filter(|c| c.id == id)
+            .filter_map(|c| c.iw44)
+            .map(|h| h.slices as u32)
+            .sum()
+    }
+
+    /// Checks the chain of IW44 chunks with ID `id` (e.g. `BG44`) against
+    /// each other and the page's `INFO`; see [`check_iw44_chain`]. A form
+    /// without such chunks has no issues.
+    pub fn check_iw44_chain(&self, id: [u8; 4]) -> Vec<Iw44ChainIssue> {
+        let headers = self.chunks.iter().filter(|c| c.id == id).map(|c| c.iw44);
+        check_iw44_chain(headers, self.info.map(|i| (i.width, i.height)))
+    }
+}
+
+/// A problem in a chain of IW44 chunks, found by
+/// [`FormStats::check_iw44_chain`]. `chunk` counts the chunks of the chain
+/// from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Iw44ChainIssue {
+    /// The chunk header could not be parsed.
+    Unreadable { chunk: usize },
+    /// The serial number does not follow the previous chunk's, so the
+    /// chunk refines an image it does not belong to.
+    SerialGap {
+        chunk: usize,
+        expected: u8,
+        found: u8,
+    },
+    /// The chunk codes no slices.
+    NoSlices { chunk: usize },
+    /// The image is not the page size reduced by any subsampling factor
+    /// a viewer accepts (1 to 12).
+    SizeMismatch {
+        width: u16,
+        height: u16,
+        page_width: u16,
+        page_height: u16,
+    },
+    /// The chrominance starts after the last slice of the chain, so the
+    /// image shows up in grayscale.
+    ChromaNeverCoded { delay: u8, slices: u32 },
+}
+
+impl fmt::Display for Iw44ChainIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Unreadable { chunk } => write!(f, "chunk {chunk} has no valid IW44 header"),
+            Self::SerialGap {
+                chunk,
+                expected,
+                found,
+            } => write!(f, "chunk {chunk} has serial {found}, expected {expected}"),
+            Self::NoSlices { chunk } => write!(f, "chunk {chunk} codes no slices"),
+            Self::SizeMismatch {
+                width,
+                height,
+                page_width,
+                page_height,
+            } => write!(
+                f,
+                "image is {width}x{height}, which no subsampling of the \
+                 {page_width}x{page_height} page gives"
+            ),
+            Self::ChromaNeverCoded { delay, slices } => write!(
+                f,
+                "chrominance starts after slice {delay} but the chain has {slices}"
+            ),
+        }
+    }
+}
+
+/// Checks a chain of IW44 chunk headers, given in file order, `None`
+/// standing for a header that could not be parsed. With `page` set to the
+/// page's width and height, the image size is checked against it.
+pub fn check_iw44_chain(
+    headers: impl IntoIterator<Item = Option<Iw44ChunkHeader>>,
+    page: Option<(u16, u16)>,
+) -> Vec<Iw44ChainIssue> {
+    let mut issues = Vec::new();
+    let mut image = None;
+    let mut slices = 0u32;
+    for (chunk, header) in headers.into_iter().enumerate() {
+        let Some(header) = header else {
+            issues.push(Iw44ChainIssue::Unreadable { chunk });
+            continue;
+        };
+        // Serial numbers are a byte and wrap around after 255.
+        let expected = chunk as u8;
+        if header.serial != expected {
+            issues.push(Iw44ChainIssue::SerialGap {
+                chunk,
+                expected,
+                found: header.serial,
+            });
+        }
+        if header.slices == 0 {
+            issues.push(Iw44ChainIssue::NoSlices { chunk });
+        }
+        slices += header.slices as u32;
+        if image.is_none() {
+            image = header.image;
+        }
+    }
+
+    if let Some(image) = image {
+        if let Some((page_width, page_height)) = page {
+            let fits = (1..=12u32).any(|r| {
+                image.width as u32 == (page_width as u32).div_ceil(r)
+                    && image.height as u32 == (page_height as u32).div_ceil(r)
+            });
+            if !fits {
+                issues.push(Iw44ChainIssue::SizeMismatch {
+                    width: image.width,
+                    height: image.height,
+                    page_width,
+                    page_height,
+                });
+            }
+        }
+        if let Some(chroma) = image.chroma
+            && chroma.delay as u32 >= slices
+        {
+            issues.push(Iw44ChainIssue::ChromaNeverCoded {
+                delay: chroma.delay,
+                slices,
+            });
+        }
+    }
+    issues
+}
+
+/// An entry of the `DIRM` directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntryStats {
+    pub id: String,
+    pub name: String,
+    pub title: String,
+    pub file_type: FileType,
+    /// Offset of the file's `FORM`; 0 in indirect documents.
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// The `DIRM` chunk of a multi-page document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryStats {
+    pub version: u8,
+    /// Whether the files are bundled in the document, rather than stored
+    /// next to it.
+    pub bundled: bool,
+    pub files: Vec<DirEntryStats>,
+}
+
+/// The chunk layout of an encoded document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentStats {
+    /// `DJVU` for single-page documents, `DJVM` for multi-page ones.
+    pub secondary_id: [u8; 4],
+    /// Size of the root form with its header.
+    pub size: u32,
+    pub directory: Option<DirectoryStats>,
+    /// Leaf chunks of a `DJVM` form itself, such as `DIRM` and `NAVM`.
+    pub chunks: Vec<ChunkStats>,
+    /// The forms of the document, in file order. A single-page document
+    /// has exactly one.
+    pub forms: Vec<FormStats>,
+}
+
+impl DocumentStats {
+    /// Scans an encoded document held in memory.
+    pub fn from_document(data: &[u8]) -> Result<Self> {
+        Self::from_reader(Cursor::new(data))
+    }
+
+    /// Scans an encoded document, reading only headers and small chunks.
+    pub fn from_reader<R: Read + Seek>(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let start = if magic == *b"AT&T" { 4 } else { 0 };
+        let (id, size) = read_header(&mut reader, start)?;
+        let secondary_id = read_array(&mut reader)?;
+        if id != *b"FORM" || !matches!(&secondary_id, b"DJVU" | b"DJVM") {
+            return Err(DjvuError::InvalidArg(
+                "Not a DJVU or DJVM document".to_string(),
+            ));
+        }
+        let end = start + 8 + size as u64;
+        let mut stats = Self {
+            secondary_id,
+            size: size.saturating_add(8),
+            directory: None,
+            chunks: Vec::new(),
+            forms: Vec::new(),
+        };
+        if secondary_id == *b"DJVU" {
+            stats
+                .forms
+                .push(scan_form(&mut reader, start, size, secondary_id)?);
+            return Ok(stats);
+        }
+
+        let mut pos = start + 12;
+        while pos + 8 <= end {
+            let (id, size) = read_header(&mut reader, pos)?;
+            if id == *b"FORM" {
+                let secondary_id = read_array(&mut reader)?;
+                stats
+                    .forms
+                    .push(scan_form(&mut reader, pos, size, secondary_id)?);
+            } else {
+                if id == *b"DIRM" {
+                    // Read through `take` so that a corrupt size cannot
+                    // allocate gigabytes up front.
+                    let mut data = Vec::new();
+                    reader.by_ref().take(size as u64).read_to_end(&mut data)?;
+                    stats.directory = Some(read_directory(&data)?);
+                }
+                stats.chunks.push(ChunkStats {
+                    id,
+                    offset: pos,
+                    size,
+                    iw44: None,
+                });
+            }
+            pos = next_chunk(pos, size);
+        }
+
+        if let Some(dir) = &stats.directory
+            && dir.bundled
+            && dir.files.len() == stats.forms.len()
+        {
+            for (form, file) in stats.forms.iter_mut().zip(&dir.files) {
+                form.id = Some(file.id.clone());
+            }
+        }
+        Ok(stats)
+    }
+
+    /// The page forms, in document order.
+    pub fn pages(&self) -> impl Iterator<Item = &FormStats> {
+        self.forms.iter().filter(|f| f.is_page())
+    }
+}
+
+/// Scans the children of the `FORM` whose header is at `offset`; the reader
+/// is just past its secondary ID.
+fn scan_form<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    size: u32,
+    secondary_id: [u8; 4],
+) -> Result<FormStats> {
+    let mut form = FormStats {
+        secondary_id,
+        id: None,
+        offset,
+        size: size.saturating_add(8),
+        info: None,
+        chunks: Vec::new(),
+    };
+    let end = offset + 8 + size as u64;
+    let mut pos = offset + 12;
+    while pos + 8 <= end {
+        let (id, size) = read_header(reader, pos)?;
+        let mut iw44 = None;
+        if id == *b"INFO" {
+            let mut data = [0u8; 10];
+            let len = read_prefix(reader, &mut data, size)?;
+            form.info = PageInfo::parse(&data[..len]);
+        } else if IW44_CHUNKS.contains(&&id) {
+            let mut data = [0u8; Iw44ChunkHeader::FIRST_LEN];
+            let len = read_prefix(reader, &mut data, size)?;
+            iw44 = Iw44ChunkHeader::parse(&data[..len]).ok();
+        }
+        form.chunks.push(ChunkStats {
+            id,
+            offset: pos,
+            size,
+            iw44,
+        });
+        pos = next_chunk(pos, size);
+    }
+    Ok(form)
+}
+
+fn read_directory(data: &[u8]) -> Result<DirectoryStats> {
+    let (dir, version) = DjVmDir::decode(data)?;
+    let files = dir
+        .get_files_list()
+        .iter()
+        .map(|file| DirEntryStats {
+            id: file.id.clone(),
+            name: file.get_save_name(),
+            title: file.get_title(),
+            file_type: file.file_type,
+            offset: file.offset,
+            size: file.size,
+        })
+        .collect();
+    Ok(DirectoryStats {
+        version,
+        bundled: data.first().is_some_and(|&b| b & 0x80 != 0),
+        files,
+    })
+}
+
+/// Reads the chunk header at `pos`.
+fn read_header<R: Read + Seek>(reader: &mut R, pos: u64) -> Result<([u8; 4], u32)> {
+    reader.seek(SeekFrom::Start(pos))?;
+    let header: [u8; 8] = read_array(reader).map_err(|_| {
+        DjvuError::ValidationError(format!("Truncated chunk header at offset {pos}"))
+    })?;
+    let [a, b, c, d, s0, s1, s2, s3] = header;
+    Ok(([a, b, c, d], u32::from_be_bytes([s0, s1, s2, s3])))
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads up to `buf.len()` bytes of a chunk of `size` bytes, returning how
+/// many were read.
+fn read_prefix<R: Read>(reader: &mut R, buf: &mut [u8], size: u32) -> Result<usize> {
+    let len = buf.len().min(size as usize);
+    reader.read_exact(&mut buf[..len])?;
+    Ok(len)
+}
+
+fn next_chunk(pos: u64, size: u32) -> u64 {
+    pos + 8 + size as u64 + (size & 1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::{DjvuBuilder, PageBuilder};
+    use crate::image::image_formats::{Pixel, Pixmap};
+
+    #[test]
+    fn test_scan_bundled_document() -> Result<()> {
+        let doc = DjvuBuilder::new(2).with_dpi(150).build();
+        for page_num in 0..2 {
+            let mut page = PageBuilder::new(page_num, 32, 24)
+                .with_background(Pixmap::from_pixel(32, 24, Pixel::new(90, 140, 200)))?;
+            if page_num == 1 {
+                page = page.with_ocr_words(vec![("word".to_string(), 2, 2, 10, 6)]);
+            }
+            doc.add_page_with_id(page.build()?, format!("p{page_num}.djvu"), "")?;
+        }
+        let data = doc.finalize()?;
+
+        let stats = DocumentStats::from_document(&data)?;
+        assert_eq!(stats.secondary_id, *b"DJVM");
+        assert_eq!(stats.size as usize + 4, data.len());
+        assert_eq!(stats.chunks[0].id, *b"DIRM");
+        let dir = stats.directory.as_ref().unwrap();
+        assert!(dir.bundled);
+        assert_eq!(dir.files.len(), 2);
+
+        let pages: Vec<_> = stats.pages().collect();
+        assert_eq!(pages.len(), 2);
+        for (page, file) in pages.iter().zip(&dir.files) {
+            assert_eq!(page.id.as_deref(), Some(file.id.as_str()));
+            assert_eq!(page.offset, file.offset as u64);
+            assert_eq!(&data[page.offset as usize..][..4], b"FORM");
+            let info = page.info.unwrap();
+            assert_eq!((info.width, info.height, info.dpi), (32, 24, 150));
+            assert!(page.iw44_slices(*b"BG44") > 0);
+            let first = page.chunks.iter().find_map(|c| c.iw44).unwrap();
+            assert_eq!(first.image.unwrap().width, 32);
+        }
+        assert!(!pages[0].has_hidden_text());
+        assert!(pages[1].has_hidden_text());
+
+        // A single page is its own form.
+        let page = &data[pages[0].offset as usize..][..pages[0].size as usize];
+        let single = DocumentStats::from_document(page)?;
+        assert_eq!(single.secondary_id, *b"DJVU");
+        assert!(single.directory.is_none());
+        let sizes = |form: &FormStats| {
+            form.chunks
+                .iter()
+                .map(|c| (c.id, c.size))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sizes(&single.forms[0]), sizes(pages[0]));
+
+        for page in &pages {
+            assert_eq!(page.check_iw44_chain(*b"BG44"), []);
+        }
+
+        assert!(DocumentStats::from_document(&data[..40]).is_err());
+        assert!(DocumentStats::from_document(b"AT&TFORM\0\0\0\x04PNG ").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_iw44_chain_issues() {
+        use crate::encode::iw44::{ChromaLayout, Iw44ImageHeader};
+
+        let chroma = Some(ChromaLayout {
+            half: false,
+            delay: 10,
+        });
+        let first = |width, height| Iw44ChunkHeader {
+            serial: 0,
+            slices: 8,
+            image: Some(Iw44ImageHeader::new(width, height, chroma).unwrap()),
+        };
+        let next = |serial, slices| Iw44ChunkHeader {
+            serial,
+            slices,
+            image: None,
+        };
+
+        // A 3x subsampled background of a 100x50 page.
+        let chain = [Some(first(34, 17)), Some(next(1, 5)), Some(next(2, 4))];
+        assert_eq!(check_iw44_chain(chain, Some((100, 50))), []);
+
+        let chain = [Some(first(34, 17)), None, Some(next(3, 0))];
+        assert_eq!(
+            check_iw44_chain(chain, Some((100, 50))),
+            [
+                Iw44ChainIssue::Unreadable { chunk: 1 },
+                Iw44ChainIssue::SerialGap {
+                    chunk: 2,
+                    expected: 2,
+                    found: 3
+                },
+                Iw44ChainIssue::NoSlices { chunk: 2 },
+                Iw44ChainIssue::ChromaNeverCoded {
+                    delay: 10,
+                    slices: 8
+                },
+            ]
+        );
+
+        let issues = check_iw44_chain([Some(next(1, 5)), Some(first(40, 17))], Some((100, 50)));
+        assert_eq!(issues.len(), 3, "{issues:?}");
+        assert!(matches!(
+            issues[2],
+            Iw44ChainIssue::SizeMismatch { width: 40, .. }
+        ));
+    }
+}
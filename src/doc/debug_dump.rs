@@ -0,0 +1,186 @@
+//! Dumping the layers of a page as image files.
+//!
+//! When a page comes out wrong (text in the background, a smeared photo,
+//! black letters that should be red) the first question is which layer is
+//! to blame. With [`PageEncodeParams::debug_dump`] set,
+//! [`PageComponents::encode`] writes the layers as they go into the codecs,
+//! after segmentation, masking and hole filling, so they can be looked at in
+//! any image viewer:
+//!
+//! | File | Layer |
+//! | --- | --- |
+//! | `p0001-mask.pbm` | The JB2 layer: the shapes coded, drawn where the page places them |
+//! | `p0001-background.ppm` | The background as its codec gets it, reduced by `bg_subsample`, with the pixels the mask hides filled as `hole_fill` says |
+//! | `p0001-background-hidden.pbm` | Black where the mask hides the background |
+//! | `p0001-foreground.ppm` | The FG44 colors, reduced by `fg_subsample` |
+//! | `p0001-foreground-hidden.pbm` | Black where no shape shows the FG44 colors |
+//!
+//! Pictures of grayscale pages are written as `.pgm`. A solid background is
+//! written at the size it is coded at, 1/12 of the page.
+//!
+//! The number is the page number given to `encode`. Layers the page does
+//! not code are not written, nor is the blank background of pages without
+//! one. With [`DumpFormat::Png`] (feature `png`) every file is a PNG
+//! instead.
+//!
+//! [`PageComponents::encode`]: crate::doc::PageComponents::encode
+
+use crate::doc::page_encoder::PageEncodeParams;
+use crate::encode::jb2::symbol_dict::BitImage;
+use crate::image::image_formats::{Bitmap, LoadedImage, Pixmap, write_pnm};
+use crate::utils::error::IoContext;
+use crate::{DjvuError, Result};
+use std::path::PathBuf;
+
+/// File format of dumped layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DumpFormat {
+    /// PBM for the mask, PPM or PGM for the pictures.
+    #[default]
+    Pnm,
+    /// PNG for everything.
+    #[cfg(feature = "png")]
+    Png,
+}
+
+/// Where [`PageComponents::encode`] writes the layers of the page; see the
+/// [module documentation](self).
+///
+/// [`PageComponents::encode`]: crate::doc::PageComponents::encode
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DebugDump {
+    /// Directory the files go to; it is created when missing.
+    pub dir: PathBuf,
+    pub format: DumpFormat,
+}
+
+impl DebugDump {
+    /// Dumps to `dir` in the default format.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            format: DumpFormat::default(),
+        }
+    }
+
+    /// Sets the file format.
+    pub fn with_format(mut self, format: DumpFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Writes the JB2 layer of page `page_num`.
+    pub(crate) fn write_mask(&self, page_num: u32, mask: BitImage) -> Result<()> {
+        self.write_layer(page_num, "mask", LoadedImage::Bilevel(mask))
+    }
+
+    /// Writes a picture layer (`"background"` or `"foreground"`) of page
+    /// `page_num`, and with `hidden` (1 where the picture does not show) its
+    /// `-hidden` map.
+    pub(crate) fn write_picture(
+        &self,
+        page_num: u32,
+        layer: &str,
+        image: Pixmap,
+        hidden: Option<&Bitmap>,
+        params: &PageEncodeParams,
+    ) -> Result<()> {
+        self.write_layer(page_num, layer, picture(image, params))?;
+        if let Some(hidden) = hidden {
+            let (width, height) = hidden.dimensions();
+            let mut map = BitImage::new(width, height).map_err(|e| {
+                DjvuError::InvalidOperation(format!("Failed to allocate hidden map: {e}"))
+            })?;
+            for (i, pixel) in hidden.pixels().iter().enumerate() {
+                let (x, y) = (i % width as usize, i / width as usize);
+                map.set_usize(x, y, pixel.y != 0);
+            }
+            let name = format!("{layer}-hidden");
+            self.write_layer(page_num, &name, LoadedImage::Bilevel(map))?;
+        }
+        Ok(())
+    }
+
+    fn write_layer(&self, page_num: u32, layer: &str, image: LoadedImage) -> Result<()> {
+        std::fs::create_dir_all(&self.dir).context(self.dir.display())?;
+        let extension = match (self.format, &image) {
+            #[cfg(feature = "png")]
+            (DumpFormat::Png, _) => "png",
+            (DumpFormat::Pnm, LoadedImage::Bilevel(_)) => "pbm",
+            (DumpFormat::Pnm, LoadedImage::Gray(_)) => "pgm",
+            (DumpFormat::Pnm, LoadedImage::Rgb(_)) => "ppm",
+        };
+        let path = self.dir.join(format!("p{page_num:04}-{layer}.{extension}"));
+        let mut data = Vec::new();
+        match self.format {
+            #[cfg(feature = "png")]
+            DumpFormat::Png => crate::image::image_formats::write_png(&mut data, &image)?,
+            DumpFormat::Pnm => write_pnm(&mut data, &image)?,
+        }
+        std::fs::write(&path, data).context(path.display())
+    }
+}
+
+/// A picture as the IW44 encoder sees it, gray unless `params.color`.
+fn picture(image: Pixmap, params: &PageEncodeParams) -> LoadedImage {
+    if params.color {
+        LoadedImage::Rgb(image)
+    } else {
+        LoadedImage::Gray(image.to_bitmap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::page_encoder::PageComponents;
+    use crate::image::fill::HoleFill;
+    use crate::image::image_formats::{Pixel, load_pnm};
+
+    #[test]
+    fn test_dump_layers() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // A red block on a blue page, hidden by the mask.
+        let blue = Pixel::new(0, 90, 180);
+        let mut background = Pixmap::from_pixel(30, 20, blue);
+        let mut mask = BitImage::new(30, 20).unwrap();
+        for y in 6..12 {
+            for x in 9..15 {
+                background.put_pixel(x, y, Pixel::new(255, 0, 0));
+                mask.set_usize(x as usize, y as usize, true);
+            }
+        }
+        let page = PageComponents::new_with_dimensions(30, 20)
+            .with_background(background)?
+            .with_mask(mask.clone())?;
+        let params = PageEncodeParams {
+            bg_subsample: 1,
+            solid_background: None,
+            hole_fill: HoleFill::Nearest,
+            debug_dump: Some(DebugDump::new(dir.path().join("layers"))),
+            ..PageEncodeParams::default()
+        };
+        let plain = PageEncodeParams {
+            debug_dump: None,
+            ..params.clone()
+        };
+        let plain = page.encode(&plain, 7, 300, 1, None)?;
+        assert_eq!(page.encode(&params, 7, 300, 1, None)?, plain);
+
+        let path = |name: &str| dir.path().join("layers").join(name);
+        for name in ["p0007-mask.pbm", "p0007-background-hidden.pbm"] {
+            match load_pnm(path(name))? {
+                LoadedImage::Bilevel(dumped) => assert_eq!(dumped, mask, "{name}"),
+                other => panic!("{name}: expected bilevel, got {other:?}"),
+            }
+        }
+        // The background is dumped as IW44 gets it: filled under the mask.
+        let background = load_pnm(path("p0007-background.ppm"))?.into_pixmap();
+        assert_eq!(background.dimensions(), (30, 20));
+        assert!(background.pixels().iter().all(|&p| p == blue));
+        assert!(!path("p0007-foreground.ppm").exists());
+        Ok(())
+    }
+}
@@ -1,11 +1,13 @@
-use crate::iff::bs_byte_stream::bzz_compress;
+use crate::iff::bs_byte_stream::{BzzOptions, bzz_compress_with, bzz_decompress};
 use crate::iff::byte_stream::{ByteStream, MemoryStream};
+use crate::iff::parse_mode::ParseLog;
 use crate::utils::error::{DjvuError, Result};
+use crate::utils::string::sanitize;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Write; // Added for write_all support
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 pub type PageId = String;
 
 // File types for DjVmDir
@@ -17,6 +19,19 @@ pub enum FileType {
     SharedAnno = 3,
 }
 
+/// Layout of the directory chunk written at the start of a bundled document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DirectoryFormat {
+    /// `DIRM` version 1, as written by current DjVuLibre.
+    #[default]
+    Dirm,
+    /// `DIRM` version 0, with each page size stored next to its offset, for
+    /// viewers predating version 1.
+    DirmV0,
+    /// Legacy `DIR0` chunk from the pre-DjVu 2 all-in-one format.
+    Dir0,
+}
+
 /// Represents a file record in a DjVmDir directory
 #[derive(Debug, Clone)]
 pub struct File {
@@ -43,8 +58,8 @@ impl File {
             offset: 0,
             size: 0,
             file_type,
-            has_name: name != id,
-            has_title: title != id,
+            has_name: !name.is_empty() && name != id,
+            has_title: !title.is_empty() && title != id,
             page_num: -1,
             valid_name: false,
             oldname: String::new(),
@@ -67,8 +82,8 @@ impl File {
             offset,
             size,
             file_type,
-            has_name: name != id,
-            has_title: title != id,
+            has_name: !name.is_empty() && name != id,
+            has_title: !title.is_empty() && title != id,
             page_num: -1,
             valid_name: false,
             oldname: String::new(),
@@ -86,17 +101,10 @@ impl File {
             .to_string();
             // Simplified check for native encoding compatibility
             // In real implementation, check against filesystem encoding
-            if retval.chars().any(|c| c.is_control() || c > '\x7F') {
-                let mut buf = String::new();
-                for c in retval.chars() {
-                    if c.is_control() || c > '\x7F' {
-                        buf.push_str(&format!("{:02X}", c as u8));
-                    } else {
-                        buf.push(c);
-                    }
-                }
-                self.oldname = std::mem::replace(&mut self.name, buf);
-                self.valid_name = true;
+            let encoded = percent_encode(&retval);
+            if encoded != retval {
+                self.oldname = std::mem::replace(&mut self.name, encoded);
+                self.has_name = self.name != self.id;
             }
             self.valid_name = true;
             self.name.clone()
@@ -123,11 +131,14 @@ impl File {
     pub fn set_load_name(&mut self, id: &str) {
         // Simplified: assumes id is the filename part of a URL
         self.id = id.to_string();
+        self.has_name = !self.name.is_empty() && self.name != self.id;
+        self.has_title = !self.title.is_empty() && self.title != self.id;
     }
 
     /// Sets the save name, resetting validity
     pub fn set_save_name(&mut self, name: &str) {
         self.valid_name = false;
+        self.has_name = !name.is_empty() && name != self.id;
         self.name = name.to_string();
         self.oldname = String::new();
     }
@@ -143,6 +154,7 @@ impl File {
 
     /// Sets the title
     pub fn set_title(&mut self, title: &str) {
+        self.has_title = !title.is_empty() && title != self.id;
         self.title = title.to_string();
     }
 
@@ -182,6 +194,66 @@ impl File {
     }
 }
 
+/// Checks that the file ID `id` can name a file in an output directory or
+/// archive: not empty, without path separators, and not `.` or `..`.
+pub fn check_file_name(id: &str) -> Result<()> {
+    if id.is_empty() || id == "." || id == ".." || id.contains(['/', '\\', '\0']) {
+        return Err(DjvuError::InvalidArg(format!(
+            "File ID {id:?} cannot be used as a file name"
+        )));
+    }
+    Ok(())
+}
+
+/// Percent-encodes every byte of `s` (as UTF-8) outside the URL unreserved
+/// set (letters, digits, `-._~`), so the result is usable as a file name
+/// and as a relative URL.
+///
+/// A `%` that already starts an escape (`%` and two hex digits) is kept, so
+/// encoding a name twice changes nothing.
+fn percent_encode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = String::with_capacity(s.len());
+    for (i, &b) in bytes.iter().enumerate() {
+        let escape = b == b'%'
+            && bytes
+                .get(i + 1..i + 3)
+                .is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+        if escape || b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+/// Returns `name`, or `stem_N.ext` with the smallest `N` for which `taken`
+/// is false.
+fn unique_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(name) {
+        return name.to_string();
+    }
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    (1..)
+        .map(|n| format!("{stem}_{n}{ext}"))
+        .find(|candidate| !taken(candidate))
+        .unwrap()
+}
+
+/// One file changed by [`DjVmDir::resolve_duplicates`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRename {
+    pub old_id: String,
+    pub new_id: String,
+    /// Save name before and after (the ID when no name was set).
+    pub old_name: String,
+    pub new_name: String,
+}
+
 /// Directory for a multipage DjVu document (DIRM chunk)
 pub struct DjVmDir {
     data: Mutex<DjVmDirData>,
@@ -198,13 +270,13 @@ pub struct DjVmDirData {
 impl Clone for DjVmDir {
     fn clone(&self) -> Self {
         DjVmDir {
-            data: Mutex::new(self.data.lock().unwrap().clone()),
+            data: Mutex::new(self.lock().clone()),
         }
     }
 }
 
 impl DjVmDir {
-    const VERSION: u8 = 1;
+    pub(crate) const VERSION: u8 = 1;
 
     pub fn new() -> Arc<Self> {
         Arc::new(DjVmDir {
@@ -212,14 +284,18 @@ impl DjVmDir {
         })
     }
 
+    /// The directory's contents. Every update leaves them consistent, so a
+    /// lock poisoned by a panic elsewhere is used as it is.
+    fn lock(&self) -> MutexGuard<'_, DjVmDirData> {
+        self.data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
     pub fn get_files_list(&self) -> Vec<Arc<File>> {
-        self.data.lock().unwrap().files_list.clone()
+        self.lock().files_list.clone()
     }
 
     pub fn get_files_ids(&self) -> Vec<String> {
-        self.data
-            .lock()
-            .unwrap()
+        self.lock()
             .files_list
             .iter()
             .map(|f| f.id.clone())
@@ -227,13 +303,11 @@ impl DjVmDir {
     }
 
     pub fn get_pages_num(&self) -> usize {
-        self.data.lock().unwrap().page2file.len()
+        self.lock().page2file.len()
     }
 
     pub fn get_shared_anno_file(&self) -> Option<Arc<File>> {
-        self.data
-            .lock()
-            .unwrap()
+        self.lock()
             .files_list
             .iter()
             .find(|f| f.is_shared_anno())
@@ -241,9 +315,11 @@ impl DjVmDir {
     }
 
     pub fn set_file_title(&self, id: &str, title: &str) -> Result<()> {
-        let mut data = self.data.lock().unwrap();
-        if let Some(file) = data.id2file.get_mut(id) {
-            Arc::get_mut(file).unwrap().set_title(title);
+        let mut data = self.lock();
+        if let Some(old) = data.id2file.get(id).cloned() {
+            let mut file = (*old).clone();
+            file.set_title(title);
+            Self::replace_file(&mut data, &old, Arc::new(file));
             Ok(())
         } else {
             Err(DjvuError::InvalidArg(format!("File not found: {}", id)))
@@ -251,7 +327,14 @@ impl DjVmDir {
     }
 
     pub fn add_file(&self, file: Arc<File>) {
-        let mut data = self.data.lock().unwrap();
+        let mut data = self.lock();
+        let file = if file.is_page() {
+            let mut file = Arc::unwrap_or_clone(file);
+            file.page_num = data.page2file.len() as i32;
+            Arc::new(file)
+        } else {
+            file
+        };
         let file_id = file.id.clone();
         let file_name = file.name.clone();
 
@@ -260,28 +343,18 @@ impl DjVmDir {
         data.name2file.insert(file_name, Arc::clone(&file));
 
         if file.is_page() {
-            let page_num = data.page2file.len() as i32;
-            // Safely get the last file and set its page number
-            if let Some(last_file) = data.files_list.last_mut() {
-                if let Some(file_mut) = Arc::get_mut(last_file) {
-                    file_mut.page_num = page_num;
-                }
-            }
             data.page2file.push(file);
         }
     }
 
     pub fn remove_file(&self, id: &str) -> Option<Arc<File>> {
-        let mut data = self.data.lock().unwrap();
+        let mut data = self.lock();
         if let Some(file) = data.id2file.remove(id) {
             data.name2file.remove(&file.name);
             data.files_list.retain(|f| f.id != id);
             if file.is_page() {
                 data.page2file.retain(|f| f.id != id);
-                // Re-number pages
-                for (i, page_file) in data.page2file.iter_mut().enumerate() {
-                    Arc::get_mut(page_file).unwrap().page_num = i as i32;
-                }
+                Self::renumber_pages(&mut data);
             }
             Some(file)
         } else {
@@ -290,7 +363,7 @@ impl DjVmDir {
     }
 
     pub fn move_file_to_page_pos(&self, id: &str, new_pos: usize) -> Result<()> {
-        let mut data = self.data.lock().unwrap();
+        let mut data = self.lock();
 
         let file_idx = data
             .files_list
@@ -312,16 +385,14 @@ impl DjVmDir {
             .page2file
             .iter()
             .position(|f| Arc::ptr_eq(f, &file))
-            .unwrap();
+            .ok_or_else(|| DjvuError::Stream(format!("Page {id} missing from the page list")))?;
         data.page2file.remove(old_page_pos);
 
         let new_pos = new_pos.min(data.page2file.len());
         data.page2file.insert(new_pos, Arc::clone(&file));
 
         // Update page_num for all affected pages
-        for i in 0..data.page2file.len() {
-            Arc::get_mut(&mut data.page2file[i]).unwrap().page_num = i as i32;
-        }
+        Self::renumber_pages(&mut data);
 
         // Re-insert into files_list at an appropriate position (e.g., after other pages)
         // This part might need more sophisticated logic depending on how files_list is used.
@@ -342,20 +413,60 @@ impl DjVmDir {
         bundled: bool,
         _do_rename: bool,
     ) -> Result<()> {
-        let data = self.data.lock().unwrap();
+        self.encode_version(stream, bundled, Self::VERSION)
+    }
+
+    /// Encodes the directory in DIRM format `version` (0 or 1).
+    ///
+    /// Version 0 stores each size unencoded next to its offset and uses the
+    /// old flag layout (0x01 = page, 0x02 = has_name, 0x04 = has_title); it
+    /// can only describe pages and included files.
+    pub fn encode_version(
+        &self,
+        stream: &mut dyn ByteStream,
+        bundled: bool,
+        version: u8,
+    ) -> Result<()> {
+        // 50KB blocks are plenty for a directory
+        let bzz = BzzOptions {
+            block_size_k: 50,
+            ..BzzOptions::default()
+        };
+        self.encode_with(stream, bundled, version, &bzz)
+    }
+
+    /// Like [`DjVmDir::encode_version`], compressing the file records with
+    /// `bzz`.
+    pub fn encode_with(
+        &self,
+        stream: &mut dyn ByteStream,
+        bundled: bool,
+        version: u8,
+        bzz: &BzzOptions,
+    ) -> Result<()> {
+        if version > Self::VERSION {
+            return Err(DjvuError::InvalidArg(format!(
+                "Unsupported DIRM version {version}"
+            )));
+        }
+        let data = self.lock();
 
         // Write unencoded header
-        stream.write_u8(Self::VERSION | if bundled { 0x80 } else { 0 })?;
+        stream.write_u8(version | if bundled { 0x80 } else { 0 })?;
         stream.write_u16(data.files_list.len() as u16)?;
 
         if data.files_list.is_empty() {
             return Ok(());
         }
 
-        // Write offsets (unencoded, only for bundled documents)
+        // Write offsets (unencoded, only for bundled documents); version 0
+        // follows each with the size as INT24
         if bundled {
             for file in &data.files_list {
                 stream.write_u32(file.offset)?;
+                if version == 0 {
+                    stream.write_u24(file.size)?;
+                }
             }
         }
 
@@ -363,41 +474,200 @@ impl DjVmDir {
         let mut bzz_buffer = MemoryStream::new();
 
         // 1. Write sizes (3 bytes each, as INT24)
-        for file in &data.files_list {
-            // Write size as 3-byte big-endian integer (INT24)
-            let size = file.size;
-            ByteStream::write_u8(&mut bzz_buffer, (size >> 16) as u8)?;
-            ByteStream::write_u8(&mut bzz_buffer, (size >> 8) as u8)?;
-            ByteStream::write_u8(&mut bzz_buffer, size as u8)?;
+        if version > 0 {
+            for file in &data.files_list {
+                // Write size as 3-byte big-endian integer (INT24)
+                let size = file.size;
+                ByteStream::write_u8(&mut bzz_buffer, (size >> 16) as u8)?;
+                ByteStream::write_u8(&mut bzz_buffer, (size >> 8) as u8)?;
+                ByteStream::write_u8(&mut bzz_buffer, size as u8)?;
+            }
         }
 
-        // 2. Write flags (1 byte each)
+        // 2. Write flags (1 byte each): type in the low bits, then
+        //    0x80 = has_name, 0x40 = has_title
         for file in &data.files_list {
-            let flags = match file.file_type {
-                FileType::Page => 0x01,
-                FileType::Include => 0x00,
-                FileType::Thumbnails => 0x02,
-                FileType::SharedAnno => 0x03,
+            let flags = if version == 0 {
+                let mut flags = match file.file_type {
+                    FileType::Page => 0x01,
+                    FileType::Include => 0x00,
+                    _ => {
+                        return Err(DjvuError::InvalidArg(format!(
+                            "DIRM version 0 cannot describe {} files",
+                            file.get_str_type()
+                        )));
+                    }
+                };
+                if file.has_name {
+                    flags |= 0x02;
+                }
+                if file.has_title {
+                    flags |= 0x04;
+                }
+                flags
+            } else {
+                let mut flags = match file.file_type {
+                    FileType::Page => 0x01,
+                    FileType::Include => 0x00,
+                    FileType::Thumbnails => 0x02,
+                    FileType::SharedAnno => 0x03,
+                };
+                if file.has_name {
+                    flags |= 0x80;
+                }
+                if file.has_title {
+                    flags |= 0x40;
+                }
+                flags
             };
             ByteStream::write_u8(&mut bzz_buffer, flags)?;
         }
 
-        // 3. Write zero-terminated IDs
+        // 3. Write zero-terminated IDs, each followed by its name and title
+        //    when the corresponding flag is set
         for file in &data.files_list {
             bzz_buffer.write_all(file.id.as_bytes())?;
             ByteStream::write_u8(&mut bzz_buffer, 0)?; // Null terminator
+            if file.has_name {
+                bzz_buffer.write_all(file.name.as_bytes())?;
+                ByteStream::write_u8(&mut bzz_buffer, 0)?;
+            }
+            if file.has_title {
+                bzz_buffer.write_all(file.title.as_bytes())?;
+                ByteStream::write_u8(&mut bzz_buffer, 0)?;
+            }
         }
 
         // Use proper BZZ compression for the DIRM data according to DjVu spec
-        let compressed = bzz_compress(bzz_buffer.as_slice(), 50)?; // 50KB block size for small DIRM
+        let compressed = bzz_compress_with(bzz_buffer.as_slice(), bzz)?;
 
         stream.write_all(&compressed)?;
 
         Ok(())
     }
 
+    /// Reads a `DIRM` chunk payload of either version, returning the
+    /// directory and its version.
+    ///
+    /// Offsets are only present in bundled directories; indirect ones
+    /// decode with every offset 0.
+    pub fn decode(data: &[u8]) -> Result<(Arc<Self>, u8)> {
+        Self::decode_with(data, &mut ParseLog::default())
+    }
+
+    /// Like [`Self::decode`], in the mode of `log`.
+    ///
+    /// A permissive decode reads directories of a newer version as the
+    /// latest it knows, takes unknown file types for include files, falls
+    /// back to the ID for missing names and titles, and keeps the files
+    /// listed before the names run out, noting each in `log`.
+    pub fn decode_with(data: &[u8], log: &mut ParseLog) -> Result<(Arc<Self>, u8)> {
+        let truncated = || DjvuError::ValidationError("Truncated DIRM chunk".to_string());
+        let &[head, count_hi, count_lo, ref rest @ ..] = data else {
+            return Err(truncated());
+        };
+        let mut version = head & 0x7f;
+        if version > Self::VERSION {
+            log.violation(0, format!("Unsupported DIRM version {version}"))?;
+            version = Self::VERSION;
+        }
+        let bundled = head & 0x80 != 0;
+        let count = u16::from_be_bytes([count_hi, count_lo]) as usize;
+        let dir = Self::new();
+        if count == 0 {
+            return Ok((dir, version));
+        }
+
+        let be = |b: &[u8]| b.iter().fold(0u32, |n, &b| n << 8 | b as u32);
+        let mut offsets = vec![0u32; count];
+        let mut sizes = vec![0u32; count];
+        let mut pos = 0;
+        if bundled {
+            let stride = if version == 0 { 7 } else { 4 };
+            let table = rest.get(..count * stride).ok_or_else(truncated)?;
+            for (i, entry) in table.chunks_exact(stride).enumerate() {
+                offsets[i] = be(&entry[..4]);
+                if version == 0 {
+                    sizes[i] = be(&entry[4..]);
+                }
+            }
+            pos = table.len();
+        }
+
+        let meta_offset = 3 + pos;
+        let meta = bzz_decompress(&rest[pos..])?;
+        let mut pos = 0;
+        if version > 0 {
+            let table = meta.get(..count * 3).ok_or_else(truncated)?;
+            for (size, entry) in sizes.iter_mut().zip(table.chunks_exact(3)) {
+                *size = be(entry);
+            }
+            pos = table.len();
+        }
+        let flags = meta.get(pos..pos + count).ok_or_else(truncated)?;
+        pos += count;
+        // Every string ends in a NUL, so a missing last one shows.
+        let names = &meta[pos..];
+        let mut strings = names
+            .strip_suffix(&[0])
+            .unwrap_or(names)
+            .split(|&b| b == 0)
+            .take(if names.is_empty() { 0 } else { usize::MAX });
+        let mut next_string = || {
+            strings
+                .next()
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+        };
+
+        for i in 0..count {
+            let (file_type, has_name, has_title) = if version == 0 {
+                let file_type = if flags[i] & 0x01 != 0 {
+                    FileType::Page
+                } else {
+                    FileType::Include
+                };
+                (file_type, flags[i] & 0x02 != 0, flags[i] & 0x04 != 0)
+            } else {
+                let file_type = match flags[i] & 0x3f {
+                    0 => FileType::Include,
+                    1 => FileType::Page,
+                    2 => FileType::Thumbnails,
+                    3 => FileType::SharedAnno,
+                    other => {
+                        log.violation(meta_offset, format!("Unknown DIRM file type {other}"))?;
+                        FileType::Include
+                    }
+                };
+                (file_type, flags[i] & 0x80 != 0, flags[i] & 0x40 != 0)
+            };
+            let Some(id) = next_string() else {
+                log.violation(meta_offset, format!("DIRM names {i} of {count} files"))?;
+                break;
+            };
+            let mut name_or_id = |present: bool, what: &str| -> Result<String> {
+                if !present {
+                    return Ok(id.clone());
+                }
+                match next_string() {
+                    Some(s) => Ok(s),
+                    None => {
+                        log.violation(meta_offset, format!("DIRM lacks the {what} of {id}"))?;
+                        Ok(id.clone())
+                    }
+                }
+            };
+            let name = name_or_id(has_name, "name")?;
+            let title = name_or_id(has_title, "title")?;
+            dir.insert_file(
+                File::new_with_offset(&id, &name, &title, file_type, offsets[i], sizes[i]),
+                -1,
+            )?;
+        }
+        Ok((dir, version))
+    }
+
     pub fn encode(&self, stream: &mut dyn ByteStream, do_rename: bool) -> Result<()> {
-        let data = self.data.lock().unwrap();
+        let data = self.lock();
         let bundled = data.files_list.iter().all(|f| f.offset > 0);
         if data.files_list.iter().any(|f| (f.offset > 0) != bundled) {
             return Err(DjvuError::Stream(
@@ -411,7 +681,7 @@ impl DjVmDir {
         if page_num < 0 {
             return None;
         }
-        let data = self.data.lock().unwrap();
+        let data = self.lock();
         if page_num as usize >= data.page2file.len() {
             return None;
         }
@@ -423,14 +693,14 @@ impl DjVmDir {
             DjvuError::InvalidOperation(format!("Page number {} not found", page_num))
         })?;
 
-        let data = self.data.lock().unwrap();
+        let data = self.lock();
         data.id2file.get(&page_id).cloned().ok_or_else(|| {
             DjvuError::InvalidOperation(format!("File for page {} not found", page_num))
         })
     }
 
     pub fn pos_to_file(&self, fileno: i32) -> Option<(Arc<File>, Option<i32>)> {
-        let data = self.data.lock().unwrap();
+        let data = self.lock();
         if fileno < 0 || fileno as usize >= data.files_list.len() {
             return None;
         }
@@ -451,7 +721,7 @@ impl DjVmDir {
 
     /// Gets the position of a file in the files list
     pub fn get_file_pos(&self, file: &File) -> Option<usize> {
-        let data = self.data.lock().unwrap();
+        let data = self.lock();
         data.files_list
             .iter()
             .position(|f| Arc::ptr_eq(f, &Arc::new(file.clone())))
@@ -463,7 +733,7 @@ impl DjVmDir {
     }
     /// Deletes a file by ID
     pub fn delete_file(&self, id: &str) -> Result<()> {
-        let mut data = self.data.lock().unwrap();
+        let mut data = self.lock();
         if let Some(pos) = data.files_list.iter().position(|f| f.id == id) {
             let file = data.files_list.remove(pos);
             data.name2file.remove(&file.name);
@@ -471,9 +741,7 @@ impl DjVmDir {
             if file.is_page() {
                 if let Some(page_pos) = data.page2file.iter().position(|f| Arc::ptr_eq(f, &file)) {
                     data.page2file.remove(page_pos);
-                    for i in page_pos..data.page2file.len() {
-                        Arc::get_mut(&mut data.page2file[i]).unwrap().page_num = i as i32;
-                    }
+                    Self::renumber_pages(&mut data);
                 }
             }
             Ok(())
@@ -484,48 +752,109 @@ impl DjVmDir {
 
     // Second implementation of move_file_to_page_pos removed to fix duplicate function error
 
-    /// Resolves duplicate file names in the directory
-    pub fn resolve_duplicates(&self, _save_names_only: bool) -> Vec<Arc<File>> {
-        let data = self.data.lock().unwrap();
-        let mut result = Vec::new();
+    /// Makes save names unique and renames the files in place
+    ///
+    /// Save names are compared case-insensitively, since they may end up on
+    /// case-insensitive file systems; a clash gets a `_N` suffix before the
+    /// extension. Unless `save_names_only` is set, each ID is replaced by its
+    /// save name, as DjVuLibre does when splitting a bundle. For `indirect`
+    /// documents, where IDs and names are relative URLs of separate files,
+    /// non-ASCII characters in both are percent-encoded.
+    ///
+    /// Returns the files that changed, in directory order, so callers can
+    /// update references to the old IDs (e.g. in bookmarks and hyperlinks).
+    pub fn resolve_duplicates(&self, save_names_only: bool, indirect: bool) -> Vec<FileRename> {
+        let mut data = self.lock();
+        let mut taken_names = HashSet::new();
+        let mut taken_ids = HashSet::new();
+        let mut renames = Vec::new();
+
+        for file in data.files_list.iter_mut() {
+            let old_id = file.id.clone();
+            let old_name = file.get_save_name();
+            let encode = |s: &str| {
+                if indirect {
+                    percent_encode(s)
+                } else {
+                    s.to_string()
+                }
+            };
 
-        for file in &data.files_list {
-            // Create a new File with the same properties
-            let new_file = File {
-                id: file.id.clone(),
-                name: file.name.clone(),
-                title: file.title.clone(),
-                file_type: file.file_type.clone(),
-                size: file.size,
-                offset: file.offset,
-                has_name: file.has_name,
-                has_title: file.has_title,
-                page_num: file.page_num,
-                valid_name: file.valid_name,
-                oldname: file.oldname.clone(),
+            let name = unique_name(&encode(&old_name), |n| {
+                taken_names.contains(&n.to_lowercase())
+            });
+            taken_names.insert(name.to_lowercase());
+            let id = if save_names_only {
+                unique_name(&encode(&old_id), |n| taken_ids.contains(n))
+            } else {
+                unique_name(&name, |n| taken_ids.contains(n))
             };
+            taken_ids.insert(id.clone());
+
+            if id != old_id || name != old_name {
+                let file = Arc::make_mut(file);
+                file.set_load_name(&id);
+                file.set_save_name(&name);
+                file.valid_name = true;
+                renames.push(FileRename {
+                    old_id,
+                    new_id: id,
+                    old_name,
+                    new_name: name,
+                });
+            }
+        }
 
-            // Create a new Arc with the new File
-            let new_arc = Arc::new(new_file);
+        if !renames.is_empty() {
+            Self::rebuild_indexes(&mut data);
+        }
+        renames
+    }
 
-            // Now we can add the Arc to our result
-            result.push(new_arc);
+    /// Puts `new` in place of `old` in the file list, page list and maps.
+    fn replace_file(data: &mut DjVmDirData, old: &Arc<File>, new: Arc<File>) {
+        let lists = data.files_list.iter_mut().chain(data.page2file.iter_mut());
+        let maps = data.id2file.values_mut().chain(data.name2file.values_mut());
+        for slot in lists.chain(maps).filter(|f| Arc::ptr_eq(f, old)) {
+            *slot = Arc::clone(&new);
         }
+    }
 
-        // Note: This implementation doesn't actually check for duplicates
-        // You'll need to implement that logic separately
-        result
+    /// Sets each page's `page_num` to its position in the page list.
+    fn renumber_pages(data: &mut DjVmDirData) {
+        for i in 0..data.page2file.len() {
+            let old = Arc::clone(&data.page2file[i]);
+            if old.page_num != i as i32 {
+                let mut file = (*old).clone();
+                file.page_num = i as i32;
+                Self::replace_file(data, &old, Arc::new(file));
+            }
+        }
+    }
+
+    /// Rebuilds the lookup maps and page list from `files_list`.
+    fn rebuild_indexes(data: &mut DjVmDirData) {
+        data.id2file.clear();
+        data.name2file.clear();
+        data.page2file.clear();
+        for file in &data.files_list {
+            data.id2file.insert(file.id.clone(), Arc::clone(file));
+            data.name2file.insert(file.name.clone(), Arc::clone(file));
+            if file.is_page() {
+                data.page2file.push(Arc::clone(file));
+            }
+        }
     }
 
     /// Gets a file by its ID
     pub fn get_file_by_id(&self, id: &str) -> Option<Arc<File>> {
-        let data = self.data.lock().unwrap();
+        let data = self.lock();
         data.id2file.get(id).cloned()
     }
 
     /// Inserts a file at a specific position
     pub fn insert_file(&self, file: Arc<File>, pos: i32) -> Result<()> {
-        let mut data = self.data.lock().unwrap();
+        let mut data = self.lock();
 
         // Check if file already exists
         if data.id2file.contains_key(&file.id) {
@@ -574,7 +903,7 @@ impl DjVmDir {
         let new_dir = DjVmDir::new();
 
         // Get the current data
-        let data = self.data.lock().unwrap();
+        let data = self.lock();
 
         // Copy all files with updated offsets
         for file in &data.files_list {
@@ -604,6 +933,7 @@ impl DjVmDir {
 }
 
 /// Directory for an older DjVu all-in-one-file format (DIR0 chunk)
+#[derive(Default)]
 pub struct DjVmDir0 {
     name2file: HashMap<String, Arc<FileRec>>,
     num2file: Vec<Arc<FileRec>>,
@@ -702,20 +1032,22 @@ impl DjVmDir0 {
     }
 }
 
-// Navigation/bookmark structures (previously in djvu_nav.rs)
+// Navigation/bookmark structures (the NDIR directory lives in djvu_nav.rs)
 
 /// Represents a single bookmark entry.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Bookmark {
     pub title: String,
     /// Destination URL, typically a page ID like "#1".
     pub dest: String,
     /// Nested bookmarks.
+    #[cfg_attr(feature = "serde", serde(default))]
     pub children: Vec<Bookmark>,
 }
 
 /// Represents the entire navigation/bookmark structure (`NAVM` chunk).
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct DjVmNav {
     pub bookmarks: Vec<Bookmark>,
 }
@@ -739,9 +1071,10 @@ impl DjVmNav {
     }
 
     /// Writes a 24-bit big-endian integer
-    fn write_int24<W: std::io::Write>(writer: &mut W, value: u32) -> std::io::Result<()> {
+    fn write_int24<W: std::io::Write>(writer: &mut W, value: u32) -> Result<()> {
         // INT24 is 3 bytes big-endian
-        writer.write_all(&[(value >> 16) as u8, (value >> 8) as u8, value as u8])
+        writer.write_all(&[(value >> 16) as u8, (value >> 8) as u8, value as u8])?;
+        Ok(())
     }
 
     /// Encodes the navigation data into the binary format required for a `NAVM` chunk.
@@ -765,6 +1098,58 @@ impl DjVmNav {
         Ok(())
     }
 
+    /// Parses a `NAVM` payload, after BZZ decompression; the inverse of
+    /// [`Self::encode`].
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let truncated = || DjvuError::ValidationError("Truncated NAVM chunk".to_string());
+        let mut pos = 0;
+        let mut take = |len: usize| -> Result<&[u8]> {
+            let bytes = data.get(pos..pos + len).ok_or_else(truncated)?;
+            pos += len;
+            Ok(bytes)
+        };
+        let Ok(head) = take(2) else {
+            return Ok(Self::new());
+        };
+        let mut remaining = u16::from_be_bytes([head[0], head[1]]) as usize;
+
+        fn read_bookmark<'a>(
+            take: &mut impl FnMut(usize) -> Result<&'a [u8]>,
+            remaining: &mut usize,
+        ) -> Result<Bookmark> {
+            if *remaining == 0 {
+                return Err(DjvuError::ValidationError(
+                    "NAVM holds more bookmarks than its count".to_string(),
+                ));
+            }
+            *remaining -= 1;
+            let children = take(1)?[0] as usize;
+            let mut string = || -> Result<String> {
+                let len = take(3)?;
+                let len = u32::from_be_bytes([0, len[0], len[1], len[2]]) as usize;
+                String::from_utf8(take(len)?.to_vec()).map_err(|_| {
+                    DjvuError::ValidationError("NAVM string is not valid UTF-8".to_string())
+                })
+            };
+            let title = string()?;
+            let dest = string()?;
+            let children = (0..children)
+                .map(|_| read_bookmark(take, remaining))
+                .collect::<Result<_>>()?;
+            Ok(Bookmark {
+                title,
+                dest,
+                children,
+            })
+        }
+
+        let mut bookmarks = Vec::new();
+        while remaining > 0 {
+            bookmarks.push(read_bookmark(&mut take, &mut remaining)?);
+        }
+        Ok(Self { bookmarks })
+    }
+
     fn encode_bookmark_binary<W: std::io::Write>(
         &self,
         bookmark: &Bookmark,
@@ -774,14 +1159,16 @@ impl DjVmNav {
         writer.write_all(&[bookmark.children.len() as u8])?;
 
         // nDesc: INT24 (size of description/title)
-        let title_bytes = bookmark.title.as_bytes();
+        let title = sanitize(&bookmark.title);
+        let title_bytes = title.as_bytes();
         Self::write_int24(writer, title_bytes.len() as u32)?;
 
         // sDesc: UTF8 string (the title)
         writer.write_all(title_bytes)?;
 
         // nURL: INT24 (size of URL)
-        let url_bytes = bookmark.dest.as_bytes();
+        let dest = sanitize(&bookmark.dest);
+        let url_bytes = dest.as_bytes();
         Self::write_int24(writer, url_bytes.len() as u32)?;
 
         // sURL: UTF8 string (the URL/destination)
@@ -795,3 +1182,385 @@ impl DjVmNav {
         Ok(())
     }
 }
+
+// Text outline format (djvused `set-outline` / `print-outline`)
+
+impl DjVmNav {
+    /// Parses a djvused-style outline:
+    ///
+    /// ```text
+    /// (bookmarks
+    ///  ("Chapter 1" "#1"
+    ///   ("Section 1.1" "#2"))
+    ///  ("Chapter 2" "#5"))
+    /// ```
+    ///
+    /// The `(bookmarks ...)` wrapper is optional. Strings accept the djvused
+    /// escapes (`\"`, `\\`, `\n`, `\t`, and octal `\ooo` bytes).
+    pub fn from_outline(text: &str) -> Result<Self> {
+        let mut parser = OutlineParser {
+            src: text.as_bytes(),
+            pos: 0,
+        };
+        let mut bookmarks = Vec::new();
+        parser.skip_ws();
+        if parser.peek_list_head("bookmarks") {
+            parser.pos += 1;
+            parser.skip_ws();
+            parser.pos += "bookmarks".len();
+            parser.parse_entries(&mut bookmarks)?;
+            parser.expect(b')')?;
+        } else {
+            parser.parse_entries(&mut bookmarks)?;
+        }
+        parser.skip_ws();
+        if parser.pos != parser.src.len() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+        Ok(Self { bookmarks })
+    }
+
+    /// Serializes the bookmarks in the format read by [`Self::from_outline`].
+    pub fn to_outline(&self) -> String {
+        fn quote(out: &mut String, s: &str) {
+            out.push('"');
+            for c in s.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    '\t' => out.push_str("\\t"),
+                    c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                        out.push_str(&format!("\\{:03o}", c as u32));
+                    }
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+        fn write_entry(out: &mut String, bookmark: &Bookmark, depth: usize) {
+            out.push('\n');
+            out.push_str(&" ".repeat(depth));
+            out.push('(');
+            quote(out, &bookmark.title);
+            out.push(' ');
+            quote(out, &bookmark.dest);
+            for child in &bookmark.children {
+                write_entry(out, child, depth + 1);
+            }
+            out.push(')');
+        }
+
+        let mut out = String::from("(bookmarks");
+        for bookmark in &self.bookmarks {
+            write_entry(&mut out, bookmark, 1);
+        }
+        out.push_str(")\n");
+        out
+    }
+}
+
+struct OutlineParser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl OutlineParser<'_> {
+    fn error(&self, msg: &str) -> DjvuError {
+        DjvuError::InvalidArg(format!("outline: {msg} at offset {}", self.pos))
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(&c) = self.src.get(self.pos) {
+            if c == b';' {
+                // Comment to end of line.
+                while self.src.get(self.pos).is_some_and(|&c| c != b'\n') {
+                    self.pos += 1;
+                }
+            } else if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        self.skip_ws();
+        if self.src.get(self.pos) != Some(&byte) {
+            return Err(self.error(&format!("expected '{}'", byte as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Checks for `(symbol` without consuming it.
+    fn peek_list_head(&self, symbol: &str) -> bool {
+        let mut p = self.pos;
+        if self.src.get(p) != Some(&b'(') {
+            return false;
+        }
+        p += 1;
+        while self.src.get(p).is_some_and(|c| c.is_ascii_whitespace()) {
+            p += 1;
+        }
+        self.src[p..].starts_with(symbol.as_bytes())
+            && self
+                .src
+                .get(p + symbol.len())
+                .is_none_or(|c| c.is_ascii_whitespace() || *c == b'(' || *c == b')')
+    }
+
+    /// Parses `("title" "dest" children...)` entries until a `)` or the end.
+    fn parse_entries(&mut self, out: &mut Vec<Bookmark>) -> Result<()> {
+        loop {
+            self.skip_ws();
+            match self.src.get(self.pos) {
+                Some(b'(') => {
+                    self.pos += 1;
+                    let title = self.parse_string()?;
+                    let dest = self.parse_string()?;
+                    let mut children = Vec::new();
+                    self.parse_entries(&mut children)?;
+                    self.expect(b')')?;
+                    out.push(Bookmark {
+                        title,
+                        dest,
+                        children,
+                    });
+                }
+                Some(b')') | None => return Ok(()),
+                Some(_) => return Err(self.error("expected '(' or ')'")),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            let c = *self
+                .src
+                .get(self.pos)
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let e = *self
+                        .src
+                        .get(self.pos)
+                        .ok_or_else(|| self.error("unterminated escape"))?;
+                    self.pos += 1;
+                    match e {
+                        b'n' => bytes.push(b'\n'),
+                        b't' => bytes.push(b'\t'),
+                        b'r' => bytes.push(b'\r'),
+                        b'0'..=b'7' => {
+                            let mut v = (e - b'0') as u32;
+                            for _ in 0..2 {
+                                match self.src.get(self.pos) {
+                                    Some(d @ b'0'..=b'7') => {
+                                        v = v * 8 + (d - b'0') as u32;
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            let byte = u8::try_from(v)
+                                .map_err(|_| self.error("octal escape above \\377"))?;
+                            bytes.push(byte);
+                        }
+                        other => bytes.push(other),
+                    }
+                }
+                other => bytes.push(other),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("string is not valid UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(id: &str, name: &str) -> Arc<File> {
+        File::new(id, name, "", FileType::Page)
+    }
+
+    #[test]
+    fn test_resolve_duplicates_renames_in_place() {
+        let dir = DjVmDir::new();
+        dir.insert_file(page("a.djvu", "page.djvu"), -1).unwrap();
+        dir.insert_file(page("b.djvu", "Page.djvu"), -1).unwrap();
+        dir.insert_file(page("c.djvu", ""), -1).unwrap();
+
+        let renames = dir.resolve_duplicates(true, false);
+        assert_eq!(
+            renames,
+            vec![FileRename {
+                old_id: "b.djvu".into(),
+                new_id: "b.djvu".into(),
+                old_name: "Page.djvu".into(),
+                new_name: "Page_1.djvu".into(),
+            }]
+        );
+        let stored = dir.get_file_by_id("b.djvu").unwrap();
+        assert_eq!(stored.get_save_name(), "Page_1.djvu");
+        assert!(stored.has_name);
+        assert_eq!(dir.page_to_file(1).unwrap().name, "Page_1.djvu");
+
+        // IDs follow the save names; nothing clashes any more.
+        let renames = dir.resolve_duplicates(false, false);
+        assert_eq!(renames.len(), 2);
+        assert_eq!(
+            dir.get_files_ids(),
+            vec!["page.djvu", "Page_1.djvu", "c.djvu"]
+        );
+        assert!(dir.get_file_by_id("a.djvu").is_none());
+        assert!(!dir.get_file_by_id("page.djvu").unwrap().has_name);
+    }
+
+    #[test]
+    fn test_edits_on_shared_files() {
+        let dir = DjVmDir::new();
+        for id in ["a.djvu", "b.djvu", "c.djvu"] {
+            dir.add_file(page(id, id));
+        }
+        // Files handed out earlier keep the directory's entries shared.
+        let held = dir.get_files_list();
+        dir.set_file_title("b.djvu", "Preface").unwrap();
+        assert_eq!(dir.get_file_by_id("b.djvu").unwrap().title, "Preface");
+        assert_eq!(held[1].title, "");
+
+        dir.delete_file("a.djvu").unwrap();
+        let pages: Vec<i32> = dir.get_files_list().iter().map(|f| f.page_num).collect();
+        assert_eq!(pages, [0, 1]);
+        assert_eq!(dir.page_to_file(0).unwrap().title, "Preface");
+        assert!(dir.set_file_title("a.djvu", "Gone").is_err());
+    }
+
+    #[test]
+    fn test_resolve_duplicates_percent_encodes_indirect_ids() {
+        let dir = DjVmDir::new();
+        dir.insert_file(page("café.djvu", ""), -1).unwrap();
+        dir.insert_file(page("plain_page-1~.djvu", ""), -1).unwrap();
+        dir.insert_file(page("a b#c?d%.djvu", ""), -1).unwrap();
+
+        assert!(dir.resolve_duplicates(true, false).is_empty());
+        let renames = dir.resolve_duplicates(true, true);
+        assert_eq!(renames.len(), 2);
+        assert_eq!(renames[1].new_id, "a%20b%23c%3Fd%25.djvu");
+        assert_eq!(renames[0].new_id, "caf%C3%A9.djvu");
+        assert_eq!(renames[0].new_name, "caf%C3%A9.djvu");
+        assert_eq!(dir.page_to_id(0).as_deref(), Some("caf%C3%A9.djvu"));
+
+        // Names already encoded are left as they are.
+        assert!(dir.resolve_duplicates(true, true).is_empty());
+        for name in ["café 100%.djvu", "a%2", "%41%zz%"] {
+            let once = percent_encode(name);
+            assert_eq!(percent_encode(&once), once, "{name}");
+        }
+        assert_eq!(percent_encode("%41%zz%"), "%41%25zz%25");
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        for version in [0, 1] {
+            let dir = DjVmDir::new();
+            for (i, (id, title)) in [("a.djvu", ""), ("b.djvu", "Preface"), ("c.djvu", "")]
+                .into_iter()
+                .enumerate()
+            {
+                let file =
+                    File::new_with_offset(id, id, title, FileType::Page, 100 * i as u32 + 16, 90);
+                dir.insert_file(file, -1).unwrap();
+            }
+            let mut stream = MemoryStream::new();
+            dir.encode_version(&mut stream, true, version).unwrap();
+
+            let (decoded, decoded_version) = DjVmDir::decode(stream.as_slice()).unwrap();
+            assert_eq!(decoded_version, version);
+            assert_eq!(decoded.get_files_ids(), dir.get_files_ids());
+            assert_eq!(decoded.get_pages_num(), 3);
+            let b = decoded.get_file_by_id("b.djvu").unwrap();
+            assert_eq!((b.offset, b.size, b.title.as_str()), (116, 90, "Preface"));
+        }
+        assert!(DjVmDir::decode(&[0x81, 0, 2, 0]).is_err());
+    }
+
+    #[test]
+    fn test_permissive_decode() {
+        // An indirect directory of one file, of unknown type 5, whose
+        // title is announced but missing.
+        let mut dirm = vec![0x01, 0, 1];
+        dirm.extend(
+            crate::iff::bs_byte_stream::bzz_compress(b"\0\0\x09\x45a.djvu\0", 100).unwrap(),
+        );
+        assert!(DjVmDir::decode(&dirm).is_err());
+
+        let mut log = ParseLog::new(crate::iff::ParseMode::Permissive);
+        let (dir, version) = DjVmDir::decode_with(&dirm, &mut log).unwrap();
+        assert_eq!(version, 1);
+        let file = dir.get_file_by_id("a.djvu").unwrap();
+        assert_eq!(file.file_type, FileType::Include);
+        assert_eq!(file.title, "a.djvu");
+        let messages: Vec<&str> = log.warnings().iter().map(|w| w.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            ["Unknown DIRM file type 5", "DIRM lacks the title of a.djvu"]
+        );
+
+        // A newer version is read as the latest known.
+        dirm[0] = 0x02;
+        assert!(DjVmDir::decode(&dirm).is_err());
+        let mut log = ParseLog::new(crate::iff::ParseMode::Permissive);
+        assert_eq!(DjVmDir::decode_with(&dirm, &mut log).unwrap().1, 1);
+        assert_eq!(log.warnings().len(), 3);
+    }
+
+    #[test]
+    fn test_outline_round_trip() {
+        let text = r##"(bookmarks
+ ("Chapter \"One\"" "#1"
+  ("Section 1.1" "#2"))
+ ; comment
+ ("Caf\303\251" "#p0005.djvu"))"##;
+        let nav = DjVmNav::from_outline(text).unwrap();
+        assert_eq!(nav.bookmarks.len(), 2);
+        assert_eq!(nav.bookmarks[0].title, "Chapter \"One\"");
+        assert_eq!(nav.bookmarks[0].children[0].dest, "#2");
+        assert_eq!(nav.bookmarks[1].title, "Café");
+
+        let reparsed = DjVmNav::from_outline(&nav.to_outline()).unwrap();
+        assert_eq!(reparsed, nav);
+    }
+
+    #[test]
+    fn test_navm_round_trip() -> Result<()> {
+        let nav = DjVmNav::from_outline(
+            r##"(bookmarks ("Part I" "#1" ("Chapter 1" "#2") ("Chapter 2" "#p0005.djvu")) ("Index" "#9"))"##,
+        )?;
+        let mut data = Vec::new();
+        nav.encode(&mut data)?;
+        assert_eq!(DjVmNav::decode(&data)?, nav);
+        assert_eq!(DjVmNav::decode(&[])?, DjVmNav::new());
+        assert!(DjVmNav::decode(&data[..data.len() - 1]).is_err());
+        // A count larger than the tree runs past the end.
+        data[1] += 1;
+        assert!(DjVmNav::decode(&data).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_outline_rejects_malformed_input() {
+        assert!(DjVmNav::from_outline("(bookmarks (\"a\" \"#1\")").is_err());
+        assert!(DjVmNav::from_outline("(\"a\")").is_err());
+        assert!(DjVmNav::from_outline("(\"a\" \"#1\") x").is_err());
+        // Octal escapes stop at \377, the largest byte.
+        assert!(DjVmNav::from_outline(r##"(bookmarks ("\400" "#1"))"##).is_err());
+        assert!(DjVmNav::from_outline(r##"(bookmarks ("\777" "#1"))"##).is_err());
+        assert_eq!(DjVmNav::from_outline("").unwrap(), DjVmNav::new());
+    }
+}
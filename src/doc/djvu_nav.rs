@@ -0,0 +1,280 @@
+//! Old-style navigation directory (`NDIR` chunk).
+//!
+//! Before `DIRM`, indirect documents were a set of single-page files that
+//! each included a shared `FORM:DJVI` holding an `NDIR` chunk: the list of
+//! page file names in document order, one per line. Legacy browser plugins
+//! still use it to move between pages, resolving each name against the URL
+//! of the directory file.
+//!
+//! [`DjVuNavDir`] writes that list and reads it back with the same rules as
+//! DjVuLibre's `DjVuNavDir::decode`: blank lines are skipped, repeated names
+//! keep their first position, and lines may not exceed 1023 bytes.
+
+use crate::doc::djvu_dir::DjVmDir;
+use crate::iff::chunk_id::ChunkId;
+use crate::iff::iff::IffWriter;
+use crate::{DjvuError, Result};
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+
+/// Chunk ID of the navigation directory.
+pub const NDIR_CHUNK_ID: ChunkId = ChunkId::NDIR;
+
+/// Longest line DjVuLibre's reader accepts, excluding the newline.
+const MAX_LINE: usize = 1023;
+
+/// Page-to-URL mapping of an indirect document.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DjVuNavDir {
+    base_url: String,
+    page2name: Vec<String>,
+    name2page: HashMap<String, usize>,
+}
+
+impl DjVuNavDir {
+    /// Creates an empty directory whose names resolve against `base_url`
+    /// (the URL of the directory file, or of the folder holding the pages).
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Builds the directory from the pages of a `DIRM` directory, using each
+    /// page's save name as its file name.
+    pub fn from_dir(dir: &DjVmDir, base_url: &str) -> Result<Self> {
+        let mut nav = Self::new(base_url);
+        for file in dir.get_files_list().iter().filter(|f| f.is_page()) {
+            nav.insert_page(None, &file.get_save_name())?;
+        }
+        Ok(nav)
+    }
+
+    /// Parses an `NDIR` payload.
+    pub fn decode(data: &[u8], base_url: &str) -> Result<Self> {
+        let mut nav = Self::new(base_url);
+        for line in data.split(|&b| b == b'\n') {
+            if line.len() > MAX_LINE {
+                return Err(DjvuError::ValidationError(format!(
+                    "NDIR line of {} bytes exceeds {MAX_LINE}",
+                    line.len()
+                )));
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let name = std::str::from_utf8(line).map_err(|_| {
+                DjvuError::ValidationError("NDIR name is not valid UTF-8".to_string())
+            })?;
+            if !nav.name2page.contains_key(name) {
+                nav.insert_page(None, name)?;
+            }
+        }
+        Ok(nav)
+    }
+
+    /// Writes the `NDIR` payload: one name per line.
+    pub fn encode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        for name in &self.page2name {
+            writer.write_all(name.as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Returns a complete `FORM:DJVI` file holding the `NDIR` chunk, for
+    /// pages to reference with an `INCL` chunk.
+    pub fn to_djvi(&self) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        self.encode(&mut payload)?;
+
+        let mut cursor = Cursor::new(Vec::new());
+        {
+            let mut writer = IffWriter::new(&mut cursor);
+            writer.write_magic_bytes()?;
+            writer.put_composite(ChunkId::FORM, ChunkId::DJVI)?;
+            writer.put_chunk(NDIR_CHUNK_ID)?;
+            writer.write_all(&payload)?;
+            writer.close_chunk()?;
+            writer.close_chunk()?;
+        }
+        Ok(cursor.into_inner())
+    }
+
+    /// Inserts `name` before page `page_num`, or appends it with `None`.
+    pub fn insert_page(&mut self, page_num: Option<usize>, name: &str) -> Result<()> {
+        if name.is_empty() || name.contains('\n') || name.len() > MAX_LINE {
+            return Err(DjvuError::InvalidArg(format!(
+                "Invalid NDIR page name {name:?}"
+            )));
+        }
+        if self.name2page.contains_key(name) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Page name {name:?} is already in the directory"
+            )));
+        }
+        let pos = match page_num {
+            Some(p) if p > self.page2name.len() => {
+                return Err(DjvuError::InvalidArg(format!(
+                    "Page {p} out of range 0..={}",
+                    self.page2name.len()
+                )));
+            }
+            Some(p) => p,
+            None => self.page2name.len(),
+        };
+        self.page2name.insert(pos, name.to_string());
+        self.reindex_from(pos);
+        Ok(())
+    }
+
+    /// Removes page `page_num`, returning its name.
+    pub fn delete_page(&mut self, page_num: usize) -> Result<String> {
+        if page_num >= self.page2name.len() {
+            return Err(DjvuError::InvalidArg(format!(
+                "Page {page_num} out of range 0..{}",
+                self.page2name.len()
+            )));
+        }
+        let name = self.page2name.remove(page_num);
+        self.name2page.remove(&name);
+        self.reindex_from(page_num);
+        Ok(name)
+    }
+
+    fn reindex_from(&mut self, pos: usize) {
+        for (i, name) in self.page2name.iter().enumerate().skip(pos) {
+            self.name2page.insert(name.clone(), i);
+        }
+    }
+
+    /// Number of pages
+    pub fn pages_num(&self) -> usize {
+        self.page2name.len()
+    }
+
+    /// Returns the file name of page `page_num`.
+    pub fn page_to_name(&self, page_num: usize) -> Option<&str> {
+        self.page2name.get(page_num).map(String::as_str)
+    }
+
+    /// Returns the page number of file `name`.
+    pub fn name_to_page(&self, name: &str) -> Option<usize> {
+        self.name2page.get(name).copied()
+    }
+
+    /// Returns the URL of page `page_num`, relative to the base URL.
+    pub fn page_to_url(&self, page_num: usize) -> Option<String> {
+        let name = self.page_to_name(page_num)?;
+        let dir = match self.base_url.rfind('/') {
+            Some(slash) => &self.base_url[..=slash],
+            None if self.base_url.is_empty() => "",
+            None => return Some(format!("{}/{name}", self.base_url)),
+        };
+        Some(format!("{dir}{name}"))
+    }
+
+    /// Returns the page whose URL is `url`.
+    pub fn url_to_page(&self, url: &str) -> Option<usize> {
+        let name = url.rsplit('/').next()?;
+        let page = self.name_to_page(name)?;
+        (self.page_to_url(page).as_deref() == Some(url)).then_some(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::djvu_dir::{File, FileType};
+
+    #[test]
+    fn test_ndir_round_trip_and_urls() -> Result<()> {
+        let dir = DjVmDir::new();
+        dir.insert_file(File::new("p1", "cover.djvu", "", FileType::Page), -1)?;
+        dir.insert_file(File::new("shared", "", "", FileType::SharedAnno), -1)?;
+        dir.insert_file(File::new("p2.djvu", "", "", FileType::Page), -1)?;
+
+        let nav = DjVuNavDir::from_dir(&dir, "http://example.com/book/directory.djvu")?;
+        let mut payload = Vec::new();
+        nav.encode(&mut payload)?;
+        assert_eq!(payload, b"cover.djvu\np2.djvu\n");
+
+        assert_eq!(
+            nav.page_to_url(1).as_deref(),
+            Some("http://example.com/book/p2.djvu")
+        );
+        assert_eq!(
+            nav.url_to_page("http://example.com/book/cover.djvu"),
+            Some(0)
+        );
+        assert_eq!(nav.url_to_page("http://example.com/other/cover.djvu"), None);
+
+        let decoded = DjVuNavDir::decode(&payload, "http://example.com/book/directory.djvu")?;
+        assert_eq!(decoded, nav);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ndir_decode_follows_djvulibre_rules() -> Result<()> {
+        let nav = DjVuNavDir::decode(b"a.djvu\n\nb.djvu\na.djvu\nc.djvu", "")?;
+        assert_eq!(nav.pages_num(), 3);
+        assert_eq!(nav.name_to_page("c.djvu"), Some(2));
+        assert_eq!(nav.page_to_url(1).as_deref(), Some("b.djvu"));
+
+        let long = vec![b'x'; MAX_LINE + 1];
+        assert!(DjVuNavDir::decode(&long, "").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ndir_edit_and_djvi_form() -> Result<()> {
+        let mut nav = DjVuNavDir::new("file:///docs/");
+        nav.insert_page(None, "b.djvu")?;
+        nav.insert_page(Some(0), "a.djvu")?;
+        assert!(nav.insert_page(None, "a.djvu").is_err());
+        assert_eq!(nav.name_to_page("b.djvu"), Some(1));
+        assert_eq!(nav.delete_page(0)?, "a.djvu");
+        assert_eq!(nav.name_to_page("b.djvu"), Some(0));
+
+        let form = nav.to_djvi()?;
+        assert_eq!(&form[..4], b"AT&T");
+        assert_eq!(&form[4..8], b"FORM");
+        assert_eq!(
+            u32::from_be_bytes(form[8..12].try_into().unwrap()),
+            4 + 8 + 8
+        );
+        assert_eq!(&form[12..20], b"DJVINDIR");
+        assert_eq!(&form[24..31], b"b.djvu\n");
+        assert_eq!(form.len(), 32);
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs DjVuLibre's djvused on the PATH"]
+    fn test_djvused_reads_ndir_pages() -> Result<()> {
+        use crate::doc::page_encoder::{PageComponents, PageEncodeParams};
+
+        // Single-page files that each include the directory are an
+        // old-style indexed document, whose pages DjVuLibre takes from NDIR.
+        let dir = tempfile::tempdir()?;
+        let mut nav = DjVuNavDir::new("");
+        for name in ["a.djvu", "b.djvu", "c.djvu"] {
+            nav.insert_page(None, name)?;
+            let page = PageComponents::new_with_dimensions(40, 30)
+                .with_include("nav.djvi")
+                .encode(&PageEncodeParams::default(), 1, 300, 1, None)?;
+            std::fs::write(dir.path().join(name), page)?;
+        }
+        std::fs::write(dir.path().join("nav.djvi"), nav.to_djvi()?)?;
+
+        let out = std::process::Command::new("djvused")
+            .args(["-e", "n"])
+            .arg(dir.path().join("b.djvu"))
+            .output()?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(out.status.success() && stderr.trim().is_empty(), "{stderr}");
+        assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "3");
+        Ok(())
+    }
+}
@@ -0,0 +1,1789 @@
+//! Chunk-level editing of finished documents.
+//!
+//! [`DjVuDocEditor`] opens a single-page `FORM:DJVU` or a bundled
+//! `FORM:DJVM` and changes individual chunks of its pages, such as swapping
+//! in a `TXTz` from new OCR or stripping an `ANTz`, without decoding or
+//! re-encoding the image layers. Saving recomputes every chunk size and, for
+//! bundled documents, the file offsets and sizes in the `DIRM`, like
+//! djvused's `save`. Pages carrying an integrity checksum (`CIDa`) get a
+//! fresh one.
+//!
+//! Removing pages can leave shared dictionaries and annotations (include
+//! files) that no page refers to with `INCL` any more. Unless turned off with
+//! [`DjVuDocEditor::with_orphan_collection`], saving drops them.
+//!
+//! [`DjVuDocEditor::redact`] blanks out a region of a page. Hidden text
+//! and hyperlinks are pruned in place; the image layers, which cannot be
+//! decoded here, are re-encoded from the page's source components.
+//! [`DjVuDocEditor::replace_page_image`] swaps in a new scan of one page,
+//! encoding only that page; [`DjVuDocEditor::replace_page_image_in_file`]
+//! does it to a document on disk in place, rewriting only the directory
+//! and the files from that page on.
+//!
+//! Indirect documents are opened with [`DjVuDocEditor::from_indirect`],
+//! which gathers the component files into a bundle.
+//!
+//! [`DjVuDocEditor::append_document`] merges documents: page forms are
+//! copied whole, hidden text and annotations included, and the bookmarks
+//! (`NAVM`) of both are kept. Removing, moving and appending pages rewrite
+//! bookmarks that point at pages by number or ID so they keep leading to the
+//! same page, and drop the ones whose page is gone.
+//!
+//! ```ignore
+//! let mut editor = DjVuDocEditor::from_bytes(&std::fs::read("book.djvu")?)?;
+//! editor.replace_chunk(3, ChunkId::TXTz, new_text)?;
+//! editor.remove_chunks(3, ChunkId::ANTz)?;
+//! std::fs::write("book.djvu", editor.to_bytes()?)?;
+//! ```
+
+use crate::annotations::{
+    HiddenText,
+    annotations::{remove_mapareas, retarget_mapareas},
+    hidden_text::BoundingBox,
+};
+use crate::doc::djvu_dir::{Bookmark, DjVmDir, DjVmNav, File, FileType};
+use crate::doc::integrity::{self, CHECKSUM_CHUNK_ID};
+use crate::doc::links::{LinkTarget, PageDirectory};
+use crate::doc::page_encoder::{PageComponents, PageEncodeParams, PageInfo, Rect};
+use crate::doc::source::ComponentSource;
+use crate::iff::MemoryStream;
+use crate::iff::bs_byte_stream::{bzz_compress, bzz_decompress};
+use crate::iff::chunk_id::ChunkId;
+use crate::iff::chunk_tree::{ChunkPayload, IffChunk, IffDocument};
+use crate::iff::parse_mode::ParseLog;
+use crate::utils::error::IoContext;
+use crate::{DjvuError, Result};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+/// An encoded document opened for chunk-level editing.
+pub struct DjVuDocEditor {
+    /// The `FORM:DJVU` or `FORM:DJVM` chunk.
+    root: IffChunk,
+    /// Directory of a bundled document.
+    bundle: Option<Bundle>,
+    collect_orphans: bool,
+}
+
+/// Chunks holding image data, which redaction has to re-encode.
+const IMAGE_CHUNKS: [ChunkId; 12] = [
+    ChunkId::Djbz,
+    ChunkId::Sjbz,
+    ChunkId::Smmr,
+    ChunkId::FGbz,
+    ChunkId::FG44,
+    ChunkId::BG44,
+    ChunkId::BGjp,
+    ChunkId::FGjp,
+    ChunkId::FG2k,
+    ChunkId::BG2k,
+    ChunkId::BM44,
+    ChunkId::PM44,
+];
+
+/// The directory of a bundled document.
+struct Bundle {
+    /// One entry per `FORM` child of the root, in the same order.
+    files: Vec<Arc<File>>,
+    /// `DIRM` version to write back.
+    version: u8,
+}
+
+impl DjVuDocEditor {
+    /// Parses a single-page or bundled document.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::from_bytes_with(data, &mut ParseLog::default())
+    }
+
+    /// Like [`Self::from_bytes`], reading the chunks and the directory in
+    /// the mode of `log`, which collects what a permissive parse recovered
+    /// from (see [`crate::iff::parse_mode`]).
+    pub fn from_bytes_with(data: &[u8], log: &mut ParseLog) -> Result<Self> {
+        Self::from_root(IffDocument::from_bytes_with(data, log)?.root, log)
+    }
+
+    /// Opens an indirect document from its index file, reading the files
+    /// its directory lists from `source` (see [`crate::doc::source`]). The
+    /// document is saved as a bundled one.
+    pub fn from_indirect(index: &[u8], source: &dyn ComponentSource) -> Result<Self> {
+        Self::from_indirect_with(index, source, &mut ParseLog::default())
+    }
+
+    /// Like [`Self::from_indirect`], reading the index and the files in the
+    /// mode of `log`. Warnings about a file name it, with offsets into that
+    /// file.
+    pub fn from_indirect_with(
+        index: &[u8],
+        source: &dyn ComponentSource,
+        log: &mut ParseLog,
+    ) -> Result<Self> {
+        let mut root = IffDocument::from_bytes_with(index, log)?.root;
+        if root.secondary_id() != Some(ChunkId::DJVM) {
+            return Err(DjvuError::InvalidArg(
+                "The index of an indirect document must be a FORM:DJVM".to_string(),
+            ));
+        }
+        let dirm = root
+            .find(ChunkId::DIRM)
+            .and_then(IffChunk::data)
+            .ok_or_else(|| DjvuError::ValidationError("Index file has no DIRM".to_string()))?;
+        if dirm.first().is_some_and(|head| head & 0x80 != 0) {
+            return Err(DjvuError::InvalidArg(
+                "Document is bundled, not indirect".to_string(),
+            ));
+        }
+        if root.children().iter().any(IffChunk::is_composite) {
+            return Err(DjvuError::ValidationError(
+                "Index file of an indirect document holds FORM chunks".to_string(),
+            ));
+        }
+        let (dir, _) = DjVmDir::decode_with(dirm, log)?;
+
+        let children = root.children_mut().expect("document roots are composite");
+        for file in dir.get_files_list() {
+            let id = file.get_load_name();
+            let mut data = Vec::new();
+            source.open(id)?.read_to_end(&mut data).context(id)?;
+            let mut file_log = ParseLog::new(log.mode());
+            let form = IffDocument::from_bytes_with(&data, &mut file_log)?.root;
+            for warning in file_log.into_warnings() {
+                log.violation(warning.offset, format!("{id}: {}", warning.message))?;
+            }
+            if form.id != ChunkId::FORM {
+                return Err(DjvuError::ValidationError(format!(
+                    "Component {id} is not a FORM"
+                )));
+            }
+            children.push(form);
+        }
+        Self::from_root(root, log)
+    }
+
+    fn from_root(root: IffChunk, log: &mut ParseLog) -> Result<Self> {
+        let ChunkPayload::Composite {
+            secondary_id,
+            children,
+        } = &root.payload
+        else {
+            unreachable!("document roots are composite");
+        };
+        let bundle = match secondary_id {
+            &ChunkId::DJVU => None,
+            &ChunkId::DJVM => {
+                let dirm = root
+                    .find(ChunkId::DIRM)
+                    .and_then(IffChunk::data)
+                    .ok_or_else(|| {
+                        DjvuError::ValidationError("Bundled document has no DIRM".to_string())
+                    })?;
+                let (dir, version) = DjVmDir::decode_with(dirm, log)?;
+                let files = children.iter().filter(|c| c.is_composite()).count();
+                if dir.get_files_list().len() != files {
+                    return Err(DjvuError::ValidationError(format!(
+                        "DIRM lists {} files but the document holds {files}",
+                        dir.get_files_list().len()
+                    )));
+                }
+                Some(Bundle {
+                    files: dir.get_files_list(),
+                    version,
+                })
+            }
+            other => {
+                return Err(DjvuError::InvalidArg(format!(
+                    "Cannot edit FORM:{other} documents"
+                )));
+            }
+        };
+        Ok(Self {
+            root,
+            bundle,
+            collect_orphans: true,
+        })
+    }
+
+    /// Sets whether include files no page refers to are dropped when the
+    /// document is saved (on by default)
+    pub fn with_orphan_collection(mut self, enabled: bool) -> Self {
+        self.collect_orphans = enabled;
+        self
+    }
+
+    /// The page stored under component ID `id` in a bundled document.
+    pub fn page_index(&self, id: &str) -> Option<usize> {
+        self.page_directory().ids.iter().position(|page| page == id)
+    }
+
+    /// Number of pages.
+    pub fn page_count(&self) -> usize {
+        match &self.bundle {
+            Some(bundle) => bundle.files.iter().filter(|f| f.is_page()).count(),
+            None => 1,
+        }
+    }
+
+    /// Removes page `page` from the document. The last page of a document
+    /// cannot be removed. Bookmarks and hyperlinks pointing at the page are
+    /// dropped, and the others follow the pages they lead to.
+    pub fn remove_page(&mut self, page: usize) -> Result<()> {
+        let location = self.locate(page)?;
+        if self.page_count() == 1 {
+            return Err(DjvuError::InvalidOperation(
+                "Cannot remove the only page of a document".to_string(),
+            ));
+        }
+        let (file, child) = location.expect("documents with several pages are bundled");
+        let old = self.page_directory();
+        self.take_file(file, child);
+        self.remap_pages(&old, |p| match p.cmp(&page) {
+            std::cmp::Ordering::Less => Some(p),
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Greater => Some(p - 1),
+        })
+    }
+
+    /// Moves page `from` so that it becomes page `to`, shifting the pages in
+    /// between. Bookmarks and hyperlinks follow the pages they lead to.
+    pub fn move_page(&mut self, from: usize, to: usize) -> Result<()> {
+        let location = self.locate(from)?;
+        self.locate(to)?;
+        let Some((file, child)) = location.filter(|_| from != to) else {
+            return Ok(());
+        };
+        let old = self.page_directory();
+        let (entry, form) = self.take_file(file, child);
+        // Insert before the page now at `to`, or after the last page.
+        let (file, child) = match self.locate(to) {
+            Ok(location) => location.expect("documents with several pages are bundled"),
+            Err(_) => {
+                let files = self.bundle.as_ref().map_or(0, |b| b.files.len());
+                (files, self.root.children().len())
+            }
+        };
+        if let Some(bundle) = &mut self.bundle {
+            bundle.files.insert(file, entry);
+        }
+        if let Some(children) = self.root.children_mut() {
+            children.insert(child, form);
+        }
+        self.remap_pages(&old, |p| {
+            Some(if p == from {
+                to
+            } else if from < p && p <= to {
+                p - 1
+            } else if to <= p && p < from {
+                p + 1
+            } else {
+                p
+            })
+        })
+    }
+
+    /// Appends the pages and include files of `other` after the last page,
+    /// with their hidden text and annotations, and its bookmarks after the
+    /// ones of this document. The result is always bundled.
+    ///
+    /// Files of `other` whose ID is taken get a fresh one (`p0001-2.djvu`),
+    /// and its `INCL` chunks, bookmarks and hyperlinks follow the rename and
+    /// the new page numbers.
+    pub fn append_document(&mut self, other: DjVuDocEditor) -> Result<()> {
+        let pages = self.page_count();
+        let mut other = other;
+        other.bundle();
+        self.bundle();
+        let mut bookmarks = self.bookmarks()?;
+        let other_bookmarks = other.bookmarks()?;
+        let old = other.page_directory();
+
+        let bundle = self.bundle.as_ref().expect("bundled above");
+        let mut taken: Vec<String> = bundle
+            .files
+            .iter()
+            .flat_map(|f| [f.id.clone(), f.name.clone()])
+            .collect();
+        let mut renamed = HashMap::new();
+        let mut files = Vec::new();
+        for file in &other.bundle.as_ref().expect("bundled above").files {
+            let id = unique_id(&file.id, &taken);
+            let name = if file.has_name {
+                unique_id(&file.name, &taken)
+            } else {
+                String::new()
+            };
+            if id != file.id {
+                renamed.insert(file.id.clone(), id.clone());
+            }
+            files.push(File::new(&id, &name, &file.title, file.file_type));
+            taken.extend([id, name]);
+        }
+
+        let ChunkPayload::Composite { children, .. } = other.root.payload else {
+            unreachable!("document roots are composite");
+        };
+        let mut forms: Vec<IffChunk> = children.into_iter().filter(|c| c.is_composite()).collect();
+        for form in &mut forms {
+            for chunk in form.children_mut().into_iter().flatten() {
+                if chunk.id != ChunkId::INCL {
+                    continue;
+                }
+                let ChunkPayload::Raw(data) = &mut chunk.payload else {
+                    continue;
+                };
+                let id = String::from_utf8_lossy(data);
+                let id = id.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+                if let Some(new_id) = renamed.get(id) {
+                    *data = new_id.as_bytes().to_vec();
+                }
+            }
+        }
+
+        let bundle = self.bundle.as_mut().expect("bundled above");
+        bundle.files.extend(files);
+        self.root
+            .children_mut()
+            .expect("document roots are composite")
+            .extend(forms);
+        let new_ids = self.page_directory().ids;
+        let new_page = |p| Some(pages + p);
+        self.retarget_links(&old.view(), &new_ids, &new_page)?;
+        bookmarks.extend(retarget(other_bookmarks, &old.view(), &new_ids, &new_page));
+        self.set_bookmarks(bookmarks)
+    }
+
+    /// IDs of the chunks of page `page` (0-based), in file order.
+    pub fn chunk_ids(&self, page: usize) -> Result<Vec<ChunkId>> {
+        Ok(self
+            .page_form(page)?
+            .children()
+            .iter()
+            .map(|c| c.id)
+            .collect())
+    }
+
+    /// Payload of the first chunk `id` of page `page`, if it has one.
+    pub fn chunk(&self, page: usize, id: ChunkId) -> Result<Option<&[u8]>> {
+        Ok(self.page_form(page)?.find(id).and_then(IffChunk::data))
+    }
+
+    /// The parsed `INFO` chunk of page `page`.
+    pub fn page_info(&self, page: usize) -> Result<PageInfo> {
+        self.chunk(page, ChunkId::INFO)?
+            .and_then(PageInfo::parse)
+            .ok_or_else(|| DjvuError::ValidationError(format!("Page {page} has no INFO chunk")))
+    }
+
+    /// Inserts chunk `id` at position `at` among the chunks of page `page`.
+    ///
+    /// `INFO` stays the first chunk of the page, so `at` must be past it.
+    pub fn insert_chunk(
+        &mut self,
+        page: usize,
+        at: usize,
+        id: ChunkId,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        check_raw_id(id)?;
+        let chunks = self.page_chunks_mut(page)?;
+        let first = usize::from(chunks.first().is_some_and(|c| c.id == ChunkId::INFO));
+        if at < first || at > chunks.len() {
+            return Err(DjvuError::InvalidArg(format!(
+                "Chunk position {at} out of range {first}..={}",
+                chunks.len()
+            )));
+        }
+        chunks.insert(at, IffChunk::new_raw(id, data));
+        Ok(())
+    }
+
+    /// Replaces the payload of the first chunk `id` of page `page`.
+    pub fn replace_chunk(&mut self, page: usize, id: ChunkId, data: Vec<u8>) -> Result<()> {
+        check_raw_id(id)?;
+        let chunk = self
+            .page_form_mut(page)?
+            .find_mut(id)
+            .ok_or_else(|| DjvuError::InvalidArg(format!("Page {page} has no {id} chunk")))?;
+        chunk.payload = ChunkPayload::Raw(data);
+        Ok(())
+    }
+
+    /// Removes every chunk `id` from page `page`, returning how many there
+    /// were. A page's `INFO` cannot be removed.
+    pub fn remove_chunks(&mut self, page: usize, id: ChunkId) -> Result<usize> {
+        if id == ChunkId::INFO {
+            return Err(DjvuError::InvalidArg(
+                "The INFO chunk of a page cannot be removed".to_string(),
+            ));
+        }
+        Ok(self.page_form_mut(page)?.remove_children(id))
+    }
+
+    /// Blanks out `rect` of page `page`, given in pixels of the stored image
+    /// with a top-left origin. Returns how many hidden text zones and
+    /// hyperlinks were removed.
+    ///
+    /// Hidden text zones and hyperlinks touching `rect` are removed from the
+    /// `TXTz`/`TXTa` and `ANTz`/`ANTa` chunks, leaving the rest of them as
+    /// they were. A page with image layers also needs `source`, the
+    /// components it was encoded from: they are redacted with
+    /// [`PageComponents::redact`] and re-encoded with `params`, and the new
+    /// image chunks replace the old ones. Every other chunk, `INFO` and
+    /// `INCL` included, is kept.
+    ///
+    /// The stored chunks are not decoded, so all image layers are encoded
+    /// afresh from `source`, not only those `rect` touches; pass the
+    /// original-quality images, since whatever was lost in them is lost
+    /// again on top of that. Only the part of `rect` on the page counts.
+    pub fn redact(
+        &mut self,
+        page: usize,
+        rect: Rect,
+        source: Option<PageComponents>,
+        params: &PageEncodeParams,
+    ) -> Result<usize> {
+        let (width, height) = {
+            let info = self.page_info(page)?;
+            (info.width as u32, info.height as u32)
+        };
+        let rect = rect.clipped_to(width, height);
+        let has_images = self
+            .chunk_ids(page)?
+            .iter()
+            .any(|id| IMAGE_CHUNKS.contains(id));
+
+        // Encode the new image chunks before touching the page, so that
+        // nothing is changed when it fails.
+        let images = match (has_images, source) {
+            (false, _) => None,
+            (true, None) => {
+                return Err(DjvuError::InvalidOperation(format!(
+                    "Page {page} has image layers; redacting it needs its source components"
+                )));
+            }
+            (true, Some(mut components)) => {
+                if components.dimensions() != (width, height) {
+                    return Err(DjvuError::InvalidArg(format!(
+                        "Source components are {:?}, page {page} is {width}x{height}",
+                        components.dimensions()
+                    )));
+                }
+                components.redact(rect);
+                components.text_layer = None;
+                components.annotations = None;
+                components.includes.clear();
+                components.raw_chunks.clear();
+                let encoded = components.encode(params, 1, 0, 1, None)?;
+                let encoded = IffDocument::from_bytes(&encoded)?;
+                let mut images = encoded.root.children().to_vec();
+                images.retain(|c| IMAGE_CHUNKS.contains(&c.id));
+                Some(images)
+            }
+        };
+
+        let area = BoundingBox {
+            x: rect.x.min(u16::MAX as u32) as u16,
+            y: (height as i64 - (rect.y + rect.height) as i64).clamp(0, u16::MAX as i64) as u16,
+            w: rect.width.min(u16::MAX as u32) as u16,
+            h: rect.height.min(u16::MAX as u32) as u16,
+        };
+        let chunks = self.page_chunks_mut(page)?;
+        let mut removed = 0;
+        for chunk in chunks.iter_mut() {
+            let ChunkPayload::Raw(data) = &mut chunk.payload else {
+                continue;
+            };
+            let compressed = matches!(chunk.id, ChunkId::TXTz | ChunkId::ANTz);
+            if !matches!(
+                chunk.id,
+                ChunkId::TXTz | ChunkId::TXTa | ChunkId::ANTz | ChunkId::ANTa
+            ) {
+                continue;
+            }
+            let raw = if compressed {
+                bzz_decompress(data)?
+            } else {
+                data.clone()
+            };
+            let (pruned, count) = if matches!(chunk.id, ChunkId::TXTz | ChunkId::TXTa) {
+                let mut text = HiddenText::decode(&raw).map_err(|e| {
+                    DjvuError::ValidationError(format!("Invalid hidden text on page {page}: {e}"))
+                })?;
+                let count = text.redact(&area);
+                let mut pruned = Vec::new();
+                text.encode(&mut pruned).map_err(|e| {
+                    DjvuError::EncodingError(format!("Failed to encode hidden text: {e}"))
+                })?;
+                (pruned, count)
+            } else {
+                remove_mapareas(&raw, &area)
+            };
+            if count > 0 {
+                removed += count;
+                *data = if compressed {
+                    bzz_compress(&pruned, 100)?
+                } else {
+                    pruned
+                };
+            }
+        }
+
+        if let Some(images) = images {
+            let at = chunks
+                .iter()
+                .position(|c| IMAGE_CHUNKS.contains(&c.id))
+                .unwrap_or(chunks.len());
+            chunks.retain(|c| !IMAGE_CHUNKS.contains(&c.id));
+            chunks.splice(at..at, images);
+        }
+        Ok(removed)
+    }
+
+    /// Replaces the image of page `page` with `components`, encoded with
+    /// `params`, for fixing a bad scan without rebuilding the document.
+    ///
+    /// Only this page is encoded; every other page is written back byte for
+    /// byte. The page's `INFO` and image chunks are replaced, keeping the
+    /// old resolution, rotation and gamma (`params.dpi` and the rotation of
+    /// `components` are not used; see [`Self::replace_page_image_with`]),
+    /// while hidden text, annotations and `INCL` chunks stay as they were:
+    /// the text, includes and raw chunks of `components` are ignored. When
+    /// the new scan has other dimensions, the kept hidden text and
+    /// hyperlinks are no longer in the right place and should be replaced
+    /// too.
+    pub fn replace_page_image(
+        &mut self,
+        page: usize,
+        components: PageComponents,
+        params: &PageEncodeParams,
+    ) -> Result<()> {
+        self.replace_page_image_with(page, components, params, |info| info)
+    }
+
+    /// Like [`Self::replace_page_image`], with the page's `INFO` passed
+    /// through `info` first, for changing its resolution, rotation or gamma
+    /// along with the image:
+    ///
+    /// ```ignore
+    /// editor.replace_page_image_with(3, scan, &params, |info| info.with_dpi(600))?;
+    /// ```
+    ///
+    /// The width and height always come from the new image.
+    pub fn replace_page_image_with(
+        &mut self,
+        page: usize,
+        mut components: PageComponents,
+        params: &PageEncodeParams,
+        info: impl FnOnce(PageInfo) -> PageInfo,
+    ) -> Result<()> {
+        let info = info(self.page_info(page)?);
+        components.text_layer = None;
+        components.annotations = None;
+        components.includes.clear();
+        components.raw_chunks.clear();
+        let params = PageEncodeParams {
+            dpi: u32::from(info.dpi),
+            ..params.clone()
+        };
+        let encoded = components.encode(
+            &params,
+            1,
+            0,
+            info.rotation.info_flags(),
+            Some(info.gamma()),
+        )?;
+        let encoded = IffDocument::from_bytes(&encoded)?;
+        let mut images = encoded.root.children().to_vec();
+        let mut new_info = images.remove(0);
+        images.retain(|c| IMAGE_CHUNKS.contains(&c.id));
+        let ChunkPayload::Raw(data) = &mut new_info.payload else {
+            unreachable!("INFO is a raw chunk");
+        };
+        let size = PageInfo::parse(data)
+            .ok_or_else(|| DjvuError::EncodingError("Encoded page has no INFO".to_string()))?;
+        *data = PageInfo {
+            width: size.width,
+            height: size.height,
+            ..info
+        }
+        .to_bytes()
+        .to_vec();
+
+        let chunks = self.page_chunks_mut(page)?;
+        let Some(old_info) = chunks.iter_mut().find(|c| c.id == ChunkId::INFO) else {
+            return Err(DjvuError::ValidationError(format!(
+                "Page {page} has no INFO chunk"
+            )));
+        };
+        *old_info = new_info;
+        let at = chunks
+            .iter()
+            .position(|c| IMAGE_CHUNKS.contains(&c.id))
+            .unwrap_or(chunks.len());
+        chunks.retain(|c| !IMAGE_CHUNKS.contains(&c.id));
+        chunks.splice(at..at, images);
+        Ok(())
+    }
+
+    /// Replaces the image of `page` in the document stored in `file`, like
+    /// [`Self::replace_page_image_with`], without loading the document.
+    ///
+    /// Only the directory and that page are read. The page is encoded
+    /// again and written back in place; the files after it are moved to
+    /// fit, and the `DIRM` and `FORM` size are rewritten. The pages before
+    /// it are not touched. If the directory changes length, everything
+    /// after it moves, but no other page is parsed or encoded.
+    ///
+    /// A single-page document is rewritten whole.
+    pub fn replace_page_image_in_file(
+        file: &mut std::fs::File,
+        page: usize,
+        components: PageComponents,
+        params: &PageEncodeParams,
+        info: impl FnOnce(PageInfo) -> PageInfo,
+    ) -> Result<()> {
+        let replace = |form: &[u8]| -> Result<Vec<u8>> {
+            let mut editor = Self::from_bytes(&[&b"AT&T"[..], form].concat())?;
+            editor.replace_page_image_with(0, components, params, info)?;
+            let mut bytes = editor.to_bytes()?;
+            bytes.drain(..4);
+            Ok(bytes)
+        };
+
+        let mut head = [0u8; 24];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut head[..16])?;
+        if &head[..8] != b"AT&TFORM" {
+            return Err(DjvuError::InvalidArg("Not a DjVu document".to_string()));
+        }
+        if &head[12..16] == b"DJVU" {
+            if page != 0 {
+                return Err(DjvuError::InvalidArg(format!(
+                    "Page {page} out of range (1 page)"
+                )));
+            }
+            let mut form = Vec::new();
+            file.seek(SeekFrom::Start(4))?;
+            file.read_to_end(&mut form)?;
+            let form = replace(&form)?;
+            file.seek(SeekFrom::Start(4))?;
+            file.write_all(&form)?;
+            file.set_len(4 + form.len() as u64)?;
+            return Ok(());
+        }
+        if &head[12..16] != b"DJVM" {
+            return Err(DjvuError::InvalidArg(format!(
+                "Cannot edit FORM:{} documents",
+                String::from_utf8_lossy(&head[12..16])
+            )));
+        }
+        file.read_exact(&mut head[16..])?;
+        if &head[16..20] != ChunkId::DIRM.as_bytes() {
+            return Err(DjvuError::ValidationError(
+                "Bundled document does not start with a DIRM".to_string(),
+            ));
+        }
+        let mut dirm =
+            vec![0u8; u32::from_be_bytes([head[20], head[21], head[22], head[23]]) as usize];
+        file.read_exact(&mut dirm)?;
+        let (dir, version) = DjVmDir::decode(&dirm)?;
+        let files = dir.get_files_list();
+        let target = files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.is_page())
+            .nth(page)
+            .map(|(i, _)| i)
+            .ok_or_else(|| {
+                DjvuError::InvalidArg(format!(
+                    "Page {page} out of range ({} pages)",
+                    files.iter().filter(|f| f.is_page()).count()
+                ))
+            })?;
+
+        let old = &files[target];
+        let mut form = vec![0u8; old.size as usize];
+        file.seek(SeekFrom::Start(u64::from(old.offset)))?;
+        file.read_exact(&mut form)?;
+        let form = replace(&form)?;
+
+        // Files are laid out in padded, even-length slots; the ones after
+        // the page move by the change in its slot, and everything after
+        // the directory by the change in the directory's.
+        let padded = |len: u64| len + len % 2;
+        let page_shift = padded(form.len() as u64) as i64 - padded(u64::from(old.size)) as i64;
+        let sizes: Vec<u32> = (0..files.len())
+            .map(|i| {
+                if i == target {
+                    form.len() as u32
+                } else {
+                    files[i].size
+                }
+            })
+            .collect();
+        let offsets_with = |dir_shift: i64| -> Vec<u32> {
+            files
+                .iter()
+                .map(|f| {
+                    let shift = if f.offset > old.offset { page_shift } else { 0 };
+                    (i64::from(f.offset) + dir_shift + shift) as u32
+                })
+                .collect()
+        };
+        let encode_dirm = |offsets: &[u32]| -> Result<Vec<u8>> {
+            let dir = DjVmDir::new();
+            for ((f, &offset), &size) in files.iter().zip(offsets).zip(&sizes) {
+                dir.insert_file(
+                    File::new_with_offset(&f.id, &f.name, &f.title, f.file_type, offset, size),
+                    -1,
+                )?;
+            }
+            let mut stream = MemoryStream::new();
+            dir.encode_version(&mut stream, true, version)?;
+            Ok(raw_bytes(ChunkId::DIRM, stream.as_slice()))
+        };
+        let probe = encode_dirm(&offsets_with(0))?;
+        let old_dirm_end = 16 + padded(8 + dirm.len() as u64);
+        let dir_shift = 16 + padded(probe.len() as u64) as i64 - old_dirm_end as i64;
+        let new_dirm = encode_dirm(&offsets_with(dir_shift))?;
+        debug_assert_eq!(new_dirm.len(), probe.len());
+
+        // Move what lies between the directory and the page, and what
+        // follows the page, without overwriting either before it moved.
+        let old_len = file.metadata()?.len();
+        let page_start = u64::from(old.offset);
+        let page_end = page_start + padded(u64::from(old.size));
+        let before = (old_dirm_end, page_start, dir_shift);
+        let after = (page_end, old_len, dir_shift + page_shift);
+        let moves = if after.2 > 0 {
+            [after, before]
+        } else {
+            [before, after]
+        };
+        for (start, end, shift) in moves {
+            move_bytes(file, start, end, shift)?;
+        }
+
+        let new_len = (old_len as i64 + dir_shift + page_shift) as u64;
+        let form_size = u32::try_from(new_len - 12)
+            .map_err(|_| DjvuError::ValidationError("Document exceeds 4 GiB".to_string()))?;
+        file.seek(SeekFrom::Start((page_start as i64 + dir_shift) as u64))?;
+        file.write_all(&form)?;
+        if form.len() % 2 != 0 {
+            file.write_all(&[0])?;
+        }
+        file.seek(SeekFrom::Start(8))?;
+        file.write_all(&form_size.to_be_bytes())?;
+        file.seek(SeekFrom::Start(16))?;
+        file.write_all(&new_dirm)?;
+        if new_dirm.len() % 2 != 0 {
+            file.write_all(&[0])?;
+        }
+        file.set_len(new_len)?;
+        Ok(())
+    }
+
+    /// Writes the edited document.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.write_to(&mut out)?;
+        Ok(out)
+    }
+
+    /// Writes the edited document to `writer`.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        let ChunkPayload::Composite { children, .. } = &self.root.payload else {
+            unreachable!("document roots are composite");
+        };
+        let Some(bundle) = &self.bundle else {
+            writer.write_all(b"AT&T")?;
+            writer.write_all(&form_bytes(&self.root)?)?;
+            return Ok(());
+        };
+
+        // Every chunk with the file it holds; the DIRM is left empty until
+        // the layout is known.
+        let kept = self.kept_files(bundle, children);
+        let mut files = bundle.files.iter().zip(kept);
+        let mut parts = Vec::new();
+        for child in children {
+            match &child.payload {
+                ChunkPayload::Raw(_) if child.id == ChunkId::DIRM => parts.push((None, None)),
+                ChunkPayload::Raw(data) => parts.push((None, Some(raw_bytes(child.id, data)))),
+                ChunkPayload::Composite { .. } => {
+                    let (file, keep) = files.next().expect("one file per FORM");
+                    if keep {
+                        parts.push((Some(file), Some(form_bytes(child)?)));
+                    }
+                }
+            }
+        }
+
+        // The DIRM size does not depend on the offsets, so encode once to
+        // measure it, then again with the real offsets.
+        let encode_dirm = |offsets: &[u32]| -> Result<Vec<u8>> {
+            let dir = DjVmDir::new();
+            let files = parts.iter().filter_map(|(file, bytes)| {
+                file.map(|f| (f, bytes.as_ref().map_or(0, Vec::len) as u32))
+            });
+            for ((file, size), &offset) in files.zip(offsets) {
+                dir.insert_file(
+                    File::new_with_offset(
+                        &file.id,
+                        &file.name,
+                        &file.title,
+                        file.file_type,
+                        offset,
+                        size,
+                    ),
+                    -1,
+                )?;
+            }
+            let mut stream = MemoryStream::new();
+            dir.encode_version(&mut stream, true, bundle.version)?;
+            Ok(raw_bytes(ChunkId::DIRM, stream.as_slice()))
+        };
+        let file_count = parts.iter().filter(|(file, _)| file.is_some()).count();
+        let probe = encode_dirm(&vec![0; file_count])?;
+
+        // Offsets count from the start of the file, `AT&T` included.
+        let mut offsets = Vec::with_capacity(file_count);
+        let mut pos = 16;
+        for (file, bytes) in &parts {
+            pos += pos % 2;
+            if file.is_some() {
+                offsets.push(pos as u32);
+            }
+            pos += bytes.as_ref().map_or(probe.len(), Vec::len);
+        }
+        let dirm = encode_dirm(&offsets)?;
+        debug_assert_eq!(dirm.len(), probe.len());
+
+        let size = u32::try_from(pos - 12)
+            .map_err(|_| DjvuError::ValidationError("Document exceeds 4 GiB".to_string()))?;
+        writer.write_all(b"AT&TFORM")?;
+        writer.write_all(&size.to_be_bytes())?;
+        writer.write_all(b"DJVM")?;
+        let mut pos = 16;
+        for (_, bytes) in &parts {
+            if pos % 2 != 0 {
+                writer.write_all(&[0])?;
+                pos += 1;
+            }
+            let bytes = bytes.as_deref().unwrap_or(&dirm);
+            writer.write_all(bytes)?;
+            pos += bytes.len();
+        }
+        Ok(())
+    }
+
+    /// Which files of `bundle` to write: everything but include files that
+    /// no page reaches through `INCL` chunks, directly or through other
+    /// include files.
+    fn kept_files(&self, bundle: &Bundle, children: &[IffChunk]) -> Vec<bool> {
+        let mut kept: Vec<bool> = bundle
+            .files
+            .iter()
+            .map(|f| !self.collect_orphans || f.file_type != FileType::Include)
+            .collect();
+        if !self.collect_orphans {
+            return kept;
+        }
+        let forms: Vec<&IffChunk> = children.iter().filter(|c| c.is_composite()).collect();
+        let mut pending: Vec<usize> = (0..kept.len()).filter(|&i| kept[i]).collect();
+        while let Some(i) = pending.pop() {
+            for chunk in forms[i].children() {
+                let Some(data) = chunk.data().filter(|_| chunk.id == ChunkId::INCL) else {
+                    continue;
+                };
+                let id = String::from_utf8_lossy(data);
+                let id = id.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+                if let Some(j) = bundle.files.iter().position(|f| f.id == id)
+                    && !kept[j]
+                {
+                    kept[j] = true;
+                    pending.push(j);
+                }
+            }
+        }
+        kept
+    }
+
+    /// Turns a single-page document into a bundle of one page.
+    fn bundle(&mut self) {
+        if self.bundle.is_some() {
+            return;
+        }
+        let page = std::mem::replace(
+            &mut self.root,
+            IffChunk::new_composite(ChunkId::FORM, ChunkId::DJVM),
+        );
+        self.root
+            .children_mut()
+            .expect("document roots are composite")
+            .extend([IffChunk::new_raw(ChunkId::DIRM, Vec::new()), page]);
+        self.bundle = Some(Bundle {
+            files: vec![File::new("p0001.djvu", "", "", FileType::Page)],
+            version: DjVmDir::VERSION,
+        });
+    }
+
+    /// Removes the file at `file` in the directory and `child` among the
+    /// root's children.
+    fn take_file(&mut self, file: usize, child: usize) -> (Arc<File>, IffChunk) {
+        let entry = self
+            .bundle
+            .as_mut()
+            .expect("only bundles have files")
+            .files
+            .remove(file);
+        let form = self
+            .root
+            .children_mut()
+            .expect("document roots are composite")
+            .remove(child);
+        (entry, form)
+    }
+
+    /// IDs and titles of the pages, which bookmarks are resolved against.
+    fn page_directory(&self) -> OwnedPageDirectory {
+        let pages = self
+            .bundle
+            .iter()
+            .flat_map(|b| &b.files)
+            .filter(|f| f.is_page());
+        OwnedPageDirectory {
+            ids: pages.clone().map(|f| f.id.clone()).collect(),
+            titles: pages
+                .map(|f| f.has_title.then(|| f.title.clone()))
+                .collect(),
+        }
+    }
+
+    /// The bookmarks of the `NAVM` chunk, if there is one.
+    fn bookmarks(&self) -> Result<Vec<Bookmark>> {
+        match self.root.find(ChunkId::NAVM).and_then(IffChunk::data) {
+            Some(data) => Ok(DjVmNav::decode(&bzz_decompress(data)?)?.bookmarks),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Replaces the `NAVM` chunk, which goes right after the `DIRM`, or
+    /// removes it when there are no bookmarks.
+    fn set_bookmarks(&mut self, bookmarks: Vec<Bookmark>) -> Result<()> {
+        let children = self
+            .root
+            .children_mut()
+            .expect("document roots are composite");
+        let at = children.iter().position(|c| c.id == ChunkId::NAVM);
+        if bookmarks.is_empty() {
+            if let Some(at) = at {
+                children.remove(at);
+            }
+            return Ok(());
+        }
+        let mut raw = Vec::new();
+        DjVmNav { bookmarks }.encode(&mut raw)?;
+        let navm = IffChunk::new_raw(ChunkId::NAVM, bzz_compress(&raw, 100)?);
+        match at {
+            Some(at) => children[at] = navm,
+            None => {
+                let dirm = children.iter().position(|c| c.id == ChunkId::DIRM);
+                children.insert(dirm.map_or(0, |i| i + 1), navm);
+            }
+        }
+        Ok(())
+    }
+
+    /// Points the bookmarks and hyperlinks at the pages they led to before a
+    /// change of the page list, `old` being the pages before it; `new_page`
+    /// maps each old page to its new number, or `None` if it was removed.
+    fn remap_pages(
+        &mut self,
+        old: &OwnedPageDirectory,
+        new_page: impl Fn(usize) -> Option<usize>,
+    ) -> Result<()> {
+        let new_ids = self.page_directory().ids;
+        self.retarget_links(&old.view(), &new_ids, &new_page)?;
+        if self.root.find(ChunkId::NAVM).is_none() {
+            return Ok(());
+        }
+        let bookmarks = self.bookmarks()?;
+        self.set_bookmarks(retarget(bookmarks, &old.view(), &new_ids, &new_page))
+    }
+
+    /// Rewrites the page links in the `ANTz`/`ANTa` chunks of the pages
+    /// `old` lists, as [`retarget`] does for bookmarks; links on a page
+    /// resolve relative to its old number. Links whose page is gone are
+    /// removed.
+    fn retarget_links(
+        &mut self,
+        old: &PageDirectory,
+        new_ids: &[String],
+        new_page: &dyn Fn(usize) -> Option<usize>,
+    ) -> Result<()> {
+        for from in 0..old.ids.len() {
+            let Some(page) = new_page(from) else {
+                continue;
+            };
+            if self.page_form(page)?.secondary_id() != Some(ChunkId::DJVU) {
+                continue;
+            }
+            for chunk in self.page_chunks_mut(page)? {
+                let compressed = chunk.id == ChunkId::ANTz;
+                let ChunkPayload::Raw(data) = &mut chunk.payload else {
+                    continue;
+                };
+                if !compressed && chunk.id != ChunkId::ANTa {
+                    continue;
+                }
+                let raw = if compressed {
+                    bzz_decompress(data)?
+                } else {
+                    data.clone()
+                };
+                let (text, changed) = retarget_mapareas(&raw, |url| {
+                    retarget_dest(url, Some((from, page)), old, new_ids, new_page)
+                });
+                if changed {
+                    *data = if compressed {
+                        bzz_compress(&text, 100)?
+                    } else {
+                        text
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Position of page `page` among the directory's files and among the
+    /// root's children; `None` for a single-page document.
+    fn locate(&self, page: usize) -> Result<Option<(usize, usize)>> {
+        let out_of_range = || {
+            DjvuError::InvalidArg(format!(
+                "Page {page} out of range (total {})",
+                self.page_count()
+            ))
+        };
+        let Some(bundle) = &self.bundle else {
+            return if page == 0 {
+                Ok(None)
+            } else {
+                Err(out_of_range())
+            };
+        };
+        let file = bundle
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.is_page())
+            .nth(page)
+            .map(|(i, _)| i)
+            .ok_or_else(out_of_range)?;
+        let ChunkPayload::Composite { children, .. } = &self.root.payload else {
+            unreachable!("document roots are composite");
+        };
+        let child = children
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.is_composite())
+            .nth(file)
+            .map(|(i, _)| i)
+            .expect("one FORM per file");
+        Ok(Some((file, child)))
+    }
+
+    /// The `FORM` of page `page`.
+    fn page_form(&self, page: usize) -> Result<&IffChunk> {
+        Ok(match (self.locate(page)?, &self.root.payload) {
+            (Some((_, i)), ChunkPayload::Composite { children, .. }) => &children[i],
+            _ => &self.root,
+        })
+    }
+
+    /// The `FORM:DJVU` of page `page`, for editing.
+    fn page_form_mut(&mut self, page: usize) -> Result<&mut IffChunk> {
+        let form = match self.locate(page)? {
+            Some((_, i)) => match self.root.children_mut() {
+                Some(children) => &mut children[i],
+                None => unreachable!("document roots are composite"),
+            },
+            None => &mut self.root,
+        };
+        if form.secondary_id() != Some(ChunkId::DJVU) {
+            return Err(DjvuError::ValidationError(format!(
+                "Page {page} is not a FORM:DJVU"
+            )));
+        }
+        Ok(form)
+    }
+
+    fn page_chunks_mut(&mut self, page: usize) -> Result<&mut Vec<IffChunk>> {
+        Ok(self
+            .page_form_mut(page)?
+            .children_mut()
+            .expect("pages are composite"))
+    }
+}
+
+/// Page IDs and titles, kept across a change of the page list.
+struct OwnedPageDirectory {
+    ids: Vec<String>,
+    titles: Vec<Option<String>>,
+}
+
+impl OwnedPageDirectory {
+    fn view(&self) -> PageDirectory<'_> {
+        PageDirectory {
+            ids: &self.ids,
+            titles: &self.titles,
+        }
+    }
+}
+
+/// Rewrites the page destinations of `bookmarks`, resolved against `old`,
+/// for the pages as renumbered by `new_page`, whose IDs are `new_ids`.
+/// Bookmarks whose page is gone are dropped, their children taking their
+/// place; other destinations are kept as they are.
+fn retarget(
+    bookmarks: Vec<Bookmark>,
+    old: &PageDirectory,
+    new_ids: &[String],
+    new_page: &dyn Fn(usize) -> Option<usize>,
+) -> Vec<Bookmark> {
+    let mut out = Vec::with_capacity(bookmarks.len());
+    for mut bookmark in bookmarks {
+        let children = retarget(
+            std::mem::take(&mut bookmark.children),
+            old,
+            new_ids,
+            new_page,
+        );
+        // Relative destinations have no page to start from in an outline.
+        match retarget_dest(&bookmark.dest, None, old, new_ids, new_page) {
+            Some(dest) => out.push(Bookmark {
+                title: bookmark.title,
+                dest,
+                children,
+            }),
+            None => out.extend(children),
+        }
+    }
+    out
+}
+
+/// The destination `dest` leads to after the pages are renumbered by
+/// `new_page`, or `None` if its page is gone. `from` is the old and new
+/// number of the page a link is on; without it, relative destinations are
+/// kept as they are, like those that are not page links or do not resolve.
+fn retarget_dest(
+    dest: &str,
+    from: Option<(usize, usize)>,
+    old: &PageDirectory,
+    new_ids: &[String],
+    new_page: &dyn Fn(usize) -> Option<usize>,
+) -> Option<String> {
+    let Some(target) = dest.strip_prefix('#') else {
+        return Some(dest.to_string());
+    };
+    let relative = target.starts_with(['+', '-']);
+    let resolved = match from {
+        Some((old_from, _)) => old.resolve(target, old_from),
+        None if relative => None,
+        None => old.resolve(target, 0),
+    };
+    match resolved {
+        None => Some(dest.to_string()),
+        Some(LinkTarget::Number(page)) => new_page(page).map(|p| match from {
+            Some((_, at)) if relative && p >= at => format!("#+{}", p - at),
+            Some((_, at)) if relative => format!("#-{}", at - p),
+            _ => format!("#{}", p + 1),
+        }),
+        Some(LinkTarget::Id(page)) => new_page(page).map(|p| format!("#{}", new_ids[p])),
+        Some(LinkTarget::Title(page)) => new_page(page).map(|_| dest.to_string()),
+    }
+}
+
+/// `id`, or `id` with a `-2`, `-3`, ... suffix before its extension if it
+/// is already in `taken`.
+fn unique_id(id: &str, taken: &[String]) -> String {
+    if !taken.iter().any(|t| t == id) {
+        return id.to_string();
+    }
+    let (stem, ext) = match id.rfind('.') {
+        Some(dot) if dot > 0 => id.split_at(dot),
+        _ => (id, ""),
+    };
+    (2..)
+        .map(|n| format!("{stem}-{n}{ext}"))
+        .find(|candidate| !taken.contains(candidate))
+        .expect("some suffix is free")
+}
+
+fn check_raw_id(id: ChunkId) -> Result<()> {
+    if id.is_composite() {
+        return Err(DjvuError::InvalidArg(format!(
+            "{id} is a composite chunk ID"
+        )));
+    }
+    Ok(())
+}
+
+fn raw_bytes(id: ChunkId, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + data.len());
+    out.extend_from_slice(id.as_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+    out
+}
+
+/// Moves the bytes `start..end` of `file` by `shift`, copying from the end
+/// that keeps the source intact when the ranges overlap.
+fn move_bytes(file: &mut std::fs::File, start: u64, end: u64, shift: i64) -> Result<()> {
+    const BLOCK: u64 = 1 << 20;
+    if shift == 0 || start >= end {
+        return Ok(());
+    }
+    let mut buf = vec![0u8; BLOCK.min(end - start) as usize];
+    let mut done = 0;
+    while done < end - start {
+        let len = BLOCK.min(end - start - done);
+        let from = if shift > 0 {
+            end - done - len
+        } else {
+            start + done
+        };
+        let buf = &mut buf[..len as usize];
+        file.seek(SeekFrom::Start(from))?;
+        file.read_exact(buf)?;
+        file.seek(SeekFrom::Start((from as i64 + shift) as u64))?;
+        file.write_all(buf)?;
+        done += len;
+    }
+    Ok(())
+}
+
+/// Serializes a `FORM` chunk without the `AT&T` prefix, refreshing the
+/// checksum of a page that has one.
+fn form_bytes(form: &IffChunk) -> Result<Vec<u8>> {
+    let mut form = form.clone();
+    let checksummed = form.remove_children(CHECKSUM_CHUNK_ID) > 0;
+    let mut out = Vec::new();
+    IffDocument::new(form).write(&mut out)?;
+    if checksummed {
+        integrity::append_checksum(&mut out)?;
+    }
+    out.drain(..4);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::integrity::{PageIntegrity, verify_document};
+    use crate::doc::page_encoder::PageRotation;
+    use crate::doc::{DjvuBuilder, PageBuilder};
+    use crate::image::image_formats::{Pixel, Pixmap};
+
+    fn document(pages: usize) -> Result<Vec<u8>> {
+        let doc = DjvuBuilder::new(pages)
+            .with_integrity_checksums(true)
+            .build();
+        for page_num in 0..pages {
+            let page = PageBuilder::new(page_num, 24, 16)
+                .with_background(Pixmap::from_pixel(24, 16, Pixel::new(200, 10, 10)))?
+                .with_hyperlink("https://example.com", 0, 0, 4, 4, "")
+                .build()?;
+            doc.add_page_with_id(page, format!("page{page_num}.djvu"), "")?;
+        }
+        doc.finalize()
+    }
+
+    #[test]
+    fn test_open_indirect_document() -> Result<()> {
+        use crate::doc::source::{DirSource, MemorySource};
+
+        // Split a bundle into an index and one file per component.
+        let original = document(3)?;
+        let bundle = IffDocument::from_bytes(&original)?.root;
+        let (dir, version) = DjVmDir::decode(bundle.find(ChunkId::DIRM).unwrap().data().unwrap())?;
+        let mut stream = MemoryStream::new();
+        dir.encode_version(&mut stream, false, version)?;
+        let mut index = IffChunk::new_composite(ChunkId::FORM, ChunkId::DJVM);
+        index.insert_child(
+            0,
+            IffChunk::new_raw(ChunkId::DIRM, stream.as_slice().to_vec()),
+        )?;
+        let index = IffDocument::new(index).to_bytes()?;
+        let forms = bundle.children().iter().filter(|c| c.is_composite());
+        let mut memory = MemorySource::new();
+        let folder = tempfile::tempdir()?;
+        for (file, form) in dir.get_files_list().iter().zip(forms) {
+            let data = [&b"AT&T"[..], &form.to_bytes()?].concat();
+            std::fs::write(folder.path().join(&file.id), &data)?;
+            memory.insert(file.id.clone(), data);
+        }
+
+        let expected = DjVuDocEditor::from_bytes(&original)?.to_bytes()?;
+        let editor = DjVuDocEditor::from_indirect(&index, &memory)?;
+        assert_eq!(editor.page_count(), 3);
+        assert_eq!(editor.to_bytes()?, expected);
+        let editor = DjVuDocEditor::from_indirect(&index, &DirSource::new(folder.path()))?;
+        assert_eq!(editor.to_bytes()?, expected);
+
+        // A file cut short is refused, or read as far as it goes.
+        let last = &dir.get_files_list()[2].id;
+        let mut cut = memory.clone();
+        let data = std::fs::read(folder.path().join(last))?;
+        cut.insert(last.clone(), data[..data.len() - 1].to_vec());
+        assert!(DjVuDocEditor::from_indirect(&index, &cut).is_err());
+        let mut log = ParseLog::new(crate::iff::ParseMode::Permissive);
+        let editor = DjVuDocEditor::from_indirect_with(&index, &cut, &mut log)?;
+        assert_eq!(editor.page_count(), 3);
+        assert!(!log.warnings().is_empty());
+        assert!(log.warnings()[0].message.starts_with(&format!("{last}: ")));
+
+        // Missing components and bundled documents are refused.
+        let partial = MemorySource::new().with_file("page0.djvu", Vec::new());
+        assert!(DjVuDocEditor::from_indirect(&index, &partial).is_err());
+        assert!(matches!(
+            DjVuDocEditor::from_indirect(&original, &memory),
+            Err(DjvuError::InvalidArg(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_bundled_document() -> Result<()> {
+        let original = document(3)?;
+        let mut editor = DjVuDocEditor::from_bytes(&original)?;
+        assert_eq!(editor.page_count(), 3);
+        let bg44 = editor.chunk(1, ChunkId::BG44)?.unwrap().to_vec();
+
+        editor.insert_chunk(1, 1, ChunkId::TXTa, b"odd".to_vec())?;
+        editor.replace_chunk(1, ChunkId::TXTa, b"new text".to_vec())?;
+        assert_eq!(editor.remove_chunks(0, ChunkId::ANTz)?, 1);
+        assert!(
+            editor
+                .insert_chunk(1, 0, ChunkId::TXTa, Vec::new())
+                .is_err()
+        );
+        assert!(editor.replace_chunk(2, ChunkId::TXTz, Vec::new()).is_err());
+        assert!(editor.remove_chunks(2, ChunkId::INFO).is_err());
+        assert!(editor.chunk_ids(3).is_err());
+        let edited = editor.to_bytes()?;
+
+        // The result parses again, with sizes, offsets and checksums intact.
+        assert_eq!(verify_document(&edited)?, [PageIntegrity::Verified; 3]);
+        let reopened = DjVuDocEditor::from_bytes(&edited)?;
+        assert_eq!(reopened.chunk(1, ChunkId::TXTa)?, Some(&b"new text"[..]));
+        assert_eq!(reopened.chunk(1, ChunkId::BG44)?, Some(&bg44[..]));
+        assert!(!reopened.chunk_ids(0)?.contains(&ChunkId::ANTz));
+        assert_eq!(reopened.chunk_ids(1)?[..2], [ChunkId::INFO, ChunkId::TXTa]);
+        let dirm_len = u32::from_be_bytes(edited[20..24].try_into().unwrap()) as usize;
+        let (dir, _) = DjVmDir::decode(&edited[24..24 + dirm_len])?;
+        for file in dir.get_files_list() {
+            let at = file.offset as usize;
+            assert_eq!(&edited[at..at + 4], b"FORM", "{}", file.id);
+            let len = u32::from_be_bytes(edited[at + 4..at + 8].try_into().unwrap());
+            assert!(file.size >= len + 8, "{}", file.id);
+        }
+
+        // Unedited documents are written back unchanged.
+        assert_eq!(DjVuDocEditor::from_bytes(&original)?.to_bytes()?, original);
+        Ok(())
+    }
+
+    #[test]
+    fn test_removed_pages_drop_orphaned_includes() -> Result<()> {
+        use crate::annotations::Annotations;
+        use crate::doc::IncludeFileBuilder;
+
+        let doc = DjvuBuilder::new(3).build();
+        for id in ["shared.iff", "unused.iff"] {
+            let file = IncludeFileBuilder::new(id)
+                .with_annotations(&Annotations::default())?
+                .build()?;
+            doc.add_include_file(file)?;
+        }
+        for page_num in 0..3 {
+            let mut page = PageBuilder::new(page_num, 16, 16)
+                .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?;
+            if page_num == 1 {
+                page = page.with_include("shared.iff");
+            }
+            doc.add_page(page.build()?)?;
+        }
+        let original = doc.finalize()?;
+        let file_ids = |bytes: &[u8]| -> Result<Vec<String>> {
+            let len = u32::from_be_bytes(bytes[20..24].try_into().unwrap()) as usize;
+            Ok(DjVmDir::decode(&bytes[24..24 + len])?.0.get_files_ids())
+        };
+
+        let mut editor = DjVuDocEditor::from_bytes(&original)?;
+        assert_eq!(file_ids(&editor.to_bytes()?)?.len(), 4);
+        editor.remove_page(1)?;
+        assert_eq!(editor.page_count(), 2);
+        let edited = editor.to_bytes()?;
+        assert_eq!(file_ids(&edited)?, ["p0001.djvu", "p0003.djvu"]);
+        assert_eq!(DjVuDocEditor::from_bytes(&edited)?.page_count(), 2);
+
+        let mut editor = DjVuDocEditor::from_bytes(&original)?.with_orphan_collection(false);
+        editor.remove_page(1)?;
+        assert_eq!(file_ids(&editor.to_bytes()?)?.len(), 4);
+        editor.remove_page(1)?;
+        assert!(editor.remove_page(0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_redact_region() -> Result<()> {
+        let page = PageBuilder::new(0, 40, 20)
+            .with_background(Pixmap::from_pixel(40, 20, Pixel::black()))?
+            .with_ocr_words(vec![
+                ("public".to_string(), 2, 2, 8, 6),
+                ("secret".to_string(), 28, 2, 8, 6),
+            ])
+            .with_hyperlink("https://example.com/a", 2, 2, 6, 6, "")
+            .with_hyperlink("https://example.com/b", 28, 12, 6, 6, "")
+            .build()?;
+        let doc = DjvuBuilder::new(1).with_integrity_checksums(true).build();
+        doc.add_page(page.clone())?;
+        let original = doc.finalize()?;
+        let params = PageEncodeParams::default();
+        let right_half = Rect::new(20, 0, 20, 20);
+
+        // Image layers cannot be redacted without the page's source.
+        let mut editor = DjVuDocEditor::from_bytes(&original)?;
+        assert!(editor.redact(0, right_half, None, &params).is_err());
+        assert_eq!(editor.to_bytes()?, original);
+
+        let bg44 = editor.chunk(0, ChunkId::BG44)?.unwrap().to_vec();
+        let removed = editor.redact(0, right_half, Some(page.to_components()?), &params)?;
+        assert_eq!(removed, 2);
+        let edited = editor.to_bytes()?;
+        assert_eq!(verify_document(&edited)?, [PageIntegrity::Verified]);
+
+        let reopened = DjVuDocEditor::from_bytes(&edited)?;
+        assert_ne!(reopened.chunk(0, ChunkId::BG44)?.unwrap(), &bg44[..]);
+        let text = bzz_decompress(reopened.chunk(0, ChunkId::TXTz)?.unwrap())?;
+        let text = HiddenText::decode(&text).unwrap();
+        let words: Vec<_> = text
+            .root_zone
+            .children
+            .iter()
+            .map(|w| w.text.as_deref())
+            .collect();
+        assert_eq!(words, [Some("public")]);
+        let annotations = bzz_decompress(reopened.chunk(0, ChunkId::ANTz)?.unwrap())?;
+        let annotations = String::from_utf8_lossy(&annotations);
+        assert!(annotations.contains("example.com/a"));
+        assert!(!annotations.contains("example.com/b"));
+        Ok(())
+    }
+
+    /// A document whose pages carry hidden text naming them, with the
+    /// bookmarks of `outline` if it is not empty.
+    fn outlined(names: &[&str], outline: &str) -> Result<Vec<u8>> {
+        linked(names, outline, &[])
+    }
+
+    /// A document like [`outlined`] with `links[i]` on page `i`.
+    fn linked(names: &[&str], outline: &str, links: &[&[&str]]) -> Result<Vec<u8>> {
+        let doc = DjvuBuilder::new(names.len()).build();
+        for (page_num, name) in names.iter().enumerate() {
+            let mut page = PageBuilder::new(page_num, 24, 16)
+                .with_background(Pixmap::from_pixel(24, 16, Pixel::white()))?
+                .with_plain_text(*name);
+            for url in links.get(page_num).copied().unwrap_or_default() {
+                page = page.with_hyperlink(*url, 0, 0, 4, 4, "");
+            }
+            let page = page.build()?;
+            doc.add_page_with_id(page, format!("{name}.djvu"), "")?;
+        }
+        if !outline.is_empty() {
+            doc.set_bookmarks(DjVmNav::from_outline(outline)?)?;
+        }
+        doc.finalize()
+    }
+
+    fn page_texts(editor: &DjVuDocEditor) -> Result<Vec<String>> {
+        (0..editor.page_count())
+            .map(|page| {
+                // The text of the page comes first, after its 24-bit length.
+                let text = bzz_decompress(editor.chunk(page, ChunkId::TXTz)?.unwrap())?;
+                let len = u32::from_be_bytes([0, text[0], text[1], text[2]]) as usize;
+                let text = String::from_utf8_lossy(&text[3..3 + len]);
+                Ok(text.trim_end_matches(char::is_control).trim().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bookmarks_follow_moved_and_removed_pages() -> Result<()> {
+        let data = outlined(
+            &["a", "b", "c", "d"],
+            r##"("A" "#1") ("B" "#b.djvu" ("C" "#3")) ("D" "#4") ("Web" "https://example.com")"##,
+        )?;
+        let mut editor = DjVuDocEditor::from_bytes(&data)?;
+        editor.move_page(0, 3)?;
+        assert_eq!(page_texts(&editor)?, ["b", "c", "d", "a"]);
+        let editor = DjVuDocEditor::from_bytes(&editor.to_bytes()?)?;
+        assert_eq!(
+            DjVmNav {
+                bookmarks: editor.bookmarks()?
+            }
+            .to_outline(),
+            DjVmNav::from_outline(
+                r##"("A" "#4") ("B" "#b.djvu" ("C" "#2")) ("D" "#3") ("Web" "https://example.com")"##
+            )?
+            .to_outline()
+        );
+
+        // Removing a page drops its bookmark; the children move up.
+        let mut editor = editor;
+        editor.remove_page(0)?;
+        editor.move_page(2, 0)?;
+        assert_eq!(page_texts(&editor)?, ["a", "c", "d"]);
+        let outline = DjVmNav {
+            bookmarks: editor.bookmarks()?,
+        };
+        assert_eq!(
+            outline,
+            DjVmNav::from_outline(
+                r##"("A" "#1") ("C" "#2") ("D" "#3") ("Web" "https://example.com")"##
+            )?
+        );
+        Ok(())
+    }
+
+    /// URLs of the hyperlinks on `page`, in order.
+    fn page_links(editor: &DjVuDocEditor, page: usize) -> Result<Vec<String>> {
+        let Some(antz) = editor.chunk(page, ChunkId::ANTz)? else {
+            return Ok(Vec::new());
+        };
+        let mut urls = Vec::new();
+        retarget_mapareas(&bzz_decompress(antz)?, |url| {
+            urls.push(url.to_string());
+            Some(url.to_string())
+        });
+        Ok(urls)
+    }
+
+    #[test]
+    fn test_links_follow_moved_and_removed_pages() -> Result<()> {
+        let data = linked(
+            &["a", "b", "c", "d"],
+            "",
+            &[
+                &["#3", "#d.djvu", "#+1", "https://example.com"],
+                &[],
+                &[],
+                &["#b.djvu", "#1"],
+            ],
+        )?;
+        let mut editor = DjVuDocEditor::from_bytes(&data)?;
+        editor.move_page(0, 3)?;
+        assert_eq!(page_texts(&editor)?, ["b", "c", "d", "a"]);
+        assert_eq!(
+            page_links(&editor, 3)?,
+            ["#2", "#d.djvu", "#-3", "https://example.com"]
+        );
+        assert_eq!(page_links(&editor, 2)?, ["#b.djvu", "#4"]);
+
+        // Links to a removed page go with it.
+        editor.remove_page(0)?;
+        assert_eq!(page_links(&editor, 1)?, ["#3"]);
+        assert_eq!(
+            page_links(&editor, 2)?,
+            ["#1", "#d.djvu", "https://example.com"]
+        );
+
+        let other = linked(&["b", "e"], "", &[&[], &["#1", "#b.djvu", "#-1"]])?;
+        editor.append_document(DjVuDocEditor::from_bytes(&other)?)?;
+        let editor = DjVuDocEditor::from_bytes(&editor.to_bytes()?)?;
+        assert_eq!(page_texts(&editor)?, ["c", "d", "a", "b", "e"]);
+        assert_eq!(page_links(&editor, 4)?, ["#4", "#b.djvu", "#-1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_document() -> Result<()> {
+        let first = outlined(&["a", "b"], r##"("A" "#1") ("B" "#b.djvu")"##)?;
+        let second = outlined(&["b", "c"], r##"("Other B" "#b.djvu" ("C" "#2"))"##)?;
+        let mut editor = DjVuDocEditor::from_bytes(&first)?;
+        editor.append_document(DjVuDocEditor::from_bytes(&second)?)?;
+        let single = DjVuDocEditor::from_bytes(&outlined(&["single"], "")?)?;
+        assert!(single.bundle.is_none());
+        editor.append_document(single)?;
+
+        let merged = editor.to_bytes()?;
+        let editor = DjVuDocEditor::from_bytes(&merged)?;
+        assert_eq!(page_texts(&editor)?, ["a", "b", "b", "c", "single"]);
+        let bundle = editor.bundle.as_ref().unwrap();
+        let ids: Vec<_> = bundle.files.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            ["a.djvu", "b.djvu", "b-2.djvu", "c.djvu", "p0001.djvu"]
+        );
+        assert_eq!(
+            DjVmNav {
+                bookmarks: editor.bookmarks()?
+            },
+            DjVmNav::from_outline(
+                r##"("A" "#1") ("B" "#b.djvu") ("Other B" "#b-2.djvu" ("C" "#4"))"##
+            )?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_page_image() -> Result<()> {
+        let original = document(3)?;
+        let mut editor = DjVuDocEditor::from_bytes(&original)?;
+        let old_bg44 = editor.chunk(1, ChunkId::BG44)?.unwrap().to_vec();
+        let other_form = editor.page_form(2)?.clone();
+
+        let scan = PageComponents::new_with_dimensions(32, 20)
+            .with_background(Pixmap::from_pixel(32, 20, Pixel::new(10, 200, 10)))?;
+        editor.replace_page_image(1, scan, &PageEncodeParams::default())?;
+        assert!(
+            editor
+                .replace_page_image(3, PageComponents::new(), &PageEncodeParams::default())
+                .is_err()
+        );
+        let edited = editor.to_bytes()?;
+        assert_eq!(verify_document(&edited)?, [PageIntegrity::Verified; 3]);
+
+        let reopened = DjVuDocEditor::from_bytes(&edited)?;
+        let info = reopened.chunk(1, ChunkId::INFO)?.unwrap();
+        assert_eq!(info[..4], [0, 32, 0, 20]);
+        assert_ne!(reopened.chunk(1, ChunkId::BG44)?.unwrap(), &old_bg44[..]);
+        // The hyperlink stays, and the other pages are untouched.
+        assert!(reopened.chunk_ids(1)?.contains(&ChunkId::ANTz));
+        assert_eq!(reopened.page_form(2)?, &other_form);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_page_image_in_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("book.djvu");
+        let scan = |w: u32, h: u32| {
+            PageComponents::new_with_dimensions(w, h).with_background(Pixmap::from_pixel(
+                w,
+                h,
+                Pixel::new(10, 200, 10),
+            ))
+        };
+
+        // Growing and shrinking the first, middle and last page gives what
+        // saving the whole edited document would.
+        let params = PageEncodeParams::default();
+        let edits: [&[(usize, u32, u32)]; 3] = [
+            &[(0, 64, 48), (0, 24, 16)],
+            &[(1, 64, 48), (1, 8, 8)],
+            &[(2, 40, 30)],
+        ];
+        for edits in edits {
+            let original = document(3)?;
+            std::fs::write(&path, &original)?;
+            let mut editor = DjVuDocEditor::from_bytes(&original)?;
+            for &(page, w, h) in edits {
+                let mut file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&path)?;
+                DjVuDocEditor::replace_page_image_in_file(
+                    &mut file,
+                    page,
+                    scan(w, h)?,
+                    &params,
+                    |info| info,
+                )?;
+                editor.replace_page_image(page, scan(w, h)?, &params)?;
+            }
+            let patched = std::fs::read(&path)?;
+            assert_eq!(patched, editor.to_bytes()?, "{edits:?}");
+            assert_eq!(verify_document(&patched)?, [PageIntegrity::Verified; 3]);
+        }
+
+        // A single page is rewritten whole; pages past the end are refused.
+        std::fs::write(&path, document(1)?)?;
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        assert!(
+            DjVuDocEditor::replace_page_image_in_file(&mut file, 1, scan(8, 8)?, &params, |i| i)
+                .is_err()
+        );
+        DjVuDocEditor::replace_page_image_in_file(&mut file, 0, scan(8, 8)?, &params, |i| i)?;
+        drop(file);
+        let reopened = DjVuDocEditor::from_bytes(&std::fs::read(&path)?)?;
+        assert_eq!(reopened.page_info(0)?.width, 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_bytes() -> Result<()> {
+        let mut file = tempfile::tempfile()?;
+        for shift in [-3i64, 3] {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(b"0123456789")?;
+            move_bytes(&mut file, 4, 8, shift)?;
+            let mut data = Vec::new();
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_end(&mut data)?;
+            let moved = (4 + shift) as usize;
+            assert_eq!(&data[moved..moved + 4], b"4567");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_page_image_keeps_info() -> Result<()> {
+        let page = PageComponents::new()
+            .with_background(Pixmap::from_pixel(24, 16, Pixel::white()))?
+            .with_rotation(PageRotation::Rotate180);
+        let flags = PageRotation::Rotate180.info_flags();
+        let params = PageEncodeParams {
+            dpi: 150,
+            ..PageEncodeParams::default()
+        };
+        let data = page.encode(&params, 1, 300, flags, Some(1.4))?;
+        let mut editor = DjVuDocEditor::from_bytes(&data)?;
+
+        let scan = || {
+            PageComponents::new_with_dimensions(32, 20).with_background(Pixmap::from_pixel(
+                32,
+                20,
+                Pixel::new(10, 200, 10),
+            ))
+        };
+        editor.replace_page_image(0, scan()?, &PageEncodeParams::default())?;
+        let info = DjVuDocEditor::from_bytes(&editor.to_bytes()?)?.page_info(0)?;
+        assert_eq!((info.width, info.height, info.dpi), (32, 20, 150));
+        assert_eq!((info.gamma, info.rotation), (14, PageRotation::Rotate180));
+
+        editor.replace_page_image_with(0, scan()?, &params, |info| info.with_dpi(600))?;
+        let info = DjVuDocEditor::from_bytes(&editor.to_bytes()?)?.page_info(0)?;
+        assert_eq!((info.dpi, info.gamma), (600, 14));
+        assert_eq!(info.rotation, PageRotation::Rotate180);
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_single_page() -> Result<()> {
+        let mut editor = DjVuDocEditor::from_bytes(&document(1)?)?;
+        assert_eq!(editor.page_count(), 1);
+        editor.replace_chunk(0, ChunkId::ANTz, vec![1, 2, 3])?;
+        let edited = editor.to_bytes()?;
+        assert_eq!(&edited[..4], b"AT&T");
+        assert_eq!(verify_document(&edited)?, [PageIntegrity::Verified]);
+        let reopened = DjVuDocEditor::from_bytes(&edited)?;
+        assert_eq!(reopened.chunk(0, ChunkId::ANTz)?, Some(&[1, 2, 3][..]));
+        Ok(())
+    }
+}
@@ -3,217 +3,1095 @@
 //! This module handles the low-level encoding and assembly of DjVu documents.
 //! It is used internally by the public builder API and not exposed directly.
 
-use crate::doc::djvu_dir::{DjVmDir, File as DjVuFile, FileType};
-// NAVM-related imports disabled for now - keep for future use
-// use crate::doc::djvu_dir::{Bookmark, DjVmNav};
-// use crate::iff::bs_byte_stream::bzz_compress;
-// use crate::iff::MemoryStream;
-use crate::Result;
-use byteorder::{BigEndian, WriteBytesExt};
+use crate::doc::builder::PageIdScheme;
+use crate::doc::djvu_dir::{
+    DirectoryFormat, DjVmDir, DjVmDir0, DjVmNav, File as DjVuFile, FileType, check_file_name,
+};
+use crate::doc::include_file::IncludeFile;
+use crate::iff::bs_byte_stream::{BzzOptions, bzz_compress_with};
+use crate::iff::chunk_id::ChunkId;
+use crate::iff::iff::{IffWriter, IffWriterExt};
+use crate::utils::zip::write_stored_zip;
+use crate::{DjvuError, Result};
 use std::io::Write;
 
+/// Directory identity of one page in a bundled document.
+///
+/// `None` fields fall back to the default `pNNNN.djvu` ID and no title.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct PageLabel {
+    pub id: Option<String>,
+    pub title: Option<String>,
+}
+
+/// One file of a bundled document, as listed in its directory.
+struct DirEntry<'a> {
+    id: String,
+    title: &'a str,
+    file_type: FileType,
+    /// Length of the file's `FORM` chunk, without the `AT&T` prefix
+    size: usize,
+}
+
+/// The pages of a document being written, handed over one at a time so
+/// that only the page being written has to be in memory.
+///
+/// Each page is asked for twice, once to size the directory and once to be
+/// written, and must be the same both times.
+pub(crate) trait PageFiles {
+    /// Number of pages
+    fn count(&self) -> usize;
+
+    /// Calls `f` with page `index`, as a file with or without its `AT&T`
+    /// prefix.
+    fn with_page(&mut self, index: usize, f: &mut dyn FnMut(&[u8]) -> Result<()>) -> Result<()>;
+
+    /// Length of page `index` without its `AT&T` prefix
+    fn size(&mut self, index: usize) -> Result<usize> {
+        let mut size = 0;
+        self.with_page(index, &mut |page| {
+            size = strip(page).len();
+            Ok(())
+        })?;
+        Ok(size)
+    }
+}
+
+impl PageFiles for &[Vec<u8>] {
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn with_page(&mut self, index: usize, f: &mut dyn FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        f(&self[index])
+    }
+}
+
+/// `file` without its `AT&T` prefix, if it has one.
+pub(crate) fn strip(file: &[u8]) -> &[u8] {
+    if file.starts_with(b"AT&TFORM") {
+        &file[4..]
+    } else {
+        file
+    }
+}
+
 /// Internal document encoder
 ///
 /// Used by the public builder API to assemble pages into complete DjVu documents.
 pub(crate) struct DocumentEncoder;
 
 impl DocumentEncoder {
-    /// Assembles encoded pages into a complete DjVu document
-    ///
-    /// Returns the complete document as bytes (single-page DJVU or multi-page DJVM)
-    pub fn assemble_pages(pages: &[Vec<u8>]) -> Result<Vec<u8>> {
+    /// Assembles encoded pages into a complete DjVu document held in memory,
+    /// see [`Self::write_to`]
+    #[cfg(test)]
+    pub fn assemble_pages(
+        pages: &[Vec<u8>],
+        includes: &[IncludeFile],
+        labels: &[PageLabel],
+        navigation: Option<&DjVmNav>,
+        directory: DirectoryFormat,
+        bzz: &BzzOptions,
+    ) -> Result<Vec<u8>> {
         let mut output = Vec::new();
+        Self::write_to(
+            &mut &pages[..],
+            includes,
+            labels,
+            navigation,
+            directory,
+            bzz,
+            &mut output,
+        )?;
+        Ok(output)
+    }
 
-        if pages.is_empty() {
-            return Ok(output);
+    /// Assembles encoded pages into a complete DjVu document written to
+    /// `writer` (single-page DJVU or multi-page DJVM).
+    ///
+    /// `labels` is indexed by page; missing entries use the defaults. Single-page
+    /// documents have no directory, so their labels are not stored; the same
+    /// goes for `directory`, which selects the directory chunk layout.
+    /// `includes` are stored ahead of the pages and, like bookmarks in
+    /// `navigation`, force a `DJVM`. The directory and bookmarks are
+    /// compressed with `bzz`.
+    ///
+    /// Every chunk size is computed before its header is emitted, so the
+    /// writer never needs to seek and may be a socket or pipe. The
+    /// directory is written first, then each page as `pages` hands it over,
+    /// so the document is never held in memory as a whole.
+    pub fn write_to<W: Write>(
+        pages: &mut dyn PageFiles,
+        includes: &[IncludeFile],
+        labels: &[PageLabel],
+        navigation: Option<&DjVmNav>,
+        directory: DirectoryFormat,
+        bzz: &BzzOptions,
+        mut writer: W,
+    ) -> Result<()> {
+        if pages.count() == 0 {
+            return Ok(());
         }
 
-        if pages.len() == 1 {
+        let navigation = navigation.filter(|nav| !nav.bookmarks.is_empty());
+        if pages.count() == 1 && includes.is_empty() && navigation.is_none() {
             // Single-page document: write directly
-            output.write_all(&pages[0])?;
-            return Ok(output);
+            return pages.with_page(0, &mut |page| Ok(writer.write_all(page)?));
         }
 
         // Multi-page document: create DJVM
-        Self::assemble_djvm(&mut output, pages)?;
-        Ok(output)
+        Self::assemble_djvm(
+            &mut writer,
+            pages,
+            includes,
+            labels,
+            navigation,
+            directory,
+            bzz,
+        )
     }
 
-    /// Assembles a multi-page DJVM document
-    fn assemble_djvm(writer: &mut Vec<u8>, pages: &[Vec<u8>]) -> Result<()> {
-        // Build cheap slice references, stripping the AT&T prefix where present.
-        // No cloning — just pointer + length.
-        let page_chunks: Vec<&[u8]> = pages
+    /// Writes the document in indirect form, packed into a ZIP archive:
+    /// the index, a `FORM:DJVM` holding the directory and bookmarks, as
+    /// `index.djvu`, then every include file and page as a file named by
+    /// its ID. Even a single page gets an index.
+    pub fn write_indirect_zip<W: Write>(
+        pages: &mut dyn PageFiles,
+        includes: &[IncludeFile],
+        labels: &[PageLabel],
+        navigation: Option<&DjVmNav>,
+        directory: DirectoryFormat,
+        bzz: &BzzOptions,
+        writer: W,
+    ) -> Result<()> {
+        const INDEX: &str = "index.djvu";
+
+        let mut files: Vec<Vec<u8>> = includes
             .iter()
-            .map(|p| {
-                if p.starts_with(b"AT&TFORM") {
-                    &p[4..] // Slice — zero allocation
-                } else {
-                    p.as_slice()
-                }
+            .map(|file| [&b"AT&T"[..], strip(file.data())].concat())
+            .collect();
+        for index in 0..pages.count() {
+            pages.with_page(index, &mut |page| {
+                files.push([&b"AT&T"[..], strip(page)].concat());
+                Ok(())
+            })?;
+        }
+        let sizes: Vec<usize> = files[includes.len()..]
+            .iter()
+            .map(|file| file.len() - 4)
+            .collect();
+        let entries = Self::dir_entries(&sizes, includes, labels);
+        for entry in &entries {
+            check_file_name(&entry.id)?;
+        }
+        if entries.iter().any(|e| e.id == INDEX) {
+            return Err(DjvuError::InvalidArg(format!(
+                "A file ID of the document clashes with the index name {INDEX}"
+            )));
+        }
+        let (dir_chunk_id, dirm) = Self::encode_dirm(&entries, None, directory, bzz)?;
+        let nav_data = Self::encode_navm(navigation.filter(|nav| !nav.bookmarks.is_empty()), bzz)?;
+
+        let mut index = Vec::new();
+        {
+            let mut iff = IffWriter::new(std::io::Cursor::new(&mut index));
+            iff.write_magic_bytes()?;
+            iff.put_composite(ChunkId::FORM, ChunkId::DJVM)?;
+            iff.write_chunk(dir_chunk_id, &dirm)?;
+            if !nav_data.is_empty() {
+                iff.write_chunk(ChunkId::NAVM, &nav_data)?;
+            }
+            iff.close_chunk()?;
+        }
+
+        let mut zip_entries = vec![(INDEX, index.as_slice())];
+        zip_entries.extend(
+            entries
+                .iter()
+                .map(|e| e.id.as_str())
+                .zip(files.iter().map(Vec::as_slice)),
+        );
+        write_stored_zip(&zip_entries, writer)
+    }
+
+    /// Directory entries for `includes`, which go first so every page
+    /// follows the files it uses, then for pages of the given `FORM` chunk
+    /// sizes.
+    fn dir_entries<'a>(
+        page_sizes: &[usize],
+        includes: &[IncludeFile],
+        labels: &'a [PageLabel],
+    ) -> Vec<DirEntry<'a>> {
+        let mut entries: Vec<DirEntry> = includes
+            .iter()
+            .map(|file| DirEntry {
+                id: file.id().to_string(),
+                title: "",
+                file_type: FileType::Include,
+                size: strip(file.data()).len(),
             })
             .collect();
+        for (i, &size) in page_sizes.iter().enumerate() {
+            let label = labels.get(i);
+            entries.push(DirEntry {
+                id: label
+                    .and_then(|l| l.id.clone())
+                    .unwrap_or_else(|| PageIdScheme::Numbered.id(i)),
+                title: label.and_then(|l| l.title.as_deref()).unwrap_or(""),
+                file_type: FileType::Page,
+                size,
+            });
+        }
+        entries
+    }
+    /// The `NAVM` payload for `navigation`, BZZ-compressed as the spec
+    /// requires; empty without bookmarks.
+    fn encode_navm(navigation: Option<&DjVmNav>, bzz: &BzzOptions) -> Result<Vec<u8>> {
+        match navigation {
+            Some(nav) => {
+                let mut raw = Vec::new();
+                nav.encode(&mut raw)?;
+                bzz_compress_with(&raw, bzz)
+                    .map_err(|e| DjvuError::EncodingError(format!("BZZ compress NAVM failed: {e}")))
+            }
+            None => Ok(Vec::new()),
+        }
+    }
 
-        // NAVM feature disabled for now - keep code for future use
-        // Create automatic navigation bookmarks for multi-page documents
-        // let navigation = Self::create_default_navigation(pages.len())?;
-        // let mut nav_stream = MemoryStream::new();
-        // navigation.encode(&mut nav_stream)?;
-        // let nav_raw = nav_stream.into_vec();
-        // BZZ-compress the navigation data as required by DjVu spec
-        // let nav_data = bzz_compress(&nav_raw, 100)
-        //     .map_err(|e| crate::DjvuError::EncodingError(format!("BZZ compress NAVM failed: {e}")))?;
-        // let nav_chunk_size = 8 + nav_data.len() + (nav_data.len() % 2);
-        let nav_chunk_size = 0; // NAVM disabled
-
-        // Create directory and calculate offsets
+    /// Builds the directory chunk for files laid out back to back from
+    /// `start`, or for an indirect document when `start` is `None`,
+    /// returning its chunk ID and payload.
+    fn encode_dirm(
+        entries: &[DirEntry],
+        start: Option<u32>,
+        format: DirectoryFormat,
+        bzz: &BzzOptions,
+    ) -> Result<(ChunkId, Vec<u8>)> {
+        let bundled = start.is_some();
+        if !bundled && format == DirectoryFormat::Dir0 {
+            return Err(DjvuError::InvalidArg(
+                "DIR0 directories cannot describe indirect documents".to_string(),
+            ));
+        }
         let dirm = DjVmDir::new();
+        let mut dir0 = DjVmDir0::default();
+        let mut offset = start.unwrap_or(0);
+        for entry in entries {
+            if !offset.is_multiple_of(2) {
+                offset += 1;
+            }
+
+            let size = entry.size as u32;
+            let file = DjVuFile::new_with_offset(
+                &entry.id,
+                &entry.id,
+                entry.title,
+                entry.file_type,
+                offset,
+                size,
+            );
+            dirm.insert_file(file, -1)?;
+            dir0.add_file(&entry.id, true, offset, size)?;
+            offset += size;
+        }
 
-        // Estimate DIRM size conservatively
-        let estimated_dirm_size = 3 + (4 * page_chunks.len()) + 80;
-        let dirm_chunk_size = 8 + estimated_dirm_size + (estimated_dirm_size % 2);
+        let mut stream = crate::iff::MemoryStream::new();
+        let id = match format {
+            DirectoryFormat::Dirm => {
+                dirm.encode_with(&mut stream, bundled, DjVmDir::VERSION, bzz)?;
+                ChunkId::DIRM
+            }
+            DirectoryFormat::DirmV0 => {
+                dirm.encode_with(&mut stream, bundled, 0, bzz)?;
+                ChunkId::DIRM
+            }
+            DirectoryFormat::Dir0 => {
+                dir0.encode(&mut stream)?;
+                ChunkId::DIR0
+            }
+        };
+        Ok((id, stream.into_vec()))
+    }
+
+    /// Assembles a multi-page DJVM document
+    fn assemble_djvm<W: Write>(
+        writer: &mut W,
+        pages: &mut dyn PageFiles,
+        includes: &[IncludeFile],
+        labels: &[PageLabel],
+        navigation: Option<&DjVmNav>,
+        directory: DirectoryFormat,
+        bzz: &BzzOptions,
+    ) -> Result<()> {
+        let page_sizes = (0..pages.count())
+            .map(|index| pages.size(index))
+            .collect::<Result<Vec<_>>>()?;
+        let entries = Self::dir_entries(&page_sizes, includes, labels);
+        let nav_data = Self::encode_navm(navigation, bzz)?;
+        let padded = |len: usize| len + len % 2;
+        let nav_chunk_size = if nav_data.is_empty() {
+            0
+        } else {
+            8 + padded(nav_data.len())
+        };
 
-        // Calculate initial page offsets (after DIRM + NAVM chunks)
         // Offsets in DIRM are ABSOLUTE file positions (confirmed by analyzing working files).
         // The base is AT&T(4) + FORM(4) + size(4) + DJVM(4) = 16 bytes.
         let base_offset = 16u32;
-        let mut current_offset = base_offset + dirm_chunk_size as u32 + nav_chunk_size as u32;
-        let mut file_offsets = Vec::new();
 
-        for (i, page_chunk) in page_chunks.iter().enumerate() {
-            if current_offset % 2 != 0 {
-                current_offset += 1;
+        // The DIRM size does not depend on the offset values (they are fixed
+        // width), so encode once to measure it, then again with the real offsets.
+        let (_, probe) = Self::encode_dirm(&entries, Some(0), directory, bzz)?;
+        let dirm_chunk_size = 8 + padded(probe.len());
+        let first_page_offset = base_offset + dirm_chunk_size as u32 + nav_chunk_size as u32;
+        let (dir_chunk_id, final_dirm_data) =
+            Self::encode_dirm(&entries, Some(first_page_offset), directory, bzz)?;
+        debug_assert_eq!(final_dirm_data.len(), probe.len());
+
+        // Every chunk is padded to even length, so the files follow the
+        // directory back to back.
+        let files_size: usize = entries.iter().map(|e| padded(e.size)).sum();
+
+        // Each file goes out as it is handed over; the writer checks the
+        // alignment of every chunk and that the FORM comes out the size its
+        // header gives.
+        let mut iff = IffWriter::streaming(writer).with_alignment_audit(true);
+        iff.write_magic_bytes()?;
+        iff.put_sized_composite(
+            ChunkId::FORM,
+            ChunkId::DJVM,
+            dirm_chunk_size + nav_chunk_size + files_size,
+        )?;
+        iff.write_chunk(dir_chunk_id, &final_dirm_data)?;
+        if !nav_data.is_empty() {
+            iff.write_chunk(ChunkId::NAVM, &nav_data)?;
+        }
+
+        let mut write_file = |data: &[u8], size: usize| -> Result<()> {
+            if data.len() != size {
+                return Err(DjvuError::InvalidOperation(format!(
+                    "A file of the document changed size while it was written: {} bytes, \
+                     {size} in the directory",
+                    data.len()
+                )));
             }
+            iff.write_complete_chunk(data)
+        };
+        for file in includes {
+            let data = strip(file.data());
+            write_file(data, data.len())?;
+        }
+        for (index, &size) in page_sizes.iter().enumerate() {
+            pages.with_page(index, &mut |page| write_file(strip(page), size))?;
+        }
+        iff.close_chunk()?;
+        iff.flush()?;
+
+        Ok(())
+    }
+}
 
-            file_offsets.push(current_offset);
-            current_offset += page_chunk.len() as u32;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::{DjvuBuilder, PageBuilder};
+    use crate::image::image_formats::{Pixel, Pixmap};
 
-            let page_id = format!("p{:04}.djvu", i + 1);
-            let file = DjVuFile::new_with_offset(
-                &page_id,
-                &page_id,
-                "",
-                FileType::Page,
-                file_offsets[i],
-                page_chunk.len() as u32,
-            );
-            dirm.insert_file(file, -1)?;
+    fn labelled_document() -> Result<Vec<u8>> {
+        labelled_builder()?.finalize()
+    }
+
+    fn labelled_builder() -> Result<crate::doc::DjvuDocument> {
+        labelled_builder_from(DjvuBuilder::new(3))
+    }
+
+    fn labelled_builder_from(builder: DjvuBuilder) -> Result<crate::doc::DjvuDocument> {
+        let doc = builder.build();
+        for page_num in 0..3 {
+            let page = PageBuilder::new(page_num, 16, 16)
+                .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?
+                .build()?;
+            if page_num == 1 {
+                doc.add_page_with_id(page, "preface.djvu", "Preface, with a long title")?;
+            } else {
+                doc.add_page(page)?;
+            }
+        }
+        doc.set_page_title(2, "iii")?;
+        Ok(doc)
+    }
+
+    #[test]
+    fn test_dirm_offsets_point_at_pages_with_titles() -> Result<()> {
+        let bytes = labelled_document()?;
+        assert_eq!(&bytes[16..20], b"DIRM");
+        let dirm = &bytes[24..];
+        assert_eq!(u16::from_be_bytes([dirm[1], dirm[2]]), 3);
+        for i in 0..3 {
+            let at = 3 + 4 * i;
+            let offset =
+                u32::from_be_bytes([dirm[at], dirm[at + 1], dirm[at + 2], dirm[at + 3]]) as usize;
+            assert_eq!(&bytes[offset..offset + 4], b"FORM", "page {i}");
+            assert_eq!(&bytes[offset + 8..offset + 12], b"DJVU", "page {i}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_to_streams_same_bytes() -> Result<()> {
+        let pages = vec![
+            b"AT&TFORM\0\0\0\x05DJVU\0".to_vec(),
+            b"FORM\0\0\0\x04DJVU".to_vec(),
+        ];
+        let labels = [PageLabel::default(), PageLabel::default()];
+        let expected = DocumentEncoder::assemble_pages(
+            &pages,
+            &[],
+            &labels,
+            None,
+            DirectoryFormat::Dirm,
+            &BzzOptions::default(),
+        )?;
+
+        // A pipe: `Write` only, flushed in small pieces.
+        let mut out = std::io::BufWriter::with_capacity(7, Vec::new());
+        DocumentEncoder::write_to(
+            &mut &pages[..],
+            &[],
+            &labels,
+            None,
+            DirectoryFormat::Dirm,
+            &BzzOptions::default(),
+            &mut out,
+        )?;
+        assert_eq!(out.into_inner().unwrap(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_document_streams_pages() -> Result<()> {
+        use crate::doc::output::{OutputTransform, TransformWriter};
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Passes the document on, noting its largest single write.
+        struct Largest(Arc<AtomicUsize>);
+        struct Noting<'a>(&'a mut dyn Write, Arc<AtomicUsize>);
+
+        impl OutputTransform for Largest {
+            fn wrap<'a>(&self, out: &'a mut dyn Write) -> Result<Box<dyn TransformWriter + 'a>> {
+                Ok(Box::new(Noting(out, Arc::clone(&self.0))))
+            }
+        }
+        impl Write for Noting<'_> {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.1.fetch_max(buf.len(), Ordering::SeqCst);
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.0.flush()
+            }
+        }
+        impl TransformWriter for Noting<'_> {
+            fn finish(self: Box<Self>) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let expected = labelled_document()?;
+        let largest = Arc::new(AtomicUsize::new(0));
+        let doc = labelled_builder_from(
+            DjvuBuilder::new(3).with_output_transform(Largest(Arc::clone(&largest))),
+        )?;
+        let mut out = Vec::new();
+        doc.write_to(&mut out)?;
+        assert_eq!(out, expected);
+        // The pages are written one at a time, and stay in the document.
+        assert!(largest.load(Ordering::SeqCst) < expected.len() / 2);
+        assert_eq!(doc.finalize()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_indirect_zip_opens_like_bundle() -> Result<()> {
+        use crate::doc::{DjVuDocEditor, MemorySource};
+        use crate::utils::zip::tests::read_stored_zip;
+
+        let mut zip = Vec::new();
+        labelled_builder()?.write_indirect_zip(&mut zip)?;
+        let files = read_stored_zip(&zip);
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["index.djvu", "p0001.djvu", "preface.djvu", "p0003.djvu"]
+        );
+        // The index FORM takes in every chunk with its pad byte.
+        let index = &files[0].1;
+        let form_size = u32::from_be_bytes(index[8..12].try_into().unwrap()) as usize;
+        assert_eq!((form_size % 2, 12 + form_size), (0, index.len()));
+
+        let mut source = MemorySource::new();
+        for (name, data) in &files[1..] {
+            assert!(data.starts_with(b"AT&TFORM"));
+            source.insert(name.clone(), data.clone());
         }
+        let indirect = DjVuDocEditor::from_indirect(&files[0].1, &source)?;
+        let bundled = DjVuDocEditor::from_bytes(&labelled_document()?)?;
+        assert_eq!(indirect.page_count(), 3);
+        assert_eq!(indirect.to_bytes()?, bundled.to_bytes()?);
 
-        // Encode DIRM to get actual size
-        let mut dirm_stream = crate::iff::MemoryStream::new();
-        dirm.encode_explicit(&mut dirm_stream, true, true)?;
-        let dirm_data = dirm_stream.into_vec();
+        // The same document always gives the same archive.
+        let mut again = Vec::new();
+        labelled_builder()?.write_indirect_zip(&mut again)?;
+        assert_eq!(again, zip);
 
-        // Check if estimate was accurate enough
-        let actual_dirm_chunk_size = 8 + dirm_data.len() + (dirm_data.len() % 2);
-        let final_dirm_data;
+        // DIR0 has no indirect form.
+        assert!(
+            DocumentEncoder::write_indirect_zip(
+                &mut &[][..],
+                &[],
+                &[],
+                None,
+                DirectoryFormat::Dir0,
+                &BzzOptions::default(),
+                Vec::new(),
+            )
+            .is_err()
+        );
 
-        if (actual_dirm_chunk_size as i32 - dirm_chunk_size as i32).abs() > 16 {
-            // Re-calculate with correct DIRM size
-            let corrected_dirm = DjVmDir::new();
-            current_offset = base_offset + actual_dirm_chunk_size as u32 + nav_chunk_size as u32;
-            let mut corrected_offsets = Vec::new();
+        // IDs that would unpack outside the destination are refused.
+        for id in ["../escape.djvu", "/tmp/escape.djvu", "sub\\escape.djvu"] {
+            let doc = DjvuBuilder::new(1).build();
+            let page = PageBuilder::new(0, 16, 16)
+                .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?
+                .build()?;
+            doc.add_page_with_id(page, id, "")?;
+            assert!(doc.write_indirect_zip(Vec::new()).is_err(), "{id}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_file_precedes_pages() -> Result<()> {
+        use crate::annotations::Annotations;
+        use crate::doc::IncludeFileBuilder;
+
+        let doc = DjvuBuilder::new(1).build();
+        let shared = IncludeFileBuilder::new("shared_anno.iff")
+            .with_annotations(&Annotations::default())?
+            .build()?;
+        doc.add_include_file(shared.clone())?;
+        assert!(doc.add_include_file(shared).is_err());
+        let page = PageBuilder::new(0, 16, 16)
+            .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?
+            .with_include("shared_anno.iff")
+            .build()?;
+        doc.add_page(page)?;
+        let bytes = doc.finalize()?;
 
-            for (i, page_chunk) in page_chunks.iter().enumerate() {
-                if current_offset % 2 != 0 {
-                    current_offset += 1;
+        // A single page with an include file is still bundled.
+        assert_eq!(&bytes[12..16], b"DJVM");
+        let offsets = page_offsets(&bytes, &bytes[24..], 4);
+        assert_eq!(offsets.len(), 2);
+        assert_eq!(&bytes[offsets[0] + 8..offsets[0] + 12], b"DJVI");
+        assert_eq!(&bytes[offsets[1] + 8..offsets[1] + 12], b"DJVU");
+
+        let page = &bytes[offsets[1]..];
+        let incl = page.windows(4).position(|w| w == b"INCL").unwrap();
+        assert_eq!(&page[incl + 8..incl + 8 + 15], b"shared_anno.iff");
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbol_dictionary_seeds_next_document() -> Result<()> {
+        use crate::doc::{PageComponents, PageEncodeParams, SYMBOL_DICT_ID};
+        use crate::encode::jb2::symbol_dict::{BitImage, SharedDict};
+        use crate::encode::jb2::{ClusterParams, cluster_symbols};
+        use std::sync::Arc;
+
+        // Pages of "L" and "T" glyphs; `extra` adds a box of its own.
+        let page = |extra: bool| -> Result<PageComponents> {
+            let mut image = BitImage::new(240, 60).unwrap();
+            for i in 0..6 {
+                let x = 10 + 36 * i;
+                for y in 10..34 {
+                    if i % 2 == 0 {
+                        image.fill_run(y, x, x + 3);
+                    } else {
+                        image.fill_run(y, x + 8, x + 11);
+                    }
+                }
+                let bar = if i % 2 == 0 { 30..34 } else { 10..14 };
+                for y in bar {
+                    image.fill_run(y, x, x + 19);
                 }
+            }
+            if extra {
+                for y in 40..56 {
+                    image.fill_run(y, 200, 230);
+                }
+            }
+            PageComponents::new().with_foreground(image)
+        };
 
-                corrected_offsets.push(current_offset);
-                current_offset += page_chunk.len() as u32;
-
-                let page_id = format!("p{:04}.djvu", i + 1);
-                let file = DjVuFile::new_with_offset(
-                    &page_id,
-                    &page_id,
-                    "",
-                    FileType::Page,
-                    corrected_offsets[i],
-                    page_chunk.len() as u32,
-                );
-                corrected_dirm.insert_file(file, -1)?;
-            }
-
-            // Re-encode with corrected offsets
-            let mut corrected_stream = crate::iff::MemoryStream::new();
-            corrected_dirm.encode_explicit(&mut corrected_stream, true, true)?;
-            final_dirm_data = corrected_stream.into_vec();
-        } else {
-            final_dirm_data = dirm_data;
-        }
-
-        // Calculate total size
-        let total_dirm_chunk_size = 8 + final_dirm_data.len() + (final_dirm_data.len() % 2);
-        let pages_total_size: usize = page_chunks.iter().map(|p| p.len()).sum();
-
-        // Calculate padding
-        let mut padding_bytes = 0;
-        let mut pos = base_offset as usize + total_dirm_chunk_size + nav_chunk_size;
-        for page_chunk in &page_chunks {
-            if pos % 2 != 0 {
-                padding_bytes += 1;
-                pos += 1;
-            }
-            pos += page_chunk.len();
-        }
-
-        let total_djvm_payload =
-            total_dirm_chunk_size + nav_chunk_size + pages_total_size + padding_bytes;
-
-        // Write DJVM header
-        writer.write_all(b"AT&TFORM")?;
-        writer.write_u32::<BigEndian>((4 + total_djvm_payload) as u32)?;
-        writer.write_all(b"DJVM")?;
-
-        // Write DIRM chunk
-        writer.write_all(b"DIRM")?;
-        writer.write_u32::<BigEndian>(final_dirm_data.len() as u32)?;
-        writer.write_all(&final_dirm_data)?;
-        if final_dirm_data.len() % 2 != 0 {
-            writer.write_u8(0)?; // padding
-        }
-
-        // NAVM chunk disabled - keep code for future use
-        // Write NAVM chunk (automatic navigation bookmarks)
-        // if !nav_data.is_empty() {
-        //     writer.write_all(b"NAVM")?;
-        //     writer.write_u32::<BigEndian>(nav_data.len() as u32)?;
-        //     writer.write_all(&nav_data)?;
-        //     if nav_data.len() % 2 != 0 {
-        //         writer.write_u8(0)?; // padding
-        //     }
-        // }
-
-        // Write page chunks with alignment
-        let mut written_pos = base_offset as usize + total_dirm_chunk_size + nav_chunk_size;
-        for page_data in &page_chunks {
-            if written_pos % 2 != 0 {
-                writer.write_u8(0)?;
-                written_pos += 1;
-            }
-
-            writer.write_all(page_data)?;
-            written_pos += page_data.len();
-        }
-
-        Ok(())
-    }
-
-    // NAVM feature disabled - keep code for future use
-    // /// Creates default navigation structure with simple page bookmarks
-    // fn create_default_navigation(page_count: usize) -> Result<DjVmNav> {
-    //     let mut nav = DjVmNav::new();
-    //
-    //     for i in 0..page_count {
-    //         let bookmark = Bookmark {
-    //             title: format!("Page {}", i + 1),
-    //             dest: format!("#p{:04}.djvu", i + 1),
-    //             children: Vec::new(), // Leaf node (no children)
-    //         };
-    //         nav.bookmarks.push(bookmark);
-    //     }
-    //
-    //     Ok(nav)
-    // }
+        // Export the shapes of a first document.
+        let params = PageEncodeParams::default();
+        let symbols = [
+            page(false)?.jb2_symbols(&params),
+            page(false)?.jb2_symbols(&params),
+        ];
+        let dict = cluster_symbols(&symbols, &ClusterParams::default()).dict;
+        assert_eq!(dict.shape_count(), 2);
+
+        // The next document blits them from the dictionary.
+        let doc = DjvuBuilder::new(2)
+            .with_symbol_dictionary(dict.clone())?
+            .build();
+        doc.add_components(0, page(false)?)?;
+        doc.add_components(1, page(true)?)?;
+        let stats = doc.encode_stats()?.symbols;
+        assert_eq!((stats.symbols, stats.hits), (13, 12));
+
+        // A checkpoint resumes with the dictionary read back from its file,
+        // but not with other shapes of the same sizes.
+        let mut checkpoint = Vec::new();
+        doc.write_checkpoint(&mut checkpoint)?;
+        let mut file = Vec::new();
+        dict.write_to(&mut file)?;
+        let reloaded = SharedDict::read_from(file.as_slice())?;
+        let resumed = DjvuBuilder::new(2)
+            .with_symbol_dictionary(reloaded)?
+            .build();
+        assert_eq!(resumed.restore_checkpoint(checkpoint.as_slice())?, 2);
+        let mut shapes = dict.shapes().to_vec();
+        let corner = shapes[0].get_pixel_unchecked(0, 0);
+        shapes[0].set_usize(0, 0, !corner);
+        let other = DjvuBuilder::new(2)
+            .with_symbol_dictionary(SharedDict::new(shapes))?
+            .build();
+        assert!(other.restore_checkpoint(checkpoint.as_slice()).is_err());
+
+        let bytes = doc.finalize()?;
+        let offsets = page_offsets(&bytes, &bytes[24..], 4);
+        assert_eq!(offsets.len(), 3);
+        assert_eq!(&bytes[offsets[0] + 8..offsets[0] + 12], b"DJVI");
+        let first = &bytes[offsets[1]..];
+        let incl = first.windows(4).position(|w| w == b"INCL").unwrap();
+        assert_eq!(
+            &first[incl + 8..incl + 8 + SYMBOL_DICT_ID.len()],
+            SYMBOL_DICT_ID.as_bytes()
+        );
+
+        // A dictionary without the include that holds it is an error.
+        let orphan = page(false)?.with_shared_dict(Arc::new(SharedDict::new(Vec::new())));
+        assert!(orphan.encode(&params, 1, 300, 1, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_recovery_skip_and_placeholder() -> Result<()> {
+        use crate::DjvuError;
+        use crate::doc::ErrorRecoveryAction;
+
+        let white = || Pixmap::from_pixel(16, 16, Pixel::white());
+        let failure = || DjvuError::EncodingError("corrupt scan".to_string());
+
+        let doc = DjvuBuilder::new(3)
+            .with_error_recovery(ErrorRecoveryAction::SkipPage)
+            .build();
+        for page_num in [0, 2] {
+            doc.add_page(
+                PageBuilder::new(page_num, 16, 16)
+                    .with_background(white())?
+                    .build()?,
+            )?;
+        }
+        doc.recover_failed_page(1, (16, 16), failure())?;
+        let stats = doc.encode_stats()?;
+        assert_eq!((stats.pages_encoded, stats.pages_skipped()), (2, 1));
+        assert_eq!(stats.failures[0].page_num, 1);
+        let bytes = doc.finalize()?;
+        assert_eq!(page_offsets(&bytes, &bytes[24..], 4).len(), 2);
+
+        let doc = DjvuBuilder::new(1)
+            .with_error_recovery(ErrorRecoveryAction::Placeholder)
+            .build();
+        doc.recover_failed_page(0, (40, 30), failure())?;
+        assert_eq!(doc.encode_stats()?.pages_substituted(), 1);
+        let bytes = doc.finalize()?;
+        assert_eq!(&bytes[12..20], b"DJVUINFO");
+        assert_eq!(&bytes[24..28], &[0, 40, 0, 30]);
+
+        let doc = DjvuBuilder::new(1).build();
+        assert!(doc.recover_failed_page(0, (16, 16), failure()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lazy_pages_load_when_written() -> Result<()> {
+        use crate::DjvuError;
+        use crate::doc::{ErrorRecoveryAction, PageComponents};
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let doc = DjvuBuilder::new(3).build();
+        for page_num in 0..3 {
+            let loaded = loaded.clone();
+            doc.add_page_lazy(page_num, move || {
+                loaded.fetch_add(1, Ordering::SeqCst);
+                PageComponents::new().with_background(Pixmap::from_pixel(16, 16, Pixel::white()))
+            })?;
+        }
+        assert!(doc.add_page_lazy(1, || Ok(PageComponents::new())).is_err());
+        assert!(doc.add_page_lazy(3, || Ok(PageComponents::new())).is_err());
+        let white =
+            || PageComponents::new().with_background(Pixmap::from_pixel(16, 16, Pixel::white()));
+        assert!(doc.add_components(2, white()?).is_err());
+        assert!(
+            doc.add_page(
+                PageBuilder::new(2, 16, 16)
+                    .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?
+                    .build()?
+            )
+            .is_err()
+        );
+        assert_eq!((loaded.load(Ordering::SeqCst), doc.pages_ready()), (0, 0));
+
+        // The pages come out as if they had been added up front.
+        let eager = DjvuBuilder::new(3).build();
+        for page_num in 0..3 {
+            eager.add_page(
+                PageBuilder::new(page_num, 16, 16)
+                    .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?
+                    .build()?,
+            )?;
+        }
+        assert_eq!(doc.finalize()?, eager.finalize()?);
+        // Each page is loaded to be measured, then again as it is written,
+        // and none of them is kept.
+        assert_eq!(loaded.load(Ordering::SeqCst), 6);
+        assert_eq!((doc.pages_ready(), doc.memory_used()), (3, 0));
+        assert_eq!(doc.finalize()?, eager.finalize()?);
+        assert_eq!(loaded.load(Ordering::SeqCst), 9);
+
+        // A source that gives another page the second time cannot be written.
+        let doc = DjvuBuilder::new(2).build();
+        doc.add_components(1, white()?)?;
+        let calls = AtomicUsize::new(0);
+        doc.add_page_lazy(0, move || {
+            let mut scan = Pixmap::from_pixel(16, 16, Pixel::white());
+            if calls.fetch_add(1, Ordering::SeqCst) > 0 {
+                for i in 0..16 {
+                    scan.put_pixel(i, (i * 7) % 16, Pixel::black());
+                }
+            }
+            PageComponents::new().with_background(scan)
+        })?;
+        assert!(doc.finalize().is_err());
+
+        // A source that fails leaves a placeholder the size of its neighbor.
+        let doc = DjvuBuilder::new(2)
+            .with_error_recovery(ErrorRecoveryAction::Placeholder)
+            .build();
+        doc.add_page(
+            PageBuilder::new(0, 40, 30)
+                .with_background(Pixmap::from_pixel(40, 30, Pixel::white()))?
+                .build()?,
+        )?;
+        doc.add_page_lazy(1, || Err(DjvuError::InvalidArg("missing scan".to_string())))?;
+        let bytes = doc.finalize()?;
+        assert_eq!(doc.encode_stats()?.pages_substituted(), 1);
+        let offsets = page_offsets(&bytes, &bytes[24..], 4);
+        assert_eq!(&bytes[offsets[1] + 20..offsets[1] + 24], &[0, 40, 0, 30]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_blank_pages() -> Result<()> {
+        use crate::doc::{BlankPageAction, BlankPageParams};
+
+        let page = |page_num: usize, inked: bool| {
+            let mut scan = Pixmap::from_pixel(64, 64, Pixel::new(245, 245, 240));
+            if inked {
+                for y in 20..40 {
+                    for x in 10..50 {
+                        scan.put_pixel(x, y, Pixel::black());
+                    }
+                }
+            }
+            PageBuilder::new(page_num, 64, 64)
+                .with_background(scan)?
+                .build()
+        };
+
+        let doc = DjvuBuilder::new(3)
+            .with_blank_pages(BlankPageAction::Drop, BlankPageParams::default())
+            .build();
+        for (page_num, inked) in [(0, true), (1, false), (2, true)] {
+            doc.add_page(page(page_num, inked)?)?;
+        }
+        let stats = doc.encode_stats()?;
+        assert_eq!((stats.pages_encoded, stats.blank_pages), (2, vec![1]));
+        let bytes = doc.finalize()?;
+        assert_eq!(page_offsets(&bytes, &bytes[24..], 4).len(), 2);
+
+        let doc = DjvuBuilder::new(1)
+            .with_blank_pages(BlankPageAction::Placeholder, BlankPageParams::default())
+            .build();
+        doc.add_page(page(0, false)?)?;
+        assert_eq!(doc.encode_stats()?.blank_pages, [0]);
+        let placeholder = doc.finalize()?;
+        assert_eq!(&placeholder[12..20], b"DJVUINFO");
+
+        let doc = DjvuBuilder::new(1)
+            .with_blank_pages(BlankPageAction::Flag, BlankPageParams::default())
+            .build();
+        doc.add_page(page(0, false)?)?;
+        assert_eq!(doc.encode_stats()?.blank_pages, [0]);
+        assert!(doc.finalize()?.len() > placeholder.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bookmarks_written_after_directory() -> Result<()> {
+        use crate::doc::djvu_dir::Bookmark;
+
+        let doc = DjvuBuilder::new(1).build();
+        doc.add_page(
+            PageBuilder::new(0, 16, 16)
+                .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?
+                .build()?,
+        )?;
+        doc.set_bookmarks(DjVmNav {
+            bookmarks: vec![Bookmark {
+                title: "Cover".to_string(),
+                dest: "#p0001.djvu".to_string(),
+                children: Vec::new(),
+            }],
+        })?;
+        let bytes = doc.finalize()?;
+
+        // A single page with bookmarks still needs a DJVM to hold them.
+        assert_eq!(&bytes[12..20], b"DJVMDIRM");
+        let dirm_len = u32::from_be_bytes(bytes[20..24].try_into().unwrap()) as usize;
+        let navm = 24 + dirm_len + dirm_len % 2;
+        assert_eq!(&bytes[navm..navm + 4], b"NAVM");
+        page_offsets(&bytes, &bytes[24..], 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_links_checked_and_canonicalized() -> Result<()> {
+        use crate::doc::integrity::{PageIntegrity, verify_document};
+        use crate::iff::bs_byte_stream::bzz_decompress;
+
+        let doc = DjvuBuilder::new(2)
+            .with_canonical_links(true)
+            .with_integrity_checksums(true)
+            .build();
+        let page = PageBuilder::new(0, 16, 16)
+            .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?
+            .with_hyperlink("#Preface", 0, 0, 8, 8, "")
+            .with_hyperlink("#Glossary", 8, 8, 8, 8, "")
+            .build()?;
+        doc.add_page(page)?;
+        let page = PageBuilder::new(1, 16, 16)
+            .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?
+            .with_hyperlink("#1", 0, 0, 8, 8, "")
+            .build()?;
+        doc.add_page_with_id(page, "preface.djvu", "Preface")?;
+        let bytes = doc.finalize()?;
+
+        let broken = doc.encode_stats()?.broken_links;
+        assert_eq!(broken.len(), 1);
+        assert_eq!(
+            (broken[0].page_num, broken[0].url.as_str()),
+            (0, "#Glossary")
+        );
+
+        assert_eq!(verify_document(&bytes)?, [PageIntegrity::Verified; 2]);
+        let first = page_offsets(&bytes, &bytes[24..], 4)[0];
+        let antz = first
+            + bytes[first..]
+                .windows(4)
+                .position(|w| w == b"ANTz")
+                .unwrap();
+        let len = u32::from_be_bytes(bytes[antz + 4..antz + 8].try_into().unwrap()) as usize;
+        let text = bzz_decompress(&bytes[antz + 8..antz + 8 + len])?;
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("\"#preface.djvu\""), "{text}");
+        assert!(text.contains("\"#Glossary\""), "{text}");
+        Ok(())
+    }
+
+    fn page_offsets(bytes: &[u8], dir: &[u8], stride: usize) -> Vec<usize> {
+        let count = u16::from_be_bytes([dir[1], dir[2]]) as usize;
+        (0..count)
+            .map(|i| {
+                let at = 3 + stride * i;
+                u32::from_be_bytes([dir[at], dir[at + 1], dir[at + 2], dir[at + 3]]) as usize
+            })
+            .inspect(|&offset| assert_eq!(&bytes[offset..offset + 4], b"FORM"))
+            .collect()
+    }
+
+    fn two_page_document(format: DirectoryFormat) -> Result<Vec<u8>> {
+        let doc = DjvuBuilder::new(2).with_directory_format(format).build();
+        for page_num in 0..2 {
+            let page = PageBuilder::new(page_num, 16, 16)
+                .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?
+                .build()?;
+            doc.add_page(page)?;
+        }
+        doc.finalize()
+    }
+
+    #[test]
+    fn test_dirm_version_0_stores_sizes_with_offsets() -> Result<()> {
+        let bytes = two_page_document(DirectoryFormat::DirmV0)?;
+        assert_eq!(&bytes[16..20], b"DIRM");
+        let dirm = &bytes[24..];
+        assert_eq!(dirm[0], 0x80);
+        let offsets = page_offsets(&bytes, dirm, 7);
+        for (i, &offset) in offsets.iter().enumerate() {
+            let at = 3 + 7 * i + 4;
+            let size = u32::from_be_bytes([0, dirm[at], dirm[at + 1], dirm[at + 2]]) as usize;
+            let form_len =
+                u32::from_be_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            assert_eq!(size, form_len + 8, "page {i}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir0_output() -> Result<()> {
+        let bytes = two_page_document(DirectoryFormat::Dir0)?;
+        assert_eq!(&bytes[16..20], b"DIR0");
+
+        let len = u32::from_be_bytes(bytes[20..24].try_into().unwrap()) as usize;
+        let mut stream = std::io::Cursor::new(bytes[24..24 + len].to_vec());
+        let mut dir0 = DjVmDir0::default();
+        dir0.decode(&mut stream)?;
+        for i in 0..2 {
+            let rec = dir0.get_file_by_num(i).unwrap();
+            assert_eq!(rec.name, format!("p{:04}.djvu", i + 1));
+            let offset = rec.offset as usize;
+            assert_eq!(&bytes[offset..offset + 4], b"FORM");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_id_validation() -> Result<()> {
+        use crate::annotations::Annotations;
+        use crate::doc::IncludeFileBuilder;
+
+        let doc = DjvuBuilder::new(2).build();
+        let page = || {
+            PageBuilder::new(0, 8, 8)
+                .with_background(Pixmap::from_pixel(8, 8, Pixel::white()))
+                .and_then(|p| p.build())
+        };
+        // Collides with the default ID of page 1.
+        assert!(doc.add_page_with_id(page()?, "p0002.djvu", "").is_err());
+        assert!(doc.add_page_with_id(page()?, "bad\0id", "").is_err());
+        assert!(doc.add_page_with_id(page()?, "", "").is_err());
+        // Links would take these for page numbers.
+        assert!(doc.add_page_with_id(page()?, "2", "").is_err());
+        assert!(doc.add_page_with_id(page()?, "+1", "").is_err());
+        assert!(doc.set_page_title(5, "x").is_err());
+
+        let shared = IncludeFileBuilder::new("shared.djvi")
+            .with_annotations(&Annotations::default())?
+            .build()?;
+        doc.add_include_file(shared)?;
+        assert!(doc.add_page_with_id(page()?, "shared.djvi", "").is_err());
+
+        // Links that lead past the last page are refused up front.
+        let linked = |url: &str| {
+            PageBuilder::new(0, 8, 8)
+                .with_background(Pixmap::from_pixel(8, 8, Pixel::white()))
+                .map(|p| p.with_hyperlink(url, 0, 0, 4, 4, ""))
+                .and_then(|p| p.build())
+        };
+        for url in ["#", "#3", "#+2", "#-1"] {
+            assert!(
+                doc.add_page_with_id(linked(url)?, "cover", "").is_err(),
+                "{url}"
+            );
+        }
+        doc.add_page_with_id(linked("#2")?, "cover", "Cover")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_id_schemes() -> Result<()> {
+        use crate::doc::builder::{PageIdScheme, page_id_from_path};
+        use crate::doc::chunk_stats::DocumentStats;
+
+        assert_eq!(
+            page_id_from_path("scans/Chapter 1 - 003.tif").as_deref(),
+            Some("Chapter_1_-_003.djvu")
+        );
+        assert_eq!(page_id_from_path("..").as_deref(), None);
+
+        let doc = DjvuBuilder::new(3)
+            .with_page_ids(PageIdScheme::custom(|i| format!("leaf-{}.djvu", i + 10)))
+            .build();
+        let page = |n| {
+            PageBuilder::new(n, 8, 8)
+                .with_background(Pixmap::from_pixel(8, 8, Pixel::white()))
+                .and_then(|p| p.build())
+        };
+        doc.add_page(page(0)?)?;
+        doc.add_page_from_file(page(1)?, "/in/plate ii.png")?;
+        // The formatter's ID for page 2 is taken by page 0.
+        assert!(doc.add_page_with_id(page(2)?, "leaf-10.djvu", "").is_err());
+        doc.add_page(page(2)?)?;
+
+        let stats = DocumentStats::from_document(&doc.finalize()?)?;
+        let ids: Vec<String> = stats
+            .directory
+            .unwrap()
+            .files
+            .into_iter()
+            .map(|f| f.id)
+            .collect();
+        assert_eq!(ids, ["leaf-10.djvu", "plate_ii.djvu", "leaf-12.djvu"]);
+
+        // Numbering counts the pages kept, both when checking IDs as pages
+        // come in and when writing them.
+        use crate::doc::blank::{BlankPageAction, BlankPageParams};
+        let doc = DjvuBuilder::new(3)
+            .with_page_ids(PageIdScheme::custom(|i| format!("leaf-{}.djvu", i + 10)))
+            .with_blank_pages(BlankPageAction::Drop, BlankPageParams::default())
+            .build();
+        let inked = |n| {
+            let half = Pixmap::from_fn(8, 8, |x, _| {
+                if x < 4 {
+                    Pixel::black()
+                } else {
+                    Pixel::white()
+                }
+            });
+            PageBuilder::new(n, 8, 8)
+                .with_background(half)
+                .and_then(|p| p.build())
+        };
+        doc.add_page(page(0)?)?;
+        doc.add_page(inked(1)?)?;
+        assert!(doc.add_page_with_id(inked(2)?, "leaf-10.djvu", "").is_err());
+        doc.add_page_with_id(inked(2)?, "leaf-12.djvu", "")?;
+        let stats = DocumentStats::from_document(&doc.finalize()?)?;
+        let ids: Vec<String> = stats
+            .directory
+            .unwrap()
+            .files
+            .into_iter()
+            .map(|f| f.id)
+            .collect();
+        assert_eq!(ids, ["leaf-10.djvu", "leaf-12.djvu"]);
+        Ok(())
+    }
 }
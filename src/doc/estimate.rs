@@ -0,0 +1,302 @@
+//! Page size prediction.
+//!
+//! [`PageComponents::estimate_size`] predicts how many bytes
+//! [`PageComponents::encode`] will produce without running the entropy
+//! coders, so a batch job can try several settings against a storage budget
+//! before committing hours to the real encode. IW44 layers are modelled from
+//! their wavelet coefficients (see [`crate::encode::iw44::estimate`]), the
+//! JB2 layer from its connected components; text, annotations, palettes and
+//! raw chunks are small and simply encoded. Expect the total to land within
+//! about 15% of the real size.
+
+use crate::doc::page_encoder::{
+    PageComponents, PageEncodeParams, extract_page_shapes, iw44_encoder, layer_mask, solid_iw44,
+};
+use crate::encode::iw44::estimate::estimate_chunk_bytes;
+use crate::encode::jb2::shapes_to_encoder_format;
+use crate::encode::jb2::symbol_dict::BitImage;
+use crate::iff::bs_byte_stream::bzz_compress;
+use crate::image::image_formats::{Bitmap, Pixel, Pixmap};
+use crate::image::solid::solid_color;
+use crate::{DjvuError, Result};
+
+// Fitted against `JB2Encoder::encode_page_with_shapes` on text and line art.
+/// Bits spent per bit of context entropy in the shapes' pixels.
+const JB2_PIXEL_FACTOR: f64 = 1.03;
+/// Bits spent per bit of entropy in the shapes' sizes and positions.
+const JB2_LAYOUT_FACTOR: f64 = 0.73;
+
+/// Predicted size of an encoded page, in bytes, split by layer. Every part
+/// includes its chunk headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeEstimate {
+    /// The JB2 layer (`Sjbz`).
+    pub mask: usize,
+    /// Colors of the JB2 layer (`FGbz` or `FG44`).
+    pub foreground: usize,
+    /// The IW44 background (`BG44`).
+    pub background: usize,
+    /// Everything else: page header, `INFO`, includes, text, annotations
+    /// and raw chunks.
+    pub other: usize,
+}
+
+impl SizeEstimate {
+    /// The predicted size of the whole page.
+    pub fn total(&self) -> usize {
+        self.mask + self.foreground + self.background + self.other
+    }
+}
+
+impl PageComponents {
+    /// Predicts the size of [`PageComponents::encode`]'s output with
+    /// `params`, in a fraction of the time the encode takes.
+    pub fn estimate_size(&self, params: &PageEncodeParams) -> Result<SizeEstimate> {
+        params.validate()?;
+        let mut estimate = SizeEstimate {
+            // AT&T magic, FORM:DJVU header and INFO.
+            other: 4 + 12 + chunk(10),
+            ..SizeEstimate::default()
+        };
+        for id in &self.includes {
+            estimate.other += chunk(id.len());
+        }
+        for (_, data) in &self.raw_chunks {
+            estimate.other += chunk(data.len());
+        }
+
+        let jb2_layer = self.foreground.as_ref().or(self.mask.as_ref());
+        if let (Some(shapes), Some(blits)) = (&self.jb2_shapes, &self.jb2_blits) {
+            estimate.mask = chunk(jb2_bytes(shapes, blits));
+        } else if let Some(layer) = jb2_layer {
+            let shapes = extract_page_shapes(layer, params);
+            let (shapes, _, blits) = shapes_to_encoder_format(shapes, self.dimensions().1 as i32);
+            estimate.mask = chunk(jb2_bytes(&shapes, &blits));
+        }
+        let has_jb2 = self.jb2_shapes.is_some() || jb2_layer.is_some();
+
+        if let Some(palette) = &self.fg_palette {
+            let mut data = Vec::new();
+            palette.encode(&mut data)?;
+            estimate.foreground = chunk(data.len());
+        }
+        if let (Some(colors), Some(layer)) = (&self.fg_colors, jb2_layer) {
+            let mask = layer_mask(layer, params.fg_subsample, false);
+            estimate.foreground = iw44_layer(colors, Some(&mask), params.fg_subsample, params)?;
+        }
+
+        let raw_background = self
+            .raw_chunks
+            .iter()
+            .any(|(id, _)| id.as_bytes().starts_with(b"BG"));
+        let (bg_subsample, bg_reduction) = self.background_scale(params)?;
+        let hidden = self.mask.as_ref().filter(|_| bg_reduction == bg_subsample);
+        let solid = match (&self.background, params.solid_background) {
+            (Some(background), Some(tolerances)) if params.use_iw44 => {
+                solid_color(background, hidden, &tolerances)
+            }
+            (None, Some(_)) if has_jb2 && !raw_background => Some(Pixel::white()),
+            _ => None,
+        };
+        if let Some(color) = solid {
+            // Coding a solid color is cheap enough to do for real.
+            let (width, height) = self.dimensions();
+            estimate.background = chunk(solid_iw44(color, width, height, params)?.0.len());
+        } else if let Some(background) = &self.background {
+            let mask = self
+                .mask
+                .as_ref()
+                .map(|m| layer_mask(m, bg_reduction, true));
+            estimate.background = iw44_layer(background, mask.as_ref(), bg_subsample, params)?;
+        } else if has_jb2 && !raw_background {
+            let white = {
+                let (width, height) = self.dimensions();
+                Pixmap::from_pixel(width, height, Pixel::white())
+            };
+            estimate.background = iw44_layer(&white, None, params.bg_subsample, params)?;
+        }
+
+        if let Some(text_layer) = &self.text_layer {
+            let mut data = Vec::new();
+            if text_layer.encode(&mut data).is_ok() {
+                estimate.other += chunk(compressed_len(&data)?);
+            }
+        }
+        if let Some(annotations) = &self.annotations {
+            let mut data = Vec::new();
+            annotations
+                .encode(&mut data)
+                .map_err(|e| DjvuError::InvalidOperation(e.to_string()))?;
+            estimate.other += chunk(compressed_len(&data)?);
+        }
+        if let Some(text) = &self.text {
+            estimate.other += chunk(text.len());
+        }
+        Ok(estimate)
+    }
+}
+
+/// Size of a chunk with `len` bytes of data: header and padding.
+fn chunk(len: usize) -> usize {
+    8 + len + len % 2
+}
+
+fn iw44_layer(
+    img: &Pixmap,
+    mask: Option<&Bitmap>,
+    subsample: u32,
+    params: &PageEncodeParams,
+) -> Result<usize> {
+    let encoder = iw44_encoder(img, mask, subsample, params)?;
+    Ok(chunk(estimate_chunk_bytes(
+        &encoder,
+        params.slices.unwrap_or(74),
+    )))
+}
+
+/// Predicts an `Sjbz` stream placing `shapes` at `blits` (left, bottom,
+/// shape), each shape coded directly the first time it is used and copied
+/// after that. Pixels cost the entropy of the JB2 direct context model and
+/// placements that of their record types, sizes, offsets and copied shapes,
+/// all learnt adaptively over the page as the coder does.
+fn jb2_bytes(shapes: &[BitImage], blits: &[(i32, i32, usize)]) -> usize {
+    let mut contexts = vec![[0u32; 2]; 1 << 10];
+    let mut pixels = 0.0;
+    let mut coded = vec![false; shapes.len()];
+    let [
+        mut widths,
+        mut heights,
+        mut dxs,
+        mut dys,
+        mut kinds,
+        mut matches,
+    ] = std::array::from_fn(|_| Adaptive::default());
+    let mut layout = 0.0;
+    let (mut last_right, mut last_bottom) = (0, 0);
+    for &(left, bottom, index) in blits {
+        let Some(shape) = shapes.get(index) else {
+            continue;
+        };
+        if coded[index] {
+            layout += kinds.cost(1) + matches.cost(index as i32);
+        } else {
+            coded[index] = true;
+            pixels += pixel_entropy(shape, &mut contexts);
+            layout +=
+                kinds.cost(0) + widths.cost(shape.width as i32) + heights.cost(shape.height as i32);
+        }
+        layout += dxs.cost(left - last_right) + dys.cost(bottom - last_bottom);
+        (last_right, last_bottom) = (left + shape.width as i32, bottom);
+    }
+    let bits = JB2_PIXEL_FACTOR * pixels + JB2_LAYOUT_FACTOR * layout;
+    (bits / 8.0).ceil() as usize
+}
+
+/// Entropy of one shape's pixels under the direct context model, updating
+/// the model's counts.
+fn pixel_entropy(shape: &BitImage, contexts: &mut [[u32; 2]]) -> f64 {
+    let (w, h) = (shape.width as isize, shape.height as isize);
+    let pixel = |x: isize, y: isize| {
+        x >= 0 && y >= 0 && x < w && y < h && shape.get_pixel_unchecked(x as usize, y as usize)
+    };
+    let mut entropy = 0.0;
+    for y in 0..h {
+        for x in 0..w {
+            // Same template as the encoder: three pixels two rows up, five
+            // one row up and two to the left.
+            let context = [
+                (x - 1, y - 2),
+                (x, y - 2),
+                (x + 1, y - 2),
+                (x - 2, y - 1),
+                (x - 1, y - 1),
+                (x, y - 1),
+                (x + 1, y - 1),
+                (x + 2, y - 1),
+                (x - 2, y),
+                (x - 1, y),
+            ]
+            .iter()
+            .fold(0, |ctx, &(px, py)| ctx << 1 | pixel(px, py) as usize);
+            let bit = pixel(x, y) as usize;
+            let seen = &mut contexts[context];
+            let p = (seen[bit] as f64 + 0.5) / (seen[0] + seen[1] + 1) as f64;
+            entropy -= p.log2();
+            seen[bit] += 1;
+        }
+    }
+    entropy
+}
+
+/// Adaptive cost of a stream of numbers: values seen before cost their
+/// frequency, new ones an escape plus a length-prefixed binary code.
+#[derive(Default)]
+struct Adaptive {
+    counts: std::collections::HashMap<i32, u32>,
+    total: u32,
+}
+
+impl Adaptive {
+    fn cost(&mut self, value: i32) -> f64 {
+        let escape = (self.total as f64 + 1.0).log2();
+        let seen = self.counts.entry(value).or_insert(0);
+        let bits = if *seen > 0 {
+            -(*seen as f64 / (self.total as f64 + 1.0)).log2()
+        } else {
+            escape + 2.0 * (value.unsigned_abs() as f64 + 1.0).log2() + 1.0
+        };
+        *seen += 1;
+        self.total += 1;
+        bits
+    }
+}
+
+fn compressed_len(data: &[u8]) -> Result<usize> {
+    bzz_compress(data, 100)
+        .map(|c| c.len())
+        .map_err(|e| DjvuError::EncodingError(format!("BZZ compression failed: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_close_to_encode() -> Result<()> {
+        let (width, height) = (600, 400);
+        let mut text = BitImage::new(width, height).unwrap();
+        for row in 0..12 {
+            for col in 0..40 {
+                let (x0, y0) = (20 + col * 14, 20 + row * 30);
+                for y in 0..10 + (col % 4) {
+                    for x in 0..8 - (row % 3) {
+                        if (x + y + col) % 5 != 0 {
+                            text.set_usize(x0 + x, y0 + y, true);
+                        }
+                    }
+                }
+            }
+        }
+        let photo = Pixmap::from_fn(width, height, |x, y| {
+            let v =
+                |a: f32, b: f32| ((x as f32 * a).sin() * (y as f32 * b).cos() * 90.0 + 130.0) as u8;
+            Pixel::new(v(0.03, 0.02), v(0.05, 0.04), v(0.02, 0.07))
+        });
+
+        let pages = [
+            PageComponents::new().with_mask(text.clone())?,
+            PageComponents::new().with_background(photo.clone())?,
+            PageComponents::new()
+                .with_background(photo)?
+                .with_mask(text)?,
+        ];
+        let params = PageEncodeParams::default();
+        for page in &pages {
+            let estimate = page.estimate_size(&params)?;
+            let actual = page.encode(&params, 0, 0, 1, None)?.len();
+            let ratio = estimate.total() as f64 / actual as f64;
+            assert!((0.85..1.15).contains(&ratio), "{estimate:?} vs {actual}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,441 @@
+//! Scan bundles to pages.
+//!
+//! [`import_file`] opens a multi-page TIFF, a PDF or any single image that
+//! [`crate::doc::batch`] reads, sorts every page into a [`PageClass`], and
+//! returns it as ready-to-encode [`PageComponents`]: bitonal pages become a
+//! JB2 mask, everything else an IW44 background. [`encode_pages`] turns the
+//! result into a document, so converting a scan bundle takes two calls:
+//!
+//! ```ignore
+//! let pages = import_file("bundle.tif", &ImportOptions::default())?;
+//! let doc = encode_pages(pages, DjvuBuilder::new(0).with_profile(Profile::ColorMagazine))?;
+//! std::fs::write("bundle.djvu", doc.finalize()?)?;
+//! ```
+//!
+//! PDF pages are rasterized by poppler's `pdftoppm`, which must be on `PATH`
+//! (feature `pdf`); TIFF needs the `tiff` feature.
+
+use crate::doc::batch::load_pages;
+use crate::doc::builder::{DjvuBuilder, DjvuDocument};
+use crate::doc::page_encoder::PageComponents;
+use crate::encode::jb2::symbol_dict::BitImage;
+pub use crate::image::classify::PageClass;
+use crate::image::deskew::{DeskewParams, deskew_bits, deskew_pixmap, estimate_skew};
+use crate::image::filter::PixmapFilter;
+use crate::image::image_formats::{LoadedImage, Pixmap};
+use crate::{DjvuError, Result};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// One imported page.
+pub struct ImportedPage {
+    pub class: PageClass,
+    pub components: PageComponents,
+    /// Skew found on the page, in degrees (see [`estimate_skew`]); `None`
+    /// unless deskewing is on.
+    pub skew: Option<f32>,
+    /// Resolution the page was rendered at, for pages of a PDF; `None` for
+    /// images, which take the document's. [`encode_pages`] gives it to the
+    /// page.
+    pub dpi: Option<u32>,
+}
+
+/// A callback run on a scan before it is classified and segmented, see
+/// [`ImportOptions::with_preprocessor`].
+pub type Preprocessor = Arc<dyn Fn(&mut Pixmap) + Send + Sync>;
+
+/// Settings for [`import_file`].
+///
+/// With the `serde` feature these can be loaded from job files, except for
+/// the [`preprocessors`](Self::preprocessors); fields left out take their
+/// default values.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ImportOptions {
+    /// Resolution PDF pages are rendered at (default: 300).
+    pub pdf_dpi: u32,
+    /// Treat gray or color pages that only contain pure black and white as
+    /// bitonal (default: true).
+    pub detect_bitonal: bool,
+    /// Straighten skewed pages before they are segmented, so JB2 finds more
+    /// matching shapes (default: off).
+    pub deskew: Option<DeskewParams>,
+    /// Filters run, in order, on gray and color pages before they are
+    /// classified and segmented (default: none); see
+    /// [`crate::image::filter`]
+    pub filters: Vec<PixmapFilter>,
+    /// Callbacks run, in order, after the `filters`, see
+    /// [`Self::with_preprocessor`]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub preprocessors: Vec<Preprocessor>,
+}
+
+impl ImportOptions {
+    /// Runs `preprocessor` on every gray and color scan before it is
+    /// classified and segmented, for denoising, background normalization,
+    /// color curves and the like.
+    ///
+    /// Preprocessors run in the order they were added, after the
+    /// [`Self::filters`], and must keep the size of the picture. Being
+    /// code, they are not saved in job files; settings that must be
+    /// replayed belong in the filters.
+    pub fn with_preprocessor(
+        mut self,
+        preprocessor: impl Fn(&mut Pixmap) + Send + Sync + 'static,
+    ) -> Self {
+        self.preprocessors.push(Arc::new(preprocessor));
+        self
+    }
+}
+
+impl fmt::Debug for ImportOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImportOptions")
+            .field("pdf_dpi", &self.pdf_dpi)
+            .field("detect_bitonal", &self.detect_bitonal)
+            .field("deskew", &self.deskew)
+            .field("filters", &self.filters)
+            .field("preprocessors", &self.preprocessors.len())
+            .finish()
+    }
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            pdf_dpi: 300,
+            detect_bitonal: true,
+            deskew: None,
+            filters: Vec::new(),
+            preprocessors: Vec::new(),
+        }
+    }
+}
+
+/// Loads every page of `path` and classifies it.
+pub fn import_file(path: impl AsRef<Path>, options: &ImportOptions) -> Result<Vec<ImportedPage>> {
+    let path = path.as_ref();
+    let is_pdf = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("pdf"));
+    let (images, dpi) = if is_pdf {
+        (rasterize_pdf(path, options.pdf_dpi)?, Some(options.pdf_dpi))
+    } else {
+        (load_pages(path)?, None)
+    };
+    images
+        .into_iter()
+        .map(|image| {
+            let mut page = import_image_with(image, options)?;
+            page.dpi = dpi;
+            Ok(page)
+        })
+        .collect()
+}
+
+/// Classifies one image and turns it into page components.
+pub fn import_image(image: LoadedImage, detect_bitonal: bool) -> Result<ImportedPage> {
+    let options = ImportOptions {
+        detect_bitonal,
+        ..ImportOptions::default()
+    };
+    import_image_with(image, &options)
+}
+
+/// Like [`import_image`], with the page settings of `options`.
+pub fn import_image_with(image: LoadedImage, options: &ImportOptions) -> Result<ImportedPage> {
+    let image = match image {
+        LoadedImage::Bilevel(_) => image,
+        _ if options.filters.is_empty() && options.preprocessors.is_empty() => image,
+        _ => {
+            let mut pixmap = image.into_pixmap();
+            let size = pixmap.dimensions();
+            for filter in &options.filters {
+                filter.validate()?;
+                filter.apply(&mut pixmap);
+            }
+            for preprocessor in &options.preprocessors {
+                preprocessor(&mut pixmap);
+            }
+            if pixmap.dimensions() != size {
+                return Err(DjvuError::InvalidOperation(format!(
+                    "Preprocessor resized a {}x{} scan to {}x{}",
+                    size.0,
+                    size.1,
+                    pixmap.width(),
+                    pixmap.height()
+                )));
+            }
+            LoadedImage::Rgb(pixmap)
+        }
+    };
+    let class = classify(&image, options.detect_bitonal);
+    let components = PageComponents::new();
+    let mut skew = None;
+    let components = if class == PageClass::Bitonal {
+        let mut bits = match image {
+            LoadedImage::Bilevel(bits) => bits,
+            other => threshold(&other.into_pixmap())?,
+        };
+        if let Some(params) = &options.deskew {
+            let angle = estimate_skew(&bits, params);
+            if angle.abs() >= params.min_angle {
+                bits = deskew_bits(&bits, angle);
+            }
+            skew = Some(angle);
+        }
+        components.with_mask(bits)?
+    } else {
+        let mut pixmap = image.into_pixmap();
+        if let Some(params) = &options.deskew {
+            let angle = estimate_skew(&threshold(&pixmap)?, params);
+            if angle.abs() >= params.min_angle {
+                pixmap = deskew_pixmap(&pixmap, angle);
+            }
+            skew = Some(angle);
+        }
+        components.with_background(pixmap)?
+    };
+    Ok(ImportedPage {
+        class,
+        components,
+        skew,
+        dpi: None,
+    })
+}
+
+/// The dark pixels of `pixmap`.
+fn threshold(pixmap: &Pixmap) -> Result<BitImage> {
+    let gray = pixmap.to_bitmap();
+    let (width, height) = gray.dimensions();
+    let mut bits =
+        BitImage::new(width, height).map_err(|e| DjvuError::InvalidArg(e.to_string()))?;
+    for (i, pixel) in gray.pixels().iter().enumerate() {
+        bits.set_usize(i % width as usize, i / width as usize, pixel.y < 128);
+    }
+    Ok(bits)
+}
+
+/// Sorts an image into a [`PageClass`].
+pub fn classify(image: &LoadedImage, detect_bitonal: bool) -> PageClass {
+    let bitonal = |v: u8| v == 0 || v == 255;
+    match image {
+        LoadedImage::Bilevel(_) => PageClass::Bitonal,
+        LoadedImage::Gray(gray) => {
+            if detect_bitonal && gray.pixels().iter().all(|p| bitonal(p.y)) {
+                PageClass::Bitonal
+            } else {
+                PageClass::Grayscale
+            }
+        }
+        LoadedImage::Rgb(rgb) => {
+            let pixels = rgb.pixels();
+            if !pixels.iter().all(|p| p.r == p.g && p.g == p.b) {
+                PageClass::Color
+            } else if detect_bitonal && pixels.iter().all(|p| bitonal(p.r)) {
+                PageClass::Bitonal
+            } else {
+                PageClass::Grayscale
+            }
+        }
+    }
+}
+
+/// Encodes imported pages, in order, into a document made by `builder`.
+///
+/// The builder's page count is replaced by the number of pages.
+pub fn encode_pages(pages: Vec<ImportedPage>, builder: DjvuBuilder) -> Result<DjvuDocument> {
+    let doc = builder.with_total_pages(pages.len()).build();
+    for (page_num, page) in pages.into_iter().enumerate() {
+        let components = match page.dpi {
+            Some(dpi) => page.components.with_dpi(dpi),
+            None => page.components,
+        };
+        doc.add_components(page_num, components)?;
+    }
+    Ok(doc)
+}
+
+#[cfg(feature = "pdf")]
+fn rasterize_pdf(path: &Path, dpi: u32) -> Result<Vec<LoadedImage>> {
+    use crate::doc::batch::find_inputs;
+
+    let dir = tempfile::tempdir()?;
+    let status = std::process::Command::new("pdftoppm")
+        .arg("-r")
+        .arg(dpi.to_string())
+        .arg(path)
+        .arg(dir.path().join("page"))
+        .status()
+        .map_err(|e| DjvuError::Custom(format!("Failed to run pdftoppm: {e}")))?;
+    if !status.success() {
+        return Err(DjvuError::InvalidArg(format!(
+            "pdftoppm could not render {} ({status})",
+            path.display()
+        )));
+    }
+    let mut pages = Vec::new();
+    for file in find_inputs(&dir.path().join("page*.ppm").to_string_lossy())? {
+        pages.extend(load_pages(&file)?);
+    }
+    Ok(pages)
+}
+
+#[cfg(not(feature = "pdf"))]
+fn rasterize_pdf(path: &Path, _dpi: u32) -> Result<Vec<LoadedImage>> {
+    Err(DjvuError::InvalidArg(format!(
+        "Importing {} needs the `pdf` feature",
+        path.display()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::image_formats::{GrayPixel, Pixel};
+
+    #[test]
+    fn test_classify_and_encode() -> Result<()> {
+        let gray = |values: [u8; 4]| {
+            LoadedImage::Gray(crate::image::image_formats::Bitmap::from_vec(
+                2,
+                2,
+                values.into_iter().map(GrayPixel::new).collect(),
+            ))
+        };
+        assert_eq!(classify(&gray([0, 255, 255, 0]), true), PageClass::Bitonal);
+        assert_eq!(
+            classify(&gray([0, 255, 255, 0]), false),
+            PageClass::Grayscale
+        );
+        assert_eq!(classify(&gray([0, 90, 255, 0]), true), PageClass::Grayscale);
+        let color = LoadedImage::Rgb(Pixmap::from_pixel(2, 2, Pixel::new(9, 80, 9)));
+        assert_eq!(classify(&color, true), PageClass::Color);
+
+        let pages = vec![
+            import_image(gray([0, 255, 255, 0]), true)?,
+            import_image(color, true)?,
+        ];
+        assert!(pages[0].components.mask.is_some());
+        assert!(pages[1].components.background.is_some());
+
+        let doc = encode_pages(pages, DjvuBuilder::new(0))?;
+        assert_eq!(doc.total_pages(), 2);
+        assert!(doc.is_complete());
+        assert_eq!(&doc.finalize()?[12..16], b"DJVM");
+
+        #[cfg(not(feature = "pdf"))]
+        assert!(import_file("scan.pdf", &ImportOptions::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rendered_page_keeps_its_dpi() -> Result<()> {
+        use crate::doc::editor::DjVuDocEditor;
+
+        let page = |dpi| -> Result<ImportedPage> {
+            let color = LoadedImage::Rgb(Pixmap::from_pixel(8, 8, Pixel::new(9, 80, 9)));
+            Ok(ImportedPage {
+                dpi,
+                ..import_image(color, true)?
+            })
+        };
+        let doc = encode_pages(
+            vec![page(Some(600))?, page(None)?],
+            DjvuBuilder::new(0).with_dpi(150),
+        )?;
+        let editor = DjVuDocEditor::from_bytes(&doc.finalize()?)?;
+        assert_eq!(editor.page_info(0)?.dpi, 600);
+        assert_eq!(editor.page_info(1)?.dpi, 150);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deskew_on_import() -> Result<()> {
+        // Rules rising to the right by about 1.1 degrees.
+        let mut bits = BitImage::new(300, 200).map_err(|e| DjvuError::InvalidArg(e.to_string()))?;
+        for rule in 0..6 {
+            for x in 10..290 {
+                let y = 30 + rule * 28 + (290 - x) / 50;
+                bits.fill_run(y, x, x);
+                bits.fill_run(y + 1, x, x);
+            }
+        }
+        let options = ImportOptions {
+            deskew: Some(DeskewParams::default()),
+            ..ImportOptions::default()
+        };
+        let page = import_image_with(LoadedImage::Bilevel(bits.clone()), &options)?;
+        let skew = page.skew.unwrap();
+        assert!((0.9..=1.3).contains(&skew), "{skew}");
+        assert_ne!(page.components.mask.as_ref(), Some(&bits));
+
+        let page = import_image_with(LoadedImage::Bilevel(bits), &ImportOptions::default())?;
+        assert_eq!(page.skew, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preprocessor_runs_before_segmentation() -> Result<()> {
+        // Gray print on tinted paper, made black and white by the hook.
+        let scan = || {
+            let mut pixmap = Pixmap::from_pixel(8, 8, Pixel::new(230, 220, 190));
+            for x in 2..6 {
+                pixmap.put_pixel(x, 4, Pixel::new(40, 40, 40));
+            }
+            LoadedImage::Rgb(pixmap)
+        };
+        let page = import_image_with(scan(), &ImportOptions::default())?;
+        assert_eq!(page.class, PageClass::Color);
+
+        let options = ImportOptions::default().with_preprocessor(|pixmap: &mut Pixmap| {
+            for pixel in pixmap.pixels_mut() {
+                let v = if pixel.g < 128 { 0 } else { 255 };
+                *pixel = Pixel::new(v, v, v);
+            }
+        });
+        let page = import_image_with(scan(), &options)?;
+        assert_eq!(page.class, PageClass::Bitonal);
+        let mask = page.components.mask.unwrap();
+        assert_eq!(mask.count_ones(), 4);
+
+        let resize = ImportOptions::default()
+            .with_preprocessor(|pixmap: &mut Pixmap| *pixmap = Pixmap::new(4, 4));
+        assert!(matches!(
+            import_image_with(scan(), &resize),
+            Err(DjvuError::InvalidOperation(_))
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_options_job_file() {
+        let options: ImportOptions = serde_json::from_str(
+            r#"{"pdf_dpi": 400, "deskew": {"max_angle": 5.0},
+                "filters": ["Median", {"Levels": {"black": 16, "white": 224}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(options.pdf_dpi, 400);
+        assert!(options.detect_bitonal);
+        assert_eq!(options.deskew.unwrap().max_angle, 5.0);
+        assert_eq!(
+            options.filters,
+            [
+                PixmapFilter::Median,
+                PixmapFilter::Levels {
+                    black: 16,
+                    white: 224
+                }
+            ]
+        );
+
+        let options = options.with_preprocessor(|_: &mut Pixmap| {});
+        let json = serde_json::to_string(&options).unwrap();
+        let loaded: ImportOptions = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.filters, options.filters);
+        assert!(loaded.preprocessors.is_empty());
+    }
+}
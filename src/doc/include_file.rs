@@ -0,0 +1,250 @@
+//! Shared component files (`FORM:DJVI`).
+//!
+//! A DjVu document can hold data used by several pages in separate include
+//! files: a JB2 shape dictionary (`Djbz`), shared annotations (`ANTz`), or a
+//! shared foreground palette (`FGbz`). Each page that uses one names it in an
+//! `INCL` chunk, and the document directory lists it as
+//! [`FileType::Include`].
+//!
+//! [`IncludeFileBuilder`] produces the form. Add it to a bundled document with
+//! [`DjvuDocument::add_include_file`](crate::doc::DjvuDocument::add_include_file)
+//! and reference it from pages with
+//! [`PageBuilder::with_include`](crate::doc::PageBuilder::with_include).
+//!
+//! When component files are renamed, e.g. after
+//! [`DjVmDir::resolve_duplicates`] for an indirect save, [`remap_includes`]
+//! rewrites the `INCL` chunks of each file to match.
+
+use crate::annotations::Annotations;
+use crate::doc::djvu_dir::{DjVmDir, File, FileRename, FileType};
+use crate::encode::jb2::{encoder::JB2Encoder, symbol_dict::SharedDict};
+use crate::iff::{
+    bs_byte_stream::bzz_compress,
+    chunk_id::ChunkId,
+    chunk_tree::{ChunkPayload, IffDocument},
+    iff::IffWriter,
+};
+use crate::{DjvuError, Result};
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+
+/// Chunks a `FORM:DJVI` may contain.
+pub const DJVI_CHUNK_IDS: [ChunkId; 4] =
+    [ChunkId::Djbz, ChunkId::FGbz, ChunkId::ANTa, ChunkId::ANTz];
+
+/// Builds a standalone `FORM:DJVI` include file.
+#[derive(Debug, Clone)]
+pub struct IncludeFileBuilder {
+    id: String,
+    chunks: Vec<(ChunkId, Vec<u8>)>,
+}
+
+impl IncludeFileBuilder {
+    /// Starts an include file stored under directory ID `id`.
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Adds a `Djbz` chunk holding the shapes of `dict`.
+    pub fn with_shared_dict(self, dict: &SharedDict) -> Result<Self> {
+        let parents = vec![-1; dict.shape_count()];
+        let data = JB2Encoder::new(Vec::new()).encode_dictionary(dict.shapes(), &parents, 0)?;
+        self.with_chunk(ChunkId::Djbz, data)
+    }
+
+    /// Adds an `ANTz` chunk with annotations shared by the including pages.
+    pub fn with_annotations(self, annotations: &Annotations) -> Result<Self> {
+        let mut raw = Vec::new();
+        annotations.encode(&mut raw).map_err(|e| {
+            DjvuError::InvalidOperation(format!("Failed to encode annotations: {e}"))
+        })?;
+        let data = bzz_compress(&raw, 100)
+            .map_err(|e| DjvuError::EncodingError(format!("BZZ compression failed: {e}")))?;
+        self.with_chunk(ChunkId::ANTz, data)
+    }
+
+    /// Adds an already encoded chunk; `id` must be one of [`DJVI_CHUNK_IDS`].
+    pub fn with_chunk(mut self, id: ChunkId, data: Vec<u8>) -> Result<Self> {
+        if !DJVI_CHUNK_IDS.contains(&id) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Chunk {id} is not allowed in FORM:DJVI"
+            )));
+        }
+        self.chunks.push((id, data));
+        Ok(self)
+    }
+
+    /// Writes the form.
+    pub fn build(self) -> Result<IncludeFile> {
+        if self.id.is_empty() || self.id.contains('\0') {
+            return Err(DjvuError::InvalidArg(format!(
+                "Invalid include file ID {:?}",
+                self.id
+            )));
+        }
+        if self.chunks.is_empty() {
+            return Err(DjvuError::InvalidOperation(
+                "Include file must have at least one chunk".to_string(),
+            ));
+        }
+
+        let mut cursor = Cursor::new(Vec::new());
+        {
+            let mut writer = IffWriter::new(&mut cursor);
+            writer.write_magic_bytes()?;
+            writer.put_composite(ChunkId::FORM, ChunkId::DJVI)?;
+            for (id, data) in &self.chunks {
+                writer.put_chunk(*id)?;
+                writer.write_all(data)?;
+                writer.close_chunk()?;
+            }
+            writer.close_chunk()?;
+        }
+        Ok(IncludeFile {
+            id: self.id,
+            data: cursor.into_inner(),
+        })
+    }
+}
+
+/// An encoded `FORM:DJVI` file and the ID pages include it by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncludeFile {
+    id: String,
+    data: Vec<u8>,
+}
+
+impl IncludeFile {
+    /// Directory ID, the payload of the pages' `INCL` chunks.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The complete file, starting with `AT&T`.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// A file that was encoded before, such as one saved in a checkpoint.
+    pub(crate) fn from_parts(id: String, data: Vec<u8>) -> Self {
+        Self { id, data }
+    }
+
+    /// Returns the directory record for this file.
+    pub fn dir_file(&self) -> Arc<File> {
+        File::new(&self.id, &self.id, "", FileType::Include)
+    }
+
+    /// Appends this file to `dir`.
+    pub fn register(&self, dir: &DjVmDir) -> Result<()> {
+        dir.insert_file(self.dir_file(), -1)
+    }
+}
+
+/// Rewrites the `INCL` chunks of a component file according to `renames`.
+///
+/// Every other byte is copied unchanged; only the sizes of the enclosing
+/// forms change when a new ID has a different length. Nested forms (the
+/// pages of a bundle) are handled too, but a `DIRM` directory is not
+/// touched: re-encode it from the renamed [`DjVmDir`].
+pub fn remap_includes(data: &[u8], renames: &[FileRename]) -> Result<Vec<u8>> {
+    let map: HashMap<&[u8], &str> = renames
+        .iter()
+        .map(|r| (r.old_id.as_bytes(), r.new_id.as_str()))
+        .collect();
+    let mut document = IffDocument::from_bytes(data)?;
+    document.root.walk_mut(&mut |chunk| {
+        if chunk.id != ChunkId::INCL {
+            return;
+        }
+        if let Some(new_id) = chunk.data().and_then(|id| map.get(id)) {
+            chunk.payload = ChunkPayload::Raw(new_id.as_bytes().to_vec());
+        }
+    });
+    let out = document.to_bytes()?;
+    Ok(match data.starts_with(b"AT&T") {
+        true => out,
+        false => out[4..].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::jb2::symbol_dict::BitImage;
+
+    #[test]
+    fn test_djvi_form_layout() -> Result<()> {
+        let mut shape = BitImage::new(4, 4).unwrap();
+        shape.set_usize(1, 1, true);
+        let file = IncludeFileBuilder::new("dict0001.iff")
+            .with_shared_dict(&SharedDict::new(vec![shape]))?
+            .with_annotations(&Annotations::default())?
+            .build()?;
+
+        let data = file.data();
+        assert_eq!(&data[..4], b"AT&T");
+        assert_eq!(&data[12..16], b"DJVI");
+        assert_eq!(&data[16..20], b"Djbz");
+        let form_size = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+        assert_eq!(form_size + 12, data.len());
+
+        let dir = DjVmDir::new();
+        file.register(&dir)?;
+        assert!(dir.get_file_by_id("dict0001.iff").unwrap().is_include());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_page_chunks() {
+        assert!(
+            IncludeFileBuilder::new("x.iff")
+                .with_chunk(ChunkId::BG44, vec![0])
+                .is_err()
+        );
+        assert!(IncludeFileBuilder::new("x.iff").build().is_err());
+    }
+
+    #[test]
+    fn test_remap_includes_preserves_other_bytes() -> Result<()> {
+        use crate::doc::page_encoder::{PageComponents, PageEncodeParams};
+
+        let encode = |ids: [&str; 2]| -> Result<Vec<u8>> {
+            let page = PageComponents::new_with_dimensions(32, 32)
+                .with_include(ids[0])
+                .with_include(ids[1])
+                .with_raw_chunk(ChunkId::ANTa, b"(mode color)".to_vec())?;
+            page.encode(&PageEncodeParams::default(), 1, 300, 1, None)
+        };
+        let original = encode(["dict.iff", "anno.iff"])?;
+
+        let renames = [FileRename {
+            old_id: "dict.iff".to_string(),
+            new_id: "shared_dict_0001.iff".to_string(),
+            old_name: "dict.iff".to_string(),
+            new_name: "shared_dict_0001.iff".to_string(),
+        }];
+        assert_eq!(
+            remap_includes(&original, &renames)?,
+            encode(["shared_dict_0001.iff", "anno.iff"])?
+        );
+        assert_eq!(remap_includes(&original, &[])?, original);
+
+        // Renaming back restores the original bytes.
+        let back = [FileRename {
+            old_id: "shared_dict_0001.iff".to_string(),
+            new_id: "dict.iff".to_string(),
+            old_name: String::new(),
+            new_name: String::new(),
+        }];
+        let remapped = remap_includes(&original, &renames)?;
+        assert_eq!(remap_includes(&remapped, &back)?, original);
+
+        assert!(remap_includes(&original[..original.len() - 3], &renames).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! Per-page integrity checksums.
+//!
+//! When enabled with [`DjvuBuilder::with_integrity_checksums`], every page
+//! form gets a trailing `CIDa` chunk holding a CRC-32 of the chunks before it.
+//! Viewers skip chunk types they do not know, so the chunk is invisible to
+//! them; archival tools can call [`verify_document`] to detect silent
+//! corruption of stored files.
+//!
+//! Because the checksum covers only the page's own chunks, it stays valid
+//! when the page is bundled into a `FORM:DJVM` at any offset.
+//!
+//! [`DjvuBuilder::with_integrity_checksums`]: crate::doc::DjvuBuilder::with_integrity_checksums
+
+use crate::iff::chunk_id::ChunkId;
+use crate::{DjvuError, Result};
+
+/// Chunk ID of the checksum chunk.
+pub const CHECKSUM_CHUNK_ID: ChunkId = ChunkId::CIDa;
+
+const CHECKSUM_VERSION: u8 = 1;
+/// CRC-32 as used by zlib/PNG (reflected, polynomial 0xEDB88320).
+const ALGORITHM_CRC32: u8 = 1;
+const PAYLOAD_LEN: usize = 6;
+
+/// Result of checking one page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageIntegrity {
+    /// The page carries a checksum and it matches.
+    Verified,
+    /// The page has no checksum chunk.
+    Unchecked,
+    /// The stored checksum does not match the page contents.
+    Corrupt { expected: u32, actual: u32 },
+}
+
+/// CRC-32 (IEEE) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    static TABLE: std::sync::OnceLock<[u32; 256]> = std::sync::OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = i as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 {
+                    0xEDB8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+            }
+            *entry = c;
+        }
+        table
+    });
+    !data.iter().fold(!0u32, |crc, &b| {
+        table[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| DjvuError::ValidationError(format!("Truncated chunk header at {pos}")))
+}
+
+/// Returns the offset of the `FORM` header, skipping the `AT&T` prefix.
+fn form_start(data: &[u8]) -> usize {
+    if data.starts_with(b"AT&T") { 4 } else { 0 }
+}
+
+/// Appends a `CIDa` chunk to an encoded `FORM:DJVU` page and fixes up the
+/// form length.
+pub fn append_checksum(page: &mut Vec<u8>) -> Result<()> {
+    let form = form_start(page);
+    if page.get(form..form + 4) != Some(b"FORM") || page.get(form + 8..form + 12) != Some(b"DJVU") {
+        return Err(DjvuError::InvalidArg(
+            "Integrity checksums can only be added to FORM:DJVU pages".to_string(),
+        ));
+    }
+    let mut size = read_u32(page, form + 4)? as usize;
+    let end = form + 8 + size;
+    if end > page.len() {
+        return Err(DjvuError::ValidationError(
+            "FORM length exceeds page data".to_string(),
+        ));
+    }
+    page.truncate(end);
+    // Keep the new chunk on an even offset.
+    if !size.is_multiple_of(2) {
+        page.push(0);
+        size += 1;
+    }
+
+    let crc = crc32(&page[form + 12..]);
+    page.extend_from_slice(CHECKSUM_CHUNK_ID.as_bytes());
+    page.extend_from_slice(&(PAYLOAD_LEN as u32).to_be_bytes());
+    page.push(CHECKSUM_VERSION);
+    page.push(ALGORITHM_CRC32);
+    page.extend_from_slice(&crc.to_be_bytes());
+    size += 8 + PAYLOAD_LEN;
+
+    let size = u32::try_from(size)
+        .map_err(|_| DjvuError::ValidationError("Page exceeds 4 GiB".to_string()))?;
+    page[form + 4..form + 8].copy_from_slice(&size.to_be_bytes());
+    Ok(())
+}
+
+/// Checks the page form whose `FORM` header starts at `form`.
+fn verify_form(data: &[u8], form: usize) -> Result<PageIntegrity> {
+    let size = read_u32(data, form + 4)? as usize;
+    let end = (form + 8 + size).min(data.len());
+    let body = form + 12;
+    let mut pos = body;
+    while pos + 8 <= end {
+        let len = read_u32(data, pos + 4)? as usize;
+        if data[pos..pos + 4] == *CHECKSUM_CHUNK_ID.as_bytes() {
+            let payload = data.get(pos + 8..pos + 8 + len).ok_or_else(|| {
+                DjvuError::ValidationError("Truncated checksum chunk".to_string())
+            })?;
+            if len != PAYLOAD_LEN || payload[0] != CHECKSUM_VERSION || payload[1] != ALGORITHM_CRC32
+            {
+                return Err(DjvuError::ValidationError(
+                    "Unsupported checksum chunk format".to_string(),
+                ));
+            }
+            let expected = u32::from_be_bytes([payload[2], payload[3], payload[4], payload[5]]);
+            let actual = crc32(&data[body..pos]);
+            return Ok(if expected == actual {
+                PageIntegrity::Verified
+            } else {
+                PageIntegrity::Corrupt { expected, actual }
+            });
+        }
+        pos += 8 + len + (len & 1);
+    }
+    Ok(PageIntegrity::Unchecked)
+}
+
+/// Checks every page of a single-page `DJVU` or bundled `DJVM` file, in
+/// document order.
+pub fn verify_document(data: &[u8]) -> Result<Vec<PageIntegrity>> {
+    let form = form_start(data);
+    if data.get(form..form + 4) != Some(b"FORM") {
+        return Err(DjvuError::ValidationError(
+            "Missing FORM header".to_string(),
+        ));
+    }
+    match data.get(form + 8..form + 12) {
+        Some(b"DJVU") => Ok(vec![verify_form(data, form)?]),
+        Some(b"DJVM") => {
+            let size = read_u32(data, form + 4)? as usize;
+            let end = (form + 8 + size).min(data.len());
+            let mut pages = Vec::new();
+            let mut pos = form + 12;
+            while pos + 12 <= end {
+                let len = read_u32(data, pos + 4)? as usize;
+                if &data[pos..pos + 4] == b"FORM" && &data[pos + 8..pos + 12] == b"DJVU" {
+                    pages.push(verify_form(data, pos)?);
+                }
+                pos += 8 + len + (len & 1);
+            }
+            Ok(pages)
+        }
+        _ => Err(DjvuError::ValidationError(
+            "Not a DJVU or DJVM document".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page_with_odd_chunk() -> Vec<u8> {
+        let mut page = b"AT&TFORM\0\0\0\x0fDJVUINFO\0\0\0\x03abc".to_vec();
+        page.push(0); // padding after the odd INFO chunk, outside the FORM length
+        page
+    }
+
+    #[test]
+    fn test_crc32_reference_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_checksum_round_trip_and_corruption() {
+        let mut page = page_with_odd_chunk();
+        append_checksum(&mut page).unwrap();
+        assert_eq!(
+            verify_document(&page).unwrap(),
+            vec![PageIntegrity::Verified]
+        );
+
+        // Flip a payload byte of the INFO chunk.
+        page[24] ^= 0x01;
+        assert!(matches!(
+            verify_document(&page).unwrap()[0],
+            PageIntegrity::Corrupt { .. }
+        ));
+
+        assert_eq!(
+            verify_document(&page_with_odd_chunk()).unwrap(),
+            vec![PageIntegrity::Unchecked]
+        );
+    }
+
+    #[test]
+    fn test_builder_checksums_survive_bundling() {
+        use crate::doc::{DjvuBuilder, PageBuilder};
+        use crate::image::image_formats::{Pixel, Pixmap};
+
+        let doc = DjvuBuilder::new(2).with_integrity_checksums(true).build();
+        for page_num in 0..2 {
+            let page = PageBuilder::new(page_num, 32, 32)
+                .with_background(Pixmap::from_pixel(32, 32, Pixel::new(90, 120, 150)))
+                .unwrap()
+                .build()
+                .unwrap();
+            doc.add_page(page).unwrap();
+        }
+        let bytes = doc.finalize().unwrap();
+        assert_eq!(
+            verify_document(&bytes).unwrap(),
+            vec![PageIntegrity::Verified; 2]
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Checking links between pages.
+//!
+//! Hyperlinks whose URL starts with `#` point to a page of the same
+//! document. Viewers resolve the name after the `#` the way DjVuLibre does:
+//! `+N`/`-N` move relative to the current page, a plain number is a 1-based
+//! page number, and anything else is matched against the page IDs and then
+//! the page titles of the directory. When a document is assembled, every such
+//! link is resolved against the final page list; the ones that lead nowhere
+//! are reported as [`BrokenLink`]s in
+//! [`EncodeStats`](crate::doc::EncodeStats).
+//!
+//! Titles are not unique and may change when pages are retitled, so
+//! [`DjvuBuilder::with_canonical_links`] can rewrite links that name a
+//! page by its title to use its ID instead.
+//!
+//! [`DjvuBuilder::with_canonical_links`]: crate::doc::DjvuBuilder::with_canonical_links
+
+use crate::annotations::Annotations;
+use crate::annotations::annotations::retarget_mapareas;
+use crate::doc::integrity::{self, CHECKSUM_CHUNK_ID};
+use crate::iff::bs_byte_stream::{BzzOptions, bzz_compress_with, bzz_decompress};
+use crate::iff::chunk_id::ChunkId;
+use crate::iff::chunk_tree::{ChunkPayload, IffDocument};
+use crate::{DjvuError, Result};
+
+/// A page link whose target is not in the document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenLink {
+    /// Page the link is on, numbered as in the written document.
+    pub page_num: usize,
+    /// The link's URL, `#` included.
+    pub url: String,
+}
+
+/// How a page link target resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LinkTarget {
+    /// A page number, absolute or relative.
+    Number(usize),
+    /// The ID of a page.
+    Id(usize),
+    /// The title of a page, which is not also the ID of one.
+    Title(usize),
+}
+
+/// The directory entries page links are resolved against.
+pub(crate) struct PageDirectory<'a> {
+    pub ids: &'a [String],
+    pub titles: &'a [Option<String>],
+}
+
+impl PageDirectory<'_> {
+    /// Resolves the part of a link after the `#`, for a link on page `from`.
+    pub fn resolve(&self, target: &str, from: usize) -> Option<LinkTarget> {
+        if let Some(page) = page_number(target, from, self.ids.len()) {
+            return page.map(LinkTarget::Number);
+        }
+        if let Some(page) = self.ids.iter().position(|id| id == target) {
+            return Some(LinkTarget::Id(page));
+        }
+        self.titles
+            .iter()
+            .position(|title| title.as_deref() == Some(target))
+            .map(LinkTarget::Title)
+    }
+
+    /// Checks the page links of the annotations of page `from`, collecting
+    /// the broken ones. With `canonical`, returns the URLs of links that
+    /// name a page by its title, each with the URL naming its ID instead.
+    pub fn check(
+        &self,
+        annotations: &Annotations,
+        from: usize,
+        canonical: bool,
+        broken: &mut Vec<BrokenLink>,
+    ) -> Vec<(String, String)> {
+        let mut rewrites = Vec::new();
+        for link in &annotations.hyperlinks {
+            let Some(target) = link.page_target() else {
+                continue;
+            };
+            match self.resolve(target, from) {
+                None => broken.push(BrokenLink {
+                    page_num: from,
+                    url: link.url.clone(),
+                }),
+                Some(LinkTarget::Title(page)) if canonical => {
+                    rewrites.push((link.url.clone(), format!("#{}", self.ids[page])));
+                }
+                Some(_) => {}
+            }
+        }
+        rewrites
+    }
+}
+
+/// Resolves a link target that is a page number, absolute or relative to
+/// page `from`, in a document of `pages` pages. Returns `None` when the
+/// target is a name rather than a number, and `Some(None)` when it is a
+/// number outside the document.
+pub(crate) fn page_number(target: &str, from: usize, pages: usize) -> Option<Option<usize>> {
+    if let Some(offset) = target
+        .strip_prefix('+')
+        .and_then(|n| n.parse::<usize>().ok())
+    {
+        return Some(from.checked_add(offset).filter(|&page| page < pages));
+    }
+    if let Some(offset) = target
+        .strip_prefix('-')
+        .and_then(|n| n.parse::<usize>().ok())
+    {
+        return Some(from.checked_sub(offset));
+    }
+    if !target.is_empty() && target.bytes().all(|b| b.is_ascii_digit()) {
+        let page = target.parse::<usize>().ok();
+        return Some(page.filter(|&n| (1..=pages).contains(&n)).map(|n| n - 1));
+    }
+    None
+}
+
+/// Rewrites the link URLs in the annotation chunks of an encoded
+/// `FORM:DJVU` page with [`retarget_mapareas`], refreshing its integrity
+/// checksum if it has one. The rest of the annotations is kept as it is.
+pub(crate) fn retarget_links(
+    page: &[u8],
+    mut retarget: impl FnMut(&str) -> Option<String>,
+    bzz: &BzzOptions,
+) -> Result<Vec<u8>> {
+    let mut document = IffDocument::from_bytes(page)?;
+    if document.root.secondary_id() != Some(ChunkId::DJVU) {
+        return Err(DjvuError::InvalidArg(
+            "Links can only be rewritten in FORM:DJVU pages".to_string(),
+        ));
+    }
+    let checksummed = document.root.remove_children(CHECKSUM_CHUNK_ID) > 0;
+    for chunk in document.root.children_mut().into_iter().flatten() {
+        let ChunkPayload::Raw(data) = &mut chunk.payload else {
+            continue;
+        };
+        if chunk.id == ChunkId::ANTa {
+            *data = retarget_mapareas(data, &mut retarget).0;
+        } else if chunk.id == ChunkId::ANTz {
+            let text = bzz_decompress(data)
+                .map_err(|e| DjvuError::ValidationError(format!("Unreadable ANTz chunk: {e}")))?;
+            let (text, changed) = retarget_mapareas(&text, &mut retarget);
+            if changed {
+                *data = bzz_compress_with(&text, bzz).map_err(|e| {
+                    DjvuError::EncodingError(format!("BZZ compression failed: {e}"))
+                })?;
+            }
+        }
+    }
+
+    let mut out = document.to_bytes()?;
+    if !page.starts_with(b"AT&T") {
+        out.drain(..4);
+    }
+    if checksummed {
+        integrity::append_checksum(&mut out)?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotations::{AnnotationShape, Hyperlink};
+
+    fn link(url: &str) -> Hyperlink {
+        Hyperlink {
+            shape: AnnotationShape::Rect {
+                x: 0,
+                y: 0,
+                w: 10,
+                h: 10,
+            },
+            url: url.to_string(),
+            comment: String::new(),
+            target: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_targets() {
+        let ids = ["cover.djvu", "p0002.djvu", "p0003.djvu"].map(String::from);
+        let titles = [Some("Cover".to_string()), None, Some("Index".to_string())];
+        let dir = PageDirectory {
+            ids: &ids,
+            titles: &titles,
+        };
+        assert_eq!(dir.resolve("cover.djvu", 2), Some(LinkTarget::Id(0)));
+        assert_eq!(dir.resolve("Index", 0), Some(LinkTarget::Title(2)));
+        assert_eq!(dir.resolve("3", 0), Some(LinkTarget::Number(2)));
+        assert_eq!(dir.resolve("+1", 1), Some(LinkTarget::Number(2)));
+        assert_eq!(dir.resolve("-2", 2), Some(LinkTarget::Number(0)));
+        for missing in ["0", "4", "+1", "-3", "Contents", ""] {
+            assert_eq!(dir.resolve(missing, 2), None, "{missing}");
+        }
+
+        let annotations = Annotations {
+            hyperlinks: vec![
+                link("#Index"),
+                link("#Appendix"),
+                link("https://example.com"),
+                link("#+1"),
+            ],
+            metadata: Vec::new(),
+        };
+        let mut broken = Vec::new();
+        let rewrites = dir.check(&annotations, 0, true, &mut broken);
+        assert_eq!(
+            rewrites,
+            [("#Index".to_string(), "#p0003.djvu".to_string())]
+        );
+        assert_eq!(
+            broken,
+            [BrokenLink {
+                page_num: 0,
+                url: "#Appendix".to_string()
+            }]
+        );
+        assert!(dir.check(&annotations, 0, false, &mut broken).is_empty());
+    }
+}
@@ -1,7 +1,31 @@
 // Core infrastructure
+pub mod background;
+pub mod batch;
+pub mod blank;
+pub mod checkpoint;
+pub mod chunk_stats;
+pub mod debug_dump;
 pub mod djvu_dir;
+pub mod djvu_nav;
+pub mod editor;
+pub mod estimate;
+pub mod import;
+pub mod include_file;
+pub mod integrity;
+pub mod links;
+#[cfg(feature = "ocr-tesseract")]
+pub mod ocr;
+pub mod output;
 pub mod page_collection;
 pub mod page_encoder;
+pub mod profile;
+#[cfg(feature = "project")]
+pub mod project;
+pub mod recovery;
+pub mod render;
+pub mod script;
+pub mod search_index;
+pub mod source;
 
 // Public builder API
 pub mod builder;
@@ -10,9 +34,33 @@ pub mod builder;
 pub(crate) mod encoder;
 
 // Re-export public builder API
-pub use builder::{DjvuBuilder, DjvuDocument, ImageLayer, LayerData, Page, PageBuilder};
+pub use builder::{
+    DjvuBuilder, DjvuDocument, ImageLayer, LayerData, Page, PageBuilder, PageIdScheme,
+    SYMBOL_DICT_ID, page_id_from_path,
+};
 
 // Re-export types needed by the builder
-pub use djvu_dir::{Bookmark, DjVmDir, DjVmNav, File as DjVuFile, FileType};
+pub use background::{BackgroundCodec, Iw44Background};
+pub use batch::BatchOptions;
+pub use blank::{BlankPageAction, BlankPageParams};
+pub use chunk_stats::{DocumentStats, Iw44ChainIssue};
+pub use debug_dump::{DebugDump, DumpFormat};
+pub use djvu_dir::{
+    Bookmark, DirectoryFormat, DjVmDir, DjVmNav, File as DjVuFile, FileRename, FileType,
+};
+pub use djvu_nav::DjVuNavDir;
+pub use editor::DjVuDocEditor;
+pub use estimate::SizeEstimate;
+pub use import::{ImportOptions, ImportedPage, PageClass, Preprocessor};
+pub use include_file::{IncludeFile, IncludeFileBuilder};
+pub use links::BrokenLink;
 pub use page_collection::{DocumentStatus, PageCollection};
-pub use page_encoder::{EncodedPage, PageComponents, PageEncodeParams, PageLayer, Rect};
+pub use page_encoder::{
+    EncodedPage, PageComponents, PageEncodeParams, PageInfo, PageLayer, PageRotation, Rect,
+};
+pub use profile::Profile;
+pub use recovery::{EncodeStats, ErrorRecoveryAction, PageFailure};
+pub use render::{RenderMode, render_page};
+pub use script::{Script, ScriptCommand, ScriptInput};
+pub use search_index::SearchIndex;
+pub use source::{ComponentSource, DirSource, MemorySource};
@@ -0,0 +1,260 @@
+//! One-step OCR with Tesseract (feature `ocr-tesseract`).
+//!
+//! [`TesseractOcr`] runs the `tesseract` program, which must be on `PATH`
+//! (or be given with [`TesseractOcr::with_program`]), on the rendered page
+//! and reads its hOCR output into a hidden text layer. Set on a document
+//! with [`DjvuBuilder::with_ocr`], it recognizes every page added without a
+//! text layer of its own, so scans come out searchable:
+//!
+//! ```ignore
+//! let doc = DjvuBuilder::new(pages.len())
+//!     .with_ocr(TesseractOcr::new().with_languages("eng+deu"))
+//!     .build();
+//! for page in pages {
+//!     doc.add_page(page)?;
+//! }
+//! ```
+//!
+//! [`DjvuBuilder::with_ocr`]: crate::doc::DjvuBuilder::with_ocr
+
+use crate::annotations::hidden_text::HiddenText;
+use crate::doc::page_encoder::{PageComponents, PageRotation};
+use crate::doc::render::{RenderMode, render_page};
+use crate::image::image_formats::{LoadedImage, write_pnm};
+use crate::utils::error::IoContext;
+use crate::{DjvuError, Result};
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Settings for running Tesseract on a page.
+#[derive(Debug, Clone)]
+pub struct TesseractOcr {
+    program: PathBuf,
+    languages: String,
+    page_segmentation: Option<u8>,
+}
+
+impl Default for TesseractOcr {
+    fn default() -> Self {
+        Self {
+            program: PathBuf::from("tesseract"),
+            languages: "eng".to_string(),
+            page_segmentation: None,
+        }
+    }
+}
+
+impl TesseractOcr {
+    /// English, with Tesseract's automatic page segmentation.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `program` instead of `tesseract` from `PATH`.
+    pub fn with_program(mut self, program: impl Into<PathBuf>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Sets the language models, such as `eng+fra` (Tesseract's `-l`).
+    pub fn with_languages(mut self, languages: impl Into<String>) -> Self {
+        self.languages = languages.into();
+        self
+    }
+
+    /// Sets the page segmentation mode, 0-13 (Tesseract's `--psm`).
+    pub fn with_page_segmentation(mut self, mode: u8) -> Self {
+        self.page_segmentation = Some(mode);
+        self
+    }
+
+    /// Recognizes the text of `page`, rendered at full resolution, and
+    /// returns it in the displayed page's coordinates, as
+    /// [`PageComponents::text_layer`] takes it.
+    pub fn recognize(&self, page: &PageComponents, dpi: u32) -> Result<HiddenText> {
+        let image = render_page(page, 1.0, RenderMode::Color)?;
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("page.ppm");
+        let mut data = Vec::new();
+        write_pnm(&mut data, &LoadedImage::Rgb(image))?;
+        std::fs::write(&input, data).context(input.display())?;
+
+        let mut command = Command::new(&self.program);
+        command
+            .arg(&input)
+            .arg("stdout")
+            .arg("-l")
+            .arg(&self.languages)
+            .arg("--dpi")
+            .arg(dpi.to_string());
+        if let Some(mode) = self.page_segmentation {
+            command.arg("--psm").arg(mode.to_string());
+        }
+        let output = command.arg("hocr").output().map_err(|e| {
+            DjvuError::Custom(format!("Failed to run {}: {e}", self.program.display()))
+        })?;
+        if !output.status.success() {
+            return Err(DjvuError::EncodingError(format!(
+                "{} failed ({}): {}",
+                self.program.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let hocr = String::from_utf8_lossy(&output.stdout);
+        let text = HiddenText::from_hocr(&hocr)
+            .map_err(|e| DjvuError::EncodingError(format!("Unreadable hOCR: {e}")))?;
+        Ok(displayed(text, page))
+    }
+}
+
+/// Maps `text`, found on the stored image of `page`, to the page as it is
+/// displayed.
+fn displayed(text: HiddenText, page: &PageComponents) -> HiddenText {
+    let (width, height) = page.dimensions();
+    let (width, height) = (width as u16, height as u16);
+    // Turning the displayed page back is the opposite quarter turn, on a
+    // page of the displayed size.
+    match page.rotation {
+        PageRotation::Upright => text,
+        PageRotation::Rotate180 => text.unrotated(PageRotation::Rotate180, width, height),
+        PageRotation::Ccw90 => text.unrotated(PageRotation::Cw90, height, width),
+        PageRotation::Cw90 => text.unrotated(PageRotation::Ccw90, height, width),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotations::hidden_text::{BoundingBox, Zone};
+
+    #[test]
+    fn test_displayed_undoes_rotation() {
+        // A word at the stored page's bottom-left corner, on a 100x40 page.
+        let mut stored = HiddenText::new(BoundingBox {
+            x: 0,
+            y: 0,
+            w: 100,
+            h: 40,
+        });
+        stored.root_zone.children.push(Zone::word(
+            "corner".to_string(),
+            BoundingBox {
+                x: 0,
+                y: 0,
+                w: 20,
+                h: 10,
+            },
+        ));
+        for rotation in [
+            PageRotation::Upright,
+            PageRotation::Ccw90,
+            PageRotation::Rotate180,
+            PageRotation::Cw90,
+        ] {
+            let page = PageComponents::new_with_dimensions(100, 40).with_rotation(rotation);
+            let shown = displayed(stored.clone(), &page);
+            let back = shown.unrotated(rotation, 100, 40).root_zone.children[0].bbox;
+            assert_eq!(
+                (back.x, back.y, back.w, back.h),
+                (0, 0, 20, 10),
+                "{rotation:?}"
+            );
+        }
+
+        let missing = TesseractOcr::new().with_program("/nonexistent/tesseract");
+        let page = PageComponents::new_with_dimensions(8, 8);
+        assert!(matches!(
+            missing.recognize(&page, 300),
+            Err(DjvuError::Custom(_))
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs the tesseract program with English data on the PATH"]
+    fn test_tesseract_reads_block_letters() -> Result<()> {
+        use crate::annotations::hidden_text::ZoneKind;
+        use crate::encode::jb2::symbol_dict::BitImage;
+
+        // 5x7 block letters, drawn 8 pixels to a dot.
+        const FONT: [(char, [&str; 7]); 7] = [
+            (
+                'D',
+                [
+                    "1110.", "1..1.", "1...1", "1...1", "1...1", "1..1.", "1110.",
+                ],
+            ),
+            (
+                'E',
+                [
+                    "11111", "1....", "1....", "1111.", "1....", "1....", "11111",
+                ],
+            ),
+            (
+                'H',
+                [
+                    "1...1", "1...1", "1...1", "11111", "1...1", "1...1", "1...1",
+                ],
+            ),
+            (
+                'L',
+                [
+                    "1....", "1....", "1....", "1....", "1....", "1....", "11111",
+                ],
+            ),
+            (
+                'O',
+                [
+                    ".111.", "1...1", "1...1", "1...1", "1...1", "1...1", ".111.",
+                ],
+            ),
+            (
+                'R',
+                [
+                    "1111.", "1...1", "1...1", "1111.", "1.1..", "1..1.", "1...1",
+                ],
+            ),
+            (
+                'W',
+                [
+                    "1...1", "1...1", "1...1", "1.1.1", "1.1.1", "11.11", "1...1",
+                ],
+            ),
+        ];
+        const DOT: usize = 8;
+        let mut mask = BitImage::new(900, 200).unwrap();
+        let mut left = 60;
+        for c in "HELLO WORLD".chars() {
+            if let Some((_, rows)) = FONT.iter().find(|(f, _)| *f == c) {
+                for (y, row) in rows.iter().enumerate() {
+                    for (x, _) in row.char_indices().filter(|(_, d)| *d == '1') {
+                        for (dx, dy) in (0..DOT).flat_map(|dx| (0..DOT).map(move |dy| (dx, dy))) {
+                            mask.set_usize(left + x * DOT + dx, 60 + y * DOT + dy, true);
+                        }
+                    }
+                }
+            }
+            left += 7 * DOT;
+        }
+        let page = PageComponents::new().with_mask(mask)?;
+
+        let text = TesseractOcr::new()
+            .with_page_segmentation(7)
+            .recognize(&page, 300)?;
+        let mut words = Vec::new();
+        let mut zones = vec![&text.root_zone];
+        while let Some(zone) = zones.pop() {
+            if zone.kind == ZoneKind::Word {
+                words.push((zone.text.clone().unwrap_or_default(), zone.bbox));
+            }
+            zones.extend(zone.children.iter().rev());
+        }
+        let found: Vec<&str> = words.iter().map(|(w, _)| w.as_str()).collect();
+        assert_eq!(found, ["HELLO", "WORLD"]);
+        // The first word covers the first letters, in DjVu's bottom-up
+        // coordinates.
+        let b = words[0].1;
+        assert!(b.x.abs_diff(60) < 8 && b.y.abs_diff(200 - 116) < 8, "{b:?}");
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Post-processing of finished documents on their way to the output.
+//!
+//! An [`OutputTransform`] sits between the DjVu bytes and the destination
+//! writer, so a document can be compressed or encrypted for distribution
+//! while it is written, page by page, instead of being written to a buffer
+//! and processed again. Configure one with
+//! [`DjvuBuilder::with_output_transform`](crate::doc::DjvuBuilder::with_output_transform)
+//! and write with [`DjvuDocument::write_to`](crate::doc::DjvuDocument::write_to).
+//!
+//! | Transform | Feature |
+//! | --- | --- |
+//! | [`Identity`] | always available |
+//! | [`Gzip`] | `gzip` |
+//! | [`AgeEncrypt`] | `age` |
+
+#[cfg(any(feature = "gzip", feature = "age"))]
+use crate::DjvuError;
+use crate::Result;
+use std::io::Write;
+
+/// A transformation applied to the document bytes as they are written.
+pub trait OutputTransform: Send + Sync {
+    /// Wraps `out` in a writer that passes on the transformed form of what
+    /// is written to it.
+    ///
+    /// The document is written to the returned writer in pieces, then
+    /// [`TransformWriter::finish`] is called.
+    fn wrap<'a>(&self, out: &'a mut dyn Write) -> Result<Box<dyn TransformWriter + 'a>>;
+
+    /// Writes the transformed form of `data` to `out`.
+    fn write_all(&self, data: &[u8], out: &mut dyn Write) -> Result<()> {
+        let mut writer = self.wrap(out)?;
+        writer.write_all(data)?;
+        writer.finish()
+    }
+}
+
+/// The writer an [`OutputTransform`] wraps the destination in.
+pub trait TransformWriter: Write {
+    /// Writes what is still pending, including any trailer.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Writes the document unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl OutputTransform for Identity {
+    fn wrap<'a>(&self, out: &'a mut dyn Write) -> Result<Box<dyn TransformWriter + 'a>> {
+        Ok(Box::new(out))
+    }
+}
+
+impl TransformWriter for &mut dyn Write {
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Wraps the document in a gzip stream.
+#[cfg(feature = "gzip")]
+#[derive(Debug, Clone, Copy)]
+pub struct Gzip {
+    level: u32,
+}
+
+#[cfg(feature = "gzip")]
+impl Gzip {
+    /// Creates a gzip transform with the given compression level (0-9).
+    pub fn new(level: u32) -> Result<Self> {
+        if level > 9 {
+            return Err(DjvuError::InvalidArg(format!(
+                "gzip level {level} out of range 0..=9"
+            )));
+        }
+        Ok(Self { level })
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Default for Gzip {
+    fn default() -> Self {
+        Self { level: 6 }
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl OutputTransform for Gzip {
+    fn wrap<'a>(&self, out: &'a mut dyn Write) -> Result<Box<dyn TransformWriter + 'a>> {
+        let encoder = flate2::write::GzEncoder::new(out, flate2::Compression::new(self.level));
+        Ok(Box::new(encoder))
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl<W: Write> TransformWriter for flate2::write::GzEncoder<W> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        flate2::write::GzEncoder::finish(*self)?;
+        Ok(())
+    }
+}
+
+/// Encrypts the document to one or more age X25519 recipients.
+#[cfg(feature = "age")]
+#[derive(Clone)]
+pub struct AgeEncrypt {
+    recipients: Vec<age::x25519::Recipient>,
+}
+
+#[cfg(feature = "age")]
+impl AgeEncrypt {
+    /// Creates a transform encrypting to `recipients`.
+    pub fn new(recipients: Vec<age::x25519::Recipient>) -> Result<Self> {
+        if recipients.is_empty() {
+            return Err(DjvuError::InvalidArg(
+                "age encryption needs at least one recipient".to_string(),
+            ));
+        }
+        Ok(Self { recipients })
+    }
+
+    /// Creates a transform from `age1...` public key strings.
+    pub fn from_public_keys<S: AsRef<str>>(keys: &[S]) -> Result<Self> {
+        let recipients = keys
+            .iter()
+            .map(|k| {
+                k.as_ref().parse::<age::x25519::Recipient>().map_err(|e| {
+                    DjvuError::InvalidArg(format!("invalid age recipient {:?}: {e}", k.as_ref()))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(recipients)
+    }
+}
+
+#[cfg(feature = "age")]
+impl OutputTransform for AgeEncrypt {
+    fn wrap<'a>(&self, out: &'a mut dyn Write) -> Result<Box<dyn TransformWriter + 'a>> {
+        let encryptor = age::Encryptor::with_recipients(
+            self.recipients.iter().map(|r| r as &dyn age::Recipient),
+        )
+        .map_err(|e| DjvuError::EncodingError(format!("age encryption failed: {e}")))?;
+        Ok(Box::new(encryptor.wrap_output(out)?))
+    }
+}
+
+#[cfg(feature = "age")]
+impl<W: Write> TransformWriter for age::stream::StreamWriter<W> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        age::stream::StreamWriter::finish(*self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_passes_through() {
+        let mut out = Vec::new();
+        Identity.write_all(b"AT&TFORM", &mut out).unwrap();
+        assert_eq!(out, b"AT&TFORM");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip_round_trip() {
+        use std::io::Read;
+
+        let data = vec![7u8; 4096];
+        let mut out = Vec::new();
+        Gzip::default().write_all(&data, &mut out).unwrap();
+        assert!(out.len() < data.len());
+
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&out[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[cfg(feature = "age")]
+    #[test]
+    fn test_age_round_trip() {
+        use std::io::Read;
+
+        let identity = age::x25519::Identity::generate();
+        let transform = AgeEncrypt::new(vec![identity.to_public()]).unwrap();
+        let mut out = Vec::new();
+        transform.write_all(b"AT&TFORM", &mut out).unwrap();
+
+        let decryptor = age::Decryptor::new(&out[..]).unwrap();
+        let mut reader = decryptor
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .unwrap();
+        let mut decoded = Vec::new();
+        reader.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, b"AT&TFORM");
+    }
+}
@@ -1,22 +1,104 @@
 use crate::doc::djvu_dir::DjVmNav;
 use crate::doc::page_encoder::{EncodedPage, PageComponents, PageEncodeParams};
+use crate::utils::djvu_global::{self, CachePolicy};
+use crate::utils::error::IoContext;
+use crate::utils::log::{debug, target, warn};
+use crate::utils::memory::MemoryTracker;
 use crate::{DjvuError, Result};
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 
 enum PageSlot {
     Pending,
     Ready(Arc<Vec<u8>>),
+    /// Encoded page moved to the spill file to stay under the memory cap.
+    Spilled {
+        offset: u64,
+        len: usize,
+    },
+    /// Dropped from the document after its encoding failed.
+    Skipped,
+    /// Encoded page that was measured but not kept; its owner encodes it
+    /// again when the document is written.
+    Deferred,
+}
+
+impl PageSlot {
+    fn is_filled(&self) -> bool {
+        !matches!(self, PageSlot::Pending)
+    }
+}
+
+/// Scratch file holding encoded pages that did not fit in memory.
+///
+/// Pages are appended and never rewritten; the file is deleted on drop.
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    end: u64,
+}
+
+impl SpillFile {
+    fn create() -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = djvu_global::config().temp_dir().join(format!(
+            "djvu-spill-{}-{}.tmp",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .context(path.display())?;
+        debug!(target: target::DOC, "Spilling encoded pages to {}", path.display());
+        Ok(Self { path, file, end: 0 })
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        let offset = self.end;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.end += data.len() as u64;
+        Ok(offset)
+    }
+
+    fn read(&mut self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
 }
 
 /// Thread-safe, out-of-order page collection with per-slot locking.
 ///
 /// Each page slot has its own `RwLock`, so concurrent insertions to different
 /// pages never contend with each other.
+///
+/// Encoded pages count against a [`MemoryTracker`]. When a page would push
+/// the total over the configured cap (or over the cache policy's page
+/// count), it is written to a temporary spill file instead and read back at
+/// assembly time.
 pub struct PageCollection {
     slots: Vec<RwLock<PageSlot>>,
     metadata: Vec<RwLock<Option<PageMetadata>>>,
     total_pages: usize,
+    memory: Arc<MemoryTracker>,
+    cache_policy: CachePolicy,
+    cached_pages: AtomicUsize,
+    spill: Mutex<Option<SpillFile>>,
 }
 
 #[derive(Clone)]
@@ -27,7 +109,21 @@ pub struct PageMetadata {
 }
 
 impl PageCollection {
+    /// Creates a collection that follows the global memory cap and cache policy.
     pub fn new(total_pages: usize) -> Self {
+        Self::with_memory(
+            total_pages,
+            Arc::new(MemoryTracker::from_global()),
+            djvu_global::config().cache_policy().clone(),
+        )
+    }
+
+    /// Creates a collection that accounts against `memory` using `policy`.
+    pub fn with_memory(
+        total_pages: usize,
+        memory: Arc<MemoryTracker>,
+        cache_policy: CachePolicy,
+    ) -> Self {
         let mut slots = Vec::with_capacity(total_pages);
         let mut metadata = Vec::with_capacity(total_pages);
         for _ in 0..total_pages {
@@ -38,9 +134,31 @@ impl PageCollection {
             slots,
             metadata,
             total_pages,
+            memory,
+            cache_policy,
+            cached_pages: AtomicUsize::new(0),
+            spill: Mutex::new(None),
         }
     }
 
+    /// The tracker this collection reports to.
+    pub fn memory(&self) -> &Arc<MemoryTracker> {
+        &self.memory
+    }
+
+    /// Number of pages currently held in the spill file.
+    pub fn spilled_count(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|s| {
+                matches!(
+                    *s.read().unwrap_or_else(PoisonError::into_inner),
+                    PageSlot::Spilled { .. }
+                )
+            })
+            .count()
+    }
+
     pub fn len(&self) -> usize {
         self.total_pages
     }
@@ -50,6 +168,26 @@ impl PageCollection {
     }
 
     pub fn insert_page(&self, page_num: usize, page: EncodedPage) -> Result<()> {
+        self.fill(page_num, page.width, page.height, || {
+            self.store(page_num, &page.data)
+        })
+    }
+
+    /// Marks a page as ready without keeping its data, which its owner
+    /// makes again when the document is written.
+    pub fn insert_deferred(&self, page_num: usize, width: u32, height: u32) -> Result<()> {
+        self.fill(page_num, width, height, || Ok(PageSlot::Deferred))
+    }
+
+    /// Fills an empty page slot with what `make` gives, recording the
+    /// page's dimensions.
+    fn fill(
+        &self,
+        page_num: usize,
+        width: u32,
+        height: u32,
+        make: impl FnOnce() -> Result<PageSlot>,
+    ) -> Result<()> {
         if page_num >= self.total_pages {
             return Err(DjvuError::InvalidOperation(format!(
                 "Page number {} exceeds total pages {}",
@@ -58,21 +196,25 @@ impl PageCollection {
         }
 
         {
-            let mut slot = self.slots[page_num].write().unwrap();
-            if matches!(*slot, PageSlot::Ready(_)) {
+            let mut slot = self.slots[page_num]
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            if slot.is_filled() {
                 return Err(DjvuError::InvalidOperation(format!(
                     "Page {} already exists",
                     page_num
                 )));
             }
-            *slot = PageSlot::Ready(Arc::clone(&page.data));
+            *slot = make()?;
         }
 
         {
-            let mut meta = self.metadata[page_num].write().unwrap();
+            let mut meta = self.metadata[page_num]
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
             *meta = Some(PageMetadata {
-                width: page.width,
-                height: page.height,
+                width,
+                height,
                 id: meta.as_ref().and_then(|m| m.id.clone()),
             });
         }
@@ -80,79 +222,194 @@ impl PageCollection {
         Ok(())
     }
 
+    /// Decides where an encoded page lives: in memory if it fits under the
+    /// cap and cache policy, otherwise in the spill file.
+    fn store(&self, page_num: usize, data: &Arc<Vec<u8>>) -> Result<PageSlot> {
+        let len = data.len();
+        let under_page_limit = self
+            .cache_policy
+            .max_cached_pages
+            .is_none_or(|max| self.cached_pages.load(Ordering::Relaxed) < max);
+
+        if under_page_limit && self.memory.try_reserve(len) {
+            self.cached_pages.fetch_add(1, Ordering::Relaxed);
+            return Ok(PageSlot::Ready(Arc::clone(data)));
+        }
+
+        if !self.cache_policy.spill_to_disk {
+            warn!(
+                target: target::DOC,
+                "Page {} exceeds the memory cap but spilling is disabled; keeping it in memory",
+                page_num
+            );
+            self.memory.reserve(len);
+            self.cached_pages.fetch_add(1, Ordering::Relaxed);
+            return Ok(PageSlot::Ready(Arc::clone(data)));
+        }
+
+        let mut spill = self.spill.lock().unwrap_or_else(PoisonError::into_inner);
+        let file = match &mut *spill {
+            Some(file) => file,
+            empty @ None => empty.insert(SpillFile::create()?),
+        };
+        let offset = file.append(data)?;
+        debug!(
+            target: target::DOC,
+            "Spilled page {} ({} bytes, {} in memory)",
+            page_num,
+            len,
+            self.memory.used()
+        );
+        Ok(PageSlot::Spilled { offset, len })
+    }
+
+    /// Returns the bytes for a filled slot, reading them back if spilled.
+    fn load(&self, slot: &PageSlot) -> Result<Option<Arc<Vec<u8>>>> {
+        match slot {
+            PageSlot::Pending | PageSlot::Skipped | PageSlot::Deferred => Ok(None),
+            PageSlot::Ready(data) => Ok(Some(Arc::clone(data))),
+            PageSlot::Spilled { offset, len } => {
+                let mut spill = self.spill.lock().unwrap_or_else(PoisonError::into_inner);
+                let file = spill
+                    .as_mut()
+                    .ok_or_else(|| DjvuError::InvalidOperation("Spill file missing".to_string()))?;
+                Ok(Some(Arc::new(file.read(*offset, *len)?)))
+            }
+        }
+    }
+
+    /// Marks a page as left out of the document, filling its slot.
+    pub fn skip_page(&self, page_num: usize) -> Result<()> {
+        if page_num >= self.total_pages {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Page number {} exceeds total pages {}",
+                page_num, self.total_pages
+            )));
+        }
+        let mut slot = self.slots[page_num]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if slot.is_filled() {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Page {} already exists",
+                page_num
+            )));
+        }
+        *slot = PageSlot::Skipped;
+        Ok(())
+    }
+
+    /// Page numbers marked with [`Self::skip_page`], in order.
+    pub fn skipped_pages(&self) -> Vec<usize> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| {
+                matches!(
+                    *s.read().unwrap_or_else(PoisonError::into_inner),
+                    PageSlot::Skipped
+                )
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
     pub fn is_page_ready(&self, page_num: usize) -> bool {
         if page_num >= self.total_pages {
             return false;
         }
-        let slot = self.slots[page_num].read().unwrap();
-        matches!(*slot, PageSlot::Ready(_))
+        let slot = self.slots[page_num]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        slot.is_filled()
     }
 
     pub fn is_complete(&self) -> bool {
         self.slots
             .iter()
-            .all(|s| matches!(*s.read().unwrap(), PageSlot::Ready(_)))
+            .all(|s| s.read().unwrap_or_else(PoisonError::into_inner).is_filled())
     }
 
     pub fn ready_count(&self) -> usize {
         self.slots
             .iter()
-            .filter(|s| matches!(*s.read().unwrap(), PageSlot::Ready(_)))
+            .filter(|s| s.read().unwrap_or_else(PoisonError::into_inner).is_filled())
             .count()
     }
 
-    pub fn get_page(&self, page_num: usize) -> Option<Arc<Vec<u8>>> {
+    pub fn get_page(&self, page_num: usize) -> Result<Option<Arc<Vec<u8>>>> {
         if page_num >= self.total_pages {
-            return None;
-        }
-        let slot = self.slots[page_num].read().unwrap();
-        match &*slot {
-            PageSlot::Ready(data) => Some(Arc::clone(data)),
-            PageSlot::Pending => None,
+            return Ok(None);
         }
+        let slot = self.slots[page_num]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.load(&slot)
     }
 
     /// Collect all pages as `Arc` references (non-destructive).
-    pub fn collect_all(&self) -> Option<Vec<Arc<Vec<u8>>>> {
+    ///
+    /// Returns `Ok(None)` if any page is still pending or deferred. Skipped
+    /// pages are left out.
+    pub fn collect_all(&self) -> Result<Option<Vec<Arc<Vec<u8>>>>> {
         let mut pages = Vec::with_capacity(self.total_pages);
         for slot_lock in &self.slots {
-            let slot = slot_lock.read().unwrap();
-            match &*slot {
-                PageSlot::Ready(data) => pages.push(Arc::clone(data)),
-                PageSlot::Pending => return None,
+            let slot = slot_lock.read().unwrap_or_else(PoisonError::into_inner);
+            if matches!(*slot, PageSlot::Skipped) {
+                continue;
+            }
+            match self.load(&slot)? {
+                Some(data) => pages.push(data),
+                None => return Ok(None),
             }
         }
-        Some(pages)
+        Ok(Some(pages))
     }
 
     /// Take all pages out of the collection, consuming the internal references.
     ///
+    /// Skipped pages are left out, so the result can be shorter than
+    /// [`Self::len`].
+    ///
     /// Each slot is swapped to `Pending`, dropping the collection's `Arc`
     /// reference. This guarantees `Arc::try_unwrap` succeeds on the returned
-    /// values, avoiding deep clones during finalization.
-    pub fn take_all(&self) -> Option<Vec<Vec<u8>>> {
-        // Quick check: all slots must be Ready before we start swapping.
-        for slot_lock in &self.slots {
-            if !matches!(*slot_lock.read().unwrap(), PageSlot::Ready(_)) {
-                return None;
-            }
+    /// values, avoiding deep clones during finalization. Spilled pages are
+    /// read back, and the spill file is removed once everything is out.
+    pub fn take_all(&self) -> Result<Vec<Vec<u8>>> {
+        // Quick check: all slots must be filled before we start swapping.
+        if !self.is_complete() {
+            return Err(DjvuError::InvalidOperation(
+                "Not all pages ready".to_string(),
+            ));
         }
 
         let mut pages = Vec::with_capacity(self.total_pages);
         for slot_lock in &self.slots {
-            let mut slot = slot_lock.write().unwrap();
-            if let PageSlot::Ready(data) = std::mem::replace(&mut *slot, PageSlot::Pending) {
-                pages.push(Arc::try_unwrap(data).unwrap_or_else(|a| (*a).clone()));
+            let mut slot = slot_lock.write().unwrap_or_else(PoisonError::into_inner);
+            match std::mem::replace(&mut *slot, PageSlot::Pending) {
+                PageSlot::Ready(data) => {
+                    self.memory.release(data.len());
+                    self.cached_pages.fetch_sub(1, Ordering::Relaxed);
+                    pages.push(Arc::try_unwrap(data).unwrap_or_else(|a| (*a).clone()));
+                }
+                spilled @ PageSlot::Spilled { .. } => {
+                    let data = self.load(&spilled)?.unwrap_or_default();
+                    pages.push(Arc::try_unwrap(data).unwrap_or_else(|a| (*a).clone()));
+                }
+                PageSlot::Pending | PageSlot::Skipped | PageSlot::Deferred => {}
             }
         }
-        Some(pages)
+        *self.spill.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        Ok(pages)
     }
 
     pub fn get_metadata(&self, page_num: usize) -> Option<(u32, u32)> {
         if page_num >= self.total_pages {
             return None;
         }
-        let meta = self.metadata[page_num].read().unwrap();
+        let meta = self.metadata[page_num]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
         meta.as_ref().map(|m| (m.width, m.height))
     }
 
@@ -164,7 +421,9 @@ impl PageCollection {
             )));
         }
 
-        let mut meta = self.metadata[page_num].write().unwrap();
+        let mut meta = self.metadata[page_num]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
         match meta.as_mut() {
             Some(m) => m.id = Some(id),
             None => {
@@ -182,7 +441,9 @@ impl PageCollection {
         if page_num >= self.total_pages {
             return None;
         }
-        let meta = self.metadata[page_num].read().unwrap();
+        let meta = self.metadata[page_num]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
         meta.clone()
     }
 }
@@ -197,13 +458,19 @@ pub struct DocumentBuilder {
 }
 
 impl DocumentBuilder {
+    /// Creates a builder for `total_pages` pages, with DPI and gamma from
+    /// [`djvu_global::config`].
     pub fn new(total_pages: usize) -> Self {
-        let params = PageEncodeParams::default();
+        let global = djvu_global::config();
+        let params = PageEncodeParams {
+            dpi: global.default_dpi(),
+            ..PageEncodeParams::default()
+        };
         Self {
             pages: Arc::new(PageCollection::new(total_pages)),
             params,
-            dpi: 300,
-            gamma: Some(2.2),
+            dpi: global.default_dpi(),
+            gamma: Some(global.default_gamma()),
             nav: None,
             metadata: HashMap::new(),
         }
@@ -267,9 +534,7 @@ impl DocumentBuilder {
             .map(|idx| pages.metadata_for(idx).and_then(|meta| meta.id.clone()))
             .collect();
 
-        let page_data = pages
-            .take_all()
-            .ok_or_else(|| DjvuError::InvalidOperation("Not all pages ready".to_string()))?;
+        let page_data = pages.take_all()?;
 
         Ok((page_data, identifiers))
     }
@@ -291,3 +556,32 @@ impl DocumentStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pages_over_cap_spill_and_round_trip() {
+        let memory = Arc::new(MemoryTracker::new(Some(10)));
+        let pages = PageCollection::with_memory(3, Arc::clone(&memory), CachePolicy::default());
+
+        pages
+            .insert_page(0, EncodedPage::new(0, vec![1; 8], 1, 1))
+            .unwrap();
+        pages
+            .insert_page(1, EncodedPage::new(1, vec![2; 8], 1, 1))
+            .unwrap();
+        pages
+            .insert_page(2, EncodedPage::new(2, vec![3; 2], 1, 1))
+            .unwrap();
+
+        assert_eq!(pages.spilled_count(), 1);
+        assert_eq!(memory.used(), 10);
+        assert_eq!(pages.get_page(1).unwrap().unwrap().as_slice(), &[2; 8]);
+
+        let all = pages.take_all().unwrap();
+        assert_eq!(all, vec![vec![1; 8], vec![2; 8], vec![3; 2]]);
+        assert_eq!(memory.used(), 0);
+    }
+}
@@ -1,71 +1,303 @@
 //! Page encoding functionality for DjVu documents
 
-use crate::annotations::{Annotations, hidden_text::HiddenText};
+use crate::annotations::{
+    Annotations,
+    hidden_text::{BoundingBox, HiddenText},
+};
+use crate::doc::background::{BackgroundCodec, Iw44Background};
+use crate::doc::checkpoint::Fingerprint;
+use crate::doc::debug_dump::DebugDump;
+use crate::doc::render::draw_blits;
 use crate::encode::{
-    iw44::encoder::{EncoderParams as IW44EncoderParams, IWEncoder},
-    jb2::encoder::JB2Encoder,
-    symbol_dict::BitImage,
+    iw44::encoder::{
+        CrcbMode, EncoderError as IW44EncoderError, EncoderParams as IW44EncoderParams, IWEncoder,
+        QualityMetric, QuantProfile,
+    },
+    iw44::header::Iw44ChunkHeader,
+    jb2::{
+        ClusterParams, DictStats, SingletonParams, drop_singletons, match_symbols,
+        symbol_dict::{BitImage, SharedDict},
+    },
+};
+use crate::iff::{
+    bs_byte_stream::{BzzOptions, bzz_compress_with},
+    chunk_id::ChunkId,
+    chunk_tree::{IffChunk, IffDocument},
+    iff::{IffWriter, IffWriterExt},
 };
-use crate::iff::{bs_byte_stream::bzz_compress, iff::IffWriter};
+use crate::image::fill::HoleFill;
 use crate::image::image_formats::{Bitmap, GrayPixel, Pixel, Pixmap};
+use crate::image::palette::Palette;
+use crate::image::solid::{SolidColorParams, solid_color};
+pub use crate::utils::geom::Rect;
+use crate::utils::log::{debug, target, warn};
 use crate::{DjvuError, Result};
-use byteorder::{BigEndian, WriteBytesExt};
-use log::debug;
+use std::borrow::Cow;
 use std::io::{self, Write};
 use std::sync::Arc;
 
-fn blit_bit_image(dst: &mut BitImage, src: &BitImage, x0: u32, y0: u32) {
-    let x0 = x0 as usize;
-    let y0 = y0 as usize;
-    for y in 0..src.height {
-        let dy = y0 + y;
-        if dy >= dst.height {
-            continue;
-        }
-        for x in 0..src.width {
-            let dx = x0 + x;
-            if dx >= dst.width {
-                continue;
-            }
-            let v = src.get_pixel_unchecked(x, y);
-            dst.set_usize(dx, dy, v);
+/// Chunk IDs accepted by [`PageComponents::with_raw_chunk`], grouped by
+/// the position they are written at, in page order.
+///
+/// This is the order of the spec and of `csepdjvu`: the mask, then the
+/// colors that paint it, then the background, so viewers can show the text
+/// before the picture behind it has arrived.
+const RAW_CHUNK_SLOTS: [&[ChunkId]; 6] = [
+    &[ChunkId::INCL, ChunkId::Djbz],
+    &[ChunkId::Sjbz, ChunkId::Smmr],
+    &[ChunkId::FGbz, ChunkId::FG44, ChunkId::FGjp, ChunkId::FG2k],
+    &[ChunkId::BG44, ChunkId::BGjp, ChunkId::BG2k],
+    &[ChunkId::TXTa, ChunkId::TXTz],
+    &[ChunkId::ANTa, ChunkId::ANTz],
+];
+
+/// How many chunks of a group of [`PAGE_CHUNK_ORDER`] a page may hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkCount {
+    One,
+    /// Any number, all with the same ID: the chunks of one picture.
+    OneCodec,
+    Many,
+}
+
+/// The chunks of a `FORM:DJVU` page in the order `djvudump` lists them for
+/// pages made by DjVuLibre: `INFO` first, the shared dictionary before the
+/// mask that uses it, the colors of the mask before the background.
+const PAGE_CHUNK_ORDER: [(&[ChunkId], ChunkCount); 8] = [
+    (&[ChunkId::INFO], ChunkCount::One),
+    (&[ChunkId::INCL], ChunkCount::Many),
+    (&[ChunkId::Djbz], ChunkCount::One),
+    (&[ChunkId::Sjbz, ChunkId::Smmr], ChunkCount::One),
+    (
+        &[ChunkId::FGbz, ChunkId::FG44, ChunkId::FGjp, ChunkId::FG2k],
+        ChunkCount::OneCodec,
+    ),
+    (
+        &[ChunkId::BG44, ChunkId::BGjp, ChunkId::BG2k],
+        ChunkCount::OneCodec,
+    ),
+    (&[ChunkId::TXTa, ChunkId::TXTz], ChunkCount::One),
+    (&[ChunkId::ANTa, ChunkId::ANTz], ChunkCount::Many),
+];
+
+/// Puts the chunks of a page in [`PAGE_CHUNK_ORDER`], keeping those of a
+/// group in the order they came in, and returns whether any moved.
+///
+/// Fails if a chunk has no place in a page, if `INFO` is missing, or if a
+/// group holds more chunks than the spec allows: two masks, say, or `BG44`
+/// next to `BGjp`.
+pub(crate) fn order_page_chunks(chunks: &mut [IffChunk]) -> Result<bool> {
+    let rank = |id: ChunkId| {
+        PAGE_CHUNK_ORDER
+            .iter()
+            .position(|(ids, _)| ids.contains(&id))
+    };
+    if let Some(chunk) = chunks.iter().find(|c| rank(c.id).is_none()) {
+        return Err(DjvuError::InvalidOperation(format!(
+            "Chunk {} has no place in a page",
+            chunk.id
+        )));
+    }
+    for (ids, count) in PAGE_CHUNK_ORDER {
+        let group: Vec<ChunkId> = chunks
+            .iter()
+            .map(|c| c.id)
+            .filter(|id| ids.contains(id))
+            .collect();
+        let allowed = match count {
+            ChunkCount::One => group.len() <= 1,
+            ChunkCount::OneCodec => group.windows(2).all(|w| w[0] == w[1]),
+            ChunkCount::Many => true,
+        };
+        if !allowed {
+            let found: Vec<String> = group.iter().map(ChunkId::to_string).collect();
+            return Err(DjvuError::InvalidOperation(format!(
+                "Page cannot hold the chunks {}",
+                found.join(", ")
+            )));
         }
     }
+    if !chunks.iter().any(|c| c.id == ChunkId::INFO) {
+        return Err(DjvuError::InvalidOperation(
+            "Page has no INFO chunk".to_string(),
+        ));
+    }
+    if chunks.is_sorted_by_key(|c| rank(c.id)) {
+        return Ok(false);
+    }
+    chunks.sort_by_key(|c| rank(c.id));
+    Ok(true)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Rect {
-    pub x: u32,
-    pub y: u32,
-    pub width: u32,
-    pub height: u32,
+#[derive(Debug, Clone)]
+pub enum PageLayer {
+    IW44Background { image: Pixmap, rect: Rect },
+    JB2Foreground { image: BitImage, rect: Rect },
+    JB2Mask { image: BitImage, rect: Rect },
 }
 
-impl Rect {
-    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
-        Self {
-            x,
-            y,
-            width,
-            height,
+/// How viewers turn the page for display, stored in the INFO chunk's flags.
+///
+/// The page's images are always stored unrotated; this only tells the
+/// viewer which way up to show them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PageRotation {
+    #[default]
+    Upright,
+    /// Shown turned 90° counter-clockwise.
+    Ccw90,
+    /// Shown upside down.
+    Rotate180,
+    /// Shown turned 90° clockwise.
+    Cw90,
+}
+
+impl PageRotation {
+    /// The INFO flags value (1=0°, 6=90°CCW, 2=180°, 5=90°CW).
+    pub fn info_flags(self) -> u8 {
+        match self {
+            Self::Upright => 1,
+            Self::Ccw90 => 6,
+            Self::Rotate180 => 2,
+            Self::Cw90 => 5,
+        }
+    }
+
+    /// Reads the rotation bits of INFO flags; unknown values mean upright,
+    /// as in DjVuLibre.
+    pub fn from_info_flags(flags: u8) -> Self {
+        match flags & 0x07 {
+            6 => Self::Ccw90,
+            2 => Self::Rotate180,
+            5 => Self::Cw90,
+            _ => Self::Upright,
+        }
+    }
+
+    /// The turn, in degrees counter-clockwise.
+    pub fn degrees(self) -> u32 {
+        match self {
+            Self::Upright => 0,
+            Self::Ccw90 => 90,
+            Self::Rotate180 => 180,
+            Self::Cw90 => 270,
+        }
+    }
+
+    /// Size of a `width` x `height` page as it is displayed.
+    pub fn displayed_size(self, width: u32, height: u32) -> (u32, u32) {
+        match self {
+            Self::Ccw90 | Self::Cw90 => (height, width),
+            Self::Upright | Self::Rotate180 => (width, height),
         }
     }
+}
+
+/// The fields of a page's `INFO` chunk, as the encoder writes them and the
+/// document tools read them back.
+///
+/// `INFO` is `width` and `height` (big-endian), the minor and major format
+/// versions, `dpi` (little-endian), gamma times 10 and the flags, whose low
+/// three bits are the rotation. Old files may stop after any field; the
+/// missing ones take DjVuLibre's defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageInfo {
+    pub width: u16,
+    pub height: u16,
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub dpi: u16,
+    /// Gamma times 10, as stored.
+    pub gamma: u8,
+    pub rotation: PageRotation,
+}
 
-    pub fn from_dimensions(width: u32, height: u32) -> Self {
+impl PageInfo {
+    /// Length of a complete `INFO` chunk.
+    pub const LEN: usize = 10;
+    /// Format version written by the encoder, like `c44`.
+    const MINOR_VERSION: u8 = 24;
+    /// Gamma of pages that do not give one.
+    const DEFAULT_GAMMA: u8 = 22;
+    /// Gamma range DjVuLibre accepts; stored values outside it are clamped.
+    const GAMMA_RANGE: (f32, f32) = (0.3, 5.0);
+
+    /// A `width` x `height` page at 300 dpi, gamma 2.2, upright.
+    pub fn new(width: u16, height: u16) -> Self {
         Self {
-            x: 0,
-            y: 0,
             width,
             height,
+            major_version: 0,
+            minor_version: Self::MINOR_VERSION,
+            dpi: 300,
+            gamma: Self::DEFAULT_GAMMA,
+            rotation: PageRotation::Upright,
         }
     }
-}
 
-#[derive(Debug, Clone)]
-pub enum PageLayer {
-    IW44Background { image: Pixmap, rect: Rect },
-    JB2Foreground { image: BitImage, rect: Rect },
-    JB2Mask { image: BitImage, rect: Rect },
+    pub fn with_dpi(mut self, dpi: u16) -> Self {
+        self.dpi = dpi;
+        self
+    }
+
+    /// Sets the gamma, clamped to 0.3-5.0 and rounded to a tenth as stored.
+    pub fn with_gamma(mut self, gamma: f32) -> Self {
+        let (min, max) = Self::GAMMA_RANGE;
+        self.gamma = (gamma.clamp(min, max) * 10.0).round() as u8;
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: PageRotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// The gamma as DjVuLibre reads it: the stored tenths, clamped to
+    /// 0.3-5.0.
+    pub fn gamma(&self) -> f32 {
+        let (min, max) = Self::GAMMA_RANGE;
+        (f32::from(self.gamma) / 10.0).clamp(min, max)
+    }
+
+    /// Reads an `INFO` chunk; `None` if it is too short to hold the page
+    /// size.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let &[wh, wl, hh, hl, ref rest @ ..] = data else {
+            return None;
+        };
+        let byte = |i: usize| rest.get(i).copied();
+        Some(Self {
+            width: u16::from_be_bytes([wh, wl]),
+            height: u16::from_be_bytes([hh, hl]),
+            minor_version: byte(0).unwrap_or(0),
+            major_version: byte(1).unwrap_or(0),
+            dpi: match (byte(2), byte(3)) {
+                (Some(lo), Some(hi)) => u16::from_le_bytes([lo, hi]),
+                _ => 300,
+            },
+            gamma: byte(4).unwrap_or(Self::DEFAULT_GAMMA),
+            rotation: byte(5).map_or(PageRotation::Upright, PageRotation::from_info_flags),
+        })
+    }
+
+    /// The chunk payload.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let [wh, wl] = self.width.to_be_bytes();
+        let [hh, hl] = self.height.to_be_bytes();
+        let [dl, dh] = self.dpi.to_le_bytes();
+        [
+            wh,
+            wl,
+            hh,
+            hl,
+            self.minor_version,
+            self.major_version,
+            dl,
+            dh,
+            self.gamma,
+            self.rotation.info_flags(),
+        ]
+    }
 }
 
 #[derive(Clone)]
@@ -74,6 +306,11 @@ pub struct EncodedPage {
     pub data: Arc<Vec<u8>>,
     pub width: u32,
     pub height: u32,
+    /// Annotations of a page that links to other pages, kept so the links
+    /// can be checked against the directory when the document is assembled.
+    pub annotations: Option<Annotations>,
+    /// How many of the page's JB2 blits used the shared dictionary.
+    pub symbols: DictStats,
 }
 
 impl EncodedPage {
@@ -83,6 +320,8 @@ impl EncodedPage {
             data: Arc::new(data),
             width,
             height,
+            annotations: None,
+            symbols: DictStats::default(),
         }
     }
 
@@ -94,20 +333,40 @@ impl EncodedPage {
         gamma: Option<f32>,
     ) -> Result<Self> {
         let (width, height) = components.dimensions();
+        let dpi = components.dpi.unwrap_or(dpi);
+        let params = match components.dpi {
+            Some(dpi) if dpi != params.dpi => Cow::Owned(PageEncodeParams {
+                dpi,
+                ..params.clone()
+            }),
+            _ => Cow::Borrowed(params),
+        };
         let dpm = (dpi * 100 / 254) as u32;
-        let rotation = if width >= height { 1 } else { 1 };
-        let data = components.encode(params, (page_num + 1) as u32, dpm, rotation, gamma)?;
+        let rotation = components.rotation.info_flags();
+        let annotations = components
+            .annotations
+            .clone()
+            .filter(Annotations::has_page_links);
+        let (data, symbols) =
+            components.encode_with_stats(&params, (page_num + 1) as u32, dpm, rotation, gamma)?;
         Ok(Self {
             page_num,
             data: Arc::new(data),
             width,
             height,
+            annotations,
+            symbols,
         })
     }
 }
 
 /// Configuration for page encoding
-#[derive(Debug, Clone)]
+///
+/// With the `serde` feature these can be loaded from job files (TOML, JSON,
+/// ...); fields left out take their default values.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct PageEncodeParams {
     /// Dots per inch (default: 300)
     pub dpi: u32,
@@ -121,10 +380,21 @@ pub struct PageEncodeParams {
     pub color: bool,
     /// Target SNR in dB for IW44 encoding (overrides bg_quality if set)
     pub decibels: Option<f32>,
+    /// Separate luminance target in dB (overrides `decibels` for Y)
+    pub decibels_y: Option<f32>,
+    /// Chrominance target in dB; see `EncoderParams::decibels_chroma`
+    pub decibels_chroma: Option<f32>,
     /// Maximum slices per chunk (default: 74, like C44)
     pub slices: Option<usize>,
     /// Maximum bytes per chunk (default: None)
     pub bytes: Option<usize>,
+    /// Splits IW44 layers into chunks of at most this many bytes, header
+    /// included, so viewers on slow links can show each refinement as it
+    /// arrives (default: None, chunks split by slice count). `slices` then
+    /// bounds the whole layer rather than each chunk, and `bytes`, which
+    /// only stops a chunk once it has passed it, is ignored; a single slice
+    /// larger than this still makes a chunk of its own
+    pub max_chunk_bytes: Option<usize>,
     /// Fraction of blocks used for quality estimation (default: 0.35)
     pub db_frac: f32,
     /// Lossless encoding mode (default: false)
@@ -133,6 +403,44 @@ pub struct PageEncodeParams {
     /// Lower = more coefficients = better quality but larger files
     /// Higher = fewer coefficients = smaller files but lower quality
     pub quant_multiplier: Option<f32>,
+    /// Dead zones of the IW44 bands (default: none); see [`QuantProfile`]
+    pub quant_profile: QuantProfile,
+    /// What the decibel targets measure (default: PSNR)
+    pub quality_metric: QualityMetric,
+    /// Chrominance mode for the IW44 background; `None` picks `Normal` for
+    /// color pages and `None` for grayscale ones, like C44
+    pub crcb_mode: Option<CrcbMode>,
+    /// Background subsampling factor, 1-12 (default: 1, full resolution)
+    pub bg_subsample: u32,
+    /// How the background pixels hidden by the foreground are filled
+    /// before wavelet coding (default: IW44 masking); see
+    /// [`crate::image::fill`]
+    pub hole_fill: HoleFill,
+    /// Subsampling factor of the FG44 foreground colors, 1-12 (default: 12,
+    /// like `csepdjvu`)
+    pub fg_subsample: u32,
+    /// Cleaning level for automatic JB2 extraction (default: 1; 0 = lossless)
+    pub jb2_loss_level: i32,
+    /// Shapes with fewer black pixels than this are dropped before JB2
+    /// encoding (default: 0, keep everything)
+    pub despeckle: usize,
+    /// Small shapes that match no other shape on the page are dropped as
+    /// noise after despeckling; see [`drop_singletons`] (default: off)
+    ///
+    /// [`drop_singletons`]: crate::encode::jb2::drop_singletons
+    pub singletons: Option<SingletonParams>,
+    /// Backgrounds of one flat color within these tolerances are written as
+    /// a tiny solid `BG44` at 1/12 resolution instead of being wavelet coded
+    /// (default: off)
+    pub solid_background: Option<SolidColorParams>,
+    /// Writes the layers of every page encoded as image files, for
+    /// debugging (default: off); see [`crate::doc::debug_dump`]
+    pub debug_dump: Option<DebugDump>,
+    /// BZZ settings for the `TXTz` and `ANTz` chunks; a [`DjvuBuilder`]
+    /// also uses them for the document's directory and bookmarks
+    ///
+    /// [`DjvuBuilder`]: crate::doc::DjvuBuilder
+    pub bzz: BzzOptions,
 }
 
 impl Default for PageEncodeParams {
@@ -144,12 +452,159 @@ impl Default for PageEncodeParams {
             use_iw44: true, // Default to IW44 for background
             color: true,    // Default to color encoding
             decibels: None,
+            decibels_y: None,
+            decibels_chroma: None,
             slices: Some(74), // C44 default
             bytes: None,
+            max_chunk_bytes: None,
             db_frac: 0.35,
             lossless: false,
             quant_multiplier: None, // Use C++ default
+            quant_profile: QuantProfile::SPEC,
+            quality_metric: QualityMetric::Psnr,
+            crcb_mode: None,
+            bg_subsample: 1,
+            hole_fill: HoleFill::Wavelet,
+            fg_subsample: 12,
+            jb2_loss_level: 1,
+            despeckle: 0,
+            singletons: None,
+            solid_background: None,
+            debug_dump: None,
+            bzz: BzzOptions::default(),
+        }
+    }
+}
+
+impl PageEncodeParams {
+    /// Feeds every setting that changes the encoded page into `f`, for
+    /// telling whether a checkpoint's pages match these settings
+    pub(crate) fn fingerprint(&self, f: &mut Fingerprint) {
+        // Destructured so that a new field is not missed here.
+        let Self {
+            dpi,
+            bg_quality,
+            fg_quality,
+            use_iw44,
+            color,
+            decibels,
+            decibels_y,
+            decibels_chroma,
+            slices,
+            bytes,
+            max_chunk_bytes,
+            db_frac,
+            lossless,
+            quant_multiplier,
+            quant_profile,
+            quality_metric,
+            crcb_mode,
+            bg_subsample,
+            hole_fill,
+            fg_subsample,
+            jb2_loss_level,
+            despeckle,
+            singletons,
+            solid_background,
+            debug_dump: _,
+            bzz,
+        } = self;
+        f.u64(u64::from(*dpi));
+        f.bytes(&[*bg_quality, *fg_quality, *use_iw44 as u8, *color as u8]);
+        for db in [decibels, decibels_y, decibels_chroma, quant_multiplier] {
+            f.opt(*db, Fingerprint::f32);
+        }
+        for n in [slices, bytes, max_chunk_bytes] {
+            f.opt(n.map(|n| n as u64), Fingerprint::u64);
+        }
+        f.f32(*db_frac);
+        f.bytes(&[*lossless as u8, *quality_metric as u8, *hole_fill as u8]);
+        for &zone in &quant_profile.dead_zones {
+            f.f32(zone);
+        }
+        f.opt(crcb_mode.map(|mode| mode as u64), Fingerprint::u64);
+        f.u64(u64::from(*bg_subsample));
+        f.u64(u64::from(*fg_subsample));
+        f.u64(*jb2_loss_level as u64);
+        f.u64(*despeckle as u64);
+        f.opt(*singletons, |f, v| {
+            f.u64(v.max_pixels as u64);
+            f.f32(v.max_error);
+        });
+        f.opt(*solid_background, |f, v| {
+            f.bytes(&[v.tolerance]);
+            f.f32(v.max_outliers);
+        });
+        f.u64(bzz.block_size_k as u64);
+        f.opt(bzz.speed.map(u64::from), Fingerprint::u64);
+    }
+
+    /// Returns the IW44 settings for the background, with the chrominance
+    /// mode resolved from `crcb_mode` and `color`.
+    pub fn iw44_params(&self) -> IW44EncoderParams {
+        let crcb_mode = match self.crcb_mode {
+            Some(mode) if self.color => mode,
+            // C++ c44.exe uses CRCBnormal by default, not CRCBfull
+            None if self.color => CrcbMode::Normal,
+            _ => CrcbMode::None,
+        };
+        IW44EncoderParams {
+            decibels: self.decibels,
+            decibels_y: self.decibels_y,
+            decibels_chroma: self.decibels_chroma,
+            crcb_mode,
+            slices: self.slices,
+            bytes: self.bytes,
+            db_frac: self.db_frac,
+            lossless: self.lossless,
+            quant_multiplier: self.quant_multiplier.unwrap_or(1.0),
+            quant_profile: self.quant_profile,
+            quality_metric: self.quality_metric,
+        }
+    }
+
+    /// Checks that every setting is in range.
+    pub fn validate(&self) -> Result<()> {
+        if self.bg_quality > 100 || self.fg_quality > 100 {
+            return Err(DjvuError::InvalidArg(format!(
+                "Quality {}/{} outside 0..=100",
+                self.bg_quality, self.fg_quality
+            )));
+        }
+        if !(1..=12).contains(&self.bg_subsample) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Background subsampling {} outside 1..=12",
+                self.bg_subsample
+            )));
+        }
+        if !(1..=12).contains(&self.fg_subsample) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Foreground subsampling {} outside 1..=12",
+                self.fg_subsample
+            )));
+        }
+        if let Some(singletons) = &self.singletons
+            && !(0.0..=1.0).contains(&singletons.max_error)
+        {
+            return Err(DjvuError::InvalidArg(format!(
+                "Singleton match error {} outside 0..=1",
+                singletons.max_error
+            )));
         }
+        if self.max_chunk_bytes == Some(0) {
+            return Err(DjvuError::InvalidArg(
+                "max_chunk_bytes must be at least 1".to_string(),
+            ));
+        }
+        if self.jb2_loss_level < 0 {
+            return Err(DjvuError::InvalidArg(format!(
+                "JB2 loss level {} must not be negative",
+                self.jb2_loss_level
+            )));
+        }
+        self.iw44_params()
+            .validate()
+            .map_err(|e| DjvuError::InvalidArg(e.to_string()))
     }
 }
 
@@ -165,10 +620,23 @@ pub struct PageComponents {
     height: u32,
     /// Optional background image data (for IW44)
     pub background: Option<Pixmap>,
+    /// How many times smaller than the page the background is, see
+    /// [`PageComponents::with_background_subsampled`]; 1 for a page-size
+    /// background
+    pub background_subsample: u32,
     /// Optional foreground image data (for JB2)
     pub foreground: Option<BitImage>,
     /// Optional mask data (bitonal)
     pub mask: Option<BitImage>,
+    /// Optional colors of the JB2 layer (FG44), at page size; only the
+    /// pixels under the JB2 shapes matter
+    pub fg_colors: Option<Pixmap>,
+    /// Optional palette of the JB2 layer (FGbz), with one color index per
+    /// blit in its `color_indices`
+    pub fg_palette: Option<Palette>,
+    /// Optional picture the blits of the JB2 layer take their palette
+    /// colors from, when they are not given by index
+    pub fg_palette_image: Option<Pixmap>,
     /// JB2 shape dictionary (bitonal symbol images)
     /// Used for manual JB2 encoding without connected component analysis
     pub jb2_shapes: Option<Vec<BitImage>>,
@@ -183,7 +651,19 @@ pub struct PageComponents {
     /// Optional hyperlink/annotation layer (ANTa/ANTz)
     pub annotations: Option<Annotations>,
     /// Optional shared JB2 dictionary for cross-page symbol sharing
-    pub shared_dict: Option<std::sync::Arc<crate::encode::jb2::symbol_dict::SharedDict>>,
+    pub shared_dict: Option<Arc<SharedDict>>,
+    /// IDs of the include files (`FORM:DJVI`) referenced by `INCL` chunks
+    pub includes: Vec<String>,
+    /// Pre-encoded chunks written verbatim, see [`PageComponents::with_raw_chunk`]
+    pub raw_chunks: Vec<(ChunkId, Vec<u8>)>,
+    /// How the page is turned for display, see [`PageComponents::with_rotation`]
+    pub rotation: PageRotation,
+    /// Resolution of this page in place of the document's, see
+    /// [`PageComponents::with_dpi`]
+    pub dpi: Option<u32>,
+    /// Codec of the background picture; `None` for IW44, see
+    /// [`PageComponents::with_background_codec`]
+    pub background_codec: Option<Arc<dyn BackgroundCodec>>,
 }
 
 impl Default for PageComponents {
@@ -192,8 +672,12 @@ impl Default for PageComponents {
             width: 0,
             height: 0,
             background: None,
+            background_subsample: 1,
             foreground: None,
             mask: None,
+            fg_colors: None,
+            fg_palette: None,
+            fg_palette_image: None,
             text: None,
             layers: Vec::new(),
             text_layer: None,
@@ -201,6 +685,11 @@ impl Default for PageComponents {
             shared_dict: None,
             jb2_shapes: None,
             jb2_blits: None,
+            includes: Vec::new(),
+            raw_chunks: Vec::new(),
+            rotation: PageRotation::Upright,
+            dpi: None,
+            background_codec: None,
         }
     }
 }
@@ -216,8 +705,12 @@ impl PageComponents {
             width,
             height,
             background: None,
+            background_subsample: 1,
             foreground: None,
             mask: None,
+            fg_colors: None,
+            fg_palette: None,
+            fg_palette_image: None,
             text: None,
             layers: Vec::new(),
             text_layer: None,
@@ -225,6 +718,11 @@ impl PageComponents {
             shared_dict: None,
             jb2_shapes: None,
             jb2_blits: None,
+            includes: Vec::new(),
+            raw_chunks: Vec::new(),
+            rotation: PageRotation::Upright,
+            dpi: None,
+            background_codec: None,
         }
     }
 
@@ -233,23 +731,290 @@ impl PageComponents {
     /// When encoding multiple pages with shared symbols (e.g., common fonts),
     /// using a shared dictionary allows referencing previously encoded shapes
     /// instead of re-encoding them, improving compression.
-    pub fn with_shared_dict(
-        mut self,
-        dict: std::sync::Arc<crate::encode::jb2::symbol_dict::SharedDict>,
-    ) -> Self {
+    ///
+    /// Shape indices of [`Self::jb2_blits`] below the dictionary's shape
+    /// count then refer to dictionary shapes, and the rest to
+    /// [`Self::jb2_shapes`]. Extracted shapes that match a dictionary shape
+    /// are blitted from it. The page must include the file holding the
+    /// dictionary's `Djbz` (see [`Self::includes`]).
+    pub fn with_shared_dict(mut self, dict: Arc<SharedDict>) -> Self {
         self.shared_dict = Some(dict);
         self
     }
 
+    /// Encodes the background picture with `codec` instead of IW44, whether
+    /// or not [`PageEncodeParams::use_iw44`] is set.
+    ///
+    /// Flat backgrounds are then coded by `codec` too, whatever
+    /// [`PageEncodeParams::solid_background`] says.
+    pub fn with_background_codec(mut self, codec: Arc<dyn BackgroundCodec>) -> Self {
+        self.background_codec = Some(codec);
+        self
+    }
+
+    /// References the include file with directory ID `id` from this page.
+    ///
+    /// See [`crate::doc::include_file`]; the document must contain a file
+    /// with that ID.
+    pub fn with_include(mut self, id: impl Into<String>) -> Self {
+        self.includes.push(id.into());
+        self
+    }
+
+    /// Attaches an already encoded chunk, written as-is.
+    ///
+    /// Useful for hybrid pipelines that take some layers from another tool,
+    /// e.g. an `Sjbz` from an external JB2 encoder or a prepared `ANTz`. The
+    /// chunk is placed where the spec expects it (`Djbz` before the mask,
+    /// `FGbz` between the mask and the background, and so on), whatever the
+    /// order of the calls. Encoding fails if a raw chunk would duplicate
+    /// one generated from the other components.
+    pub fn with_raw_chunk(mut self, id: ChunkId, data: Vec<u8>) -> Result<Self> {
+        if !RAW_CHUNK_SLOTS.iter().any(|slot| slot.contains(&id)) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Chunk {id} cannot be attached to a page"
+            )));
+        }
+        self.raw_chunks.push((id, data));
+        Ok(self)
+    }
+
+    fn raw_count(&self, ids: &[ChunkId]) -> usize {
+        self.raw_chunks
+            .iter()
+            .filter(|(id, _)| ids.contains(id))
+            .count()
+    }
+
+    /// Rejects raw chunks that clash with each other or with generated ones.
+    fn check_raw_chunks(&self) -> Result<()> {
+        let has_jb2 = self.foreground.is_some() || self.mask.is_some() || self.jb2_shapes.is_some();
+        // (IDs, generated from components, may repeat)
+        let checks: [(&[ChunkId], bool, bool); 6] = [
+            (&[ChunkId::Djbz], false, false),
+            (RAW_CHUNK_SLOTS[1], has_jb2, false),
+            (
+                RAW_CHUNK_SLOTS[2],
+                self.fg_colors.is_some() || self.fg_palette.is_some(),
+                false,
+            ),
+            (RAW_CHUNK_SLOTS[3], self.background.is_some(), true),
+            (RAW_CHUNK_SLOTS[4], self.text_layer.is_some(), false),
+            (RAW_CHUNK_SLOTS[5], self.annotations.is_some(), false),
+        ];
+        for (ids, generated, repeatable) in checks {
+            let count = self.raw_count(ids);
+            if (count > 0 && generated) || (count > 1 && !repeatable) {
+                return Err(DjvuError::InvalidOperation(format!(
+                    "Raw {} chunk conflicts with another chunk of the page",
+                    ids[0]
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists everything that would make this page fail to encode or
+    /// encode into something other than what was asked for.
+    ///
+    /// Checks the page size, empty or oversized layers, JB2 shape references,
+    /// a mask with nothing to apply to, raw chunk conflicts, and that
+    /// `dpi` is within the 10–6000 range the format allows.
+    pub fn problems(&self, dpi: u32) -> Vec<String> {
+        let mut problems = Vec::new();
+        let (w, h) = (self.width, self.height);
+        if w == 0 || h == 0 {
+            problems.push(format!("page size {w}x{h} is empty"));
+        }
+        if w > u16::MAX as u32 || h > u16::MAX as u32 {
+            problems.push(format!("page size {w}x{h} exceeds 65535x65535"));
+        }
+        if !(10..=6000).contains(&dpi) {
+            problems.push(format!("resolution {dpi} dpi is outside 10..=6000"));
+        }
+
+        for layer in &self.layers {
+            let (name, rect, size) = match layer {
+                PageLayer::IW44Background { image, rect } => {
+                    ("background", rect, (image.width(), image.height()))
+                }
+                PageLayer::JB2Foreground { image, rect } => (
+                    "foreground",
+                    rect,
+                    (image.width as u32, image.height as u32),
+                ),
+                PageLayer::JB2Mask { image, rect } => {
+                    ("mask", rect, (image.width as u32, image.height as u32))
+                }
+            };
+            if size.0 == 0 || size.1 == 0 {
+                problems.push(format!("{name} layer at ({}, {}) is empty", rect.x, rect.y));
+            }
+            if rect.x.saturating_add(rect.width) > w || rect.y.saturating_add(rect.height) > h {
+                problems.push(format!(
+                    "{name} layer at ({}, {}) of {}x{} extends past the page",
+                    rect.x, rect.y, rect.width, rect.height
+                ));
+            }
+        }
+
+        if self.fg_colors.is_some() && self.fg_palette.is_some() {
+            problems.push("foreground has both FG44 colors and an FGbz palette".to_string());
+        }
+        if (self.fg_colors.is_some() || self.fg_palette.is_some())
+            && self.foreground.is_none()
+            && self.mask.is_none()
+            && self.jb2_shapes.is_none()
+        {
+            problems.push("foreground colors have no JB2 layer to paint".to_string());
+        }
+        // Beside a foreground the mask still marks the background pixels
+        // the foreground hides; it is only unused without a background.
+        if self.mask.is_some()
+            && (self.foreground.is_some() || self.jb2_shapes.is_some())
+            && self.background.is_none()
+        {
+            problems.push(
+                "mask is unused: the foreground provides the JB2 layer and there is no background"
+                    .to_string(),
+            );
+        }
+        match (&self.jb2_shapes, &self.jb2_blits) {
+            (Some(shapes), Some(blits)) => {
+                if let Some(&(_, _, i)) = blits.iter().find(|b| b.2 >= shapes.len()) {
+                    problems.push(format!("JB2 blit refers to shape {i} of {}", shapes.len()));
+                }
+                if shapes.iter().any(|s| s.width == 0 || s.height == 0) {
+                    problems.push("JB2 shape dictionary holds an empty shape".to_string());
+                }
+                // Blits are in JB2's bottom-up coordinates, which does not
+                // matter for containment. Viewers clip the part of a blit
+                // past the page; one wholly off it shows nothing.
+                let outside = blits.iter().find(|&&(left, bottom, i)| {
+                    shapes.get(i).is_some_and(|s| {
+                        let (Ok(x), Ok(y)) = (u32::try_from(left), u32::try_from(bottom)) else {
+                            return true;
+                        };
+                        let visible =
+                            Rect::new(x, y, s.width as u32, s.height as u32).clipped_to(w, h);
+                        visible.width == 0 || visible.height == 0
+                    })
+                });
+                if let Some(&(left, bottom, i)) = outside {
+                    problems.push(format!(
+                        "JB2 blit of shape {i} at ({left}, {bottom}) is not on the page"
+                    ));
+                }
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                problems.push("JB2 shapes and blits must be given together".to_string())
+            }
+            (None, None) => {}
+        }
+        if let Err(e) = self.check_raw_chunks() {
+            problems.push(e.to_string());
+        }
+        problems
+    }
+
+    /// Checks the page with [`Self::problems`], reporting all of them in
+    /// one error.
+    pub fn validate(&self, dpi: u32) -> Result<()> {
+        let problems = self.problems(dpi);
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(DjvuError::ValidationError(format!(
+            "page has {} problem(s): {}",
+            problems.len(),
+            problems.join("; ")
+        )))
+    }
+
+    fn write_raw_chunks(&self, writer: &mut IffWriter, ids: &[ChunkId]) -> Result<()> {
+        for (id, data) in self.raw_chunks.iter().filter(|(id, _)| ids.contains(id)) {
+            writer.write_chunk(*id, data)?;
+        }
+        Ok(())
+    }
+
+    /// The distinct shapes the page's JB2 layer is made of: the manual
+    /// [`Self::jb2_shapes`], or those found in the foreground or mask with
+    /// the cleaning settings of `params`.
+    ///
+    /// Clustered across the pages of a document with
+    /// [`cluster_symbols`](crate::encode::jb2::cluster_symbols), they give
+    /// a dictionary to seed the next document with.
+    pub fn jb2_symbols(&self, params: &PageEncodeParams) -> Vec<BitImage> {
+        if let Some(shapes) = &self.jb2_shapes {
+            return shapes.clone();
+        }
+        match self.foreground.as_ref().or(self.mask.as_ref()) {
+            Some(image) => {
+                let shapes = extract_page_shapes(image, params);
+                crate::encode::jb2::shapes_to_encoder_format(shapes, self.height as i32).0
+            }
+            None => Vec::new(),
+        }
+    }
+
     /// Returns the dimensions of the page.
     pub fn dimensions(&self) -> (u32, u32) {
         (self.width, self.height)
     }
 
+    /// The palette index of each of `blits` (JB2 coordinates), from the
+    /// average color of `fg_palette_image` under the shape's black pixels.
+    /// Shape indices count the `inherited` dictionary shapes first.
+    fn blit_palette_indices(
+        &self,
+        inherited: &[BitImage],
+        shapes: &[BitImage],
+        blits: &[(i32, i32, usize)],
+    ) -> Option<Vec<u16>> {
+        let (palette, image) = (self.fg_palette.as_ref()?, self.fg_palette_image.as_ref()?);
+        let (w, h) = image.dimensions();
+        let indices = blits
+            .iter()
+            .map(|&(left, bottom, index)| {
+                let shape = match index.checked_sub(inherited.len()) {
+                    Some(local) => shapes.get(local),
+                    None => inherited.get(index),
+                };
+                let Some(shape) = shape else {
+                    return 0;
+                };
+                let top = h as i32 - bottom - shape.height as i32;
+                let (mut sum, mut count) = ([0u64; 3], 0u64);
+                for y in 0..shape.height {
+                    for (start, end) in shape.row_runs(y) {
+                        for x in start..=end {
+                            let (px, py) = (left + x as i32, top + y as i32);
+                            if px < 0 || py < 0 || px >= w as i32 || py >= h as i32 {
+                                continue;
+                            }
+                            let pixel = image.get_pixel(px as u32, py as u32);
+                            sum[0] += pixel.r as u64;
+                            sum[1] += pixel.g as u64;
+                            sum[2] += pixel.b as u64;
+                            count += 1;
+                        }
+                    }
+                }
+                if count == 0 {
+                    return 0;
+                }
+                let [r, g, b] = sum.map(|c| (c / count) as u8);
+                palette.color_to_index(&Pixel::new(r, g, b))
+            })
+            .collect();
+        Some(indices)
+    }
+
     /// Checks and sets the page dimensions if they are not already set.
     /// Returns an error if the new dimensions conflict with existing ones.
     fn check_and_set_dimensions(&mut self, new_dims: (u32, u32)) -> Result<()> {
-        if self.width == 0 && self.height == 0 {
+        if self.width == 0 && self.height == 0 || self.sized_by_background(new_dims) {
             self.width = new_dims.0;
             self.height = new_dims.1;
         } else if self.width != new_dims.0 || self.height != new_dims.1 {
@@ -261,7 +1026,32 @@ impl PageComponents {
         Ok(())
     }
 
+    /// Whether the page only got its size from a subsampled background that
+    /// a page of `dims` reduces to as well.
+    ///
+    /// A reduced background only gives the page size to a multiple of the
+    /// factor, so the full-resolution layers added after it settle it.
+    fn sized_by_background(&self, dims: (u32, u32)) -> bool {
+        let k = self.background_subsample;
+        k > 1
+            && self.foreground.is_none()
+            && self.mask.is_none()
+            && self.fg_colors.is_none()
+            && self.fg_palette_image.is_none()
+            && self.layers.is_empty()
+            && self
+                .background
+                .as_ref()
+                .is_some_and(|bg| bg.dimensions() == (dims.0.div_ceil(k), dims.1.div_ceil(k)))
+    }
+
     pub fn add_iw44_background(mut self, image: Pixmap, rect: Rect) -> Result<Self> {
+        if self.background_subsample > 1 {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Page already has a background subsampled by {}",
+                self.background_subsample
+            )));
+        }
         let new_dims = (rect.x + rect.width, rect.y + rect.height);
         self.check_and_set_dimensions(new_dims)?;
         if image.width() != rect.width || image.height() != rect.height {
@@ -305,7 +1095,7 @@ impl PageComponents {
                 DjvuError::InvalidOperation(format!("Failed to allocate foreground bitmap: {e}"))
             })?,
         };
-        blit_bit_image(&mut dest, &image, rect.x, rect.y);
+        dest.blit(&image, rect.x as usize, rect.y as usize);
         self.foreground = Some(dest);
         self.layers.push(PageLayer::JB2Foreground { image, rect });
         Ok(self)
@@ -326,7 +1116,7 @@ impl PageComponents {
                 DjvuError::InvalidOperation(format!("Failed to allocate mask bitmap: {e}"))
             })?,
         };
-        blit_bit_image(&mut dest, &image, rect.x, rect.y);
+        dest.blit(&image, rect.x as usize, rect.y as usize);
         self.mask = Some(dest);
         self.layers.push(PageLayer::JB2Mask { image, rect });
         Ok(self)
@@ -338,18 +1128,134 @@ impl PageComponents {
         self.add_iw44_background(image, rect)
     }
 
+    /// Adds a background that is already `subsample` times smaller than the
+    /// page, the way a scan is split for DjVu: the mask at the full 300 or
+    /// 600 dpi of the scan, the background at 100 dpi or less.
+    ///
+    /// A page of `w`x`h` pixels takes a background of
+    /// `ceil(w / subsample)`x`ceil(h / subsample)`, the size DjVu decoders
+    /// expect from `BG44`. Unless the page was sized by
+    /// [`Self::new_with_dimensions`] or another layer, it is `subsample`
+    /// times the background. [`PageEncodeParams::bg_subsample`] reduces the
+    /// background further, and the two factors together must not exceed 12;
+    /// this is checked when the page is encoded.
+    pub fn with_background_subsampled(mut self, image: Pixmap, subsample: u32) -> Result<Self> {
+        if !(1..=MAX_BG_SUBSAMPLE).contains(&subsample) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Background subsampling {subsample} outside 1..={MAX_BG_SUBSAMPLE}"
+            )));
+        }
+        if subsample == 1 {
+            return self.with_background(image);
+        }
+        if self.background.is_some() {
+            return Err(DjvuError::InvalidOperation(
+                "Page already has a background".to_string(),
+            ));
+        }
+        let (w, h) = image.dimensions();
+        if self.width == 0 && self.height == 0 {
+            (self.width, self.height) = (w * subsample, h * subsample);
+        } else if (w, h)
+            != (
+                self.width.div_ceil(subsample),
+                self.height.div_ceil(subsample),
+            )
+        {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Background of {w}x{h} does not fit a {}x{} page subsampled by {subsample}",
+                self.width, self.height
+            )));
+        }
+        self.background = Some(image);
+        self.background_subsample = subsample;
+        Ok(self)
+    }
+
     /// Adds a foreground image to the page.
+    ///
+    /// Like [`Self::with_mask`], this is the JB2 layer and is written as
+    /// `Sjbz`; when both are given the foreground is encoded and the mask
+    /// only keeps the background from spending bits under it. Color it with
+    /// [`Self::with_foreground_palette`] or [`Self::with_foreground_colors`].
     pub fn with_foreground(self, image: BitImage) -> Result<Self> {
         let rect = Rect::from_dimensions(image.width as u32, image.height as u32);
         self.add_jb2_foreground(image, rect)
     }
 
-    /// Adds a mask to the page.
+    /// Adds a mask to the page, written as the `Sjbz` JB2 layer.
+    ///
+    /// The background is encoded without regard to the pixels it covers.
     pub fn with_mask(self, image: BitImage) -> Result<Self> {
         let rect = Rect::from_dimensions(image.width as u32, image.height as u32);
         self.add_jb2_mask(image, rect)
     }
 
+    /// Colors the JB2 layer with a picture, written as `FG44`.
+    ///
+    /// Each black pixel of the foreground (or mask) takes the color of
+    /// `image` at the same spot. The picture is stored subsampled by
+    /// [`PageEncodeParams::fg_subsample`], so it only needs to be right
+    /// around the shapes. Without it the JB2 layer is drawn in black.
+    pub fn with_foreground_colors(mut self, image: Pixmap) -> Result<Self> {
+        self.check_and_set_dimensions(image.dimensions())?;
+        self.fg_colors = Some(image);
+        Ok(self)
+    }
+
+    /// Colors the JB2 layer from a palette, written as `FGbz`.
+    ///
+    /// `index_map` gives the palette index of each JB2 blit, in blit order,
+    /// and must match the number of blits when the page is encoded; it is
+    /// only predictable for [`Self::with_jb2_manual`] pages. With an empty
+    /// map every shape takes the first color.
+    pub fn with_foreground_palette(
+        mut self,
+        mut palette: Palette,
+        index_map: Vec<u16>,
+    ) -> Result<Self> {
+        if palette.len() == 0 {
+            return Err(DjvuError::InvalidArg(
+                "Foreground palette has no colors".to_string(),
+            ));
+        }
+        if let Some(&i) = index_map.iter().find(|&&i| i as usize >= palette.len()) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Color index {i} outside a palette of {}",
+                palette.len()
+            )));
+        }
+        palette.set_color_indices(index_map);
+        self.fg_palette = Some(palette);
+        self.fg_palette_image = None;
+        Ok(self)
+    }
+
+    /// Colors the JB2 layer from a palette, written as `FGbz`, giving each
+    /// blit the palette color closest to the average of `image` under its
+    /// black pixels.
+    ///
+    /// The indices are picked when the page is encoded, in the final blit
+    /// order, so unlike [`Self::with_foreground_palette`] this works for
+    /// extracted JB2 layers too: a red stamp over black text comes out red
+    /// wherever its shapes end up in the stream.
+    pub fn with_foreground_palette_image(
+        mut self,
+        mut palette: Palette,
+        image: Pixmap,
+    ) -> Result<Self> {
+        if palette.len() == 0 {
+            return Err(DjvuError::InvalidArg(
+                "Foreground palette has no colors".to_string(),
+            ));
+        }
+        self.check_and_set_dimensions(image.dimensions())?;
+        palette.set_color_indices(Vec::new());
+        self.fg_palette = Some(palette);
+        self.fg_palette_image = Some(image);
+        Ok(self)
+    }
+
     /// Adds text/annotations to the page.
     pub fn with_text(mut self, text: String) -> Self {
         self.text = Some(text);
@@ -362,6 +1268,27 @@ impl PageComponents {
         self
     }
 
+    /// Sets how viewers turn the page for display.
+    ///
+    /// The images stay as given; the hidden text layer is taken to be in
+    /// the coordinates of the displayed page, as OCR of the upright page
+    /// produces them, and is mapped back onto the stored images when
+    /// encoded.
+    pub fn with_rotation(mut self, rotation: PageRotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Sets the resolution of this page, for a page scanned or rendered at
+    /// another resolution than the rest of the document.
+    ///
+    /// It is written to the page's INFO chunk and used for segmentation and
+    /// validation in place of the document's.
+    pub fn with_dpi(mut self, dpi: u32) -> Self {
+        self.dpi = Some(dpi);
+        self
+    }
+
     /// Adds JB2 data manually (shapes and blit positions).
     ///
     /// This allows encoding JB2 without connected component analysis.
@@ -428,25 +1355,150 @@ impl PageComponents {
         self
     }
 
-    /// Encodes the page to a byte vector using the given parameters
-    pub fn encode(
-        &self,
-        params: &PageEncodeParams,
-        page_num: u32,
-        dpm: u32,
-        rotation: u8,       // 1=0°, 6=90°CCW, 2=180°, 5=90°CW
-        gamma: Option<f32>, // If None, use 2.2
-    ) -> Result<Vec<u8>> {
-        let mut output = Vec::new();
-        {
-            let mut cursor = io::Cursor::new(&mut output);
-            let mut writer = IffWriter::new(&mut cursor);
+    /// Blanks out `rect` (top-left origin) before the page is encoded.
+    ///
+    /// JB2 pixels inside it are cleared and background pixels painted
+    /// white; manually placed JB2 shapes (see [`Self::with_jb2_manual`]) and
+    /// hidden text zones or hyperlinks touching it are removed whole, so no
+    /// part of them survives. Only the part of `rect` on the page counts.
+    pub fn redact(&mut self, rect: Rect) {
+        fn clear(image: &mut BitImage, rect: Rect, left: u32, top: u32) {
+            for y in rect.y.max(top)..(rect.y + rect.height).min(top + image.height as u32) {
+                for x in rect.x.max(left)..(rect.x + rect.width).min(left + image.width as u32) {
+                    image.set_usize((x - left) as usize, (y - top) as usize, false);
+                }
+            }
+        }
+        fn paint(image: &mut Pixmap, rect: Rect, left: u32, top: u32) {
+            for y in rect.y.max(top)..(rect.y + rect.height).min(top + image.height()) {
+                for x in rect.x.max(left)..(rect.x + rect.width).min(left + image.width()) {
+                    image.put_pixel(x - left, y - top, Pixel::white());
+                }
+            }
+        }
 
-            // Write AT&T magic bytes first
-            writer.write_magic_bytes()?;
+        let rect = rect.clipped_to(self.width, self.height);
+        if let Some(background) = &mut self.background {
+            // Backgrounds may be subsampled; scale the rectangle to them.
+            let scale = self.width.div_ceil(background.width().max(1)).max(1);
+            let scaled = Rect::new(
+                rect.x / scale,
+                rect.y / scale,
+                (rect.x + rect.width).div_ceil(scale) - rect.x / scale,
+                (rect.y + rect.height).div_ceil(scale) - rect.y / scale,
+            );
+            paint(background, scaled, 0, 0);
+        }
+        for image in [&mut self.foreground, &mut self.mask].into_iter().flatten() {
+            clear(image, rect, 0, 0);
+        }
+        for layer in &mut self.layers {
+            match layer {
+                PageLayer::IW44Background { image, rect: at } => paint(image, rect, at.x, at.y),
+                PageLayer::JB2Foreground { image, rect: at }
+                | PageLayer::JB2Mask { image, rect: at } => clear(image, rect, at.x, at.y),
+            }
+        }
 
-            // Start the FORM:DJVU chunk
-            writer.put_chunk("FORM:DJVU")?;
+        let height = self.height as i64;
+        let area = BoundingBox {
+            x: rect.x.min(u16::MAX as u32) as u16,
+            y: (height - (rect.y + rect.height) as i64).clamp(0, u16::MAX as i64) as u16,
+            w: rect.width.min(u16::MAX as u32) as u16,
+            h: rect.height.min(u16::MAX as u32) as u16,
+        };
+        if let (Some(shapes), Some(blits)) = (&self.jb2_shapes, &mut self.jb2_blits) {
+            blits.retain(|&(left, bottom, shape)| {
+                let Some(shape) = shapes.get(shape) else {
+                    return true;
+                };
+                let (left, right) = (left as i64, left as i64 + shape.width as i64);
+                let (bottom, top) = (bottom as i64, bottom as i64 + shape.height as i64);
+                let area_left = area.x as i64;
+                let area_bottom = area.y as i64;
+                !(left < area_left + area.w as i64
+                    && area_left < right
+                    && bottom < area_bottom + area.h as i64
+                    && area_bottom < top)
+            });
+        }
+        if let Some(text) = &mut self.text_layer {
+            text.redact(&area);
+        }
+        if let Some(annotations) = &mut self.annotations {
+            annotations.redact(&area);
+        }
+    }
+
+    /// How the background is reduced: the subsampling to apply to the
+    /// background picture, and its total reduction from the page, which is
+    /// also that of the mask hiding it.
+    ///
+    /// Checks that a background given with
+    /// [`Self::with_background_subsampled`] still fits the page, and that
+    /// with [`PageEncodeParams::bg_subsample`] it stays within what IW44
+    /// decoders take.
+    pub(crate) fn background_scale(&self, params: &PageEncodeParams) -> Result<(u32, u32)> {
+        let (reduced, subsample) = (self.background_subsample.max(1), params.bg_subsample);
+        if reduced == 1 {
+            return Ok((subsample, subsample));
+        }
+        let total = reduced * subsample;
+        if total > MAX_BG_SUBSAMPLE {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Background subsampled by {reduced} and then by {subsample} is \
+                 reduced {total} times, above {MAX_BG_SUBSAMPLE}"
+            )));
+        }
+        let page = (self.width.div_ceil(reduced), self.height.div_ceil(reduced));
+        if let Some(background) = &self.background
+            && background.dimensions() != page
+        {
+            let (w, h) = background.dimensions();
+            return Err(DjvuError::InvalidOperation(format!(
+                "Background of {w}x{h} does not fit a {}x{} page subsampled by {reduced}",
+                self.width, self.height
+            )));
+        }
+        Ok((subsample, total))
+    }
+
+    /// Encodes the page to a byte vector using the given parameters
+    pub fn encode(
+        &self,
+        params: &PageEncodeParams,
+        page_num: u32,
+        dpm: u32,
+        rotation: u8,       // 1=0°, 6=90°CCW, 2=180°, 5=90°CW
+        gamma: Option<f32>, // If None, use 2.2
+    ) -> Result<Vec<u8>> {
+        self.encode_with_stats(params, page_num, dpm, rotation, gamma)
+            .map(|(data, _)| data)
+    }
+
+    /// Like [`Self::encode`], also telling how many JB2 blits used the
+    /// shared dictionary.
+    pub(crate) fn encode_with_stats(
+        &self,
+        params: &PageEncodeParams,
+        page_num: u32,
+        dpm: u32,
+        rotation: u8,
+        gamma: Option<f32>,
+    ) -> Result<(Vec<u8>, DictStats)> {
+        params.validate()?;
+        self.check_raw_chunks()?;
+        let dump = params.debug_dump.as_ref();
+        let mut output = Vec::new();
+        let symbols = {
+            let mut cursor = io::Cursor::new(&mut output);
+            let mut writer = IffWriter::new(&mut cursor);
+
+            // Write AT&T magic bytes first
+            writer.write_magic_bytes()?;
+
+            // Start the FORM:DJVU chunk
+            writer.put_composite(ChunkId::FORM, ChunkId::DJVU)?;
 
             // Write INFO chunk (required for all pages)
             self.write_info_chunk(
@@ -458,190 +1510,232 @@ impl PageComponents {
                 gamma,
             )?;
 
-            // --- BG44: Always emit a blank background for bitonal/JB2 pages ---
-            let mut wrote_bg44 = false;
-            if let Some(bg_img) = &self.background {
-                if params.use_iw44 {
-                    self.encode_iw44_background(bg_img, &mut writer, params)?;
-                    wrote_bg44 = true;
-                } else {
-                    return Err(DjvuError::InvalidOperation(
-                        "JB2 background encoding requires a bitonal image. Use foreground instead."
-                            .to_string(),
-                    ));
-                }
-            }
-            // If no background but JB2 content exists, emit an all-white BG44
-            if !wrote_bg44
-                && (self.foreground.is_some() || self.mask.is_some() || self.jb2_shapes.is_some())
-            {
-                let (w, h) = (self.width, self.height);
-                let white_bg = Pixmap::from_pixel(w, h, Pixel::white());
-                self.encode_iw44_background(&white_bg, &mut writer, params)?;
+            // --- INCL: references to shared include files ---
+            for id in &self.includes {
+                writer.put_chunk(ChunkId::INCL)?;
+                writer.write_all(id.as_bytes())?;
+                writer.close_chunk()?;
             }
+            self.write_raw_chunks(&mut writer, RAW_CHUNK_SLOTS[0])?;
 
             // --- Djbz + Sjbz: JB2 encoding ---
             let mut num_blits = 0;
             let mut encoded_sjbz: Option<Vec<u8>> = None;
+            // Palette indices picked from `fg_palette_image`, in blit order.
+            let mut blit_colors: Option<Vec<u16>> = None;
+            let mut symbols = DictStats::default();
+
+            // Shapes of the shared dictionary come first in the library.
+            let dict = self.shared_dict.as_deref();
+            if dict.is_some() && self.includes.is_empty() && self.raw_count(RAW_CHUNK_SLOTS[0]) == 0
+            {
+                return Err(DjvuError::InvalidOperation(
+                    "Shared dictionary needs an include file holding its Djbz".to_string(),
+                ));
+            }
+            let inherited = dict.map_or(&[][..], SharedDict::shapes);
 
             // JB2 can come from three sources (in priority order):
             // 1. Manual jb2_shapes/jb2_blits (always available, no feature required)
             // 2. Auto-extracted from foreground (requires symboldict feature)
             // 3. Auto-extracted from mask (requires symboldict feature)
+            let source = self.foreground.as_ref().or(self.mask.as_ref());
+            let jb2_layer = if let (Some(shapes), Some(blits)) = (&self.jb2_shapes, &self.jb2_blits)
+            {
+                blit_colors = self.blit_palette_indices(inherited, shapes, blits);
+                let parents: Vec<i32> = vec![-1; shapes.len()];
+                symbols.symbols = blits.len();
+                symbols.hits = blits.iter().filter(|b| b.2 < inherited.len()).count();
+                Some((shapes.clone(), parents, blits.clone()))
+            } else if let Some(image) = source {
+                // Run connected component analysis
+                let shapes = extract_page_shapes(image, params);
+                let (dictionary, parents, mut blits) =
+                    crate::encode::jb2::shapes_to_encoder_format(shapes, self.height as i32);
+                blit_colors = self.blit_palette_indices(&[], &dictionary, &blits);
+                match dict {
+                    Some(dict) => {
+                        // Lossless pages only take exact matches.
+                        let max_error = if params.jb2_loss_level == 0 {
+                            0.0
+                        } else {
+                            ClusterParams::default().max_error
+                        };
+                        let (local, stats) =
+                            inherit_shapes(dict, dictionary, &mut blits, max_error);
+                        symbols = stats;
+                        let parents = vec![-1; local.len()];
+                        Some((local, parents, blits))
+                    }
+                    None => Some((dictionary, parents, blits)),
+                }
+            } else {
+                None
+            };
+
+            if let Some((shapes, parents, blits)) = jb2_layer {
+                use crate::encode::jb2::encoder::JB2Encoder;
+                num_blits = blits.len();
+                if let Some(dump) = dump {
+                    let library: Vec<&BitImage> = inherited.iter().chain(&shapes).collect();
+                    let layer = draw_blits(self.width, self.height, &library, &blits)?;
+                    dump.write_mask(page_num, layer)?;
+                }
+                let mut page_encoder = JB2Encoder::new(Vec::new());
+                let sjbz_raw = page_encoder.encode_page_with_shapes(
+                    self.width,
+                    self.height,
+                    &shapes,
+                    &parents,
+                    &blits,
+                    inherited.len(),
+                    dict.map(SharedDict::shapes),
+                )?;
+                encoded_sjbz = Some(sjbz_raw);
+            }
 
-            let _jb2_encoded =
-                if let (Some(shapes), Some(blits)) = (&self.jb2_shapes, &self.jb2_blits) {
-                    num_blits = blits.len();
-                    // Manual JB2 encoding (no feature required)
-                    use crate::encode::jb2::encoder::JB2Encoder;
-                    let parents: Vec<i32> = vec![-1; shapes.len()];
-
-                    // --- Sjbz ---
-                    let mut page_encoder = JB2Encoder::new(Vec::new());
-                    let sjbz_raw = page_encoder
-                        .encode_page_with_shapes(
-                            self.width,
-                            self.height,
-                            shapes,
-                            &parents,
-                            blits,
-                            0,
-                            None,
-                        )
-                        .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
-
-                    encoded_sjbz = Some(sjbz_raw);
-                    true
-                } else {
-                    false
+            // --- Sjbz: the mask, written first so the text shows early ---
+            if let Some(sjbz_data) = &encoded_sjbz {
+                // Write raw JB2 stream (already ZP-compressed, no BZZ needed)
+                writer.put_chunk(ChunkId::Sjbz)?;
+                writer.write_all(sjbz_data)?;
+                writer.close_chunk()?;
+            }
+            self.write_raw_chunks(&mut writer, RAW_CHUNK_SLOTS[1])?;
+
+            // --- FGbz/FG44: colors of the mask ---
+            // A plain black JB2 layer needs no FGbz or FG44: black is what
+            // decoders use when both are missing.
+            self.write_raw_chunks(&mut writer, RAW_CHUNK_SLOTS[2])?;
+            if let Some(palette) = &self.fg_palette {
+                if encoded_sjbz.is_none() {
+                    return Err(DjvuError::InvalidOperation(
+                        "Foreground palette needs a JB2 layer to paint".to_string(),
+                    ));
+                }
+                let indices = palette.color_indices.len();
+                if indices > 0 && indices != num_blits {
+                    return Err(DjvuError::InvalidOperation(format!(
+                        "Foreground palette maps {indices} blits but the JB2 layer has {num_blits}"
+                    )));
+                }
+                writer.put_chunk(ChunkId::FGbz)?;
+                match blit_colors {
+                    Some(indices) => {
+                        let mut palette = palette.clone();
+                        palette.set_color_indices(indices);
+                        palette.encode(&mut writer)?;
+                    }
+                    None => palette.encode(&mut writer)?,
+                }
+                writer.close_chunk()?;
+            }
+            if let Some(fg_img) = &self.fg_colors {
+                if self.fg_palette.is_some() {
+                    return Err(DjvuError::InvalidOperation(
+                        "Foreground colors and palette cannot both be used".to_string(),
+                    ));
+                }
+                let Some(shapes) = self.foreground.as_ref().or(self.mask.as_ref()) else {
+                    return Err(DjvuError::InvalidOperation(
+                        "Foreground colors need a foreground or mask to paint".to_string(),
+                    ));
                 };
-
-            // Auto-extraction fallback (only if manual JB2 wasn't used)
-            if !_jb2_encoded {
-                if let Some(fg_img) = &self.foreground {
-                    // Auto-extract from foreground (requires symboldict feature)
-                    use crate::encode::jb2::{
-                        analyze_page, encoder::JB2Encoder, shapes_to_encoder_format,
-                    };
-
-                    let mut page_encoder = JB2Encoder::new(Vec::new());
-
-                    // Run connected component analysis
-                    let dpi = 300;
-                    let losslevel = 1;
-                    let cc_image = analyze_page(fg_img, dpi, losslevel);
-                    let shapes = cc_image.extract_shapes();
-                    let (dictionary, parents, blits) =
-                        shapes_to_encoder_format(shapes, self.height as i32);
-                    num_blits = blits.len();
-
-                    // --- Sjbz ---
-                    let sjbz_raw = page_encoder
-                        .encode_page_with_shapes(
-                            self.width,
-                            self.height,
-                            &dictionary,
-                            &parents,
-                            &blits,
-                            0,
-                            None,
-                        )
-                        .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
-
-                    encoded_sjbz = Some(sjbz_raw);
-                } else if let Some(mask_img) = &self.mask {
-                    // Auto-extract from mask (requires symboldict feature)
-                    use crate::encode::jb2::{
-                        analyze_page, encoder::JB2Encoder, shapes_to_encoder_format,
-                    };
-
-                    let mut page_encoder = JB2Encoder::new(Vec::new());
-
-                    // Run connected component analysis
-                    let dpi = 300;
-                    let losslevel = 1;
-                    let cc_image = analyze_page(mask_img, dpi, losslevel);
-                    let shapes = cc_image.extract_shapes();
-                    let (dictionary, parents, blits) =
-                        shapes_to_encoder_format(shapes, self.height as i32);
-                    num_blits = blits.len();
-
-                    // --- Sjbz ---
-                    let sjbz_raw = page_encoder
-                        .encode_page_with_shapes(
-                            self.width,
-                            self.height,
-                            &dictionary,
-                            &parents,
-                            &blits,
-                            0,
-                            None,
-                        )
-                        .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
-
-                    encoded_sjbz = Some(sjbz_raw);
+                // Only the pixels under the shapes are visible.
+                let mask = layer_mask(shapes, params.fg_subsample, false);
+                let subsample = params.fg_subsample;
+                if let Some(dump) = dump {
+                    let image = fg_img.subsample(subsample);
+                    dump.write_picture(page_num, "foreground", image, Some(&mask), params)?;
                 }
+                encode_iw44_layer(
+                    fg_img,
+                    Some(&mask),
+                    ChunkId::FG44,
+                    subsample,
+                    &mut writer,
+                    params,
+                )?;
             }
 
-            // --- FGbz: Foreground colors for compound images ---
-            // Must be written BEFORE Sjbz to inform viewer of colors?
-            // Spec says no strict order, but standard is BG44 -> FGbz -> Sjbz.
-
-            let has_jb2 = encoded_sjbz.is_some();
-            if wrote_bg44 && has_jb2 {
-                // Determine if we have blits to color
-                if num_blits > 0 {
-                    // Write FGbz with correspondence (Version 0x80 | 0)
-                    writer.put_chunk("FGbz")?;
-
-                    // Version 0 with correspondence bit (0x80)
-                    writer.write_u8(0x80)?;
-
-                    // Palette size: 1 (black)
-                    writer.write_u16::<BigEndian>(1)?;
-                    writer.write_all(&[0x00, 0x00, 0x00])?; // Black BGR
-
-                    // Correspondence Data (per DjVuPalette.cpp)
-                    // nDataSize: INT24 = number of blits (NOT compressed size)
-                    let n = num_blits as u32;
-                    writer.write_u8(((n >> 16) & 0xFF) as u8)?;
-                    writer.write_u8(((n >> 8) & 0xFF) as u8)?;
-                    writer.write_u8((n & 0xFF) as u8)?;
-
-                    // Indices: BZZ encoded stream of INT16 indices (big-endian)
-                    // Since we have only 1 color (index 0), all blits get index 0.
-                    // Each index is written as a 16-bit big-endian integer.
-                    let mut index_bytes = Vec::with_capacity(num_blits * 2);
-                    for _ in 0..num_blits {
-                        index_bytes.push(0u8); // High byte of index 0
-                        index_bytes.push(0u8); // Low byte of index 0
+            // --- BG44: the background, blank for bitonal/JB2 pages ---
+            let mut wrote_bg44 = self.raw_count(RAW_CHUNK_SLOTS[3]) > 0;
+            self.write_raw_chunks(&mut writer, RAW_CHUNK_SLOTS[3])?;
+            let (bg_subsample, bg_reduction) = self.background_scale(params)?;
+            // The mask only lines up with a page-size background.
+            let hidden = self.mask.as_ref().filter(|_| bg_reduction == bg_subsample);
+            let solid = match (&self.background, params.solid_background) {
+                (Some(bg_img), Some(tolerances))
+                    if params.use_iw44 && self.background_codec.is_none() =>
+                {
+                    solid_color(bg_img, hidden, &tolerances)
+                }
+                _ => None,
+            };
+            if let Some(color) = solid {
+                let (data, coded) = solid_iw44(color, self.width, self.height, params)?;
+                if let Some(dump) = dump {
+                    let (w, h) = (self.width, self.height);
+                    let image = Pixmap::from_pixel(
+                        w.div_ceil(MAX_BG_SUBSAMPLE).max(1),
+                        h.div_ceil(MAX_BG_SUBSAMPLE).max(1),
+                        coded,
+                    );
+                    dump.write_picture(page_num, "background", image, None, params)?;
+                }
+                writer.put_chunk(ChunkId::BG44)?;
+                writer.write_all(&data)?;
+                writer.close_chunk()?;
+                wrote_bg44 = true;
+            } else if let Some(bg_img) = &self.background {
+                if params.use_iw44 || self.background_codec.is_some() {
+                    // Pixels under the mask are hidden by the foreground.
+                    let mask = self
+                        .mask
+                        .as_ref()
+                        .map(|m| layer_mask(m, bg_reduction, true));
+                    let codec = self.background_codec.as_deref().unwrap_or(&Iw44Background);
+                    if let Some(dump) = dump {
+                        let mask = mask.as_ref();
+                        let image = codec.prepared(bg_img, mask, bg_subsample, params);
+                        dump.write_picture(page_num, "background", image, mask, params)?;
                     }
-                    let compressed_indices = bzz_compress(&index_bytes, 50).map_err(|e| {
-                        DjvuError::EncodingError(format!("FGbz compression failed: {e}"))
-                    })?;
-                    writer.write_all(&compressed_indices)?;
-
-                    writer.close_chunk()?;
+                    encode_background(
+                        codec,
+                        bg_img,
+                        mask.as_ref(),
+                        bg_subsample,
+                        &mut writer,
+                        params,
+                    )?;
+                    wrote_bg44 = true;
                 } else {
-                    // Fallback for 0 blits: Write simple black FGbz palette
-                    // Format: BYTE version | INT16 nPaletteSize | BYTE3 bgrColor
-                    let fgbz_data: [u8; 6] = [
-                        0x00, // Version (no correspondence data)
-                        0x00, 0x01, // nPaletteSize = 1 (big-endian)
-                        0x00, 0x00, 0x00, // BGR color = black
-                    ];
-                    writer.put_chunk("FGbz")?;
-                    writer.write_all(&fgbz_data)?;
-                    writer.close_chunk()?;
+                    return Err(DjvuError::InvalidOperation(
+                        "JB2 background encoding requires a bitonal image. Use foreground instead."
+                            .to_string(),
+                    ));
                 }
             }
-
-            // --- Write Delayed Sjbz ---
-            if let Some(sjbz_data) = encoded_sjbz {
-                // Write raw JB2 stream (already ZP-compressed, no BZZ needed)
-                writer.put_chunk("Sjbz")?;
-                writer.write_all(&sjbz_data)?;
-                writer.close_chunk()?;
+            // If no background but JB2 content exists, emit an all-white BG44
+            if !wrote_bg44 && encoded_sjbz.is_some() {
+                let (w, h) = (self.width, self.height);
+                if params.solid_background.is_some() {
+                    let (data, _) = solid_iw44(Pixel::white(), w, h, params)?;
+                    writer.put_chunk(ChunkId::BG44)?;
+                    writer.write_all(&data)?;
+                    writer.close_chunk()?;
+                } else {
+                    let white_bg = Pixmap::from_pixel(w, h, Pixel::white());
+                    let subsample = params.bg_subsample;
+                    encode_iw44_layer(
+                        &white_bg,
+                        None,
+                        ChunkId::BG44,
+                        subsample,
+                        &mut writer,
+                        params,
+                    )?;
+                }
             }
 
             // --- TXTa/TXTz: Hidden text layer ---
@@ -650,48 +1744,51 @@ impl PageComponents {
             // from breaking the visual output.
             if let Some(text_layer) = &self.text_layer {
                 let mut txt_buf = Vec::new();
-                let tl = text_layer;
+                let tl = text_layer.unrotated(
+                    PageRotation::from_info_flags(rotation),
+                    self.width as u16,
+                    self.height as u16,
+                );
                 match tl.encode(&mut txt_buf) {
-                    Ok(()) => {
-                        // Use BZZ compression for DJVU spec compliance (100KB blocks)
-                        match bzz_compress(&txt_buf, 100) {
-                            Ok(data) => {
-                                writer.put_chunk("TXTz")?;
-                                writer.write_all(&data)?;
-                                writer.close_chunk()?;
-                            }
-                            Err(_e) => {
-                                #[cfg(feature = "debug-logging")]
-                                eprintln!(
-                                    "[page_encoder] Warning: BZZ compression for TXTz failed: {e}. Skipping text layer."
-                                );
-                            }
+                    Ok(()) => match bzz_compress_with(&txt_buf, &params.bzz) {
+                        Ok(data) => {
+                            writer.put_chunk(ChunkId::TXTz)?;
+                            writer.write_all(&data)?;
+                            writer.close_chunk()?;
                         }
-                    }
-                    Err(_e) => {
+                        Err(e) => {
+                            warn!(
+                                target: target::DOC,
+                                "BZZ compression for TXTz failed: {e}. Skipping text layer."
+                            );
+                        }
+                    },
+                    Err(e) => {
                         // Log but don't fail - page will still be viewable without searchable text
-                        #[cfg(feature = "debug-logging")]
-                        eprintln!(
-                            "[page_encoder] Warning: Failed to encode hidden text: {e}. Skipping text layer."
+                        warn!(
+                            target: target::DOC,
+                            "Failed to encode hidden text: {e}. Skipping text layer."
                         );
                     }
                 }
             }
 
+            self.write_raw_chunks(&mut writer, RAW_CHUNK_SLOTS[4])?;
+
             // --- ANTa/ANTz: Hyperlink/annotation layer ---
             if let Some(annotations) = &self.annotations {
                 let mut ann_buf = Vec::new();
                 annotations.encode(&mut ann_buf).map_err(|e| {
                     DjvuError::InvalidOperation(format!("Failed to encode annotations: {e}"))
                 })?;
-                // Use BZZ compression for DJVU spec compliance (100KB blocks)
-                let data = bzz_compress(&ann_buf, 100).map_err(|e| {
+                let data = bzz_compress_with(&ann_buf, &params.bzz).map_err(|e| {
                     DjvuError::EncodingError(format!("BZZ compression failed: {e}"))
                 })?;
-                writer.put_chunk("ANTz")?;
+                writer.put_chunk(ChunkId::ANTz)?;
                 writer.write_all(&data)?;
                 writer.close_chunk()?;
             }
+            self.write_raw_chunks(&mut writer, RAW_CHUNK_SLOTS[5])?;
 
             // Write text/annotations if present (legacy plain text)
             if let Some(text) = &self.text {
@@ -700,12 +1797,20 @@ impl PageComponents {
 
             // Close the FORM:DJVU chunk
             writer.close_chunk()?;
+            symbols
+        };
+
+        // Raw chunks and legacy text may have landed out of place.
+        let mut page = IffDocument::from_bytes(&output)?;
+        if let Some(chunks) = page.root.children_mut()
+            && order_page_chunks(chunks)?
+        {
+            output = page.to_bytes()?;
         }
-        Ok(output)
+        Ok((output, symbols))
     }
 
-    /// Writes the INFO chunk as per DjVu spec (10 bytes)
-    /// Format: width(2,BE) height(2,BE) minor_ver(1) major_ver(1) dpi(2,LE) gamma(1) flags(1)
+    /// Writes the INFO chunk; see [`PageInfo`].
     fn write_info_chunk(
         &self,
         writer: &mut IffWriter,
@@ -715,223 +1820,417 @@ impl PageComponents {
         rotation: u8,       // 1=0°, 6=90°CCW, 2=180°, 5=90°CW
         gamma: Option<f32>, // If None, use 2.2
     ) -> Result<()> {
-        use byteorder::LittleEndian;
-
-        writer.put_chunk("INFO")?;
-
-        // Width and height (2 bytes each, big-endian)
-        writer.write_u16::<BigEndian>(self.width as u16)?;
-        writer.write_u16::<BigEndian>(self.height as u16)?;
-
-        // Minor version (1 byte, currently 24 per C44 reference)
-        writer.write_u8(24)?;
-
-        // Major version (1 byte, currently 0 per spec)
-        writer.write_u8(0)?;
-
-        // DPI (2 bytes, little-endian per spec)
-        writer.write_u16::<LittleEndian>(dpi)?;
+        let mut info = PageInfo::new(self.width as u16, self.height as u16)
+            .with_dpi(dpi)
+            .with_rotation(PageRotation::from_info_flags(rotation));
+        if let Some(gamma) = gamma {
+            info = info.with_gamma(gamma);
+        }
+        writer.write_chunk(ChunkId::INFO, &info.to_bytes())
+    }
 
-        // Gamma (1 byte, gamma * 10)
-        let gamma_val = gamma.map_or(22, |g| (g * 10.0 + 0.5) as u8); // Default gamma = 2.2
-        writer.write_u8(gamma_val)?;
+    /// Writes the text/annotations chunk
+    fn write_text_chunk(&self, text: &str, writer: &mut IffWriter) -> Result<()> {
+        writer.put_chunk(ChunkId::TXTa)?;
+        writer.write_all(text.as_bytes())?;
+        writer.close_chunk()?;
+        Ok(())
+    }
+}
 
-        // Flags (1 byte: bits 0-2 = rotation, bits 3-7 = reserved)
-        let flags = rotation & 0x07; // Ensure only bottom 3 bits are used
-        writer.write_u8(flags)?;
+/// Encodes a page-sized picture with IW44 (wavelet) as `chunk_id` chunks,
+/// reduced by `subsample`. `mask` is at the reduced size, 1 where the
+/// picture is hidden.
+fn encode_iw44_layer(
+    img: &Pixmap,
+    mask: Option<&Bitmap>,
+    chunk_id: ChunkId,
+    subsample: u32,
+    writer: &mut IffWriter,
+    params: &PageEncodeParams,
+) -> Result<()> {
+    if mask.is_some() {
+        debug!(target: target::DOC, "Using mask-aware IW44 encoding for {}", chunk_id);
+    }
+    for chunk in iw44_layer_chunks(img, mask, subsample, params)? {
+        writer.put_chunk(chunk_id)?;
+        writer.write_all(&chunk)?;
+        writer.close_chunk()?;
+    }
+    Ok(())
+}
 
+/// Writes the background with `codec`, its header leading the first chunk,
+/// checking that a codec that is not progressive gives a single chunk.
+fn encode_background(
+    codec: &dyn BackgroundCodec,
+    img: &Pixmap,
+    mask: Option<&Bitmap>,
+    subsample: u32,
+    writer: &mut IffWriter,
+    params: &PageEncodeParams,
+) -> Result<()> {
+    let id = codec.chunk_id();
+    let chunks = codec.encode(img, mask, subsample, params)?;
+    if chunks.is_empty() || (chunks.len() > 1 && !codec.is_progressive()) {
+        return Err(DjvuError::EncodingError(format!(
+            "Background codec for {id} returned {} chunks",
+            chunks.len()
+        )));
+    }
+    let (width, height) = img.dimensions();
+    let reduced = subsample.max(1);
+    let mut header = codec.header(width.div_ceil(reduced), height.div_ceil(reduced));
+    for chunk in chunks {
+        writer.put_chunk(id)?;
+        if let Some(header) = header.take() {
+            writer.write_all(&header)?;
+        }
+        writer.write_all(&chunk)?;
         writer.close_chunk()?;
-        Ok(())
     }
+    Ok(())
+}
 
-    /// Encodes the background using IW44 (wavelet)
-    fn encode_iw44_background(
-        &self,
-        img: &Pixmap,
-        writer: &mut IffWriter,
-        params: &PageEncodeParams,
-    ) -> Result<()> {
-        let crcb_mode = if params.color {
-            // C++ c44.exe uses CRCBnormal by default, not CRCBfull
-            crate::encode::iw44::encoder::CrcbMode::Normal
-        } else {
-            crate::encode::iw44::encoder::CrcbMode::None
-        };
+/// Codes a page-sized picture with IW44, reduced by `subsample`, and returns
+/// the payloads of its chunks.
+pub(crate) fn iw44_layer_chunks(
+    img: &Pixmap,
+    mask: Option<&Bitmap>,
+    subsample: u32,
+    params: &PageEncodeParams,
+) -> Result<Vec<Vec<u8>>> {
+    let mut encoder = iw44_encoder(img, mask, subsample, params)?;
+    if let Some(max_bytes) = params.max_chunk_bytes {
+        return iw44_chunks_within(&mut encoder, max_bytes, params.slices);
+    }
 
-        // Debug: Check input image properties
-        let (w, h) = img.dimensions();
-        let raw_data = img.as_raw();
-        debug!("Input image {}x{}, {} bytes", w, h, raw_data.len());
+    // Encode and write IW44 data - use consistent slice limit for all chunks
+    let mut chunks = Vec::new();
+    let slices_per_chunk = params.slices.unwrap_or(74);
+    let mut total_slices_encoded = 0;
+    let total_slices_target = slices_per_chunk; // For now, match first chunk limit
 
-        // Check some sample pixels
-        if raw_data.len() >= 9 {
+    loop {
+        // Check if we've reached total slice target
+        if total_slices_encoded >= total_slices_target {
             debug!(
-                "First 3 pixels: RGB({},{},{}) RGB({},{},{}) RGB({},{},{})",
-                raw_data[0],
-                raw_data[1],
-                raw_data[2],
-                raw_data[3],
-                raw_data[4],
-                raw_data[5],
-                raw_data[6],
-                raw_data[7],
-                raw_data[8]
+                target: target::DOC,
+                "Reached total slice target {}, stopping",
+                total_slices_target
             );
+            break;
         }
 
-        let iw44_params = IW44EncoderParams {
-            decibels: params.decibels,
-            crcb_mode,
-            slices: params.slices,
-            bytes: params.bytes,
-            db_frac: params.db_frac,
-            lossless: params.lossless,
-            quant_multiplier: params.quant_multiplier.unwrap_or(1.0),
-        };
-
-        // If a mask is present, convert it to Bitmap and pass to IWEncoder for mask-aware encoding
-        let mask_gray = if let Some(mask_bitimg) = &self.mask {
-            // Convert BitImage to Bitmap (1=masked, 0=unmasked)
-            let (mw, mh) = (mask_bitimg.width as u32, mask_bitimg.height as u32);
-            let mut mask_pixels = Vec::with_capacity((mw * mh) as usize);
-            for y in 0..mh {
-                for x in 0..mw {
-                    let pixel_value = if mask_bitimg.get_pixel_unchecked(x as usize, y as usize) {
-                        1
-                    } else {
-                        0
-                    };
-                    mask_pixels.push(GrayPixel::new(pixel_value));
-                }
-            }
-            Some(Bitmap::from_vec(mw, mh, mask_pixels))
-        } else {
-            None
-        };
+        // Use consistent slice limit for all chunks
+        let (iw44_stream, more) = encoder
+            .encode_chunk(slices_per_chunk)
+            .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
 
-        if mask_gray.is_some() {
-            debug!("Using mask-aware IW44 encoding for background");
+        if iw44_stream.is_empty() {
+            break;
         }
 
-        let mut encoder = if params.color {
-            IWEncoder::from_rgb(img, mask_gray.as_ref(), iw44_params)
-        } else {
-            let gray = img.to_bitmap();
-            IWEncoder::from_gray(&gray, mask_gray.as_ref(), iw44_params)
-        }
-        .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+        // Count slices in this chunk (from header)
+        let header = Iw44ChunkHeader::parse(&iw44_stream)
+            .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+        total_slices_encoded += header.slices as usize;
+        chunks.push(iw44_stream);
 
-        // Choose the correct chunk type for IW44 background images:
-        // - BG44 for background layer (the main use case for IW44 in DjVu pages)
-        // - FG44 for foreground layer (has mask)
-        // Note: PM44/BM44 are for standalone IW44 files, not DjVu page backgrounds
-        let iw_chunk_id = if self.mask.is_some() {
-            "FG44"
-        } else {
-            "BG44" // Use BG44 for background images in DjVu pages
-        };
+        if !more {
+            break;
+        }
+    }
+    debug!(target: target::DOC, "Completed IW44 encoding with {} chunks", chunks.len());
 
-        // Encode and write IW44 data - use consistent slice limit for all chunks
-        let mut chunk_count = 0;
-        let slices_per_chunk = params.slices.unwrap_or(74);
-        let mut total_slices_encoded = 0;
-        let total_slices_target = slices_per_chunk; // For now, match first chunk limit
-
-        loop {
-            // Check if we've reached total slice target
-            if total_slices_encoded >= total_slices_target {
-                debug!(
-                    "Reached total slice target {}, stopping",
-                    total_slices_target
-                );
-                break;
-            }
+    Ok(chunks)
+}
 
-            // Use consistent slice limit for all chunks
-            let (iw44_stream, more) = encoder
-                .encode_chunk(slices_per_chunk)
-                .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+/// Codes the layer in chunks of at most `max_bytes`, stopping after
+/// `total_slices` slices in all.
+fn iw44_chunks_within(
+    encoder: &mut IWEncoder,
+    max_bytes: usize,
+    total_slices: Option<usize>,
+) -> Result<Vec<Vec<u8>>> {
+    let mut remaining = total_slices.unwrap_or(usize::MAX);
+    let mut chunks = Vec::new();
+    while remaining > 0 {
+        let (chunk, more) = encoder
+            .encode_chunk_within(max_bytes, remaining)
+            .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+        if chunk.is_empty() {
+            break;
+        }
+        let header =
+            Iw44ChunkHeader::parse(&chunk).map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+        remaining -= (header.slices as usize).min(remaining);
+        chunks.push(chunk);
+        if !more {
+            break;
+        }
+    }
+    debug!(
+        target: target::DOC,
+        "Completed IW44 encoding with {} chunks of at most {} bytes",
+        chunks.len(),
+        max_bytes
+    );
+    Ok(chunks)
+}
 
-            if iw44_stream.is_empty() {
-                break;
-            }
+/// Largest subsampling a `BG44` may use.
+const MAX_BG_SUBSAMPLE: u32 = 12;
 
-            chunk_count += 1;
-            writer.put_chunk(iw_chunk_id)?;
-            writer.write_all(&iw44_stream)?;
-            writer.close_chunk()?;
+/// Codes a `width` x `height` background of one `color` as a single IW44
+/// chunk at 1/12 resolution, returning the chunk data and the color a
+/// decoder reconstructs from it.
+///
+/// Only the DC coefficients carry anything, so slices are coded a band
+/// cycle at a time until the reconstructed color is within one level of
+/// `color` in every channel.
+pub(crate) fn solid_iw44(
+    color: Pixel,
+    width: u32,
+    height: u32,
+    params: &PageEncodeParams,
+) -> Result<(Vec<u8>, Pixel)> {
+    let (w, h) = (
+        width.div_ceil(MAX_BG_SUBSAMPLE).max(1),
+        height.div_ceil(MAX_BG_SUBSAMPLE).max(1),
+    );
+    let image = Pixmap::from_pixel(w, h, color);
+    let iw44_params = IW44EncoderParams {
+        decibels: None,
+        decibels_y: None,
+        decibels_chroma: None,
+        ..params.iw44_params()
+    };
+    let encoding_error = |e: IW44EncoderError| DjvuError::EncodingError(e.to_string());
+    let (encoder, target) = if params.color {
+        (IWEncoder::from_rgb(&image, None, iw44_params), color)
+    } else {
+        let gray = image.to_bitmap();
+        let y = gray.get_pixel(0, 0).y;
+        (
+            IWEncoder::from_gray(&gray, None, iw44_params),
+            Pixel::new(y, y, y),
+        )
+    };
+    let mut encoder = encoder.map_err(encoding_error)?;
+    let mut decoded = decoded_dc_color(&encoder);
+    while !encoder.is_finished() && !same_color(decoded, target) {
+        encoder.encode_slices(10).map_err(encoding_error)?;
+        decoded = decoded_dc_color(&encoder);
+    }
+    let data = encoder
+        .finish_chunk()
+        .map_err(encoding_error)?
+        .ok_or_else(|| DjvuError::EncodingError("Solid background coded no slices".to_string()))?;
+    Ok((data, decoded))
+}
 
-            // Count slices in this chunk (from header)
-            if iw44_stream.len() >= 2 {
-                total_slices_encoded += iw44_stream[1] as usize;
-            }
+fn same_color(a: Pixel, b: Pixel) -> bool {
+    a.r.abs_diff(b.r) <= 1 && a.g.abs_diff(b.g) <= 1 && a.b.abs_diff(b.b) <= 1
+}
 
-            if !more {
-                break;
-            }
-        }
-        debug!("Completed IW44 encoding with {} chunks", chunk_count);
+/// The color a decoder shows for the first block of what `encoder` has
+/// coded so far, reconstructed the way DjVuLibre does: coefficients are
+/// scaled by 64, and color goes through the inverse of the YCbCr transform.
+fn decoded_dc_color(encoder: &IWEncoder) -> Pixel {
+    let dc = |codec: &crate::encode::iw44::codec::Codec| {
+        let coded = codec.emap.blocks[0].get_bucket_raw(0)[0] as i32;
+        let sign = if codec.map.blocks[0].get_bucket_raw(0)[0] < 0 {
+            -1
+        } else {
+            1
+        };
+        ((sign * coded + 32) >> 6).clamp(-128, 127)
+    };
+    let (y_codec, chroma, _) = encoder.codecs();
+    let y = dc(y_codec);
+    let Some((cb_codec, cr_codec)) = chroma else {
+        let v = (y + 128) as u8;
+        return Pixel::new(v, v, v);
+    };
+    let (b, r) = (dc(cb_codec), dc(cr_codec));
+    let t1 = b >> 2;
+    let t2 = r + (r >> 1);
+    let t3 = y + 128 - t1;
+    let channel = |v: i32| v.clamp(0, 255) as u8;
+    Pixel::new(
+        channel(y + 128 + t2),
+        channel(t3 - (t2 >> 1)),
+        channel(t3 + (b << 1)),
+    )
+}
 
-        Ok(())
+/// Sets up the IW44 encoder for a page-sized picture reduced by `subsample`,
+/// in color or gray as `params` asks.
+pub(crate) fn iw44_encoder(
+    img: &Pixmap,
+    mask: Option<&Bitmap>,
+    subsample: u32,
+    params: &PageEncodeParams,
+) -> Result<IWEncoder> {
+    let subsampled;
+    let img = if subsample > 1 {
+        subsampled = img.subsample(subsample);
+        &subsampled
+    } else {
+        img
+    };
+
+    // Debug: Check input image properties
+    let (w, h) = img.dimensions();
+    let raw_data = img.as_raw();
+    debug!(target: target::DOC, "Input image {}x{}, {} bytes", w, h, raw_data.len());
+
+    // Check some sample pixels
+    if raw_data.len() >= 9 {
+        debug!(
+            target: target::DOC,
+            "First 3 pixels: RGB({},{},{}) RGB({},{},{}) RGB({},{},{})",
+            raw_data[0],
+            raw_data[1],
+            raw_data[2],
+            raw_data[3],
+            raw_data[4],
+            raw_data[5],
+            raw_data[6],
+            raw_data[7],
+            raw_data[8]
+        );
     }
 
-    /// Encodes the foreground using JB2
-    fn _encode_jb2_foreground(
-        &self,
-        img: &BitImage,
-        writer: &mut IffWriter,
-        _quality: u8,
-    ) -> Result<()> {
-        // Create JB2 encoder and encode as single page (non-symbol data)
-        let mut jb2_encoder = JB2Encoder::new(Vec::new());
-        let jb2_raw = jb2_encoder.encode_single_page(img)?;
-
-        // BZZ-compress the JB2 data as required by DjVu spec (§3.2.5)
-        let sjbz_payload =
-            bzz_compress(&jb2_raw, 256).map_err(|e| DjvuError::EncodingError(e.to_string()))?;
-
-        // Write Sjbz chunk for JB2 bitmap data (shapes and positions)
-        // Note: FGbz is for JB2 colors, Sjbz is for the actual bitmap content
-        writer.put_chunk("Sjbz")?;
-        writer.write_all(&sjbz_payload)?;
-        writer.close_chunk()?;
-
-        Ok(())
+    let iw44_params = params.iw44_params();
+    if params.color {
+        IWEncoder::from_rgb(img, mask, iw44_params)
+    } else {
+        let gray = img.to_bitmap();
+        IWEncoder::from_gray(&gray, mask, iw44_params)
     }
+    .map_err(|e| DjvuError::EncodingError(e.to_string()))
+}
 
-    /// Encodes the mask using JB2
-    fn _encode_jb2_mask(&self, img: &BitImage, writer: &mut IffWriter) -> Result<()> {
-        // Create JB2 encoder and encode as single page (non-symbol data)
-        let mut jb2_encoder = JB2Encoder::new(Vec::new());
-        let jb2_raw = jb2_encoder.encode_single_page(img)?;
-
-        // BZZ-compress the JB2 data as required by DjVu spec
-        let sjbz_payload =
-            bzz_compress(&jb2_raw, 256).map_err(|e| DjvuError::EncodingError(e.to_string()))?;
-
-        // Write Sjbz chunk
-        writer.put_chunk("Sjbz")?;
-        writer.write_all(&sjbz_payload)?;
-        writer.close_chunk()?;
+/// Turns a page-sized bilevel layer into an IW44 mask reduced by `factor`.
+///
+/// A reduced pixel is masked (1) when every pixel of its block is black if
+/// `covered` is true, or white if it is false: the background can ignore
+/// what the shapes cover, the foreground colors what they do not.
+pub(crate) fn layer_mask(layer: &BitImage, factor: u32, covered: bool) -> Bitmap {
+    let factor = factor.max(1) as usize;
+    let (mw, mh) = (layer.width.div_ceil(factor), layer.height.div_ceil(factor));
+    let mut mask_pixels = Vec::with_capacity(mw * mh);
+    for y in 0..mh {
+        for x in 0..mw {
+            let (x0, y0) = (x * factor, y * factor);
+            let x1 = (x0 + factor).min(layer.width);
+            let y1 = (y0 + factor).min(layer.height);
+            let masked =
+                (y0..y1).all(|sy| (x0..x1).all(|sx| layer.get_pixel_unchecked(sx, sy) == covered));
+            mask_pixels.push(GrayPixel::new(masked as u8));
+        }
+    }
+    Bitmap::from_vec(mw as u32, mh as u32, mask_pixels)
+}
 
-        Ok(())
+/// Replaces the `shapes` of a page that match a shape of `dict` by that
+/// shape, renumbering `blits` so the dictionary's shapes come first, and
+/// returns the shapes left to the page.
+fn inherit_shapes(
+    dict: &SharedDict,
+    shapes: Vec<BitImage>,
+    blits: &mut [(i32, i32, usize)],
+    max_error: f32,
+) -> (Vec<BitImage>, DictStats) {
+    let matches = match_symbols(&shapes, dict, max_error);
+    let mut local = Vec::new();
+    let index: Vec<usize> = shapes
+        .into_iter()
+        .zip(&matches)
+        .map(|(shape, found)| {
+            found.unwrap_or_else(|| {
+                local.push(shape);
+                dict.shape_count() + local.len() - 1
+            })
+        })
+        .collect();
+    let mut stats = DictStats {
+        symbols: blits.len(),
+        ..DictStats::default()
+    };
+    for blit in blits.iter_mut() {
+        blit.2 = index[blit.2];
+        if blit.2 < dict.shape_count() {
+            stats.hits += 1;
+        }
     }
+    (local, stats)
+}
 
-    /// Writes the text/annotations chunk
-    fn write_text_chunk(&self, text: &str, writer: &mut IffWriter) -> Result<()> {
-        writer.put_chunk("TXTa")?;
-        writer.write_all(text.as_bytes())?;
-        writer.close_chunk()?;
-        Ok(())
+/// Runs connected-component analysis on a bilevel layer with the page's
+/// cleaning settings, dropping specks smaller than `params.despeckle` and,
+/// with `params.singletons`, small shapes that occur only once.
+pub(crate) fn extract_page_shapes(
+    image: &BitImage,
+    params: &PageEncodeParams,
+) -> Vec<(BitImage, crate::encode::jb2::BBox)> {
+    let dpi = params.dpi.clamp(1, i32::MAX as u32) as i32;
+    let cc_image = crate::encode::jb2::analyze_page(image, dpi, params.jb2_loss_level);
+    let mut shapes = cc_image.extract_shapes();
+    if params.despeckle > 0 {
+        shapes.retain(|(bm, _)| bm.count_ones() >= params.despeckle);
+    }
+    if let Some(singletons) = &params.singletons {
+        drop_singletons(&mut shapes, singletons);
     }
+    shapes
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::encode::symbol_dict::BitImage;
+    use crate::encode::jb2::symbol_dict::BitImage;
     use crate::image::image_formats::{Pixel, Pixmap};
 
+    #[test]
+    fn test_solid_background() -> Result<()> {
+        let tint = Pixel::new(242, 236, 224);
+        let paper = Pixmap::from_fn(600, 400, |x, y| {
+            let grain = ((x * 7 + y * 13) % 5) as u8;
+            Pixel::new(tint.r - 2 + grain, tint.g, tint.b + 2 - grain)
+        });
+        let page = PageComponents::new().with_background(paper)?;
+        let wavelet = page.encode(&PageEncodeParams::default(), 1, 300, 1, None)?;
+        let params = PageEncodeParams {
+            solid_background: Some(SolidColorParams::default()),
+            ..PageEncodeParams::default()
+        };
+        let solid = page.encode(&params, 1, 300, 1, None)?;
+        let chunks = page_chunks(&solid);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].0, b"BG44");
+        assert_eq!(&chunks[1].1[4..8], &[0, 50, 0, 34]);
+        assert!(solid.len() * 4 < wavelet.len());
+
+        for color in [true, false] {
+            let params = PageEncodeParams {
+                color,
+                ..params.clone()
+            };
+            let (_, decoded) = solid_iw44(tint, 600, 400, &params)?;
+            let expected = if color {
+                tint
+            } else {
+                let y = Pixmap::from_pixel(1, 1, tint).to_bitmap().get_pixel(0, 0).y;
+                Pixel::new(y, y, y)
+            };
+            assert!(same_color(decoded, expected), "{decoded:?} vs {expected:?}");
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_page_encoding_with_builder() {
         // Create a simple white background image
@@ -964,6 +2263,436 @@ mod tests {
         assert!(encoded.windows(4).any(|w| w == b"TXTa"));
     }
 
+    #[test]
+    fn test_page_info_round_trip() -> Result<()> {
+        let page = PageComponents::new()
+            .with_background(Pixmap::from_pixel(40, 20, Pixel::white()))?
+            .with_rotation(PageRotation::Ccw90);
+        let flags = PageRotation::Ccw90.info_flags();
+        let params = PageEncodeParams {
+            dpi: 150,
+            ..Default::default()
+        };
+        let data = page.encode(&params, 1, 0, flags, Some(1.8))?;
+        let info = PageInfo::parse(&data[24..34]).unwrap();
+        assert_eq!(
+            info,
+            PageInfo::new(40, 20)
+                .with_dpi(150)
+                .with_gamma(1.8)
+                .with_rotation(PageRotation::Ccw90)
+        );
+        assert_eq!((info.gamma, info.gamma()), (18, 1.8));
+        assert_eq!(PageInfo::parse(&info.to_bytes()), Some(info));
+
+        // Short chunks from old encoders take the defaults, and gamma is
+        // kept in range.
+        let short = PageInfo::parse(&[0, 40, 0, 20, 24, 0]).unwrap();
+        assert_eq!(short, PageInfo::new(40, 20));
+        assert_eq!(PageInfo::parse(&[0, 40, 0]), None);
+        assert_eq!(PageInfo::new(1, 1).with_gamma(9.0).gamma, 50);
+        assert_eq!(PageInfo { gamma: 0, ..short }.gamma(), 0.3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotation_sets_info_flags() -> Result<()> {
+        let page = PageComponents::new()
+            .with_background(Pixmap::from_pixel(40, 20, Pixel::white()))?
+            .with_text_layer(HiddenText::from_word_boxes(
+                20,
+                40,
+                vec![("up".to_string(), 2, 3, 8, 4)],
+            ))
+            .with_rotation(PageRotation::Cw90);
+        let encoded =
+            EncodedPage::from_components(0, page, &PageEncodeParams::default(), 300, None)?;
+        // INFO data starts after the magic, the FORM header and INFO's own.
+        let info = PageInfo::parse(&encoded.data[24..34]).unwrap();
+        assert_eq!(&encoded.data[16..20], b"INFO");
+        assert_eq!((info.width, info.height), (40, 20));
+        assert_eq!(info.rotation, PageRotation::Cw90);
+        assert!(encoded.data.windows(4).any(|w| w == b"TXTz"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_redact_clears_region() -> Result<()> {
+        let mut mask = BitImage::new(20, 10).unwrap();
+        for y in 0..10 {
+            mask.fill_run(y, 0, 19);
+        }
+        let dot = BitImage::from_bytes(2, 2, &[0xf0]);
+        let mut page = PageComponents::new()
+            .with_background(Pixmap::from_pixel(20, 10, Pixel::black()))?
+            .with_mask(mask)?
+            .with_jb2_manual(vec![dot], vec![(1, 1, 0), (15, 1, 0)])
+            .with_text_layer(HiddenText::from_word_boxes(
+                20,
+                10,
+                vec![
+                    ("keep".to_string(), 0, 0, 4, 4),
+                    ("drop".to_string(), 12, 6, 4, 4),
+                ],
+            ));
+        page.redact(Rect::new(10, 5, 10, 5));
+
+        let PageLayer::IW44Background { image, .. } = &page.layers[0] else {
+            panic!("background layer expected");
+        };
+        assert_eq!(image.get_pixel(12, 7), Pixel::white());
+        assert_eq!(image.get_pixel(9, 7), Pixel::black());
+        let PageLayer::JB2Mask { image, .. } = &page.layers[1] else {
+            panic!("mask layer expected");
+        };
+        assert!(!image.get_pixel_unchecked(12, 7));
+        assert!(image.get_pixel_unchecked(12, 4));
+        assert_eq!(page.jb2_blits, Some(vec![(1, 1, 0)]));
+
+        // Rects reaching past the page are cut to it.
+        page.redact(Rect::new(u32::MAX - 1, 0, 10, u32::MAX));
+        page.redact(Rect::new(18, 0, u32::MAX, u32::MAX));
+        let PageLayer::JB2Mask { image, .. } = &page.layers[1] else {
+            panic!("mask layer expected");
+        };
+        assert!(!image.get_pixel_unchecked(19, 0));
+        assert!(image.get_pixel_unchecked(17, 0));
+        let words = &page.text_layer.unwrap().root_zone.children;
+        assert_eq!(words.len(), 1);
+        assert_eq!(words[0].text.as_deref(), Some("keep"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_chunks_follow_spec_order() -> Result<()> {
+        let page = PageComponents::new_with_dimensions(32, 32)
+            .with_raw_chunk(ChunkId::ANTz, vec![1, 2, 3])?
+            .with_raw_chunk(ChunkId::Sjbz, vec![4; 4])?
+            .with_raw_chunk(ChunkId::Djbz, vec![5; 2])?
+            .with_raw_chunk(ChunkId::BG44, vec![6; 5])?;
+        let encoded = page.encode(&PageEncodeParams::default(), 1, 300, 1, None)?;
+
+        let pos = |id: &[u8]| encoded.windows(4).position(|w| w == id).unwrap();
+        assert!(pos(b"INFO") < pos(b"Djbz"));
+        assert!(pos(b"Djbz") < pos(b"Sjbz"));
+        assert!(pos(b"Sjbz") < pos(b"BG44"));
+        assert!(pos(b"BG44") < pos(b"ANTz"));
+        assert_eq!(&encoded[pos(b"ANTz") + 8..pos(b"ANTz") + 11], &[1, 2, 3]);
+        // The raw BG44 stands in for the generated one.
+        assert_eq!(encoded.windows(4).filter(|w| *w == b"BG44").count(), 1);
+
+        assert!(
+            PageComponents::new()
+                .with_raw_chunk(ChunkId::INFO, vec![])
+                .is_err()
+        );
+        let clash = PageComponents::new()
+            .with_background(Pixmap::from_pixel(8, 8, Pixel::white()))?
+            .with_raw_chunk(ChunkId::BG44, vec![0])?;
+        assert!(
+            clash
+                .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    /// Top-level chunk IDs of an encoded page, with their payloads.
+    fn page_chunks(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut pos = 16;
+        while pos + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            chunks.push((&data[pos..pos + 4], &data[pos + 8..pos + 8 + size]));
+            pos += 8 + size + size % 2;
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_background_codec() -> Result<()> {
+        use crate::doc::background::{BackgroundCodec, Iw44Background};
+
+        /// Stores the reduced size and the gray of the first pixel, in as
+        /// many chunks as asked, after a header with the reduced size.
+        struct Probe(usize);
+
+        impl BackgroundCodec for Probe {
+            fn chunk_id(&self) -> ChunkId {
+                ChunkId::BGjp
+            }
+
+            fn is_progressive(&self) -> bool {
+                false
+            }
+
+            fn header(&self, width: u32, height: u32) -> Option<Vec<u8>> {
+                Some(vec![width as u8, height as u8])
+            }
+
+            fn encode(
+                &self,
+                image: &Pixmap,
+                _mask: Option<&Bitmap>,
+                subsample: u32,
+                _params: &PageEncodeParams,
+            ) -> Result<Vec<Vec<u8>>> {
+                let (w, h) = image.subsample(subsample).dimensions();
+                let gray = image.get_pixel(0, 0).r;
+                Ok(vec![vec![w as u8, h as u8, gray]; self.0])
+            }
+        }
+
+        let page = || {
+            PageComponents::new_with_dimensions(30, 20).with_background(Pixmap::from_pixel(
+                30,
+                20,
+                Pixel::new(40, 40, 40),
+            ))
+        };
+        let params = PageEncodeParams {
+            bg_subsample: 3,
+            solid_background: Some(SolidColorParams::default()),
+            ..PageEncodeParams::default()
+        };
+        let encoded = page()?
+            .with_background_codec(Arc::new(Probe(1)))
+            .encode(&params, 1, 300, 1, None)?;
+        let chunks = page_chunks(&encoded);
+        assert_eq!(chunks[1], (&b"BGjp"[..], &[10, 7, 10, 7, 40][..]));
+        assert!(!chunks.iter().any(|(id, _)| *id == b"BG44"));
+
+        // Only progressive codecs may split the background.
+        let split = page()?.with_background_codec(Arc::new(Probe(2)));
+        assert!(split.encode(&params, 1, 300, 1, None).is_err());
+
+        // A codec of its own is used without IW44.
+        let no_iw44 = PageEncodeParams {
+            use_iw44: false,
+            ..params.clone()
+        };
+        let encoded = page()?
+            .with_background_codec(Arc::new(Probe(1)))
+            .encode(&no_iw44, 1, 300, 1, None)?;
+        assert_eq!(
+            page_chunks(&encoded)[1],
+            (&b"BGjp"[..], &[10, 7, 10, 7, 40][..])
+        );
+
+        // IW44 is the default.
+        let params = PageEncodeParams::default();
+        let iw44 = page()?.with_background_codec(Arc::new(Iw44Background));
+        assert_eq!(
+            iw44.encode(&params, 1, 300, 1, None)?,
+            page()?.encode(&params, 1, 300, 1, None)?
+        );
+
+        // Filled holes are coded instead of IW44 masking.
+        let mut mask = BitImage::new(30, 20).unwrap();
+        (0..30).for_each(|x| mask.set_usize(x, 10, true));
+        let masked = || page().and_then(|p| p.with_mask(mask.clone()));
+        let inpainted = PageEncodeParams {
+            hole_fill: HoleFill::Inpaint,
+            ..params.clone()
+        };
+        assert_ne!(
+            masked()?.encode(&inpainted, 1, 300, 1, None)?,
+            masked()?.encode(&params, 1, 300, 1, None)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_background_split_by_bytes() -> Result<()> {
+        let page = || {
+            let background = Pixmap::from_fn(120, 90, |x, y| {
+                let noise = ((x * 7919 + y * 104_729) % 253) as u8;
+                Pixel::new(noise, noise.wrapping_mul(3), (x + y) as u8)
+            });
+            PageComponents::new().with_background(background)
+        };
+        let bg44 = |encoded: &[u8]| -> Vec<(usize, u8)> {
+            page_chunks(encoded)
+                .iter()
+                .filter(|(id, _)| *id == b"BG44")
+                .map(|(_, data)| (data.len(), data[1]))
+                .collect()
+        };
+
+        let params = PageEncodeParams::default();
+        let whole = bg44(&page()?.encode(&params, 1, 300, 1, None)?);
+        let split_params = PageEncodeParams {
+            max_chunk_bytes: Some(400),
+            ..params
+        };
+        let split = bg44(&page()?.encode(&split_params, 1, 300, 1, None)?);
+
+        // The same 74 slices, in pieces of at most 400 bytes.
+        assert_eq!(whole.len(), 1);
+        assert!(split.len() > 2);
+        assert!(split.iter().all(|&(len, slices)| len <= 400 || slices == 1));
+        let total = |chunks: &[(usize, u8)]| chunks.iter().map(|&(_, s)| s as usize).sum::<usize>();
+        assert_eq!(total(&split), total(&whole));
+
+        let invalid = PageEncodeParams {
+            max_chunk_bytes: Some(0),
+            ..PageEncodeParams::default()
+        };
+        assert!(invalid.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compound_page_layout() -> Result<()> {
+        use crate::doc::profile::Profile;
+
+        let (w, h) = (48, 36);
+        let photo = Pixmap::from_fn(w, h, |x, y| Pixel::new((x * 5) as u8, (y * 7) as u8, 90));
+        let mut mask = BitImage::new(w, h).unwrap();
+        for y in 10..20 {
+            for x in 8..30 {
+                mask.set_usize(x, y, true);
+            }
+        }
+        let page = PageComponents::new()
+            .with_background(photo)?
+            .with_mask(mask.clone())?
+            .with_foreground_colors(Pixmap::from_pixel(w, h, Pixel::new(200, 0, 0)))?;
+        let data = page.encode(&Profile::TextOverPhoto.params(), 1, 300, 1, None)?;
+
+        let chunks = page_chunks(&data);
+        let ids: Vec<&[u8]> = chunks.iter().map(|c| c.0).collect();
+        let first_bg = ids.iter().position(|id| *id == b"BG44").unwrap();
+        assert_eq!(&ids[..first_bg], [b"INFO", b"Sjbz", b"FG44"]);
+        assert!(ids[first_bg..].iter().all(|id| *id == b"BG44"));
+        // FG44 at 1/12 and BG44 at 1/3 of the page size.
+        let dims = |payload: &[u8]| {
+            let dim = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+            (dim(4), dim(6))
+        };
+        assert_eq!(dims(chunks[2].1), (4, 3));
+        assert_eq!(dims(chunks[first_bg].1), (16, 12));
+
+        // A plain black foreground gets no color chunk at all.
+        let bitonal = PageComponents::new().with_foreground(mask)?;
+        let data = bitonal.encode(&PageEncodeParams::default(), 1, 300, 1, None)?;
+        let ids: Vec<&[u8]> = page_chunks(&data).iter().map(|c| c.0).collect();
+        assert_eq!(ids, [b"INFO", b"Sjbz", b"BG44"]);
+
+        // Colors without shapes to paint are rejected.
+        let unpainted = PageComponents::new().with_foreground_colors(Pixmap::new(8, 8))?;
+        assert_eq!(unpainted.problems(300).len(), 1);
+        assert!(
+            unpainted
+                .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_foreground_palette() -> Result<()> {
+        let mut dot = BitImage::new(3, 3).unwrap();
+        for i in 0..9 {
+            dot.set_usize(i % 3, i / 3, true);
+        }
+        let palette = Palette::from_colors(vec![Pixel::new(255, 0, 0), Pixel::new(0, 0, 255)]);
+        let page = || {
+            PageComponents::new_with_dimensions(32, 16)
+                .with_jb2_manual(vec![dot.clone()], vec![(2, 2, 0), (10, 2, 0), (20, 8, 0)])
+        };
+
+        let colored = page().with_foreground_palette(palette.clone(), vec![0, 1, 0])?;
+        let data = colored.encode(&PageEncodeParams::default(), 1, 300, 1, None)?;
+        let chunks = page_chunks(&data);
+        let ids: Vec<&[u8]> = chunks.iter().map(|c| c.0).collect();
+        assert_eq!(ids, [b"INFO", b"Sjbz", b"FGbz", b"BG44"]);
+        // Version with indices, two BGR colors, then three blits.
+        assert_eq!(
+            &chunks[2].1[..12],
+            &[0x80, 0, 2, 0, 0, 255, 255, 0, 0, 0, 0, 3]
+        );
+        let decoded = Palette::decode(&mut &chunks[2].1[..])?;
+        assert_eq!(decoded.color_indices, [0, 1, 0]);
+        assert_eq!(decoded.index_to_color(1), Some(&Pixel::new(0, 0, 255)));
+
+        #[cfg(feature = "interop")]
+        if let Some(dump) = crate::interop::dump_document(&data)? {
+            assert!(dump.contains("Sjbz") && dump.contains("FGbz"), "{dump}");
+        }
+
+        let miscounted = page().with_foreground_palette(palette.clone(), vec![0, 1])?;
+        assert!(
+            miscounted
+                .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+                .is_err()
+        );
+        assert!(page().with_foreground_palette(palette, vec![2]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_foreground_palette_image() -> Result<()> {
+        // A stamp on the top line over the red half, text below it in black.
+        let mut mask = BitImage::new(64, 32).unwrap();
+        for y in 2..10 {
+            mask.fill_run(y, 4, 12);
+        }
+        for y in 18..28 {
+            mask.fill_run(y, 30, 34);
+            mask.fill_run(y, 40, 44);
+        }
+        let picture = Pixmap::from_fn(64, 32, |x, _| {
+            if x < 20 {
+                Pixel::new(220, 10, 10)
+            } else {
+                Pixel::new(20, 20, 20)
+            }
+        });
+        let palette = Palette::from_colors(vec![Pixel::black(), Pixel::new(255, 0, 0)]);
+        let page = PageComponents::new()
+            .with_mask(mask)?
+            .with_foreground_palette_image(palette, picture)?;
+        assert!(page.problems(300).is_empty());
+
+        let data = page.encode(&PageEncodeParams::default(), 1, 300, 1, None)?;
+        let chunks = page_chunks(&data);
+        let fgbz = chunks.iter().find(|c| c.0 == b"FGbz").unwrap();
+        // Blits run top to bottom, so the stamp comes first.
+        let decoded = Palette::decode(&mut &fgbz.1[..])?;
+        assert_eq!(decoded.color_indices, [1, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() -> Result<()> {
+        let page = PageComponents::new_with_dimensions(16, 16)
+            .with_jb2_manual(vec![BitImage::new(2, 2).unwrap()], vec![(0, 0, 3)])
+            .with_raw_chunk(ChunkId::ANTz, vec![0])?
+            .with_raw_chunk(ChunkId::ANTa, vec![0])?;
+        let problems = page.problems(5);
+        assert_eq!(problems.len(), 3, "{problems:?}");
+        assert!(problems[0].contains("5 dpi"));
+        assert!(problems[1].contains("shape 3 of 1"));
+        assert!(matches!(
+            page.validate(5),
+            Err(DjvuError::ValidationError(_))
+        ));
+
+        let ok = PageComponents::new().with_background(Pixmap::from_pixel(8, 8, Pixel::white()))?;
+        assert!(ok.validate(300).is_ok());
+        // A mask beside the foreground still shapes the background.
+        let text = BitImage::from_bytes(8, 8, &[0xff; 8]);
+        let masked = ok.with_foreground(text.clone())?.with_mask(text.clone())?;
+        assert!(masked.validate(300).is_ok());
+        let unused = PageComponents::new()
+            .with_foreground(text.clone())?
+            .with_mask(text)?;
+        assert!(unused.problems(300)[0].contains("mask is unused"));
+        assert!(PageComponents::new().validate(300).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_dimension_mismatch() {
         let bg_image = Pixmap::new(100, 200);
@@ -981,4 +2710,164 @@ mod tests {
             panic!("Expected a DimensionMismatch error");
         }
     }
+
+    #[test]
+    fn test_params_validate() {
+        assert!(PageEncodeParams::default().validate().is_ok());
+        let bad = [
+            PageEncodeParams {
+                bg_subsample: 13,
+                ..PageEncodeParams::default()
+            },
+            PageEncodeParams {
+                slices: Some(0),
+                ..PageEncodeParams::default()
+            },
+            PageEncodeParams {
+                bg_quality: 101,
+                ..PageEncodeParams::default()
+            },
+        ];
+        let page = PageComponents::new_with_dimensions(8, 8);
+        for params in bad {
+            assert!(params.validate().is_err());
+            assert!(page.encode(&params, 1, 300, 1, None).is_err());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_params_job_file() {
+        let params: PageEncodeParams =
+            serde_json::from_str(r#"{"dpi": 600, "bg_subsample": 3, "crcb_mode": "Full"}"#)
+                .unwrap();
+        assert_eq!(params.dpi, 600);
+        assert_eq!(params.bg_subsample, 3);
+        assert_eq!(params.iw44_params().crcb_mode, CrcbMode::Full);
+        assert_eq!(params.slices, Some(74));
+    }
+
+    #[test]
+    fn test_subsampled_background() -> Result<()> {
+        // A 600 dpi mask over a 200 dpi background.
+        let mut mask = BitImage::new(61, 40).unwrap();
+        mask.set_usize(30, 20, true);
+        let background = || Pixmap::from_pixel(21, 14, Pixel::new(200, 180, 120));
+        let page = PageComponents::new()
+            .with_background_subsampled(background(), 3)?
+            .with_mask(mask.clone())?;
+        assert_eq!(page.dimensions(), (61, 40));
+
+        let bg44_size = |params: &PageEncodeParams| -> Result<(u16, u16)> {
+            let encoded = page.encode(params, 1, 600, 1, None)?;
+            let chunks = page_chunks(&encoded);
+            assert_eq!(chunks[0].1[..4], [0, 61, 0, 40]);
+            let (_, bg44) = chunks.iter().find(|(id, _)| *id == b"BG44").unwrap();
+            let header = Iw44ChunkHeader::parse(bg44).unwrap().image.unwrap();
+            Ok((header.width, header.height))
+        };
+        assert_eq!(bg44_size(&PageEncodeParams::default())?, (21, 14));
+        let params = PageEncodeParams {
+            bg_subsample: 2,
+            ..PageEncodeParams::default()
+        };
+        assert_eq!(bg44_size(&params)?, (11, 7));
+        let params = PageEncodeParams {
+            bg_subsample: 5,
+            ..PageEncodeParams::default()
+        };
+        assert!(page.encode(&params, 1, 600, 1, None).is_err());
+
+        // The background must be the page reduced by the factor.
+        let sized = || PageComponents::new_with_dimensions(61, 40);
+        assert!(sized().with_background_subsampled(background(), 2).is_err());
+        assert!(sized().with_background_subsampled(background(), 3).is_ok());
+        let wide = PageComponents::new().with_background_subsampled(background(), 3)?;
+        assert!(wide.with_mask(BitImage::new(66, 40).unwrap()).is_err());
+        assert!(
+            PageComponents::new()
+                .with_background_subsampled(background(), 13)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_order() -> Result<()> {
+        let white = || Pixmap::from_pixel(16, 16, Pixel::white());
+        let mut mask = BitImage::new(16, 16).unwrap();
+        mask.set_usize(4, 4, true);
+        let params = PageEncodeParams::default();
+
+        // Pages built in any order list like `djvudump` shows them.
+        let matrix: Vec<(PageComponents, &[&str])> = vec![
+            (
+                PageComponents::new()
+                    .with_raw_chunk(ChunkId::ANTz, vec![1])?
+                    .with_raw_chunk(ChunkId::FGbz, vec![2])?
+                    .with_background(white())?
+                    .with_raw_chunk(ChunkId::Sjbz, vec![3])?
+                    .with_raw_chunk(ChunkId::INCL, b"dict.iff".to_vec())?,
+                &["INFO", "INCL", "Sjbz", "FGbz", "BG44", "ANTz"],
+            ),
+            (
+                PageComponents::new()
+                    .with_raw_chunk(ChunkId::Djbz, vec![4])?
+                    .with_mask(mask)?
+                    .with_raw_chunk(ChunkId::TXTz, vec![5])?,
+                &["INFO", "Djbz", "Sjbz", "BG44", "TXTz"],
+            ),
+            (
+                PageComponents::new_with_dimensions(16, 16)
+                    .with_raw_chunk(ChunkId::ANTz, vec![6])?
+                    .with_text("legacy".to_string()),
+                &["INFO", "TXTa", "ANTz"],
+            ),
+        ];
+        for (page, expected) in matrix {
+            let encoded = page.encode(&params, 1, 300, 1, None)?;
+            let ids: Vec<&[u8]> = page_chunks(&encoded)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+            let expected: Vec<&[u8]> = expected.iter().map(|id| id.as_bytes()).collect();
+            assert_eq!(ids, expected);
+        }
+
+        let chunk = |id| IffChunk::new_raw(id, vec![0]);
+        let mut chunks = vec![
+            chunk(ChunkId::ANTz),
+            chunk(ChunkId::BG44),
+            chunk(ChunkId::INFO),
+            IffChunk::new_raw(ChunkId::BG44, vec![1]),
+            chunk(ChunkId::Sjbz),
+        ];
+        assert!(order_page_chunks(&mut chunks)?);
+        let ids: Vec<ChunkId> = chunks.iter().map(|c| c.id).collect();
+        assert_eq!(
+            ids,
+            [
+                ChunkId::INFO,
+                ChunkId::Sjbz,
+                ChunkId::BG44,
+                ChunkId::BG44,
+                ChunkId::ANTz
+            ]
+        );
+        // Chunks of a progressive picture keep their order.
+        assert_eq!(chunks[3].data(), Some(&[1][..]));
+        assert!(!order_page_chunks(&mut chunks)?);
+
+        for bad in [
+            &[ChunkId::INFO, ChunkId::Sjbz, ChunkId::Smmr][..],
+            &[ChunkId::INFO, ChunkId::BG44, ChunkId::BGjp],
+            &[ChunkId::INFO, ChunkId::FGbz, ChunkId::FG44],
+            &[ChunkId::Sjbz, ChunkId::BG44],
+            &[ChunkId::INFO, ChunkId::DIRM],
+        ] {
+            let mut chunks: Vec<IffChunk> = bad.iter().map(|&id| chunk(id)).collect();
+            assert!(order_page_chunks(&mut chunks).is_err(), "{bad:?}");
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,151 @@
+//! Encoder presets for common kinds of scanned material.
+//!
+//! Each [`Profile`] fills in the handful of [`PageEncodeParams`] fields that
+//! usually get tuned together: JB2 cleaning and despeckling for the text
+//! layer, background subsampling, chrominance mode, and quality targets for
+//! the IW44 background. Apply one with
+//! [`DjvuBuilder::with_profile`](crate::doc::DjvuBuilder::with_profile), then
+//! override individual settings as needed.
+
+use crate::doc::page_encoder::PageEncodeParams;
+use crate::encode::iw44::encoder::CrcbMode;
+
+/// A named bundle of encoder settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Profile {
+    /// Black-and-white text pages scanned at 300 dpi: aggressive JB2
+    /// cleaning, and a heavily subsampled grayscale background.
+    BitonalText300dpi,
+    /// Color pages with text over pictures: background at a third of the
+    /// resolution with normal chrominance, like `cpaldjvu` and `didjvu`.
+    ColorMagazine,
+    /// Scanned text printed over photographs, split into a JB2 mask colored
+    /// by a coarse `FG44` layer and a photo background, like `csepdjvu`.
+    /// Pages need a background, a mask and
+    /// [foreground colors](crate::doc::page_encoder::PageComponents::with_foreground_colors).
+    TextOverPhoto,
+    /// Photographs and artwork: full-resolution background with full
+    /// chrominance, coded to high quality targets.
+    PhotoArchive,
+    /// Bilevel pages coded like DjVuLibre's `cjb2 -clean`, for checking a
+    /// migration from DjVuLibre against its output.
+    ///
+    /// The connected components are found, cleaned and ordered with cjb2's
+    /// thresholds for the page's DPI (see
+    /// [`CCImage::new`](crate::encode::jb2::CCImage::new)): components
+    /// under its tiny size are erased, small ones merged and large ones
+    /// split. Nothing else is dropped, and only shapes that are exact
+    /// copies of an earlier one are matched, as in cjb2's lossless tuning.
+    /// cjb2 may also refine a shape against a close but different earlier
+    /// one, which this crate does not, so `Sjbz` chunks come out close to
+    /// cjb2's but not identical; `tests/cjb2_parity_test.rs` checks how
+    /// close.
+    Cjb2Clean,
+}
+
+impl Profile {
+    /// Returns default parameters with this profile applied.
+    pub fn params(self) -> PageEncodeParams {
+        let mut params = PageEncodeParams::default();
+        self.apply(&mut params);
+        params
+    }
+
+    /// Overwrites the fields this profile controls; DPI and the rest of
+    /// `params` are left as they are.
+    pub fn apply(self, params: &mut PageEncodeParams) {
+        match self {
+            Profile::BitonalText300dpi => {
+                params.color = false;
+                params.crcb_mode = Some(CrcbMode::None);
+                params.bg_subsample = 12;
+                params.decibels = None;
+                params.decibels_y = None;
+                params.decibels_chroma = None;
+                params.slices = Some(74);
+                params.jb2_loss_level = 1;
+                params.despeckle = 3;
+            }
+            Profile::ColorMagazine => {
+                params.color = true;
+                params.crcb_mode = Some(CrcbMode::Normal);
+                params.bg_subsample = 3;
+                params.decibels = None;
+                params.decibels_y = Some(38.0);
+                params.decibels_chroma = Some(34.0);
+                params.slices = Some(89);
+                params.jb2_loss_level = 1;
+                params.despeckle = 2;
+            }
+            Profile::TextOverPhoto => {
+                params.color = true;
+                params.crcb_mode = Some(CrcbMode::Normal);
+                params.bg_subsample = 3;
+                params.fg_subsample = 12;
+                params.decibels = None;
+                params.decibels_y = Some(40.0);
+                params.decibels_chroma = Some(36.0);
+                params.slices = Some(89);
+                params.jb2_loss_level = 1;
+                params.despeckle = 2;
+            }
+            Profile::PhotoArchive => {
+                params.color = true;
+                params.crcb_mode = Some(CrcbMode::Full);
+                params.bg_subsample = 1;
+                params.decibels = None;
+                params.decibels_y = Some(45.0);
+                params.decibels_chroma = Some(42.0);
+                params.slices = Some(140);
+                params.jb2_loss_level = 0;
+                params.despeckle = 0;
+            }
+            Profile::Cjb2Clean => {
+                params.jb2_loss_level = 1;
+                params.despeckle = 0;
+                params.singletons = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::page_encoder::PageComponents;
+    use crate::{Pixel, Pixmap};
+
+    #[test]
+    fn test_profiles_encode_pages() {
+        let bg = Pixmap::from_fn(90, 60, |x, y| Pixel::new((x * 2) as u8, (y * 4) as u8, 128));
+        let page = PageComponents::new().with_background(bg).unwrap();
+
+        // Background dimensions from the first BG44 chunk header.
+        let bg_size = |profile: Profile| {
+            let data = page.encode(&profile.params(), 1, 300, 1, None).unwrap();
+            let pos = data.windows(4).position(|w| w == b"BG44").unwrap() + 8;
+            let dim = |i: usize| u16::from_be_bytes([data[pos + i], data[pos + i + 1]]);
+            (data[pos + 2] >> 7 == 1, dim(4), dim(6))
+        };
+        // The top bit of the major version marks a grayscale image.
+        assert_eq!(bg_size(Profile::BitonalText300dpi), (true, 8, 5));
+        assert_eq!(bg_size(Profile::ColorMagazine), (false, 30, 20));
+        assert_eq!(bg_size(Profile::PhotoArchive), (false, 90, 60));
+
+        let mut params = PageEncodeParams {
+            dpi: 600,
+            ..PageEncodeParams::default()
+        };
+        Profile::ColorMagazine.apply(&mut params);
+        assert_eq!(params.dpi, 600);
+        assert_eq!(params.bg_subsample, 3);
+
+        // Like `cjb2 -clean`: the CC cleaning only, no extra speck removal.
+        params.despeckle = 4;
+        Profile::Cjb2Clean.apply(&mut params);
+        assert_eq!((params.jb2_loss_level, params.despeckle), (1, 0));
+        assert_eq!(params.singletons, None);
+        assert_eq!(params.bg_subsample, 3);
+    }
+}
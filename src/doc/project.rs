@@ -0,0 +1,421 @@
+//! Declarative book builds from a TOML or JSON project file.
+//!
+//! A project file lists the pages of a document with their sources and
+//! optional hidden text, plus bookmarks, document metadata and where to
+//! write the result. Paths are relative to the project file, so a book can
+//! be rebuilt from the same file anywhere:
+//!
+//! ```toml
+//! profile = "ColorMagazine"
+//! dpi = 400
+//!
+//! [output]
+//! mode = "bundled"
+//! path = "book.djvu"
+//!
+//! [metadata]
+//! Title = "Collected Essays"
+//!
+//! [[pages]]
+//! source = "scans/cover.ppm"
+//! id = "cover.djvu"
+//! title = "Cover"
+//!
+//! [[pages]]
+//! source = "scans/p001.pbm"
+//! text = "ocr/p001.txt"
+//!
+//! [[bookmarks]]
+//! title = "Cover"
+//! dest = "#cover.djvu"
+//! ```
+//!
+//! Hidden-text files hold one word per line as `x y width height word`, in
+//! the coordinates of [`PageBuilder::with_ocr_words`]; blank lines and lines
+//! starting with `#` are ignored.
+//!
+//! Requires the `project` feature.
+
+use crate::annotations::Annotations;
+use crate::doc::batch::{load_pages, page_builder};
+use crate::doc::builder::{DjvuBuilder, DjvuDocument, Page, PageBuilder, PageIdScheme};
+use crate::doc::djvu_dir::{Bookmark, DjVmNav, check_file_name};
+use crate::doc::import::classify;
+use crate::doc::include_file::IncludeFileBuilder;
+use crate::doc::page_encoder::PageEncodeParams;
+use crate::doc::profile::Profile;
+use crate::utils::error::IoContext;
+use crate::{DjvuError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// ID of the include file that carries document metadata.
+pub const METADATA_FILE_ID: &str = "shared_anno.iff";
+
+/// A document description, as read from a project file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Project {
+    /// Preset applied on top of `params`: the settings it covers replace
+    /// those of `params`. `dpi` is applied last.
+    #[serde(default)]
+    pub profile: Option<Profile>,
+    /// Full encoder settings; missing fields take their defaults.
+    #[serde(default)]
+    pub params: Option<PageEncodeParams>,
+    #[serde(default)]
+    pub dpi: Option<u32>,
+    /// Encode pure black-and-white sources as bitonal pages.
+    #[serde(default = "default_detect_bitonal")]
+    pub detect_bitonal: bool,
+    pub output: Output,
+    pub pages: Vec<PageSpec>,
+    #[serde(default)]
+    pub bookmarks: Vec<Bookmark>,
+    /// Document metadata (`Title`, `Author`, ...), stored as shared
+    /// annotations that every page includes.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// Directory relative paths are resolved against.
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+fn default_detect_bitonal() -> bool {
+    true
+}
+
+/// Where and how the result is written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Output {
+    /// One bundled document.
+    Bundled { path: PathBuf },
+    /// One single-page document per page, named after the page ID.
+    /// Bookmarks are not written in this mode.
+    Pages { dir: PathBuf },
+}
+
+/// One page of the project.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PageSpec {
+    /// Image file (PNM, or PNG/TIFF with those features).
+    pub source: PathBuf,
+    /// Page of a multi-page source (TIFF), zero-based.
+    #[serde(default)]
+    pub frame: usize,
+    /// Directory ID; `pNNNN.djvu` when unset.
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Hidden-text file for the page.
+    #[serde(default)]
+    pub text: Option<PathBuf>,
+}
+
+impl Project {
+    /// Reads a project file, picking the format from its extension
+    /// (`.toml` or `.json`).
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).context(path.display())?;
+        let base_dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::from_toml_str(&text, base_dir),
+            Some("json") => Self::from_json_str(&text, base_dir),
+            _ => Err(DjvuError::InvalidArg(format!(
+                "Project file {} must end in .toml or .json",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Parses a TOML project; relative paths resolve against `base_dir`.
+    pub fn from_toml_str(text: &str, base_dir: impl Into<PathBuf>) -> Result<Self> {
+        let mut project: Self = toml::from_str(text)
+            .map_err(|e| DjvuError::InvalidArg(format!("Project file: {e}")))?;
+        project.base_dir = base_dir.into();
+        Ok(project)
+    }
+
+    /// Parses a JSON project; relative paths resolve against `base_dir`.
+    pub fn from_json_str(text: &str, base_dir: impl Into<PathBuf>) -> Result<Self> {
+        let mut project: Self = serde_json::from_str(text)
+            .map_err(|e| DjvuError::InvalidArg(format!("Project file: {e}")))?;
+        project.base_dir = base_dir.into();
+        Ok(project)
+    }
+
+    /// Encodes the pages into one document, with bookmarks and metadata.
+    pub fn encode(&self) -> Result<DjvuDocument> {
+        if self.pages.is_empty() {
+            return Err(DjvuError::InvalidArg("Project has no pages".to_string()));
+        }
+        let doc = self.builder(self.pages.len()).build();
+        let with_metadata = self.add_metadata(&doc)?;
+        for (page_num, spec) in self.pages.iter().enumerate() {
+            let page = self.page(page_num, spec, with_metadata)?;
+            match &spec.id {
+                Some(id) => doc.add_page_with_id(page, id, spec.title.as_deref().unwrap_or(""))?,
+                None => {
+                    doc.add_page(page)?;
+                    if let Some(title) = &spec.title {
+                        doc.set_page_title(page_num, title)?;
+                    }
+                }
+            }
+        }
+        if !self.bookmarks.is_empty() {
+            doc.set_bookmarks(DjVmNav {
+                bookmarks: self.bookmarks.clone(),
+            })?;
+        }
+        Ok(doc)
+    }
+
+    /// Builds the project and writes the output, returning the files written.
+    pub fn run(&self) -> Result<Vec<PathBuf>> {
+        match &self.output {
+            Output::Bundled { path } => {
+                let path = self.resolve(path);
+                let bytes = self.encode()?.finalize()?;
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir).context(dir.display())?;
+                }
+                std::fs::write(&path, bytes).context(path.display())?;
+                Ok(vec![path])
+            }
+            Output::Pages { dir } => {
+                for id in self.pages.iter().filter_map(|spec| spec.id.as_deref()) {
+                    check_file_name(id)?;
+                }
+                let dir = self.resolve(dir);
+                std::fs::create_dir_all(&dir).context(dir.display())?;
+                let mut written = Vec::new();
+                for (page_num, spec) in self.pages.iter().enumerate() {
+                    let doc = self.builder(1).build();
+                    let with_metadata = self.add_metadata(&doc)?;
+                    doc.add_page(self.page(0, spec, with_metadata)?)?;
+                    let name = spec
+                        .id
+                        .clone()
+                        .unwrap_or_else(|| PageIdScheme::Numbered.id(page_num));
+                    let path = dir.join(name);
+                    std::fs::write(&path, doc.finalize()?).context(path.display())?;
+                    written.push(path);
+                }
+                Ok(written)
+            }
+        }
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.base_dir.join(path)
+    }
+
+    fn builder(&self, total_pages: usize) -> DjvuBuilder {
+        let mut builder = DjvuBuilder::new(total_pages);
+        if let Some(params) = &self.params {
+            builder = builder.with_params(params.clone());
+        }
+        if let Some(profile) = self.profile {
+            builder = builder.with_profile(profile);
+        }
+        if let Some(dpi) = self.dpi {
+            builder = builder.with_dpi(dpi);
+        }
+        builder
+    }
+
+    /// Adds the metadata include file, if there is any metadata.
+    fn add_metadata(&self, doc: &DjvuDocument) -> Result<bool> {
+        if self.metadata.is_empty() {
+            return Ok(false);
+        }
+        let annotations = Annotations {
+            metadata: self
+                .metadata
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            ..Annotations::default()
+        };
+        doc.add_include_file(
+            IncludeFileBuilder::new(METADATA_FILE_ID)
+                .with_annotations(&annotations)?
+                .build()?,
+        )?;
+        Ok(true)
+    }
+
+    fn page(&self, page_num: usize, spec: &PageSpec, with_metadata: bool) -> Result<Page> {
+        let source = self.resolve(&spec.source);
+        let image = load_pages(&source)?
+            .into_iter()
+            .nth(spec.frame)
+            .ok_or_else(|| {
+                DjvuError::InvalidArg(format!("{} has no page {}", source.display(), spec.frame))
+            })?;
+        let mut page: PageBuilder =
+            page_builder(page_num, classify(&image, self.detect_bitonal), image)?;
+        if let Some(text) = &spec.text {
+            let path = self.resolve(text);
+            let text = std::fs::read_to_string(&path).context(path.display())?;
+            let words = parse_word_boxes(&text)?;
+            page = page.with_ocr_words(words);
+        }
+        if with_metadata {
+            page = page.with_include(METADATA_FILE_ID);
+        }
+        page.build()
+    }
+}
+
+/// A word and its box, as taken by [`PageBuilder::with_ocr_words`].
+pub type WordBox = (String, u16, u16, u16, u16);
+
+/// Parses a hidden-text file: `x y width height word` per line.
+pub fn parse_word_boxes(text: &str) -> Result<Vec<WordBox>> {
+    let mut words = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad = || DjvuError::InvalidArg(format!("Hidden text line {}: {line:?}", n + 1));
+        let mut fields = line.splitn(5, char::is_whitespace);
+        let mut coord =
+            || -> Result<u16> { fields.next().and_then(|f| f.parse().ok()).ok_or_else(bad) };
+        let (x, y, w, h) = (coord()?, coord()?, coord()?, coord()?);
+        let word = fields.next().map(str::trim).filter(|w| !w.is_empty());
+        words.push((word.ok_or_else(bad)?.to_string(), x, y, w, h));
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::DjVuDocEditor;
+    use crate::iff::chunk_id::ChunkId;
+
+    #[test]
+    fn test_parse_word_boxes() {
+        let words =
+            parse_word_boxes("# page 1\n10 20 30 12 Hello\n\n50 20 40 12 wide world\n").unwrap();
+        assert_eq!(
+            words,
+            [
+                ("Hello".to_string(), 10, 20, 30, 12),
+                ("wide world".to_string(), 50, 20, 40, 12)
+            ]
+        );
+        assert!(parse_word_boxes("10 20 30 Hello").is_err());
+        assert!(parse_word_boxes("10 20 30 12").is_err());
+    }
+
+    #[test]
+    fn test_project_builds_book() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("scans"))?;
+        std::fs::write(
+            dir.path().join("scans/cover.ppm"),
+            b"P3\n2 2\n255\n255 0 0  0 255 0\n0 0 255  9 9 9\n",
+        )?;
+        std::fs::write(dir.path().join("scans/p1.pbm"), b"P1\n2 2\n1 0\n0 1\n")?;
+        std::fs::write(dir.path().join("p1.txt"), b"0 0 2 2 Hi\n")?;
+        let project_file = dir.path().join("book.toml");
+        std::fs::write(
+            &project_file,
+            r##"
+dpi = 400
+
+[output]
+mode = "bundled"
+path = "out/book.djvu"
+
+[metadata]
+Title = "Test Book"
+
+[[pages]]
+source = "scans/cover.ppm"
+id = "cover.djvu"
+title = "Cover"
+
+[[pages]]
+source = "scans/p1.pbm"
+text = "p1.txt"
+
+[[bookmarks]]
+title = "Cover"
+dest = "#cover.djvu"
+"##,
+        )?;
+
+        let project = Project::load(&project_file)?;
+        assert_eq!(project.pages.len(), 2);
+        let written = project.run()?;
+        assert_eq!(written, [dir.path().join("out/book.djvu")]);
+        let bytes = std::fs::read(&written[0])?;
+        assert_eq!(&bytes[12..16], b"DJVM");
+        assert!(bytes.windows(4).any(|w| w == b"NAVM"));
+        assert!(bytes.windows(4).any(|w| w == b"DJVI"));
+        let editor = DjVuDocEditor::from_bytes(&bytes)?;
+        assert_eq!(editor.page_count(), 2);
+        assert_eq!(editor.page_index("cover.djvu"), Some(0));
+        assert_eq!(editor.page_info(0)?.dpi, 400);
+        assert!(editor.chunk(1, ChunkId::TXTz)?.is_some());
+
+        let json = r#"{"output": {"mode": "pages", "dir": "pages"},
+                       "pages": [{"source": "scans/p1.pbm"}, {"source": "scans/cover.ppm"}]}"#;
+        let written = Project::from_json_str(json, dir.path())?.run()?;
+        assert_eq!(
+            written,
+            [
+                dir.path().join("pages/p0001.djvu"),
+                dir.path().join("pages/p0002.djvu")
+            ]
+        );
+        for path in &written {
+            let page = DjVuDocEditor::from_bytes(&std::fs::read(path)?)?;
+            assert_eq!(page.page_count(), 1);
+            assert!(page.chunk(0, ChunkId::INFO)?.is_some());
+        }
+
+        // Page IDs name files, so they must stay inside the output directory.
+        let escaping = r#"{"output": {"mode": "pages", "dir": "pages"},
+                           "pages": [{"source": "scans/p1.pbm", "id": "../../x.djvu"}]}"#;
+        assert!(Project::from_json_str(escaping, dir.path())?.run().is_err());
+        assert!(!dir.path().join("../x.djvu").exists());
+
+        // The profile is applied over explicit params, so the settings it
+        // covers win.
+        let with_params = r#"{"profile": "ColorMagazine", "params": {"bg_subsample": 1},
+                              "output": {"mode": "bundled", "path": "a.djvu"},
+                              "pages": [{"source": "scans/cover.ppm"}]}"#;
+        let profile_only = r#"{"profile": "ColorMagazine",
+                               "output": {"mode": "bundled", "path": "b.djvu"},
+                               "pages": [{"source": "scans/cover.ppm"}]}"#;
+        let plain = r#"{"output": {"mode": "bundled", "path": "c.djvu"},
+                        "pages": [{"source": "scans/cover.ppm"}]}"#;
+        let encoded = |json| -> Result<Vec<u8>> {
+            Project::from_json_str(json, dir.path())?
+                .encode()?
+                .finalize()
+        };
+        assert_eq!(encoded(with_params)?, encoded(profile_only)?);
+        assert_ne!(encoded(profile_only)?, encoded(plain)?);
+
+        assert!(
+            Project::from_json_str(
+                r#"{"output": {"mode": "pages", "dir": "x"}, "pages": [], "bogus": 1}"#,
+                ""
+            )
+            .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+//! What to do when a page fails to encode.
+//!
+//! Batch conversions of thousands of scans should not lose all their work to
+//! one unreadable page. [`ErrorRecoveryAction`] selects whether a failure
+//! aborts, drops the page, or replaces it with a blank one; every recovered
+//! failure is recorded in [`EncodeStats`] so the caller can report it.
+//! Links to pages missing from the finished document and pages found blank
+//! (see [`crate::doc::blank`]) are listed there too.
+
+use crate::doc::links::BrokenLink;
+use crate::encode::jb2::DictStats;
+
+/// Policy applied to a page whose encoding failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorRecoveryAction {
+    /// Return the error to the caller.
+    #[default]
+    Abort,
+    /// Leave the page out of the document.
+    SkipPage,
+    /// Insert a blank page of the same size in its place.
+    Placeholder,
+}
+
+/// One page failure that was recovered from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageFailure {
+    pub page_num: usize,
+    /// What was done about it (never [`ErrorRecoveryAction::Abort`]).
+    pub action: ErrorRecoveryAction,
+    /// The encoder error, as text.
+    pub error: String,
+}
+
+/// Counters collected while pages are added to a document.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncodeStats {
+    /// Pages encoded and added normally.
+    pub pages_encoded: usize,
+    /// Recovered failures, in the order they happened.
+    pub failures: Vec<PageFailure>,
+    /// Page links whose target is not in the document, found when it was
+    /// last finalized.
+    pub broken_links: Vec<BrokenLink>,
+    /// Pages found blank, whatever the [`BlankPageAction`] did with them.
+    ///
+    /// [`BlankPageAction`]: crate::doc::BlankPageAction
+    pub blank_pages: Vec<usize>,
+    /// JB2 blits of the added pages, and how many of them the symbol
+    /// dictionary covered.
+    pub symbols: DictStats,
+}
+
+impl EncodeStats {
+    /// Number of pages left out of the document.
+    pub fn pages_skipped(&self) -> usize {
+        self.count(ErrorRecoveryAction::SkipPage)
+    }
+
+    /// Number of pages replaced by a blank placeholder.
+    pub fn pages_substituted(&self) -> usize {
+        self.count(ErrorRecoveryAction::Placeholder)
+    }
+
+    fn count(&self, action: ErrorRecoveryAction) -> usize {
+        self.failures.iter().filter(|f| f.action == action).count()
+    }
+}
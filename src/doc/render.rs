@@ -0,0 +1,211 @@
+//! Rasterization of pages for previews, thumbnails and tests.
+//!
+//! The crate has no decoders yet, so rendering works from the layers a page
+//! is built from rather than from an encoded file: [`render_page`] composites
+//! a [`PageComponents`] the way a viewer composites the chunks the encoder
+//! writes for it. The JB2 layer is chosen with the same priority the encoder
+//! uses (manual blits, then foreground, then mask) and is drawn in black,
+//! the color decoders give a JB2 layer without `FGbz` or `FG44`. The colors
+//! of [`PageComponents::fg_colors`] and [`PageComponents::fg_palette`] are
+//! not drawn, so colored text renders black.
+
+use crate::doc::page_encoder::PageComponents;
+use crate::encode::jb2::symbol_dict::BitImage;
+use crate::image::image_formats::{Pixel, Pixmap};
+use crate::{DjvuError, Result};
+
+/// Which layers of the page to draw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenderMode {
+    /// Background with the JB2 layer composited over it.
+    #[default]
+    Color,
+    /// The JB2 layer alone, black on white.
+    Bitonal,
+    /// The background alone (white if the page has none).
+    Background,
+}
+
+/// Renders `page` at `zoom` (1.0 = full resolution) in the given mode.
+///
+/// Downscaling averages the covered source pixels, so thin strokes stay
+/// visible in thumbnails; upscaling repeats pixels.
+pub fn render_page(page: &PageComponents, zoom: f32, mode: RenderMode) -> Result<Pixmap> {
+    let (width, height) = page.dimensions();
+    if width == 0 || height == 0 {
+        return Err(DjvuError::InvalidOperation(
+            "Cannot render a page without dimensions".to_string(),
+        ));
+    }
+    if !zoom.is_finite() || zoom <= 0.0 {
+        return Err(DjvuError::InvalidArg(format!("Invalid zoom factor {zoom}")));
+    }
+
+    let full = composite(page, mode)?;
+    let out_w = ((width as f32 * zoom).round() as u32).max(1);
+    let out_h = ((height as f32 * zoom).round() as u32).max(1);
+    if (out_w, out_h) == (width, height) {
+        return Ok(full);
+    }
+    Ok(resample(&full, out_w, out_h))
+}
+
+/// Builds the full-resolution page image for `mode`.
+fn composite(page: &PageComponents, mode: RenderMode) -> Result<Pixmap> {
+    let (width, height) = page.dimensions();
+    let mut canvas = match (&page.background, mode) {
+        // A subsampled background is scaled up to the page.
+        (Some(bg), RenderMode::Color | RenderMode::Background)
+            if bg.dimensions() != (width, height) =>
+        {
+            resample(bg, width, height)
+        }
+        (Some(bg), RenderMode::Color | RenderMode::Background) => bg.clone(),
+        _ => Pixmap::from_pixel(width, height, Pixel::white()),
+    };
+    if mode == RenderMode::Background {
+        return Ok(canvas);
+    }
+
+    if let Some(mask) = jb2_layer(page)? {
+        for y in 0..height.min(mask.height as u32) {
+            for x in 0..width.min(mask.width as u32) {
+                if mask.get_pixel_unchecked(x as usize, y as usize) {
+                    canvas.put_pixel(x, y, Pixel::black());
+                }
+            }
+        }
+    }
+    Ok(canvas)
+}
+
+/// Returns the bilevel layer the encoder would emit as `Sjbz`, in page
+/// coordinates.
+fn jb2_layer(page: &PageComponents) -> Result<Option<BitImage>> {
+    let (Some(shapes), Some(blits)) = (&page.jb2_shapes, &page.jb2_blits) else {
+        return Ok(page.foreground.clone().or_else(|| page.mask.clone()));
+    };
+    let (width, height) = page.dimensions();
+    let shapes: Vec<&BitImage> = shapes.iter().collect();
+    draw_blits(width, height, &shapes, blits).map(Some)
+}
+
+/// Draws `shapes` where `blits` place them on a `width` by `height` page.
+pub(crate) fn draw_blits(
+    width: u32,
+    height: u32,
+    shapes: &[&BitImage],
+    blits: &[(i32, i32, usize)],
+) -> Result<BitImage> {
+    let mut layer = BitImage::new(width, height)
+        .map_err(|e| DjvuError::InvalidOperation(format!("Failed to allocate JB2 layer: {e}")))?;
+    for &(left, bottom, index) in blits {
+        let shape = shapes.get(index).ok_or_else(|| {
+            DjvuError::ValidationError(format!(
+                "Blit references shape {index}, but only {} shapes exist",
+                shapes.len()
+            ))
+        })?;
+        // Blits are in DjVu coordinates: origin at the bottom-left corner.
+        let top = height as i64 - bottom as i64 - shape.height as i64;
+        for sy in 0..shape.height {
+            let py = top + sy as i64;
+            if py < 0 || py >= height as i64 {
+                continue;
+            }
+            for sx in 0..shape.width {
+                let px = left as i64 + sx as i64;
+                if px >= 0 && px < width as i64 && shape.get_pixel_unchecked(sx, sy) {
+                    layer.set_usize(px as usize, py as usize, true);
+                }
+            }
+        }
+    }
+    Ok(layer)
+}
+
+/// Resizes `src` by box averaging when shrinking and pixel replication when
+/// enlarging.
+fn resample(src: &Pixmap, out_w: u32, out_h: u32) -> Pixmap {
+    let (sw, sh) = (src.width() as u64, src.height() as u64);
+    let (ow, oh) = (out_w as u64, out_h as u64);
+    Pixmap::from_fn(out_w, out_h, |x, y| {
+        let x0 = x as u64 * sw / ow;
+        let x1 = ((x as u64 + 1) * sw / ow).max(x0 + 1);
+        let y0 = y as u64 * sh / oh;
+        let y1 = ((y as u64 + 1) * sh / oh).max(y0 + 1);
+        let (mut r, mut g, mut b) = (0u64, 0u64, 0u64);
+        for sy in y0..y1 {
+            for sx in x0..x1 {
+                let p = src.get_pixel(sx as u32, sy as u32);
+                r += p.r as u64;
+                g += p.g as u64;
+                b += p.b as u64;
+            }
+        }
+        let n = (x1 - x0) * (y1 - y0);
+        Pixel::new(
+            ((r + n / 2) / n) as u8,
+            ((g + n / 2) / n) as u8,
+            ((b + n / 2) / n) as u8,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compound_page() -> PageComponents {
+        let bg = Pixmap::from_pixel(8, 8, Pixel::new(200, 100, 50));
+        let mut mask = BitImage::new(8, 8).unwrap();
+        for x in 0..8 {
+            mask.set_usize(x, 0, true);
+        }
+        PageComponents::new()
+            .with_background(bg)
+            .unwrap()
+            .with_mask(mask)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_render_modes() {
+        let page = compound_page();
+
+        let color = render_page(&page, 1.0, RenderMode::Color).unwrap();
+        assert_eq!(color.get_pixel(3, 0), Pixel::black());
+        assert_eq!(color.get_pixel(3, 5), Pixel::new(200, 100, 50));
+
+        let bitonal = render_page(&page, 1.0, RenderMode::Bitonal).unwrap();
+        assert_eq!(bitonal.get_pixel(3, 0), Pixel::black());
+        assert_eq!(bitonal.get_pixel(3, 5), Pixel::white());
+
+        let background = render_page(&page, 1.0, RenderMode::Background).unwrap();
+        assert_eq!(background.get_pixel(3, 0), Pixel::new(200, 100, 50));
+    }
+
+    #[test]
+    fn test_render_manual_blits_use_bottom_left_origin() {
+        let mut dot = BitImage::new(1, 1).unwrap();
+        dot.set_usize(0, 0, true);
+        // (left = 2, bottom = 0) is the bottom row of a 4-pixel-high page.
+        let page =
+            PageComponents::new_with_dimensions(4, 4).with_jb2_manual(vec![dot], vec![(2, 0, 0)]);
+
+        let out = render_page(&page, 1.0, RenderMode::Bitonal).unwrap();
+        assert_eq!(out.get_pixel(2, 3), Pixel::black());
+        assert_eq!(out.get_pixel(2, 0), Pixel::white());
+    }
+
+    #[test]
+    fn test_render_zoom_averages() {
+        let out = render_page(&compound_page(), 0.25, RenderMode::Bitonal).unwrap();
+        assert_eq!(out.dimensions(), (2, 2));
+        // Top-left 4x4 block has one black row out of four.
+        assert_eq!(out.get_pixel(0, 0), Pixel::new(191, 191, 191));
+        assert_eq!(out.get_pixel(0, 1), Pixel::white());
+
+        assert!(render_page(&compound_page(), 0.0, RenderMode::Color).is_err());
+    }
+}
@@ -0,0 +1,428 @@
+//! djvused-style editing scripts.
+//!
+//! DjVuLibre's `djvused` edits documents with small scripts, and archives
+//! have automation built around them. [`Script`] runs a subset of its
+//! commands against a [`DjVuDocEditor`], so such scripts work without
+//! DjVuLibre installed:
+//!
+//! | Command | Effect |
+//! |---|---|
+//! | `select [page]` | Selects a page by number, from 1, or by component ID; the whole document without an argument |
+//! | `set-meta [file]` | Replaces the metadata of the selected page with `key "value"` lines |
+//! | `set-txt [file]` | Replaces the hidden text of the selected page, in the format of `print-txt` |
+//! | `set-ant [file]` | Replaces the annotations of the selected page |
+//! | `remove-txt` | Removes the hidden text of the selected page, or of every page |
+//! | `save-bundled file` | Writes the document to `file` |
+//!
+//! Commands are separated by newlines or `;`, and `#` starts a comment.
+//! The `set-*` commands read the named file or, without one, the script
+//! lines that follow, up to a line holding only `.`, as in scripts given
+//! to djvused with `-f`. They need a page selected, unless the document
+//! has only one: the shared annotations djvused edits with the whole
+//! document selected are not supported. Other djvused commands are
+//! rejected when the script is parsed.
+//!
+//! ```ignore
+//! let script = Script::parse("select 2; remove-txt; save-bundled out.djvu")?;
+//! let mut editor = DjVuDocEditor::from_bytes(&std::fs::read("in.djvu")?)?;
+//! script.run(&mut editor)?;
+//! ```
+
+use crate::annotations::{HiddenText, annotations::replace_metadata};
+use crate::doc::editor::DjVuDocEditor;
+use crate::iff::bs_byte_stream::{bzz_compress, bzz_decompress};
+use crate::iff::chunk_id::ChunkId;
+use crate::utils::error::IoContext;
+use crate::{DjvuError, Result};
+use std::io;
+use std::path::PathBuf;
+
+/// Where a `set-*` command reads its data from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptInput {
+    /// The script lines after the command, up to a line holding only `.`.
+    Inline(String),
+    /// A file, named relative to the current directory.
+    File(PathBuf),
+}
+
+impl ScriptInput {
+    fn read(&self) -> Result<String> {
+        match self {
+            Self::Inline(text) => Ok(text.clone()),
+            Self::File(path) => std::fs::read_to_string(path).context(path.display()),
+        }
+    }
+}
+
+/// A command of the subset of djvused that [`Script`] runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptCommand {
+    /// `select [page]`
+    Select(Option<String>),
+    /// `set-meta [file]`
+    SetMeta(ScriptInput),
+    /// `set-txt [file]`
+    SetTxt(ScriptInput),
+    /// `set-ant [file]`
+    SetAnt(ScriptInput),
+    /// `remove-txt`
+    RemoveTxt,
+    /// `save-bundled file`
+    SaveBundled(PathBuf),
+}
+
+/// A parsed djvused script.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    /// The commands, with the line each starts on, from 1.
+    commands: Vec<(usize, ScriptCommand)>,
+}
+
+/// What the commands of a script apply to.
+#[derive(Clone, Copy)]
+enum Selection {
+    Document,
+    Page(usize),
+}
+
+impl Script {
+    /// Parses `text`, reporting the first error with its line number.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut commands = Vec::new();
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+        while let Some((number, line)) = lines.next() {
+            let parsed = split_commands(line).map_err(|e| syntax(number, &e))?;
+            let count = parsed.len();
+            for (i, words) in parsed.into_iter().enumerate() {
+                let last = i + 1 == count;
+                let mut input = || -> Result<ScriptInput> {
+                    match words.get(1) {
+                        Some(path) => Ok(ScriptInput::File(PathBuf::from(path))),
+                        None if last => inline_block(&mut lines)
+                            .map(ScriptInput::Inline)
+                            .ok_or_else(|| syntax(number, "data is not ended by a `.` line")),
+                        None => Err(syntax(number, "inline data must end the line")),
+                    }
+                };
+                let command = match words[0].as_str() {
+                    "select" => ScriptCommand::Select(words.get(1).cloned()),
+                    "set-meta" => ScriptCommand::SetMeta(input()?),
+                    "set-txt" => ScriptCommand::SetTxt(input()?),
+                    "set-ant" => ScriptCommand::SetAnt(input()?),
+                    "remove-txt" => ScriptCommand::RemoveTxt,
+                    "save-bundled" => match words.get(1) {
+                        Some(path) => ScriptCommand::SaveBundled(PathBuf::from(path)),
+                        None => return Err(syntax(number, "save-bundled needs a file name")),
+                    },
+                    other => {
+                        return Err(syntax(number, &format!("unsupported command {other:?}")));
+                    }
+                };
+                commands.push((number, command));
+            }
+        }
+        Ok(Self { commands })
+    }
+
+    /// The commands, in order.
+    pub fn commands(&self) -> impl Iterator<Item = &ScriptCommand> {
+        self.commands.iter().map(|(_, command)| command)
+    }
+
+    /// Runs the script on `editor`, starting with the whole document
+    /// selected. Stops at the first failing command, naming its line;
+    /// what the commands before it did stays done.
+    pub fn run(&self, editor: &mut DjVuDocEditor) -> Result<()> {
+        let mut selection = Selection::Document;
+        for (line, command) in &self.commands {
+            run_command(editor, command, &mut selection).map_err(|e| at_line(*line, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn run_command(
+    editor: &mut DjVuDocEditor,
+    command: &ScriptCommand,
+    selection: &mut Selection,
+) -> Result<()> {
+    let page = |selection: Selection, editor: &DjVuDocEditor| match selection {
+        Selection::Page(page) => Ok(page),
+        Selection::Document if editor.page_count() == 1 => Ok(0),
+        Selection::Document => Err(DjvuError::InvalidOperation(
+            "No page selected; shared annotations are not supported".to_string(),
+        )),
+    };
+    match command {
+        ScriptCommand::Select(None) => *selection = Selection::Document,
+        ScriptCommand::Select(Some(name)) => {
+            let count = editor.page_count();
+            let page = match name.parse::<usize>() {
+                Ok(number) if (1..=count).contains(&number) => number - 1,
+                Ok(number) => {
+                    return Err(DjvuError::InvalidArg(format!(
+                        "Page {number} out of range 1..={count}"
+                    )));
+                }
+                Err(_) => editor
+                    .page_index(name)
+                    .ok_or_else(|| DjvuError::InvalidArg(format!("No page with ID {name:?}")))?,
+            };
+            *selection = Selection::Page(page);
+        }
+        ScriptCommand::SetMeta(input) => {
+            let page = page(*selection, editor)?;
+            let metadata = parse_metadata(&input.read()?)?;
+            let old = match (
+                editor.chunk(page, ChunkId::ANTz)?,
+                editor.chunk(page, ChunkId::ANTa)?,
+            ) {
+                (Some(compressed), _) => bzz_decompress(compressed)?,
+                (None, Some(plain)) => plain.to_vec(),
+                (None, None) => Vec::new(),
+            };
+            let text = replace_metadata(&old, &metadata);
+            set_annotations(editor, page, &text)?;
+        }
+        ScriptCommand::SetTxt(input) => {
+            let page = page(*selection, editor)?;
+            let text = input.read()?;
+            if text.trim().is_empty() {
+                remove_text(editor, page)?;
+            } else {
+                let hidden = HiddenText::from_djvused(&text)
+                    .map_err(|e| DjvuError::ValidationError(e.to_string()))?;
+                let mut raw = Vec::new();
+                hidden
+                    .encode(&mut raw)
+                    .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+                let data = bzz_compress(&raw, 100)?;
+                let ids = [ChunkId::TXTa, ChunkId::TXTz];
+                put_chunk(editor, page, &ids, ChunkId::TXTz, data, &ANNOTATION_CHUNKS)?;
+            }
+        }
+        ScriptCommand::SetAnt(input) => {
+            let page = page(*selection, editor)?;
+            set_annotations(editor, page, input.read()?.as_bytes())?;
+        }
+        ScriptCommand::RemoveTxt => match *selection {
+            Selection::Page(page) => remove_text(editor, page)?,
+            Selection::Document => {
+                for page in 0..editor.page_count() {
+                    remove_text(editor, page)?;
+                }
+            }
+        },
+        ScriptCommand::SaveBundled(path) => {
+            std::fs::write(path, editor.to_bytes()?).context(path.display())?;
+        }
+    }
+    Ok(())
+}
+
+const ANNOTATION_CHUNKS: [ChunkId; 2] = [ChunkId::ANTa, ChunkId::ANTz];
+
+/// Stores `text` as the annotations of `page`, or removes them if it is
+/// blank.
+fn set_annotations(editor: &mut DjVuDocEditor, page: usize, text: &[u8]) -> Result<()> {
+    if text.iter().all(u8::is_ascii_whitespace) {
+        for id in ANNOTATION_CHUNKS {
+            editor.remove_chunks(page, id)?;
+        }
+        return Ok(());
+    }
+    let data = bzz_compress(text, 100)?;
+    put_chunk(editor, page, &ANNOTATION_CHUNKS, ChunkId::ANTz, data, &[])
+}
+
+fn remove_text(editor: &mut DjVuDocEditor, page: usize) -> Result<()> {
+    editor.remove_chunks(page, ChunkId::TXTa)?;
+    editor.remove_chunks(page, ChunkId::TXTz)?;
+    Ok(())
+}
+
+/// Replaces the chunks `old` of `page` by chunk `id`, where the first of
+/// them was; without any, `id` goes before the first chunk of `before`, or
+/// last.
+fn put_chunk(
+    editor: &mut DjVuDocEditor,
+    page: usize,
+    old: &[ChunkId],
+    id: ChunkId,
+    data: Vec<u8>,
+    before: &[ChunkId],
+) -> Result<()> {
+    let ids = editor.chunk_ids(page)?;
+    let at = ids
+        .iter()
+        .position(|id| old.contains(id))
+        .or_else(|| ids.iter().position(|id| before.contains(id)))
+        .unwrap_or(ids.len());
+    for &id in old {
+        editor.remove_chunks(page, id)?;
+    }
+    editor.insert_chunk(page, at, id, data)
+}
+
+/// Reads the lines of `set-meta` data: a key, then its value, quoted or
+/// not.
+fn parse_metadata(text: &str) -> Result<Vec<(String, String)>> {
+    let mut metadata = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = value.trim();
+        let value = match value.strip_prefix('"') {
+            Some(quoted) => unquote(&mut quoted.chars()).ok_or_else(|| {
+                DjvuError::ValidationError(format!("Unterminated value for {key}"))
+            })?,
+            None => value.to_string(),
+        };
+        metadata.push((key.to_string(), value));
+    }
+    Ok(metadata)
+}
+
+/// Reads a quoted string from `chars`, which follow its opening quote, up
+/// to the closing quote; `None` if there is none.
+fn unquote(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut out = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(out),
+            '\\' => match chars.next()? {
+                'n' => out.push('\n'),
+                't' => out.push('\t'),
+                other => out.push(other),
+            },
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+/// Splits a script line into commands, each a list of words, dropping a
+/// trailing comment. Words may be quoted.
+fn split_commands(line: &str) -> std::result::Result<Vec<Vec<String>>, String> {
+    let mut commands = Vec::new();
+    let mut words: Vec<String> = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.peek() {
+            None | Some('#') => break,
+            Some(';') => {
+                chars.next();
+                if !words.is_empty() {
+                    commands.push(std::mem::take(&mut words));
+                }
+            }
+            Some('"') => {
+                chars.next();
+                words.push(unquote(&mut chars).ok_or("unterminated string")?);
+            }
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != ';') {
+                    word.push(c);
+                }
+                words.push(word);
+            }
+        }
+    }
+    if !words.is_empty() {
+        commands.push(words);
+    }
+    Ok(commands)
+}
+
+/// Takes the lines up to one holding only `.`, or `None` if there is no
+/// such line.
+fn inline_block<'a>(lines: &mut impl Iterator<Item = (usize, &'a str)>) -> Option<String> {
+    let mut block = String::new();
+    for (_, line) in lines {
+        if line.trim_end() == "." {
+            return Some(block);
+        }
+        block.push_str(line);
+        block.push('\n');
+    }
+    None
+}
+
+fn syntax(line: usize, message: &str) -> DjvuError {
+    DjvuError::InvalidArg(format!("Script line {line}: {message}"))
+}
+
+/// Names the script line that failed in `error`.
+fn at_line(line: usize, error: DjvuError) -> DjvuError {
+    let prefix = |message: String| format!("script line {line}: {message}");
+    match error {
+        DjvuError::Io(err) => DjvuError::Io(io::Error::new(err.kind(), prefix(err.to_string()))),
+        DjvuError::InvalidArg(m) => DjvuError::InvalidArg(prefix(m)),
+        DjvuError::InvalidOperation(m) => DjvuError::InvalidOperation(prefix(m)),
+        DjvuError::ValidationError(m) => DjvuError::ValidationError(prefix(m)),
+        DjvuError::Stream(m) => DjvuError::Stream(prefix(m)),
+        DjvuError::Custom(m) => DjvuError::Custom(prefix(m)),
+        DjvuError::EncodingError(m) => DjvuError::EncodingError(prefix(m)),
+        DjvuError::SymbolOutOfRange(m) => DjvuError::SymbolOutOfRange(prefix(m)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::{DjvuBuilder, PageBuilder};
+    use crate::image::image_formats::{Pixel, Pixmap};
+
+    #[test]
+    fn test_run_djvused_script() -> Result<()> {
+        let doc = DjvuBuilder::new(2).build();
+        for page_num in 0..2 {
+            let page = PageBuilder::new(page_num, 40, 20)
+                .with_background(Pixmap::from_pixel(40, 20, Pixel::white()))?
+                .with_ocr_words(vec![("old".to_string(), 2, 2, 10, 6)])
+                .build()?;
+            doc.add_page_with_id(page, format!("p{page_num}.djvu"), "")?;
+        }
+        let mut editor = DjVuDocEditor::from_bytes(&doc.finalize()?)?;
+
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("out file.djvu");
+        let script = format!(
+            "# retext the second page\n\
+             select p1.djvu; set-txt\n\
+             (page 0 0 40 20\n  (line 2 2 30 10 (word 2 2 12 10 \"new\") (word 14 2 30 10 \"w\\303\\266rds\")))\n\
+             .\n\
+             set-meta\n\
+             Title \"A \\\"quoted\\\" title\"\n\
+             .\n\
+             select 1 ; remove-txt\n\
+             save-bundled \"{}\"\n",
+            out.display()
+        );
+        let script = Script::parse(&script)?;
+        assert_eq!(script.commands().count(), 6);
+        script.run(&mut editor)?;
+
+        let saved = DjVuDocEditor::from_bytes(&std::fs::read(&out)?)?;
+        assert!(saved.chunk(0, ChunkId::TXTz)?.is_none());
+        let text = HiddenText::decode(&bzz_decompress(saved.chunk(1, ChunkId::TXTz)?.unwrap())?)
+            .map_err(|e| DjvuError::ValidationError(e.to_string()))?;
+        let line = &text.root_zone.children[0];
+        assert_eq!(line.children[1].text.as_deref(), Some("wörds"));
+        let annotations = bzz_decompress(saved.chunk(1, ChunkId::ANTz)?.unwrap())?;
+        assert_eq!(annotations, br#"(metadata (Title "A \"quoted\" title"))"#);
+
+        // Errors name their line; a failing command stops the script.
+        let err = Script::parse("select 1\nprint-pure-txt").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{err}");
+        assert!(Script::parse("set-ant\n(background #fff)").is_err());
+        let err = Script::parse("select\nset-ant\n.")?
+            .run(&mut editor)
+            .unwrap_err();
+        assert!(matches!(err, DjvuError::InvalidOperation(ref m) if m.contains("line 2")));
+        assert!(Script::parse("select 3")?.run(&mut editor).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,381 @@
+//! Word-level search index export.
+//!
+//! [`SearchIndex::from_document`] reads the hidden text (`TXTz`/`TXTa`) of
+//! every page of an encoded document and maps each word to the places it
+//! occurs, so a web viewer can offer full-text search from a small JSON file
+//! instead of decoding text layers at runtime. With the `sqlite` feature the
+//! index can also be written as an SQLite database.
+//!
+//! Words are lowercased and stripped of surrounding punctuation. Rectangles
+//! are in pixels of the stored page image with a top-left origin; pages
+//! shown rotated record their rotation so the viewer can turn them.
+
+use crate::annotations::hidden_text::{HiddenText, Zone, ZoneKind};
+use crate::doc::page_encoder::{PageInfo, PageRotation, Rect};
+use crate::iff::bs_byte_stream::bzz_decompress;
+use crate::{DjvuError, Result};
+use std::collections::BTreeMap;
+
+/// A page of the indexed document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexedPage {
+    pub width: u32,
+    pub height: u32,
+    pub rotation: PageRotation,
+}
+
+/// One occurrence of a word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WordHit {
+    /// Zero-based page number.
+    pub page: usize,
+    pub rect: Rect,
+}
+
+/// Words of a document and where they occur.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchIndex {
+    pub pages: Vec<IndexedPage>,
+    /// Occurrences of each normalized word, in page and reading order.
+    pub words: BTreeMap<String, Vec<WordHit>>,
+}
+
+impl SearchIndex {
+    /// Indexes a single-page `DJVU` or bundled `DJVM` document. Pages
+    /// without hidden text are listed but contribute no words.
+    pub fn from_document(data: &[u8]) -> Result<Self> {
+        let form = if data.starts_with(b"AT&T") { 4 } else { 0 };
+        let mut index = Self::default();
+        match data.get(form..form + 12) {
+            Some([b'F', b'O', b'R', b'M', _, _, _, _, b'D', b'J', b'V', b'U']) => {
+                index.add_page(form_body(data, form)?)?;
+            }
+            Some([b'F', b'O', b'R', b'M', _, _, _, _, b'D', b'J', b'V', b'M']) => {
+                for (id, chunk) in chunks(form_body(data, form)?) {
+                    if id == b"FORM" && chunk.get(..4) == Some(b"DJVU") {
+                        index.add_page(&chunk[4..])?;
+                    }
+                }
+            }
+            _ => {
+                return Err(DjvuError::InvalidArg(
+                    "Not a DJVU or DJVM document".to_string(),
+                ));
+            }
+        }
+        Ok(index)
+    }
+
+    fn add_page(&mut self, body: &[u8]) -> Result<()> {
+        let page = self.pages.len();
+        let mut info = IndexedPage {
+            width: 0,
+            height: 0,
+            rotation: PageRotation::Upright,
+        };
+        let mut text = None;
+        for (id, chunk) in chunks(body) {
+            match id {
+                b"INFO" => {
+                    if let Some(page_info) = PageInfo::parse(chunk) {
+                        info.width = page_info.width as u32;
+                        info.height = page_info.height as u32;
+                        info.rotation = page_info.rotation;
+                    }
+                }
+                b"TXTz" => text = Some(bzz_decompress(chunk)?),
+                b"TXTa" => text = Some(chunk.to_vec()),
+                _ => {}
+            }
+        }
+        self.pages.push(info);
+
+        if let Some(text) = text {
+            let text = HiddenText::decode(&text)
+                .map_err(|e| DjvuError::ValidationError(format!("Page {}: {e}", page + 1)))?;
+            self.add_zone(page, info.height, &text.root_zone);
+        }
+        Ok(())
+    }
+
+    fn add_zone(&mut self, page: usize, page_height: u32, zone: &Zone) {
+        let is_word = zone.kind == ZoneKind::Word;
+        if !is_word && !zone.children.is_empty() {
+            for child in &zone.children {
+                self.add_zone(page, page_height, child);
+            }
+            return;
+        }
+        let b = zone.bbox;
+        let rect = Rect::new(
+            b.x as u32,
+            page_height.saturating_sub(b.y as u32 + b.h as u32),
+            b.w as u32,
+            b.h as u32,
+        );
+        // A word split into characters keeps its text in them; a leaf above
+        // word level may hold several words, all given its rectangle.
+        let mut text = String::new();
+        collect_text(zone, &mut text);
+        let words: Vec<&str> = if is_word {
+            vec![&text]
+        } else {
+            text.split_whitespace().collect()
+        };
+        for word in words {
+            if let Some(word) = normalize(word) {
+                self.words
+                    .entry(word)
+                    .or_default()
+                    .push(WordHit { page, rect });
+            }
+        }
+    }
+
+    /// The occurrences of `word`, normalized like the index.
+    pub fn lookup(&self, word: &str) -> &[WordHit] {
+        normalize(word)
+            .and_then(|w| self.words.get(&w))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Compact JSON form:
+    ///
+    /// ```text
+    /// {"pages":[{"width":2550,"height":3300,"rotation":0}, ...],
+    ///  "words":{"hello":[[page,x,y,w,h], ...], ...}}
+    /// ```
+    ///
+    /// `rotation` is in degrees counter-clockwise.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"pages\":[");
+        for (i, page) in self.pages.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out += &format!(
+                "{{\"width\":{},\"height\":{},\"rotation\":{}}}",
+                page.width,
+                page.height,
+                page.rotation.degrees()
+            );
+        }
+        out += "],\"words\":{";
+        for (i, (word, hits)) in self.words.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            push_json_string(&mut out, word);
+            out.push_str(":[");
+            for (j, hit) in hits.iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                let r = hit.rect;
+                out += &format!("[{},{},{},{},{}]", hit.page, r.x, r.y, r.width, r.height);
+            }
+            out.push(']');
+        }
+        out.push_str("}}");
+        out
+    }
+
+    /// Writes the index to a new SQLite database at `path`, replacing any
+    /// file there, with tables
+    /// `pages(page, width, height, rotation)` and
+    /// `words(word, page, x, y, width, height)` (indexed by `word`).
+    #[cfg(feature = "sqlite")]
+    pub fn write_sqlite(&self, path: impl AsRef<std::path::Path>) -> Result<()> {
+        sqlite::write(self, path.as_ref())
+    }
+}
+
+fn collect_text(zone: &Zone, out: &mut String) {
+    if let Some(text) = &zone.text {
+        out.push_str(text);
+    }
+    for child in &zone.children {
+        collect_text(child, out);
+    }
+}
+
+/// Lowercases `word` and strips the punctuation around it.
+fn normalize(word: &str) -> Option<String> {
+    let word = word.trim_matches(|c: char| !c.is_alphanumeric());
+    (!word.is_empty()).then(|| word.to_lowercase())
+}
+
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// The chunks of a form body (after its secondary ID), skipping padding.
+fn chunks(body: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let header = body.get(pos..pos + 8)?;
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let data = body.get(pos + 8..(pos + 8 + len).min(body.len()))?;
+        pos += 8 + len + (len & 1);
+        Some((&header[..4], data))
+    })
+}
+
+/// The body of the `FORM` whose header starts at `form`, after its
+/// secondary ID.
+fn form_body(data: &[u8], form: usize) -> Result<&[u8]> {
+    let len = u32::from_be_bytes([
+        data[form + 4],
+        data[form + 5],
+        data[form + 6],
+        data[form + 7],
+    ]) as usize;
+    data.get(form + 12..(form + 8 + len).min(data.len()))
+        .ok_or_else(|| DjvuError::ValidationError("Truncated FORM chunk".to_string()))
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::SearchIndex;
+    use crate::utils::error::IoContext;
+    use crate::{DjvuError, Result};
+    use rusqlite::{Connection, params};
+    use std::path::Path;
+
+    fn error(e: rusqlite::Error) -> DjvuError {
+        DjvuError::Stream(format!("SQLite: {e}"))
+    }
+
+    pub(super) fn write(index: &SearchIndex, path: &Path) -> Result<()> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).context(path.display());
+            }
+            _ => {}
+        }
+        let mut db = Connection::open(path).map_err(error)?;
+        let tx = db.transaction().map_err(error)?;
+        tx.execute_batch(
+            "CREATE TABLE pages(page INTEGER PRIMARY KEY, width INTEGER, height INTEGER,
+                                rotation INTEGER);
+             CREATE TABLE words(word TEXT NOT NULL, page INTEGER, x INTEGER, y INTEGER,
+                                width INTEGER, height INTEGER);",
+        )
+        .map_err(error)?;
+        {
+            let mut pages = tx
+                .prepare("INSERT INTO pages VALUES (?, ?, ?, ?)")
+                .map_err(error)?;
+            for (i, page) in index.pages.iter().enumerate() {
+                pages
+                    .execute(params![
+                        i as i64,
+                        page.width,
+                        page.height,
+                        page.rotation.degrees()
+                    ])
+                    .map_err(error)?;
+            }
+            let mut words = tx
+                .prepare("INSERT INTO words VALUES (?, ?, ?, ?, ?, ?)")
+                .map_err(error)?;
+            for (word, hits) in &index.words {
+                for hit in hits {
+                    let r = hit.rect;
+                    words
+                        .execute(params![word, hit.page as i64, r.x, r.y, r.width, r.height])
+                        .map_err(error)?;
+                }
+            }
+        }
+        tx.execute_batch("CREATE INDEX words_word ON words(word);")
+            .map_err(error)?;
+        tx.commit().map_err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::{DjvuBuilder, PageBuilder};
+    use crate::image::image_formats::{Pixel, Pixmap};
+
+    fn document() -> Vec<u8> {
+        let doc = DjvuBuilder::new(3).build();
+        let words = [
+            vec![("Hello,".to_string(), 10, 20, 50, 12)],
+            vec![],
+            vec![
+                ("hello".to_string(), 5, 40, 30, 10),
+                ("\"World\"".to_string(), 40, 40, 35, 10),
+            ],
+        ];
+        for (page_num, words) in words.into_iter().enumerate() {
+            let mut page = PageBuilder::new(page_num, 120, 80)
+                .with_background(Pixmap::from_pixel(120, 80, Pixel::new(200, 210, 220)))
+                .unwrap();
+            if !words.is_empty() {
+                page = page.with_ocr_words(words);
+            }
+            doc.add_page(page.build().unwrap()).unwrap();
+        }
+        doc.finalize().unwrap()
+    }
+
+    #[test]
+    fn test_index_document() -> Result<()> {
+        let index = SearchIndex::from_document(&document())?;
+        assert_eq!(index.pages.len(), 3);
+        assert_eq!((index.pages[1].width, index.pages[1].height), (120, 80));
+        assert_eq!(index.words.keys().collect::<Vec<_>>(), ["hello", "world"]);
+
+        let hello = index.lookup("HELLO!");
+        assert_eq!(hello.len(), 2);
+        // Rectangles come back in the top-left coordinates OCR gave.
+        assert_eq!(
+            hello[0],
+            WordHit {
+                page: 0,
+                rect: Rect::new(10, 20, 50, 12)
+            }
+        );
+        assert_eq!(hello[1].page, 2);
+
+        assert_eq!(
+            index.to_json(),
+            "{\"pages\":[{\"width\":120,\"height\":80,\"rotation\":0},\
+             {\"width\":120,\"height\":80,\"rotation\":0},\
+             {\"width\":120,\"height\":80,\"rotation\":0}],\
+             \"words\":{\"hello\":[[0,10,20,50,12],[2,5,40,30,10]],\
+             \"world\":[[2,40,40,35,10]]}}"
+        );
+        assert!(SearchIndex::from_document(b"AT&TFORM\0\0\0\x04PM44").is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_write_sqlite() -> Result<()> {
+        let index = SearchIndex::from_document(&document())?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("index.sqlite");
+        index.write_sqlite(&path)?;
+        // Written twice to check that an existing file is replaced.
+        index.write_sqlite(&path)?;
+        let db = rusqlite::Connection::open(&path).unwrap();
+        let count = |sql: &str| db.query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count("SELECT COUNT(*) FROM pages"), 3);
+        assert_eq!(count("SELECT COUNT(*) FROM words WHERE word = 'hello'"), 2);
+        assert_eq!(count("SELECT x FROM words WHERE word = 'world'"), 40);
+        Ok(())
+    }
+}
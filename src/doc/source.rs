@@ -0,0 +1,129 @@
+//! Where the component files of indirect documents are read from.
+//!
+//! An indirect document is an index file, a `FORM:DJVM` holding the `DIRM`
+//! (and possibly a `NAVM`), plus one file per page, shared dictionary or
+//! annotation, named by its ID in the directory. A [`ComponentSource`]
+//! resolves those IDs to readers, so the files can sit in a directory
+//! ([`DirSource`]), in memory ([`MemorySource`]), or anywhere a caller can
+//! reach, such as a zip archive or a web server.
+//!
+//! [`DjVuDocEditor::from_indirect`] opens an indirect document through a
+//! source.
+//!
+//! [`DjVuDocEditor::from_indirect`]: crate::doc::DjVuDocEditor::from_indirect
+
+use crate::utils::error::IoContext;
+use crate::{DjvuError, Result};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// Resolves the component files of an indirect document.
+pub trait ComponentSource {
+    /// Opens the file with directory ID `id`.
+    fn open(&self, id: &str) -> Result<Box<dyn Read + '_>>;
+}
+
+/// Component files in a directory on disk, usually the one holding the
+/// index file.
+#[derive(Debug, Clone)]
+pub struct DirSource {
+    dir: PathBuf,
+}
+
+impl DirSource {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl ComponentSource for DirSource {
+    /// Opens `id` relative to the directory. IDs that would leave it
+    /// (absolute paths, `..`) are refused.
+    fn open(&self, id: &str) -> Result<Box<dyn Read + '_>> {
+        let relative = Path::new(id);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(DjvuError::InvalidArg(format!(
+                "Component ID {id:?} points outside the document directory"
+            )));
+        }
+        let path = self.dir.join(relative);
+        let file = std::fs::File::open(&path).context(path.display())?;
+        Ok(Box::new(io::BufReader::new(file)))
+    }
+}
+
+/// Component files held in memory, keyed by ID.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySource {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces the file with ID `id`.
+    pub fn insert(&mut self, id: impl Into<String>, data: Vec<u8>) {
+        self.files.insert(id.into(), data);
+    }
+
+    /// Adds the file with ID `id`.
+    pub fn with_file(mut self, id: impl Into<String>, data: Vec<u8>) -> Self {
+        self.insert(id, data);
+        self
+    }
+}
+
+impl ComponentSource for MemorySource {
+    fn open(&self, id: &str) -> Result<Box<dyn Read + '_>> {
+        match self.files.get(id) {
+            Some(data) => Ok(Box::new(data.as_slice())),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)).context(id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(source: &dyn ComponentSource, id: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        source.open(id)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_sources() -> Result<()> {
+        let memory = MemorySource::new().with_file("p1.djvu", b"one".to_vec());
+        assert_eq!(read(&memory, "p1.djvu")?, b"one");
+        match read(&memory, "p2.djvu") {
+            Err(DjvuError::Io(err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::NotFound);
+                assert!(err.to_string().starts_with("p2.djvu"));
+            }
+            other => panic!("expected not found, got {other:?}"),
+        }
+
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("pages"))?;
+        std::fs::write(dir.path().join("pages/p1.djvu"), b"one")?;
+        let disk = DirSource::new(dir.path());
+        assert_eq!(read(&disk, "pages/p1.djvu")?, b"one");
+        assert!(matches!(read(&disk, "p2.djvu"), Err(DjvuError::Io(_))));
+        assert!(matches!(
+            read(&disk, "../p1.djvu"),
+            Err(DjvuError::InvalidArg(_))
+        ));
+        assert!(matches!(
+            read(&disk, "/etc/passwd"),
+            Err(DjvuError::InvalidArg(_))
+        ));
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@
 
 use super::coeff_map::CoeffMap;
 use super::constants::BAND_BUCKETS;
-use crate::encode::zc::{BitContext, ZpEncoderCursor};
+use crate::encode::zc::{ContextBank, ZpContext, ZpEncoderCursor};
 
 // State flags for coefficients and buckets
 const UNK: u8 = 0x01; // Unknown state
@@ -22,16 +22,16 @@ fn words_for_coeffs(n: usize) -> usize {
 /// Represents the IW44 codec for encoding wavelet coefficients.
 /// Each codec instance owns its own slice state (curbit, curband) as per djvulibre design.
 pub struct Codec {
-    pub map: CoeffMap,                    // Original coefficient map
-    pub emap: CoeffMap,                   // Encoded coefficient map
-    pub coeff_state: Vec<u8>,             // State of each coefficient
-    pub bucket_state: Vec<u8>,            // State of each bucket
-    pub quant_hi: [i32; 10],              // Quantization thresholds for bands 1-9
-    pub quant_lo: [i32; 16],              // Quantization thresholds for band 0
-    pub ctx_root: BitContext,             // Context for root bit
-    pub ctx_bucket: Vec<Vec<BitContext>>, // Contexts for bucket bits [band][ctx]
-    pub ctx_start: Vec<BitContext>,       // Contexts for new coefficient activation [ctx]
-    pub ctx_mant: BitContext,             // Context for mantissa bits
+    pub map: CoeffMap,                // Original coefficient map
+    pub emap: CoeffMap,               // Encoded coefficient map
+    pub coeff_state: Vec<u8>,         // State of each coefficient
+    pub bucket_state: Vec<u8>,        // State of each bucket
+    pub quant_hi: [i32; 10],          // Quantization thresholds for bands 1-9
+    pub quant_lo: [i32; 16],          // Quantization thresholds for band 0
+    pub ctx_root: ZpContext,          // Context for root bit
+    pub ctx_bucket: Vec<ContextBank>, // Contexts for bucket bits [band][ctx]
+    pub ctx_start: ContextBank,       // Contexts for new coefficient activation [ctx]
+    pub ctx_mant: ZpContext,          // Context for mantissa bits
     pub signif: Vec<u32>, // 1 bit / coefficient (1 == coefficient is already significant)
     // Per-codec slice state (owned by each Y/Cb/Cr codec independently)
     pub curbit: i32,    // Current bitplane (starts at 1, goes to -1 when done)
@@ -39,13 +39,37 @@ pub struct Codec {
     pub lossless: bool, // True if encoding in lossless mode (thresholds stay >= 1)
 }
 
+/// Saved encoding progress of a [`Codec`], taken with [`Codec::snapshot`].
+///
+/// Together with a [`ZpCheckpoint`](crate::encode::zc::ZpCheckpoint) of the
+/// ZP encoder this lets a rate controller trial-encode slices and roll back.
+#[derive(Clone)]
+pub struct CodecState {
+    emap: CoeffMap,
+    coeff_state: Vec<u8>,
+    bucket_state: Vec<u8>,
+    quant_hi: [i32; 10],
+    quant_lo: [i32; 16],
+    ctx_root: ZpContext,
+    ctx_bucket: Vec<ContextBank>,
+    ctx_start: ContextBank,
+    ctx_mant: ZpContext,
+    signif: Vec<u32>,
+    curbit: i32,
+    curband: i32,
+}
+
 impl Codec {
     /// Creates a new Codec instance for the given coefficient map and parameters.
-    pub fn new(map: CoeffMap, params: &super::EncoderParams) -> Self {
+    pub fn new(mut map: CoeffMap, params: &super::EncoderParams) -> Self {
         let num_blocks = map.num_blocks;
         let max_buckets = 64; // Each block has up to 64 buckets
         let max_coeffs_per_bucket = 16;
 
+        if !params.lossless {
+            params.quant_profile.apply(&mut map);
+        }
+
         // Initialize quantization thresholds exactly like djvulibre IW44Image.cpp constructor
         let iw_quant = &super::constants::IW_QUANT;
         let mut quant_lo = [0i32; 16];
@@ -114,9 +138,9 @@ impl Codec {
         // Initialize contexts
         let mut ctx_bucket = Vec::with_capacity(10);
         for _ in 0..10 {
-            ctx_bucket.push(vec![0u8; 8]); // 8 contexts per band (0-7)
+            ctx_bucket.push(ContextBank::new(8)); // 8 contexts per band (0-7)
         }
-        let ctx_start = vec![0u8; 16]; // 16 contexts (0-15)
+        let ctx_start = ContextBank::new(16); // 16 contexts (0-15)
 
         let coeffs = num_blocks * max_buckets * max_coeffs_per_bucket;
 
@@ -127,10 +151,10 @@ impl Codec {
             bucket_state: vec![ZERO; num_blocks * max_buckets],
             quant_hi,
             quant_lo,
-            ctx_root: 0u8,
+            ctx_root: ZpContext::new(),
             ctx_bucket,
             ctx_start,
-            ctx_mant: 0u8,
+            ctx_mant: ZpContext::new(),
             signif: vec![0; words_for_coeffs(coeffs)],
             // Initialize slice state (matches djvulibre IW44Image constructor)
             curbit: 1,  // Start at bitplane 1
@@ -144,6 +168,44 @@ impl Codec {
         &self.map
     }
 
+    /// Captures everything `code_slice` mutates: the encoded map, coefficient
+    /// and bucket states, thresholds, contexts and the slice position.
+    ///
+    /// The source map is not copied, so the cost is one copy of the encoded
+    /// map plus the per-coefficient state.
+    pub fn snapshot(&self) -> CodecState {
+        CodecState {
+            emap: self.emap.clone(),
+            coeff_state: self.coeff_state.clone(),
+            bucket_state: self.bucket_state.clone(),
+            quant_hi: self.quant_hi,
+            quant_lo: self.quant_lo,
+            ctx_root: self.ctx_root,
+            ctx_bucket: self.ctx_bucket.clone(),
+            ctx_start: self.ctx_start.clone(),
+            ctx_mant: self.ctx_mant,
+            signif: self.signif.clone(),
+            curbit: self.curbit,
+            curband: self.curband,
+        }
+    }
+
+    /// Returns the codec to a state taken with [`snapshot`](Self::snapshot).
+    pub fn restore(&mut self, state: CodecState) {
+        self.emap = state.emap;
+        self.coeff_state = state.coeff_state;
+        self.bucket_state = state.bucket_state;
+        self.quant_hi = state.quant_hi;
+        self.quant_lo = state.quant_lo;
+        self.ctx_root = state.ctx_root;
+        self.ctx_bucket = state.ctx_bucket;
+        self.ctx_start = state.ctx_start;
+        self.ctx_mant = state.ctx_mant;
+        self.signif = state.signif;
+        self.curbit = state.curbit;
+        self.curband = state.curband;
+    }
+
     #[inline]
     fn is_signif(&self, idx: usize) -> bool {
         (self.signif[idx / WORD_BITS] >> (idx % WORD_BITS)) & 1 != 0
@@ -654,4 +716,65 @@ impl Codec {
         let factor = 255.0 * (1 << super::constants::IW_SHIFT) as f32;
         10.0 * (factor * factor / mse_avg).log10()
     }
+
+    /// Estimates the perceived quality of the encoded image as multi-scale
+    /// SSIM between the original and the coded coefficients, in decibels
+    /// (`-10 log10(1 - ssim)`) so it can be held against the same targets
+    /// as [`Codec::estimate_decibel`].
+    ///
+    /// Each octave of the decomposition is one scale: every octave adds its
+    /// contrast-structure term and band 0 the luminance term, weighted as in
+    /// MS-SSIM. Like the PSNR estimate it averages the worst `db_frac` of
+    /// the blocks, but it looks at every coefficient instead of band 0 only,
+    /// so it costs a few times more per slice.
+    pub fn estimate_ssim_decibel(&self, db_frac: f32) -> f32 {
+        let mut ssim: Vec<f32> = (0..self.map.num_blocks)
+            .map(|blockno| self.block_ssim(blockno))
+            .collect();
+        ssim.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let worst = ssim.len() - (ssim.len() as f32 * (1.0 - db_frac)).floor() as usize;
+        let ssim_avg = ssim[..worst.max(1)].iter().sum::<f32>() / worst.max(1) as f32;
+        -10.0 * (1.0 - ssim_avg).max(1e-10).log10()
+    }
+
+    /// MS-SSIM of one block, over the octaves of its coefficients.
+    fn block_ssim(&self, blockno: usize) -> f32 {
+        // The MS-SSIM weights of the four finest of its five scales,
+        // coarsest first; they are scaled to sum to one below.
+        const WEIGHTS: [f32; 4] = [0.1333, 0.2363, 0.3001, 0.2856];
+        const OCTAVES: [std::ops::Range<usize>; 4] = [0..1, 1..4, 4..7, 7..10];
+        let range = 255.0 * (1 << super::constants::IW_SHIFT) as f32;
+        let (c1, c2) = ((0.01 * range).powi(2), (0.03 * range).powi(2));
+
+        let (src, coded) = (&self.map.blocks[blockno], &self.emap.blocks[blockno]);
+        let total: f32 = WEIGHTS.iter().sum();
+        let mut ssim = 1.0f32;
+        for (octave, bands) in OCTAVES.iter().enumerate() {
+            let (mut n, mut sx, mut sy, mut sxx, mut syy, mut sxy) =
+                (0.0f32, 0.0, 0.0, 0.0, 0.0, 0.0);
+            for band in bands.clone() {
+                let info = BAND_BUCKETS[band];
+                for bucket in info.start..info.start + info.size {
+                    let x = src.get_bucket_raw(bucket as u8);
+                    let e = coded.get_bucket_raw(bucket as u8);
+                    for (&x, &e) in x.iter().zip(e) {
+                        // The coded map holds magnitudes; the sign is coded
+                        // with them.
+                        let (x, y) = (x as f32, if x < 0 { -(e as f32) } else { e as f32 });
+                        n += 1.0;
+                        (sx, sy) = (sx + x, sy + y);
+                        (sxx, syy, sxy) = (sxx + x * x, syy + y * y, sxy + x * y);
+                    }
+                }
+            }
+            let (mx, my) = (sx / n, sy / n);
+            let (vx, vy, cov) = (sxx / n - mx * mx, syy / n - my * my, sxy / n - mx * my);
+            let mut term = ((2.0 * cov + c2) / (vx + vy + c2)).max(0.0);
+            if octave == 0 {
+                term *= (2.0 * mx * my + c1) / (mx * mx + my * my + c1);
+            }
+            ssim *= term.max(0.0).powf(WEIGHTS[octave] / total);
+        }
+        ssim
+    }
 }
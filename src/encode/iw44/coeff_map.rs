@@ -2,6 +2,7 @@ use super::masking;
 use super::transform::Encode;
 use super::zigzag::ZIGZAG_LOC;
 use crate::image::image_formats::Bitmap;
+use crate::{DjvuError, Result};
 
 /// Replaces `IW44Image::Block`, storing coefficients for a 32x32 image block.
 /// Uses flat arrays for maximum cache efficiency: 32 bytes per bucket, 2 buckets per cache line.
@@ -172,14 +173,33 @@ impl CoeffMap {
     where
         F: FnOnce(&mut [i16], usize, usize, usize),
     {
-        let mut map = Self::new(width, height);
+        let (bw, bh) = Self::padded_size(width, height);
+        let mut data16 = vec![0i16; bw * bh];
+        transform_fn(&mut data16, width, height, bw);
+        Self::create_from_padded_plane(width, height, data16, mask)
+    }
 
-        let mut data16 = vec![0i16; map.bw * map.bh];
+    /// Size of the transform buffer for a `width` x `height` image: whole
+    /// 32x32 blocks.
+    pub(crate) fn padded_size(width: usize, height: usize) -> (usize, usize) {
+        ((width + 31) & !31, (height + 31) & !31)
+    }
 
-        transform_fn(&mut data16, map.iw, map.ih, map.bw);
+    /// Create coefficients from a filled transform buffer: `width` x
+    /// `height` samples, already scaled and flipped bottom-up, in a plane
+    /// padded to whole 32x32 blocks with zeros.
+    pub(crate) fn create_from_padded_plane(
+        width: usize,
+        height: usize,
+        mut data16: Vec<i16>,
+        mask: Option<&Bitmap>,
+    ) -> Self {
+        let mut map = Self::new(width, height);
+        debug_assert_eq!(data16.len(), map.bw * map.bh);
 
-        let levels = ((map.iw.min(map.ih) as f32).log2() as usize).min(5);
-        Encode::forward(&mut data16, map.iw, map.ih, map.bw, levels);
+        // Always the full five levels: decoders undo exactly five, whatever
+        // the image size, so a smaller image must not stop any earlier.
+        Encode::forward(&mut data16, map.iw, map.ih, map.bw, 5);
 
         if let Some(mask_img) = mask {
             let mask8 = masking::image_to_mask8(mask_img, map.bw, map.ih);
@@ -243,6 +263,78 @@ impl CoeffMap {
         )
     }
 
+    /// Create a CoeffMap from a signed i16 plane with `precision` significant
+    /// bits per sample (8..=14), e.g. 12-bit sensor data centered on 0.
+    ///
+    /// Samples are clamped to the signed `precision`-bit range and scaled so
+    /// that range covers the same coefficient range as 8-bit input; the
+    /// extra bits are kept as sub-8-bit precision instead of being rounded
+    /// away before the wavelet transform.
+    pub fn create_from_i16_plane(
+        plane: &[i16],
+        width: u32,
+        height: u32,
+        precision: u32,
+        mask: Option<&Bitmap>,
+    ) -> Result<Self> {
+        if !(8..=14).contains(&precision) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Sample precision must be 8..=14 bits, got {precision}"
+            )));
+        }
+        Self::check_plane_len(plane.len(), width, height)?;
+
+        let max = (1i16 << (precision - 1)) - 1;
+        let clamped: Vec<i16> = plane.iter().map(|&v| v.clamp(-max - 1, max)).collect();
+        let shift = super::constants::IW_SHIFT as u32 + 8 - precision;
+        Ok(Self::create_from_transform(
+            width as usize,
+            height as usize,
+            mask,
+            |data16, iw, ih, stride| {
+                Encode::from_i16_channel_with_stride(&clamped, data16, iw, ih, stride, shift);
+            },
+        ))
+    }
+
+    /// Create a CoeffMap from an f32 plane centered on 0, where ±1.0 spans
+    /// the range of an 8-bit channel (so `1.0` corresponds to `128` in the
+    /// i8 constructors). Values outside `-1.0..1.0` are clamped.
+    pub fn create_from_f32_plane(
+        plane: &[f32],
+        width: u32,
+        height: u32,
+        mask: Option<&Bitmap>,
+    ) -> Result<Self> {
+        Self::check_plane_len(plane.len(), width, height)?;
+
+        let full = (128 << super::constants::IW_SHIFT) as f32;
+        let scaled: Vec<i16> = plane
+            .iter()
+            .map(|&v| {
+                let v = if v.is_nan() { 0.0 } else { v };
+                (v * full).round().clamp(-full, full - 1.0) as i16
+            })
+            .collect();
+        Ok(Self::create_from_transform(
+            width as usize,
+            height as usize,
+            mask,
+            |data16, iw, ih, stride| {
+                Encode::from_i16_channel_with_stride(&scaled, data16, iw, ih, stride, 0);
+            },
+        ))
+    }
+
+    fn check_plane_len(len: usize, width: u32, height: u32) -> Result<()> {
+        if width == 0 || height == 0 || len != width as usize * height as usize {
+            return Err(DjvuError::InvalidArg(format!(
+                "Plane of {len} samples does not match {width}x{height}"
+            )));
+        }
+        Ok(())
+    }
+
     pub fn slash_res(&mut self, res: usize) {
         self.iw = (self.iw + res - 1) / res;
         self.ih = (self.ih + res - 1) / res;
@@ -266,6 +358,60 @@ impl CoeffMap {
     }
 }
 
+#[cfg(test)]
+mod plane_tests {
+    use super::*;
+
+    #[test]
+    fn test_high_precision_planes_match_8bit_at_same_scale() {
+        let (w, h) = (32u32, 32u32);
+        let i8_plane: Vec<i8> = (0..w * h)
+            .map(|i| (((i * 7) % 200) as i32 - 100) as i8)
+            .collect();
+        let reference = CoeffMap::create_from_signed_channel(&i8_plane, w, h, None, "Y");
+
+        let i16_plane: Vec<i16> = i8_plane.iter().map(|&v| (v as i16) << 4).collect();
+        let wide = CoeffMap::create_from_i16_plane(&i16_plane, w, h, 12, None).unwrap();
+        let f32_plane: Vec<f32> = i8_plane.iter().map(|&v| v as f32 / 128.0).collect();
+        let float = CoeffMap::create_from_f32_plane(&f32_plane, w, h, None).unwrap();
+
+        for bucket in 0..64u8 {
+            let expected = reference.blocks[0].get_bucket_raw(bucket);
+            assert_eq!(wide.blocks[0].get_bucket_raw(bucket), expected);
+            assert_eq!(float.blocks[0].get_bucket_raw(bucket), expected);
+        }
+
+        assert!(CoeffMap::create_from_i16_plane(&i16_plane, w, h, 16, None).is_err());
+        assert!(CoeffMap::create_from_f32_plane(&f32_plane[1..], w, h, None).is_err());
+    }
+}
+
+#[cfg(test)]
+mod layout_tests {
+    use super::*;
+    use crate::image::image_formats::GrayPixel;
+
+    #[test]
+    fn test_small_and_odd_sizes_use_full_decomposition() {
+        // A flat image leaves nothing but the low-pass sample at the origin
+        // of each block, however small or oddly sized the image is.
+        for (w, h) in [(1, 1), (1, 37), (37, 1), (2, 3), (5, 5), (33, 17), (31, 65)] {
+            let img = Bitmap::from_vec(w, h, vec![GrayPixel::new(200); (w * h) as usize]);
+            let map = CoeffMap::create_from_image(&img, None);
+            assert_eq!(
+                map.blocks.len(),
+                (w as usize).div_ceil(32) * (h as usize).div_ceil(32)
+            );
+            let mut liftblock = [0i16; 1024];
+            for block in &map.blocks {
+                block.write_liftblock(&mut liftblock);
+                assert_ne!(liftblock[0], 0, "{w}x{h}");
+                assert!(liftblock[1..].iter().all(|&c| c == 0), "{w}x{h}");
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod zigzag_tests {
     // include!("zigzag_test.rs"); // Commented out since the file doesn't exist
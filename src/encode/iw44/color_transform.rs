@@ -0,0 +1,142 @@
+// src/encode/iw44/color_transform.rs
+
+//! Pluggable color decorrelation for high-precision IW44 input.
+//!
+//! The 8-bit RGB path hard-codes the DjVu YCbCr matrix. Callers encoding
+//! scientific or medical data can instead supply `f32` planes and a
+//! [`ColorTransform`], then build the coefficient maps with
+//! [`CoeffMap::create_from_f32_planes`] and encode them with
+//! [`IWEncoder::from_coeff_maps`](super::IWEncoder::from_coeff_maps).
+//!
+//! Transform outputs use the same scale as [`CoeffMap::create_from_f32_plane`]:
+//! each component is centered on 0, and ±1.0 spans the range an 8-bit
+//! channel would cover.
+
+use super::coeff_map::CoeffMap;
+use crate::image::image_formats::Bitmap;
+use crate::{DjvuError, Result};
+
+/// Maps one three-channel input pixel to a luminance-like component and two
+/// chrominance-like components.
+pub trait ColorTransform: Send + Sync {
+    /// Returns `[luma, chroma1, chroma2]` for `pixel`.
+    fn forward(&self, pixel: [f32; 3]) -> [f32; 3];
+}
+
+/// The DjVu YCbCr matrix, for RGB input in `0.0..=1.0`.
+///
+/// Matches the 8-bit encoder path: outputs are `[Y, Cb, Cr]`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DjvuYcc;
+
+impl ColorTransform for DjvuYcc {
+    fn forward(&self, [r, g, b]: [f32; 3]) -> [f32; 3] {
+        let y = 0.304348 * r + 0.608696 * g + 0.086956 * b;
+        let cb = -0.173913 * r - 0.347826 * g + 0.521739 * b;
+        let cr = 0.463768 * r - 0.405797 * g - 0.057971 * b;
+        [2.0 * y - 1.0, 2.0 * cb, 2.0 * cr]
+    }
+}
+
+/// Leaves already decorrelated input unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Passthrough;
+
+impl ColorTransform for Passthrough {
+    fn forward(&self, pixel: [f32; 3]) -> [f32; 3] {
+        pixel
+    }
+}
+
+/// An arbitrary affine transform: `out = matrix * pixel + offset`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearTransform {
+    pub matrix: [[f32; 3]; 3],
+    pub offset: [f32; 3],
+}
+
+impl ColorTransform for LinearTransform {
+    fn forward(&self, pixel: [f32; 3]) -> [f32; 3] {
+        let row = |i: usize| {
+            self.matrix[i][0] * pixel[0]
+                + self.matrix[i][1] * pixel[1]
+                + self.matrix[i][2] * pixel[2]
+                + self.offset[i]
+        };
+        [row(0), row(1), row(2)]
+    }
+}
+
+impl CoeffMap {
+    /// Applies `transform` to three input planes and builds one coefficient
+    /// map per output component (luma first).
+    pub fn create_from_f32_planes(
+        planes: [&[f32]; 3],
+        width: u32,
+        height: u32,
+        mask: Option<&Bitmap>,
+        transform: &dyn ColorTransform,
+    ) -> Result<[CoeffMap; 3]> {
+        let npix = width as usize * height as usize;
+        if planes.iter().any(|p| p.len() != npix) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Color planes must each hold {width}x{height} samples"
+            )));
+        }
+
+        let mut out = [vec![0f32; npix], vec![0f32; npix], vec![0f32; npix]];
+        for i in 0..npix {
+            let [a, b, c] = transform.forward([planes[0][i], planes[1][i], planes[2][i]]);
+            out[0][i] = a;
+            out[1][i] = b;
+            out[2][i] = c;
+        }
+        Ok([
+            CoeffMap::create_from_f32_plane(&out[0], width, height, mask)?,
+            CoeffMap::create_from_f32_plane(&out[1], width, height, mask)?,
+            CoeffMap::create_from_f32_plane(&out[2], width, height, mask)?,
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_djvu_ycc_matches_8bit_path() {
+        use crate::encode::iw44::rgb_to_ycbcr_planes;
+
+        let (mut y, mut cb, mut cr) = ([0i8; 1], [0i8; 1], [0i8; 1]);
+        rgb_to_ycbcr_planes(&[200, 40, 90], &mut y, &mut cb, &mut cr);
+        let [fy, fcb, fcr] = DjvuYcc.forward([200.0 / 255.0, 40.0 / 255.0, 90.0 / 255.0]);
+        assert!((fy * 128.0 - y[0] as f32).abs() <= 1.0);
+        assert!((fcb * 128.0 - cb[0] as f32).abs() <= 1.0);
+        assert!((fcr * 128.0 - cr[0] as f32).abs() <= 1.0);
+    }
+
+    #[test]
+    fn test_f32_planes_encode() {
+        use crate::encode::iw44::{EncoderParams, IWEncoder};
+
+        let (w, h) = (40u32, 24u32);
+        let plane = |k: f32| -> Vec<f32> {
+            (0..w * h)
+                .map(|i| ((i % w) as f32 * k / w as f32).fract())
+                .collect()
+        };
+        let (r, g, b) = (plane(1.0), plane(2.0), plane(3.0));
+        let [y, c1, c2] =
+            CoeffMap::create_from_f32_planes([&r, &g, &b], w, h, None, &DjvuYcc).unwrap();
+        let mut encoder =
+            IWEncoder::from_coeff_maps(y, Some((c1, c2)), EncoderParams::default()).unwrap();
+        let (chunk, _) = encoder.encode_chunk(10).unwrap();
+        // Color major version, then the image dimensions.
+        assert_eq!(chunk[2], 1);
+        assert_eq!(&chunk[4..8], &[0, 40, 0, 24]);
+
+        assert!(
+            CoeffMap::create_from_f32_planes([&r, &g, &b[1..]], w, h, None, &Passthrough).is_err()
+        );
+    }
+}
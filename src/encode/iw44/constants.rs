@@ -8,6 +8,20 @@ pub const IW_QUANT: [i32; 16] = [
 ];
 
 pub const IW_SHIFT: i32 = 6;
+
+// DjVu Spec 3.2 "IW44 Chunks": header fields written by the encoder
+/// Major version in the secondary header of the first chunk.
+pub const IW44_MAJOR_VERSION: u8 = 1;
+/// Minor version; 2 is the first to carry the chrominance delay byte.
+pub const IW44_MINOR_VERSION: u8 = 2;
+/// Set in the major version byte of grayscale (`BM44`-style) images.
+pub const IW44_GRAYSCALE_FLAG: u8 = 0x80;
+/// Set in the chrominance byte when chroma is coded at full resolution.
+pub const IW44_CHROMA_FULL_FLAG: u8 = 0x80;
+/// Most slices one chunk can hold; the header counts them in a byte.
+pub const IW44_MAX_CHUNK_SLICES: usize = u8::MAX as usize;
+/// Largest width or height the 16-bit header fields can hold.
+pub const IW44_MAX_DIMENSION: usize = u16::MAX as usize;
 pub const IW_ROUND: i32 = 1 << (IW_SHIFT - 1); // = 32
 
 // From IW44EncodeCodec.cpp - DECIBEL_PRUNE constant
@@ -1,11 +1,14 @@
 // src/encode/iw44/encoder.rs
 
-use super::codec::Codec;
+use super::codec::{Codec, CodecState};
 use super::coeff_map::CoeffMap;
-use crate::encode::zc::ZpEncoderCursor;
+use super::constants::{BAND_BUCKETS, IW_SHIFT, IW44_MAX_CHUNK_SLICES};
+use super::header::{ChromaLayout, Iw44ChunkHeader, Iw44ImageHeader};
+use crate::encode::zc::{ZpCheckpoint, ZpEncoderCursor};
 use crate::image::image_formats::{Bitmap, Pixmap};
+use crate::image::view::{ImageView, PixelFormat};
+use crate::utils::log::{debug, target};
 use bytemuck;
-use log::{debug, info};
 use std::io::Cursor;
 use std::sync::OnceLock;
 use thiserror::Error;
@@ -20,9 +23,16 @@ pub enum EncoderError {
     ZCodec(#[from] crate::encode::zc::ZCodecError),
     #[error("General error: {0}")]
     General(#[from] crate::utils::error::DjvuError),
+    #[error("Invalid encoder parameter: {0}")]
+    InvalidParam(String),
+    #[error("Image is {0}x{1}; IW44 allows at most 65535 pixels per side")]
+    TooLarge(usize, usize),
+    #[error("Invalid IW44 chunk header: {0}")]
+    InvalidHeader(String),
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CrcbMode {
     #[default]
     None,
@@ -31,9 +41,119 @@ pub enum CrcbMode {
     Full,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// How [`IWEncoder`] measures quality against the decibel targets of
+/// [`EncoderParams`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum QualityMetric {
+    /// PSNR of the coarsest band, like DjVuLibre.
+    #[default]
+    Psnr,
+    /// Multi-scale SSIM over all bands, as `-10 log10(1 - ssim)` decibels
+    /// (see [`Codec::estimate_ssim_decibel`]). PSNR keeps spending on flat
+    /// areas that already look right; SSIM stops once structure is kept.
+    /// About 20 dB is good, 30 dB hard to tell from the original.
+    Ssim,
+}
+
+/// Per-band dead zones of the IW44 codecs: before coding, coefficients
+/// smaller than the zone of their band are dropped, in units of one level of
+/// an 8-bit sample. Bands are numbered as in the spec, 0 the coarsest and 9
+/// the finest.
+///
+/// The quantization steps themselves stay the spec's, so the stream decodes
+/// with any decoder; a profile only chooses which detail is worth its bits.
+/// Use [`QuantProfile::tilted`] to weight fine detail against smooth areas.
+/// Profiles have no effect on lossless encoding, and the SSIM metric
+/// measures against the coefficients that are kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QuantProfile {
+    pub dead_zones: [f32; 10],
+}
+
+impl QuantProfile {
+    /// No dead zones: every coefficient is coded, as DjVuLibre does.
+    pub const SPEC: Self = Self {
+        dead_zones: [0.0; 10],
+    };
+
+    /// Dead zones of `tilt` to the power of the band's octave, less one:
+    /// nothing for band 0, growing towards the fine bands above 1. A tilt of
+    /// 1 or less is [`QuantProfile::SPEC`].
+    pub fn tilted(tilt: f32) -> Self {
+        let mut dead_zones = [0.0; 10];
+        for (band, zone) in dead_zones.iter_mut().enumerate() {
+            let octave = band.div_ceil(3);
+            *zone = (tilt.powi(octave as i32) - 1.0).max(0.0);
+        }
+        Self { dead_zones }
+    }
+
+    /// Checks that every zone is finite and not negative.
+    pub fn validate(&self) -> Result<(), EncoderError> {
+        match self
+            .dead_zones
+            .iter()
+            .position(|zone| !zone.is_finite() || *zone < 0.0)
+        {
+            Some(band) => Err(EncoderError::InvalidParam(format!(
+                "Dead zone of band {band} is {}; zones must be finite and not negative",
+                self.dead_zones[band]
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Drops the coefficients of `map` inside the zones.
+    pub(crate) fn apply(&self, map: &mut CoeffMap) {
+        for (band, &zone) in self.dead_zones.iter().enumerate() {
+            let limit = (zone * (1 << IW_SHIFT) as f32).min(i16::MAX as f32) as i16;
+            if limit == 0 {
+                continue;
+            }
+            let info = BAND_BUCKETS[band];
+            for block in &mut map.blocks {
+                for bucket in info.start..info.start + info.size {
+                    for coeff in block.get_bucket_mut(bucket as u8) {
+                        if coeff.unsigned_abs() < limit as u16 {
+                            *coeff = 0;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for QuantProfile {
+    fn default() -> Self {
+        Self::SPEC
+    }
+}
+
+/// IW44 encoder settings.
+///
+/// Build them with [`EncoderParams::builder`] to have them checked, or fill
+/// in the fields directly and call [`EncoderParams::validate`]. With the
+/// `serde` feature, missing fields take their default values when loading.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct EncoderParams {
     pub decibels: Option<f32>,
+    /// Luminance quality target in dB; overrides `decibels` for the Y codec.
+    pub decibels_y: Option<f32>,
+    /// Chrominance quality target in dB, checked against the worse of Cb/Cr.
+    ///
+    /// IW44 interleaves the codecs slice by slice and a decoder expects a
+    /// slice from every codec that is still active, so a codec that reaches
+    /// its target keeps being coded until the others have reached theirs:
+    /// encoding stops once every codec with a target is satisfied. Chroma
+    /// slices only start after `crcb_delay` slices (see [`CrcbMode`]), so a
+    /// chroma target extends the stream by at least that many slices. When
+    /// unset, chroma stops together with luminance, as before.
+    pub decibels_chroma: Option<f32>,
     pub slices: Option<usize>, // Max slices per chunk (C44 default: 74 for first chunk)
     pub bytes: Option<usize>,  // Max bytes per chunk
     pub crcb_mode: CrcbMode,
@@ -44,22 +164,139 @@ pub struct EncoderParams {
     /// Lower values = less aggressive filtering = larger files, potentially higher quality
     /// Range: 0.5 to 2.0 recommended
     pub quant_multiplier: f32,
+    /// Initial quantization steps (default: the spec's table)
+    pub quant_profile: QuantProfile,
+    /// What the decibel targets measure (default: PSNR)
+    pub quality_metric: QualityMetric,
 }
 
 impl Default for EncoderParams {
     fn default() -> Self {
         Self {
-            decibels: None,   // No quality limit to match C44 behavior
+            decibels: None, // No quality limit to match C44 behavior
+            decibels_y: None,
+            decibels_chroma: None,
             slices: Some(74), // C44 default: 74 slices for first chunk
             bytes: None,
             crcb_mode: CrcbMode::Full,
             db_frac: 0.35,
             lossless: false,
             quant_multiplier: 1.0, // Start with C++ default behavior
+            quant_profile: QuantProfile::SPEC,
+            quality_metric: QualityMetric::Psnr,
         }
     }
 }
 
+impl EncoderParams {
+    /// Starts a builder from the default settings.
+    pub fn builder() -> EncoderParamsBuilder {
+        EncoderParamsBuilder::default()
+    }
+
+    /// Checks that every setting is in range.
+    pub fn validate(&self) -> Result<(), EncoderError> {
+        let invalid = |msg: String| Err(EncoderError::InvalidParam(msg));
+        for (name, db) in [
+            ("decibels", self.decibels),
+            ("decibels_y", self.decibels_y),
+            ("decibels_chroma", self.decibels_chroma),
+        ] {
+            if let Some(db) = db
+                && !(db.is_finite() && db > 0.0 && db <= 100.0)
+            {
+                return invalid(format!("{name} {db} outside 0..=100 dB"));
+            }
+        }
+        if self.slices == Some(0) {
+            return invalid("slices must be at least 1".to_string());
+        }
+        if self.bytes == Some(0) {
+            return invalid("bytes must be at least 1".to_string());
+        }
+        if !(self.db_frac > 0.0 && self.db_frac <= 1.0) {
+            return invalid(format!("db_frac {} outside (0, 1]", self.db_frac));
+        }
+        if !(self.quant_multiplier.is_finite() && self.quant_multiplier > 0.0) {
+            return invalid(format!(
+                "quant_multiplier {} must be positive",
+                self.quant_multiplier
+            ));
+        }
+        self.quant_profile.validate()
+    }
+}
+
+/// Builder for [`EncoderParams`]; [`build`](Self::build) validates.
+#[derive(Debug, Clone, Default)]
+pub struct EncoderParamsBuilder {
+    params: EncoderParams,
+}
+
+impl EncoderParamsBuilder {
+    pub fn decibels(mut self, db: f32) -> Self {
+        self.params.decibels = Some(db);
+        self
+    }
+
+    pub fn decibels_y(mut self, db: f32) -> Self {
+        self.params.decibels_y = Some(db);
+        self
+    }
+
+    pub fn decibels_chroma(mut self, db: f32) -> Self {
+        self.params.decibels_chroma = Some(db);
+        self
+    }
+
+    /// Maximum slices per chunk; `None` removes the limit.
+    pub fn slices(mut self, slices: Option<usize>) -> Self {
+        self.params.slices = slices;
+        self
+    }
+
+    /// Maximum bytes per chunk; `None` removes the limit.
+    pub fn bytes(mut self, bytes: Option<usize>) -> Self {
+        self.params.bytes = bytes;
+        self
+    }
+
+    pub fn crcb_mode(mut self, mode: CrcbMode) -> Self {
+        self.params.crcb_mode = mode;
+        self
+    }
+
+    pub fn db_frac(mut self, frac: f32) -> Self {
+        self.params.db_frac = frac;
+        self
+    }
+
+    pub fn lossless(mut self, lossless: bool) -> Self {
+        self.params.lossless = lossless;
+        self
+    }
+
+    pub fn quant_multiplier(mut self, multiplier: f32) -> Self {
+        self.params.quant_multiplier = multiplier;
+        self
+    }
+
+    pub fn quant_profile(mut self, profile: QuantProfile) -> Self {
+        self.params.quant_profile = profile;
+        self
+    }
+
+    pub fn quality_metric(mut self, metric: QualityMetric) -> Self {
+        self.params.quality_metric = metric;
+        self
+    }
+
+    pub fn build(self) -> Result<EncoderParams, EncoderError> {
+        self.params.validate()?;
+        Ok(self.params)
+    }
+}
+
 static YCC_TABLES: OnceLock<([[i32; 256]; 3], [[i32; 256]; 3], [[i32; 256]; 3])> = OnceLock::new();
 
 fn get_ycc_tables() -> &'static ([[i32; 256]; 3], [[i32; 256]; 3], [[i32; 256]; 3]) {
@@ -138,7 +375,7 @@ pub fn ycbcr_from_rgb(img: &Pixmap) -> (Vec<i8>, Vec<i8>, Vec<i8>) {
 
     rgb_to_ycbcr_planes(img.as_raw(), &mut y_buf, &mut cb_buf, &mut cr_buf);
 
-    debug!("YCbCr conversion completed for {}x{} image", w, h);
+    debug!(target: target::IW44, "YCbCr conversion completed for {}x{} image", w, h);
 
     (y_buf, cb_buf, cr_buf)
 }
@@ -358,6 +595,9 @@ pub fn encoder_from_rgb_with_helpers(
             CrcbMode::Normal => 10,
             CrcbMode::Full => 0,
         },
+        pending: None,
+        spare_coder: None,
+        band_bytes: [0; 10],
         crcb_half: match params.crcb_mode {
             CrcbMode::Half => true,
             _ => false,
@@ -382,6 +622,9 @@ pub fn encoder_from_gray_with_helpers(
         total_slices: 0,
         serial: 0,
         crcb_delay: -1,
+        pending: None,
+        spare_coder: None,
+        band_bytes: [0; 10],
         crcb_half: false, // Grayscale has no chroma
                           // Note: curbit/curband state is now owned by each codec (initialized in Codec::new)
     })
@@ -395,6 +638,13 @@ pub struct IWEncoder {
     total_slices: usize,
     serial: u8,
     crcb_delay: i32,
+    /// Chunk opened by `encode_slices` and not finished yet.
+    pending: Option<PendingChunk>,
+    /// Coder of the last chunk, reset for the next one.
+    spare_coder: Option<SliceCoder>,
+    /// Bytes the last slice of each luminance band took, 0 if unknown; how
+    /// [`IWEncoder::encode_chunk_within`] judges if the next may not fit.
+    pub(super) band_bytes: [usize; 10],
     crcb_half: bool, // Added to match C++ behavior
                      // Note: curbit/curband state is now owned by each codec independently
 }
@@ -413,7 +663,8 @@ impl IWEncoder {
         mask: Option<&Bitmap>,
         params: EncoderParams,
     ) -> Result<Self, EncoderError> {
-        info!(
+        debug!(
+            target: target::IW44,
             "IWEncoder::from_rgb called with image {}x{}",
             img.width(),
             img.height()
@@ -421,84 +672,260 @@ impl IWEncoder {
         encoder_from_rgb_with_helpers(img, mask, params)
     }
 
-    pub fn encode_chunk(&mut self, max_slices: usize) -> Result<(Vec<u8>, bool), EncoderError> {
-        info!("encode_chunk called with max_slices={}", max_slices);
-
-        let (w, h) = {
-            let map = self.y_codec.map();
-            let w = map.width();
-            let h = map.height();
-            if w == 0 || h == 0 {
-                return Err(EncoderError::EmptyObject);
+    /// Creates an encoder from prebuilt coefficient maps, e.g. from
+    /// [`CoeffMap::create_from_f32_planes`]. `chroma` holds the two
+    /// chrominance maps (written as Cb, then Cr); without it the output is
+    /// grayscale.
+    pub fn from_coeff_maps(
+        y: CoeffMap,
+        chroma: Option<(CoeffMap, CoeffMap)>,
+        params: EncoderParams,
+    ) -> Result<Self, EncoderError> {
+        let dims = (y.width(), y.height());
+        if dims.0 == 0 || dims.1 == 0 {
+            return Err(EncoderError::EmptyObject);
+        }
+        if let Some((cb, cr)) = &chroma
+            && ((cb.width(), cb.height()) != dims || (cr.width(), cr.height()) != dims)
+        {
+            return Err(EncoderError::General(
+                crate::utils::error::DjvuError::InvalidArg(
+                    "Chroma maps must match the luminance map size".to_string(),
+                ),
+            ));
+        }
+        let is_color = chroma.is_some();
+        let (cb_codec, cr_codec) = match chroma {
+            Some((cb, cr)) => (Some(Codec::new(cb, &params)), Some(Codec::new(cr, &params))),
+            None => (None, None),
+        };
+        Ok(IWEncoder {
+            y_codec: Codec::new(y, &params),
+            cb_codec,
+            cr_codec,
+            params,
+            total_slices: 0,
+            serial: 0,
+            crcb_delay: match params.crcb_mode {
+                _ if !is_color => -1,
+                CrcbMode::None => -1,
+                CrcbMode::Half | CrcbMode::Normal => 10,
+                CrcbMode::Full => 0,
+            },
+            // Maps are already at full resolution; there is nothing to halve.
+            pending: None,
+            spare_coder: None,
+            band_bytes: [0; 10],
+            crcb_half: false,
+        })
+    }
+
+    /// Creates an encoder from `height` rows of `width` pixels, top row
+    /// first, for sources that produce a picture a line at a time (cameras,
+    /// scanners). Each row goes straight into the wavelet transform
+    /// buffers, so the decoded frame never has to be held alongside them.
+    ///
+    /// Rows are gray (`width` bytes) or RGB (`3 * width` bytes), as the
+    /// first row decides. The result is the same as [`IWEncoder::from_gray`]
+    /// or [`IWEncoder::from_rgb`] on the whole picture, without a mask.
+    pub fn from_scanlines<R: AsRef<[u8]>>(
+        width: u32,
+        height: u32,
+        rows: impl Iterator<Item = R>,
+        params: EncoderParams,
+    ) -> Result<Self, EncoderError> {
+        if width == 0 || height == 0 {
+            return Err(EncoderError::EmptyObject);
+        }
+        let (w, h) = (width as usize, height as usize);
+        let mut rows = rows.peekable();
+        let color = rows.peek().is_some_and(|row| row.as_ref().len() == 3 * w);
+        let row_len = if color { 3 * w } else { w };
+        let chroma = color && params.crcb_mode != CrcbMode::None;
+        let half = chroma && params.crcb_mode == CrcbMode::Half;
+
+        let mut y_plane = ScanPlane::new(w, h);
+        let mut cb_plane = (chroma && !half).then(|| ScanPlane::new(w, h));
+        let mut cr_plane = (chroma && !half).then(|| ScanPlane::new(w, h));
+        let (hw, hh) = (w.div_ceil(2), h.div_ceil(2));
+        let mut half_sums = if half {
+            vec![[0i32; 2]; hw * hh]
+        } else {
+            Vec::new()
+        };
+        let (mut y_row, mut cb_row, mut cr_row) = (vec![0i8; w], vec![0i8; w], vec![0i8; w]);
+
+        let mut count = 0;
+        for (y, row) in rows.enumerate() {
+            let row = row.as_ref();
+            if y >= h || row.len() != row_len {
+                return Err(EncoderError::General(
+                    crate::utils::error::DjvuError::InvalidArg(format!(
+                        "Scanline {y} has {} bytes; expected {height} rows of {row_len}",
+                        row.len()
+                    )),
+                ));
+            }
+            if color {
+                rgb_to_ycbcr_planes(row, &mut y_row, &mut cb_row, &mut cr_row);
+            } else {
+                for (dst, &gray) in y_row.iter_mut().zip(row) {
+                    *dst = (gray as i32 - 128) as i8;
+                }
+            }
+            y_plane.put_row(y, &y_row);
+            if let (Some(cb), Some(cr)) = (&mut cb_plane, &mut cr_plane) {
+                cb.put_row(y, &cb_row);
+                cr.put_row(y, &cr_row);
             }
-            (w, h)
+            if half {
+                let sums = &mut half_sums[(y / 2) * hw..(y / 2 + 1) * hw];
+                for x in 0..w {
+                    sums[x / 2][0] += cb_row[x] as i32;
+                    sums[x / 2][1] += cr_row[x] as i32;
+                }
+            }
+            count += 1;
+        }
+        if count != h {
+            return Err(EncoderError::General(
+                crate::utils::error::DjvuError::InvalidArg(format!(
+                    "Got {count} scanlines for an image {height} rows high"
+                )),
+            ));
+        }
+
+        let (cb_map, cr_map) = if half {
+            // Average each 2x2 block, as `make_ycbcr_codecs` does.
+            let mut cb_half = vec![0i8; hw * hh];
+            let mut cr_half = vec![0i8; hw * hh];
+            for (i, [cb, cr]) in half_sums.into_iter().enumerate() {
+                let (x, y) = (i % hw, i / hw);
+                let n = ((w - 2 * x).min(2) * (h - 2 * y).min(2)) as i32;
+                cb_half[i] = (cb / n) as i8;
+                cr_half[i] = (cr / n) as i8;
+            }
+            let (hw, hh) = (hw as u32, hh as u32);
+            (
+                Some(CoeffMap::create_from_signed_channel(
+                    &cb_half, hw, hh, None, "Cb",
+                )),
+                Some(CoeffMap::create_from_signed_channel(
+                    &cr_half, hw, hh, None, "Cr",
+                )),
+            )
+        } else {
+            (
+                cb_plane.map(ScanPlane::into_map),
+                cr_plane.map(ScanPlane::into_map),
+            )
         };
 
-        if !self.params.lossless && self.params.decibels.is_none() && max_slices == 0 {
+        Ok(IWEncoder {
+            y_codec: Codec::new(y_plane.into_map(), &params),
+            cb_codec: cb_map.map(|map| Codec::new(map, &params)),
+            cr_codec: cr_map.map(|map| Codec::new(map, &params)),
+            params,
+            total_slices: 0,
+            serial: 0,
+            crcb_delay: match params.crcb_mode {
+                _ if !chroma => -1,
+                CrcbMode::None => -1,
+                CrcbMode::Half | CrcbMode::Normal => 10,
+                CrcbMode::Full => 0,
+            },
+            pending: None,
+            spare_coder: None,
+            band_bytes: [0; 10],
+            crcb_half: half,
+        })
+    }
+
+    /// Creates an encoder straight from a borrowed frame, such as a video
+    /// decoder's or a scanner SDK's buffer. Gray and RGB rows are read in
+    /// place; other layouts are converted a row at a time.
+    pub fn from_view(view: &ImageView<'_>, params: EncoderParams) -> Result<Self, EncoderError> {
+        let (width, height) = (view.width(), view.height());
+        match view.format() {
+            PixelFormat::Gray8 | PixelFormat::Rgb8 => {
+                Self::from_scanlines(width, height, view.rows(), params)
+            }
+            _ => {
+                let rows = view.rows().map(|row| {
+                    let mut rgb = Vec::with_capacity(3 * width as usize);
+                    view.unpack_row(row, &mut rgb);
+                    rgb
+                });
+                Self::from_scanlines(width, height, rows, params)
+            }
+        }
+    }
+
+    /// The luminance codec, the chrominance codecs if any, and the slice
+    /// the chrominance starts at.
+    pub(crate) fn codecs(&self) -> (&Codec, Option<(&Codec, &Codec)>, i32) {
+        let chroma = self.cb_codec.as_ref().zip(self.cr_codec.as_ref());
+        (&self.y_codec, chroma, self.crcb_delay)
+    }
+
+    pub(crate) fn params(&self) -> &EncoderParams {
+        &self.params
+    }
+
+    pub fn encode_chunk(&mut self, max_slices: usize) -> Result<(Vec<u8>, bool), EncoderError> {
+        debug!(target: target::IW44, "encode_chunk called with max_slices={}", max_slices);
+
+        let image = self.image_header()?;
+        let y_target = self.params.decibels_y.or(self.params.decibels);
+        let chroma_target = self.params.decibels_chroma;
+        if !self.params.lossless && y_target.is_none() && chroma_target.is_none() && max_slices == 0
+        {
             return Err(EncoderError::NeedStopCondition);
         }
 
-        // Check if encoding is finished (check Y codec state)
-        if self.y_codec.curbit < 0 {
+        if self.is_finished() && self.pending.is_none() {
             return Ok((Vec::new(), false));
         }
 
-        let mut chunk_data = Vec::new();
-        // Create the ZP encoder for IW44 only. When the `asm_zp` feature is enabled,
-        // use the assembly-backed encoder; otherwise, use the Rust implementation.
-        #[cfg(feature = "asm_zp")]
-        let mut zp_impl = crate::encode::zc::asm::ZEncoder::new(Cursor::new(Vec::new()), true)?;
-        #[cfg(not(feature = "asm_zp"))]
-        let mut zp_impl = crate::encode::zc::zcodec::ZEncoder::new(Cursor::new(Vec::new()), true)?;
-        let mut slices_encoded = 0;
-        let mut estdb = -1.0;
+        // Slices already coded with `encode_slices` open this chunk.
+        let PendingChunk {
+            mut zp,
+            slices: mut slices_encoded,
+        } = self.open_chunk()?;
 
         // IMPORTANT: Do NOT reset contexts between progressive chunks of the same image
         // Contexts should only be reset when creating a new encoder for a different image
         // The ZP encoder's adaptive state must persist across progressive chunks
 
-        let _more = self.y_codec.curbit >= 0;
-        while slices_encoded < max_slices && self.y_codec.curbit >= 0 {
-            // Encode one slice using codec-controlled scheduling (mirrors DjVuLibre)
-            // Each codec manages its own curbit/curband state independently
-            let should_continue = self.y_codec.code_slice(&mut zp_impl)?;
-
-            if let Some(ref mut cb) = self.cb_codec {
-                if self.total_slices as i32 >= self.crcb_delay {
-                    debug!("Encoding Cb slice {}", self.total_slices);
-                    cb.code_slice(&mut zp_impl)?;
-                }
-            }
-            if let Some(ref mut cr) = self.cr_codec {
-                if self.total_slices as i32 >= self.crcb_delay {
-                    debug!("Encoding Cr slice {}", self.total_slices);
-                    cr.code_slice(&mut zp_impl)?;
-                }
-            }
-
-            // A slice is always processed, so we always increment
+        let unlimited = max_slices == usize::MAX;
+        let max_slices = max_slices.min(IW44_MAX_CHUNK_SLICES);
+        while slices_encoded < max_slices && !self.is_finished() {
+            let should_continue = self.code_slice(&mut zp)?;
             slices_encoded += 1;
-            self.total_slices += 1;
 
             // Check slice limit only if not overridden by max_slices parameter
             // When max_slices is usize::MAX, we encode all remaining slices
-            if max_slices < usize::MAX {
-                if let Some(slice_limit) = self.params.slices {
-                    if slices_encoded >= slice_limit {
-                        info!(
-                            "encode_chunk: Reached slice limit {}, stopping",
-                            slice_limit
-                        );
-                        break;
-                    }
-                }
+            if !unlimited
+                && let Some(slice_limit) = self.params.slices
+                && slices_encoded >= slice_limit
+            {
+                debug!(
+                    target: target::IW44,
+                    "encode_chunk: Reached slice limit {}, stopping",
+                    slice_limit
+                );
+                break;
             }
 
             // Check byte limit
             if let Some(byte_limit) = self.params.bytes {
-                let current_bytes = zp_impl.tell_bytes();
+                let current_bytes = zp.tell_bytes();
                 if current_bytes >= byte_limit {
-                    info!("encode_chunk: Reached byte limit {}, stopping", byte_limit);
+                    debug!(
+                        target: target::IW44,
+                        "encode_chunk: Reached byte limit {}, stopping",
+                        byte_limit
+                    );
                     break;
                 }
             }
@@ -508,84 +935,419 @@ impl IWEncoder {
                 break;
             }
 
-            // Quality control - estimate decibels (skip if lossless mode)
-            if !self.params.lossless {
-                if let Some(db_target) = self.params.decibels {
-                    // Always check quality after first slice or when appropriate
-                    if slices_encoded > 0
-                        || self.y_codec.curband == 0
-                        || estdb >= db_target - super::constants::DECIBEL_PRUNE
-                    {
-                        estdb = self.y_codec.estimate_decibel(self.params.db_frac);
-                        if estdb >= db_target {
-                            self.y_codec.curbit = -1;
-                            break;
+            if self.quality_reached() {
+                debug!(
+                    target: target::IW44,
+                    "encode_chunk: quality targets reached after {} slices",
+                    slices_encoded
+                );
+                self.stop();
+                break;
+            }
+        }
+
+        if slices_encoded == 0 {
+            debug!(
+                target: target::IW44,
+                "encode_chunk: No slices encoded (slices_encoded=0). Returning empty chunk."
+            );
+            return Ok((Vec::new(), false));
+        }
+
+        let chunk_data = self.close_chunk(zp, slices_encoded, image)?;
+        // Determine if more chunks are needed
+        let more = !self.is_finished();
+        Ok((chunk_data, more))
+    }
+
+    /// Codes up to `n` more slices without closing a chunk, for callers
+    /// that pick chunk boundaries themselves, e.g. one chunk per HTTP range
+    /// request. Returns a record per slice coded; fewer than `n` when the
+    /// image is finished, its quality targets are met, or the open chunk
+    /// holds the 255 slices a chunk header can count.
+    ///
+    /// Slice and byte limits in the parameters are per-chunk budgets and do
+    /// not apply here. Call [`IWEncoder::finish_chunk`] to close the chunk;
+    /// a chunk boundary can only fall between slices, so every chunk it
+    /// returns decodes.
+    pub fn encode_slices(&mut self, n: usize) -> Result<Vec<SliceRecord>, EncoderError> {
+        self.image_header()?;
+        let mut pending = self.open_chunk()?;
+        let mut records = Vec::new();
+        while records.len() < n && pending.slices < IW44_MAX_CHUNK_SLICES && !self.is_finished() {
+            let (band, bit) = (self.y_codec.curband as u8, self.y_codec.curbit);
+            let before = pending.zp.tell_bytes();
+            let should_continue = self.code_slice(&mut pending.zp)?;
+            pending.slices += 1;
+            records.push(SliceRecord {
+                band,
+                bit,
+                bytes: pending.zp.tell_bytes() - before,
+            });
+            if !should_continue {
+                break;
+            }
+            if self.quality_reached() {
+                self.stop();
+                break;
+            }
+        }
+        if pending.slices > 0 {
+            self.pending = Some(pending);
+        }
+        Ok(records)
+    }
+
+    /// Closes the chunk holding the slices coded by
+    /// [`IWEncoder::encode_slices`] since the last chunk, header included;
+    /// `None` when there are none.
+    pub fn finish_chunk(&mut self) -> Result<Option<Vec<u8>>, EncoderError> {
+        let image = self.image_header()?;
+        match self.pending.take() {
+            Some(PendingChunk { zp, slices }) => Ok(Some(self.close_chunk(zp, slices, image)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Codes a chunk of at most `max_slices` slices whose size, header
+    /// included, stays within `max_bytes`, for streams that should arrive
+    /// in pieces of a bounded size. Returns the chunk and whether more
+    /// slices remain, like [`IWEncoder::encode_chunk`].
+    ///
+    /// A slice that would overflow the chunk is rolled back and opens the
+    /// next one instead. The first slice of a chunk is always kept, since a
+    /// chunk boundary can only fall between slices, so a slice larger than
+    /// `max_bytes` makes a chunk of its own. The slice limit and the `bytes`
+    /// soft limit of the parameters do not apply: `max_bytes` bounds each
+    /// chunk, where `bytes` only stops the whole encode once passed. Quality
+    /// targets do apply.
+    ///
+    /// Far from the limit, slices are coded without saving the codec state:
+    /// the chunk size is bounded by the bytes written plus what flushing can
+    /// add. Near it, taken as less room than four times what the slice's
+    /// band took the last time, the state is saved before each slice. A
+    /// slice that overflows without a saved state has the chunk coded again
+    /// from its start up to it.
+    pub fn encode_chunk_within(
+        &mut self,
+        max_bytes: usize,
+        max_slices: usize,
+    ) -> Result<(Vec<u8>, bool), EncoderError> {
+        let image = self.image_header()?;
+        if self.is_finished() && self.pending.is_none() {
+            return Ok((Vec::new(), false));
+        }
+        let header_len = Iw44ChunkHeader {
+            serial: self.serial,
+            slices: 0,
+            image,
+        }
+        .encoded_len();
+        let room = max_bytes.saturating_sub(header_len);
+
+        let mut pending = self.open_chunk()?;
+        let start = self.save_slice(&pending.zp);
+        let max_slices = max_slices.min(IW44_MAX_CHUNK_SLICES);
+        while pending.slices < max_slices && !self.is_finished() {
+            let band = self.y_codec.curband.clamp(0, 9) as usize;
+            let before = pending.zp.tell_bytes();
+            let last = self.band_bytes[band];
+            let near = last == 0 || before + pending.zp.flush_bound() + SLICE_GROWTH * last > room;
+            let saved = (pending.slices > 0 && near).then(|| self.save_slice(&pending.zp));
+            let should_continue = self.code_slice(&mut pending.zp)?;
+            self.band_bytes[band] = pending.zp.tell_bytes() - before;
+            if pending.slices > 0 && Self::overflows(&pending.zp, room)? {
+                debug!(
+                    target: target::IW44,
+                    "encode_chunk_within: slice {} would pass {} bytes, starting a new chunk",
+                    pending.slices,
+                    max_bytes
+                );
+                match saved {
+                    Some(saved) => self.restore_slice(&mut pending.zp, saved)?,
+                    None => {
+                        self.restore_slice(&mut pending.zp, start)?;
+                        for _ in 0..pending.slices {
+                            self.code_slice(&mut pending.zp)?;
                         }
                     }
                 }
+                break;
+            }
+            pending.slices += 1;
+            if !should_continue {
+                break;
+            }
+            if self.quality_reached() {
+                self.stop();
+                break;
             }
         }
 
-        // Finish on the concrete implementation
-        let zp_data = zp_impl.finish()?.into_inner();
-
-        if slices_encoded == 0 {
-            info!("encode_chunk: No slices encoded (slices_encoded=0). Returning empty chunk.");
+        if pending.slices == 0 {
             return Ok((Vec::new(), false));
         }
+        let chunk = self.close_chunk(pending.zp, pending.slices, image)?;
+        Ok((chunk, !self.is_finished()))
+    }
+
+    /// Whether the stream in `zp`, once flushed, takes more than `room`
+    /// bytes. Only flushes a copy when the bound on flushing leaves doubt.
+    fn overflows(zp: &SliceCoder, room: usize) -> Result<bool, EncoderError> {
+        if zp.tell_bytes() + zp.flush_bound() <= room {
+            return Ok(false);
+        }
+        Ok(zp.clone().flush()?.into_inner().len() > room)
+    }
+
+    /// Everything coding a slice changes, so it can be undone.
+    fn save_slice(&self, zp: &SliceCoder) -> SavedSlice {
+        SavedSlice {
+            codecs: [
+                Some(&self.y_codec),
+                self.cb_codec.as_ref(),
+                self.cr_codec.as_ref(),
+            ]
+            .map(|codec| codec.map(Codec::snapshot)),
+            total_slices: self.total_slices,
+            zp: zp.checkpoint(),
+        }
+    }
+
+    fn restore_slice(
+        &mut self,
+        zp: &mut SliceCoder,
+        saved: SavedSlice,
+    ) -> Result<(), EncoderError> {
+        let codecs = [
+            Some(&mut self.y_codec),
+            self.cb_codec.as_mut(),
+            self.cr_codec.as_mut(),
+        ];
+        for (codec, state) in codecs.into_iter().zip(saved.codecs) {
+            if let (Some(codec), Some(state)) = (codec, state) {
+                codec.restore(state);
+            }
+        }
+        self.total_slices = saved.total_slices;
+        zp.restore(&saved.zp)?;
+        Ok(())
+    }
+
+    /// Whether every slice has been coded (open chunks may remain).
+    ///
+    /// The chrominance runs `crcb_delay` slices behind the luminance, so a
+    /// color image goes on after the luminance is done until its Cb and Cr
+    /// planes are too.
+    pub fn is_finished(&self) -> bool {
+        [
+            Some(&self.y_codec),
+            self.cb_codec.as_ref(),
+            self.cr_codec.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .all(|codec| codec.curbit < 0)
+    }
+
+    /// Ends the image after the current slice.
+    fn stop(&mut self) {
+        self.y_codec.curbit = -1;
+        for codec in [&mut self.cb_codec, &mut self.cr_codec]
+            .into_iter()
+            .flatten()
+        {
+            codec.curbit = -1;
+        }
+    }
+
+    /// The image part of the next chunk's header, which only the first
+    /// chunk has.
+    fn image_header(&self) -> Result<Option<Iw44ImageHeader>, EncoderError> {
+        let map = self.y_codec.map();
+        let (w, h) = (map.width(), map.height());
+        if w == 0 || h == 0 {
+            return Err(EncoderError::EmptyObject);
+        }
+        if self.serial != 0 {
+            return Ok(None);
+        }
+        let chroma = self.cb_codec.is_some().then(|| ChromaLayout {
+            half: self.crcb_half,
+            delay: self.crcb_delay.max(0) as u8,
+        });
+        Iw44ImageHeader::new(w, h, chroma).map(Some)
+    }
+
+    /// The chunk `encode_slices` left open, or a new one on the coder the
+    /// last chunk used.
+    fn open_chunk(&mut self) -> Result<PendingChunk, EncoderError> {
+        if let Some(pending) = self.pending.take() {
+            return Ok(pending);
+        }
+        let zp = match self.spare_coder.take() {
+            Some(zp) => zp,
+            None => SliceCoder::new(Cursor::new(Vec::new()), true)?,
+        };
+        Ok(PendingChunk { zp, slices: 0 })
+    }
+
+    /// Codes the next slice of every component.
+    ///
+    /// A slice, as counted in the chunk header, is one slice of Y followed,
+    /// once `crcb_delay` slices have been coded, by one of Cb and one of Cr.
+    /// A component that is done codes nothing, and the image goes on while
+    /// any of them is not: DjVuLibre's decoder stops at the first slice
+    /// where all three are done, so this has to match it exactly.
+    fn code_slice(&mut self, zp: &mut SliceCoder) -> Result<bool, EncoderError> {
+        // Encode one slice using codec-controlled scheduling (mirrors DjVuLibre)
+        // Each codec manages its own curbit/curband state independently
+        let mut should_continue = self.y_codec.code_slice(zp)?;
+        if self.total_slices as i32 >= self.crcb_delay {
+            if let Some(cb) = &mut self.cb_codec {
+                debug!(target: target::IW44, "Encoding Cb slice {}", self.total_slices);
+                should_continue |= cb.code_slice(zp)?;
+            }
+            if let Some(cr) = &mut self.cr_codec {
+                debug!(target: target::IW44, "Encoding Cr slice {}", self.total_slices);
+                should_continue |= cr.code_slice(zp)?;
+            }
+        }
+        // A slice is always processed, so we always increment
+        self.total_slices += 1;
+        Ok(should_continue)
+    }
+
+    /// Whether the decibel targets are met (never in lossless mode).
+    fn quality_reached(&self) -> bool {
+        let y_target = self.params.decibels_y.or(self.params.decibels);
+        let chroma_target = self.params.decibels_chroma;
+        if self.params.lossless || (y_target.is_none() && chroma_target.is_none()) {
+            return false;
+        }
+        let db_frac = self.params.db_frac;
+        let quality = |codec: &Codec| match self.params.quality_metric {
+            QualityMetric::Psnr => codec.estimate_decibel(db_frac),
+            QualityMetric::Ssim => codec.estimate_ssim_decibel(db_frac),
+        };
+        let y_done = y_target.is_none_or(|db| quality(&self.y_codec) >= db);
+        let chroma_done = match (chroma_target, &self.cb_codec, &self.cr_codec) {
+            (Some(db), Some(cb), Some(cr)) => {
+                self.total_slices as i32 > self.crcb_delay && quality(cb).min(quality(cr)) >= db
+            }
+            _ => true,
+        };
+        y_done && chroma_done
+    }
+
+    /// Flushes `zp` into a chunk of `slices` slices.
+    fn close_chunk(
+        &mut self,
+        mut zp: SliceCoder,
+        slices: usize,
+        image: Option<Iw44ImageHeader>,
+    ) -> Result<Vec<u8>, EncoderError> {
+        let mut zp_data = zp.flush()?.into_inner();
 
         // IMPORTANT: DjVuLibre may output a chunk that contains only headers (no ZP payload)
         // when all processed slices are null. The slice count in the primary header is still
         // meaningful to the decoder. Therefore we must not drop the chunk when zp_data is empty.
         if zp_data.is_empty() {
-            info!(
-                "encode_chunk: Encoded {} slices but ZP payload is empty (all-null slices). Emitting header-only chunk.",
-                slices_encoded
+            debug!(
+                target: target::IW44,
+                "Encoded {} slices but ZP payload is empty (all-null slices). Emitting header-only chunk.",
+                slices
             );
         }
 
-        // Write IW44 chunk header
-        chunk_data.push(self.serial);
-        chunk_data.push(slices_encoded as u8);
-
-        // Full secondary header only for the first chunk (serial == 0)
-        if self.serial == 0 {
-            let is_color = self.cb_codec.is_some() && self.cr_codec.is_some();
-            // Major version: bit 7 set (0x80) indicates grayscale/BM44, clear indicates color/PM44
-            // C++ uses: major = 1 | 0x80 for grayscale, major = 1 for color
-            let major = if is_color { 1 } else { 1 | 0x80 };
-            chunk_data.push(major);
-            chunk_data.push(2); // Minor version 2 per C++
-            chunk_data.extend_from_slice(&(w as u16).to_be_bytes());
-            chunk_data.extend_from_slice(&(h as u16).to_be_bytes());
-
-            // Tertiary header CrCbDelay byte: For grayscale (no chroma), use 0x00.
-            // For color images, set 0x80 flag and OR in the delay value.
-            // From C++ IW44EncodeCodec.cpp:
-            // - CRCBfull: crcb_half=0, crcb_delay=0 -> crcbdelay = 0x80 | 0 = 0x80
-            // - CRCBnormal: crcb_half=0, crcb_delay=10 -> crcbdelay = 0x80 | 10 = 0x8a
-            // - CRCBhalf: crcb_half=1, crcb_delay=10 -> crcbdelay = 0x00 | 10 = 0x0a
-            let crcb_delay_byte: u8 = if is_color {
-                let mut byte = 0x80;
-                if self.crcb_delay >= 0 && !self.crcb_half {
-                    byte |= self.crcb_delay as u8;
-                }
-                byte
-            } else {
-                0x00
-            };
-            chunk_data.push(crcb_delay_byte);
+        let mut chunk_data = Vec::new();
+        Iw44ChunkHeader {
+            serial: self.serial,
+            slices: slices as u8,
+            image,
         }
-
-        // Append ZP payload
+        .write(&mut chunk_data);
         chunk_data.extend_from_slice(&zp_data);
 
-        // Determine if more chunks are needed
-        let more = self.y_codec.curbit >= 0;
+        // Keep the coder and its buffer for the next chunk.
+        zp_data.clear();
+        zp.reset(Cursor::new(zp_data));
+        self.spare_coder = Some(zp);
 
         // Increment serial for next chunk
         self.serial = self.serial.wrapping_add(1);
+        Ok(chunk_data)
+    }
+}
 
-        Ok((chunk_data, more))
+/// A wavelet transform buffer filled a row at a time, for
+/// [`IWEncoder::from_scanlines`].
+struct ScanPlane {
+    width: usize,
+    height: usize,
+    stride: usize,
+    data: Vec<i16>,
+}
+
+impl ScanPlane {
+    fn new(width: usize, height: usize) -> Self {
+        let (stride, padded_height) = CoeffMap::padded_size(width, height);
+        Self {
+            width,
+            height,
+            stride,
+            data: vec![0; stride * padded_height],
+        }
+    }
+
+    /// Stores row `y` (counted from the top); the buffer is bottom-up, like
+    /// DjVuLibre's.
+    fn put_row(&mut self, y: usize, row: &[i8]) {
+        let start = (self.height - 1 - y) * self.stride;
+        for (dst, &v) in self.data[start..start + self.width].iter_mut().zip(row) {
+            *dst = (v as i16) << super::constants::IW_SHIFT;
+        }
+    }
+
+    fn into_map(self) -> CoeffMap {
+        CoeffMap::create_from_padded_plane(self.width, self.height, self.data, None)
     }
 }
+
+/// One slice coded by [`IWEncoder::encode_slices`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SliceRecord {
+    /// Wavelet band (0-9) of the slice.
+    pub band: u8,
+    /// Bit plane of the slice, counting from the coarsest; -1 for the
+    /// trailing slices of a color image that only code chrominance.
+    pub bit: i32,
+    /// Bytes the coder emitted while coding it. The arithmetic coder lags
+    /// a few bytes behind, so the sum over a chunk falls short of its size
+    /// by the final flush and the header.
+    pub bytes: usize,
+}
+
+// Create the ZP encoder for IW44 only. When the `asm_zp` feature is enabled,
+// use the assembly-backed encoder; otherwise, use the Rust implementation.
+#[cfg(feature = "asm_zp")]
+type SliceCoder = crate::encode::zc::asm::ZEncoder<Cursor<Vec<u8>>>;
+#[cfg(not(feature = "asm_zp"))]
+type SliceCoder = crate::encode::zc::zcodec::ZEncoder<Cursor<Vec<u8>>>;
+
+/// How many times the bytes its band took the last time a slice is assumed
+/// to take at most in [`IWEncoder::encode_chunk_within`].
+const SLICE_GROWTH: usize = 4;
+
+/// The state before a slice, taken by [`IWEncoder::save_slice`].
+struct SavedSlice {
+    codecs: [Option<CodecState>; 3],
+    total_slices: usize,
+    zp: ZpCheckpoint,
+}
+
+/// A chunk being coded by [`IWEncoder::encode_slices`].
+struct PendingChunk {
+    zp: SliceCoder,
+    slices: usize,
+}
@@ -0,0 +1,240 @@
+// src/encode/iw44/estimate.rs
+
+//! Chunk size prediction from coefficient statistics.
+//!
+//! [`estimate_chunk_bytes`] replays the slice schedule of
+//! [`IWEncoder::encode_chunk`] without running the ZP coder. For every band
+//! it counts how many coefficients become significant at each threshold;
+//! each slice then costs the entropy of those significance decisions plus
+//! fixed rates per new and per refined coefficient. Quality
+//! targets are checked with the same block error measure the encoder uses,
+//! modelled from the thresholds; SSIM targets
+//! ([`QualityMetric::Ssim`](super::QualityMetric::Ssim)) are modelled as
+//! PSNR ones, so expect a larger error for them.
+
+use super::codec::Codec;
+use super::constants::{BAND_BUCKETS, IW_SHIFT};
+use super::encoder::IWEncoder;
+use super::header::Iw44ChunkHeader;
+
+/// Threshold halvings tracked per band; thresholds start below 2^20.
+const MAX_PASSES: usize = 24;
+
+// Fitted against `encode_chunk` on photographs, scans, gradients and noise.
+/// Bits per significance decision, relative to the band's entropy: bucket
+/// flags and adaptive contexts skip most of the zeros.
+const SIGNIFICANCE_FACTOR: f64 = 0.089;
+/// Bits per newly significant coefficient: its sign and the bucket and
+/// block flags that lead to it.
+const NEW_COEFFICIENT_BITS: f64 = 4.67;
+/// Bits per refinement of an already significant coefficient.
+const REFINEMENT_BITS: f64 = 0.73;
+
+/// Predicts the size in bytes of the first chunk `encoder` would produce
+/// with `encode_chunk(max_slices)`, header included.
+pub fn estimate_chunk_bytes(encoder: &IWEncoder, max_slices: usize) -> usize {
+    let params = encoder.params();
+    let (y, chroma, crcb_delay) = encoder.codecs();
+    let mut y = CodecModel::new(y);
+    let mut chroma = chroma.map(|(cb, cr)| (CodecModel::new(cb), CodecModel::new(cr)));
+
+    let y_target = params.decibels_y.or(params.decibels);
+    let chroma_target = params.decibels_chroma;
+    let limit = params.slices.map_or(max_slices, |s| s.min(max_slices));
+
+    let mut slices = 0;
+    while slices < limit && !y.done {
+        y.code_slice();
+        if let Some((cb, cr)) = &mut chroma
+            && slices as i32 >= crcb_delay
+        {
+            cb.code_slice();
+            cr.code_slice();
+        }
+        slices += 1;
+        if let Some(bytes) = params.bytes
+            && total_bits(&y, &chroma) / 8.0 >= bytes as f64
+        {
+            break;
+        }
+        if params.lossless || (y_target.is_none() && chroma_target.is_none()) {
+            continue;
+        }
+        let y_done = y_target.is_none_or(|db| y.decibels(params.db_frac) >= db);
+        let chroma_done = match (chroma_target, &chroma) {
+            (Some(db), Some((cb, cr))) => {
+                slices as i32 > crcb_delay
+                    && cb.decibels(params.db_frac).min(cr.decibels(params.db_frac)) >= db
+            }
+            _ => true,
+        };
+        if y_done && chroma_done {
+            break;
+        }
+    }
+
+    Iw44ChunkHeader::FIRST_LEN + (total_bits(&y, &chroma) / 8.0).ceil() as usize
+}
+
+fn total_bits(y: &CodecModel, chroma: &Option<(CodecModel, CodecModel)>) -> f64 {
+    y.bits + chroma.as_ref().map_or(0.0, |(cb, cr)| cb.bits + cr.bits)
+}
+
+/// Significance statistics of one band.
+struct BandStats {
+    coefficients: u64,
+    /// Coefficients that become significant at each pass over the band.
+    activations: [u64; MAX_PASSES],
+}
+
+struct CodecModel {
+    bands: Vec<BandStats>,
+    /// Band-zero coefficients of each block, for the quality estimate.
+    dc: Vec<[i32; 16]>,
+    quant_lo: [i32; 16],
+    last_hi: i32,
+    passes: [usize; 10],
+    curband: usize,
+    bits: f64,
+    done: bool,
+}
+
+impl CodecModel {
+    fn new(codec: &Codec) -> Self {
+        let map = codec.map();
+        let mut bands: Vec<BandStats> = (0..10)
+            .map(|_| BandStats {
+                coefficients: 0,
+                activations: [0; MAX_PASSES],
+            })
+            .collect();
+        let mut dc = Vec::with_capacity(map.num_blocks);
+
+        for block in &map.blocks {
+            let mut block_dc = [0i32; 16];
+            for (band, info) in BAND_BUCKETS.iter().enumerate() {
+                for bucket in info.start..info.start + info.size {
+                    let coeffs = block.get_bucket_raw(bucket as u8);
+                    for (i, &c) in coeffs.iter().enumerate() {
+                        let magnitude = (c as i32).abs();
+                        let quant = if band == 0 {
+                            block_dc[i] = c as i32;
+                            codec.quant_lo[i]
+                        } else {
+                            codec.quant_hi[band]
+                        };
+                        let stats = &mut bands[band];
+                        stats.coefficients += 1;
+                        if let Some(pass) = activation_pass(magnitude, quant) {
+                            stats.activations[pass] += 1;
+                        }
+                    }
+                }
+            }
+            dc.push(block_dc);
+        }
+
+        Self {
+            bands,
+            dc,
+            quant_lo: codec.quant_lo,
+            last_hi: codec.quant_hi[9],
+            passes: [0; 10],
+            curband: 0,
+            bits: 0.0,
+            done: false,
+        }
+    }
+
+    fn code_slice(&mut self) {
+        if self.done {
+            return;
+        }
+        let band = &self.bands[self.curband];
+        let pass = self.passes[self.curband];
+        if pass < MAX_PASSES {
+            let active: u64 = band.activations[..pass].iter().sum();
+            let new = band.activations[pass];
+            let unknown = band.coefficients - active;
+            if new + active > 0 {
+                let p = new as f64 / unknown as f64;
+                self.bits += SIGNIFICANCE_FACTOR * unknown as f64 * entropy(p)
+                    + NEW_COEFFICIENT_BITS * new as f64
+                    + REFINEMENT_BITS * active as f64;
+            }
+        }
+        self.passes[self.curband] += 1;
+
+        self.curband += 1;
+        if self.curband == BAND_BUCKETS.len() {
+            self.curband = 0;
+            if self.last_hi >> self.passes[9] == 0 {
+                self.done = true;
+            }
+        }
+    }
+
+    /// Mirrors `Codec::estimate_decibel`: the mean error of the worst
+    /// blocks, from band zero. The codec reconstructs magnitudes only and
+    /// compares them with the signed coefficients, so negative ones count
+    /// against the estimate as they become significant; the model does the
+    /// same.
+    fn decibels(&self, db_frac: f32) -> f32 {
+        let passes = self.passes[0];
+        let mut xmse: Vec<f32> = self
+            .dc
+            .iter()
+            .map(|block| {
+                let mut mse = 0.0f32;
+                for (i, &m) in block.iter().enumerate() {
+                    let e = reconstruct(m.abs(), self.quant_lo[i], passes);
+                    let diff = (m - e) as f32;
+                    mse += diff * diff;
+                }
+                mse / 1024.0
+            })
+            .collect();
+        xmse.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let p = (xmse.len() as f32 * (1.0 - db_frac)).floor() as usize;
+        let worst = &xmse[p.min(xmse.len().saturating_sub(1))..];
+        let mse_avg = worst.iter().sum::<f32>() / worst.len().max(1) as f32;
+        let factor = 255.0 * (1 << IW_SHIFT) as f32;
+        10.0 * (factor * factor / mse_avg).log10()
+    }
+}
+
+/// The magnitude the codec has reconstructed for a coefficient after
+/// `passes` passes starting at threshold `quant`.
+fn reconstruct(magnitude: i32, quant: i32, passes: usize) -> i32 {
+    let mut e = 0;
+    for pass in 0..passes.min(MAX_PASSES) {
+        let t = quant >> pass;
+        if t == 0 || t >= 0x8000 {
+            continue;
+        }
+        if e == 0 {
+            if magnitude >= t {
+                e = t + (t >> 1);
+            }
+        } else {
+            e = e - if magnitude >= e { 0 } else { t } + (t >> 1);
+        }
+    }
+    e
+}
+
+/// First pass whose threshold (`quant` halved once per pass) is at most
+/// `magnitude`; zero coefficients never become significant.
+fn activation_pass(magnitude: i32, quant: i32) -> Option<usize> {
+    if magnitude == 0 {
+        return None;
+    }
+    (0..MAX_PASSES).find(|&p| (quant >> p) <= magnitude)
+}
+
+fn entropy(p: f64) -> f64 {
+    if p <= 0.0 || p >= 1.0 {
+        return 0.0;
+    }
+    -(p * p.log2() + (1.0 - p) * (1.0 - p).log2())
+}
@@ -0,0 +1,285 @@
+// src/encode/iw44/header.rs
+
+//! IW44 chunk headers.
+//!
+//! Every `BG44`/`FG44`/`BM44`/`PM44` chunk starts with its serial number and
+//! slice count. The first chunk of an image (serial 0) goes on with the
+//! version, the image size and the chrominance layout:
+//!
+//! ```text
+//! serial  slices  major  minor  width(BE16)  height(BE16)  crcb
+//! ```
+//!
+//! `major` has [`IW44_GRAYSCALE_FLAG`] set for grayscale images; `crcb`
+//! holds the number of slices coded before chrominance starts, with
+//! [`IW44_CHROMA_FULL_FLAG`] set unless the chrominance is coded at half
+//! resolution. Grayscale images write 0 there.
+
+use super::constants::{
+    IW44_CHROMA_FULL_FLAG, IW44_GRAYSCALE_FLAG, IW44_MAJOR_VERSION, IW44_MAX_DIMENSION,
+    IW44_MINOR_VERSION,
+};
+use super::encoder::EncoderError;
+use crate::DjvuError;
+use crate::iff::parse_mode::ParseLog;
+
+/// How the chrominance of a color image is coded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChromaLayout {
+    /// Chrominance is coded at half resolution.
+    pub half: bool,
+    /// Slices coded before the chrominance starts (0..=127).
+    pub delay: u8,
+}
+
+/// The part of the header only the first chunk carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Iw44ImageHeader {
+    pub major: u8,
+    pub minor: u8,
+    pub width: u16,
+    pub height: u16,
+    /// `None` for grayscale images.
+    pub chroma: Option<ChromaLayout>,
+}
+
+impl Iw44ImageHeader {
+    /// Header for a `width` x `height` image, rejecting sizes the 16-bit
+    /// fields cannot hold.
+    pub fn new(
+        width: usize,
+        height: usize,
+        chroma: Option<ChromaLayout>,
+    ) -> Result<Self, EncoderError> {
+        if width > IW44_MAX_DIMENSION || height > IW44_MAX_DIMENSION {
+            return Err(EncoderError::TooLarge(width, height));
+        }
+        if let Some(layout) = chroma
+            && layout.delay > 0x7f
+        {
+            return Err(EncoderError::InvalidParam(format!(
+                "Chrominance delay {} does not fit in 7 bits",
+                layout.delay
+            )));
+        }
+        Ok(Self {
+            major: IW44_MAJOR_VERSION,
+            minor: IW44_MINOR_VERSION,
+            width: width as u16,
+            height: height as u16,
+            chroma,
+        })
+    }
+
+    pub fn is_color(&self) -> bool {
+        self.chroma.is_some()
+    }
+}
+
+/// The header at the start of an IW44 chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Iw44ChunkHeader {
+    pub serial: u8,
+    pub slices: u8,
+    /// Present exactly when `serial` is 0.
+    pub image: Option<Iw44ImageHeader>,
+}
+
+impl Iw44ChunkHeader {
+    /// Length of the header of the first chunk.
+    pub const FIRST_LEN: usize = 9;
+    /// Length of the header of every later chunk.
+    pub const NEXT_LEN: usize = 2;
+
+    /// Length of this header once written.
+    pub fn encoded_len(&self) -> usize {
+        match self.image {
+            Some(image) if image.minor < 2 => Self::FIRST_LEN - 1,
+            Some(_) => Self::FIRST_LEN,
+            None => Self::NEXT_LEN,
+        }
+    }
+
+    /// Appends the header to `out`.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.serial);
+        out.push(self.slices);
+        let Some(image) = self.image else {
+            return;
+        };
+        let major = match image.chroma {
+            Some(_) => image.major,
+            None => image.major | IW44_GRAYSCALE_FLAG,
+        };
+        out.push(major);
+        out.push(image.minor);
+        out.extend_from_slice(&image.width.to_be_bytes());
+        out.extend_from_slice(&image.height.to_be_bytes());
+        if image.minor >= 2 {
+            out.push(match image.chroma {
+                Some(ChromaLayout { half: true, delay }) => delay,
+                Some(ChromaLayout { half: false, delay }) => IW44_CHROMA_FULL_FLAG | delay,
+                None => 0,
+            });
+        }
+    }
+
+    /// Like [`Self::parse`], in the mode of `log`: a permissive parse takes
+    /// a first chunk cut short before its chrominance byte as coding the
+    /// chrominance at full resolution from the start, like versions
+    /// before 2 do.
+    pub fn parse_with(data: &[u8], log: &mut ParseLog) -> crate::Result<Self> {
+        if let &[0, _, _, minor, _, _, _, _] = data
+            && minor >= 2
+        {
+            log.violation(8, "First IW44 chunk lacks its chrominance byte")?;
+            let mut data = data.to_vec();
+            data.push(IW44_CHROMA_FULL_FLAG);
+            return Self::parse_with(&data, log);
+        }
+        Self::parse(data).map_err(|e| DjvuError::ValidationError(e.to_string()))
+    }
+
+    /// Reads the header at the start of a chunk's data.
+    pub fn parse(data: &[u8]) -> Result<Self, EncoderError> {
+        let invalid = |msg: &str| Err(EncoderError::InvalidHeader(msg.to_string()));
+        let &[serial, slices, ref rest @ ..] = data else {
+            return invalid("chunk shorter than 2 bytes");
+        };
+        if serial != 0 {
+            return Ok(Self {
+                serial,
+                slices,
+                image: None,
+            });
+        }
+        let &[major, minor, wh, wl, hh, hl, ref rest @ ..] = rest else {
+            return invalid("first chunk shorter than 8 bytes");
+        };
+        if major & !IW44_GRAYSCALE_FLAG != IW44_MAJOR_VERSION {
+            return invalid(&format!("unsupported major version {}", major & 0x7f));
+        }
+        let color = major & IW44_GRAYSCALE_FLAG == 0;
+        let crcb = match (minor, rest.first()) {
+            (0..2, _) => IW44_CHROMA_FULL_FLAG,
+            (_, Some(&byte)) => byte,
+            (_, None) => return invalid("first chunk shorter than 9 bytes"),
+        };
+        Ok(Self {
+            serial,
+            slices,
+            image: Some(Iw44ImageHeader {
+                major: major & !IW44_GRAYSCALE_FLAG,
+                minor,
+                width: u16::from_be_bytes([wh, wl]),
+                height: u16::from_be_bytes([hh, hl]),
+                chroma: color.then_some(ChromaLayout {
+                    half: crcb & IW44_CHROMA_FULL_FLAG == 0,
+                    delay: crcb & 0x7f,
+                }),
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // First-chunk headers as written by DjVuLibre's c44 for a 640x480 page
+    // with its default 74-slice first chunk.
+    const C44_COLOR: [u8; 9] = [0x00, 0x4a, 0x01, 0x02, 0x02, 0x80, 0x01, 0xe0, 0x8a];
+    const C44_COLOR_FULL: [u8; 9] = [0x00, 0x4a, 0x01, 0x02, 0x02, 0x80, 0x01, 0xe0, 0x80];
+    const C44_COLOR_HALF: [u8; 9] = [0x00, 0x4a, 0x01, 0x02, 0x02, 0x80, 0x01, 0xe0, 0x0a];
+    const C44_GRAY: [u8; 9] = [0x00, 0x4a, 0x81, 0x02, 0x02, 0x80, 0x01, 0xe0, 0x00];
+
+    fn first(chroma: Option<ChromaLayout>) -> Vec<u8> {
+        let header = Iw44ChunkHeader {
+            serial: 0,
+            slices: 74,
+            image: Some(Iw44ImageHeader::new(640, 480, chroma).unwrap()),
+        };
+        let mut out = Vec::new();
+        header.write(&mut out);
+        assert_eq!(out.len(), header.encoded_len());
+        assert_eq!(Iw44ChunkHeader::parse(&out).unwrap(), header);
+        out
+    }
+
+    #[test]
+    fn test_matches_c44() {
+        let layout = |half, delay| Some(ChromaLayout { half, delay });
+        assert_eq!(first(layout(false, 10)), C44_COLOR);
+        assert_eq!(first(layout(false, 0)), C44_COLOR_FULL);
+        assert_eq!(first(layout(true, 10)), C44_COLOR_HALF);
+        assert_eq!(first(None), C44_GRAY);
+
+        let next = Iw44ChunkHeader::parse(&[3, 15, 0xaa]).unwrap();
+        assert_eq!((next.serial, next.slices, next.image), (3, 15, None));
+        assert_eq!(next.encoded_len(), Iw44ChunkHeader::NEXT_LEN);
+    }
+
+    #[test]
+    fn test_rejects_bad_headers() {
+        assert!(matches!(
+            Iw44ImageHeader::new(65536, 10, None),
+            Err(EncoderError::TooLarge(65536, 10))
+        ));
+        assert!(Iw44ImageHeader::new(65535, 65535, None).is_ok());
+        assert!(Iw44ChunkHeader::parse(&[0]).is_err());
+        assert!(Iw44ChunkHeader::parse(&C44_COLOR[..8]).is_err());
+        let mut future = C44_COLOR;
+        future[2] = 2;
+        assert!(Iw44ChunkHeader::parse(&future).is_err());
+    }
+
+    #[test]
+    fn test_parse_modes() {
+        use crate::iff::ParseMode;
+
+        let mut strict = ParseLog::default();
+        assert!(Iw44ChunkHeader::parse_with(&C44_COLOR[..8], &mut strict).is_err());
+        assert_eq!(
+            Iw44ChunkHeader::parse_with(&C44_COLOR, &mut strict).unwrap(),
+            Iw44ChunkHeader::parse(&C44_COLOR).unwrap()
+        );
+
+        let mut permissive = ParseLog::new(ParseMode::Permissive);
+        let header = Iw44ChunkHeader::parse_with(&C44_COLOR[..8], &mut permissive).unwrap();
+        assert_eq!(
+            header.image.unwrap().chroma,
+            Some(ChromaLayout {
+                half: false,
+                delay: 0
+            })
+        );
+        assert_eq!(permissive.warnings().len(), 1);
+        // Damage past recovery still fails.
+        assert!(Iw44ChunkHeader::parse_with(&C44_COLOR[..7], &mut permissive).is_err());
+    }
+
+    #[test]
+    fn test_encoder_writes_parsable_headers() {
+        use super::super::encoder::{CrcbMode, EncoderParams, IWEncoder};
+        use crate::image::image_formats::{Pixel, Pixmap};
+
+        let img = Pixmap::from_fn(33, 17, |x, y| Pixel::new(x as u8 * 7, y as u8 * 9, 90));
+        for (mode, crcb) in [
+            (CrcbMode::Normal, 0x8a),
+            (CrcbMode::Half, 0x0a),
+            (CrcbMode::Full, 0x80),
+        ] {
+            let params = EncoderParams {
+                slices: Some(20),
+                crcb_mode: mode,
+                ..EncoderParams::default()
+            };
+            let mut encoder = IWEncoder::from_rgb(&img, None, params).unwrap();
+            let (chunk, _) = encoder.encode_chunk(20).unwrap();
+            let header = Iw44ChunkHeader::parse(&chunk).unwrap();
+            let image = header.image.unwrap();
+            assert_eq!((image.width, image.height), (33, 17));
+            assert_eq!(chunk[8], crcb);
+        }
+    }
+}
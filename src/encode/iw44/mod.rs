@@ -5,10 +5,20 @@
 //! This module provides the IW44 (Incremental Wavelet 44) encoding functionality
 //! for DjVu image compression.
 
+#[cfg(feature = "std")]
 pub mod codec;
+#[cfg(feature = "std")]
 pub mod coeff_map;
+#[cfg(feature = "std")]
+pub mod color_transform;
 pub mod constants;
+#[cfg(feature = "std")]
 pub mod encoder;
+#[cfg(feature = "std")]
+pub mod estimate;
+#[cfg(feature = "std")]
+pub mod header;
+#[cfg(feature = "std")]
 pub mod masking;
 #[cfg(test)]
 mod tests;
@@ -16,9 +26,17 @@ pub mod transform;
 pub mod zigzag;
 
 // Re-export commonly used types and functions
+#[cfg(feature = "std")]
 pub use codec::*;
+#[cfg(feature = "std")]
 pub use coeff_map::*;
+#[cfg(feature = "std")]
+pub use color_transform::*;
 pub use constants::*;
+#[cfg(feature = "std")]
 pub use encoder::*;
+#[cfg(feature = "std")]
+pub use header::{ChromaLayout, Iw44ChunkHeader, Iw44ImageHeader};
+#[cfg(feature = "std")]
 pub use masking::*;
 pub use zigzag::{ZIGZAG_LOC, get_zigzag_loc, get_zigzag_loc_checked};
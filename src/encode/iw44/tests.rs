@@ -157,6 +157,415 @@ mod tests {
         assert_eq!(params.db_frac, 0.35);
     }
 
+    #[test]
+    fn test_codec_snapshot_restore_matches_untouched_run() {
+        use crate::encode::iw44::{Codec, CoeffMap};
+        use crate::encode::zc::ZEncoder;
+        use crate::image::image_formats::{Bitmap, GrayPixel};
+        use std::io::Cursor;
+
+        let data = (0..64 * 64)
+            .map(|i| GrayPixel::new(((i * 37) % 251) as u8))
+            .collect();
+        let map = CoeffMap::create_from_image(&Bitmap::from_vec(64, 64, data), None);
+        let params = EncoderParams::default();
+
+        let encode = |trial: bool| {
+            let mut codec = Codec::new(map.clone(), &params);
+            let mut zp = ZEncoder::new(Cursor::new(Vec::new()), true).unwrap();
+            for _ in 0..8 {
+                codec.code_slice(&mut zp).unwrap();
+            }
+            if trial {
+                let (state, checkpoint) = (codec.snapshot(), zp.checkpoint());
+                for _ in 0..12 {
+                    codec.code_slice(&mut zp).unwrap();
+                }
+                codec.restore(state);
+                zp.restore(&checkpoint).unwrap();
+            }
+            for _ in 0..4 {
+                codec.code_slice(&mut zp).unwrap();
+            }
+            zp.finish().unwrap().into_inner()
+        };
+
+        assert_eq!(encode(true), encode(false));
+    }
+
+    #[test]
+    fn test_chroma_target_extends_luma_target() {
+        use crate::encode::iw44::IWEncoder;
+        use crate::image::image_formats::{Pixel, Pixmap};
+
+        let img = Pixmap::from_fn(64, 64, |x, y| {
+            Pixel::new((x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8)
+        });
+        let slices = |decibels_chroma| {
+            let params = EncoderParams {
+                decibels_y: Some(20.0),
+                decibels_chroma,
+                slices: None,
+                crcb_mode: CrcbMode::Normal,
+                ..EncoderParams::default()
+            };
+            let mut encoder = IWEncoder::from_rgb(&img, None, params).unwrap();
+            let (chunk, more) = encoder.encode_chunk(usize::MAX).unwrap();
+            assert!(!more);
+            chunk[1]
+        };
+
+        let luma_only = slices(None);
+        assert!(slices(Some(40.0)) > luma_only);
+        // Even a trivially met chroma target waits for chroma to start, after
+        // the 10-slice delay of CrcbMode::Normal.
+        assert_eq!(slices(Some(-100.0)), 11.max(luma_only));
+    }
+
+    #[test]
+    fn test_slices_match_chunks() {
+        use crate::encode::iw44::IWEncoder;
+        use crate::image::image_formats::{Pixel, Pixmap};
+
+        let img = Pixmap::from_fn(48, 40, |x, y| {
+            Pixel::new((x * 5) as u8, (y * 6) as u8, ((x + y) * 3) as u8)
+        });
+        let params = EncoderParams {
+            slices: Some(74),
+            crcb_mode: CrcbMode::Normal,
+            ..EncoderParams::default()
+        };
+        let mut chunked = IWEncoder::from_rgb(&img, None, params).unwrap();
+        let mut sliced = IWEncoder::from_rgb(&img, None, params).unwrap();
+
+        // The same boundaries give the same chunks, whichever API picks them.
+        for n in [5, 30, 20] {
+            let (chunk, _) = chunked.encode_chunk(n).unwrap();
+            let records = sliced.encode_slices(2).unwrap();
+            let rest = sliced.encode_slices(n - 2).unwrap();
+            assert_eq!(records.len() + rest.len(), n);
+            assert_eq!(sliced.finish_chunk().unwrap(), Some(chunk));
+        }
+        assert_eq!(sliced.finish_chunk().unwrap(), None);
+
+        // Slices run band by band, then move to the next bit plane.
+        let records = sliced.encode_slices(12).unwrap();
+        let bands: Vec<u8> = records.iter().map(|r| r.band).collect();
+        assert_eq!(bands, [5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(records[4].bit + 1, records[5].bit);
+        assert!(sliced.finish_chunk().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_chunks_within_byte_budget() {
+        use crate::encode::iw44::{IWEncoder, Iw44ChunkHeader};
+        use crate::image::image_formats::{Pixel, Pixmap};
+
+        let img = Pixmap::from_fn(96, 80, |x, y| {
+            let noise = (x * 7919 + y * 104_729) % 251;
+            Pixel::new(noise as u8, (x * 3 + noise) as u8, (y * 2) as u8)
+        });
+        let params = EncoderParams {
+            crcb_mode: CrcbMode::Normal,
+            ..EncoderParams::default()
+        };
+
+        // A budget nothing reaches changes nothing.
+        let mut unbounded = IWEncoder::from_rgb(&img, None, params).unwrap();
+        let mut reference = IWEncoder::from_rgb(&img, None, params).unwrap();
+        assert_eq!(
+            unbounded.encode_chunk_within(usize::MAX, 40).unwrap(),
+            reference.encode_chunk(40).unwrap()
+        );
+
+        // Every other chunk believes its slices tiny, so the slice that
+        // overflows has no saved state and the chunk is coded again.
+        let mut split = IWEncoder::from_rgb(&img, None, params).unwrap();
+        let mut chunks = Vec::new();
+        for (i, budget) in [1500, 60, 1500, 300, 4000, 90]
+            .into_iter()
+            .cycle()
+            .enumerate()
+        {
+            if i % 2 == 1 {
+                split.band_bytes = [1; 10];
+            }
+            let (chunk, more) = split.encode_chunk_within(budget, 74).unwrap();
+            chunks.push((chunk, budget));
+            if !more {
+                break;
+            }
+        }
+        assert!(chunks.len() > 2);
+
+        // Rolled back slices leave no trace: cutting a fresh encoder at the
+        // same slices gives the same chunks.
+        let mut sliced = IWEncoder::from_rgb(&img, None, params).unwrap();
+        for (chunk, budget) in &chunks {
+            let slices = Iw44ChunkHeader::parse(chunk).unwrap().slices as usize;
+            assert!(
+                chunk.len() <= *budget || slices == 1,
+                "{} bytes",
+                chunk.len()
+            );
+            assert_eq!(sliced.encode_slices(slices).unwrap().len(), slices);
+            assert_eq!(sliced.finish_chunk().unwrap().as_ref(), Some(chunk));
+        }
+        assert!(sliced.is_finished());
+    }
+
+    #[test]
+    fn test_delayed_chroma_is_coded_to_the_end() {
+        use crate::encode::iw44::{IWEncoder, Iw44ChunkHeader};
+        use crate::image::image_formats::{Pixel, Pixmap};
+
+        let img = Pixmap::from_fn(40, 24, |x, y| {
+            Pixel::new((x * 6) as u8, (y * 9) as u8, ((x ^ y) * 5) as u8)
+        });
+        let params = EncoderParams {
+            crcb_mode: CrcbMode::Normal,
+            ..EncoderParams::default()
+        };
+        let mut encoder = IWEncoder::from_rgb(&img, None, params).unwrap();
+        let mut records = Vec::new();
+        let mut header_slices = 0;
+        while !encoder.is_finished() {
+            records.extend(encoder.encode_slices(50).unwrap());
+            let chunk = encoder.finish_chunk().unwrap().unwrap();
+            header_slices += Iw44ChunkHeader::parse(&chunk).unwrap().slices as usize;
+        }
+
+        // Luminance and chrominance follow the same schedule, so the
+        // chrominance ends the 10 slices of CrcbMode::Normal later, and
+        // the headers count those slices too.
+        let luma = records.iter().filter(|r| r.bit >= 0).count();
+        assert_eq!(records.len(), luma + 10);
+        assert_eq!(header_slices, records.len());
+    }
+
+    #[test]
+    fn test_tiny_and_odd_sizes_encode() {
+        use crate::encode::iw44::{IWEncoder, Iw44ChunkHeader};
+        use crate::image::image_formats::{Pixel, Pixmap};
+
+        for (w, h) in [
+            (1, 1),
+            (1, 37),
+            (37, 1),
+            (3, 2),
+            (33, 17),
+            (65, 33),
+            (1, 200),
+        ] {
+            let img = Pixmap::from_fn(w, h, |x, y| {
+                Pixel::new((x * 5) as u8, (y * 6) as u8, ((x ^ y) * 3) as u8)
+            });
+            let mut encoder = IWEncoder::from_rgb(&img, None, EncoderParams::default()).unwrap();
+            let (chunk, _) = encoder.encode_chunk(74).unwrap();
+            let header = Iw44ChunkHeader::parse(&chunk).unwrap();
+            let image = header.image.unwrap();
+            assert_eq!((image.width, image.height), (w as u16, h as u16));
+            assert_eq!(header.slices, 74, "{w}x{h}");
+            assert!(chunk.len() > header.encoded_len(), "{w}x{h}");
+        }
+
+        let mut empty =
+            IWEncoder::from_rgb(&Pixmap::new(0, 4), None, EncoderParams::default()).unwrap();
+        assert!(empty.encode_chunk(74).is_err());
+    }
+
+    #[test]
+    fn test_scanlines_match_whole_image() {
+        use crate::encode::iw44::IWEncoder;
+        use crate::image::image_formats::{Pixel, Pixmap};
+
+        let (w, h) = (45, 38);
+        let img = Pixmap::from_fn(w, h, |x, y| {
+            Pixel::new((x * 5) as u8, (y * 6) as u8, ((x ^ y) * 3) as u8)
+        });
+        let rows = || img.as_raw().chunks(3 * w as usize);
+        for mode in [CrcbMode::Full, CrcbMode::Half, CrcbMode::None] {
+            let params = EncoderParams {
+                crcb_mode: mode,
+                ..EncoderParams::default()
+            };
+            let mut whole = IWEncoder::from_rgb(&img, None, params).unwrap();
+            let mut streamed = IWEncoder::from_scanlines(w, h, rows(), params).unwrap();
+            assert_eq!(
+                streamed.encode_chunk(74).unwrap(),
+                whole.encode_chunk(74).unwrap(),
+                "{mode:?}"
+            );
+        }
+
+        let gray = img.to_bitmap();
+        let gray_rows: Vec<u8> = gray.pixels().iter().map(|p| p.y).collect();
+        let params = EncoderParams::default();
+        let mut whole = IWEncoder::from_gray(&gray, None, params).unwrap();
+        let mut streamed =
+            IWEncoder::from_scanlines(w, h, gray_rows.chunks(w as usize), params).unwrap();
+        assert_eq!(
+            streamed.encode_chunk(74).unwrap(),
+            whole.encode_chunk(74).unwrap()
+        );
+
+        assert!(IWEncoder::from_scanlines(w, h + 1, rows(), params).is_err());
+        assert!(IWEncoder::from_scanlines(w, h - 1, rows(), params).is_err());
+    }
+
+    #[test]
+    fn test_strided_view_matches_pixmap() {
+        use crate::encode::iw44::IWEncoder;
+        use crate::image::image_formats::{Pixel, Pixmap};
+        use crate::image::view::{ImageView, PixelFormat};
+
+        let (w, h) = (37, 29);
+        let img = Pixmap::from_fn(w, h, |x, y| {
+            Pixel::new((x * 7) as u8, (y * 4) as u8, ((x + y) * 3) as u8)
+        });
+        // A BGRA frame with 16 bytes of padding after each row.
+        let stride = 4 * w as usize + 16;
+        let mut frame = vec![0xAAu8; stride * h as usize];
+        for (y, row) in frame.chunks_mut(stride).enumerate() {
+            for x in 0..w {
+                let p = img.get_pixel(x, y as u32);
+                row[4 * x as usize..][..4].copy_from_slice(&[p.b, p.g, p.r, 0]);
+            }
+        }
+        let params = EncoderParams {
+            crcb_mode: CrcbMode::Normal,
+            ..EncoderParams::default()
+        };
+        let view = ImageView::new(&frame, w, h, stride, PixelFormat::Bgra8).unwrap();
+        let mut whole = IWEncoder::from_rgb(&img, None, params).unwrap();
+        let mut viewed = IWEncoder::from_view(&view, params).unwrap();
+        assert_eq!(
+            viewed.encode_chunk(74).unwrap(),
+            whole.encode_chunk(74).unwrap()
+        );
+
+        let gray = img.to_bitmap();
+        let luma: Vec<u8> = gray.pixels().iter().map(|p| p.y).collect();
+        let view = ImageView::packed(&luma, w, h, PixelFormat::Gray8).unwrap();
+        let mut whole = IWEncoder::from_gray(&gray, None, params).unwrap();
+        let mut viewed = IWEncoder::from_view(&view, params).unwrap();
+        assert_eq!(
+            viewed.encode_chunk(74).unwrap(),
+            whole.encode_chunk(74).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_quant_profiles() {
+        use crate::encode::iw44::{Codec, CoeffMap, QuantProfile};
+        use crate::encode::zc::ZEncoder;
+        use crate::image::image_formats::{Bitmap, GrayPixel};
+        use std::io::Cursor;
+
+        assert_eq!(QuantProfile::default(), QuantProfile::SPEC);
+        assert_eq!(QuantProfile::tilted(1.0), QuantProfile::SPEC);
+        let steep = QuantProfile::tilted(2.0);
+        assert_eq!(steep.dead_zones[0], 0.0);
+        assert_eq!(steep.dead_zones[1], 1.0);
+        assert_eq!(steep.dead_zones[9], 7.0);
+
+        let data = (0..64 * 64)
+            .map(|i: usize| {
+                let (x, y) = (i % 64, i / 64);
+                GrayPixel::new(((x * 37 + y * 11) % 97 + (x ^ y) % 31) as u8)
+            })
+            .collect();
+        let map = CoeffMap::create_from_image(&Bitmap::from_vec(64, 64, data), None);
+        let params = |quant_profile| EncoderParams {
+            quant_profile,
+            ..EncoderParams::default()
+        };
+        // The stream and what a decoder rebuilds from it, coded to the end.
+        let encode = |map: &CoeffMap, params: &EncoderParams| {
+            let mut codec = Codec::new(map.clone(), params);
+            let mut zp = ZEncoder::new(Cursor::new(Vec::new()), true).unwrap();
+            while codec.code_slice(&mut zp).unwrap() {}
+            (zp.finish().unwrap().into_inner(), codec)
+        };
+        let psnr = |coded: &Codec| {
+            let mut sse = 0.0f64;
+            for (src, dst) in map.blocks.iter().zip(&coded.emap.blocks) {
+                for bucket in 0..64 {
+                    let (x, e) = (src.get_bucket_raw(bucket), dst.get_bucket_raw(bucket));
+                    for (&x, &e) in x.iter().zip(e) {
+                        let y = if x < 0 { -(e as f64) } else { e as f64 };
+                        sse += (x as f64 - y).powi(2);
+                    }
+                }
+            }
+            let range = 255.0 * 64.0;
+            10.0 * (range * range * (64 * 64) as f64 / sse).log10()
+        };
+
+        // A profile is coded with the spec's steps: its stream is the plain
+        // stream of the coefficients it keeps.
+        let (stream, coded) = encode(&map, &params(steep));
+        let mut kept = map.clone();
+        steep.apply(&mut kept);
+        let (plain, spec) = encode(&kept, &params(QuantProfile::SPEC));
+        assert_eq!(stream, plain);
+        assert_eq!(
+            (coded.quant_lo, coded.quant_hi),
+            (spec.quant_lo, spec.quant_hi)
+        );
+
+        let (full, everything) = encode(&map, &params(QuantProfile::SPEC));
+        assert!(stream.len() < full.len());
+        let (steep_db, full_db) = (psnr(&coded), psnr(&everything));
+        assert!(
+            steep_db < full_db && steep_db > 35.0,
+            "{steep_db} {full_db}"
+        );
+
+        let negative = QuantProfile {
+            dead_zones: [-1.0; 10],
+        };
+        assert!(
+            EncoderParams::builder()
+                .quant_profile(negative)
+                .build()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_ssim_metric_stops_on_target() {
+        use crate::encode::iw44::{IWEncoder, QualityMetric};
+        use crate::image::image_formats::{Bitmap, GrayPixel};
+
+        // Flat on the left, busy on the right.
+        let data = (0..96 * 64)
+            .map(|i| {
+                let (x, y) = (i % 96, i / 96);
+                let busy = ((x * 37 + y * 11) % 97 + (x ^ y) % 31) as u8;
+                GrayPixel::new(if x < 64 { 180 } else { busy })
+            })
+            .collect();
+        let img = Bitmap::from_vec(96, 64, data);
+        let slices = |decibels| {
+            let params = EncoderParams {
+                decibels,
+                slices: None,
+                quality_metric: QualityMetric::Ssim,
+                ..EncoderParams::default()
+            };
+            let mut encoder = IWEncoder::from_gray(&img, None, params).unwrap();
+            let (chunk, more) = encoder.encode_chunk(usize::MAX).unwrap();
+            assert!(!more);
+            chunk[1]
+        };
+
+        let all = slices(None);
+        let (low, high) = (slices(Some(15.0)), slices(Some(40.0)));
+        assert!(low < high, "{low} {high}");
+        assert!(high < all, "{high} {all}");
+    }
+
     #[test]
     fn test_crcb_mode_values() {
         // Test enum variants exist
@@ -169,5 +578,43 @@ mod tests {
         let default_mode = CrcbMode::default();
         assert!(matches!(default_mode, CrcbMode::None));
     }
-}
 
+    #[test]
+    fn test_params_builder_validates() {
+        let params = EncoderParams::builder()
+            .slices(Some(100))
+            .decibels(42.0)
+            .crcb_mode(CrcbMode::Half)
+            .build()
+            .unwrap();
+        assert_eq!(params.slices, Some(100));
+        assert_eq!(params.decibels, Some(42.0));
+        assert_eq!(params.crcb_mode, CrcbMode::Half);
+
+        assert!(EncoderParams::builder().slices(Some(0)).build().is_err());
+        assert!(EncoderParams::builder().decibels(f32::NAN).build().is_err());
+        assert!(EncoderParams::builder().db_frac(0.0).build().is_err());
+        assert!(
+            EncoderParams::builder()
+                .quant_multiplier(-1.0)
+                .build()
+                .is_err()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_params_from_json() {
+        let params: EncoderParams =
+            serde_json::from_str(r#"{"slices": 90, "crcb_mode": "Normal"}"#).unwrap();
+        assert_eq!(params.slices, Some(90));
+        assert_eq!(params.crcb_mode, CrcbMode::Normal);
+        assert_eq!(params.db_frac, EncoderParams::default().db_frac);
+
+        let json = serde_json::to_string(&params).unwrap();
+        assert_eq!(
+            serde_json::from_str::<EncoderParams>(&json).unwrap(),
+            params
+        );
+    }
+}
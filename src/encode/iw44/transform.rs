@@ -1,5 +1,6 @@
 // Removed SIMD dependencies for stable Rust compatibility
 
+#[cfg(feature = "std")]
 use crate::image::image_formats::Bitmap;
 
 /// Saturating conversion from i32 to i16 to prevent overflow
@@ -16,6 +17,7 @@ fn _sat16(x: i32) -> i16 {
 
 /// Create gray level conversion table (bconv) matching C++ IW44EncodeCodec.cpp:1656
 /// This handles different bit depths and ensures proper value clamping.
+#[cfg(feature = "std")]
 fn create_bconv_table(grays: u32) -> [i8; 256] {
     let mut bconv = [0i8; 256];
     let g = (grays - 1) as i32;
@@ -41,6 +43,7 @@ impl Encode {
     /// IMPORTANT: C++ GPixmap uses bottom-up coordinates (row 0 = bottom of image).
     /// The image crate uses top-down coordinates (row 0 = top of image).
     /// We flip vertically here to match C++ coordinate system.
+    #[cfg(feature = "std")]
     pub fn from_u8_image(img: &Bitmap, data16: &mut [i16], w: usize, h: usize) {
         // Create bconv table for gray level normalization (matches C++ line 1656)
         // For standard 8-bit images, grays=256, so bconv[i] = i - 128
@@ -75,6 +78,7 @@ impl Encode {
     ///
     /// Note: C++ fills padding area with zeros, NOT edge replication.
     /// This matches IW44EncodeCodec.cpp Encode::create() behavior.
+    #[cfg(feature = "std")]
     pub fn from_u8_image_with_stride(
         img: &Bitmap,
         data16: &mut [i16],
@@ -152,8 +156,36 @@ impl Encode {
         // This matches C++ behavior exactly
     }
 
+    /// Fill data16 from a signed i16 buffer with stride, shifting each sample
+    /// left by `shift` bits.
+    ///
+    /// Same vertical flip and zero padding as `from_i8_channel_with_stride`;
+    /// callers choose `shift` so samples end up in the i8 << IW_SHIFT range.
+    pub fn from_i16_channel_with_stride(
+        channel_buf: &[i16],
+        data16: &mut [i16],
+        w: usize,
+        h: usize,
+        stride: usize,
+        shift: u32,
+    ) {
+        data16.fill(0);
+
+        for y in 0..h {
+            let src_y = h - 1 - y; // Flip: top becomes bottom
+            for x in 0..w {
+                let val = channel_buf.get(src_y * w + x).copied().unwrap_or(0) as i32;
+                data16[y * stride + x] = _sat16(val << shift);
+            }
+        }
+    }
+
     /// Forward wavelet transform using the streaming algorithm from DjVuLibre.
     /// Now operates on i16 throughout, matching C++'s short* buffer behavior.
+    ///
+    /// Any `w` and `h` work, down to a single row or column: the filters
+    /// handle edges themselves and levels coarser than the image only touch
+    /// the sample at the origin. Decoders always undo five levels.
     pub fn forward(buf: &mut [i16], w: usize, h: usize, rowsize: usize, levels: usize) {
         let mut scale = 1;
         for _ in 0..levels {
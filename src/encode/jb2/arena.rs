@@ -0,0 +1,283 @@
+//! Pixel storage for JB2 shape bitmaps.
+//!
+//! A scanned page easily has 10 000 connected components, and giving each
+//! its own `Vec` makes the allocator a visible part of shape extraction.
+//! Two things keep that down:
+//!
+//! - Bitmaps of at most [`INLINE_WORDS`] packed words (dots, commas,
+//!   hyphens, specks) are stored inside the [`BitImage`] itself.
+//! - The bigger shapes of a page are painted into a [`ShapeArena`], one
+//!   bump-allocated buffer handed out as shared slices. The buffer is freed
+//!   when the last shape of the page is dropped.
+//!
+//! Writing to a shape that lives in an arena first copies it out, so a
+//! `BitImage` behaves the same whatever its storage.
+
+use crate::encode::jb2::bit_image::{BitImage, fill_row_run};
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::{Deref, DerefMut};
+
+/// Most packed words a bitmap keeps inline, without an allocation.
+pub const INLINE_WORDS: usize = 8;
+
+/// Packed words of a [`BitImage`].
+#[derive(Clone)]
+pub(crate) enum Words {
+    Inline {
+        len: u8,
+        buf: [u32; INLINE_WORDS],
+    },
+    Owned(Vec<u32>),
+    Shared {
+        arena: Arc<[u32]>,
+        start: usize,
+        len: usize,
+    },
+}
+
+impl Words {
+    pub(crate) fn zeroed(len: usize) -> Self {
+        if len <= INLINE_WORDS {
+            Words::Inline {
+                len: len as u8,
+                buf: [0; INLINE_WORDS],
+            }
+        } else {
+            Words::Owned(vec![0; len])
+        }
+    }
+
+    pub(crate) fn from_vec(words: Vec<u32>) -> Self {
+        if words.len() <= INLINE_WORDS {
+            Self::from_slice(&words)
+        } else {
+            Words::Owned(words)
+        }
+    }
+
+    fn from_slice(words: &[u32]) -> Self {
+        if words.len() <= INLINE_WORDS {
+            let mut buf = [0; INLINE_WORDS];
+            buf[..words.len()].copy_from_slice(words);
+            Words::Inline {
+                len: words.len() as u8,
+                buf,
+            }
+        } else {
+            Words::Owned(words.to_vec())
+        }
+    }
+
+    pub(crate) fn is_shared(&self) -> bool {
+        matches!(self, Words::Shared { .. })
+    }
+
+    /// Gives shared words storage of their own.
+    pub(crate) fn unshare(&mut self) {
+        if self.is_shared() {
+            let copy = Self::from_slice(self);
+            *self = copy;
+        }
+    }
+}
+
+impl Deref for Words {
+    type Target = [u32];
+
+    fn deref(&self) -> &[u32] {
+        match self {
+            Words::Inline { len, buf } => &buf[..*len as usize],
+            Words::Owned(words) => words,
+            Words::Shared { arena, start, len } => &arena[*start..*start + *len],
+        }
+    }
+}
+
+impl DerefMut for Words {
+    fn deref_mut(&mut self) -> &mut [u32] {
+        self.unshare();
+        match self {
+            Words::Inline { len, buf } => &mut buf[..*len as usize],
+            Words::Owned(words) => words,
+            Words::Shared { .. } => unreachable!("unshared above"),
+        }
+    }
+}
+
+impl PartialEq for Words {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Words {}
+
+impl Hash for Words {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl fmt::Debug for Words {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// One bitmap of a [`ShapeArena`], with its words inline or at `start` in
+/// the arena's buffer.
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    width: usize,
+    height: usize,
+    start: usize,
+    inline: Option<[u32; INLINE_WORDS]>,
+}
+
+impl Slot {
+    fn words_per_row(&self) -> usize {
+        self.width.div_ceil(32)
+    }
+
+    fn len(&self) -> usize {
+        self.words_per_row() * self.height
+    }
+}
+
+/// Bump allocator for the shape bitmaps of one page; see the
+/// [module documentation](self).
+///
+/// ```
+/// use djvu_encoder::encode::jb2::ShapeArena;
+///
+/// let mut arena = ShapeArena::with_capacity(ShapeArena::words_for(40, 40));
+/// let bar = arena.alloc(40, 40);
+/// arena.fill_run(bar, 0, 0, 39);
+/// let shapes = arena.into_shapes();
+/// assert_eq!(shapes[bar].count_ones(), 40);
+/// ```
+#[derive(Debug, Default)]
+pub struct ShapeArena {
+    words: Vec<u32>,
+    slots: Vec<Slot>,
+}
+
+impl ShapeArena {
+    /// An empty arena with room for `words` packed words before it grows.
+    pub fn with_capacity(words: usize) -> Self {
+        Self {
+            words: Vec::with_capacity(words),
+            slots: Vec::new(),
+        }
+    }
+
+    /// Packed words a `width` x `height` bitmap takes in the arena's
+    /// buffer: none for bitmaps small enough to be inline.
+    pub fn words_for(width: usize, height: usize) -> usize {
+        match width.div_ceil(32) * height {
+            len if len <= INLINE_WORDS => 0,
+            len => len,
+        }
+    }
+
+    /// Adds a blank `width` x `height` bitmap and returns its index.
+    pub fn alloc(&mut self, width: usize, height: usize) -> usize {
+        let start = self.words.len();
+        let len = Self::words_for(width, height);
+        self.words.resize(start + len, 0);
+        self.slots.push(Slot {
+            width,
+            height,
+            start,
+            inline: (len == 0).then_some([0; INLINE_WORDS]),
+        });
+        self.slots.len() - 1
+    }
+
+    /// Sets pixels `x1..=x2` of row `y` of bitmap `index`, clipped to it.
+    pub fn fill_run(&mut self, index: usize, y: usize, x1: usize, x2: usize) {
+        let slot = &mut self.slots[index];
+        if y >= slot.height {
+            return;
+        }
+        let wpr = slot.words_per_row();
+        let (words, start) = match &mut slot.inline {
+            Some(buf) => (&mut buf[..], 0),
+            None => (&mut self.words[..], slot.start),
+        };
+        fill_row_run(
+            &mut words[start + y * wpr..start + (y + 1) * wpr],
+            slot.width,
+            x1,
+            x2,
+        );
+    }
+
+    /// Number of bitmaps allocated.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Returns the bitmaps in the order they were allocated. Small ones are
+    /// stored inline; the others share the arena's buffer.
+    pub fn into_shapes(self) -> Vec<BitImage> {
+        let arena: Arc<[u32]> = self.words.into();
+        self.slots
+            .iter()
+            .map(|slot| {
+                let words = match slot.inline {
+                    Some(buf) => Words::Inline {
+                        len: slot.len() as u8,
+                        buf,
+                    },
+                    None => Words::Shared {
+                        arena: Arc::clone(&arena),
+                        start: slot.start,
+                        len: slot.len(),
+                    },
+                };
+                BitImage::from_words(slot.width, slot.height, words)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arena_shapes() {
+        let mut arena = ShapeArena::default();
+        let dot = arena.alloc(3, 3);
+        let bar = arena.alloc(40, 10);
+        arena.fill_run(dot, 1, 1, 1);
+        arena.fill_run(bar, 9, 2, 50);
+        arena.fill_run(bar, 10, 0, 5);
+        let mut shapes = arena.into_shapes();
+
+        let mut expected_dot = BitImage::new(3, 3).unwrap();
+        expected_dot.set_usize(1, 1, true);
+        assert_eq!(shapes[dot], expected_dot);
+        assert!(!shapes[dot].is_shared());
+
+        let mut expected_bar = BitImage::new(40, 10).unwrap();
+        expected_bar.fill_run(9, 2, 39);
+        assert_eq!(shapes[bar], expected_bar);
+        assert!(shapes[bar].is_shared());
+
+        // Writing copies the shape out of the arena.
+        let copy = shapes[bar].clone();
+        shapes[bar].set_usize(0, 0, true);
+        assert!(!shapes[bar].is_shared());
+        assert!(copy.is_shared());
+        assert!(!copy.get_pixel_unchecked(0, 0));
+        assert!(shapes[bar].get_pixel_unchecked(0, 0));
+    }
+}
@@ -0,0 +1,276 @@
+//! The bilevel bitmap the JB2 encoder codes.
+//!
+//! Builds without `std`, so that the JB2 encoder can run on `core` and
+//! `alloc` alone.
+
+use crate::encode::jb2::arena::Words;
+use alloc::{vec, vec::Vec};
+use core::fmt;
+
+/// Errors that can occur when creating or manipulating a `BitImage`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitImageError {
+    /// The specified dimensions would result in a bitmap that is too large to allocate.
+    TooLarge { width: u32, height: u32 },
+}
+
+impl fmt::Display for BitImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitImageError::TooLarge { width, height } => {
+                write!(f, "image dimensions ({}x{}) are too large", width, height)
+            }
+        }
+    }
+}
+
+impl core::error::Error for BitImageError {}
+
+/// A simple rectangle, used for bounding boxes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A bitmap image using MSB-first bit ordering for JB2 compatibility.
+///
+/// Pixels are packed one bit each into `u32` words, MSB first, with every
+/// row starting on a word boundary and the unused bits of the last word kept
+/// clear. Row-level operations work on whole words.
+///
+/// Small bitmaps keep their words inline and shapes extracted from a page
+/// may share one buffer; see [`crate::encode::jb2::arena`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BitImage {
+    pub width: usize,
+    pub height: usize,
+    words: Words,
+}
+
+impl BitImage {
+    pub fn new(width: u32, height: u32) -> Result<Self, BitImageError> {
+        let width_us = width as usize;
+        let height_us = height as usize;
+        let total_words = match width_us.div_ceil(32).checked_mul(height_us) {
+            Some(words) if words < (isize::MAX as usize) / 4 => words,
+            _ => return Err(BitImageError::TooLarge { width, height }),
+        };
+
+        Ok(Self {
+            width: width_us,
+            height: height_us,
+            words: Words::zeroed(total_words),
+        })
+    }
+
+    pub(crate) fn from_words(width: usize, height: usize, words: Words) -> Self {
+        Self {
+            width,
+            height,
+            words,
+        }
+    }
+
+    /// Builds an image from a continuous MSB-first bit stream of
+    /// `width * height` bits (rows are not padded).
+    pub fn from_bytes(width: usize, height: usize, bytes: &[u8]) -> Self {
+        let words_per_row = width.div_ceil(32);
+        let mut words = vec![0u32; words_per_row * height];
+        let total_bits = (width * height).min(bytes.len() * 8);
+        for idx in 0..total_bits {
+            if bytes[idx / 8] & (0x80 >> (idx % 8)) != 0 {
+                let (x, y) = (idx % width, idx / width);
+                words[y * words_per_row + x / 32] |= 0x8000_0000 >> (x % 32);
+            }
+        }
+        Self {
+            width,
+            height,
+            words: Words::from_vec(words),
+        }
+    }
+
+    /// Whether the pixels live in the buffer of a
+    /// [`ShapeArena`](crate::encode::jb2::ShapeArena), shared with the
+    /// other shapes of the page.
+    pub fn is_shared(&self) -> bool {
+        self.words.is_shared()
+    }
+
+    /// Copies the pixels out of a shared arena buffer, so that keeping this
+    /// bitmap does not keep the rest of its page's shapes in memory.
+    pub fn unshare(&mut self) {
+        self.words.unshare();
+    }
+
+    /// Number of `u32` words in each row.
+    #[inline(always)]
+    pub fn words_per_row(&self) -> usize {
+        self.width.div_ceil(32)
+    }
+
+    /// Gets the value of a pixel without bounds checking.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that `x` and `y` are within the bitmap's bounds,
+    /// otherwise this function will panic.
+    #[inline(always)]
+    pub fn get_pixel_unchecked(&self, x: usize, y: usize) -> bool {
+        self.words[y * self.words_per_row() + x / 32] & (0x8000_0000 >> (x % 32)) != 0
+    }
+
+    pub fn set_usize(&mut self, x: usize, y: usize, val: bool) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let idx = y * self.words_per_row() + x / 32;
+        let mask = 0x8000_0000 >> (x % 32);
+        if val {
+            self.words[idx] |= mask;
+        } else {
+            self.words[idx] &= !mask;
+        }
+    }
+
+    /// Returns the packed words of all rows, `words_per_row()` per row.
+    pub fn to_packed_words(&self) -> &[u32] {
+        &self.words
+    }
+
+    /// Returns the packed words of row `y`.
+    #[inline]
+    pub fn row_words(&self, y: usize) -> &[u32] {
+        let wpr = self.words_per_row();
+        &self.words[y * wpr..(y + 1) * wpr]
+    }
+
+    /// Sets pixels `x1..=x2` of row `y`, clipped to the image.
+    pub fn fill_run(&mut self, y: usize, x1: usize, x2: usize) {
+        if y >= self.height || x1 >= self.width || x1 > x2 {
+            return;
+        }
+        let wpr = self.words_per_row();
+        fill_row_run(&mut self.words[y * wpr..(y + 1) * wpr], self.width, x1, x2);
+    }
+
+    /// Returns the black runs of row `y` as inclusive `(x1, x2)` pairs.
+    pub fn row_runs(&self, y: usize) -> Vec<(usize, usize)> {
+        let row = self.row_words(y);
+        let mut runs = Vec::new();
+        let mut x = 0usize;
+        let find = |from: usize, black: bool| -> usize {
+            let mut i = from / 32;
+            if i >= row.len() {
+                return self.width;
+            }
+            let flip = if black { 0 } else { u32::MAX };
+            // Bits at or after `from` in the first word.
+            let mut w = (row[i] ^ flip) & (u32::MAX >> (from % 32));
+            loop {
+                if w != 0 {
+                    return (i * 32 + w.leading_zeros() as usize).min(self.width);
+                }
+                i += 1;
+                if i == row.len() {
+                    return self.width;
+                }
+                w = row[i] ^ flip;
+            }
+        };
+        while x < self.width {
+            let start = find(x, true);
+            if start >= self.width {
+                break;
+            }
+            let end = find(start, false);
+            runs.push((start, end - 1));
+            x = end;
+        }
+        runs
+    }
+
+    /// ORs `src` into this image with its top-left corner at `(x0, y0)`,
+    /// clipping anything outside.
+    pub fn blit(&mut self, src: &BitImage, x0: usize, y0: usize) {
+        if x0 >= self.width {
+            return;
+        }
+        let wpr = self.words_per_row();
+        let shift = x0 % 32;
+        let last_word = (self.width - 1) / 32;
+        let last_mask = u32::MAX << (31 - (self.width - 1) % 32);
+        for sy in 0..src.height.min(self.height.saturating_sub(y0)) {
+            let row = &mut self.words[(y0 + sy) * wpr..(y0 + sy + 1) * wpr];
+            for (i, &w) in src.row_words(sy).iter().enumerate() {
+                if w == 0 {
+                    continue;
+                }
+                let d = x0 / 32 + i;
+                let spill = if shift == 0 { 0 } else { w << (32 - shift) };
+                for (idx, bits) in [(d, w >> shift), (d + 1, spill)] {
+                    if idx > last_word || bits == 0 {
+                        continue;
+                    }
+                    row[idx] |= if idx == last_word {
+                        bits & last_mask
+                    } else {
+                        bits
+                    };
+                }
+            }
+        }
+    }
+
+    /// Returns the bounding box of the black pixels, or `None` if there are
+    /// none.
+    pub fn bounding_box(&self) -> Option<Rect> {
+        let wpr = self.words_per_row();
+        let rows = self.words.chunks_exact(wpr.max(1));
+        let nonempty = |row: &&[u32]| row.iter().any(|&w| w != 0);
+        let top = rows.clone().position(|r| nonempty(&r))?;
+        let bottom = self.height - 1 - rows.clone().rev().position(|r| nonempty(&r))?;
+
+        let (mut left, mut right) = (self.width, 0);
+        for row in self.words[top * wpr..(bottom + 1) * wpr].chunks_exact(wpr) {
+            for (i, &w) in row.iter().enumerate() {
+                if w != 0 {
+                    left = left.min(i * 32 + w.leading_zeros() as usize);
+                    right = right.max(i * 32 + 31 - w.trailing_zeros() as usize);
+                }
+            }
+        }
+        Some(Rect {
+            x: left as u32,
+            y: top as u32,
+            width: (right - left + 1) as u32,
+            height: (bottom - top + 1) as u32,
+        })
+    }
+
+    /// Number of black pixels.
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+}
+
+/// Sets pixels `x1..=x2` of a packed row `width` pixels wide, clipped to it.
+pub(crate) fn fill_row_run(row: &mut [u32], width: usize, x1: usize, x2: usize) {
+    if x1 >= width || x1 > x2 {
+        return;
+    }
+    let x2 = x2.min(width - 1);
+    let (w1, w2) = (x1 / 32, x2 / 32);
+    let head = u32::MAX >> (x1 % 32);
+    let tail = u32::MAX << (31 - x2 % 32);
+    if w1 == w2 {
+        row[w1] |= head & tail;
+    } else {
+        row[w1] |= head;
+        row[w1 + 1..w2].fill(u32::MAX);
+        row[w2] |= tail;
+    }
+}
@@ -62,7 +62,8 @@
 //! preserves the algorithmic structure but is a clean-room reimplementation
 //! of the public API and data flow described in the DjVu specification.
 
-use crate::encode::jb2::symbol_dict::BitImage;
+use crate::encode::jb2::arena::ShapeArena;
+use crate::encode::jb2::symbol_dict::{BitImage, Comparator};
 
 // ─── Run ────────────────────────────────────────────────────────────────────
 
@@ -185,20 +186,8 @@ impl CCImage {
     /// of millions of pixel tuples.
     pub fn add_bitmap_runs(&mut self, bm: &BitImage) {
         for y in 0..bm.height {
-            let mut x = 0usize;
-            while x < bm.width {
-                // Skip white pixels
-                while x < bm.width && !bm.get_pixel_unchecked(x, y) {
-                    x += 1;
-                }
-                if x < bm.width {
-                    let x1 = x;
-                    // Consume black pixels
-                    while x < bm.width && bm.get_pixel_unchecked(x, y) {
-                        x += 1;
-                    }
-                    self.add_single_run(y as i32, x1 as i32, (x - 1) as i32);
-                }
+            for (x1, x2) in bm.row_runs(y) {
+                self.add_single_run(y as i32, x1 as i32, x2 as i32);
             }
         }
     }
@@ -725,11 +714,11 @@ impl CCImage {
                 break;
             }
             let run = &self.runs[i];
-            let row = run.y - bb.ymin;
-            for x in run.x1..=run.x2 {
-                let col = x - bb.xmin;
-                bm.set_usize(col as usize, row as usize, true);
-            }
+            bm.fill_run(
+                (run.y - bb.ymin) as usize,
+                (run.x1 - bb.xmin) as usize,
+                (run.x2 - bb.xmin) as usize,
+            );
         }
 
         Some(bm)
@@ -761,17 +750,43 @@ impl CCImage {
 
     /// Convert the analyzed CCs into (bitmap, bounding_box) pairs ready
     /// for JB2 encoding, filtering out empty results.
+    ///
+    /// The bitmaps are painted into one [`ShapeArena`] sized for the page,
+    /// so extraction makes a handful of allocations however many shapes
+    /// there are.
     pub fn extract_shapes(&self) -> Vec<(BitImage, BBox)> {
-        let mut shapes = Vec::with_capacity(self.ccs.len());
-        for ccid in 0..self.ccs.len() {
-            if self.ccs[ccid].nrun <= 0 {
-                continue;
-            }
-            if let Some(bm) = self.get_bitmap_for_cc(ccid) {
-                shapes.push((bm, self.ccs[ccid].bb));
+        let ccs: Vec<&CC> = self
+            .ccs
+            .iter()
+            .filter(|cc| cc.nrun > 0 && cc.bb.width() > 0 && cc.bb.height() > 0)
+            .collect();
+        let words = ccs
+            .iter()
+            .map(|cc| ShapeArena::words_for(cc.bb.width() as usize, cc.bb.height() as usize))
+            .sum();
+        let mut arena = ShapeArena::with_capacity(words);
+        for cc in &ccs {
+            let bb = &cc.bb;
+            let shape = arena.alloc(bb.width() as usize, bb.height() as usize);
+            let frun = cc.frun as usize;
+            let runs = self
+                .runs
+                .get(frun..frun + cc.nrun as usize)
+                .unwrap_or_default();
+            for run in runs {
+                arena.fill_run(
+                    shape,
+                    (run.y - bb.ymin) as usize,
+                    (run.x1 - bb.xmin) as usize,
+                    (run.x2 - bb.xmin) as usize,
+                );
             }
         }
-        shapes
+        arena
+            .into_shapes()
+            .into_iter()
+            .zip(ccs.iter().map(|cc| cc.bb))
+            .collect()
     }
 }
 
@@ -805,27 +820,41 @@ pub fn analyze_page(image: &BitImage, dpi: i32, losslevel: i32) -> CCImage {
 /// - parents: Vec<i32> - parent indices for refinement (-1 for no parent)
 /// - blits: Vec<(i32, i32, usize)> - (left, bottom, shapeno) for each symbol instance
 ///
-/// Note: Currently returns no parents (-1 for all shapes) and one blit per shape.
-/// For production use with symbol matching and refinement, you'd need to:
-/// 1. Compare shapes to find duplicates/similar symbols
-/// 2. Build parent relationships for refinement
-/// 3. Map multiple blits to the same shape index
+/// Shapes that are exact pixel copies of an earlier one are dropped and
+/// their blits point at the earlier shape, which the encoder then codes as
+/// a cheap matched copy. Candidates are found by a hash of the packed
+/// bitmap, so this costs one pass over the shapes; on text pages most
+/// components are repeats. Lossy matching and refinement parents are left
+/// to the caller (see `symbol_dict::Comparator`), so every parent is -1.
 pub fn shapes_to_encoder_format(
     shapes: Vec<(BitImage, BBox)>,
     page_height: i32,
 ) -> (Vec<BitImage>, Vec<i32>, Vec<(i32, i32, usize)>) {
-    let mut bitmaps = Vec::with_capacity(shapes.len());
-    let mut parents = Vec::with_capacity(shapes.len());
-    let mut blits = Vec::with_capacity(shapes.len());
+    use std::collections::HashMap;
+    use std::hash::{DefaultHasher, Hash, Hasher};
 
-    for (idx, (bitmap, bbox)) in shapes.into_iter().enumerate() {
-        bitmaps.push(bitmap);
-        parents.push(-1); // No parent (no refinement)
+    let mut bitmaps: Vec<BitImage> = Vec::with_capacity(shapes.len());
+    let mut blits = Vec::with_capacity(shapes.len());
+    let mut by_hash: HashMap<u64, Vec<usize>> = HashMap::new();
+
+    for (bitmap, bbox) in shapes {
+        let mut hasher = DefaultHasher::new();
+        bitmap.hash(&mut hasher);
+        let same = by_hash.entry(hasher.finish()).or_default();
+        let idx = match same.iter().find(|&&i| bitmaps[i] == bitmap) {
+            Some(&i) => i,
+            None => {
+                same.push(bitmaps.len());
+                bitmaps.push(bitmap);
+                bitmaps.len() - 1
+            }
+        };
 
         // Convert top-down y to DjVu bottom-up y coordinate
         let bottom = page_height - bbox.ymax;
         blits.push((bbox.xmin, bottom, idx));
     }
+    let parents = vec![-1; bitmaps.len()]; // No parent (no refinement)
 
     // Sort blits by DjVu reading order: top-to-bottom (descending bottom), then left-to-right (ascending left)
     // This ensures that when we go to a new line, `left` decreases (resets to left margin),
@@ -840,6 +869,80 @@ pub fn shapes_to_encoder_format(
     (bitmaps, parents, blits)
 }
 
+/// Settings for [`drop_singletons`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SingletonParams {
+    /// Only shapes with at most this many black pixels can be dropped.
+    pub max_pixels: usize,
+    /// Two shapes count as the same symbol if they differ in at most this
+    /// fraction of the black pixels of the larger one.
+    pub max_error: f32,
+}
+
+impl Default for SingletonParams {
+    fn default() -> Self {
+        Self {
+            max_pixels: 16,
+            max_error: 0.25,
+        }
+    }
+}
+
+/// Drops small shapes that look like nothing else on the page.
+///
+/// A real glyph, even a period, nearly always recurs: the other periods,
+/// the dots of the i's. Dust and toner specks above the cleaning threshold
+/// of [`CCImage::erase_tiny_ccs`] come out as one-off shapes that each cost
+/// a new dictionary entry. This keeps a shape of at most
+/// `params.max_pixels` black pixels only if another shape of about the same
+/// size matches it within `params.max_error`. Returns how many were dropped.
+pub fn drop_singletons(shapes: &mut Vec<(BitImage, BBox)>, params: &SingletonParams) -> usize {
+    use std::collections::HashMap;
+
+    let npix: Vec<usize> = shapes.iter().map(|(bm, _)| bm.count_ones()).collect();
+    let mut by_size: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    for (i, (bm, _)) in shapes.iter().enumerate() {
+        by_size.entry((bm.width, bm.height)).or_default().push(i);
+    }
+
+    let mut comparator = Comparator::default();
+    let mut keep = vec![true; shapes.len()];
+    for (i, (bm, _)) in shapes.iter().enumerate() {
+        if npix[i] > params.max_pixels {
+            continue;
+        }
+        let recurs = (-1isize..=1).any(|dw| {
+            (-1isize..=1).any(|dh| {
+                let size = (
+                    bm.width.wrapping_add_signed(dw),
+                    bm.height.wrapping_add_signed(dh),
+                );
+                by_size.get(&size).is_some_and(|others| {
+                    others.iter().any(|&j| {
+                        if j == i {
+                            return false;
+                        }
+                        let other = &shapes[j].0;
+                        if other == bm {
+                            return true;
+                        }
+                        let max_err = (params.max_error * npix[i].max(npix[j]) as f32) as u32;
+                        comparator.distance(bm, other, max_err).is_some()
+                    })
+                })
+            })
+        });
+        keep[i] = recurs;
+    }
+
+    let before = shapes.len();
+    let mut flags = keep.into_iter();
+    shapes.retain(|_| flags.next().unwrap_or(true));
+    before - shapes.len()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -899,6 +1002,29 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_exact_duplicates_share_a_shape() {
+        let mut bm = BitImage::new(60, 20).unwrap();
+        for x0 in [2, 12, 22] {
+            for y in 2..7 {
+                bm.fill_run(y, x0, x0 + 4);
+            }
+        }
+        // Same size, different pixels.
+        for y in 2..7 {
+            bm.fill_run(y, 40 + (y & 1), 43 + (y & 1));
+        }
+        let shapes = analyze_page(&bm, 300, 0).extract_shapes();
+        assert_eq!(shapes.len(), 4);
+
+        let (bitmaps, parents, blits) = shapes_to_encoder_format(shapes, 20);
+        assert_eq!(bitmaps.len(), 2);
+        assert_eq!(parents, [-1, -1]);
+        let indices: Vec<usize> = blits.iter().map(|b| b.2).collect();
+        assert_eq!(indices, [0, 0, 0, 1]);
+        assert_eq!(blits[2], (22, 13, 0));
+    }
+
     #[test]
     fn test_tiny_cc_removal() {
         let mut bm = BitImage::new(40, 20).unwrap();
@@ -918,4 +1044,35 @@ mod tests {
         assert_eq!(shapes.len(), 1);
         assert_eq!(shapes[0].0.width, 5);
     }
+
+    #[test]
+    fn test_drop_singletons() {
+        // Three identical 3x3 dots, one near copy of them, one lone 2x5 bar
+        // and a 6x6 block too big to be a speck.
+        let mut bm = BitImage::new(60, 20).unwrap();
+        let mut fill = |x0: usize, y0: usize, w: usize, h: usize| {
+            for y in y0..y0 + h {
+                for x in x0..x0 + w {
+                    bm.set_usize(x, y, true);
+                }
+            }
+        };
+        for x0 in [2, 8, 14] {
+            fill(x0, 2, 3, 3);
+        }
+        fill(20, 2, 3, 3);
+        fill(23, 3, 1, 1);
+        fill(30, 2, 2, 5);
+        fill(40, 2, 6, 6);
+        let mut shapes = analyze_page(&bm, 300, 0).extract_shapes();
+        assert_eq!(shapes.len(), 6);
+
+        let dropped = drop_singletons(&mut shapes, &SingletonParams::default());
+        assert_eq!(dropped, 1);
+        let sizes: Vec<(usize, usize)> =
+            shapes.iter().map(|(bm, _)| (bm.width, bm.height)).collect();
+        assert!(!sizes.contains(&(2, 5)));
+        assert!(sizes.contains(&(4, 3)));
+        assert!(sizes.contains(&(6, 6)));
+    }
 }
@@ -0,0 +1,333 @@
+//! Test-only JB2 page decoder.
+//!
+//! Reads back the page streams [`JB2Encoder`](super::JB2Encoder) writes,
+//! following DjVuLibre's `JB2Image` decoder record for record, so that tests
+//! can check what a reader actually sees rather than comparing encoder
+//! outputs with each other. Dictionaries (`Djbz`) are not decoded; pages
+//! that inherit shapes are given them directly.
+
+use crate::encode::jb2::bit_image::BitImage;
+use crate::encode::jb2::error::Jb2Error;
+use crate::encode::jb2::num_coder::{BIG_NEGATIVE, BIG_POSITIVE, Jb2Context, NumCoder};
+use crate::encode::jb2::template::{CrossContext, TemplateContext};
+use crate::encode::zc::{ContextBank, ZDecoder, ZpContext};
+use alloc::{format, vec, vec::Vec};
+
+const START_OF_DATA: i32 = 0;
+const NEW_MARK: i32 = 1;
+const NEW_MARK_IMAGE_ONLY: i32 = 3;
+const MATCHED_REFINE: i32 = 4;
+const MATCHED_COPY: i32 = 7;
+const NON_MARK_DATA: i32 = 8;
+const REQUIRED_DICT_OR_RESET: i32 = 9;
+const END_OF_DATA: i32 = 11;
+
+/// Decodes a JB2 page stream into its bitmap, top row first.
+///
+/// `inherited` holds the shapes of the dictionary the page refers to.
+pub(crate) fn decode_page(data: &[u8], inherited: &[BitImage]) -> Result<BitImage, Jb2Error> {
+    PageDecoder::new(data)?.run(inherited)
+}
+
+struct PageDecoder<'a> {
+    zp: ZDecoder<'a>,
+    num_coder: NumCoder,
+    dist_record_type: Jb2Context,
+    dist_match_index: Jb2Context,
+    abs_loc_x: Jb2Context,
+    abs_loc_y: Jb2Context,
+    abs_size_x: Jb2Context,
+    abs_size_y: Jb2Context,
+    image_size_dist: Jb2Context,
+    inherited_shape_count_dist: Jb2Context,
+    rel_size_x: Jb2Context,
+    rel_size_y: Jb2Context,
+    offset_type_dist: ZpContext,
+    rel_loc_x_last: Jb2Context,
+    rel_loc_y_last: Jb2Context,
+    rel_loc_x_current: Jb2Context,
+    rel_loc_y_current: Jb2Context,
+    bitdist: ContextBank,
+    cbitdist: ContextBank,
+    dist_refinement_flag: ZpContext,
+    // DjVuLibre's location state, 1-based
+    last_left: i32,
+    last_right: i32,
+    last_bottom: i32,
+    last_row_left: i32,
+    last_row_bottom: i32,
+    short_list: [i32; 3],
+    short_list_pos: usize,
+}
+
+impl<'a> PageDecoder<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, Jb2Error> {
+        Ok(Self {
+            zp: ZDecoder::new(data)?,
+            num_coder: NumCoder::new(),
+            dist_record_type: Jb2Context::default(),
+            dist_match_index: Jb2Context::default(),
+            abs_loc_x: Jb2Context::default(),
+            abs_loc_y: Jb2Context::default(),
+            abs_size_x: Jb2Context::default(),
+            abs_size_y: Jb2Context::default(),
+            image_size_dist: Jb2Context::default(),
+            inherited_shape_count_dist: Jb2Context::default(),
+            rel_size_x: Jb2Context::default(),
+            rel_size_y: Jb2Context::default(),
+            offset_type_dist: ZpContext::new(),
+            rel_loc_x_last: Jb2Context::default(),
+            rel_loc_y_last: Jb2Context::default(),
+            rel_loc_x_current: Jb2Context::default(),
+            rel_loc_y_current: Jb2Context::default(),
+            bitdist: ContextBank::new(TemplateContext::COUNT),
+            cbitdist: ContextBank::new(CrossContext::COUNT),
+            dist_refinement_flag: ZpContext::new(),
+            last_left: 0,
+            last_right: 0,
+            last_bottom: 0,
+            last_row_left: 0,
+            last_row_bottom: 0,
+            short_list: [0; 3],
+            short_list_pos: 0,
+        })
+    }
+
+    fn num(
+        &mut self,
+        ctx: fn(&mut Self) -> &mut Jb2Context,
+        low: i32,
+        high: i32,
+    ) -> Result<i32, Jb2Error> {
+        let mut root = *ctx(self);
+        let value = self
+            .num_coder
+            .decode_num(&mut self.zp, &mut root, low, high)?;
+        *ctx(self) = root;
+        Ok(value)
+    }
+
+    fn reset_numcoder(&mut self) {
+        self.num_coder.reset();
+        for ctx in [
+            &mut self.dist_record_type,
+            &mut self.dist_match_index,
+            &mut self.abs_loc_x,
+            &mut self.abs_loc_y,
+            &mut self.abs_size_x,
+            &mut self.abs_size_y,
+            &mut self.image_size_dist,
+            &mut self.inherited_shape_count_dist,
+            &mut self.rel_size_x,
+            &mut self.rel_size_y,
+            &mut self.rel_loc_x_last,
+            &mut self.rel_loc_y_last,
+            &mut self.rel_loc_x_current,
+            &mut self.rel_loc_y_current,
+        ] {
+            *ctx = Jb2Context::default();
+        }
+    }
+
+    fn run(mut self, inherited: &[BitImage]) -> Result<BitImage, Jb2Error> {
+        let mut library: Vec<BitImage> = Vec::new();
+        let mut page: Option<BitImage> = None;
+        loop {
+            let record = self.num(|d| &mut d.dist_record_type, START_OF_DATA, END_OF_DATA)?;
+            match (record, page.as_mut()) {
+                (REQUIRED_DICT_OR_RESET, None) => {
+                    let count = self.num(|d| &mut d.inherited_shape_count_dist, 0, BIG_POSITIVE)?;
+                    let shapes = inherited.get(..count as usize).ok_or_else(|| {
+                        Jb2Error::InvalidData(format!("page inherits {count} shapes"))
+                    })?;
+                    library = shapes.to_vec();
+                }
+                (START_OF_DATA, None) => page = Some(self.start_of_image()?),
+                (REQUIRED_DICT_OR_RESET, Some(_)) => self.reset_numcoder(),
+                (NEW_MARK | NEW_MARK_IMAGE_ONLY, Some(page)) => {
+                    let shape = self.absolute_shape()?;
+                    let (left, bottom) = self.relative_location(&shape)?;
+                    blit(page, &shape, left, bottom);
+                    if record == NEW_MARK {
+                        library.push(shape);
+                    }
+                }
+                (MATCHED_REFINE, Some(page)) => {
+                    let parent = self.match_index(&library)?;
+                    let shape = self.refined_shape(&library[parent])?;
+                    let (left, bottom) = self.relative_location(&shape)?;
+                    blit(page, &shape, left, bottom);
+                    library.push(shape);
+                }
+                (MATCHED_COPY, Some(page)) => {
+                    let index = self.match_index(&library)?;
+                    let (left, bottom) = self.relative_location(&library[index])?;
+                    blit(page, &library[index], left, bottom);
+                }
+                (NON_MARK_DATA, Some(page)) => {
+                    let shape = self.absolute_shape()?;
+                    let (width, height) = (page.width as i32, page.height as i32);
+                    let left = self.num(|d| &mut d.abs_loc_x, 1, width)?;
+                    let top = self.num(|d| &mut d.abs_loc_y, 1, height)?;
+                    blit(page, &shape, left - 1, top - shape.height as i32);
+                }
+                (END_OF_DATA, Some(_)) => break,
+                _ => {
+                    return Err(Jb2Error::InvalidData(format!(
+                        "unexpected JB2 record type {record}"
+                    )));
+                }
+            }
+        }
+        page.ok_or_else(|| Jb2Error::InvalidData("no start of image".into()))
+    }
+
+    fn start_of_image(&mut self) -> Result<BitImage, Jb2Error> {
+        let width = self.num(|d| &mut d.image_size_dist, 0, BIG_POSITIVE)?;
+        let height = self.num(|d| &mut d.image_size_dist, 0, BIG_POSITIVE)?;
+        if self.zp.decode(&mut self.dist_refinement_flag)? {
+            return Err(Jb2Error::InvalidData("refinement flag set".into()));
+        }
+        self.last_left = 1 + width;
+        self.last_row_left = 0;
+        self.last_row_bottom = height;
+        self.last_right = 0;
+        self.fill_short_list(self.last_row_bottom);
+        BitImage::new(width as u32, height as u32).map_err(|_| Jb2Error::InvalidBitmap)
+    }
+
+    fn match_index(&mut self, library: &[BitImage]) -> Result<usize, Jb2Error> {
+        if library.is_empty() {
+            return Err(Jb2Error::InvalidData("match in an empty library".into()));
+        }
+        let high = library.len() as i32 - 1;
+        Ok(self.num(|d| &mut d.dist_match_index, 0, high)? as usize)
+    }
+
+    fn absolute_shape(&mut self) -> Result<BitImage, Jb2Error> {
+        let width = self.num(|d| &mut d.abs_size_x, 0, BIG_POSITIVE)?;
+        let height = self.num(|d| &mut d.abs_size_y, 0, BIG_POSITIVE)?;
+        // Rows bottom-up, as the templates read them.
+        let (dw, dh) = (width, height);
+        let mut rows = vec![0u8; (dw * dh) as usize];
+        for dy in (0..dh).rev() {
+            for dx in 0..dw {
+                let pixel = |x: i32, y: i32| {
+                    if x < 0 || y < 0 || x >= dw || y >= dh {
+                        0
+                    } else {
+                        rows[(y * dw + x) as usize]
+                    }
+                };
+                let context = TemplateContext::gather(pixel, dx, dy);
+                let bit = self.zp.decode(&mut self.bitdist[context.index()])?;
+                rows[(dy * dw + dx) as usize] = bit as u8;
+            }
+        }
+        Ok(to_bit_image(&rows, dw, dh))
+    }
+
+    fn refined_shape(&mut self, parent: &BitImage) -> Result<BitImage, Jb2Error> {
+        let (cw, ch) = (parent.width as i32, parent.height as i32);
+        let dw = cw + self.num(|d| &mut d.rel_size_x, BIG_NEGATIVE, BIG_POSITIVE)?;
+        let dh = ch + self.num(|d| &mut d.rel_size_y, BIG_NEGATIVE, BIG_POSITIVE)?;
+        if dw < 0 || dh < 0 {
+            return Err(Jb2Error::InvalidData(format!("refined shape of {dw}x{dh}")));
+        }
+        let xd2c = (dw / 2 - dw + 1) - (cw / 2 - cw + 1);
+        let yd2c = (dh / 2 - dh + 1) - (ch / 2 - ch + 1);
+        let reference = |x: i32, y: i32| {
+            let ry = y + yd2c;
+            if x < 0 || ry < 0 || x >= cw || ry >= ch {
+                0
+            } else {
+                parent.get_pixel_unchecked(x as usize, (ch - 1 - ry) as usize) as u8
+            }
+        };
+        let mut rows = vec![0u8; (dw * dh) as usize];
+        for dy in (0..dh).rev() {
+            for dx in 0..dw {
+                let current = |x: i32, y: i32| {
+                    if x < 0 || y < 0 || x >= dw || y >= dh {
+                        0
+                    } else {
+                        rows[(y * dw + x) as usize]
+                    }
+                };
+                let context = CrossContext::gather(current, reference, dx, dy, dx + xd2c);
+                let bit = self.zp.decode(&mut self.cbitdist[context.index()])?;
+                rows[(dy * dw + dx) as usize] = bit as u8;
+            }
+        }
+        Ok(to_bit_image(&rows, dw, dh))
+    }
+
+    /// DjVuLibre's `code_relative_location()`: returns the 0-based left and
+    /// bottom edges of `shape`.
+    fn relative_location(&mut self, shape: &BitImage) -> Result<(i32, i32), Jb2Error> {
+        let (rows, columns) = (shape.height as i32, shape.width as i32);
+        let (left, bottom);
+        if self.zp.decode(&mut self.offset_type_dist)? {
+            let x_diff = self.num(|d| &mut d.rel_loc_x_last, BIG_NEGATIVE, BIG_POSITIVE)?;
+            let y_diff = self.num(|d| &mut d.rel_loc_y_last, BIG_NEGATIVE, BIG_POSITIVE)?;
+            left = self.last_row_left + x_diff;
+            let top = self.last_row_bottom + y_diff;
+            bottom = top - rows + 1;
+            self.last_left = left;
+            self.last_row_left = left;
+            self.last_right = left + columns - 1;
+            self.last_bottom = bottom;
+            self.last_row_bottom = bottom;
+            self.fill_short_list(bottom);
+        } else {
+            let x_diff = self.num(|d| &mut d.rel_loc_x_current, BIG_NEGATIVE, BIG_POSITIVE)?;
+            let y_diff = self.num(|d| &mut d.rel_loc_y_current, BIG_NEGATIVE, BIG_POSITIVE)?;
+            left = self.last_right + x_diff;
+            bottom = self.last_bottom + y_diff;
+            self.last_left = left;
+            self.last_right = left + columns - 1;
+            self.last_bottom = self.update_short_list(bottom);
+        }
+        Ok((left - 1, bottom - 1))
+    }
+
+    fn fill_short_list(&mut self, v: i32) {
+        self.short_list = [v; 3];
+        self.short_list_pos = 0;
+    }
+
+    fn update_short_list(&mut self, v: i32) -> i32 {
+        self.short_list_pos = (self.short_list_pos + 1) % 3;
+        self.short_list[self.short_list_pos] = v;
+        let mut sorted = self.short_list;
+        sorted.sort_unstable();
+        sorted[1]
+    }
+}
+
+/// Turns bottom-up pixel rows into a [`BitImage`].
+fn to_bit_image(rows: &[u8], width: i32, height: i32) -> BitImage {
+    let mut image = BitImage::new(width as u32, height as u32).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            if rows[(y * width + x) as usize] != 0 {
+                image.set_usize(x as usize, (height - 1 - y) as usize, true);
+            }
+        }
+    }
+    image
+}
+
+/// ORs `shape` into `page` with its bottom-left corner at (`left`,
+/// `bottom`) in JB2's bottom-up coordinates, clipped to the page.
+fn blit(page: &mut BitImage, shape: &BitImage, left: i32, bottom: i32) {
+    let top = page.height as i32 - bottom - shape.height as i32;
+    for y in 0..shape.height {
+        for x in 0..shape.width {
+            let (px, py) = (left + x as i32, top + y as i32);
+            if px >= 0 && py >= 0 && shape.get_pixel_unchecked(x, y) {
+                page.set_usize(px as usize, py as usize, true);
+            }
+        }
+    }
+}
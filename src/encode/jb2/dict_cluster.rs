@@ -0,0 +1,370 @@
+//! Clustering the symbols of many pages into a shared dictionary.
+//!
+//! Comparing every symbol of a book with every other is quadratic, which
+//! gets slow past a few dozen pages. Instead, each symbol is reduced to a few
+//! cheap features (size, aspect ratio, ink density, centroid and the number
+//! of runs through its middle row and column), symbols are bucketed by those,
+//! and the pixel-level [`Comparator`] only runs within a bucket. Buckets are
+//! independent, so with the `rayon` feature they are clustered on the global
+//! thread pool.
+//!
+//! Symbols that match but land in different buckets (a glyph one pixel wider
+//! on the other side of a size class, say) stay apart; that costs some
+//! sharing, never correctness.
+//!
+//! Documents set in the same typeface share most of their glyphs, so the
+//! dictionary of one can seed the next: [`cluster_symbols_seeded`] keeps the
+//! seed's shapes at their indices, matches the new symbols against them, and
+//! appends the clusters that are new. [`SharedDict::save`] keeps a
+//! dictionary on disk between runs, and [`DictStats`] tells how much of a
+//! corpus the seed covered.
+
+use super::symbol_dict::{BitImage, Comparator, SharedDict};
+use std::collections::BTreeMap;
+
+/// Settings for [`cluster_symbols`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterParams {
+    /// Largest difference between two symbols of a cluster, as a fraction
+    /// of the black pixels of the cluster's first symbol.
+    pub max_error: f32,
+    /// A cluster goes into the dictionary only if its symbols come from at
+    /// least this many pages.
+    pub min_pages: usize,
+}
+
+impl Default for ClusterParams {
+    fn default() -> Self {
+        Self {
+            max_error: 0.06,
+            min_pages: 2,
+        }
+    }
+}
+
+/// The result of [`cluster_symbols`].
+#[derive(Debug, Clone)]
+pub struct SymbolClusters {
+    /// One representative shape per shared cluster.
+    pub dict: SharedDict,
+    /// For each page, the dictionary index of each of its symbols, or
+    /// `None` for symbols that stay local to the page.
+    pub assignments: Vec<Vec<Option<usize>>>,
+    /// How the symbols were shared.
+    pub stats: DictStats,
+}
+
+/// How many symbols a dictionary covered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DictStats {
+    /// Symbols looked up.
+    pub symbols: usize,
+    /// Symbols that matched a shape already in the dictionary (the seed,
+    /// or the one a page inherits).
+    pub hits: usize,
+    /// Symbols that went into new shared clusters.
+    pub shared: usize,
+    /// Shapes added to the dictionary.
+    pub new_shapes: usize,
+}
+
+impl DictStats {
+    /// Fraction of the symbols that matched the dictionary, 0 when there
+    /// were none.
+    pub fn hit_rate(&self) -> f64 {
+        if self.symbols == 0 {
+            0.0
+        } else {
+            self.hits as f64 / self.symbols as f64
+        }
+    }
+
+    /// Symbols left local to their page.
+    pub fn local(&self) -> usize {
+        self.symbols - self.hits - self.shared
+    }
+}
+
+impl std::ops::AddAssign for DictStats {
+    fn add_assign(&mut self, other: Self) {
+        self.symbols += other.symbols;
+        self.hits += other.hits;
+        self.shared += other.shared;
+        self.new_shapes += other.new_shapes;
+    }
+}
+
+/// Bucket key: symbols with different keys are never compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Features {
+    width_class: usize,
+    height_class: usize,
+    aspect: usize,
+    density: usize,
+    centroid: (usize, usize),
+    runs: (usize, usize),
+}
+
+impl Features {
+    fn of(image: &BitImage) -> Self {
+        let (w, h) = (image.width.max(1), image.height.max(1));
+        let (mut ones, mut sum_x, mut sum_y) = (0usize, 0usize, 0usize);
+        for y in 0..image.height {
+            for (start, end) in image.row_runs(y) {
+                let len = end - start + 1;
+                ones += len;
+                sum_x += (start + end) * len / 2;
+                sum_y += y * len;
+            }
+        }
+        let ones_div = ones.max(1);
+        let mid_col = (0..image.height)
+            .map(|y| image.width > 0 && image.get_pixel_unchecked(image.width / 2, y))
+            .collect::<Vec<_>>();
+        let col_runs = mid_col.windows(2).filter(|p| !p[0] && p[1]).count()
+            + usize::from(mid_col.first() == Some(&true));
+        let row_runs = if image.height > 0 {
+            image.row_runs(image.height / 2).len()
+        } else {
+            0
+        };
+        Self {
+            width_class: w / 4,
+            height_class: h / 4,
+            aspect: 8 * w / (w + h),
+            density: 8 * ones / (w * h),
+            centroid: (2 * sum_x / ones_div / w, 2 * sum_y / ones_div / h),
+            runs: (row_runs.min(3), col_runs.min(3)),
+        }
+    }
+}
+
+/// Clusters the symbols of `pages` and collects the clusters shared by
+/// enough pages into a dictionary.
+///
+/// Each cluster is represented in the dictionary by its first symbol, in
+/// page order. The result is the same with and without `rayon`.
+pub fn cluster_symbols(pages: &[Vec<BitImage>], params: &ClusterParams) -> SymbolClusters {
+    cluster_symbols_seeded(pages, &SharedDict::new(Vec::new()), params)
+}
+
+/// Like [`cluster_symbols`], starting from the shapes of `seed`.
+///
+/// The dictionary begins with the seed's shapes, at the same indices, so
+/// pages encoded against the seed still decode with the result; symbols
+/// matching a seed shape are assigned to it whatever `min_pages` says. New
+/// clusters follow.
+pub fn cluster_symbols_seeded(
+    pages: &[Vec<BitImage>],
+    seed: &SharedDict,
+    params: &ClusterParams,
+) -> SymbolClusters {
+    // Seed shapes first, with no page, so they head their clusters.
+    let seeds = seed.shape_count();
+    let symbols: Vec<(usize, usize, &BitImage)> = seed
+        .shapes()
+        .iter()
+        .enumerate()
+        .map(|(index, shape)| (usize::MAX, index, shape))
+        .chain(pages.iter().enumerate().flat_map(|(page, shapes)| {
+            shapes
+                .iter()
+                .enumerate()
+                .map(move |(index, shape)| (page, index, shape))
+        }))
+        .collect();
+
+    let mut buckets: BTreeMap<Features, Vec<usize>> = BTreeMap::new();
+    for (i, (_, _, shape)) in symbols.iter().enumerate() {
+        buckets.entry(Features::of(shape)).or_default().push(i);
+    }
+    let buckets: Vec<Vec<usize>> = buckets.into_values().collect();
+
+    let cluster_bucket = |bucket: &Vec<usize>| -> Vec<Vec<usize>> {
+        let mut comparator = Comparator::default();
+        let mut clusters: Vec<(u32, Vec<usize>)> = Vec::new();
+        for &i in bucket {
+            let shape = symbols[i].2;
+            let found = clusters.iter_mut().find(|(max_err, members)| {
+                i >= seeds
+                    && comparator
+                        .distance(symbols[members[0]].2, shape, *max_err)
+                        .is_some()
+            });
+            match found {
+                Some((_, members)) => members.push(i),
+                None => {
+                    let max_err = (shape.count_ones() as f32 * params.max_error) as u32;
+                    clusters.push((max_err, vec![i]));
+                }
+            }
+        }
+        clusters.into_iter().map(|(_, members)| members).collect()
+    };
+
+    #[cfg(feature = "rayon")]
+    let clustered: Vec<Vec<Vec<usize>>> = {
+        use rayon::prelude::*;
+        buckets.par_iter().map(cluster_bucket).collect()
+    };
+    #[cfg(not(feature = "rayon"))]
+    let clustered: Vec<Vec<Vec<usize>>> = buckets.iter().map(cluster_bucket).collect();
+
+    // Members are in symbol order, so the first one is the earliest; order
+    // the dictionary by it too. That puts the seed clusters first, in seed
+    // order.
+    let mut shared: Vec<Vec<usize>> = clustered
+        .into_iter()
+        .flatten()
+        .filter(|members| {
+            let mut pages: Vec<usize> = members.iter().map(|&i| symbols[i].0).collect();
+            pages.dedup();
+            members[0] < seeds || pages.len() >= params.min_pages
+        })
+        .collect();
+    shared.sort_by_key(|members| members[0]);
+
+    let mut assignments: Vec<Vec<Option<usize>>> = pages
+        .iter()
+        .map(|shapes| vec![None; shapes.len()])
+        .collect();
+    let mut stats = DictStats {
+        symbols: symbols.len() - seeds,
+        ..DictStats::default()
+    };
+    let mut shapes = Vec::with_capacity(shared.len());
+    for (entry, members) in shared.iter().enumerate() {
+        // The dictionary outlives the pages, so it must not hold on to
+        // their shape arenas.
+        let mut shape = symbols[members[0]].2.clone();
+        shape.unshare();
+        shapes.push(shape);
+        let seeded = members[0] < seeds;
+        if !seeded {
+            stats.new_shapes += 1;
+        }
+        for &i in members.iter().filter(|&&i| i >= seeds) {
+            let (page, index, _) = symbols[i];
+            assignments[page][index] = Some(entry);
+            if seeded {
+                stats.hits += 1;
+            } else {
+                stats.shared += 1;
+            }
+        }
+    }
+    SymbolClusters {
+        dict: SharedDict::new(shapes),
+        assignments,
+        stats,
+    }
+}
+
+/// The index of a shape of `dict` matching each of `shapes`, or `None`.
+///
+/// Shapes match as in [`cluster_symbols`]: within `max_error` of the
+/// black pixels of the dictionary shape, and only within a feature bucket.
+pub fn match_symbols(shapes: &[BitImage], dict: &SharedDict, max_error: f32) -> Vec<Option<usize>> {
+    let mut buckets: BTreeMap<Features, Vec<usize>> = BTreeMap::new();
+    for (i, shape) in dict.shapes().iter().enumerate() {
+        buckets.entry(Features::of(shape)).or_default().push(i);
+    }
+    let mut comparator = Comparator::default();
+    shapes
+        .iter()
+        .map(|shape| {
+            let candidates = buckets.get(&Features::of(shape))?;
+            candidates.iter().copied().find(|&i| {
+                let entry = &dict.shapes()[i];
+                let max_err = (entry.count_ones() as f32 * max_error) as u32;
+                comparator.distance(entry, shape, max_err).is_some()
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glyph(width: usize, height: usize, noise: usize) -> BitImage {
+        let mut image = BitImage::new(width as u32, height as u32).unwrap();
+        for y in 0..height {
+            image.fill_run(y, 1, 2);
+            if y % 3 == 0 {
+                image.fill_run(y, 1, width - 2);
+            }
+        }
+        image.set_usize(noise % width, noise % height, true);
+        image
+    }
+
+    #[test]
+    fn test_cluster_symbols_across_pages() {
+        let pages = vec![
+            vec![glyph(12, 16, 0), glyph(30, 8, 0), glyph(9, 9, 0)],
+            vec![glyph(30, 8, 5), glyph(12, 16, 7)],
+            vec![glyph(12, 16, 3)],
+        ];
+        let clusters = cluster_symbols(&pages, &ClusterParams::default());
+
+        // The 9x9 symbol appears on one page only and stays local.
+        assert_eq!(clusters.dict.shape_count(), 2);
+        assert_eq!(
+            clusters.assignments,
+            [
+                vec![Some(0), Some(1), None],
+                vec![Some(1), Some(0)],
+                vec![Some(0)],
+            ]
+        );
+        assert_eq!(clusters.dict.get_shape(0).unwrap().width, 12);
+
+        let strict = ClusterParams {
+            max_error: 0.0,
+            ..ClusterParams::default()
+        };
+        let clusters = cluster_symbols(&pages, &strict);
+        assert_eq!(clusters.dict.shape_count(), 0);
+    }
+
+    #[test]
+    fn test_seeded_clustering() {
+        let first = vec![
+            vec![glyph(12, 16, 0), glyph(30, 8, 0)],
+            vec![glyph(30, 8, 5), glyph(12, 16, 7)],
+        ];
+        let seed = cluster_symbols(&first, &ClusterParams::default()).dict;
+        assert_eq!(seed.shape_count(), 2);
+
+        // The next document reuses both glyphs and adds one of its own.
+        let second = vec![
+            vec![glyph(30, 8, 3), glyph(20, 20, 0)],
+            vec![glyph(20, 20, 4), glyph(9, 9, 0), glyph(12, 16, 1)],
+        ];
+        let clusters = cluster_symbols_seeded(&second, &seed, &ClusterParams::default());
+        assert_eq!(clusters.dict.shape_count(), 3);
+        assert_eq!(clusters.dict.shapes()[..2], seed.shapes()[..]);
+        assert_eq!(
+            clusters.assignments,
+            [vec![Some(1), Some(2)], vec![Some(2), None, Some(0)]]
+        );
+        let stats = clusters.stats;
+        assert_eq!(
+            stats,
+            DictStats {
+                symbols: 5,
+                hits: 2,
+                shared: 2,
+                new_shapes: 1
+            }
+        );
+        assert_eq!(stats.local(), 1);
+        assert!((stats.hit_rate() - 0.4).abs() < 1e-9);
+
+        assert_eq!(
+            match_symbols(&second[1], &clusters.dict, 0.06),
+            [Some(2), None, Some(0)]
+        );
+    }
+}
@@ -3,16 +3,20 @@
 //! This implements the JB2 encoding as specified in Appendix 2 of the DjVu specification,
 //! producing a single Sjbz chunk with arithmetically encoded records.
 
+use crate::encode::jb2::bit_image::BitImage;
 use crate::encode::jb2::error::Jb2Error;
-use crate::encode::jb2::num_coder::{BIG_POSITIVE, NumCoder, NumContext};
-use crate::encode::jb2::symbol_dict::BitImage;
-use crate::encode::zc::ZEncoder;
-use std::io::Write;
+use crate::encode::jb2::num_coder::{BIG_POSITIVE, Jb2Context, NumCoder};
+use crate::encode::jb2::template::{CrossContext, TemplateContext};
+use crate::encode::zc::{ByteSink, ContextBank, ZEncoder, ZpContext};
+use crate::utils::geom::Rect;
+use crate::utils::log::{debug, target};
+use alloc::{format, string::ToString, vec, vec::Vec};
 
 // Record types as per DjVu specification Table 6
 const START_OF_DATA: i32 = 0;
 const NEW_MARK: i32 = 1;
 const NEW_MARK_LIBRARY_ONLY: i32 = 2;
+const NEW_MARK_IMAGE_ONLY: i32 = 3;
 const MATCHED_REFINE: i32 = 4;
 const MATCHED_REFINE_LIBRARY_ONLY: i32 = 5;
 const MATCHED_COPY: i32 = 7;
@@ -20,8 +24,9 @@ const NON_MARK_DATA: i32 = 8;
 const REQUIRED_DICT_OR_RESET: i32 = 9;
 const END_OF_DATA: i32 = 11;
 
-// Constants from DjVuLibre
-const CELLCHUNK: usize = 20000;
+/// Most shapes a library can hold: match indexes are coded up to
+/// `BIG_POSITIVE`.
+pub const LIBRARY_LIMIT: usize = BIG_POSITIVE as usize + 1;
 
 /// Blit information for page encoding
 #[derive(Clone, Debug)]
@@ -39,29 +44,29 @@ pub struct Jb2ShapeInfo<'a> {
 }
 
 /// DjVu-compatible JB2 encoder matching DjVuLibre's exact algorithm.
-pub struct JB2Encoder<W: Write> {
+pub struct JB2Encoder<W: ByteSink> {
     _writer: W,
     image_width: u32,
     image_height: u32,
     // Number coder with tree structure
     num_coder: NumCoder,
-    // NumContext variables for different number types (matching DjVuLibre)
-    dist_record_type: NumContext,
-    dist_match_index: NumContext,
-    abs_loc_x: NumContext,
-    abs_loc_y: NumContext,
-    abs_size_x: NumContext,
-    abs_size_y: NumContext,
-    image_size_dist: NumContext,
-    inherited_shape_count_dist: NumContext,
-    rel_size_x: NumContext,
-    rel_size_y: NumContext,
+    // Tree roots for different number types (matching DjVuLibre)
+    dist_record_type: Jb2Context,
+    dist_match_index: Jb2Context,
+    abs_loc_x: Jb2Context,
+    abs_loc_y: Jb2Context,
+    abs_size_x: Jb2Context,
+    abs_size_y: Jb2Context,
+    image_size_dist: Jb2Context,
+    inherited_shape_count_dist: Jb2Context,
+    rel_size_x: Jb2Context,
+    rel_size_y: Jb2Context,
     // Relative location contexts (for NEW_MARK, MATCHED_REFINE, MATCHED_COPY)
-    offset_type_dist: u8,          // Bit context: new row vs same row
-    rel_loc_x_last: NumContext,    // X offset for new row
-    rel_loc_y_last: NumContext,    // Y offset for new row
-    rel_loc_x_current: NumContext, // X offset for same row
-    rel_loc_y_current: NumContext, // Y offset for same row
+    offset_type_dist: ZpContext,   // Bit context: new row vs same row
+    rel_loc_x_last: Jb2Context,    // X offset for new row
+    rel_loc_y_last: Jb2Context,    // Y offset for new row
+    rel_loc_x_current: Jb2Context, // X offset for same row
+    rel_loc_y_current: Jb2Context, // Y offset for same row
     // Relative location state tracking
     last_left: i32,
     last_right: i32,
@@ -72,18 +77,20 @@ pub struct JB2Encoder<W: Write> {
     short_list: [i32; 3],
     short_list_pos: usize,
     // Bit contexts for direct bitmap coding (1024 contexts)
-    bitdist: [u8; 1024],
+    bitdist: ContextBank,
     // Bit contexts for cross/refinement coding (2048 contexts)
-    cbitdist: [u8; 2048],
+    cbitdist: ContextBank,
     // Bit context for refinement flag
-    dist_refinement_flag: u8,
+    dist_refinement_flag: ZpContext,
     // State
     gotstartrecordp: bool,
-    // Track number of cells used for REQUIRED_DICT_OR_RESET
-    cur_ncell: usize,
+    // Most shapes the library may hold (lowered by tests)
+    library_limit: usize,
+    // REQUIRED_DICT_OR_RESET records emitted in the current stream
+    context_resets: usize,
 }
 
-impl<W: Write> JB2Encoder<W> {
+impl<W: ByteSink> JB2Encoder<W> {
     /// Create a new DjVu JB2 encoder
     pub fn new(writer: W) -> Self {
         Self {
@@ -91,22 +98,22 @@ impl<W: Write> JB2Encoder<W> {
             image_width: 0,
             image_height: 0,
             num_coder: NumCoder::new(),
-            dist_record_type: 0,
-            dist_match_index: 0,
-            abs_loc_x: 0,
-            abs_loc_y: 0,
-            abs_size_x: 0,
-            abs_size_y: 0,
-            image_size_dist: 0,
-            inherited_shape_count_dist: 0,
-            rel_size_x: 0,
-            rel_size_y: 0,
+            dist_record_type: Jb2Context::default(),
+            dist_match_index: Jb2Context::default(),
+            abs_loc_x: Jb2Context::default(),
+            abs_loc_y: Jb2Context::default(),
+            abs_size_x: Jb2Context::default(),
+            abs_size_y: Jb2Context::default(),
+            image_size_dist: Jb2Context::default(),
+            inherited_shape_count_dist: Jb2Context::default(),
+            rel_size_x: Jb2Context::default(),
+            rel_size_y: Jb2Context::default(),
             // Relative location contexts
-            offset_type_dist: 0,
-            rel_loc_x_last: 0,
-            rel_loc_y_last: 0,
-            rel_loc_x_current: 0,
-            rel_loc_y_current: 0,
+            offset_type_dist: ZpContext::new(),
+            rel_loc_x_last: Jb2Context::default(),
+            rel_loc_y_last: Jb2Context::default(),
+            rel_loc_x_current: Jb2Context::default(),
+            rel_loc_y_current: Jb2Context::default(),
             // Relative location state
             last_left: 0,
             last_right: 0,
@@ -116,39 +123,53 @@ impl<W: Write> JB2Encoder<W> {
             // Short list for baseline median
             short_list: [0; 3],
             short_list_pos: 0,
-            bitdist: [0; 1024],
-            cbitdist: [0; 2048],
-            dist_refinement_flag: 0,
+            bitdist: ContextBank::new(1024),
+            cbitdist: ContextBank::new(2048),
+            dist_refinement_flag: ZpContext::new(),
             gotstartrecordp: false,
-            cur_ncell: 1, // Start at 1 like DjVuLibre
+            library_limit: LIBRARY_LIMIT,
+            context_resets: 0,
         }
     }
 
     /// Reset all numerical contexts (called by REQUIRED_DICT_OR_RESET after start)
+    ///
+    /// Like DjVuLibre's `reset_numcoder()`, this frees the number trees but
+    /// keeps the bit contexts and the location state, which the decoder
+    /// keeps too.
     fn reset_numcoder(&mut self) {
-        self.dist_record_type = 0;
-        self.dist_match_index = 0;
-        self.abs_loc_x = 0;
-        self.abs_loc_y = 0;
-        self.abs_size_x = 0;
-        self.abs_size_y = 0;
-        self.image_size_dist = 0;
-        self.inherited_shape_count_dist = 0;
-        self.rel_size_x = 0;
-        self.rel_size_y = 0;
-        // Reset relative location contexts
-        self.offset_type_dist = 0;
-        self.rel_loc_x_last = 0;
-        self.rel_loc_y_last = 0;
-        self.rel_loc_x_current = 0;
-        self.rel_loc_y_current = 0;
-        // Reset relative location state
+        self.num_coder.reset();
+        self.dist_record_type = Jb2Context::default();
+        self.dist_match_index = Jb2Context::default();
+        self.abs_loc_x = Jb2Context::default();
+        self.abs_loc_y = Jb2Context::default();
+        self.abs_size_x = Jb2Context::default();
+        self.abs_size_y = Jb2Context::default();
+        self.image_size_dist = Jb2Context::default();
+        self.inherited_shape_count_dist = Jb2Context::default();
+        self.rel_size_x = Jb2Context::default();
+        self.rel_size_y = Jb2Context::default();
+        self.rel_loc_x_last = Jb2Context::default();
+        self.rel_loc_y_last = Jb2Context::default();
+        self.rel_loc_x_current = Jb2Context::default();
+        self.rel_loc_y_current = Jb2Context::default();
+    }
+
+    /// Returns every context to its initial state for a fresh ZP stream, so
+    /// one encoder can code page after page.
+    fn reset_stream(&mut self) {
+        self.reset_numcoder();
+        self.offset_type_dist = ZpContext::new();
         self.last_left = 0;
         self.last_right = 0;
         self.last_bottom = 0;
         self.last_row_left = 0;
         self.last_row_bottom = 0;
-        self.cur_ncell = 1;
+        self.context_resets = 0;
+        self.bitdist.reset();
+        self.cbitdist.reset();
+        self.dist_refinement_flag = ZpContext::new();
+        self.gotstartrecordp = false;
     }
 
     /// Fill short list with a single value (called at start of image or new row)
@@ -220,14 +241,29 @@ impl<W: Write> JB2Encoder<W> {
         } else {
             // After START_OF_DATA: reset contexts
             self.reset_numcoder();
+            self.context_resets += 1;
         }
 
         Ok(())
     }
 
     /// Check if we need to emit REQUIRED_DICT_OR_RESET for context reset
+    ///
+    /// Dense pages would otherwise grow the number trees without bound;
+    /// DjVuLibre resets them once they pass `CELLCHUNK` cells.
     fn should_reset_contexts(&self) -> bool {
-        self.cur_ncell > CELLCHUNK
+        self.num_coder.needs_reset()
+    }
+
+    /// Rejects libraries whose match indexes JB2 cannot code.
+    fn check_library_size(&self, shapes: usize, what: &str) -> Result<(), Jb2Error> {
+        if shapes > self.library_limit {
+            return Err(Jb2Error::SymbolOutOfRange(format!(
+                "{what} of {shapes} shapes exceeds the JB2 limit of {}",
+                self.library_limit
+            )));
+        }
+        Ok(())
     }
 
     /// Encode a bitmap as a single-page DjVu JB2 stream
@@ -258,6 +294,13 @@ impl<W: Write> JB2Encoder<W> {
 
     /// Encode start of image record (record type 0)
     fn encode_start_of_image(&mut self, zc: &mut ZEncoder<Vec<u8>>) -> Result<(), Jb2Error> {
+        let limit = BIG_POSITIVE as u32;
+        if self.image_width > limit || self.image_height > limit {
+            return Err(Jb2Error::SymbolOutOfRange(format!(
+                "image size {}x{} exceeds the JB2 maximum of {limit}",
+                self.image_width, self.image_height
+            )));
+        }
         // Encode record type
         self.num_coder.code_num(
             zc,
@@ -302,7 +345,8 @@ impl<W: Write> JB2Encoder<W> {
         Ok(())
     }
 
-    /// Encode non-symbol data record (record type 8)
+    /// Encode non-symbol data record (record type 8), coded directly at an
+    /// absolute location as DjVuLibre's `code_absolute_location()` reads it
     fn encode_non_symbol_data(
         &mut self,
         zc: &mut ZEncoder<Vec<u8>>,
@@ -343,15 +387,11 @@ impl<W: Write> JB2Encoder<W> {
         self.encode_bitmap_directly(zc, bitmap)?;
 
         // Encode absolute location (1-based as per DjVuLibre)
-        self.num_coder.code_num(
-            zc,
-            &mut self.abs_loc_x,
-            1,
-            self.image_width as i32,
-            abs_x + 1,
-        )?;
-        // For NON_MARK_DATA, top = bottom + rows - 1 + 1 (adjusted for 1-based)
-        let top = abs_y + bitmap.height as i32;
+        let left = checked_coord(abs_x as i64 + 1, "left edge")?;
+        self.num_coder
+            .code_num(zc, &mut self.abs_loc_x, 1, self.image_width as i32, left)?;
+        // Top = bottom + rows - 1 + 1 (adjusted for 1-based)
+        let top = checked_coord(abs_y as i64 + bitmap.height as i64, "top edge")?;
         self.num_coder
             .code_num(zc, &mut self.abs_loc_y, 1, self.image_height as i32, top)?;
 
@@ -401,18 +441,22 @@ impl<W: Write> JB2Encoder<W> {
         // Iterate from top row down (DjVuLibre order)
         for dy in (0..dh).rev() {
             // Get initial context for this row
-            let mut context = self.get_direct_context(&get_pixel, 0, dy);
+            let mut context = TemplateContext::gather(get_pixel, 0, dy);
 
             for dx in 0..dw {
                 // Get pixel value
                 let n = get_pixel(dx, dy);
 
                 // Encode the pixel
-                zc.encode(n != 0, &mut self.bitdist[context])?;
+                zc.encode(n != 0, &mut self.bitdist[context.index()])?;
 
                 // Shift context for next pixel
                 if dx + 1 < dw {
-                    context = self.shift_direct_context(context, n, &get_pixel, dx + 1, dy);
+                    context = context.shift(
+                        n != 0,
+                        get_pixel(dx + 3, dy + 1) != 0,
+                        get_pixel(dx + 2, dy + 2) != 0,
+                    );
                 }
             }
         }
@@ -420,61 +464,6 @@ impl<W: Write> JB2Encoder<W> {
         Ok(())
     }
 
-    /// Get the direct context for position (x, y).
-    /// This matches DjVuLibre's get_direct_context() exactly.
-    fn get_direct_context<F>(&self, get_pixel: &F, x: i32, y: i32) -> usize
-    where
-        F: Fn(i32, i32) -> u8,
-    {
-        // DjVuLibre uses up2, up1, up0 where up0 is current row, up1 is row above, up2 is 2 rows above
-        // Since we're scanning top-down, "up" means higher y values
-        // up2 = y + 2, up1 = y + 1, up0 = y
-        let up2_y = y + 2;
-        let up1_y = y + 1;
-        // up0_y = y (current row)
-
-        // Template positions (column offsets relative to current x):
-        // up2: [x-1, x, x+1] -> bits [9, 8, 7]
-        // up1: [x-2, x-1, x, x+1, x+2] -> bits [6, 5, 4, 3, 2]
-        // up0: [x-2, x-1] -> bits [1, 0]
-
-        ((get_pixel(x - 1, up2_y) as usize) << 9)
-            | ((get_pixel(x, up2_y) as usize) << 8)
-            | ((get_pixel(x + 1, up2_y) as usize) << 7)
-            | ((get_pixel(x - 2, up1_y) as usize) << 6)
-            | ((get_pixel(x - 1, up1_y) as usize) << 5)
-            | ((get_pixel(x, up1_y) as usize) << 4)
-            | ((get_pixel(x + 1, up1_y) as usize) << 3)
-            | ((get_pixel(x + 2, up1_y) as usize) << 2)
-            | ((get_pixel(x - 2, y) as usize) << 1)
-            | ((get_pixel(x - 1, y) as usize) << 0)
-    }
-
-    /// Shift the direct context for the next pixel.
-    /// This matches DjVuLibre's shift_direct_context() exactly.
-    fn shift_direct_context<F>(
-        &self,
-        context: usize,
-        next: u8,
-        get_pixel: &F,
-        x: i32,
-        y: i32,
-    ) -> usize
-    where
-        F: Fn(i32, i32) -> u8,
-    {
-        let up2_y = y + 2;
-        let up1_y = y + 1;
-
-        // Shift and bring in new bits
-        // ((context << 1) & 0x37a) preserves bits [9,8,6,5,4,3,1] shifted left
-        // Then we add: up1[x+2] at bit 2, up2[x+1] at bit 7, next at bit 0
-        ((context << 1) & 0x37a)
-            | ((get_pixel(x + 2, up1_y) as usize) << 2)
-            | ((get_pixel(x + 1, up2_y) as usize) << 7)
-            | (next as usize)
-    }
-
     /// Encode start of dictionary record (width=0, height=0 for dictionaries)
     fn encode_start_of_dict(&mut self, zc: &mut ZEncoder<Vec<u8>>) -> Result<(), Jb2Error> {
         // Encode record type
@@ -591,40 +580,6 @@ impl<W: Write> JB2Encoder<W> {
         Ok(())
     }
 
-    /// Get the cross-coding context for position (x, y).
-    /// This matches DjVuLibre's get_cross_context().
-    fn get_cross_context<F, G>(
-        &self,
-        get_current: &F,
-        get_ref: &G,
-        x: i32,
-        y: i32,
-        xd2c: i32, // x offset from current to reference
-    ) -> usize
-    where
-        F: Fn(i32, i32) -> u8,
-        G: Fn(i32, i32) -> u8,
-    {
-        // Current image pixels (up1 = row above, up0 = current row)
-        let up1_y = y + 1;
-        // Reference image pixels
-        let rx = x + xd2c;
-
-        // Bits 0-3: current image causal neighborhood
-        // Bits 4-10: reference image 3x3 centered neighborhood
-        ((get_current(x - 1, up1_y) as usize) << 10)
-            | ((get_current(x, up1_y) as usize) << 9)
-            | ((get_current(x + 1, up1_y) as usize) << 8)
-            | ((get_current(x - 1, y) as usize) << 7)
-            | ((get_ref(rx - 1, y + 1) as usize) << 6)
-            | ((get_ref(rx, y + 1) as usize) << 5)
-            | ((get_ref(rx + 1, y + 1) as usize) << 4)
-            | ((get_ref(rx - 1, y) as usize) << 3)
-            | ((get_ref(rx, y) as usize) << 2)
-            | ((get_ref(rx + 1, y) as usize) << 1)
-            | ((get_ref(rx, y - 1) as usize) << 0)
-    }
-
     /// Encode bitmap by cross-coding against a reference bitmap.
     /// This matches DjVuLibre's code_bitmap_by_cross_coding().
     fn encode_bitmap_by_cross_coding(
@@ -665,14 +620,14 @@ impl<W: Write> JB2Encoder<W> {
 
         // Iterate from top row down (DjVuLibre order)
         for dy in (0..dh).rev() {
-            let mut context = self.get_cross_context(&get_current, &get_ref, 0, dy, xd2c);
+            let mut context = CrossContext::gather(get_current, get_ref, 0, dy, xd2c);
 
             for dx in 0..dw {
                 let n = get_current(dx, dy);
-                zc.encode(n != 0, &mut self.cbitdist[context])?;
+                zc.encode(n != 0, &mut self.cbitdist[context.index()])?;
 
                 if dx + 1 < dw {
-                    context = self.get_cross_context(&get_current, &get_ref, dx + 1, dy, xd2c);
+                    context = CrossContext::gather(get_current, get_ref, dx + 1, dy, dx + 1 + xd2c);
                 }
             }
         }
@@ -729,10 +684,9 @@ impl<W: Write> JB2Encoder<W> {
         parents: &[i32], // parent index for each shape, -1 if no parent
         inherited_shape_count: usize,
     ) -> Result<Vec<u8>, Jb2Error> {
-        // Reset state for a fresh dictionary stream
-        self.num_coder.reset();
-        self.reset_numcoder();
-        self.gotstartrecordp = false;
+        // Every shape of a dictionary goes into the library.
+        self.check_library_size(inherited_shape_count + shapes.len(), "dictionary")?;
+        self.reset_stream();
 
         let buffer = Vec::new();
         let mut zc = ZEncoder::new(buffer, true)?;
@@ -786,24 +740,43 @@ impl<W: Write> JB2Encoder<W> {
         Ok(zc.finish()?)
     }
 
-    /// Encode relative location for a blit (record types 1, 4, 7)
+    /// Rejects a blit with no pixel on the page. One partly off the page is
+    /// kept: decoders clip it to the page, as DjVuLibre does.
+    fn check_blit(&self, blit: &Rect) -> Result<(), Jb2Error> {
+        let visible = blit.clipped_to(self.image_width, self.image_height);
+        if visible.width == 0 || visible.height == 0 {
+            return Err(Jb2Error::SymbolOutOfRange(format!(
+                "{}x{} blit at ({}, {}) is not on the {}x{} page",
+                blit.width, blit.height, blit.x, blit.y, self.image_width, self.image_height
+            )));
+        }
+        Ok(())
+    }
+
+    /// Encode relative location for a blit (record types 1, 3, 4, 7)
     /// This matches DjVuLibre's code_relative_location function.
+    ///
+    /// `blit` is in JB2's bottom-up page coordinates: `x` and `y` are the
+    /// left and bottom edges.
     fn encode_relative_location(
         &mut self,
         zc: &mut ZEncoder<Vec<u8>>,
-        left: i32,
-        bottom: i32,
-        rows: i32,
-        columns: i32,
+        blit: Rect,
     ) -> Result<(), Jb2Error> {
         // Check start record
         if !self.gotstartrecordp {
             return Err(Jb2Error::InvalidState("No start record".to_string()));
         }
+        self.check_blit(&blit)?;
+        // DjVuLibre codes 1-based edges, counting from the initial state set
+        // by the start record; 0-based ones would shift the page by a pixel.
+        let left = checked_coord(blit.x as i64 + 1, "left edge")?;
+        let bottom = checked_coord(blit.y as i64 + 1, "bottom edge")?;
+        let (rows, columns) = (blit.height, blit.width);
 
-        // Calculate top and right (DjVuLibre uses 1-based coordinates internally)
-        let top = bottom + rows - 1;
-        let right = left + columns - 1;
+        // Calculate top and right
+        let top = checked_coord(bottom as i64 + rows as i64 - 1, "top edge")?;
+        let right = checked_coord(left as i64 + columns as i64 - 1, "right edge")?;
 
         // Determine if this is a new row (left < last_left means we went to a new row)
         let new_row = left < self.last_left;
@@ -813,8 +786,8 @@ impl<W: Write> JB2Encoder<W> {
 
         if new_row {
             // New row: encode offset from last_row_left and last_row_bottom
-            let x_diff = left - self.last_row_left;
-            let y_diff = top - self.last_row_bottom;
+            let x_diff = checked_coord(left as i64 - self.last_row_left as i64, "row offset")?;
+            let y_diff = checked_coord(top as i64 - self.last_row_bottom as i64, "row offset")?;
 
             self.num_coder.code_num(
                 zc,
@@ -841,8 +814,8 @@ impl<W: Write> JB2Encoder<W> {
             self.fill_short_list(bottom);
         } else {
             // Same row: encode offset from last_right and last_bottom
-            let x_diff = left - self.last_right;
-            let y_diff = bottom - self.last_bottom;
+            let x_diff = checked_coord(left as i64 - self.last_right as i64, "symbol offset")?;
+            let y_diff = checked_coord(bottom as i64 - self.last_bottom as i64, "symbol offset")?;
 
             self.num_coder.code_num(
                 zc,
@@ -869,13 +842,52 @@ impl<W: Write> JB2Encoder<W> {
         Ok(())
     }
 
+    /// Encode NEW_MARK_IMAGE_ONLY record (type 3) - new shape blitted
+    /// without adding it to the library
+    ///
+    /// Coded like NEW_MARK, relative location included, so the location
+    /// state stays in step with the decoder's.
+    ///
+    /// `blit` is where `bitmap` lands, left and bottom edges first.
+    pub fn encode_new_mark_image_only(
+        &mut self,
+        zc: &mut ZEncoder<Vec<u8>>,
+        bitmap: &BitImage,
+        blit: Rect,
+    ) -> Result<(), Jb2Error> {
+        if !self.gotstartrecordp {
+            return Err(Jb2Error::InvalidState("No start record".to_string()));
+        }
+
+        // Encode record type
+        self.num_coder.code_num(
+            zc,
+            &mut self.dist_record_type,
+            START_OF_DATA,
+            END_OF_DATA,
+            NEW_MARK_IMAGE_ONLY,
+        )?;
+
+        // Encode absolute symbol size
+        self.encode_absolute_mark_size(zc, bitmap.width as i32, bitmap.height as i32)?;
+
+        // Encode bitmap by direct coding
+        self.encode_bitmap_directly(zc, bitmap)?;
+
+        // Encode relative location, as DjVuLibre's decoder reads it
+        self.encode_relative_location(zc, blit)?;
+
+        Ok(())
+    }
+
     /// Encode NEW_MARK record (type 1) - new shape added to library with blit
+    ///
+    /// `blit` is where `bitmap` lands, left and bottom edges first.
     pub fn encode_new_mark(
         &mut self,
         zc: &mut ZEncoder<Vec<u8>>,
         bitmap: &BitImage,
-        left: i32,
-        bottom: i32,
+        blit: Rect,
     ) -> Result<(), Jb2Error> {
         if !self.gotstartrecordp {
             return Err(Jb2Error::InvalidState("No start record".to_string()));
@@ -897,20 +909,19 @@ impl<W: Write> JB2Encoder<W> {
         self.encode_bitmap_directly(zc, bitmap)?;
 
         // Encode relative location (NEW_MARK uses relative, not absolute!)
-        self.encode_relative_location(zc, left, bottom, bitmap.height as i32, bitmap.width as i32)?;
+        self.encode_relative_location(zc, blit)?;
 
         Ok(())
     }
 
     /// Encode MATCHED_COPY record (type 7) - reference existing shape from library
+    ///
+    /// `blit` is where the shape lands, left and bottom edges first.
     pub fn encode_matched_copy(
         &mut self,
         zc: &mut ZEncoder<Vec<u8>>,
         shape_index: i32,
-        left: i32,
-        bottom: i32,
-        shape_height: i32,
-        shape_width: i32,
+        blit: Rect,
         lib_size: i32,
     ) -> Result<(), Jb2Error> {
         if !self.gotstartrecordp {
@@ -930,20 +941,21 @@ impl<W: Write> JB2Encoder<W> {
         self.encode_match_index(zc, shape_index, lib_size - 1)?;
 
         // Encode relative location (MATCHED_COPY uses relative, not absolute!)
-        self.encode_relative_location(zc, left, bottom, shape_height, shape_width)?;
+        self.encode_relative_location(zc, blit)?;
 
         Ok(())
     }
 
     /// Encode MATCHED_REFINE record (type 4) - refined shape added to library with blit
+    ///
+    /// `blit` is where `bitmap` lands, left and bottom edges first.
     pub fn encode_matched_refine(
         &mut self,
         zc: &mut ZEncoder<Vec<u8>>,
         bitmap: &BitImage,
         parent_index: i32,
         parent_bitmap: &BitImage,
-        left: i32,
-        bottom: i32,
+        blit: Rect,
         lib_size: i32,
     ) -> Result<(), Jb2Error> {
         if !self.gotstartrecordp {
@@ -975,7 +987,7 @@ impl<W: Write> JB2Encoder<W> {
         self.encode_bitmap_by_cross_coding(zc, bitmap, parent_bitmap)?;
 
         // Encode relative location (MATCHED_REFINE uses relative, not absolute!)
-        self.encode_relative_location(zc, left, bottom, bitmap.height as i32, bitmap.width as i32)?;
+        self.encode_relative_location(zc, blit)?;
 
         Ok(())
     }
@@ -984,6 +996,10 @@ impl<W: Write> JB2Encoder<W> {
     ///
     /// This produces the raw JB2 stream for a page (Sjbz chunk content).
     /// If `inherited_shape_count` > 0, the page references shapes from an external dictionary.
+    ///
+    /// Once the library holds [`LIBRARY_LIMIT`] shapes, further new shapes
+    /// are blitted without being added to it, so pages with any number of
+    /// components can be coded; only their reuse is lost.
     pub fn encode_page_with_shapes(
         &mut self,
         width: u32,
@@ -994,10 +1010,18 @@ impl<W: Write> JB2Encoder<W> {
         inherited_shape_count: usize,
         inherited_shapes: Option<&[BitImage]>, // shapes from inherited dict if available
     ) -> Result<Vec<u8>, Jb2Error> {
-        // Reset state for a fresh page stream
-        self.num_coder.reset();
-        self.reset_numcoder();
-        self.gotstartrecordp = false;
+        debug!(
+            target: target::JB2,
+            "Encoding {}x{} page: {} shapes, {} blits, {} inherited",
+            width,
+            height,
+            shapes.len(),
+            blits.len(),
+            inherited_shape_count
+        );
+
+        self.check_library_size(inherited_shape_count, "inherited dictionary")?;
+        self.reset_stream();
 
         let buffer = Vec::new();
         let mut zc = ZEncoder::new(buffer, true)?;
@@ -1013,13 +1037,16 @@ impl<W: Write> JB2Encoder<W> {
         // Emit START_OF_DATA with page dimensions
         self.encode_start_of_image(&mut zc)?;
 
-        // Track which shapes have been encoded to library
+        // Library index of each shape, once it has been coded. The library
+        // grows in blit order, which need not be shape order.
         let total_shapes = inherited_shape_count + shapes.len();
-        let mut shape_in_lib: Vec<bool> = vec![false; total_shapes];
+        let mut lib_index: Vec<Option<i32>> = vec![None; total_shapes];
+        let mut lib_size = inherited_shape_count as i32;
+        let mut image_only = 0usize;
 
         // Inherited shapes are already in library
-        for i in 0..inherited_shape_count {
-            shape_in_lib[i] = true;
+        for (i, slot) in lib_index.iter_mut().take(inherited_shape_count).enumerate() {
+            *slot = Some(i as i32);
         }
 
         // Encode each blit
@@ -1032,34 +1059,48 @@ impl<W: Write> JB2Encoder<W> {
                 )));
             }
 
-            if shape_in_lib[shapeno] {
+            if let Some(index) = lib_index[shapeno] {
                 // Shape already in library - use MATCHED_COPY
-                let (shape_height, shape_width) = if shapeno < inherited_shape_count {
+                let shape = if shapeno < inherited_shape_count {
                     inherited_shapes
                         .and_then(|s| s.get(shapeno))
-                        .map(|b| (b.height as i32, b.width as i32))
-                        .unwrap_or((10, 10)) // Default if not available
+                        .ok_or_else(|| {
+                            Jb2Error::InvalidData(format!(
+                                "Inherited shape {shapeno} not available"
+                            ))
+                        })?
                 } else {
-                    let bm = &shapes[shapeno - inherited_shape_count];
-                    (bm.height as i32, bm.width as i32)
+                    &shapes[shapeno - inherited_shape_count]
                 };
 
                 self.encode_matched_copy(
                     &mut zc,
-                    shapeno as i32,
-                    left,
-                    bottom,
-                    shape_height,
-                    shape_width,
-                    shape_in_lib.iter().filter(|&&x| x).count() as i32,
+                    index,
+                    blit_rect(left, bottom, shape)?,
+                    lib_size,
                 )?;
             } else {
                 // Shape not in library - encode it
                 let local_idx = shapeno - inherited_shape_count;
                 let bitmap = &shapes[local_idx];
                 let parent = parents.get(local_idx).copied().unwrap_or(-1);
+                let blit = blit_rect(left, bottom, bitmap)?;
+
+                if lib_size as usize >= self.library_limit {
+                    // The library is full: blit the shape alone. Later uses
+                    // cannot match it and are coded the same way.
+                    self.encode_new_mark_image_only(&mut zc, bitmap, blit)?;
+                    image_only += 1;
+                    if self.should_reset_contexts() {
+                        self.encode_required_dict_or_reset(&mut zc, None)?;
+                    }
+                    continue;
+                }
 
-                if parent >= 0 && shape_in_lib[parent as usize] {
+                let parent_index = usize::try_from(parent)
+                    .ok()
+                    .and_then(|p| lib_index.get(p).copied().flatten());
+                if let Some(parent_index) = parent_index {
                     // Use MATCHED_REFINE
                     let parent_bitmap = if (parent as usize) < inherited_shape_count {
                         inherited_shapes
@@ -1074,19 +1115,19 @@ impl<W: Write> JB2Encoder<W> {
                     self.encode_matched_refine(
                         &mut zc,
                         bitmap,
-                        parent,
+                        parent_index,
                         parent_bitmap,
-                        left,
-                        bottom,
-                        shape_in_lib.iter().filter(|&&x| x).count() as i32,
+                        blit,
+                        lib_size,
                     )?;
                 } else {
                     // Use NEW_MARK
-                    self.encode_new_mark(&mut zc, bitmap, left, bottom)?;
+                    self.encode_new_mark(&mut zc, bitmap, blit)?;
                 }
 
                 // Mark shape as in library
-                shape_in_lib[shapeno] = true;
+                lib_index[shapeno] = Some(lib_size);
+                lib_size += 1;
             }
 
             // Check if we need to reset contexts
@@ -1097,15 +1138,57 @@ impl<W: Write> JB2Encoder<W> {
 
         // Emit END_OF_DATA
         self.encode_end_of_data(&mut zc)?;
+        debug!(
+            target: target::JB2,
+            "Coded {} library shapes, {} image-only blits, {} context resets",
+            lib_size as usize - inherited_shape_count,
+            image_only,
+            self.context_resets
+        );
 
         let result = zc.finish()?;
         Ok(result)
     }
 }
 
+/// Narrows a blit coordinate or offset computed in `i64` to what JB2's
+/// number coder accepts, `[-BIG_POSITIVE, BIG_POSITIVE]`.
+/// Where `shape` lands when blitted at (`left`, `bottom`). JB2 stores blit
+/// positions unsigned, so one starting left of or below the page cannot be
+/// coded.
+fn blit_rect(left: i32, bottom: i32, shape: &BitImage) -> Result<Rect, Jb2Error> {
+    match (u32::try_from(left), u32::try_from(bottom)) {
+        (Ok(x), Ok(y)) => Ok(Rect::new(x, y, shape.width as u32, shape.height as u32)),
+        _ => Err(Jb2Error::SymbolOutOfRange(format!(
+            "Blit at ({left}, {bottom}) starts off the page"
+        ))),
+    }
+}
+
+fn checked_coord(value: i64, what: &str) -> Result<i32, Jb2Error> {
+    if value.unsigned_abs() > BIG_POSITIVE as u64 {
+        return Err(Jb2Error::SymbolOutOfRange(format!(
+            "{what} {value} exceeds the JB2 limit of ±{BIG_POSITIVE}"
+        )));
+    }
+    Ok(value as i32)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::encode::jb2::decoder::decode_page;
+
+    /// The page `blits` draw, top row first.
+    fn draw(width: u32, height: u32, shapes: &[BitImage], blits: &[(i32, i32, usize)]) -> BitImage {
+        let mut page = BitImage::new(width, height).unwrap();
+        for &(left, bottom, shapeno) in blits {
+            let shape = &shapes[shapeno];
+            let top = height as i32 - bottom - shape.height as i32;
+            page.blit(shape, left as usize, top as usize);
+        }
+        page
+    }
 
     #[test]
     fn test_simple_pattern_encoding() {
@@ -1164,4 +1247,198 @@ mod tests {
         let data = result.unwrap();
         println!("Encoded {} bytes for 16x16 checkerboard", data.len());
     }
+
+    #[test]
+    fn test_encoder_reuse_across_pages() {
+        let mut glyph = BitImage::new(6, 9).unwrap();
+        for y in 0..9 {
+            for x in 0..6 {
+                if (x * y + x) % 3 != 0 {
+                    glyph.set_usize(x, y, true);
+                }
+            }
+        }
+        let shapes = [glyph];
+        let blits = [(10, 10, 0), (20, 10, 0), (30, 12, 0)];
+
+        // The second page must not inherit the first page's learnt contexts.
+        let mut encoder = JB2Encoder::new(Vec::new());
+        let first = encoder
+            .encode_page_with_shapes(64, 32, &shapes, &[-1], &blits, 0, None)
+            .unwrap();
+        let second = encoder
+            .encode_page_with_shapes(64, 32, &shapes, &[-1], &blits, 0, None)
+            .unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_blits_on_16k_page() {
+        use crate::DjvuError;
+
+        // Marks in the far corners of a 16k x 16k mask, coded both ways.
+        let size = 16_384;
+        let mut mask = BitImage::new(size as u32, size as u32).unwrap();
+        for (x, y) in [(0, 0), (size - 3, 0), (0, size - 3), (size - 3, size - 3)] {
+            for dy in 0..3 {
+                mask.fill_run(y + dy, x, x + 2);
+            }
+        }
+        let shapes = crate::encode::jb2::analyze_page(&mask, 300, 1).extract_shapes();
+        let (dictionary, parents, blits) =
+            crate::encode::jb2::shapes_to_encoder_format(shapes, size as i32);
+        assert_eq!(blits.len(), 4);
+        let mut encoder = JB2Encoder::new(Vec::new());
+        let (width, height) = (size as u32, size as u32);
+        assert!(
+            encoder
+                .encode_page_with_shapes(width, height, &dictionary, &parents, &blits, 0, None)
+                .is_ok()
+        );
+
+        let shapes = [BitImage::from_bytes(3, 3, &[0xff, 0x80])];
+        let corners = [
+            (0, size as i32 - 3, 0),
+            (size as i32 - 3, size as i32 - 3, 0),
+            (0, 0, 0),
+            (size as i32 - 3, 0, 0),
+        ];
+        let size = size as u32;
+        assert!(
+            encoder
+                .encode_page_with_shapes(size, size, &shapes, &[-1], &corners, 0, None)
+                .is_ok()
+        );
+
+        // Coordinates JB2 cannot code are errors, not overflow panics.
+        for blit in [(i32::MAX, 0, 0), (0, i32::MIN, 0), (300_000, 0, 0)] {
+            let result =
+                encoder.encode_page_with_shapes(size, size, &shapes, &[-1], &[blit], 0, None);
+            let err = DjvuError::from(result.unwrap_err());
+            assert!(
+                matches!(err, DjvuError::SymbolOutOfRange(_)),
+                "{blit:?}: {err}"
+            );
+        }
+        let result = encoder.encode_page_with_shapes(300_000, 16, &shapes, &[-1], &[], 0, None);
+        assert!(matches!(result, Err(Jb2Error::SymbolOutOfRange(_))));
+    }
+
+    #[test]
+    fn test_dense_page_resets_contexts() {
+        // Thousands of marks scattered over a large page give every
+        // location offset its own tree path, far past CELLCHUNK cells.
+        let shapes = [BitImage::from_bytes(3, 3, &[0xe0, 0xa0, 0xe0])];
+        let mut state = 1u32;
+        let blits: Vec<_> = (0..20_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                ((state % 9_997) as i32, (state / 9_997 % 9_997) as i32, 0)
+            })
+            .collect();
+        let mut encoder = JB2Encoder::new(Vec::new());
+        let first = encoder
+            .encode_page_with_shapes(10_000, 10_000, &shapes, &[-1], &blits, 0, None)
+            .unwrap();
+        assert!(encoder.context_resets > 0);
+        // The trees are freed as they pass CELLCHUNK, never much later.
+        assert!(encoder.num_coder.cur_ncell < 21_000);
+        let second = encoder
+            .encode_page_with_shapes(10_000, 10_000, &shapes, &[-1], &blits, 0, None)
+            .unwrap();
+        assert_eq!(first, second);
+        // A decoder freeing its trees at the same records reads it back.
+        let expected = draw(10_000, 10_000, &shapes, &blits);
+        assert_eq!(decode_page(&first, &[]).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_full_library_blits_image_only() {
+        let shapes: Vec<_> = (1..=6)
+            .map(|w| BitImage::from_bytes(w, 2, &[0xff, 0xff]))
+            .collect();
+        let blits: Vec<_> = (0..12)
+            .map(|i| (i as i32 * 10, (i / 6) as i32 * 10, i % 6))
+            .collect();
+        let parents = [-1; 6];
+
+        let mut encoder = JB2Encoder::new(Vec::new());
+        let unlimited = encoder
+            .encode_page_with_shapes(128, 32, &shapes, &parents, &blits, 0, None)
+            .unwrap();
+        encoder.library_limit = 4;
+        let limited = encoder
+            .encode_page_with_shapes(128, 32, &shapes, &parents, &blits, 0, None)
+            .unwrap();
+        assert_ne!(limited, unlimited);
+
+        // Both read back as the page drawn, so the image-only blits keep the
+        // location state in step.
+        let expected = draw(128, 32, &shapes, &blits);
+        assert_eq!(decode_page(&unlimited, &[]).unwrap(), expected);
+        assert_eq!(decode_page(&limited, &[]).unwrap(), expected);
+
+        // Dictionaries hold every shape, so there the limit is an error.
+        let result = encoder.encode_dictionary(&shapes, &parents, 0);
+        assert!(matches!(result, Err(Jb2Error::SymbolOutOfRange(_))));
+        let result = encoder.encode_page_with_shapes(128, 32, &shapes, &parents, &[], 5, None);
+        assert!(matches!(result, Err(Jb2Error::SymbolOutOfRange(_))));
+    }
+
+    #[test]
+    fn test_blits_must_fit_page() {
+        let shapes = [BitImage::from_bytes(6, 9, &[0xfc; 9])];
+        let mut encoder = JB2Encoder::new(Vec::new());
+        assert!(
+            encoder
+                .encode_page_with_shapes(64, 32, &shapes, &[-1], &[(58, 23, 0)], 0, None)
+                .is_ok()
+        );
+        // Blits partly off the page are clipped by decoders.
+        for blit in [(60, 10, 0), (10, 24, 0), (63, 31, 0)] {
+            let result = encoder.encode_page_with_shapes(64, 32, &shapes, &[-1], &[blit], 0, None);
+            assert!(result.is_ok(), "{blit:?}");
+        }
+        for blit in [(64, 10, 0), (10, 32, 0), (-1, 0, 0), (0, -3, 0)] {
+            let result = encoder.encode_page_with_shapes(64, 32, &shapes, &[-1], &[blit], 0, None);
+            assert!(
+                matches!(result, Err(Jb2Error::SymbolOutOfRange(_))),
+                "{blit:?}"
+            );
+        }
+        // So are matched copies, sized as the shape they copy.
+        let blits = [(10, 10, 0), (60, 28, 0)];
+        let result = encoder.encode_page_with_shapes(64, 32, &shapes, &[-1], &blits, 0, None);
+        assert!(result.is_ok());
+
+        let on_page = |blits| {
+            crate::doc::PageComponents::new_with_dimensions(64, 32)
+                .with_jb2_manual(shapes.to_vec(), blits)
+                .problems(300)
+        };
+        assert!(on_page(vec![(60, 10, 0)]).is_empty());
+        let problems = on_page(vec![(64, 10, 0)]);
+        assert!(
+            problems.iter().any(|p| p.contains("is not on the page")),
+            "{problems:?}"
+        );
+    }
+
+    #[test]
+    fn test_inherited_shapes_must_be_given() {
+        let dict = [BitImage::from_bytes(6, 9, &[0xfc; 9])];
+        let mut encoder = JB2Encoder::new(Vec::new());
+        let blits = [(60, 28, 0)];
+        assert!(
+            encoder
+                .encode_page_with_shapes(64, 32, &[], &[], &blits, 1, Some(&dict))
+                .is_ok()
+        );
+        assert!(matches!(
+            encoder.encode_page_with_shapes(64, 32, &[], &[], &blits, 1, None),
+            Err(Jb2Error::InvalidData(_))
+        ));
+    }
 }
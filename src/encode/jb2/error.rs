@@ -1,9 +1,11 @@
 // src/jb2/error.rs
 use crate::encode::zc::ZCodecError;
+use alloc::string::String;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum Jb2Error {
+    #[cfg(feature = "std")]
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
 
@@ -36,4 +38,7 @@ pub enum Jb2Error {
 
     #[error("Invalid encoder state: {0}")]
     InvalidState(String),
+
+    #[error("Symbol out of range: {0}")]
+    SymbolOutOfRange(String),
 }
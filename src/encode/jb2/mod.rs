@@ -4,23 +4,56 @@
 //!
 //! Three-stage pipeline:
 //! 1. **Connected component analysis** (`cc_image`) - Extract shapes from page
-//! 2. **Dictionary building** (user code + `symbol_dict::Comparator`) - Match/refine shapes
+//! 2. **Dictionary building** (user code + `symbol_dict::Comparator`, or
+//!    `dict_cluster` across pages) - Match/refine shapes
 //! 3. **Encoding** (`encoder`) - Emit DjVu-compatible JB2 bitstream
 //!
 //! ## Module Map
 //!
+//! - `arena` - Inline and per-page arena storage for shape bitmaps
+//! - `bit_image` - BitImage, the packed bilevel bitmap the encoder codes
 //! - `cc_image` - cjb2-based CC analysis (run-length + union-find)
-//! - `symbol_dict` - BitImage, Comparator, SharedDict
+//! - `decoder` - Test-only page decoder, following DjVuLibre
+//! - `dict_cluster` - Feature-bucketed symbol clustering into a `SharedDict`
+//! - `symbol_dict` - BitImage, Comparator, SharedDict, run-based `find_connected_components`
 //! - `encoder` - JB2Encoder with all 12 DjVu record types
+//! - `template` - Typed direct and cross coding template contexts
 //! - `num_coder` - Tree-based integer coder (DjVuLibre-compatible)
 //! - `error` - Error types
+//!
+//! Without the `std` feature only the encoder and what it needs (`arena`,
+//! `bit_image`, `num_coder`, `template`) are built, on `core` and `alloc`.
 
+pub mod arena;
+pub mod bit_image;
+#[cfg(feature = "std")]
 pub mod cc_image;
+#[cfg(test)]
+mod decoder;
+#[cfg(feature = "std")]
+pub mod dict_cluster;
 pub mod encoder;
 pub mod error;
 pub mod num_coder;
+#[cfg(feature = "std")]
 pub mod symbol_dict;
+pub mod template;
 
-pub use cc_image::{BBox, CC, CCImage, Run, analyze_page, shapes_to_encoder_format};
+pub use arena::ShapeArena;
+pub use bit_image::BitImage;
+#[cfg(feature = "std")]
+pub use cc_image::{
+    BBox, CC, CCImage, Run, SingletonParams, analyze_page, drop_singletons,
+    shapes_to_encoder_format,
+};
+#[cfg(feature = "std")]
+pub use dict_cluster::{
+    ClusterParams, DictStats, SymbolClusters, cluster_symbols, cluster_symbols_seeded,
+    match_symbols,
+};
 pub use encoder::JB2Encoder;
-pub use symbol_dict::{BitImage, Comparator, Rect, SharedDict};
+#[cfg(feature = "std")]
+pub use symbol_dict::{
+    Comparator, ConnectedComponent, Connectivity, Rect, SharedDict, find_connected_components,
+    find_connected_components_with,
+};
@@ -1,12 +1,12 @@
-//! DjVu-compatible integer coder using tree-based NumContext.
+//! DjVu-compatible integer coder using tree-based number contexts.
 //!
 //! This implements the exact algorithm from DjVuLibre's JB2Image.cpp CodeNum function.
 //! The algorithm uses a binary tree where each node contains a bit context, and
 //! left/right child pointers to navigate based on encoding decisions.
 
 use crate::encode::jb2::error::Jb2Error;
-use crate::encode::zc::ZEncoder;
-use std::io::Write;
+use crate::encode::zc::{ByteSink, ContextBank, ZEncoder, ZpContext};
+use alloc::{format, vec, vec::Vec};
 
 /// Bounds for signed integer coding (from DjVuLibre).
 pub const BIG_POSITIVE: i32 = 262_142;
@@ -15,8 +15,22 @@ pub const BIG_NEGATIVE: i32 = -262_143;
 /// Chunk size for cell allocation (matches DjVuLibre CELLCHUNK).
 const CELLCHUNK: usize = 20000;
 
-/// A NumContext is an index into the tree structure.
-pub type NumContext = u32;
+/// The root of one number type's tree in a [`NumCoder`]: a node index,
+/// not a ZP context, so the two cannot be swapped by mistake.
+///
+/// A fresh context points nowhere; its node is allocated on first use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Jb2Context(u32);
+
+impl Jb2Context {
+    fn is_allocated(self) -> bool {
+        self.0 != 0
+    }
+
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
 
 /// Tree-based number coder matching DjVuLibre's exact algorithm.
 ///
@@ -26,13 +40,13 @@ pub type NumContext = u32;
 /// - `rightcell[ctx]` is the right child (decision = true)
 pub struct NumCoder {
     /// Bit contexts for each tree node
-    pub bitcells: Vec<u8>,
+    pub bitcells: ContextBank,
     /// Left child pointers (decision = false)
-    pub leftcell: Vec<NumContext>,
+    pub leftcell: Vec<Jb2Context>,
     /// Right child pointers (decision = true)
-    pub rightcell: Vec<NumContext>,
+    pub rightcell: Vec<Jb2Context>,
     /// Next cell to allocate
-    pub cur_ncell: NumContext,
+    pub cur_ncell: u32,
 }
 
 impl Default for NumCoder {
@@ -44,27 +58,20 @@ impl Default for NumCoder {
 impl NumCoder {
     /// Creates a new NumCoder with initial capacity.
     pub fn new() -> Self {
-        let mut coder = Self {
-            bitcells: vec![0; CELLCHUNK],
-            leftcell: vec![0; CELLCHUNK],
-            rightcell: vec![0; CELLCHUNK],
+        Self {
+            bitcells: ContextBank::new(CELLCHUNK),
+            leftcell: vec![Jb2Context::default(); CELLCHUNK],
+            rightcell: vec![Jb2Context::default(); CELLCHUNK],
             cur_ncell: 1, // Cell 0 is a dummy cell
-        };
-        // Initialize cell 0 as dummy
-        coder.bitcells[0] = 0;
-        coder.leftcell[0] = 0;
-        coder.rightcell[0] = 0;
-        coder
+        }
     }
 
     /// Resets all numerical contexts (called after REQUIRED_DICT_OR_RESET record).
     pub fn reset(&mut self) {
         // Clear all cells and reset counter
-        for i in 0..self.bitcells.len() {
-            self.bitcells[i] = 0;
-            self.leftcell[i] = 0;
-            self.rightcell[i] = 0;
-        }
+        self.bitcells.reset();
+        self.leftcell.fill(Jb2Context::default());
+        self.rightcell.fill(Jb2Context::default());
         self.cur_ncell = 1;
     }
 
@@ -82,14 +89,21 @@ impl NumCoder {
     ///
     /// The `ctx` parameter is the root context for this number type (e.g., dist_record_type).
     /// It will be updated as the tree grows.
-    pub fn code_num<W: Write>(
+    pub fn code_num<W: ByteSink>(
         &mut self,
         zc: &mut ZEncoder<W>,
-        ctx: &mut NumContext,
+        ctx: &mut Jb2Context,
         mut low: i32,
         mut high: i32,
         mut v: i32,
     ) -> Result<(), Jb2Error> {
+        // Wider ranges would overflow the cutoff doubling below.
+        if low < BIG_NEGATIVE || high > BIG_POSITIVE {
+            return Err(Jb2Error::BadNumber(format!(
+                "Range [{}, {}] exceeds [{}, {}]",
+                low, high, BIG_NEGATIVE, BIG_POSITIVE
+            )));
+        }
         if v < low || v > high {
             return Err(Jb2Error::InvalidNumber(format!(
                 "Value {} outside range [{}, {}]",
@@ -122,19 +136,19 @@ impl NumCoder {
             };
 
             // Ensure we have a valid cell, allocating if necessary
-            let current_ctx = if current_ctx == 0 {
+            let current_ctx = if !current_ctx.is_allocated() {
                 // Grow arrays if needed
                 if self.cur_ncell as usize >= self.bitcells.len() {
                     let new_size = self.bitcells.len() + CELLCHUNK;
-                    self.bitcells.resize(new_size, 0);
-                    self.leftcell.resize(new_size, 0);
-                    self.rightcell.resize(new_size, 0);
+                    self.bitcells.resize(new_size);
+                    self.leftcell.resize(new_size, Jb2Context::default());
+                    self.rightcell.resize(new_size, Jb2Context::default());
                 }
-                let new_cell = self.cur_ncell;
+                let new_cell = Jb2Context(self.cur_ncell);
                 self.cur_ncell += 1;
-                self.bitcells[new_cell as usize] = 0;
-                self.leftcell[new_cell as usize] = 0;
-                self.rightcell[new_cell as usize] = 0;
+                self.bitcells[new_cell.index()] = ZpContext::new();
+                self.leftcell[new_cell.index()] = Jb2Context::default();
+                self.rightcell[new_cell.index()] = Jb2Context::default();
 
                 // Update the pointer
                 match ctx_ref {
@@ -151,7 +165,7 @@ impl NumCoder {
             let decision = if low < cutoff && high >= cutoff {
                 // Need to encode a bit
                 let bit = v >= cutoff;
-                zc.encode(bit, &mut self.bitcells[current_ctx as usize])?;
+                zc.encode(bit, &mut self.bitcells[current_ctx.index()])?;
                 bit
             } else {
                 // No encoding needed - decision is determined by range
@@ -160,9 +174,9 @@ impl NumCoder {
 
             // Navigate to child based on decision
             ctx_ref = if decision {
-                CtxRef::Right(current_ctx as usize)
+                CtxRef::Right(current_ctx.index())
             } else {
-                CtxRef::Left(current_ctx as usize)
+                CtxRef::Left(current_ctx.index())
             };
 
             // Phase-dependent logic
@@ -215,10 +229,101 @@ impl NumCoder {
         Ok(())
     }
 
+    /// Decodes an integer coded by [`Self::code_num`] with the same range,
+    /// as DjVuLibre's `CodeNum()` does when decoding. Only tests read JB2.
+    #[cfg(test)]
+    pub(crate) fn decode_num(
+        &mut self,
+        zp: &mut crate::encode::zc::ZDecoder,
+        ctx: &mut Jb2Context,
+        mut low: i32,
+        mut high: i32,
+    ) -> Result<i32, Jb2Error> {
+        let mut cutoff: i32 = 0;
+        let mut phase = 1;
+        let mut range: u32 = 0xffffffff;
+        let mut negative = false;
+        // The child slot the next node hangs from; `None` is `ctx` itself.
+        let mut slot: Option<(usize, bool)> = None;
+
+        while range != 1 {
+            let current = match slot {
+                None => *ctx,
+                Some((idx, false)) => self.leftcell[idx],
+                Some((idx, true)) => self.rightcell[idx],
+            };
+            let current = if current.is_allocated() {
+                current
+            } else {
+                if self.cur_ncell as usize >= self.bitcells.len() {
+                    let new_size = self.bitcells.len() + CELLCHUNK;
+                    self.bitcells.resize(new_size);
+                    self.leftcell.resize(new_size, Jb2Context::default());
+                    self.rightcell.resize(new_size, Jb2Context::default());
+                }
+                let cell = Jb2Context(self.cur_ncell);
+                self.cur_ncell += 1;
+                self.bitcells[cell.index()] = ZpContext::new();
+                self.leftcell[cell.index()] = Jb2Context::default();
+                self.rightcell[cell.index()] = Jb2Context::default();
+                match slot {
+                    None => *ctx = cell,
+                    Some((idx, false)) => self.leftcell[idx] = cell,
+                    Some((idx, true)) => self.rightcell[idx] = cell,
+                }
+                cell
+            };
+
+            let decision = low >= cutoff
+                || (high >= cutoff && zp.decode(&mut self.bitcells[current.index()])?);
+            slot = Some((current.index(), decision));
+
+            match phase {
+                1 => {
+                    negative = !decision;
+                    if negative {
+                        let temp = -low - 1;
+                        low = -high - 1;
+                        high = temp;
+                    }
+                    phase = 2;
+                    cutoff = 1;
+                }
+                2 => {
+                    if !decision {
+                        phase = 3;
+                        range = ((cutoff + 1) / 2) as u32;
+                        if range == 1 {
+                            cutoff = 0;
+                        } else {
+                            cutoff -= (range / 2) as i32;
+                        }
+                    } else {
+                        cutoff = cutoff * 2 + 1;
+                    }
+                }
+                _ => {
+                    range /= 2;
+                    if range != 1 {
+                        if !decision {
+                            cutoff -= (range / 2) as i32;
+                        } else {
+                            cutoff += (range / 2) as i32;
+                        }
+                    } else if !decision {
+                        cutoff -= 1;
+                    }
+                }
+            }
+        }
+
+        Ok(if negative { -cutoff - 1 } else { cutoff })
+    }
+
     /// Helper function to allocate a new context and return its pointer.
-    /// The context starts at 0 which will be allocated on first use.
-    pub fn alloc_context(&self) -> NumContext {
-        0
+    /// The context starts unallocated; its node is allocated on first use.
+    pub fn alloc_context(&self) -> Jb2Context {
+        Jb2Context::default()
     }
 }
 
@@ -226,9 +331,9 @@ impl NumCoder {
 /// This uses a simple approach that may not match DjVuLibre exactly.
 /// For full compatibility, use NumCoder directly.
 #[deprecated(note = "Use NumCoder::code_num directly for DjVuLibre compatibility")]
-pub fn encode_integer_simple<W: Write>(
+pub fn encode_integer_simple<W: ByteSink>(
     zc: &mut ZEncoder<W>,
-    contexts: &mut [u8],
+    contexts: &mut [ZpContext],
     base_context: usize,
     value: i32,
     low: i32,
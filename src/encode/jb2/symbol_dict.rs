@@ -2,170 +2,149 @@
 //!
 //! This module provides:
 //! - `BitImage`: The canonical bilevel bitmap type used by the encoder
+//!   (defined in [`crate::encode::jb2::bit_image`])
 //! - `Rect`: Simple bounding box for regions
 //! - `Comparator`: Symbol matching with spatial search for dictionary building
 //! - Simple shared dictionary support for multi-page encoding
 
-use bitvec::order::Msb0;
-use bitvec::prelude::*;
-use std::error::Error;
-use std::fmt;
-use std::hash::{Hash, Hasher};
-use std::sync::{Arc, OnceLock};
-
-/// Errors that can occur when creating or manipulating a `BitImage`.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum BitImageError {
-    /// The specified dimensions would result in a bitmap that is too large to allocate.
-    TooLarge { width: u32, height: u32 },
-}
+use crate::DjvuError;
+use crate::iff::bs_byte_stream::{bzz_compress, bzz_decompress};
+use crate::utils::error::IoContext;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
 
-impl fmt::Display for BitImageError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            BitImageError::TooLarge { width, height } => {
-                write!(f, "image dimensions ({}x{}) are too large", width, height)
-            }
-        }
-    }
-}
+pub use crate::encode::jb2::bit_image::{BitImage, BitImageError, Rect};
 
-impl Error for BitImageError {}
+// Lutz trait implementation removed - using homegrown connected components instead
 
 // ==============================================
-// Core Data Structures (from jbig2sym.rs)
+// Connected Components (from jbig2lutz.rs)
 // ==============================================
 
-/// A simple rectangle, used for bounding boxes.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
-pub struct Rect {
-    pub x: u32,
-    pub y: u32,
-    pub width: u32,
-    pub height: u32,
-}
-
-/// A bitmap image using MSB-first bit ordering for JB2 compatibility.
-#[derive(Clone, Debug, Eq)]
-pub struct BitImage {
-    pub width: usize,
-    pub height: usize,
-    bits: BitVec<u8, Msb0>,
-    packed_cache: OnceLock<Vec<u32>>,
+/// A connected component with bounding box and pixel information
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectedComponent {
+    pub bitmap: BitImage,
+    pub bounds: Rect,
+    // The index of the symbol in the dictionary that this component was matched to.
+    pub dict_symbol_index: Option<usize>,
+    pub pixel_count: usize,
+    pub pixels: Vec<(u32, u32)>,
 }
 
-impl PartialEq for BitImage {
-    fn eq(&self, other: &Self) -> bool {
-        if self.width != other.width || self.height != other.height {
-            return false;
-        }
-        self.to_packed_words() == other.to_packed_words()
-    }
+/// Pixel adjacency used when grouping black pixels into components.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Connectivity {
+    /// Pixels touch only through an edge.
+    Four,
+    /// Pixels also touch through a corner (what `cjb2` uses).
+    #[default]
+    Eight,
 }
 
-impl Hash for BitImage {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.width.hash(state);
-        self.height.hash(state);
-        self.to_packed_words().hash(state);
-    }
+/// Finds the 8-connected components of `image` with at least `min_size`
+/// black pixels, in reading order of their first pixel.
+pub fn find_connected_components(image: &BitImage, min_size: usize) -> Vec<ConnectedComponent> {
+    find_connected_components_with(image, min_size, Connectivity::Eight)
 }
 
-impl BitImage {
-    pub fn new(width: u32, height: u32) -> Result<Self, BitImageError> {
-        let width_us = width as usize;
-        let height_us = height as usize;
-        let total_bits = match width_us.checked_mul(height_us) {
-            Some(bits) if bits < (isize::MAX as usize) => bits,
-            _ => return Err(BitImageError::TooLarge { width, height }),
-        };
-
-        let mut bits = BitVec::with_capacity(total_bits);
-        bits.resize(total_bits, false);
-        Ok(Self {
-            width: width_us,
-            height: height_us,
-            bits,
-            packed_cache: OnceLock::new(),
-        })
-    }
-
-    pub fn from_bytes(width: usize, height: usize, bytes: &[u8]) -> Self {
-        let mut bv = BitVec::from_slice(bytes);
-        bv.truncate(width * height);
-        Self {
-            width,
-            height,
-            bits: bv,
-            packed_cache: OnceLock::new(),
+/// Finds connected components with the given adjacency.
+///
+/// Works on the runs of each packed row: the first pass unions every run
+/// with the runs it touches on the row above, the second gathers the runs
+/// of each root into a component.
+pub fn find_connected_components_with(
+    image: &BitImage,
+    min_size: usize,
+    connectivity: Connectivity,
+) -> Vec<ConnectedComponent> {
+    // Inclusive runs (y, x1, x2), rows in order.
+    let mut runs: Vec<(usize, usize, usize)> = Vec::new();
+    let mut parent: Vec<usize> = Vec::new();
+    let reach = match connectivity {
+        Connectivity::Four => 0,
+        Connectivity::Eight => 1,
+    };
+
+    fn find(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
         }
+        i
     }
 
-    /// Gets the value of a pixel without bounds checking.
-    ///
-    /// # Safety
-    ///
-    /// The caller must ensure that `x` and `y` are within the bitmap's bounds,
-    /// otherwise this function will panic.
-    #[inline(always)]
-    pub fn get_pixel_unchecked(&self, x: usize, y: usize) -> bool {
-        self.bits[y * self.width + x]
+    let mut prev_row = 0..0;
+    for y in 0..image.height {
+        let row_start = runs.len();
+        let mut p = prev_row.start;
+        for (x1, x2) in image.row_runs(y) {
+            let id = runs.len();
+            runs.push((y, x1, x2));
+            parent.push(id);
+            // Skip runs above that end left of this one's reach.
+            while p < prev_row.end && runs[p].2 + reach < x1 {
+                p += 1;
+            }
+            let mut q = p;
+            while q < prev_row.end && runs[q].1 <= x2 + reach {
+                let (a, b) = (find(&mut parent, q), find(&mut parent, id));
+                if a != b {
+                    // Keep the earliest run as root, so ids follow reading order.
+                    parent[a.max(b)] = a.min(b);
+                }
+                q += 1;
+            }
+        }
+        prev_row = row_start..runs.len();
     }
 
-    pub fn set_usize(&mut self, x: usize, y: usize, val: bool) {
-        if x >= self.width || y >= self.height {
-            return;
-        }
-        let idx = y * self.width + x;
-        if idx < self.bits.len() {
-            self.bits.set(idx, val);
+    let mut slot = vec![usize::MAX; runs.len()];
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for i in 0..runs.len() {
+        let root = find(&mut parent, i);
+        if slot[root] == usize::MAX {
+            slot[root] = groups.len();
+            groups.push(Vec::new());
         }
-        self.packed_cache.take(); // Invalidate cache
+        groups[slot[root]].push(i);
     }
 
-    pub fn to_packed_words(&self) -> &[u32] {
-        self.packed_cache.get_or_init(|| {
-            let words_per_row = (self.width + 31) / 32;
-            let mut out = Vec::with_capacity(words_per_row * self.height);
-            for y in 0..self.height {
-                for word_x in 0..words_per_row {
-                    let mut w = 0u32;
-                    for bit in 0..32 {
-                        let x = word_x * 32 + bit;
-                        if x < self.width && self.get_pixel_unchecked(x, y) {
-                            w |= 1u32 << (31 - bit);
-                        }
-                    }
-                    out.push(w);
-                }
+    groups
+        .into_iter()
+        .filter_map(|members| {
+            let pixel_count: usize = members.iter().map(|&i| runs[i].2 - runs[i].1 + 1).sum();
+            if pixel_count < min_size {
+                return None;
+            }
+            let ymin = runs[*members.first()?].0;
+            let ymax = runs[*members.last()?].0;
+            let xmin = members.iter().map(|&i| runs[i].1).min()?;
+            let xmax = members.iter().map(|&i| runs[i].2).max()?;
+
+            let mut bitmap =
+                BitImage::new((xmax - xmin + 1) as u32, (ymax - ymin + 1) as u32).ok()?;
+            let mut pixels = Vec::with_capacity(pixel_count);
+            for &i in &members {
+                let (y, x1, x2) = runs[i];
+                bitmap.fill_run(y - ymin, x1 - xmin, x2 - xmin);
+                pixels.extend((x1..=x2).map(|x| (x as u32, y as u32)));
             }
-            out
+            Some(ConnectedComponent {
+                bitmap,
+                bounds: Rect {
+                    x: xmin as u32,
+                    y: ymin as u32,
+                    width: (xmax - xmin + 1) as u32,
+                    height: (ymax - ymin + 1) as u32,
+                },
+                dict_symbol_index: None,
+                pixel_count,
+                pixels,
+            })
         })
-    }
-}
-
-// Lutz trait implementation removed - using homegrown connected components instead
-
-// ==============================================
-// Connected Components (from jbig2lutz.rs)
-// ==============================================
-
-/// A connected component with bounding box and pixel information
-#[derive(Debug, Clone, PartialEq)]
-pub struct ConnectedComponent {
-    pub bitmap: BitImage,
-    pub bounds: Rect,
-    // The index of the symbol in the dictionary that this component was matched to.
-    pub dict_symbol_index: Option<usize>,
-    pub pixel_count: usize,
-    pub pixels: Vec<(u32, u32)>,
-}
-
-/// Finds connected components using homegrown algorithm
-/// Note: This is a placeholder - actual implementation uses the homegrown CC algorithm
-pub fn find_connected_components(_image: &BitImage, _min_size: usize) -> Vec<ConnectedComponent> {
-    // This function is deprecated - use the homegrown connected components instead
-    Vec::new()
+        .collect()
 }
 
 // ==============================================
@@ -321,6 +300,100 @@ impl SharedDict {
     pub fn shapes(&self) -> &[BitImage] {
         &self.shapes
     }
+
+    /// Writes the dictionary in this crate's dictionary file format, so a
+    /// later run can seed its symbol matching with it.
+    ///
+    /// The file is [`DICT_FILE_MAGIC`] and a version byte, followed by the
+    /// BZZ-compressed shapes: a big-endian `u32` count, then per shape its
+    /// width and height as `u32`s and its pixels, row by row, packed 8 to
+    /// a byte as [`BitImage::from_bytes`] reads them.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> crate::Result<()> {
+        let mut body = Vec::new();
+        body.extend((self.shapes.len() as u32).to_be_bytes());
+        for shape in self.shapes.iter() {
+            body.extend((shape.width as u32).to_be_bytes());
+            body.extend((shape.height as u32).to_be_bytes());
+            let mut packed = vec![0u8; (shape.width * shape.height).div_ceil(8)];
+            for y in 0..shape.height {
+                for x in 0..shape.width {
+                    if shape.get_pixel_unchecked(x, y) {
+                        let idx = y * shape.width + x;
+                        packed[idx / 8] |= 0x80 >> (idx % 8);
+                    }
+                }
+            }
+            body.extend(packed);
+        }
+        writer.write_all(DICT_FILE_MAGIC)?;
+        writer.write_all(&[DICT_FILE_VERSION])?;
+        writer.write_all(&bzz_compress(&body, 100)?)?;
+        Ok(())
+    }
+
+    /// Reads a dictionary written by [`Self::write_to`].
+    pub fn read_from<R: Read>(mut reader: R) -> crate::Result<Self> {
+        let invalid = |what: &str| DjvuError::ValidationError(format!("Dictionary file: {what}"));
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let Some((&version, compressed)) = data
+            .strip_prefix(DICT_FILE_MAGIC)
+            .and_then(|rest| rest.split_first())
+        else {
+            return Err(invalid("not a dictionary file"));
+        };
+        if version != DICT_FILE_VERSION {
+            return Err(invalid(&format!("unsupported version {version}")));
+        }
+        let body = bzz_decompress(compressed)?;
+        let mut rest = body.as_slice();
+        let truncated = || invalid("truncated");
+        let count = take_u32(&mut rest).ok_or_else(truncated)?;
+        let mut shapes = Vec::with_capacity(count.min(body.len() / 8));
+        for _ in 0..count {
+            let width = take_u32(&mut rest).ok_or_else(truncated)?;
+            let height = take_u32(&mut rest).ok_or_else(truncated)?;
+            let len = width
+                .checked_mul(height)
+                .ok_or_else(|| invalid("shape too large"))?
+                .div_ceil(8);
+            let packed = take(&mut rest, len).ok_or_else(truncated)?;
+            shapes.push(BitImage::from_bytes(width, height, packed));
+        }
+        Ok(Self::new(shapes))
+    }
+
+    /// Writes the dictionary to the file at `path`; see [`Self::write_to`].
+    pub fn save(&self, path: impl AsRef<Path>) -> crate::Result<()> {
+        let path = path.as_ref();
+        let mut data = Vec::new();
+        self.write_to(&mut data)?;
+        std::fs::write(path, data).context(path.display())
+    }
+
+    /// Reads a dictionary saved with [`Self::save`].
+    pub fn load(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).context(path.display())?;
+        Self::read_from(std::io::BufReader::new(file))
+    }
+}
+
+/// First bytes of a dictionary file, see [`SharedDict::write_to`].
+pub const DICT_FILE_MAGIC: &[u8; 4] = b"JB2D";
+const DICT_FILE_VERSION: u8 = 1;
+
+/// Splits the first `len` bytes off `data`.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (head, tail) = data.split_at_checked(len)?;
+    *data = tail;
+    Some(head)
+}
+
+/// Splits a big-endian `u32` off `data`.
+fn take_u32(data: &mut &[u8]) -> Option<usize> {
+    let bytes = take(data, 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
 }
 
 #[cfg(test)]
@@ -336,6 +409,75 @@ mod tests {
         assert_eq!(img.height, 10);
     }
 
+    #[test]
+    fn test_packed_row_operations() {
+        let mut img = BitImage::new(70, 4).unwrap();
+        assert_eq!(img.words_per_row(), 3);
+        img.fill_run(1, 30, 65);
+        img.set_usize(3, 2, true);
+        assert_eq!(img.row_runs(1), vec![(30, 65)]);
+        assert_eq!(img.row_runs(2), vec![(3, 3)]);
+        assert_eq!(img.count_ones(), 37);
+        assert_eq!(
+            img.bounding_box(),
+            Some(Rect {
+                x: 3,
+                y: 1,
+                width: 63,
+                height: 2
+            })
+        );
+        assert_eq!(BitImage::new(8, 8).unwrap().bounding_box(), None);
+
+        // Blitting at an unaligned offset clips at the right edge.
+        let mut page = BitImage::new(40, 4).unwrap();
+        page.blit(&img, 5, 0);
+        assert_eq!(page.row_runs(1), vec![(35, 39)]);
+        assert_eq!(page.row_runs(2), vec![(8, 8)]);
+        assert_eq!(page.to_packed_words()[3] & 0xFF_FFFF, 0);
+
+        let from_stream = BitImage::from_bytes(3, 2, &[0b1000_0100]);
+        assert!(from_stream.get_pixel_unchecked(0, 0));
+        assert!(from_stream.get_pixel_unchecked(2, 1));
+        assert_eq!(from_stream.count_ones(), 2);
+    }
+
+    #[test]
+    fn test_connected_components_connectivity() {
+        // Two diagonal pixels, a 3-pixel bar, and a lone dot.
+        let mut img = BitImage::new(10, 4).unwrap();
+        img.set_usize(0, 0, true);
+        img.set_usize(1, 1, true);
+        img.fill_run(0, 5, 7);
+        img.set_usize(9, 3, true);
+
+        let eight = find_connected_components(&img, 1);
+        assert_eq!(eight.len(), 3);
+        assert_eq!(eight[0].pixel_count, 2);
+        assert_eq!(eight[0].bounds.width, 2);
+        assert_eq!(eight[1].bounds.x, 5);
+        assert_eq!(eight[1].bitmap.count_ones(), 3);
+
+        let four = find_connected_components_with(&img, 1, Connectivity::Four);
+        assert_eq!(four.len(), 4);
+        assert_eq!(find_connected_components(&img, 2).len(), 2);
+    }
+
+    #[test]
+    fn test_connected_components_merge_u_shape() {
+        // Two arms joined only at the bottom row must end up as one component.
+        let mut img = BitImage::new(5, 3).unwrap();
+        for y in 0..3 {
+            img.set_usize(0, y, true);
+            img.set_usize(4, y, true);
+        }
+        img.fill_run(2, 0, 4);
+        let ccs = find_connected_components(&img, 1);
+        assert_eq!(ccs.len(), 1);
+        assert_eq!(ccs[0].pixel_count, 9);
+        assert_eq!(ccs[0].pixels.len(), 9);
+    }
+
     #[test]
     fn test_comparator_exact_match() {
         let mut img1 = BitImage::new(5, 5).unwrap();
@@ -366,4 +508,26 @@ mod tests {
         assert!(dict.get_shape(1).is_some());
         assert!(dict.get_shape(2).is_none());
     }
+
+    #[test]
+    fn test_dict_file_round_trip() -> crate::Result<()> {
+        let mut odd = BitImage::new(7, 3).unwrap();
+        odd.fill_run(1, 2, 6);
+        odd.set_usize(0, 2, true);
+        let dict = SharedDict::new(vec![odd, BitImage::new(1, 1).unwrap()]);
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("font.jb2d");
+        dict.save(&path)?;
+        let loaded = SharedDict::load(&path)?;
+        assert_eq!(loaded.shapes(), dict.shapes());
+
+        let mut data = Vec::new();
+        dict.write_to(&mut data)?;
+        assert!(data.starts_with(DICT_FILE_MAGIC));
+        assert!(SharedDict::read_from(&data[..data.len() - 1]).is_err());
+        data[4] = 9;
+        assert!(SharedDict::read_from(&data[..]).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,330 @@
+//! Typed JB2 template contexts.
+//!
+//! JB2 codes each pixel of a bitmap with a ZP context chosen by the pixels
+//! around it that are already coded. Direct coding looks at ten of them,
+//! cross (refinement) coding at four of the bitmap and seven of the shape it
+//! refines. The decoder rebuilds the same index, so the bit each neighbor
+//! lands on is part of the format: a swapped pair still decodes, but every
+//! pixel goes through the wrong statistics and the stream bloats without an
+//! error anywhere. [`TemplateContext`] and [`CrossContext`] name the
+//! neighbors so that the layout is written down once.
+//!
+//! Rows are counted upwards, as in DjVu's bottom-left coordinates: a `dy` of
+//! 1 is the row coded just before the current one.
+
+/// A pixel of the direct coding template, relative to the pixel coded.
+///
+/// ```text
+///            Up2Left   Up2   Up2Right
+/// Up1Left2   Up1Left   Up1   Up1Right   Up1Right2
+/// Left2      Left      [x]
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Neighbor {
+    Up2Left,
+    Up2,
+    Up2Right,
+    Up1Left2,
+    Up1Left,
+    Up1,
+    Up1Right,
+    Up1Right2,
+    Left2,
+    Left,
+}
+
+impl Neighbor {
+    /// Every neighbor, from the most significant context bit down.
+    pub const ALL: [Self; 10] = [
+        Self::Up2Left,
+        Self::Up2,
+        Self::Up2Right,
+        Self::Up1Left2,
+        Self::Up1Left,
+        Self::Up1,
+        Self::Up1Right,
+        Self::Up1Right2,
+        Self::Left2,
+        Self::Left,
+    ];
+
+    /// Column and row offset from the pixel coded; rows count upwards.
+    pub const fn offset(self) -> (i32, i32) {
+        match self {
+            Self::Up2Left => (-1, 2),
+            Self::Up2 => (0, 2),
+            Self::Up2Right => (1, 2),
+            Self::Up1Left2 => (-2, 1),
+            Self::Up1Left => (-1, 1),
+            Self::Up1 => (0, 1),
+            Self::Up1Right => (1, 1),
+            Self::Up1Right2 => (2, 1),
+            Self::Left2 => (-2, 0),
+            Self::Left => (-1, 0),
+        }
+    }
+
+    /// The bit of the context index the pixel sets.
+    pub const fn bit(self) -> u32 {
+        9 - self as u32
+    }
+}
+
+/// The direct coding context of a pixel, one of
+/// [`TemplateContext::COUNT`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TemplateContext(u16);
+
+impl TemplateContext {
+    /// Number of distinct contexts.
+    pub const COUNT: usize = 1 << Neighbor::ALL.len();
+
+    /// The context with every neighbor white.
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    /// The context with index `index`, if it is below [`Self::COUNT`].
+    pub const fn from_index(index: usize) -> Option<Self> {
+        if index < Self::COUNT {
+            Some(Self(index as u16))
+        } else {
+            None
+        }
+    }
+
+    /// Index into the table of ZP contexts.
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    /// This context with `neighbor` set to `black`.
+    pub const fn with(self, neighbor: Neighbor, black: bool) -> Self {
+        let mask = 1 << neighbor.bit();
+        Self(if black { self.0 | mask } else { self.0 & !mask })
+    }
+
+    /// Whether `neighbor` is black.
+    pub const fn get(self, neighbor: Neighbor) -> bool {
+        self.0 & (1 << neighbor.bit()) != 0
+    }
+
+    /// Reads the context of pixel (`x`, `y`) from `pixel`, which returns 1
+    /// for black and 0 for white or outside the bitmap.
+    pub fn gather(pixel: impl Fn(i32, i32) -> u8, x: i32, y: i32) -> Self {
+        Neighbor::ALL.iter().fold(Self::new(), |ctx, &n| {
+            let (dx, dy) = n.offset();
+            ctx.with(n, pixel(x + dx, y + dy) != 0)
+        })
+    }
+
+    /// The context of the next pixel to the right. `left` is the pixel
+    /// just coded; `up1_right2` and `up2_right` are the new pixels at the
+    /// right edge of the template. Everything else moves one place left.
+    pub const fn shift(self, left: bool, up1_right2: bool, up2_right: bool) -> Self {
+        // Up2 -> Up2Left, Up2Right -> Up2, Up1Left -> Up1Left2, ...,
+        // Left -> Left2; the old leftmost pixels drop out.
+        const KEPT: u16 = (1 << 9 | 1 << 8 | 1 << 6 | 1 << 5 | 1 << 4 | 1 << 3 | 1 << 1) >> 1;
+        Self((self.0 & KEPT) << 1)
+            .with(Neighbor::Left, left)
+            .with(Neighbor::Up1Right2, up1_right2)
+            .with(Neighbor::Up2Right, up2_right)
+    }
+}
+
+/// A pixel of the cross coding template. `Cur*` pixels are in the bitmap
+/// coded, relative to the pixel coded; `Ref*` pixels are in the shape it
+/// refines, relative to the aligned pixel.
+///
+/// ```text
+/// bitmap:  CurUp1Left  CurUp1  CurUp1Right     shape:  RefUpLeft  RefUp    RefUpRight
+///          CurLeft     [x]                             RefLeft    Ref      RefRight
+///                                                                 RefDown
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrossNeighbor {
+    CurUp1Left,
+    CurUp1,
+    CurUp1Right,
+    CurLeft,
+    RefUpLeft,
+    RefUp,
+    RefUpRight,
+    RefLeft,
+    Ref,
+    RefRight,
+    RefDown,
+}
+
+impl CrossNeighbor {
+    /// Every neighbor, from the most significant context bit down.
+    pub const ALL: [Self; 11] = [
+        Self::CurUp1Left,
+        Self::CurUp1,
+        Self::CurUp1Right,
+        Self::CurLeft,
+        Self::RefUpLeft,
+        Self::RefUp,
+        Self::RefUpRight,
+        Self::RefLeft,
+        Self::Ref,
+        Self::RefRight,
+        Self::RefDown,
+    ];
+
+    /// Whether the pixel is in the refined shape rather than the bitmap.
+    pub const fn in_reference(self) -> bool {
+        self as u32 >= Self::RefUpLeft as u32
+    }
+
+    /// Column and row offset; rows count upwards.
+    pub const fn offset(self) -> (i32, i32) {
+        match self {
+            Self::CurUp1Left | Self::RefUpLeft => (-1, 1),
+            Self::CurUp1 | Self::RefUp => (0, 1),
+            Self::CurUp1Right | Self::RefUpRight => (1, 1),
+            Self::CurLeft | Self::RefLeft => (-1, 0),
+            Self::Ref => (0, 0),
+            Self::RefRight => (1, 0),
+            Self::RefDown => (0, -1),
+        }
+    }
+
+    /// The bit of the context index the pixel sets.
+    pub const fn bit(self) -> u32 {
+        10 - self as u32
+    }
+}
+
+/// The cross coding context of a pixel, one of [`CrossContext::COUNT`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CrossContext(u16);
+
+impl CrossContext {
+    /// Number of distinct contexts.
+    pub const COUNT: usize = 1 << CrossNeighbor::ALL.len();
+
+    /// Index into the table of ZP contexts.
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    /// This context with `neighbor` set to `black`.
+    pub const fn with(self, neighbor: CrossNeighbor, black: bool) -> Self {
+        let mask = 1 << neighbor.bit();
+        Self(if black { self.0 | mask } else { self.0 & !mask })
+    }
+
+    /// Whether `neighbor` is black.
+    pub const fn get(self, neighbor: CrossNeighbor) -> bool {
+        self.0 & (1 << neighbor.bit()) != 0
+    }
+
+    /// Reads the context of pixel (`x`, `y`) of the bitmap from `current`,
+    /// and of the aligned pixel (`rx`, `y`) of the shape from `reference`.
+    pub fn gather(
+        current: impl Fn(i32, i32) -> u8,
+        reference: impl Fn(i32, i32) -> u8,
+        x: i32,
+        y: i32,
+        rx: i32,
+    ) -> Self {
+        CrossNeighbor::ALL.iter().fold(Self::default(), |ctx, &n| {
+            let (dx, dy) = n.offset();
+            let black = if n.in_reference() {
+                reference(rx + dx, y + dy)
+            } else {
+                current(x + dx, y + dy)
+            };
+            ctx.with(n, black != 0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The template as DjVuLibre's `get_direct_context` spells it out.
+    fn djvulibre_direct(pixel: &impl Fn(i32, i32) -> u8, x: i32, y: i32) -> usize {
+        let p = |x, y| pixel(x, y) as usize;
+        (p(x - 1, y + 2) << 9)
+            | (p(x, y + 2) << 8)
+            | (p(x + 1, y + 2) << 7)
+            | (p(x - 2, y + 1) << 6)
+            | (p(x - 1, y + 1) << 5)
+            | (p(x, y + 1) << 4)
+            | (p(x + 1, y + 1) << 3)
+            | (p(x + 2, y + 1) << 2)
+            | (p(x - 2, y) << 1)
+            | p(x - 1, y)
+    }
+
+    /// A 6x3 patch whose pixels are the bits of `bits`, row-major from the
+    /// top left; the pixel coded is at (2, 0) and the next one at (3, 0).
+    fn patch(bits: u32) -> impl Fn(i32, i32) -> u8 {
+        move |x, y| {
+            if !(0..6).contains(&x) || !(0..3).contains(&y) {
+                return 0;
+            }
+            ((bits >> ((2 - y) * 6 + x)) & 1) as u8
+        }
+    }
+
+    #[test]
+    fn test_all_direct_contexts() {
+        let mut seen = [false; TemplateContext::COUNT];
+        for index in 0..TemplateContext::COUNT {
+            let ctx = TemplateContext::from_index(index).unwrap();
+            // Paint the neighbors the index names, then read them back.
+            let mut bits = 0u32;
+            for n in Neighbor::ALL {
+                let (dx, dy) = n.offset();
+                if ctx.get(n) {
+                    bits |= 1 << ((2 - dy) * 6 + 2 + dx);
+                }
+            }
+            let pixel = patch(bits);
+            let gathered = TemplateContext::gather(&pixel, 2, 0);
+            assert_eq!(gathered, ctx);
+            assert_eq!(gathered.index(), djvulibre_direct(&pixel, 2, 0));
+            seen[gathered.index()] = true;
+
+            // Shifting matches gathering at the next pixel, whatever comes in.
+            for incoming in 0..8u32 {
+                let bits = bits | (incoming & 1) << 14 | (incoming >> 1 & 1) << 11;
+                let bits = bits | (incoming >> 2 & 1) << 4;
+                let pixel = patch(bits);
+                let left = pixel(2, 0) != 0;
+                let shifted = TemplateContext::gather(&pixel, 2, 0).shift(
+                    left,
+                    pixel(5, 1) != 0,
+                    pixel(4, 2) != 0,
+                );
+                assert_eq!(shifted.index(), djvulibre_direct(&pixel, 3, 0));
+            }
+        }
+        assert!(seen.iter().all(|&s| s));
+        assert_eq!(TemplateContext::from_index(TemplateContext::COUNT), None);
+    }
+
+    #[test]
+    fn test_cross_context_layout() {
+        let bits: Vec<u32> = CrossNeighbor::ALL.iter().map(|n| n.bit()).collect();
+        assert_eq!(bits, (0..11).rev().collect::<Vec<_>>());
+        for n in CrossNeighbor::ALL {
+            // A single black pixel at the neighbor's place sets its bit only.
+            let (dx, dy) = n.offset();
+            let at = move |x: i32, y: i32| u8::from((x, y) == (5 + dx, 5 + dy));
+            let blank = |_, _| 0;
+            let ctx = if n.in_reference() {
+                CrossContext::gather(blank, at, 0, 5, 5)
+            } else {
+                CrossContext::gather(at, blank, 5, 5, 0)
+            };
+            assert_eq!(ctx.index(), 1 << n.bit(), "{n:?}");
+            assert!(ctx.get(n));
+        }
+        assert_eq!(CrossContext::COUNT, 2048);
+    }
+}
@@ -1,11 +1,15 @@
-pub mod iw44;
-// pub mod iw44_ffi;  // FFI-based IW44 encoder - disabled for now
-pub mod jb2;
-pub mod zc;
-
-// Re-export commonly used encoding functionality
-pub use jb2::*;
-pub use zc::*;
-
-// Re-export error types for convenience
-pub use crate::utils::error::{DjvuError, Result};
+// Codec paths report malformed input as errors; they must not panic.
+#![cfg_attr(not(test), deny(clippy::unwrap_used))]
+
+pub mod iw44;
+// pub mod iw44_ffi;  // FFI-based IW44 encoder - disabled for now
+pub mod jb2;
+pub mod zc;
+
+// Re-export commonly used encoding functionality
+pub use jb2::{BitImage, JB2Encoder};
+pub use zc::{ZCodecError, ZEncoder, ZpContext};
+
+// Re-export error types for convenience
+#[cfg(feature = "std")]
+pub use crate::utils::error::{DjvuError, Result};
@@ -1,6 +1,8 @@
 use super::ZpEncoderCursor;
+use super::context::ZpContext;
 use super::table::DEFAULT_ZP_TABLE;
-use super::zcodec::{BitContext, ZCodecError};
+use super::zcodec::ZCodecError;
+use crate::utils::log::{target, trace};
 use std::ffi::c_void;
 use std::io::{Cursor, Write};
 use std::marker::PhantomData;
@@ -47,8 +49,9 @@ pub struct ZpAsmState {
 pub extern "C" fn zp_debug_hook(event: i32, a: u32, subend: u32, buffer: u32, nrun: u32, bit: i32) {
     unsafe {
         if DBG_COUNT < DBG_MAX {
-            eprintln!(
-                "[ZPDBG] ev={} a={:04x} sub={:04x} buf={:06x} nrun={} bit={}",
+            trace!(
+                target: target::ZP,
+                "asm outbit ev={} a={:04x} sub={:04x} buf={:06x} nrun={} bit={}",
                 event,
                 a & 0xffff,
                 subend & 0xffff,
@@ -58,7 +61,7 @@ pub extern "C" fn zp_debug_hook(event: i32, a: u32, subend: u32, buffer: u32, nr
             );
             DBG_COUNT += 1;
             if DBG_COUNT == DBG_MAX {
-                eprintln!("[ZPDBG] ... (truncated) ...");
+                trace!(target: target::ZP, "asm outbit trace truncated");
             }
         }
     }
@@ -109,6 +112,21 @@ pub extern "C" fn bytestream_write(bs: *mut c_void, data: *const c_void, len: us
     }
 }
 
+/// Fills the adaptation table from DEFAULT_ZP_TABLE.
+fn load_table(state: &mut ZpAsmState) {
+    for i in 0..256 {
+        let e = if i < DEFAULT_ZP_TABLE.len() {
+            DEFAULT_ZP_TABLE[i]
+        } else {
+            DEFAULT_ZP_TABLE[DEFAULT_ZP_TABLE.len() - 1]
+        };
+        state.p[i] = e.p as u32;
+        state.m[i] = e.m as u32;
+        state.up[i] = e.up as u8;
+        state.dn[i] = e.dn as u8;
+    }
+}
+
 pub struct ZEncoder<W: Write> {
     state: ZpAsmState,
     writer: Option<Box<Cursor<Vec<u8>>>>,
@@ -130,18 +148,7 @@ impl ZEncoder<Cursor<Vec<u8>>> {
         let mut state: ZpAsmState = unsafe { mem::zeroed() };
         unsafe { zpcodec_einit(&mut state as *mut ZpAsmState) };
 
-        // Fill adaptation table from DEFAULT_ZP_TABLE
-        for i in 0..256 {
-            let e = if i < DEFAULT_ZP_TABLE.len() {
-                DEFAULT_ZP_TABLE[i]
-            } else {
-                DEFAULT_ZP_TABLE[DEFAULT_ZP_TABLE.len() - 1]
-            };
-            state.p[i] = e.p as u32;
-            state.m[i] = e.m as u32;
-            state.up[i] = e.up as u8;
-            state.dn[i] = e.dn as u8;
-        }
+        load_table(&mut state);
 
         // Hook bytestream and enable emission
         state.encoding = 1;
@@ -156,15 +163,18 @@ impl ZEncoder<Cursor<Vec<u8>>> {
     }
 
     #[inline(always)]
-    pub fn encode(&mut self, bit: bool, ctx: &mut BitContext) -> Result<(), ZCodecError> {
+    pub fn encode(&mut self, bit: bool, ctx: &mut ZpContext) -> Result<(), ZCodecError> {
         // Compute z = a + p[ctx]
-        let z = self.state.a.wrapping_add(self.state.p[*ctx as usize]);
-        let mps = (*ctx & 1) != 0;
+        let z = self
+            .state
+            .a
+            .wrapping_add(self.state.p[ctx.state() as usize]);
+        let mps = ctx.mps();
         unsafe {
             if bit != mps {
-                zpcodec_encode_lps(&mut self.state, ctx as *mut u8, z);
+                zpcodec_encode_lps(&mut self.state, ctx as *mut ZpContext as *mut u8, z);
             } else if z >= 0x8000 {
-                zpcodec_encode_mps(&mut self.state, ctx as *mut u8, z);
+                zpcodec_encode_mps(&mut self.state, ctx as *mut ZpContext as *mut u8, z);
             } else {
                 // a = z
                 self.state.a = z & 0xffff;
@@ -188,12 +198,39 @@ impl ZEncoder<Cursor<Vec<u8>>> {
     }
 
     pub fn finish(mut self) -> Result<Cursor<Vec<u8>>, ZCodecError> {
+        self.flush()
+    }
+
+    /// Starts a fresh stream on `writer`, returning the previous writer if
+    /// [`ZEncoder::flush`] has not taken it.
+    pub fn reset(&mut self, writer: Cursor<Vec<u8>>) -> Option<Cursor<Vec<u8>>> {
+        let previous = self.writer.take().map(|w| *w);
+        unsafe { zpcodec_einit(&mut self.state as *mut ZpAsmState) };
+        load_table(&mut self.state);
+        let mut writer_box = Box::new(writer);
+        let bs_ptr: *mut Cursor<Vec<u8>> = &mut *writer_box;
+        self.state.encoding = 1;
+        self.state.bs = bs_ptr as *mut c_void;
+        self.writer = Some(writer_box);
+        previous
+    }
+
+    /// At most how many bytes [`ZEncoder::flush`] adds to those written so
+    /// far.
+    pub fn flush_bound(&self) -> usize {
+        (self.state.scount as usize + self.state.nrun as usize + 48).div_ceil(8)
+    }
+
+    /// Finalizes encoding and returns the writer, keeping the encoder for
+    /// [`ZEncoder::reset`].
+    pub fn flush(&mut self) -> Result<Cursor<Vec<u8>>, ZCodecError> {
+        let Some(mut writer) = self.writer.take() else {
+            return Err(ZCodecError::Finished);
+        };
         unsafe {
             zpcodec_eflush(&mut self.state);
         }
 
-        let mut writer = self.writer.take().expect("writer present");
-
         // Ensure the stream ends with 0xFF for proper termination
         writer.write_all(&[0xFF]).expect("write termination byte");
 
@@ -201,7 +238,7 @@ impl ZEncoder<Cursor<Vec<u8>>> {
 
         // Debug: Check what the ZP stream looks like
         if buf.len() > 20 {
-            eprintln!("[DEBUG] First 20 bytes of ZP stream: {:02x?}", &buf[..20]);
+            trace!(target: target::ZP, "First 20 bytes of ZP stream: {:02x?}", &buf[..20]);
         }
 
         // Return the full buffer without truncation to preserve the complete ZP stream
@@ -263,7 +300,7 @@ mod tests {
 
 impl ZpEncoderCursor for ZEncoder<Cursor<Vec<u8>>> {
     #[inline(always)]
-    fn encode(&mut self, bit: bool, ctx: &mut BitContext) -> Result<(), ZCodecError> {
+    fn encode(&mut self, bit: bool, ctx: &mut ZpContext) -> Result<(), ZCodecError> {
         ZEncoder::encode(self, bit, ctx)
     }
     #[inline(always)]
@@ -0,0 +1,111 @@
+//! Adaptive contexts of the ZP coder.
+//!
+//! A [`ZpContext`] is the probability state of one kind of binary decision;
+//! only [`ZEncoder`](super::ZEncoder) reads or updates it, so a context
+//! cannot be confused with the numbers codecs index their contexts by (such
+//! as JB2's `Jb2Context` tree nodes). Codecs keep related contexts in a
+//! [`ContextBank`], which can be reset and reused from page to page.
+
+use alloc::{vec, vec::Vec};
+use core::ops::{Index, IndexMut};
+
+/// The adaptive state of one binary decision.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ZpContext(u8);
+
+impl ZpContext {
+    /// A context in its initial, unbiased state.
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    /// Raw state byte: the index of the context's row in the ZP table.
+    pub const fn state(self) -> u8 {
+        self.0
+    }
+
+    /// The decision this context currently expects.
+    pub(crate) const fn mps(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub(crate) const fn from_state(state: u8) -> Self {
+        Self(state)
+    }
+}
+
+/// A fixed set of contexts coded together, such as the pixel contexts of a
+/// JB2 bitmap or the bucket contexts of an IW44 band.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextBank(Vec<ZpContext>);
+
+impl ContextBank {
+    /// `len` contexts in their initial state.
+    pub fn new(len: usize) -> Self {
+        Self(vec![ZpContext::new(); len])
+    }
+
+    /// Returns every context to its initial state, as a fresh bank.
+    pub fn reset(&mut self) {
+        self.0.fill(ZpContext::new());
+    }
+
+    /// Grows or shrinks the bank; new contexts start in their initial state.
+    pub fn resize(&mut self, len: usize) {
+        self.0.resize(len, ZpContext::new());
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [ZpContext] {
+        &mut self.0
+    }
+}
+
+impl Index<usize> for ContextBank {
+    type Output = ZpContext;
+
+    fn index(&self, index: usize) -> &ZpContext {
+        &self.0[index]
+    }
+}
+
+impl IndexMut<usize> for ContextBank {
+    fn index_mut(&mut self, index: usize) -> &mut ZpContext {
+        &mut self.0[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::zc::ZEncoder;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_bank_reset_restarts_adaptation() {
+        let encode = |bank: &mut ContextBank| {
+            let mut zp = ZEncoder::new(Cursor::new(Vec::new()), true).unwrap();
+            for i in 0..200 {
+                zp.encode(i % 5 != 0, &mut bank[i % 3]).unwrap();
+            }
+            zp.finish().unwrap().into_inner()
+        };
+        let mut bank = ContextBank::new(3);
+        let first = encode(&mut bank);
+        assert_ne!(bank, ContextBank::new(3));
+
+        // A clone carries the learnt state on; a reset bank starts over.
+        let mut learnt = bank.clone();
+        assert_ne!(encode(&mut learnt), first);
+        bank.reset();
+        assert_eq!(encode(&mut bank), first);
+    }
+}
@@ -0,0 +1,317 @@
+//! ZP-Coder decoder.
+//!
+//! The counterpart of [`ZEncoder`](super::ZEncoder) in DjVu-compatible mode,
+//! for the tools that read back what the encoder wrote (such as the hidden
+//! text of finished documents). It follows DjVuLibre's `ZPCodec` decoder.
+
+use super::context::ZpContext;
+use super::table::ZpTableEntry;
+use super::zcodec::{DJVU_ZP_TABLE, ZCodecError};
+
+/// Reads bits coded by a DjVu-compatible [`ZEncoder`](super::ZEncoder) from
+/// a byte slice.
+pub struct ZDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    a: u32,
+    code: u32,
+    fence: u32,
+    buffer: u32,
+    scount: i32,
+    delay: i32,
+    table: [ZpTableEntry; 256],
+}
+
+impl<'a> ZDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, ZCodecError> {
+        Self::with_table(data, DJVU_ZP_TABLE)
+    }
+
+    /// Reads a stream coded with `table` (see [`ZEncoder::with_table`]).
+    ///
+    /// [`ZEncoder::with_table`]: super::ZEncoder::with_table
+    pub fn with_table(data: &'a [u8], table: [ZpTableEntry; 256]) -> Result<Self, ZCodecError> {
+        let mut decoder = Self {
+            data,
+            pos: 0,
+            a: 0,
+            code: 0,
+            fence: 0,
+            buffer: 0,
+            scount: 0,
+            delay: 25,
+            table,
+        };
+        let high = decoder.next_byte().unwrap_or(0xff) as u32;
+        let low = decoder.next_byte().unwrap_or(0xff) as u32;
+        decoder.code = high << 8 | low;
+        decoder.preload()?;
+        decoder.update_fence();
+        Ok(decoder)
+    }
+
+    /// Decodes a bit coded with `ctx`, adapting it as the encoder did.
+    #[inline]
+    pub fn decode(&mut self, ctx: &mut ZpContext) -> Result<bool, ZCodecError> {
+        let state = ctx.state() as usize;
+        let z = self.a + self.table[state].p as u32;
+        if z <= self.fence {
+            self.a = z;
+            return Ok(ctx.mps());
+        }
+
+        // Avoid interval reversion, as the encoder does.
+        let z = z.min(0x6000 + ((z + self.a) >> 2));
+        let mps = ctx.mps();
+        if z > self.code {
+            *ctx = ZpContext::from_state(self.table[state].dn);
+            self.lps(z)?;
+            Ok(!mps)
+        } else {
+            if self.a >= self.table[state].m as u32 {
+                *ctx = ZpContext::from_state(self.table[state].up);
+            }
+            self.mps(z)?;
+            Ok(mps)
+        }
+    }
+
+    /// Decodes a bit written by [`ZEncoder::encode_raw`](super::ZEncoder::encode_raw).
+    pub fn decode_raw(&mut self) -> Result<bool, ZCodecError> {
+        let z = 0x8000 + ((self.a + self.a + self.a) >> 3);
+        if z > self.code {
+            self.lps(z)?;
+            Ok(true)
+        } else {
+            self.mps(z)?;
+            Ok(false)
+        }
+    }
+
+    fn lps(&mut self, z: u32) -> Result<(), ZCodecError> {
+        let z = 0x10000 - z;
+        self.a += z;
+        self.code += z;
+        let shift = (self.a as u16).leading_ones() as i32;
+        self.scount -= shift;
+        self.a = (self.a << shift) & 0xffff;
+        let bits = (self.buffer >> self.scount) & ((1 << shift) - 1);
+        self.code = ((self.code << shift) | bits) & 0xffff;
+        self.refill()
+    }
+
+    fn mps(&mut self, z: u32) -> Result<(), ZCodecError> {
+        self.scount -= 1;
+        self.a = (z << 1) & 0xffff;
+        let bit = (self.buffer >> self.scount) & 1;
+        self.code = ((self.code << 1) | bit) & 0xffff;
+        self.refill()
+    }
+
+    fn refill(&mut self) -> Result<(), ZCodecError> {
+        if self.scount < 16 {
+            self.preload()?;
+        }
+        self.update_fence();
+        Ok(())
+    }
+
+    /// Tops the bit buffer up; past the end of the data the stream reads as
+    /// 1 bits for a while, then as truncated.
+    fn preload(&mut self) -> Result<(), ZCodecError> {
+        while self.scount <= 24 {
+            let byte = match self.next_byte() {
+                Some(byte) => byte,
+                None => {
+                    self.delay -= 1;
+                    if self.delay < 1 {
+                        return Err(ZCodecError::Truncated);
+                    }
+                    0xff
+                }
+            };
+            self.buffer = (self.buffer << 8) | byte as u32;
+            self.scount += 8;
+        }
+        Ok(())
+    }
+
+    fn update_fence(&mut self) {
+        self.fence = self.code.min(0x7fff);
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        let byte = self.data.get(self.pos).copied();
+        self.pos += byte.is_some() as usize;
+        byte
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::zc::zcodec::{PATCHED_ZP_TABLE, RAW_CONTEXT_128, RAW_CONTEXT_129};
+    use crate::encode::zc::{ContextBank, ZEncoder};
+    use std::io::Cursor;
+
+    /// One coded decision: raw, or with a context of the bank.
+    #[derive(Debug, Clone, Copy)]
+    enum Op {
+        Raw(bool),
+        Context(usize, bool),
+    }
+
+    /// A random sequence of `len` decisions over `contexts` contexts, each
+    /// context with its own bias so that some adapt to extreme states.
+    fn random_ops(seed: u32, len: usize, contexts: usize) -> Vec<Op> {
+        let mut state = seed.max(1);
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let bias: Vec<u32> = (0..contexts).map(|_| next() % 1025).collect();
+        (0..len)
+            .map(|_| {
+                let ctx = next() as usize % (contexts + 1);
+                if ctx == contexts {
+                    Op::Raw(next().is_multiple_of(2))
+                } else {
+                    Op::Context(ctx, next() % 1024 < bias[ctx])
+                }
+            })
+            .collect()
+    }
+
+    fn encode_ops(ops: &[Op], contexts: usize, compat: bool) -> Vec<u8> {
+        let mut zp = ZEncoder::new(Vec::new(), compat).unwrap();
+        let mut bank = ContextBank::new(contexts);
+        for &op in ops {
+            match op {
+                Op::Raw(bit) => zp.encode_raw(bit).unwrap(),
+                Op::Context(ctx, bit) => zp.encode(bit, &mut bank[ctx]).unwrap(),
+            }
+        }
+        zp.finish().unwrap()
+    }
+
+    /// Decodes `data` and checks that it gives back `ops`, bit for bit.
+    fn assert_decodes(data: &[u8], ops: &[Op], contexts: usize, compat: bool, case: &str) {
+        let table = if compat {
+            DJVU_ZP_TABLE
+        } else {
+            PATCHED_ZP_TABLE
+        };
+        let mut zp = ZDecoder::with_table(data, table).unwrap();
+        let mut bank = ContextBank::new(contexts);
+        for (i, &op) in ops.iter().enumerate() {
+            let (decoded, bit) = match op {
+                Op::Raw(bit) => (zp.decode_raw(), bit),
+                Op::Context(ctx, bit) => (zp.decode(&mut bank[ctx]), bit),
+            };
+            assert_eq!(decoded.unwrap(), bit, "{case}: decision {i}");
+        }
+    }
+
+    #[test]
+    fn test_decodes_encoder_output() {
+        let bits: Vec<bool> = (0..5000u32)
+            .map(|i| (i * 7919) % 13 < 4 || i % 97 == 0)
+            .collect();
+        let mut zp = ZEncoder::new(Vec::new(), true).unwrap();
+        let mut contexts = ContextBank::new(4);
+        for (i, &bit) in bits.iter().enumerate() {
+            if i % 5 == 4 {
+                zp.encode_raw(bit).unwrap();
+            } else {
+                zp.encode(bit, &mut contexts[i % 4]).unwrap();
+            }
+        }
+        let data = zp.finish().unwrap();
+
+        let mut zp = ZDecoder::new(&data).unwrap();
+        let mut contexts = ContextBank::new(4);
+        for (i, &bit) in bits.iter().enumerate() {
+            let decoded = if i % 5 == 4 {
+                zp.decode_raw().unwrap()
+            } else {
+                zp.decode(&mut contexts[i % 4]).unwrap()
+            };
+            assert_eq!(decoded, bit, "bit {i}");
+        }
+    }
+
+    #[test]
+    fn test_round_trips_random_sequences() {
+        // Short streams end inside the encoder's first bytes, where the
+        // delay and flush logic matter most; long ones cross many runs of
+        // carries.
+        let lengths = (0..48).chain([63, 64, 65, 255, 256, 1000, 20_000]);
+        for (n, len) in lengths.enumerate() {
+            for contexts in [1, 3, 16] {
+                for compat in [true, false] {
+                    let seed =
+                        ((n * 31 + contexts) as u32).wrapping_mul(2_654_435_761) + compat as u32;
+                    let ops = random_ops(seed, len, contexts);
+                    let data = encode_ops(&ops, contexts, compat);
+                    let case = format!("{len} decisions, {contexts} contexts, compat {compat}");
+                    assert_decodes(&data, &ops, contexts, compat, &case);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trips_extreme_runs() {
+        // Long runs of one decision drive a context to its most skewed
+        // state and the encoder through long runs of pending bits; a lone
+        // opposite decision after them codes a long carry.
+        for compat in [true, false] {
+            for (run, bit) in [(1, false), (5000, false), (5000, true)] {
+                let mut ops = vec![Op::Context(0, bit); run];
+                ops.push(Op::Context(0, !bit));
+                ops.extend(vec![Op::Context(0, bit); run]);
+                ops.extend((0..64).map(|i| Op::Raw(i % 9 == 0)));
+                ops.extend((0..64).map(|_| Op::Raw(true)));
+                let data = encode_ops(&ops, 1, compat);
+                let case = format!("run of {run} {bit}, compat {compat}");
+                assert_decodes(&data, &ops, 1, compat, &case);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decodes_routed_and_restored_streams() {
+        let ops = random_ops(7, 3000, 4);
+        let mut zp = ZEncoder::new(Cursor::new(Vec::new()), true).unwrap();
+        let mut bank = ContextBank::new(4);
+        for (i, &op) in ops.iter().enumerate() {
+            if i == 1500 {
+                // A trial encode that is rolled back leaves no trace.
+                let checkpoint = zp.checkpoint();
+                let saved = bank.clone();
+                for j in 0..700 {
+                    zp.encode(j % 3 == 0, &mut bank[j % 4]).unwrap();
+                }
+                zp.restore(&checkpoint).unwrap();
+                bank = saved;
+            }
+            match op {
+                Op::Raw(bit) => {
+                    let mut raw = if i % 2 == 0 {
+                        RAW_CONTEXT_128
+                    } else {
+                        RAW_CONTEXT_129
+                    };
+                    zp.encode_with_context_routing(bit, &mut raw).unwrap();
+                }
+                Op::Context(ctx, bit) => {
+                    zp.encode_with_context_routing(bit, &mut bank[ctx]).unwrap()
+                }
+            }
+        }
+        let data = zp.finish().unwrap().into_inner();
+        assert_decodes(&data, &ops, 4, true, "routed");
+    }
+}
@@ -1,5 +1,8 @@
+#[cfg(feature = "std")]
+use super::zcodec::ZCodecError;
+
 /// Z-Coder probability table entry
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct ZpTableEntry {
     pub p: u16, // Probability value (16-bit)
     pub m: u16, // Threshold for MPS adaptation
@@ -300,3 +303,294 @@ pub const DEFAULT_ZP_TABLE: [ZpTableEntry; 256] = zp_table_c!(
   { 0x0000,  0x0000,   0,   0 },
   { 0x0000,  0x0000,   0,   0 },
 );
+
+/// How far a state falls back after coding an LPS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ZpAdaptation {
+    /// One level, as in the DjVu table. Decoders assume this.
+    #[default]
+    Slow,
+    /// Two levels where the LPS also renormalizes the interval by more than
+    /// the MPS threshold, as DjVuLibre does outside compatibility mode.
+    Fast,
+}
+
+/// Applies `adaptation` to the LPS transitions of `table`.
+///
+/// For [`ZpAdaptation::Fast`], a state whose LPS leaves an interval
+/// `a = 0x10000 - p` that, once renormalized, still passes both the
+/// renormalization point and the MPS threshold `m` skips a level: its `dn`
+/// becomes the `dn` of its `dn`.
+pub const fn adapt_table(
+    mut table: [ZpTableEntry; 256],
+    adaptation: ZpAdaptation,
+) -> [ZpTableEntry; 256] {
+    if let ZpAdaptation::Slow = adaptation {
+        return table;
+    }
+    let base = table;
+    let mut j = 0;
+    while j < 256 {
+        let mut a = 0x10000 - base[j].p as u32;
+        while a >= 0x8000 {
+            a = (a << 1) & 0xffff;
+        }
+        if base[j].m > 0 && a + base[j].p as u32 >= 0x8000 && a >= base[j].m as u32 {
+            table[j].dn = base[base[j].dn as usize].dn;
+        }
+        j += 1;
+    }
+    table
+}
+
+/// The LPS probability a state with the given `p` codes for.
+///
+/// This is the coding-cost equation of the Z-Coder paper (Bottou et al.,
+/// 1998), which DjVuLibre also uses to pick a state for a probability: linear
+/// below `p = 1/6`, and the exact expected cost of the ZP approximation above.
+#[cfg(feature = "std")]
+pub fn p_to_plps(p: u16) -> f64 {
+    let fp = p as f64 / 65536.0;
+    let ln2 = std::f64::consts::LN_2;
+    if fp < 1.0 / 6.0 {
+        fp * 2.0 * ln2
+    } else {
+        let x = 1.5 * fp + 0.25;
+        (1.5 * fp - 0.25) - x * x.ln() + (0.5 * fp - 0.25) * ln2
+    }
+}
+
+/// The `p` (at most `0x8000`) whose [`p_to_plps`] is nearest `plps`.
+#[cfg(feature = "std")]
+pub fn plps_to_p(plps: f64) -> u16 {
+    // p_to_plps increases with p, so bisect for the first p at or above.
+    let (mut lo, mut hi) = (0u32, 0x8000u32);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if p_to_plps(mid as u16) < plps {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    let p = lo as u16;
+    if p > 0 && plps - p_to_plps(p - 1) <= p_to_plps(p) - plps {
+        p - 1
+    } else {
+        p
+    }
+}
+
+/// A state of a table for [`generate_table`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZpStateSpec {
+    /// LPS probability the state estimates; 0 for unused states.
+    pub plps: f64,
+    /// MPS adaptation threshold; 0 for states that always move up.
+    pub m: u16,
+    /// Next state after an MPS that adapts.
+    pub up: u8,
+    /// Next state after an LPS, before [`ZpAdaptation`] is applied.
+    pub dn: u8,
+}
+
+/// Builds a table from the probabilities and transitions of its states:
+/// each `p` is [`plps_to_p`] of the state's probability, and the LPS
+/// transitions follow `adaptation`. States past the end of `states` are
+/// unused.
+///
+/// Streams coded with anything but [`DEFAULT_ZP_TABLE`] and
+/// [`ZpAdaptation::Slow`] only decode with the same table.
+#[cfg(feature = "std")]
+pub fn generate_table(states: &[ZpStateSpec], adaptation: ZpAdaptation) -> [ZpTableEntry; 256] {
+    let unused = ZpTableEntry {
+        p: 0,
+        m: 0,
+        up: 0,
+        dn: 0,
+    };
+    let mut table = [unused; 256];
+    for (entry, state) in table.iter_mut().zip(states) {
+        *entry = ZpTableEntry {
+            p: plps_to_p(state.plps),
+            m: state.m,
+            up: state.up,
+            dn: state.dn,
+        };
+    }
+    adapt_table(table, adaptation)
+}
+
+/// The states of [`DEFAULT_ZP_TABLE`], as probabilities.
+///
+/// The thresholds and transitions were tuned by the authors of the coder and
+/// are carried over as they are.
+#[cfg(feature = "std")]
+pub fn default_states() -> Vec<ZpStateSpec> {
+    DEFAULT_ZP_TABLE
+        .iter()
+        .map(|e| ZpStateSpec {
+            plps: p_to_plps(e.p),
+            m: e.m,
+            up: e.up,
+            dn: e.dn,
+        })
+        .collect()
+}
+
+/// The states of a table that estimates the LPS probabilities of `ladder`,
+/// listed from the most even (at most 1/2) down.
+///
+/// Rung `k` of the ladder gives two states, `2k + 1` and `2k + 2`, one for
+/// each MPS; state 0, where contexts start, is a copy of state 2. Each
+/// state's MPS threshold `m` is `0x8000 * (1 - plps)`: the state moves up a
+/// rung once the interval left after an MPS is wider than the share it
+/// expects the MPS to take. An MPS on the first rung always moves up. An LPS
+/// moves down a rung, or from the first rung to the second with the other
+/// MPS.
+///
+/// Fails unless `ladder` holds 1 to 127 decreasing probabilities in
+/// `(0, 0.5]`.
+#[cfg(feature = "std")]
+pub fn ladder_states(ladder: &[f64]) -> Result<Vec<ZpStateSpec>, ZCodecError> {
+    let decreasing = ladder.windows(2).all(|w| w[1] < w[0]);
+    let in_range = ladder.iter().all(|&q| q > 0.0 && q <= 0.5);
+    if ladder.is_empty() || ladder.len() > 127 || !decreasing || !in_range {
+        return Err(ZCodecError::InvalidState(
+            "a ZP ladder holds 1 to 127 decreasing probabilities in (0, 0.5]",
+        ));
+    }
+
+    let unused = ZpStateSpec {
+        plps: 0.0,
+        m: 0,
+        up: 0,
+        dn: 0,
+    };
+    let mut states = vec![unused; 2 * ladder.len() + 1];
+    let last = ladder.len() - 1;
+    for (k, &plps) in ladder.iter().enumerate() {
+        let m = if k == 0 {
+            0
+        } else {
+            (0x8000 as f64 * (1.0 - plps)).round() as u16
+        };
+        let up = 2 * (k + 1).min(last) + 1;
+        for mps in [1, 0] {
+            // States 2k + 1 code MPS 1 and 2k + 2 MPS 0.
+            let state = 2 * k + 2 - mps;
+            let dn = match k {
+                0 if last == 0 => 3 - state,
+                0 => 3 + mps,
+                _ => state - 2,
+            };
+            states[state] = ZpStateSpec {
+                plps,
+                m,
+                up: (up + 1 - mps) as u8,
+                dn: dn as u8,
+            };
+        }
+    }
+    states[0] = states[2];
+    Ok(states)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::zc::{ZDecoder, ZEncoder, ZpContext};
+
+    /// A ladder shaped like the DjVu table's: from 1/2 down to about
+    /// 1/40000, each rung 12% below the last.
+    fn ladder() -> Vec<f64> {
+        std::iter::successors(Some(0.5), |q| Some(q * 0.88))
+            .take_while(|&q| q > 2e-5)
+            .collect()
+    }
+
+    #[test]
+    fn test_generated_table_codes_like_shipped_table() {
+        let states = ladder_states(&ladder()).unwrap();
+        let table = generate_table(&states, ZpAdaptation::Slow);
+        let rungs = ladder().len();
+        for state in 1..=2 * rungs {
+            let e = table[state];
+            assert!(e.p > 0 && (e.up as usize) <= 2 * rungs && (e.dn as usize) <= 2 * rungs);
+            assert_eq!(state % 2, e.up as usize % 2, "state {state} keeps its MPS");
+        }
+        assert_eq!((table[0], table[1].p), (table[2], 0x8000));
+
+        // Bernoulli sources of several skews code to about the size the
+        // shipped table gives, and decode with the generated table.
+        let mut seed = 0x2545_f491u32;
+        for plps in [0.3, 0.1, 0.02, 0.002] {
+            let bits: Vec<bool> = (0..40_000)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    ((seed >> 8) as f64 / (1 << 24) as f64) < plps
+                })
+                .collect();
+            let encode = |zp: ZEncoder<Vec<u8>>| {
+                let (mut zp, mut ctx) = (zp, ZpContext::new());
+                for &bit in &bits {
+                    zp.encode(bit, &mut ctx).unwrap();
+                }
+                zp.finish().unwrap()
+            };
+            let shipped = encode(ZEncoder::new(Vec::new(), true).unwrap());
+            let generated = encode(ZEncoder::with_table(Vec::new(), table).unwrap());
+            let ratio = generated.len() as f64 / shipped.len() as f64;
+            assert!(ratio < 1.03, "plps {plps}: {ratio}");
+
+            let mut zp = ZDecoder::with_table(&generated, table).unwrap();
+            let mut ctx = ZpContext::new();
+            for (i, &bit) in bits.iter().enumerate() {
+                assert_eq!(zp.decode(&mut ctx).unwrap(), bit, "plps {plps}: bit {i}");
+            }
+        }
+
+        assert!(ladder_states(&[]).is_err());
+        assert!(ladder_states(&[0.5, 0.6]).is_err());
+        assert!(ladder_states(&[0.4, 0.4]).is_err());
+    }
+
+    #[test]
+    fn test_generated_default_matches_shipped_table() {
+        let states = default_states();
+        assert_eq!(
+            generate_table(&states, ZpAdaptation::Slow),
+            DEFAULT_ZP_TABLE
+        );
+
+        // The startup states estimate simple fractions after the first few
+        // symbols; their p follows from those alone, up to the rounding of
+        // the original generator (1/5 appears as both 0x24ee and 0x24ef).
+        for (state, plps) in [
+            (0, 1.0 / 2.0),
+            (84, 1.0 / 5.0),
+            (86, 1.0 / 14.0),
+            (87, 4.0 / 11.0),
+            (89, 2.0 / 7.0),
+            (95, 1.0 / 11.0),
+        ] {
+            let p = plps_to_p(plps);
+            assert!(
+                p.abs_diff(DEFAULT_ZP_TABLE[state].p) <= 1,
+                "state {state}: {p:#x}"
+            );
+        }
+
+        // Fast adaptation only ever skips further down the chain.
+        let fast = generate_table(&states, ZpAdaptation::Fast);
+        assert_eq!(fast, adapt_table(DEFAULT_ZP_TABLE, ZpAdaptation::Fast));
+        let changed: Vec<usize> = (0..256)
+            .filter(|&i| fast[i] != DEFAULT_ZP_TABLE[i])
+            .collect();
+        assert!(!changed.is_empty());
+        for i in changed {
+            let dn = DEFAULT_ZP_TABLE[i].dn as usize;
+            assert_eq!(fast[i].dn, DEFAULT_ZP_TABLE[dn].dn);
+        }
+    }
+}
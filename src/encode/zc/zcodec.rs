@@ -1,38 +1,96 @@
+#[cfg(feature = "std")]
 use super::ZpEncoderCursor;
-use super::table::{DEFAULT_ZP_TABLE, ZpTableEntry};
+use super::context::ZpContext;
+use super::table::{DEFAULT_ZP_TABLE, ZpAdaptation, ZpTableEntry, adapt_table};
+use crate::utils::log::{target, warn};
+#[cfg(feature = "std")]
 use std::io::Cursor;
-use std::io::Write;
 use thiserror::Error;
 
-/// A single byte representing the statistical context for encoding a bit.
-pub type BitContext = u8;
-
 /// Raw (non-adaptive) contexts according to the DjVu IW44 spec.
-pub const RAW_CONTEXT_128: BitContext = 128;
-pub const RAW_CONTEXT_129: BitContext = 129;
+pub const RAW_CONTEXT_128: ZpContext = ZpContext::from_state(128);
+pub const RAW_CONTEXT_129: ZpContext = ZpContext::from_state(129);
 
 /// Errors that can occur during Z-Coder encoding.
 #[derive(Error, Debug)]
 pub enum ZCodecError {
+    #[cfg(feature = "std")]
     #[error("I/O error during write operation")]
     Io(#[from] std::io::Error),
+    #[error("Output buffer is full")]
+    SinkFull,
     #[error("Attempted to encode after the stream was finished")]
     Finished,
+    #[error("ZP stream ended before the data it codes")]
+    Truncated,
+    #[error("Invalid ZP coder state: {0}")]
+    InvalidState(&'static str),
 }
 
+#[cfg(feature = "std")]
 impl From<ZCodecError> for std::io::Error {
     fn from(err: ZCodecError) -> Self {
         match err {
             ZCodecError::Io(e) => e,
+            ZCodecError::SinkFull => {
+                std::io::Error::new(std::io::ErrorKind::WriteZero, err.to_string())
+            }
             ZCodecError::Finished => {
                 std::io::Error::new(std::io::ErrorKind::Other, err.to_string())
             }
+            ZCodecError::Truncated => {
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, err.to_string())
+            }
+            ZCodecError::InvalidState(_) => {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+            }
         }
     }
 }
 
+/// Where a [`ZEncoder`] puts its output.
+///
+/// The coder emits whole bytes one at a time, so this is all it needs from
+/// a writer. With the `std` feature every [`std::io::Write`] is a sink;
+/// without it `Vec<u8>` is, and firmware can implement it for a fixed
+/// buffer (failing with [`ZCodecError::SinkFull`]) or a DMA ring.
+pub trait ByteSink {
+    fn put_byte(&mut self, byte: u8) -> Result<(), ZCodecError>;
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write + ?Sized> ByteSink for W {
+    #[inline]
+    fn put_byte(&mut self, byte: u8) -> Result<(), ZCodecError> {
+        Ok(self.write_all(&[byte])?)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ByteSink for alloc::vec::Vec<u8> {
+    #[inline]
+    fn put_byte(&mut self, byte: u8) -> Result<(), ZCodecError> {
+        self.push(byte);
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<S: ByteSink + ?Sized> ByteSink for &mut S {
+    #[inline]
+    fn put_byte(&mut self, byte: u8) -> Result<(), ZCodecError> {
+        (**self).put_byte(byte)
+    }
+}
+
 /// An adaptive quasi-arithmetic encoder implementing the ZP-Coder algorithm.
-pub struct ZEncoder<W: Write> {
+///
+/// The stream must be ended with [`ZEncoder::finish`] or [`ZEncoder::flush`];
+/// an encoder dropped mid-stream writes nothing more, and its output is
+/// incomplete.
+#[must_use = "the stream is incomplete until `finish` is called"]
+#[derive(Clone)]
+pub struct ZEncoder<W: ByteSink> {
     writer: Option<W>,
     // Core ZP-Coder registers (matching djvulibre exactly)
     a: u32,      // range register (unsigned!)
@@ -43,40 +101,31 @@ pub struct ZEncoder<W: Write> {
     scount: i32, // bit counter in current byte
     delay: i32,  // delay counter
     finished: bool,
-    table: [ZpTableEntry; 256], // mutable table for patching
+    table: [ZpTableEntry; 256],
 }
 
-impl<W: Write> ZEncoder<W> {
+/// [`DEFAULT_ZP_TABLE`] as the encoder uses it in DjVu-compatible mode.
+pub(super) static DJVU_ZP_TABLE: [ZpTableEntry; 256] = DEFAULT_ZP_TABLE;
+/// [`DEFAULT_ZP_TABLE`] with the faster LPS adaptation DjVuLibre enables
+/// outside compatibility mode.
+pub(super) static PATCHED_ZP_TABLE: [ZpTableEntry; 256] =
+    adapt_table(DEFAULT_ZP_TABLE, ZpAdaptation::Fast);
+
+impl<W: ByteSink> ZEncoder<W> {
     /// Creates a new ZP-Coder encoder that writes to the given writer.
     pub fn new(writer: W, djvu_compat: bool) -> Result<Self, ZCodecError> {
-        // Create a 256-entry table, starting with the default 251 entries
-        let mut table = [ZpTableEntry {
-            p: 0,
-            m: 0,
-            up: 0,
-            dn: 0,
-        }; 256];
-
-        // Copy the default table entries
-        for (i, &entry) in DEFAULT_ZP_TABLE.iter().enumerate() {
-            table[i] = entry;
-        }
-
-        // Patch table when djvu_compat is false
-        if !djvu_compat {
-            for j in 0..256 {
-                let mut a = 0x10000 - table[j].p as u32;
-                while a >= 0x8000 {
-                    a = (a << 1) & 0xffff;
-                }
-                if table[j].m > 0 && a + table[j].p as u32 >= 0x8000 && a >= table[j].m as u32 {
-                    let x = DEFAULT_ZP_TABLE[j].dn;
-                    let y = DEFAULT_ZP_TABLE[x as usize].dn;
-                    table[j].dn = y;
-                }
-            }
-        }
+        let table = if djvu_compat {
+            DJVU_ZP_TABLE
+        } else {
+            PATCHED_ZP_TABLE
+        };
+        Self::with_table(writer, table)
+    }
 
+    /// Creates an encoder that adapts its contexts with `table`, such as one
+    /// made by [`generate_table`](super::table::generate_table). Only a
+    /// decoder with the same table reads the stream back.
+    pub fn with_table(writer: W, table: [ZpTableEntry; 256]) -> Result<Self, ZCodecError> {
         Ok(ZEncoder {
             writer: Some(writer),
             a: 0,             // Initialize to 0 as per DjVuLibre
@@ -91,16 +140,51 @@ impl<W: Write> ZEncoder<W> {
         })
     }
 
+    /// Starts a fresh stream on `writer`, as [`ZEncoder::new`] would with
+    /// the same table, without allocating. Returns the previous writer, if
+    /// [`ZEncoder::flush`] has not taken it; whatever it holds is not
+    /// flushed.
+    pub fn reset(&mut self, writer: W) -> Option<W> {
+        self.a = 0;
+        self.subend = 0;
+        self.buffer = 0xffffff;
+        self.nrun = 0;
+        self.byte = 0;
+        self.scount = 0;
+        self.delay = 25;
+        self.finished = false;
+        self.writer.replace(writer)
+    }
+
+    /// At most how many bytes [`ZEncoder::flush`] adds to those written so
+    /// far: the bits still held back, the 24-bit buffer and the subinterval
+    /// end, rounded up to a whole byte.
+    pub fn flush_bound(&self) -> usize {
+        (self.scount.max(0) as usize + self.nrun as usize + 48).div_ceil(8)
+    }
+
+    /// Whether the stream is finished, or nothing has been coded since the
+    /// encoder was created or reset.
+    pub fn is_idle(&self) -> bool {
+        self.finished
+            || (self.a == 0
+                && self.subend == 0
+                && self.buffer == 0xffffff
+                && self.nrun == 0
+                && self.scount == 0
+                && self.delay == 25)
+    }
+
     /// Encodes a single bit using the provided statistical context.
     #[inline(always)]
-    pub fn encode(&mut self, bit: bool, ctx: &mut BitContext) -> Result<(), ZCodecError> {
+    pub fn encode(&mut self, bit: bool, ctx: &mut ZpContext) -> Result<(), ZCodecError> {
         if self.finished {
             return Err(ZCodecError::Finished);
         }
 
         // CRITICAL: z = a + p[ctx], not just p[ctx]!
-        let z = self.a + self.table[*ctx as usize].p as u32;
-        if bit != (*ctx & 1 != 0) {
+        let z = self.a + self.table[ctx.state() as usize].p as u32;
+        if bit != ctx.mps() {
             // LPS path
             self.encode_lps(ctx, z)?;
         } else if z >= 0x8000 {
@@ -140,13 +224,13 @@ impl<W: Write> ZEncoder<W> {
     }
 
     #[inline(always)]
-    fn encode_mps(&mut self, ctx: &mut BitContext, mut z: u32) -> Result<(), ZCodecError> {
+    fn encode_mps(&mut self, ctx: &mut ZpContext, mut z: u32) -> Result<(), ZCodecError> {
         let d = 0x6000 + ((z + self.a) >> 2);
         if z > d {
             z = d;
         }
-        if self.a >= self.table[*ctx as usize].m as u32 {
-            *ctx = self.table[*ctx as usize].up;
+        if self.a >= self.table[ctx.state() as usize].m as u32 {
+            *ctx = ZpContext::from_state(self.table[ctx.state() as usize].up);
         }
         self.a = z;
         if self.a >= 0x8000 {
@@ -158,12 +242,12 @@ impl<W: Write> ZEncoder<W> {
     }
 
     #[inline(always)]
-    fn encode_lps(&mut self, ctx: &mut BitContext, mut z: u32) -> Result<(), ZCodecError> {
+    fn encode_lps(&mut self, ctx: &mut ZpContext, mut z: u32) -> Result<(), ZCodecError> {
         let d = 0x6000 + ((z + self.a) >> 2);
         if z > d {
             z = d;
         }
-        *ctx = self.table[*ctx as usize].dn;
+        *ctx = ZpContext::from_state(self.table[ctx.state() as usize].dn);
         z = 0x10000 - z;
         self.subend = self.subend.wrapping_add(z);
         self.a = self.a.wrapping_add(z);
@@ -226,10 +310,7 @@ impl<W: Write> ZEncoder<W> {
                 self.nrun += 1;
             }
             _ => {
-                return Err(ZCodecError::Io(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "invalid zemit bit",
-                )));
+                return Err(ZCodecError::InvalidState("invalid zemit bit"));
             }
         }
         Ok(())
@@ -246,7 +327,7 @@ impl<W: Write> ZEncoder<W> {
             self.scount += 1;
             if self.scount == 8 {
                 if let Some(ref mut writer) = self.writer {
-                    writer.write_all(&[self.byte])?;
+                    writer.put_byte(self.byte)?;
                 }
                 self.scount = 0;
                 self.byte = 0;
@@ -400,6 +481,12 @@ impl<W: Write> ZEncoder<W> {
 
     /// Finalizes encoding and returns the writer.
     pub fn finish(mut self) -> Result<W, ZCodecError> {
+        self.flush()
+    }
+
+    /// Finalizes encoding and returns the writer, keeping the encoder for
+    /// [`ZEncoder::reset`].
+    pub fn flush(&mut self) -> Result<W, ZCodecError> {
         if !self.finished {
             self.eflush()?;
             self.finished = true;
@@ -419,7 +506,7 @@ impl<W: Write> ZEncoder<W> {
     pub fn encode_with_context_routing(
         &mut self,
         bit: bool,
-        ctx: &mut BitContext,
+        ctx: &mut ZpContext,
     ) -> Result<(), ZCodecError> {
         match *ctx {
             RAW_CONTEXT_128 | RAW_CONTEXT_129 => {
@@ -434,10 +521,87 @@ impl<W: Write> ZEncoder<W> {
     }
 }
 
-impl<W: Write> Drop for ZEncoder<W> {
+/// Saved register state of a [`ZEncoder`], taken with
+/// [`ZEncoder::checkpoint`].
+///
+/// Contexts are owned by the caller, so they are not part of the checkpoint;
+/// save them alongside (e.g. with `Codec::snapshot` in IW44).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZpCheckpoint {
+    a: u32,
+    subend: u32,
+    buffer: u32,
+    nrun: u32,
+    byte: u8,
+    scount: i32,
+    delay: i32,
+    bytes_written: usize,
+}
+
+impl ZpCheckpoint {
+    /// Bytes the encoder had written when the checkpoint was taken.
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+}
+
+#[cfg(feature = "std")]
+impl ZEncoder<Cursor<Vec<u8>>> {
+    /// Captures the encoder state so a trial encode can be rolled back with
+    /// [`restore`](Self::restore).
+    ///
+    /// This copies a handful of registers; the output buffer is not copied,
+    /// only its length.
+    pub fn checkpoint(&self) -> ZpCheckpoint {
+        ZpCheckpoint {
+            a: self.a,
+            subend: self.subend,
+            buffer: self.buffer,
+            nrun: self.nrun,
+            byte: self.byte,
+            scount: self.scount,
+            delay: self.delay,
+            bytes_written: self.writer.as_ref().map_or(0, |w| w.get_ref().len()),
+        }
+    }
+
+    /// Rolls back to `checkpoint`, discarding everything written since.
+    ///
+    /// The checkpoint must come from this encoder, and the encoder must not
+    /// have been finished in between.
+    pub fn restore(&mut self, checkpoint: &ZpCheckpoint) -> Result<(), ZCodecError> {
+        if self.finished {
+            return Err(ZCodecError::Finished);
+        }
+        if let Some(ref mut writer) = self.writer {
+            if writer.get_ref().len() < checkpoint.bytes_written {
+                return Err(ZCodecError::InvalidState(
+                    "checkpoint is ahead of the encoder output",
+                ));
+            }
+            writer.get_mut().truncate(checkpoint.bytes_written);
+            writer.set_position(checkpoint.bytes_written as u64);
+        }
+        self.a = checkpoint.a;
+        self.subend = checkpoint.subend;
+        self.buffer = checkpoint.buffer;
+        self.nrun = checkpoint.nrun;
+        self.byte = checkpoint.byte;
+        self.scount = checkpoint.scount;
+        self.delay = checkpoint.delay;
+        Ok(())
+    }
+}
+
+impl<W: ByteSink> Drop for ZEncoder<W> {
     fn drop(&mut self) {
-        if !self.finished {
-            let _ = self.eflush();
+        // Flushing here could only fail silently, or write the end of a
+        // stream an error cut short.
+        if !self.is_idle() {
+            warn!(
+                target: target::ZP,
+                "ZEncoder dropped without finish(); its output is incomplete"
+            );
         }
     }
 }
@@ -447,10 +611,77 @@ mod tests {
     use super::*;
     use std::io::Cursor;
 
+    #[test]
+    fn test_drop_without_finish_writes_nothing() {
+        let mut out = Vec::new();
+        let mut encoder = ZEncoder::new(&mut out, true).unwrap();
+        assert!(encoder.is_idle());
+        let mut ctx = ZpContext::new();
+        for i in 0..10 {
+            encoder.encode(i % 3 == 0, &mut ctx).unwrap();
+        }
+        assert!(!encoder.is_idle());
+        drop(encoder);
+        assert!(out.is_empty());
+    }
+
+    /// A fixed buffer, as firmware without an allocator would use.
+    struct FixedSink<const N: usize> {
+        buf: [u8; N],
+        len: usize,
+    }
+
+    impl<const N: usize> ByteSink for FixedSink<N> {
+        fn put_byte(&mut self, byte: u8) -> Result<(), ZCodecError> {
+            *self.buf.get_mut(self.len).ok_or(ZCodecError::SinkFull)? = byte;
+            self.len += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_byte_sink() {
+        let encode = |bits: usize, sink| {
+            let mut encoder = ZEncoder::new(sink, true)?;
+            let mut ctx = ZpContext::new();
+            for i in 0..bits {
+                encoder.encode(i % 7 == 0, &mut ctx)?;
+                encoder.encode_raw(i % 2 == 0)?;
+            }
+            encoder.finish()
+        };
+        let mut expected = ZEncoder::new(Vec::new(), true).unwrap();
+        let mut ctx = ZpContext::new();
+        for i in 0..200 {
+            expected.encode(i % 7 == 0, &mut ctx).unwrap();
+            expected.encode_raw(i % 2 == 0).unwrap();
+        }
+        let expected = expected.finish().unwrap();
+
+        let sink = encode(
+            200,
+            FixedSink {
+                buf: [0; 64],
+                len: 0,
+            },
+        )
+        .unwrap();
+        assert_eq!(&sink.buf[..sink.len], expected.as_slice());
+
+        let overflow = encode(
+            2000,
+            FixedSink {
+                buf: [0; 64],
+                len: 0,
+            },
+        );
+        assert!(matches!(overflow, Err(ZCodecError::SinkFull)));
+    }
+
     #[test]
     fn test_encode_simple_sequence() {
         let mut encoder = ZEncoder::new(Cursor::new(Vec::new()), false).unwrap();
-        let mut ctx = 0;
+        let mut ctx = ZpContext::new();
 
         for i in 0..100 {
             encoder.encode(i % 2 == 0, &mut ctx).unwrap();
@@ -466,7 +697,7 @@ mod tests {
     #[test]
     fn test_encode_highly_probable_sequence() {
         let mut encoder = ZEncoder::new(Cursor::new(Vec::new()), false).unwrap();
-        let mut ctx = 0;
+        let mut ctx = ZpContext::new();
 
         for _ in 0..1000 {
             encoder.encode(false, &mut ctx).unwrap();
@@ -476,11 +707,59 @@ mod tests {
         let data = encoder.finish().unwrap().into_inner();
         assert!(data.len() < 20);
     }
+
+    #[test]
+    fn test_reset_matches_new_encoder() {
+        let encode = |encoder: &mut ZEncoder<Cursor<Vec<u8>>>| {
+            let mut ctx = ZpContext::new();
+            for i in 0..300 {
+                encoder.encode(i % 7 == 0, &mut ctx).unwrap();
+            }
+            encoder.flush().unwrap().into_inner()
+        };
+        for compat in [true, false] {
+            let expected = encode(&mut ZEncoder::new(Cursor::new(Vec::new()), compat).unwrap());
+
+            let mut encoder = ZEncoder::new(Cursor::new(Vec::new()), compat).unwrap();
+            let first = encode(&mut encoder);
+            assert!(encoder.reset(Cursor::new(Vec::with_capacity(64))).is_none());
+            assert_eq!(encode(&mut encoder), expected);
+            assert_eq!(first, expected);
+            assert!(encoder.flush().is_err());
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_restore_discards_trial_bits() {
+        let bits = |i: usize| i.is_multiple_of(3) || i.is_multiple_of(7);
+
+        let mut reference = ZEncoder::new(Cursor::new(Vec::new()), true).unwrap();
+        let mut ctx = ZpContext::new();
+        for i in 0..500 {
+            reference.encode(bits(i), &mut ctx).unwrap();
+        }
+        let expected = reference.finish().unwrap().into_inner();
+
+        let mut encoder = ZEncoder::new(Cursor::new(Vec::new()), true).unwrap();
+        let mut ctx = ZpContext::new();
+        for i in 0..500 {
+            encoder.encode(bits(i), &mut ctx).unwrap();
+        }
+        let checkpoint = encoder.checkpoint();
+        for i in 0..2000 {
+            encoder.encode(i % 2 == 0, &mut ctx).unwrap();
+        }
+        assert!(ZpEncoderCursor::tell_bytes(&encoder) > checkpoint.bytes_written());
+        encoder.restore(&checkpoint).unwrap();
+        let data = encoder.finish().unwrap().into_inner();
+        assert_eq!(data, expected);
+    }
 }
 
 // Implement ZpEncoderCursor trait for ZEncoder<Cursor<Vec<u8>>>
+#[cfg(feature = "std")]
 impl ZpEncoderCursor for ZEncoder<Cursor<Vec<u8>>> {
-    fn encode(&mut self, bit: bool, ctx: &mut BitContext) -> Result<(), ZCodecError> {
+    fn encode(&mut self, bit: bool, ctx: &mut ZpContext) -> Result<(), ZCodecError> {
         self.encode(bit, ctx)
     }
 
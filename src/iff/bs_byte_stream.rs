@@ -3,10 +3,12 @@
 //! This module implements the BZZ compression algorithm as required by the DjVu specification.
 //! It is a port of the C++ BSByteStream implementation from DjVuLibre.
 
-use crate::encode::zc::BitContext;
+use crate::encode::zc::{ContextBank, ZDecoder, ZpContext};
 // IMPORTANT: Always use the Rust ZEncoder for BZZ to avoid FFI writer constraints
 use crate::encode::zc::zcodec::ZEncoder as RustZEncoder;
 use crate::utils::error::{DjvuError, Result};
+use crate::utils::log::{target, trace, warn};
+use std::cell::Cell;
 use std::io::Write;
 
 const MIN_BLOCK_SIZE: usize = 10 * 1024;
@@ -14,24 +16,93 @@ const MAX_BLOCK_SIZE: usize = 4096 * 1024;
 const OVERFLOW: usize = 32; // Extra bytes for encoding safety
 const FREQMAX: usize = 4; // Max frequencies for MTF
 const CTXIDS: usize = 3; // Context IDs for ZP encoding
+const CONTEXTS: usize = 300; // Context array size as in C++ code
 const FREQS0: u32 = 100000; // Thresholds for estimation speed
 const FREQS1: u32 = 1000000;
 
+/// Settings for BZZ compression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct BzzOptions {
+    /// Block size in kilobytes, clamped to 10-4096 (default: 100)
+    pub block_size_k: usize,
+    /// How fast the move-to-front frequencies adapt, 0-2; `None` picks it
+    /// from the size of each block, like DjVuLibre (default)
+    pub speed: Option<u8>,
+}
+
+impl Default for BzzOptions {
+    fn default() -> Self {
+        Self {
+            block_size_k: 100,
+            speed: None,
+        }
+    }
+}
+
+/// A BZZ compressor. End the stream with [`BsEncoder::finish`]; an encoder
+/// dropped before that writes nothing more, and its output is incomplete.
+#[must_use = "the stream is incomplete until `finish` is called"]
 pub struct BsEncoder<W: Write> {
     zp_encoder: RustZEncoder<W>,
     buffer: Vec<u8>,
     block_size: usize,
+    speed: Option<u8>,
+    // Carried over from block to block, as in DjVuLibre
+    contexts: ContextBank,
 }
 
 impl<W: Write> BsEncoder<W> {
     pub fn new(writer: W, block_size_k: usize) -> Result<Self> {
-        let block_size = (block_size_k * 1024).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+        Self::with_options(
+            writer,
+            &BzzOptions {
+                block_size_k,
+                ..BzzOptions::default()
+            },
+        )
+    }
+
+    pub fn with_options(writer: W, options: &BzzOptions) -> Result<Self> {
         let zp_encoder = RustZEncoder::new(writer, true)?; // djvu_compat=true to match C++ BSByteStream
-        Ok(Self {
+        let mut encoder = Self {
             zp_encoder,
-            buffer: Vec::with_capacity(block_size + OVERFLOW),
-            block_size,
-        })
+            buffer: Vec::new(),
+            block_size: 0,
+            speed: None,
+            contexts: ContextBank::new(CONTEXTS),
+        };
+        encoder.set_options(options);
+        Ok(encoder)
+    }
+
+    /// Changes the settings for the blocks still to come.
+    fn set_options(&mut self, options: &BzzOptions) {
+        self.block_size = (options.block_size_k * 1024).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+        self.speed = options.speed.map(|speed| speed.min(2));
+        self.buffer
+            .reserve((self.block_size + OVERFLOW).saturating_sub(self.buffer.len()));
+    }
+
+    /// Encodes the last block and the end-of-stream marker, and returns the
+    /// writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.encode_block()?;
+        // Zero-length block, as in C++ BSByteStream::Encode::~Encode()
+        self.encode_raw(24, 0)?;
+        Ok(self.zp_encoder.flush()?)
+    }
+
+    /// Ends the stream as [`BsEncoder::finish`] does, and starts a new one
+    /// on `writer`, keeping the block buffer. Returns the finished writer.
+    pub fn finish_and_reset(&mut self, writer: W) -> Result<W> {
+        self.encode_block()?;
+        self.encode_raw(24, 0)?;
+        let finished = self.zp_encoder.flush()?;
+        self.zp_encoder.reset(writer);
+        self.contexts.reset();
+        Ok(finished)
     }
 
     fn encode_block(&mut self) -> Result<()> {
@@ -110,18 +181,24 @@ impl<W: Write> BsEncoder<W> {
 
         // Determine and encode estimation speed
         // DjVuLibre uses pass-thru coding for these bits: zp.encoder(bit)
-        let fshift = if size < FREQS0 {
-            self.zp_encoder.encode_raw(false)?;
+        let fshift = self.speed.unwrap_or(if size < FREQS0 {
             0
         } else if size < FREQS1 {
-            self.zp_encoder.encode_raw(true)?;
-            self.zp_encoder.encode_raw(false)?;
             1
         } else {
-            self.zp_encoder.encode_raw(true)?;
-            self.zp_encoder.encode_raw(true)?;
             2
-        };
+        });
+        match fshift {
+            0 => self.zp_encoder.encode_raw(false)?,
+            1 => {
+                self.zp_encoder.encode_raw(true)?;
+                self.zp_encoder.encode_raw(false)?;
+            }
+            _ => {
+                self.zp_encoder.encode_raw(true)?;
+                self.zp_encoder.encode_raw(true)?;
+            }
+        }
 
         // Initialize Move-to-Front (MTF) tables
         let mut mtf: Vec<u8> = (0..=255).collect();
@@ -134,7 +211,7 @@ impl<W: Write> BsEncoder<W> {
 
         // Encode data with MTF and ZP
         let mut mtfno = 3; // This should be mutable and track current MTF state
-        let mut contexts: Vec<BitContext> = vec![0; 300]; // Context array as in C++ code
+        let mut contexts = std::mem::replace(&mut self.contexts, ContextBank::new(0));
         for (i, &c) in data.iter().enumerate() {
             let mut ctxid = (CTXIDS - 1) as u8;
             if ctxid as usize > mtfno {
@@ -156,7 +233,7 @@ impl<W: Write> BsEncoder<W> {
             self.zp_encoder
                 .encode(bit, &mut contexts[cx_idx + ctxid as usize])?;
             if bit {
-                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift as u8);
+                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift);
                 continue;
             }
 
@@ -165,7 +242,7 @@ impl<W: Write> BsEncoder<W> {
             self.zp_encoder
                 .encode(bit, &mut contexts[cx_idx + ctxid as usize])?;
             if bit {
-                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift as u8);
+                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift);
                 continue;
             }
 
@@ -173,8 +250,12 @@ impl<W: Write> BsEncoder<W> {
             let bit = mtfno_current < 4;
             self.zp_encoder.encode(bit, &mut contexts[cx_idx])?;
             if bit {
-                self.encode_binary(&mut contexts[cx_idx + 1..], 1, mtfno_current - 2)?;
-                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift as u8);
+                self.encode_binary(
+                    &mut contexts.as_mut_slice()[cx_idx + 1..],
+                    1,
+                    mtfno_current - 2,
+                )?;
+                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift);
                 continue;
             }
 
@@ -182,8 +263,12 @@ impl<W: Write> BsEncoder<W> {
             let bit = mtfno_current < 8;
             self.zp_encoder.encode(bit, &mut contexts[cx_idx])?;
             if bit {
-                self.encode_binary(&mut contexts[cx_idx + 1..], 2, mtfno_current - 4)?;
-                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift as u8);
+                self.encode_binary(
+                    &mut contexts.as_mut_slice()[cx_idx + 1..],
+                    2,
+                    mtfno_current - 4,
+                )?;
+                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift);
                 continue;
             }
 
@@ -191,8 +276,12 @@ impl<W: Write> BsEncoder<W> {
             let bit = mtfno_current < 16;
             self.zp_encoder.encode(bit, &mut contexts[cx_idx])?;
             if bit {
-                self.encode_binary(&mut contexts[cx_idx + 1..], 3, mtfno_current - 8)?;
-                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift as u8);
+                self.encode_binary(
+                    &mut contexts.as_mut_slice()[cx_idx + 1..],
+                    3,
+                    mtfno_current - 8,
+                )?;
+                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift);
                 continue;
             }
 
@@ -200,8 +289,12 @@ impl<W: Write> BsEncoder<W> {
             let bit = mtfno_current < 32;
             self.zp_encoder.encode(bit, &mut contexts[cx_idx])?;
             if bit {
-                self.encode_binary(&mut contexts[cx_idx + 1..], 4, mtfno_current - 16)?;
-                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift as u8);
+                self.encode_binary(
+                    &mut contexts.as_mut_slice()[cx_idx + 1..],
+                    4,
+                    mtfno_current - 16,
+                )?;
+                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift);
                 continue;
             }
 
@@ -209,8 +302,12 @@ impl<W: Write> BsEncoder<W> {
             let bit = mtfno_current < 64;
             self.zp_encoder.encode(bit, &mut contexts[cx_idx])?;
             if bit {
-                self.encode_binary(&mut contexts[cx_idx + 1..], 5, mtfno_current - 32)?;
-                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift as u8);
+                self.encode_binary(
+                    &mut contexts.as_mut_slice()[cx_idx + 1..],
+                    5,
+                    mtfno_current - 32,
+                )?;
+                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift);
                 continue;
             }
 
@@ -218,8 +315,12 @@ impl<W: Write> BsEncoder<W> {
             let bit = mtfno_current < 128;
             self.zp_encoder.encode(bit, &mut contexts[cx_idx])?;
             if bit {
-                self.encode_binary(&mut contexts[cx_idx + 1..], 6, mtfno_current - 64)?;
-                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift as u8);
+                self.encode_binary(
+                    &mut contexts.as_mut_slice()[cx_idx + 1..],
+                    6,
+                    mtfno_current - 64,
+                )?;
+                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift);
                 continue;
             }
 
@@ -227,8 +328,12 @@ impl<W: Write> BsEncoder<W> {
             let bit = mtfno_current < 256;
             self.zp_encoder.encode(bit, &mut contexts[cx_idx])?;
             if bit {
-                self.encode_binary(&mut contexts[cx_idx + 1..], 7, mtfno_current - 128)?;
-                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift as u8);
+                self.encode_binary(
+                    &mut contexts.as_mut_slice()[cx_idx + 1..],
+                    7,
+                    mtfno_current - 128,
+                )?;
+                self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift);
                 continue;
             }
 
@@ -238,9 +343,10 @@ impl<W: Write> BsEncoder<W> {
             }
 
             // Should not be reachable, but keep behavior consistent.
-            self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift as u8);
+            self.rotate_mtf(&mut mtf, &mut rmtf, &mut freq, c, &mut fadd, fshift);
         }
 
+        self.contexts = contexts;
         Ok(())
     }
 
@@ -261,7 +367,7 @@ impl<W: Write> BsEncoder<W> {
     }
 
     /// Encodes a binary value with the specified number of bits using contexts.
-    fn encode_binary(&mut self, ctx: &mut [BitContext], bits: u8, x: usize) -> Result<()> {
+    fn encode_binary(&mut self, ctx: &mut [ZpContext], bits: u8, x: usize) -> Result<()> {
         // Implementation matches C++ exactly: ctx = ctx - 1; ctx[n]
         let mut n = 1u32;
         let m = 1u32 << bits;
@@ -352,17 +458,20 @@ impl<W: Write> Write for BsEncoder<W> {
     fn flush(&mut self) -> std::io::Result<()> {
         self.encode_block()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-        // Note: ZEncoder doesn't have a public flush method, finish() will be called in Drop
         Ok(())
     }
 }
 
 impl<W: Write> Drop for BsEncoder<W> {
     fn drop(&mut self) {
-        let _ = self.flush();
-        // Encode EOF marker (zero-length block) - matches C++ BSByteStream::Encode::~Encode()
-        let _ = self.encode_raw(24, 0);
-        // Note: ZEncoder will be dropped naturally, which calls its Drop impl that flushes
+        // Writing the end-of-stream marker here would pass off a stream cut
+        // short by an error as complete.
+        if !self.buffer.is_empty() || !self.zp_encoder.is_idle() {
+            warn!(
+                target: target::BZZ,
+                "BsEncoder dropped without finish(); its output is incomplete"
+            );
+        }
     }
 }
 
@@ -377,11 +486,254 @@ impl<W: Write> Drop for BsEncoder<W> {
 /// # Returns
 /// A `Result` containing the compressed data as a `Vec<u8>`
 pub fn bzz_compress(data: &[u8], block_size_k: usize) -> Result<Vec<u8>> {
-    let mut compressed_data = Vec::new();
-    {
-        let mut encoder = BsEncoder::new(&mut compressed_data, block_size_k)?;
-        encoder.write_all(data).map_err(|e| DjvuError::Io(e))?;
-        encoder.flush().map_err(|e| DjvuError::Io(e))?;
-    }
+    bzz_compress_with(
+        data,
+        &BzzOptions {
+            block_size_k,
+            ..BzzOptions::default()
+        },
+    )
+}
+
+thread_local! {
+    /// Encoder reused by [`bzz_compress_with`] on this thread, so documents
+    /// with thousands of small compressed chunks don't set up a block buffer
+    /// and contexts for each.
+    static POOLED_ENCODER: Cell<Option<BsEncoder<Vec<u8>>>> = const { Cell::new(None) };
+}
+
+/// Compresses data using the DjVu BZZ compression algorithm with the given
+/// settings. The output is the same as from a fresh [`BsEncoder`].
+pub fn bzz_compress_with(data: &[u8], options: &BzzOptions) -> Result<Vec<u8>> {
+    let mut encoder = match POOLED_ENCODER.take() {
+        Some(mut encoder) => {
+            encoder.set_options(options);
+            encoder
+        }
+        None => BsEncoder::with_options(Vec::new(), options)?,
+    };
+    encoder.write_all(data).map_err(DjvuError::Io)?;
+    // A failed encoder is dropped rather than put back mid-stream.
+    let compressed_data = encoder.finish_and_reset(Vec::new())?;
+    POOLED_ENCODER.set(Some(encoder));
+    trace!(
+        target: target::BZZ,
+        "bzz_compress: {} -> {} bytes (block {}k)",
+        data.len(),
+        compressed_data.len(),
+        options.block_size_k
+    );
     Ok(compressed_data)
 }
+
+/// Decompresses BZZ data, as written by [`bzz_compress`] or DjVuLibre.
+pub fn bzz_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut zp = ZDecoder::new(data)?;
+    let mut contexts = ContextBank::new(CONTEXTS);
+    let mut output = Vec::new();
+    loop {
+        let size = decode_raw(&mut zp, 24)? as usize;
+        if size == 0 {
+            return Ok(output);
+        }
+        if size > MAX_BLOCK_SIZE + 1 {
+            return Err(DjvuError::Stream(format!("BZZ block of {size} bytes")));
+        }
+        decode_block(&mut zp, &mut contexts, size, &mut output)?;
+    }
+}
+
+/// Decodes one block of `size` symbols (its marker included) and appends
+/// the text it holds to `output`.
+fn decode_block(
+    zp: &mut ZDecoder,
+    contexts: &mut ContextBank,
+    size: usize,
+    output: &mut Vec<u8>,
+) -> Result<()> {
+    let mut fshift = 0;
+    if zp.decode_raw()? {
+        fshift += 1;
+        if zp.decode_raw()? {
+            fshift += 1;
+        }
+    }
+
+    // Undo the move-to-front coding, mirroring `encode_transformed`.
+    let mut mtf: Vec<u8> = (0..=255).collect();
+    let mut freq = [0u32; FREQMAX];
+    let mut fadd = 4u32;
+    let mut mtfno = 3;
+    let mut markerpos = None;
+    let mut block = vec![0u8; size];
+    for (i, byte) in block.iter_mut().enumerate() {
+        let ctxid = (CTXIDS - 1).min(mtfno);
+        mtfno = if zp.decode(&mut contexts[ctxid])? {
+            0
+        } else if zp.decode(&mut contexts[CTXIDS + ctxid])? {
+            1
+        } else {
+            // Ranges 2..4, 4..8, ..., 128..256, each behind a flag context
+            // followed by the tree contexts of its low bits.
+            let mut cx = 2 * CTXIDS;
+            let mut found = None;
+            for bits in 1..=7 {
+                if zp.decode(&mut contexts[cx])? {
+                    let low = decode_binary(zp, &mut contexts.as_mut_slice()[cx + 1..], bits)?;
+                    found = Some((1 << bits) + low as usize);
+                    break;
+                }
+                cx += 1 << bits;
+            }
+            match found {
+                Some(mtfno) => mtfno,
+                None => {
+                    markerpos = Some(i);
+                    mtfno = 256;
+                    continue;
+                }
+            }
+        };
+        *byte = mtf[mtfno];
+
+        fadd += fadd >> fshift;
+        if fadd > 0x1000_0000 {
+            fadd >>= 24;
+            for f in freq.iter_mut() {
+                *f >>= 24;
+            }
+        }
+        let mut fc = fadd;
+        if mtfno < FREQMAX {
+            fc += freq[mtfno];
+        }
+        let mut k = mtfno;
+        while k >= FREQMAX {
+            mtf[k] = mtf[k - 1];
+            k -= 1;
+        }
+        while k > 0 && fc >= freq[k - 1] {
+            mtf[k] = mtf[k - 1];
+            freq[k] = freq[k - 1];
+            k -= 1;
+        }
+        mtf[k] = *byte;
+        freq[k] = fc;
+    }
+
+    // Undo the Burrows-Wheeler transform.
+    let markerpos = markerpos
+        .filter(|&m| m > 0 && m < size)
+        .ok_or_else(|| DjvuError::Stream("BZZ block without a valid marker".to_string()))?;
+    let mut count = [0u32; 256];
+    let mut posn = vec![0u32; size];
+    for (i, &c) in block.iter().enumerate() {
+        if i != markerpos {
+            posn[i] = (c as u32) << 24 | (count[c as usize] & 0xff_ffff);
+            count[c as usize] += 1;
+        }
+    }
+    let mut last = 1;
+    for c in count.iter_mut() {
+        (*c, last) = (last, last + *c);
+    }
+    let start = output.len();
+    output.resize(start + size - 1, 0);
+    let mut i = 0;
+    for out in output[start..].iter_mut().rev() {
+        let n = posn[i];
+        let c = (n >> 24) as u8;
+        *out = c;
+        i = (count[c as usize] + (n & 0xff_ffff)) as usize;
+        if i >= size {
+            break;
+        }
+    }
+    if i != markerpos {
+        return Err(DjvuError::Stream("Corrupt BZZ block".to_string()));
+    }
+    Ok(())
+}
+
+/// Reads a `bits`-bit number written by `BsEncoder::encode_raw`.
+fn decode_raw(zp: &mut ZDecoder, bits: u32) -> Result<u32> {
+    let mut n = 1u32;
+    while n < 1 << bits {
+        n = (n << 1) | zp.decode_raw()? as u32;
+    }
+    Ok(n - (1 << bits))
+}
+
+/// Reads a `bits`-bit number written by `BsEncoder::encode_binary`.
+fn decode_binary(zp: &mut ZDecoder, ctx: &mut [ZpContext], bits: u32) -> Result<u32> {
+    let mut n = 1u32;
+    while n < 1 << bits {
+        n = (n << 1) | zp.decode(&mut ctx[n as usize - 1])? as u32;
+    }
+    Ok(n - (1 << bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress_round_trip() {
+        let text: Vec<u8> = (0..4_000u32)
+            .flat_map(|i| format!("word{} ", i % 1013).into_bytes())
+            .collect();
+        // Several 10 KiB blocks, a single byte and nothing at all.
+        for (data, block_k) in [(&text[..], 10), (b"x".as_slice(), 100), (&[][..], 100)] {
+            let compressed = bzz_compress(data, block_k).unwrap();
+            assert_eq!(bzz_decompress(&compressed).unwrap(), data);
+        }
+        assert!(bzz_decompress(&bzz_compress(&text, 10).unwrap()[..40]).is_err());
+    }
+
+    #[test]
+    fn test_pooled_encoder_and_options() {
+        let text: Vec<u8> = (0..1_200u32)
+            .flat_map(|i| format!("line {} ", i % 97).into_bytes())
+            .collect();
+        let fresh = |data: &[u8], options: &BzzOptions| {
+            let mut encoder = BsEncoder::with_options(Vec::new(), options).unwrap();
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        // The pooled encoder carries nothing over from one call to the next.
+        let options = BzzOptions::default();
+        for data in [&text[..], b"short", &text[..500], &text[..]] {
+            assert_eq!(
+                bzz_compress_with(data, &options).unwrap(),
+                fresh(data, &options)
+            );
+        }
+
+        for speed in 0..=2 {
+            let options = BzzOptions {
+                block_size_k: 10,
+                speed: Some(speed),
+            };
+            let compressed = bzz_compress_with(&text, &options).unwrap();
+            assert_eq!(compressed, fresh(&text, &options));
+            assert_eq!(bzz_decompress(&compressed).unwrap(), text);
+        }
+        // Without finish() the pending block and the end-of-stream marker
+        // are not written, rather than passing off a stream as complete.
+        let mut out = Vec::new();
+        let mut encoder = BsEncoder::with_options(&mut out, &options).unwrap();
+        encoder.write_all(&text).unwrap();
+        drop(encoder);
+        assert!(out.is_empty());
+
+        let slow = BzzOptions {
+            speed: Some(2),
+            ..options
+        };
+        assert_ne!(
+            bzz_compress_with(&text, &slow).unwrap(),
+            bzz_compress_with(&text, &options).unwrap()
+        );
+    }
+}
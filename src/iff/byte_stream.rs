@@ -38,11 +38,10 @@ pub trait ByteStream: Read + Write {
 
     fn write_u24(&mut self, value: u32) -> Result<()> {
         if value > 0xFFFFFF {
-            eprintln!(
-                "ERROR: Trying to write u24 value {} which is too large (max={})",
+            return Err(DjvuError::InvalidArg(format!(
+                "Value {} too large for u24 (max={})",
                 value, 0xFFFFFF
-            );
-            return Err(DjvuError::InvalidArg("Value too large for u24".to_string()));
+            )));
         }
         let bytes = [
             ((value >> 16) & 0xFF) as u8,
@@ -80,16 +79,10 @@ pub trait ByteStream: Read + Write {
 
     /// Efficiently write a slice of u24 values in big-endian format using bytemuck
     fn write_u24_slice(&mut self, values: &[u32]) -> Result<()> {
-        for &value in values {
-            if value > 0xFFFFFF {
-                eprintln!(
-                    "ERROR: Trying to write u24 slice value {} which is too large (max={})",
-                    value, 0xFFFFFF
-                );
-                return Err(DjvuError::InvalidArg("Value too large for u24".to_string()));
-            }
-        }
-        let be_values: Vec<BeU24> = values.iter().map(|&v| v.into()).collect();
+        let be_values = values
+            .iter()
+            .map(|&v| BeU24::try_from(v))
+            .collect::<Result<Vec<BeU24>>>()?;
         let bytes: &[u8] = cast_slice(&be_values);
         self.write_all(bytes)?;
         Ok(())
@@ -233,16 +226,21 @@ impl From<BeU32> for u32 {
     }
 }
 
-impl From<u32> for BeU24 {
-    fn from(value: u32) -> Self {
+impl TryFrom<u32> for BeU24 {
+    type Error = DjvuError;
+
+    fn try_from(value: u32) -> Result<Self> {
         if value > 0xFFFFFF {
-            panic!("Value too large for u24");
+            return Err(DjvuError::InvalidArg(format!(
+                "Value {} too large for u24 (max={})",
+                value, 0xFFFFFF
+            )));
         }
-        BeU24([
+        Ok(BeU24([
             ((value >> 16) & 0xFF) as u8,
             ((value >> 8) & 0xFF) as u8,
             (value & 0xFF) as u8,
-        ])
+        ]))
     }
 }
 
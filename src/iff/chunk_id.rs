@@ -0,0 +1,205 @@
+//! Four-character chunk IDs.
+//!
+//! IFF names every chunk with four printable ASCII characters, padded with
+//! trailing spaces. [`ChunkId`] holds one that has been checked, so the
+//! writer cannot emit a malformed header, and its constants spell out every
+//! chunk of the DjVu specification so a typo is a compile error rather than
+//! a broken file.
+
+use crate::{DjvuError, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// A checked four-character chunk ID, such as `BG44` or `FORM`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChunkId([u8; 4]);
+
+#[allow(non_upper_case_globals)]
+impl ChunkId {
+    // Composite chunks, which hold a type ID and other chunks.
+    pub const FORM: Self = Self::new(*b"FORM");
+    pub const LIST: Self = Self::new(*b"LIST");
+    pub const PROP: Self = Self::new(*b"PROP");
+    pub const CAT: Self = Self::new(*b"CAT ");
+
+    // Types of `FORM` chunks.
+    /// A single page.
+    pub const DJVU: Self = Self::new(*b"DJVU");
+    /// A multi-page document.
+    pub const DJVM: Self = Self::new(*b"DJVM");
+    /// A file included by pages: shared dictionaries and annotations.
+    pub const DJVI: Self = Self::new(*b"DJVI");
+    /// Thumbnails.
+    pub const THUM: Self = Self::new(*b"THUM");
+    /// A color IW44 image, outside a page.
+    pub const PM44: Self = Self::new(*b"PM44");
+    /// A grayscale IW44 image, outside a page.
+    pub const BM44: Self = Self::new(*b"BM44");
+
+    // Document chunks.
+    /// Directory of a multi-page document.
+    pub const DIRM: Self = Self::new(*b"DIRM");
+    /// The obsolete DjVu 2 directory.
+    pub const DIR0: Self = Self::new(*b"DIR0");
+    /// Bookmarks.
+    pub const NAVM: Self = Self::new(*b"NAVM");
+    /// The obsolete navigation directory of `DJVI` files.
+    pub const NDIR: Self = Self::new(*b"NDIR");
+
+    // Page chunks.
+    /// Page size, resolution, gamma and rotation.
+    pub const INFO: Self = Self::new(*b"INFO");
+    /// ID of an included `DJVI` file.
+    pub const INCL: Self = Self::new(*b"INCL");
+    /// JB2 mask.
+    pub const Sjbz: Self = Self::new(*b"Sjbz");
+    /// Shared JB2 dictionary.
+    pub const Djbz: Self = Self::new(*b"Djbz");
+    /// CCITT G4 mask.
+    pub const Smmr: Self = Self::new(*b"Smmr");
+    /// Foreground colors of the JB2 shapes.
+    pub const FGbz: Self = Self::new(*b"FGbz");
+    /// IW44 foreground colors.
+    pub const FG44: Self = Self::new(*b"FG44");
+    /// JPEG foreground colors.
+    pub const FGjp: Self = Self::new(*b"FGjp");
+    /// JPEG 2000 foreground colors.
+    pub const FG2k: Self = Self::new(*b"FG2k");
+    /// IW44 background.
+    pub const BG44: Self = Self::new(*b"BG44");
+    /// JPEG background.
+    pub const BGjp: Self = Self::new(*b"BGjp");
+    /// JPEG 2000 background.
+    pub const BG2k: Self = Self::new(*b"BG2k");
+    /// IW44 thumbnail.
+    pub const TH44: Self = Self::new(*b"TH44");
+    /// Annotations.
+    pub const ANTa: Self = Self::new(*b"ANTa");
+    /// BZZ-compressed annotations.
+    pub const ANTz: Self = Self::new(*b"ANTz");
+    /// Hidden text.
+    pub const TXTa: Self = Self::new(*b"TXTa");
+    /// BZZ-compressed hidden text.
+    pub const TXTz: Self = Self::new(*b"TXTz");
+    /// Free-form metadata; this crate keeps page checksums in it.
+    pub const CIDa: Self = Self::new(*b"CIDa");
+
+    /// The ID `bytes`, for constants; panics (at compile time, in a
+    /// constant) if it is not a valid ID.
+    pub const fn new(bytes: [u8; 4]) -> Self {
+        assert!(is_valid(&bytes), "invalid chunk ID");
+        Self(bytes)
+    }
+
+    /// The ID `bytes`, if valid: printable ASCII, with spaces only at the
+    /// end.
+    pub fn from_bytes(bytes: [u8; 4]) -> Result<Self> {
+        if is_valid(&bytes) {
+            Ok(Self(bytes))
+        } else {
+            Err(DjvuError::InvalidArg(format!(
+                "Invalid chunk ID {:?}",
+                String::from_utf8_lossy(&bytes)
+            )))
+        }
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; 4] {
+        &self.0
+    }
+
+    /// The ID as text, trailing spaces included.
+    pub fn as_str(&self) -> &str {
+        // Checked to be ASCII on construction.
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+
+    /// Whether chunks with this ID hold other chunks.
+    pub const fn is_composite(&self) -> bool {
+        matches!(&self.0, b"FORM" | b"LIST" | b"PROP" | b"CAT ")
+    }
+}
+
+const fn is_valid(bytes: &[u8; 4]) -> bool {
+    let mut i = 0;
+    let mut padding = false;
+    while i < 4 {
+        match bytes[i] {
+            b' ' if i > 0 => padding = true,
+            0x21..=0x7e if !padding => {}
+            _ => return false,
+        }
+        i += 1;
+    }
+    true
+}
+
+impl TryFrom<[u8; 4]> for ChunkId {
+    type Error = DjvuError;
+
+    fn try_from(bytes: [u8; 4]) -> Result<Self> {
+        Self::from_bytes(bytes)
+    }
+}
+
+impl FromStr for ChunkId {
+    type Err = DjvuError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = s
+            .as_bytes()
+            .try_into()
+            .map_err(|_| DjvuError::InvalidArg(format!("Chunk ID must be 4 characters: {s:?}")))?;
+        Self::from_bytes(bytes)
+    }
+}
+
+impl From<ChunkId> for [u8; 4] {
+    fn from(id: ChunkId) -> Self {
+        id.0
+    }
+}
+
+impl PartialEq<[u8; 4]> for ChunkId {
+    fn eq(&self, other: &[u8; 4]) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<ChunkId> for [u8; 4] {
+    fn eq(&self, other: &ChunkId) -> bool {
+        *self == other.0
+    }
+}
+
+impl fmt::Display for ChunkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for ChunkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChunkId({:?})", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_id_validation() {
+        assert_eq!("BG44".parse::<ChunkId>().unwrap(), ChunkId::BG44);
+        assert_eq!(ChunkId::from_bytes(*b"CAT ").unwrap(), ChunkId::CAT);
+        assert_eq!(ChunkId::TXTz, *b"TXTz");
+        assert_eq!(ChunkId::DJVU.to_string(), "DJVU");
+        assert!(ChunkId::FORM.is_composite() && !ChunkId::INFO.is_composite());
+
+        for bad in ["BG4", "BG444", " BG4", "B G4", "BG\u{1}4"] {
+            assert!(bad.parse::<ChunkId>().is_err(), "{bad:?}");
+        }
+        assert!(ChunkId::from_bytes([b'B', b'G', 0, 0]).is_err());
+        assert!(ChunkId::from_bytes(*b"\xffG44").is_err());
+    }
+}
@@ -1,14 +1,15 @@
 // src/chunk_tree.rs
 
 use crate::doc::djvu_dir::FileType as DirFileType;
+use crate::iff::chunk_id::ChunkId;
 
 /// Maps a DjVu file type to its canonical chunk ID.
-pub fn file_type_to_id(file_type: DirFileType) -> [u8; 4] {
+pub fn file_type_to_id(file_type: DirFileType) -> ChunkId {
     match file_type {
-        DirFileType::Page => *b"FORM", // FORM:DJVU (handled with secondary_id)
-        DirFileType::Include => *b"INCL", // Included file
-        DirFileType::Thumbnails => *b"THUM", // Thumbnails chunk
-        DirFileType::SharedAnno => *b"ANTa", // Shared annotation chunk
+        DirFileType::Page => ChunkId::FORM, // FORM:DJVU (handled with secondary_id)
+        DirFileType::Include => ChunkId::INCL, // Included file
+        DirFileType::Thumbnails => ChunkId::THUM, // Thumbnails chunk
+        DirFileType::SharedAnno => ChunkId::ANTa, // Shared annotation chunk
     }
 }
 
@@ -26,8 +27,8 @@ pub fn align_even<W: Write + Seek>(w: &mut W) -> std::io::Result<()> {
 // This module replaces the C++ `GIFFManager` and `GIFFChunk` classes. It provides
 // a tree-like data structure, `IffChunk`, that can be loaded from a stream,
 // manipulated in memory, and saved back to a stream.
-use crate::iff::iff::IffReaderExt;
 use crate::iff::iff::IffWriter;
+use crate::iff::parse_mode::{ParseLog, ParseMode};
 use std::io::{Read, Seek, SeekFrom, Write};
 
 use crate::utils::error::{DjvuError, Result};
@@ -40,7 +41,7 @@ pub enum ChunkPayload {
     /// A list of child chunks for composite chunks (e.g., "FORM", "LIST").
     Composite {
         /// The 4-character secondary identifier (e.g., "DJVU" in "FORM:DJVU").
-        secondary_id: [u8; 4],
+        secondary_id: ChunkId,
         /// The vector of child chunks.
         children: Vec<IffChunk>,
     },
@@ -50,7 +51,7 @@ pub enum ChunkPayload {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct IffChunk {
     /// The 4-character primary identifier (e.g., "FORM", "PM44").
-    pub id: [u8; 4],
+    pub id: ChunkId,
     /// The chunk's data, which can be raw bytes or a collection of sub-chunks.
     pub payload: ChunkPayload,
 }
@@ -58,7 +59,7 @@ pub struct IffChunk {
 impl IffChunk {
     /// Creates a new raw data chunk.
     #[inline]
-    pub fn new_raw(id: [u8; 4], data: Vec<u8>) -> Self {
+    pub fn new_raw(id: ChunkId, data: Vec<u8>) -> Self {
         IffChunk {
             id,
             payload: ChunkPayload::Raw(data),
@@ -67,7 +68,7 @@ impl IffChunk {
 
     /// Creates a new, empty composite chunk.
     #[inline]
-    pub fn new_composite(id: [u8; 4], secondary_id: [u8; 4]) -> Self {
+    pub fn new_composite(id: ChunkId, secondary_id: ChunkId) -> Self {
         IffChunk {
             id,
             payload: ChunkPayload::Composite {
@@ -86,27 +87,121 @@ impl IffChunk {
     /// Returns the chunk's primary ID as a string slice.
     #[inline]
     pub fn id_as_str(&self) -> &str {
-        std::str::from_utf8(&self.id).unwrap_or("????")
+        self.id.as_str()
+    }
+
+    /// The payload of a raw chunk.
+    pub fn data(&self) -> Option<&[u8]> {
+        match &self.payload {
+            ChunkPayload::Raw(data) => Some(data),
+            ChunkPayload::Composite { .. } => None,
+        }
+    }
+
+    /// The secondary ID of a composite chunk.
+    pub fn secondary_id(&self) -> Option<ChunkId> {
+        match &self.payload {
+            ChunkPayload::Composite { secondary_id, .. } => Some(*secondary_id),
+            ChunkPayload::Raw(_) => None,
+        }
+    }
+
+    /// The children of a composite chunk; empty for a raw one.
+    pub fn children(&self) -> &[IffChunk] {
+        match &self.payload {
+            ChunkPayload::Composite { children, .. } => children,
+            ChunkPayload::Raw(_) => &[],
+        }
+    }
+
+    /// The children of a composite chunk, for editing in place.
+    pub fn children_mut(&mut self) -> Option<&mut Vec<IffChunk>> {
+        match &mut self.payload {
+            ChunkPayload::Composite { children, .. } => Some(children),
+            ChunkPayload::Raw(_) => None,
+        }
+    }
+
+    /// The first child with ID `id`.
+    pub fn find(&self, id: ChunkId) -> Option<&IffChunk> {
+        self.children().iter().find(|c| c.id == id)
+    }
+
+    /// The first child with ID `id`, for editing in place.
+    pub fn find_mut(&mut self, id: ChunkId) -> Option<&mut IffChunk> {
+        self.children_mut()?.iter_mut().find(|c| c.id == id)
+    }
+
+    /// Inserts `chunk` as child number `at`.
+    pub fn insert_child(&mut self, at: usize, chunk: IffChunk) -> Result<()> {
+        let id = self.id;
+        let children = self.children_mut().ok_or_else(|| not_composite(id))?;
+        if at > children.len() {
+            return Err(DjvuError::InvalidArg(format!(
+                "Chunk position {at} out of range 0..={}",
+                children.len()
+            )));
+        }
+        children.insert(at, chunk);
+        Ok(())
+    }
+
+    /// Replaces the first child with the ID of `chunk`, returning it, or
+    /// appends `chunk` if there is none.
+    pub fn replace_child(&mut self, chunk: IffChunk) -> Result<Option<IffChunk>> {
+        let id = self.id;
+        let children = self.children_mut().ok_or_else(|| not_composite(id))?;
+        match children.iter_mut().find(|c| c.id == chunk.id) {
+            Some(old) => Ok(Some(std::mem::replace(old, chunk))),
+            None => {
+                children.push(chunk);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes every child with ID `id`, returning how many there were.
+    pub fn remove_children(&mut self, id: ChunkId) -> usize {
+        let Some(children) = self.children_mut() else {
+            return 0;
+        };
+        let before = children.len();
+        children.retain(|c| c.id != id);
+        before - children.len()
+    }
+
+    /// Calls `f` on this chunk and then, depth first, on every chunk below
+    /// it. Children added or removed by `f` are walked as they are after
+    /// the call.
+    pub fn walk_mut(&mut self, f: &mut impl FnMut(&mut IffChunk)) {
+        f(self);
+        if let Some(children) = self.children_mut() {
+            for child in children {
+                child.walk_mut(f);
+            }
+        }
+    }
+
+    /// Serializes this chunk, with its header, padding and recomputed
+    /// sizes, but without the `AT&T` prefix of a file.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut out = std::io::Cursor::new(Vec::new());
+        self.write(&mut IffWriter::new(&mut out))?;
+        Ok(out.into_inner())
     }
 
     /// Recursively writes this chunk and its children to the `IffWriter`.
     fn write(&self, writer: &mut IffWriter<'_>) -> Result<()> {
         match &self.payload {
             ChunkPayload::Raw(data) => {
-                let id_str = std::str::from_utf8(&self.id).unwrap_or("????");
-                writer.put_chunk(id_str)?;
+                writer.put_chunk(self.id)?;
                 writer.write_all(data)?;
             }
             ChunkPayload::Composite {
                 secondary_id,
                 children,
             } => {
-                let id_str = std::str::from_utf8(&self.id).unwrap_or("????");
-                let secondary_str = std::str::from_utf8(secondary_id)
-                    .unwrap_or("????")
-                    .trim_end_matches('\0');
-                let full_id = format!("{}:{}", id_str, secondary_str);
-                writer.put_chunk(&full_id)?;
+                writer.put_composite(self.id, *secondary_id)?;
                 for child in children {
                     child.write(writer)?;
                 }
@@ -117,6 +212,110 @@ impl IffChunk {
     }
 }
 
+fn not_composite(id: ChunkId) -> DjvuError {
+    DjvuError::InvalidOperation(format!("{id} is not a composite chunk"))
+}
+
+/// Reads the chunk at `data[pos..]`, found at offset `base + pos` of the
+/// input, and returns it with the position after it and its padding.
+/// `None` when a permissive parse gives up on the rest of `data`.
+fn read_chunk(
+    data: &[u8],
+    pos: usize,
+    base: usize,
+    log: &mut ParseLog,
+) -> Result<Option<(IffChunk, usize)>> {
+    let at = base + pos;
+    let Some(header) = data.get(pos..pos + 8) else {
+        let stray = data.len() - pos;
+        log.violation(at, format!("{stray} stray bytes after the last chunk"))?;
+        return Ok(None);
+    };
+    let Some(id) = chunk_id_at(header, 0) else {
+        log.violation(
+            at,
+            format!(
+                "Malformed chunk ID {:?}",
+                String::from_utf8_lossy(&header[..4])
+            ),
+        )?;
+        return Ok(None);
+    };
+    let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if id.is_composite() && size < 4 {
+        log.violation(
+            at,
+            format!("Composite chunk {id} declares size {size}, too small for its type ID"),
+        )?;
+        return Ok(None);
+    }
+
+    let start = pos + 8;
+    let available = data.len() - start;
+    let len = if size > available {
+        log.violation(
+            at,
+            format!("Chunk {id} truncated: {available} of {size} bytes"),
+        )?;
+        available
+    } else {
+        size
+    };
+    let body = &data[start..start + len];
+    let chunk = if id.is_composite() {
+        let Some(secondary_id) = chunk_id_at(body, 0) else {
+            log.violation(at, format!("Composite chunk {id} has no readable type ID"))?;
+            return Ok(None);
+        };
+        IffChunk {
+            id,
+            payload: ChunkPayload::Composite {
+                secondary_id,
+                children: read_children(&body[4..], base + start + 4, log)?,
+            },
+        }
+    } else {
+        IffChunk::new_raw(id, body.to_vec())
+    };
+
+    // Chunks are padded to an even size. Some writers leave the pad byte
+    // out, which shows as a chunk header one byte early.
+    let mut end = start + len;
+    if !len.is_multiple_of(2) && end < data.len() {
+        let missing_pad = log.mode() == ParseMode::Permissive
+            && data[end] != 0
+            && chunk_id_at(data, end).is_some()
+            && chunk_id_at(data, end + 1).is_none();
+        if missing_pad {
+            log.violation(base + end, format!("Pad byte missing after chunk {id}"))?;
+        } else {
+            end += 1;
+        }
+    }
+    Ok(Some((chunk, end)))
+}
+
+/// Reads the chunks filling `data`, the body of a composite chunk found at
+/// offset `base` of the input.
+fn read_children(data: &[u8], base: usize, log: &mut ParseLog) -> Result<Vec<IffChunk>> {
+    let mut children = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let Some((chunk, end)) = read_chunk(data, pos, base, log)? else {
+            break;
+        };
+        children.push(chunk);
+        pos = end;
+    }
+    Ok(children)
+}
+
+/// The valid chunk ID at `data[pos..]`, if there is one.
+fn chunk_id_at(data: &[u8], pos: usize) -> Option<ChunkId> {
+    let bytes: [u8; 4] = data.get(pos..pos + 4)?.try_into().ok()?;
+    ChunkId::from_bytes(bytes).ok()
+}
+
 /// Represents an entire IFF document as a tree of chunks.
 /// This is the main entry point for creating, loading, and saving IFF files.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -134,68 +333,64 @@ impl IffDocument {
 
     /// Parses an entire IFF stream from a reader into an `IffDocument`.
     pub fn from_reader<R: Read + Seek>(mut reader: R) -> Result<Self> {
-        use crate::iff::iff::IffReaderExt;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Self::parse(&data, 0, &mut ParseLog::default())
+    }
 
-        // Read the root chunk header
-        let root_chunk_header = reader.next_chunk()?.ok_or_else(|| {
-            DjvuError::Stream("Cannot create document from empty stream.".to_string())
-        })?;
+    /// Parses an IFF file held in memory, with or without its `AT&T` prefix.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::from_bytes_with(data, &mut ParseLog::default())
+    }
 
-        if !root_chunk_header.is_composite {
+    /// Like [`Self::from_bytes`], in the mode of `log`.
+    ///
+    /// A permissive parse keeps the bytes that are there of chunks cut
+    /// short, steps over missing pad bytes, and drops stray bytes after the
+    /// last chunk of a composite, noting each in `log`.
+    pub fn from_bytes_with(data: &[u8], log: &mut ParseLog) -> Result<Self> {
+        match data.strip_prefix(b"AT&T") {
+            Some(rest) => Self::parse(rest, 4, log),
+            None => Self::parse(data, 0, log),
+        }
+    }
+
+    /// Parses the root chunk at the start of `data`, which is found at
+    /// offset `base` of the input.
+    fn parse(data: &[u8], base: usize, log: &mut ParseLog) -> Result<Self> {
+        if data.is_empty() {
             return Err(DjvuError::Stream(
-                "Root chunk of a document must be a composite type (e.g., FORM).".to_string(),
+                "Cannot create document from empty stream.".to_string(),
             ));
         }
-
-        // Get the data of the root chunk to parse its children
-        let root_data = reader.get_chunk_data(&root_chunk_header)?;
-        let mut root_data_reader = std::io::Cursor::new(root_data);
-
-        // Read the children recursively from the root's data
-        let children = Self::read_chunk_tree(&mut root_data_reader)?;
-
-        let root = IffChunk {
-            id: root_chunk_header.id,
-            payload: ChunkPayload::Composite {
-                secondary_id: root_chunk_header.secondary_id,
-                children,
-            },
+        let root = match read_chunk(data, 0, base, log)? {
+            Some((root, _)) if root.is_composite() => root,
+            Some(_) => {
+                return Err(DjvuError::Stream(
+                    "Root chunk of a document must be a composite type (e.g., FORM).".to_string(),
+                ));
+            }
+            None => {
+                return Err(DjvuError::ValidationError(
+                    "No readable root chunk".to_string(),
+                ));
+            }
         };
-
         Ok(IffDocument { root })
     }
 
-    /// A recursive helper to read a tree of chunks from a seekable reader.
-    fn read_chunk_tree<R: Read + Seek>(mut reader: R) -> Result<Vec<IffChunk>> {
-        let mut children = Vec::new();
-
-        while let Some(chunk_header) = reader.next_chunk()? {
-            let chunk = if chunk_header.is_composite {
-                // For composite chunks, read their data and recurse
-                let chunk_data = reader.get_chunk_data(&chunk_header)?;
-                let mut chunk_data_reader = std::io::Cursor::new(chunk_data);
-                let sub_children = Self::read_chunk_tree(&mut chunk_data_reader)?;
-                IffChunk {
-                    id: chunk_header.id,
-                    payload: ChunkPayload::Composite {
-                        secondary_id: chunk_header.secondary_id,
-                        children: sub_children,
-                    },
-                }
-            } else {
-                // For raw chunks, just read their data
-                let data = reader.get_chunk_data(&chunk_header)?;
-                IffChunk::new_raw(chunk_header.id, data)
-            };
-            children.push(chunk);
-        }
-
-        Ok(children)
+    /// Serializes the document, `AT&T` prefix included.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.write(&mut out)?;
+        Ok(out)
     }
 
     /// Writes the entire IFF document to the given writer.
-    pub fn write<W: Write + Seek>(&self, writer: W) -> Result<()> {
-        let mut iff_writer = IffWriter::new(writer);
+    ///
+    /// The writer does not need to seek; chunk sizes are resolved in memory.
+    pub fn write<W: Write>(&self, writer: W) -> Result<()> {
+        let mut iff_writer = IffWriter::buffered(writer);
         iff_writer.write_magic_bytes()?;
         self.root.write(&mut iff_writer)?;
         Ok(())
@@ -227,12 +422,12 @@ impl IffDocument {
 
         // Write FORM:DJVM root chunk header (reserve size)
         let form_start = iff_writer.stream_position()?;
-        iff_writer.put_chunk("FORM:DJVM")?;
+        iff_writer.put_composite(ChunkId::FORM, ChunkId::DJVM)?;
 
         // --- DIRM chunk ---
         let dirm_offset = iff_writer.stream_position()?;
         // Write DIRM header and dummy payload (size to be patched)
-        iff_writer.put_chunk("DIRM")?;
+        iff_writer.put_chunk(ChunkId::DIRM)?;
         let dirm_payload_offset = iff_writer.stream_position()?;
         // Encode directory with dummy offsets to reserve space
         let mut dummy_dir_stream = crate::iff::byte_stream::MemoryStream::new();
@@ -247,20 +442,16 @@ impl IffDocument {
         let files_list = dir_model.get_files_list();
         for file in files_list {
             let file_id = &file.id;
-            #[allow(unused_assignments)]
-            let mut chunk_id_buf = [0u8; 4];
-            let chunk_id_str = if file.file_type == DirFileType::Page {
-                "FORM:DJVU"
-            } else {
-                chunk_id_buf = file_type_to_id(file.file_type);
-                std::str::from_utf8(&chunk_id_buf).unwrap_or("????")
-            };
 
             let payload = data_map.get(file_id).ok_or_else(|| {
                 DjvuError::Stream(format!("Missing data for file_id: {}", file_id))
             })?;
             let chunk_start = iff_writer.stream_position()?;
-            iff_writer.put_chunk(chunk_id_str)?;
+            if file.file_type == DirFileType::Page {
+                iff_writer.put_composite(ChunkId::FORM, ChunkId::DJVU)?;
+            } else {
+                iff_writer.put_chunk(file_type_to_id(file.file_type))?;
+            }
             iff_writer.write_all(&payload.to_vec()?)?;
             iff_writer.close_chunk()?;
             let chunk_end = iff_writer.stream_position()?; // Position after padding
@@ -297,3 +488,118 @@ impl IffDocument {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_and_rewrite_tree() -> Result<()> {
+        let mut page = IffChunk::new_composite(ChunkId::FORM, ChunkId::DJVU);
+        page.insert_child(0, IffChunk::new_raw(ChunkId::INFO, vec![0; 10]))?;
+        page.insert_child(1, IffChunk::new_raw(ChunkId::ANTa, b"(mode)".to_vec()))?;
+        page.insert_child(1, IffChunk::new_raw(ChunkId::INCL, b"a.iff".to_vec()))?;
+        assert!(
+            page.insert_child(9, IffChunk::new_raw(ChunkId::TXTa, vec![]))
+                .is_err()
+        );
+        let mut raw = IffChunk::new_raw(ChunkId::TXTa, vec![]);
+        assert!(
+            raw.insert_child(0, IffChunk::new_raw(ChunkId::INFO, vec![]))
+                .is_err()
+        );
+
+        let mut root = IffChunk::new_composite(ChunkId::FORM, ChunkId::DJVM);
+        root.insert_child(0, page)?;
+        let mut document = IffDocument::new(root);
+        let bytes = document.to_bytes()?;
+        assert_eq!(IffDocument::from_bytes(&bytes)?, document);
+
+        // Rename includes and strip annotations all through the tree.
+        let mut stripped = 0;
+        document.root.walk_mut(&mut |chunk| {
+            stripped += chunk.remove_children(ChunkId::ANTa);
+            if chunk.id == ChunkId::INCL {
+                chunk.payload = ChunkPayload::Raw(b"shared.iff".to_vec());
+            }
+        });
+        assert_eq!(stripped, 1);
+        let page = document.root.find_mut(ChunkId::FORM).unwrap();
+        let old = page.replace_child(IffChunk::new_raw(ChunkId::INFO, vec![1; 10]))?;
+        assert_eq!(old.unwrap().data(), Some(&[0u8; 10][..]));
+        assert!(
+            page.replace_child(IffChunk::new_raw(ChunkId::TXTa, vec![7]))?
+                .is_none()
+        );
+
+        let bytes = document.to_bytes()?;
+        let reread = IffDocument::from_bytes(&bytes)?;
+        assert_eq!(reread, document);
+        let page = reread.root.find(ChunkId::FORM).unwrap();
+        let ids: Vec<ChunkId> = page.children().iter().map(|c| c.id).collect();
+        assert_eq!(ids, [ChunkId::INFO, ChunkId::INCL, ChunkId::TXTa]);
+        assert_eq!(
+            page.find(ChunkId::INCL).unwrap().data(),
+            Some(&b"shared.iff"[..])
+        );
+        // "DJVU", INFO (8 + 10), INCL (8 + 10), TXTa (8 + 1) and its pad byte
+        let form_size = u32::from_be_bytes(bytes[20..24].try_into().unwrap());
+        assert_eq!(form_size, 4 + 18 + 18 + 10);
+        assert_eq!(bytes.len() % 2, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_modes() -> Result<()> {
+        let permissive = || ParseLog::new(ParseMode::Permissive);
+        let mut page = IffChunk::new_composite(ChunkId::FORM, ChunkId::DJVU);
+        page.insert_child(0, IffChunk::new_raw(ChunkId::INCL, b"a.iff".to_vec()))?;
+        page.insert_child(1, IffChunk::new_raw(ChunkId::TXTa, b"text".to_vec()))?;
+        let bytes = IffDocument::new(page.clone()).to_bytes()?;
+
+        // A download cut short keeps what arrived of the last chunk.
+        let cut = &bytes[..bytes.len() - 1];
+        assert!(matches!(
+            IffDocument::from_bytes(cut),
+            Err(DjvuError::ValidationError(_))
+        ));
+        let mut log = permissive();
+        let document = IffDocument::from_bytes_with(cut, &mut log)?;
+        assert_eq!(
+            document.root.find(ChunkId::TXTa).unwrap().data(),
+            Some(&b"tex"[..])
+        );
+        let messages: Vec<&str> = log.warnings().iter().map(|w| w.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "Chunk FORM truncated: 29 of 30 bytes",
+                "Chunk TXTa truncated: 3 of 4 bytes"
+            ]
+        );
+        assert_eq!(log.warnings()[1].offset, 4 + 12 + 14);
+
+        // A writer that left out the pad byte after the odd-sized INCL.
+        let mut unpadded = bytes.clone();
+        unpadded.remove(4 + 12 + 13);
+        unpadded[7 + 4] -= 1;
+        assert!(IffDocument::from_bytes(&unpadded).is_err());
+        let mut log = permissive();
+        let document = IffDocument::from_bytes_with(&unpadded, &mut log)?;
+        assert_eq!(document.root, page);
+        assert_eq!(
+            log.warnings()[0].message,
+            "Pad byte missing after chunk INCL"
+        );
+
+        // Stray bytes after the last chunk are dropped.
+        let mut stray = bytes.clone();
+        stray.extend(b"xy");
+        stray[7 + 4] += 2;
+        assert!(IffDocument::from_bytes(&stray).is_err());
+        let mut log = permissive();
+        assert_eq!(IffDocument::from_bytes_with(&stray, &mut log)?.root, page);
+        assert_eq!(log.warnings().len(), 1);
+        Ok(())
+    }
+}
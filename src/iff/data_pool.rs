@@ -11,7 +11,7 @@ use bytemuck::{Pod, Zeroable};
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 
 /// A trait representing a source of byte data that can be read and sought.
 ///
@@ -207,7 +207,7 @@ impl DataPool {
     where
         F: FnOnce(&mut dyn DataSource) -> R,
     {
-        let mut guard = self.source.lock().expect("DataPool mutex poisoned");
+        let mut guard = self.source.lock().unwrap_or_else(PoisonError::into_inner);
         f(&mut *guard)
     }
 
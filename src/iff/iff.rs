@@ -4,8 +4,11 @@
 //!
 //! This module provides:
 //! - `IffReaderExt`: A trait for parsing IFF chunks from any source that implements `Read` and `Seek`.
-//! - `IffWriter`: A struct for creating IFF files on any destination that implements `Write` and `Seek`.
+//! - `IffWriter`: A struct for creating IFF files on any destination that implements `Write` and `Seek`,
+//!   or on a plain `Write` (sockets, pipes) via [`IffWriter::buffered`], or
+//!   [`IffWriter::streaming`] when every chunk size is known up front.
 
+use crate::iff::chunk_id::ChunkId;
 use crate::utils::error::{DjvuError, Result};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Seek, SeekFrom, Write};
@@ -13,28 +16,38 @@ use std::io::{Read, Seek, SeekFrom, Write};
 /// Represents the header of an IFF chunk.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Chunk {
-    /// The 4-character primary identifier (e.g., "FORM", "PM44").
-    pub id: [u8; 4],
-    /// The 4-character secondary identifier for composite chunks (e.g., "DJVU" in "FORM:DJVU").
-    /// For simple chunks, this is typically all spaces or nulls.
-    pub secondary_id: [u8; 4],
+    /// The primary identifier (e.g., "FORM", "PM44").
+    pub id: ChunkId,
+    /// The type of a composite chunk (e.g., "DJVU" in "FORM:DJVU"); `None`
+    /// for simple chunks.
+    pub secondary_id: Option<ChunkId>,
     /// The size of the chunk's data payload in bytes.
     pub size: u32,
-    /// Indicates if the chunk is a composite type like 'FORM' or 'LIST'.
-    pub is_composite: bool,
 }
 
 impl Chunk {
+    /// Indicates if the chunk is a composite type like 'FORM' or 'LIST'.
+    #[inline]
+    pub fn is_composite(&self) -> bool {
+        self.secondary_id.is_some()
+    }
+
     /// Returns the full chunk ID as a string, e.g., "FORM:DJVU".
     #[inline]
     pub fn full_id(&self) -> String {
-        let primary = String::from_utf8_lossy(&self.id);
-        if self.is_composite {
-            let secondary = String::from_utf8_lossy(&self.secondary_id);
-            format!("{}:{}", primary, secondary.trim_end())
-        } else {
-            primary.trim_end().to_string()
-        }
+        full_id(self.id, self.secondary_id)
+    }
+}
+
+/// `id`, or `id:secondary_id` for a composite chunk, without padding.
+fn full_id(id: ChunkId, secondary_id: Option<ChunkId>) -> String {
+    match secondary_id {
+        Some(secondary) => format!(
+            "{}:{}",
+            id.as_str().trim_end(),
+            secondary.as_str().trim_end()
+        ),
+        None => id.as_str().trim_end().to_string(),
     }
 }
 
@@ -57,22 +70,28 @@ pub trait IffReaderExt: Read + Seek {
             Err(e) => return Err(e.into()),
         }
 
+        let id = read_id(id)?;
         let size = self.read_u32::<BigEndian>()?;
-        let is_composite = matches!(&id, b"FORM" | b"LIST" | b"PROP" | b"CAT ");
+        let is_composite = id.is_composite();
+
+        if is_composite && size < 4 {
+            return Err(DjvuError::ValidationError(format!(
+                "Composite chunk {id} declares size {size}, too small for its type ID"
+            )));
+        }
 
         let secondary_id = if is_composite {
             let mut sid = [0u8; 4];
             self.read_exact(&mut sid)?;
-            sid
+            Some(read_id(sid)?)
         } else {
-            [b' '; 4]
+            None
         };
 
         Ok(Some(Chunk {
             id,
             secondary_id,
             size: if is_composite { size - 4 } else { size },
-            is_composite,
         }))
     }
 
@@ -82,30 +101,159 @@ pub trait IffReaderExt: Read + Seek {
     /// and returns them in a `Vec<u8>`. It also handles the IFF padding byte
     /// by seeking past it if necessary.
     fn get_chunk_data(&mut self, chunk: &Chunk) -> Result<Vec<u8>> {
-        let mut data = vec![0; chunk.size as usize];
-        self.read_exact(&mut data)?;
+        let mut data = Vec::new();
+        self.copy_chunk_data(chunk, &mut data)?;
+        Ok(data)
+    }
+
+    /// Streams the data payload of a given chunk into `writer`.
+    ///
+    /// Like [`get_chunk_data`](Self::get_chunk_data), but without holding the
+    /// payload in memory, and without trusting the declared size for an
+    /// allocation. Fails if the stream ends before `chunk.size` bytes.
+    fn copy_chunk_data<W: Write + ?Sized>(&mut self, chunk: &Chunk, writer: &mut W) -> Result<()> {
+        let mut payload = Read::take(&mut *self, chunk.size as u64);
+        let copied = std::io::copy(&mut payload, writer)?;
+        if copied < chunk.size as u64 {
+            return Err(DjvuError::ValidationError(format!(
+                "Chunk {} truncated: {copied} of {} bytes",
+                chunk.full_id(),
+                chunk.size
+            )));
+        }
 
         // IFF chunks are padded to an even number of bytes.
         if chunk.size % 2 != 0 {
             self.seek(SeekFrom::Current(1))?;
         }
-
-        Ok(data)
+        Ok(())
     }
 }
 
 // Blanket implementation for any type that is Read + Seek.
 impl<T: Read + Seek> IffReaderExt for T {}
 
+/// Checks an ID read from a stream.
+fn read_id(bytes: [u8; 4]) -> Result<ChunkId> {
+    ChunkId::from_bytes(bytes).map_err(|_| {
+        DjvuError::ValidationError(format!(
+            "Malformed chunk ID {:?}",
+            String::from_utf8_lossy(&bytes)
+        ))
+    })
+}
+
 /// A writer for creating IFF-structured data on a byte stream.
 /// The underlying writer must also implement `Seek` to allow for patching chunk sizes.
 pub trait WriteSeek: Write + Seek {}
 impl<T: Write + Seek> WriteSeek for T {}
 
+/// Gives a plain `Write` the seekability `IffWriter` needs by holding the
+/// bytes of the open top-level chunk in memory.
+///
+/// Seeks may only move within the held bytes; `flush` emits them and starts
+/// a new window at the current position.
+struct SpoolWriter<W: Write> {
+    out: W,
+    buf: Vec<u8>,
+    /// Stream offset of `buf[0]`.
+    base: u64,
+    /// Cursor relative to `base`.
+    pos: usize,
+}
+
+impl<W: Write> Write for SpoolWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let end = self.pos + data.len();
+        if end > self.buf.len() {
+            self.buf.resize(end, 0);
+        }
+        self.buf[self.pos..end].copy_from_slice(data);
+        self.pos = end;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.write_all(&self.buf)?;
+        self.base += self.buf.len() as u64;
+        self.buf.clear();
+        self.pos = 0;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Seek for SpoolWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => p as i128,
+            SeekFrom::Current(d) => (self.base + self.pos as u64) as i128 + d as i128,
+            SeekFrom::End(d) => (self.base + self.buf.len() as u64) as i128 + d as i128,
+        };
+        let window = self.base as i128..=(self.base + self.buf.len() as u64) as i128;
+        if !window.contains(&target) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "cannot seek outside the open chunk of a buffered IffWriter",
+            ));
+        }
+        self.pos = (target - self.base as i128) as usize;
+        Ok(target as u64)
+    }
+}
+
+impl<W: Write> Drop for SpoolWriter<W> {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            let _ = self.flush();
+        }
+    }
+}
+
+/// Passes writes straight through, counting them, for an `IffWriter` whose
+/// chunk sizes are all given up front.
+///
+/// Only the current position can be asked for; any other seek fails.
+struct CountingWriter<W: Write> {
+    out: W,
+    pos: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let written = self.out.write(data)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Seek for CountingWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.pos),
+            SeekFrom::Start(p) if p == self.pos => Ok(p),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "a streaming IffWriter cannot seek; give chunk sizes when they start",
+            )),
+        }
+    }
+}
+
 pub struct IffWriter<'a> {
     writer: Box<dyn WriteSeek + 'a>,
-    // Stack of (size_field_position, payload_start_position, is_composite)
-    chunk_stack: Vec<(u64, u64, bool)>,
+    // Stack of (size_field_position, payload_start_position, is_composite,
+    // size given when the chunk began)
+    chunk_stack: Vec<(u64, u64, bool, Option<u64>)>,
+    // Whether `writer` is a SpoolWriter that must be flushed after each
+    // top-level chunk.
+    spooled: bool,
+    // Whether a chunk starting at an odd offset is an error rather than
+    // padded.
+    audit_alignment: bool,
 }
 
 impl<'a> IffWriter<'a> {
@@ -115,7 +263,76 @@ impl<'a> IffWriter<'a> {
         IffWriter {
             writer: Box::new(writer),
             chunk_stack: Vec::new(),
+            spooled: false,
+            audit_alignment: true,
+        }
+    }
+
+    /// Creates an `IffWriter` for a destination that cannot seek.
+    ///
+    /// Each top-level chunk is assembled in memory, sized, and written out
+    /// when it is closed, so peak memory is the size of the largest
+    /// top-level chunk (for a DjVu file, the whole `FORM`). Seeking with
+    /// `Seek` only works inside the chunk being built.
+    pub fn buffered(writer: impl Write + 'a) -> Self {
+        IffWriter {
+            writer: Box::new(SpoolWriter {
+                out: writer,
+                buf: Vec::new(),
+                base: 0,
+                pos: 0,
+            }),
+            chunk_stack: Vec::new(),
+            spooled: true,
+            audit_alignment: true,
+        }
+    }
+
+    /// Creates an `IffWriter` that writes straight through to a destination
+    /// that cannot seek, holding nothing back.
+    ///
+    /// Sizes cannot be patched afterwards, so every chunk must be given its
+    /// size when it starts: with
+    /// [`put_sized_composite`](Self::put_sized_composite),
+    /// [`write_chunk`](IffWriterExt::write_chunk) or
+    /// [`write_complete_chunk`](Self::write_complete_chunk).
+    pub fn streaming(writer: impl Write + 'a) -> Self {
+        IffWriter {
+            writer: Box::new(CountingWriter {
+                out: writer,
+                pos: 0,
+            }),
+            chunk_stack: Vec::new(),
+            spooled: false,
+            audit_alignment: true,
+        }
+    }
+
+    /// Sets whether a chunk that would start at an odd offset is an error.
+    ///
+    /// Chunks are padded to even length in [`close_chunk`](Self::close_chunk),
+    /// so a misaligned start only happens when an odd number of raw bytes was
+    /// written between chunks. With the audit on (the default), the chunk is
+    /// rejected so the stray write can be found; with it off, a pad byte is
+    /// inserted before the chunk.
+    pub fn with_alignment_audit(mut self, enabled: bool) -> Self {
+        self.audit_alignment = enabled;
+        self
+    }
+
+    /// Moves to an even offset before a chunk header, per the IFF rules.
+    fn align_chunk_start(&mut self, full_id: impl std::fmt::Display) -> Result<()> {
+        let pos = self.writer.stream_position()?;
+        if pos.is_multiple_of(2) {
+            return Ok(());
+        }
+        if self.audit_alignment {
+            return Err(DjvuError::ValidationError(format!(
+                "Chunk '{full_id}' would start at odd offset {pos}"
+            )));
         }
+        self.writer.write_all(&[0])?;
+        Ok(())
     }
 
     /// Writes the DjVu "AT&T" magic bytes to the start of the stream.
@@ -128,14 +345,12 @@ impl<'a> IffWriter<'a> {
 
     /// Begins a new chunk, writes its header with a placeholder size, and returns the position of the size field.
     /// The caller is responsible for calling `patch_chunk_size` later.
-    pub fn write_chunk_header(&mut self, full_id: &str) -> Result<u64> {
-        let (id, secondary_id) = Self::parse_full_id(full_id)?;
-        self.writer.write_all(&id)?;
+    pub fn write_chunk_header(&mut self, id: ChunkId) -> Result<u64> {
+        Self::check_simple(id)?;
+        self.align_chunk_start(id)?;
+        self.writer.write_all(id.as_bytes())?;
         let size_pos = self.writer.stream_position()?;
         self.writer.write_u32::<BigEndian>(0)?; // Placeholder size
-        if let Some(sid) = secondary_id {
-            self.writer.write_all(&sid)?;
-        }
         Ok(size_pos)
     }
 
@@ -162,31 +377,113 @@ impl<'a> IffWriter<'a> {
         Ok(())
     }
 
-    /// Begins a new chunk with the given ID.
+    /// Begins a new simple chunk with the given ID.
+    ///
+    /// The writer is now positioned to write the chunk's payload. Composite
+    /// chunks are begun with [`put_composite`](Self::put_composite).
+    pub fn put_chunk(&mut self, id: ChunkId) -> Result<()> {
+        Self::check_simple(id)?;
+        self.begin_chunk(id, None, None)
+    }
+
+    /// Begins a new composite chunk, such as `FORM:DJVU`, whose payload is
+    /// the chunks written until it is closed.
+    pub fn put_composite(&mut self, id: ChunkId, secondary_id: ChunkId) -> Result<()> {
+        if !id.is_composite() {
+            return Err(DjvuError::InvalidArg(format!(
+                "{id} is not a composite chunk ID"
+            )));
+        }
+        self.begin_chunk(id, Some(secondary_id), None)
+    }
+
+    /// Begins a composite chunk whose contents, the chunks written until it
+    /// is closed, are `content_len` bytes long with their padding.
     ///
-    /// For composite chunks, the ID should be in the format "FORM:DJVU".
-    /// The writer is now positioned to write the chunk's payload.
-    pub fn put_chunk(&mut self, full_id: &str) -> Result<()> {
-        let (id, secondary_id) = Self::parse_full_id(full_id)?;
+    /// The size is written at once, so this works on a
+    /// [`streaming`](Self::streaming) writer; [`close_chunk`](Self::close_chunk)
+    /// fails if the contents come out a different length.
+    pub fn put_sized_composite(
+        &mut self,
+        id: ChunkId,
+        secondary_id: ChunkId,
+        content_len: usize,
+    ) -> Result<()> {
+        if !id.is_composite() {
+            return Err(DjvuError::InvalidArg(format!(
+                "{id} is not a composite chunk ID"
+            )));
+        }
+        self.begin_chunk(id, Some(secondary_id), Some(4 + content_len as u64))
+    }
+
+    /// Writes a chunk that is already complete, header and all, such as a
+    /// page `FORM` read back from disk, padding it to even length.
+    ///
+    /// Fails if the size in the header does not match the data.
+    pub fn write_complete_chunk(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() < 8 {
+            return Err(DjvuError::ValidationError(format!(
+                "A chunk of {} bytes is too short for its header",
+                data.len()
+            )));
+        }
+        let id = read_id([data[0], data[1], data[2], data[3]])?;
+        let size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        // The data may end with the pad byte of an odd-sized chunk.
+        if data.len() != 8 + size && data.len() != 8 + size + (size & 1) {
+            return Err(DjvuError::ValidationError(format!(
+                "Chunk '{id}' holds {} bytes after its header, which gives {size}",
+                data.len() - 8
+            )));
+        }
+        self.align_chunk_start(id)?;
+        self.writer.write_all(data)?;
+        if !data.len().is_multiple_of(2) {
+            self.writer.write_all(&[0])?;
+        }
+        if self.spooled && self.chunk_stack.is_empty() {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn check_simple(id: ChunkId) -> Result<()> {
+        if id.is_composite() {
+            return Err(DjvuError::InvalidArg(format!(
+                "Composite chunk {id} needs a type ID"
+            )));
+        }
+        Ok(())
+    }
+
+    fn begin_chunk(
+        &mut self,
+        id: ChunkId,
+        secondary_id: Option<ChunkId>,
+        size: Option<u64>,
+    ) -> Result<()> {
         let is_composite = secondary_id.is_some();
 
-        self.writer.write_all(&id)?;
+        self.align_chunk_start(full_id(id, secondary_id))?;
+        self.writer.write_all(id.as_bytes())?;
 
         // Store the position of the size field to be patched later.
         let size_pos = self.writer.stream_position()?;
 
-        // Write a placeholder for the size.
-        self.writer.write_u32::<BigEndian>(0)?;
+        // Write the size if it is known, or a placeholder for it.
+        self.writer
+            .write_u32::<BigEndian>(size.unwrap_or(0) as u32)?;
 
         let payload_start_pos = if let Some(sid) = secondary_id {
-            self.writer.write_all(&sid)?;
+            self.writer.write_all(sid.as_bytes())?;
             self.writer.stream_position()?
         } else {
             self.writer.stream_position()?
         };
 
         self.chunk_stack
-            .push((size_pos, payload_start_pos, is_composite));
+            .push((size_pos, payload_start_pos, is_composite, size));
 
         Ok(())
     }
@@ -196,10 +493,10 @@ impl<'a> IffWriter<'a> {
     /// For composite chunks, the size includes the 4-byte secondary id
     /// to match the DjVu specification and standard IFF format.
     pub fn close_chunk(&mut self) -> Result<()> {
-        let (size_pos, _payload_start_pos, _is_composite) = self
-            .chunk_stack
-            .pop()
-            .ok_or_else(|| DjvuError::InvalidOperation("close_chunk: no open chunk".into()))?;
+        let (size_pos, _payload_start_pos, _is_composite, declared_size) =
+            self.chunk_stack
+                .pop()
+                .ok_or_else(|| DjvuError::InvalidOperation("close_chunk: no open chunk".into()))?;
 
         let mut end_pos = self.writer.stream_position()?;
 
@@ -214,11 +511,25 @@ impl<'a> IffWriter<'a> {
             end_pos += 1;
         }
 
-        // Patch the size field and restore position
-        self.writer.seek(SeekFrom::Start(size_pos))?;
-        self.writer
-            .write_u32::<BigEndian>(chunk_size_field as u32)?;
-        self.writer.seek(SeekFrom::Start(end_pos))?;
+        match declared_size {
+            // Sized when it began: check it came out that size instead.
+            Some(declared) if declared != chunk_size_field => {
+                return Err(DjvuError::ValidationError(format!(
+                    "Chunk came out {chunk_size_field} bytes long, not the {declared} it declared"
+                )));
+            }
+            Some(_) => {}
+            None => {
+                // Patch the size field and restore position
+                self.writer.seek(SeekFrom::Start(size_pos))?;
+                self.writer
+                    .write_u32::<BigEndian>(chunk_size_field as u32)?;
+                self.writer.seek(SeekFrom::Start(end_pos))?;
+            }
+        }
+        if self.spooled && self.chunk_stack.is_empty() {
+            self.writer.flush()?;
+        }
         Ok(())
     }
 
@@ -226,50 +537,18 @@ impl<'a> IffWriter<'a> {
     pub fn nesting_level(&self) -> usize {
         self.chunk_stack.len()
     }
-
-    /// Helper to parse a user-friendly ID string into IFF bytes.
-    fn parse_full_id(full_id: &str) -> Result<([u8; 4], Option<[u8; 4]>)> {
-        let parts: Vec<_> = full_id.split(':').collect();
-        match parts.as_slice() {
-            [primary] => {
-                if primary.len() != 4 {
-                    return Err(DjvuError::InvalidArg(format!(
-                        "Chunk ID must be 4 characters: '{}'",
-                        primary
-                    )));
-                }
-                Ok((primary.as_bytes().try_into().unwrap(), None))
-            }
-            [primary, secondary] => {
-                if primary.len() != 4 || secondary.len() > 4 {
-                    return Err(DjvuError::InvalidArg(format!(
-                        "Composite chunk IDs must be 4 chars: '{}:{}'",
-                        primary, secondary
-                    )));
-                }
-                let mut sid_buf = [0u8; 4]; // Pad with nulls, not spaces
-                sid_buf[..secondary.len()].copy_from_slice(secondary.as_bytes());
-                Ok((primary.as_bytes().try_into().unwrap(), Some(sid_buf)))
-            }
-            _ => Err(DjvuError::InvalidArg(format!(
-                "Invalid chunk ID format: '{}'",
-                full_id
-            ))),
-        }
-    }
 }
 
 /// An extension trait to provide helper methods for `IffWriter`.
 pub trait IffWriterExt {
     /// Writes a complete simple chunk (header, data, and padding) to the stream.
-    fn write_chunk(&mut self, id: [u8; 4], data: &[u8]) -> Result<()>;
+    fn write_chunk(&mut self, id: ChunkId, data: &[u8]) -> Result<()>;
 }
 
 impl<'a> IffWriterExt for IffWriter<'a> {
-    fn write_chunk(&mut self, id: [u8; 4], data: &[u8]) -> Result<()> {
-        let id_str = std::str::from_utf8(&id)
-            .map_err(|e| DjvuError::InvalidArg(format!("Invalid UTF-8 in chunk ID: {}", e)))?;
-        self.put_chunk(id_str)?;
+    fn write_chunk(&mut self, id: ChunkId, data: &[u8]) -> Result<()> {
+        Self::check_simple(id)?;
+        self.begin_chunk(id, None, Some(data.len() as u64))?;
         self.write_all(data)?;
         self.close_chunk()
     }
@@ -295,3 +574,152 @@ impl<'a> Seek for IffWriter<'a> {
         self.writer.seek(pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sink that only implements `Write`, like a socket or pipe.
+    struct Pipe(Vec<u8>);
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn write_sample(writer: &mut IffWriter) -> Result<()> {
+        writer.write_magic_bytes()?;
+        writer.put_composite(ChunkId::FORM, ChunkId::DJVU)?;
+        writer.write_chunk(ChunkId::INFO, &[1, 2, 3])?;
+        writer.put_composite(ChunkId::FORM, ChunkId::DJVI)?;
+        writer.write_chunk(ChunkId::ANTz, &[4; 5])?;
+        writer.close_chunk()?;
+        writer.close_chunk()
+    }
+
+    #[test]
+    fn test_buffered_writer_matches_seekable_output() -> Result<()> {
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        write_sample(&mut IffWriter::new(&mut cursor))?;
+
+        let mut pipe = Pipe(Vec::new());
+        {
+            let mut writer = IffWriter::buffered(&mut pipe);
+            write_sample(&mut writer)?;
+            // Everything is emitted once the top-level chunk closes.
+            assert_eq!(writer.nesting_level(), 0);
+        }
+        assert_eq!(pipe.0, cursor.into_inner());
+        Ok(())
+    }
+
+    #[test]
+    fn test_streaming_writer_takes_sizes_up_front() -> Result<()> {
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        write_sample(&mut IffWriter::new(&mut cursor))?;
+        let expected = cursor.into_inner();
+
+        // The inner FORM goes in whole, as a page read back from disk would.
+        let mut pipe = Pipe(Vec::new());
+        let mut writer = IffWriter::streaming(&mut pipe);
+        writer.write_magic_bytes()?;
+        writer.put_sized_composite(ChunkId::FORM, ChunkId::DJVU, 12 + 26)?;
+        writer.write_chunk(ChunkId::INFO, &[1, 2, 3])?;
+        writer.write_complete_chunk(&expected[28..54])?;
+        writer.close_chunk()?;
+        drop(writer);
+        assert_eq!(pipe.0, expected);
+
+        // Sizes cannot be patched, and must come out as declared.
+        let mut writer = IffWriter::streaming(Pipe(Vec::new()));
+        writer.put_chunk(ChunkId::INFO)?;
+        assert!(writer.close_chunk().is_err());
+        let mut writer = IffWriter::streaming(Pipe(Vec::new()));
+        writer.put_sized_composite(ChunkId::FORM, ChunkId::DJVU, 8)?;
+        writer.write_chunk(ChunkId::INFO, &[0])?;
+        assert!(writer.close_chunk().is_err());
+
+        // A complete chunk must match its header, and is padded to even.
+        let mut writer = IffWriter::streaming(Pipe(Vec::new()));
+        assert!(writer.write_complete_chunk(b"INFO\0\0\0\x04ab").is_err());
+        assert!(writer.write_complete_chunk(b"INF").is_err());
+        writer.write_complete_chunk(b"INFO\0\0\0\x01a")?;
+        writer.write_chunk(ChunkId::ANTz, &[])?;
+        assert_eq!(writer.stream_position()?, 18);
+        Ok(())
+    }
+
+    #[test]
+    fn test_alignment_audit() -> Result<()> {
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        let mut writer = IffWriter::new(&mut cursor).with_alignment_audit(true);
+        writer.put_composite(ChunkId::FORM, ChunkId::DJVU)?;
+        writer.write_all(&[0; 3])?;
+        assert!(writer.put_chunk(ChunkId::INFO).is_err());
+
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        let mut writer = IffWriter::new(&mut cursor).with_alignment_audit(false);
+        writer.put_composite(ChunkId::FORM, ChunkId::DJVU)?;
+        writer.write_all(&[0; 3])?;
+        writer.write_chunk(ChunkId::INFO, &[1])?;
+        writer.close_chunk()?;
+        drop(writer);
+        let data = cursor.into_inner();
+        // The pad byte before INFO is part of the FORM payload.
+        assert_eq!(&data[16..20], b"INFO");
+        assert_eq!(u32::from_be_bytes(data[4..8].try_into().unwrap()), 18);
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffered_writer_rejects_seeking_into_emitted_data() -> Result<()> {
+        let mut pipe = Pipe(Vec::new());
+        let mut writer = IffWriter::buffered(&mut pipe);
+        writer.write_chunk(ChunkId::INFO, &[0; 4])?;
+        assert_eq!(writer.stream_position()?, 12);
+        assert!(writer.seek(SeekFrom::Start(0)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reader_rejects_bad_sizes() {
+        use std::io::Cursor;
+
+        // A FORM too small to hold its type ID.
+        let mut reader = Cursor::new(b"FORM\0\0\0\x02DJ".to_vec());
+        assert!(reader.next_chunk().is_err());
+
+        // A chunk claiming more data than the stream holds.
+        let mut reader = Cursor::new(b"INCL\0\0\x10\0abc".to_vec());
+        let chunk = reader.next_chunk().unwrap().unwrap();
+        assert!(reader.get_chunk_data(&chunk).is_err());
+
+        // IDs that are not printable ASCII.
+        let mut reader = Cursor::new(b"IN\0L\0\0\0\0".to_vec());
+        assert!(reader.next_chunk().is_err());
+        let mut reader = Cursor::new(b"FORM\0\0\0\x04D\x01VU".to_vec());
+        assert!(reader.next_chunk().is_err());
+    }
+
+    #[test]
+    fn test_composite_ids_checked() -> Result<()> {
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        let mut writer = IffWriter::new(&mut cursor);
+        assert!(writer.put_chunk(ChunkId::FORM).is_err());
+        assert!(writer.put_composite(ChunkId::INFO, ChunkId::DJVU).is_err());
+        writer.put_composite(ChunkId::CAT, ChunkId::DJVU)?;
+        writer.close_chunk()?;
+        drop(writer);
+        let mut reader = std::io::Cursor::new(cursor.into_inner());
+        let chunk = reader.next_chunk()?.unwrap();
+        assert_eq!(chunk.full_id(), "CAT:DJVU");
+        assert!(chunk.is_composite());
+        Ok(())
+    }
+}
@@ -1,8 +1,15 @@
+// Codec paths report malformed input as errors; they must not panic.
+#![cfg_attr(not(test), deny(clippy::unwrap_used))]
+
 pub mod bs_byte_stream;
 pub mod byte_stream;
+pub mod chunk_id;
 pub mod chunk_tree;
 pub mod data_pool;
 pub mod iff;
+pub mod parse_mode;
 
 // Re-export commonly used types
 pub use byte_stream::{ByteStream, MemoryStream};
+pub use chunk_id::ChunkId;
+pub use parse_mode::{ParseLog, ParseMode, ParseWarning};
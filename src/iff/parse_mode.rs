@@ -0,0 +1,93 @@
+//! How strictly readers hold input to the specification.
+//!
+//! Files found in the wild are not always well formed: some writers leave
+//! out the pad byte after odd-sized chunks, and downloads get cut short.
+//! [`ParseMode::Strict`] rejects any such file; [`ParseMode::Permissive`]
+//! recovers what it can and records each problem as a [`ParseWarning`],
+//! like the `recover_errors` setting of DjVuLibre's `DjVuFile`.
+//!
+//! A [`ParseLog`] carries the mode through a parse and collects the
+//! warnings; the IFF reader ([`IffDocument::from_bytes_with`]), the
+//! directory reader ([`DjVmDir::decode_with`]), the IW44 chunk header
+//! reader ([`Iw44ChunkHeader::parse_with`]) and the document editor
+//! ([`DjVuDocEditor::from_bytes_with`],
+//! [`DjVuDocEditor::from_indirect_with`]) take one.
+//!
+//! JB2 data is not read at all: the editor and the validators pass `Sjbz`
+//! and `Djbz` chunks through as they are, so a mode has nothing to govern
+//! there beyond the IFF structure around them.
+//!
+//! [`IffDocument::from_bytes_with`]: crate::iff::chunk_tree::IffDocument::from_bytes_with
+//! [`DjVmDir::decode_with`]: crate::doc::djvu_dir::DjVmDir::decode_with
+//! [`Iw44ChunkHeader::parse_with`]: crate::encode::iw44::header::Iw44ChunkHeader::parse_with
+//! [`DjVuDocEditor::from_bytes_with`]: crate::doc::DjVuDocEditor::from_bytes_with
+//! [`DjVuDocEditor::from_indirect_with`]: crate::doc::DjVuDocEditor::from_indirect_with
+
+use crate::{DjvuError, Result};
+use std::fmt;
+
+/// How a reader treats input that breaks the specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Fail on the first violation.
+    #[default]
+    Strict,
+    /// Recover from common damage and record a warning for each.
+    Permissive,
+}
+
+/// A violation a permissive parse recovered from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseWarning {
+    /// Byte offset of the problem in the parsed data.
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at byte {}: {}", self.offset, self.message)
+    }
+}
+
+/// The mode of a parse and the warnings it has collected.
+#[derive(Debug, Clone, Default)]
+pub struct ParseLog {
+    mode: ParseMode,
+    warnings: Vec<ParseWarning>,
+}
+
+impl ParseLog {
+    pub fn new(mode: ParseMode) -> Self {
+        Self {
+            mode,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn mode(&self) -> ParseMode {
+        self.mode
+    }
+
+    /// The violations recovered from so far, in the order they were found.
+    pub fn warnings(&self) -> &[ParseWarning] {
+        &self.warnings
+    }
+
+    pub fn into_warnings(self) -> Vec<ParseWarning> {
+        self.warnings
+    }
+
+    /// Reports a violation at `offset`: an error in strict mode, a warning
+    /// to recover from in permissive mode.
+    pub fn violation(&mut self, offset: usize, message: impl Into<String>) -> Result<()> {
+        let message = message.into();
+        match self.mode {
+            ParseMode::Strict => Err(DjvuError::ValidationError(message)),
+            ParseMode::Permissive => {
+                self.warnings.push(ParseWarning { offset, message });
+                Ok(())
+            }
+        }
+    }
+}
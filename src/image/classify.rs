@@ -0,0 +1,160 @@
+//! Guessing what kind of page a scan is.
+//!
+//! Real scans are rarely exact: a black-and-white page picks up gray
+//! antialiasing along its strokes, and a grayscale page printed on cream
+//! paper comes back faintly tinted. [`classify_page`] looks at statistics of
+//! the whole page rather than at every pixel, so such pages still land in the
+//! cheap class. Color is measured with the Hasler–Süsstrunk colorfulness
+//! metric plus the share of clearly colored pixels (which catches a small
+//! colored logo on an otherwise gray page); bitonality with the share of
+//! pixels near either end of the luminance histogram.
+
+use crate::image::image_formats::LoadedImage;
+
+/// What a page holds, as far as the encoder is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageClass {
+    /// Only pure black and white: encoded with JB2.
+    Bitonal,
+    /// Shades of gray.
+    Grayscale,
+    /// Anything else.
+    Color,
+}
+
+/// Thresholds for [`classify_page`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClassifyParams {
+    /// Pages whose colorfulness is above this are color. 0 is perfectly
+    /// gray; 15 is barely colorful and 33 moderately so.
+    pub colorfulness: f32,
+    /// A pixel counts as colored when its largest and smallest channel
+    /// differ by more than this.
+    pub chroma: u8,
+    /// Pages with more than this fraction of colored pixels are color,
+    /// whatever their overall colorfulness.
+    pub colored_fraction: f32,
+    /// A pixel counts as black or white when its luminance is within this
+    /// of 0 or 255.
+    pub bitonal_margin: u8,
+    /// Non-color pages with at least this fraction of black or white pixels
+    /// are bitonal.
+    pub bitonal_fraction: f32,
+}
+
+impl Default for ClassifyParams {
+    fn default() -> Self {
+        Self {
+            colorfulness: 15.0,
+            chroma: 48,
+            colored_fraction: 0.005,
+            bitonal_margin: 48,
+            bitonal_fraction: 0.98,
+        }
+    }
+}
+
+/// Sorts a scanned page into a [`PageClass`] using page-wide statistics.
+///
+/// Unlike [`crate::doc::import::classify`], which only accepts exact black
+/// and white or exact gray, this tolerates scanner noise as set by `params`.
+/// Bilevel images are always bitonal.
+pub fn classify_page(image: &LoadedImage, params: &ClassifyParams) -> PageClass {
+    let (lumas, color): (Vec<u8>, bool) = match image {
+        LoadedImage::Bilevel(_) => return PageClass::Bitonal,
+        LoadedImage::Gray(gray) => (gray.pixels().iter().map(|p| p.y).collect(), false),
+        LoadedImage::Rgb(rgb) => {
+            let pixels = rgb.pixels();
+            let n = pixels.len().max(1) as f64;
+            let (mut sum_rg, mut sum_yb, mut sq_rg, mut sq_yb) = (0f64, 0f64, 0f64, 0f64);
+            let mut colored = 0usize;
+            for p in pixels {
+                let (r, g, b) = (p.r as f64, p.g as f64, p.b as f64);
+                let rg = r - g;
+                let yb = 0.5 * (r + g) - b;
+                sum_rg += rg;
+                sum_yb += yb;
+                sq_rg += rg * rg;
+                sq_yb += yb * yb;
+                let chroma = p.r.max(p.g).max(p.b) - p.r.min(p.g).min(p.b);
+                colored += usize::from(chroma > params.chroma);
+            }
+            let (mean_rg, mean_yb) = (sum_rg / n, sum_yb / n);
+            let var_rg = (sq_rg / n - mean_rg * mean_rg).max(0.0);
+            let var_yb = (sq_yb / n - mean_yb * mean_yb).max(0.0);
+            let colorfulness =
+                (var_rg + var_yb).sqrt() + 0.3 * (mean_rg * mean_rg + mean_yb * mean_yb).sqrt();
+            let color = colorfulness > params.colorfulness as f64
+                || colored as f64 / n > params.colored_fraction as f64;
+            (
+                rgb.to_bitmap().pixels().iter().map(|p| p.y).collect(),
+                color,
+            )
+        }
+    };
+    if color {
+        return PageClass::Color;
+    }
+
+    let margin = params.bitonal_margin;
+    let extreme = lumas
+        .iter()
+        .filter(|&&y| y <= margin || y >= 255 - margin)
+        .count();
+    if extreme as f64 >= params.bitonal_fraction as f64 * lumas.len() as f64 {
+        PageClass::Bitonal
+    } else {
+        PageClass::Grayscale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::image_formats::{Bitmap, GrayPixel, Pixel, Pixmap};
+
+    #[test]
+    fn test_classify_noisy_scans() {
+        let params = ClassifyParams::default();
+
+        // Text with a little antialiasing on slightly tinted paper.
+        let text = Pixmap::from_fn(100, 100, |x, y| match (x + y) % 10 {
+            0..=3 => Pixel::new(12, 10, 14),
+            4 if x == y => Pixel::new(128, 126, 124),
+            _ => Pixel::new(250, 244, 232),
+        });
+        let text = LoadedImage::Rgb(text);
+        assert_eq!(classify_page(&text, &params), PageClass::Bitonal);
+        let strict = ClassifyParams {
+            bitonal_fraction: 1.0,
+            ..params
+        };
+        assert_eq!(classify_page(&text, &strict), PageClass::Grayscale);
+
+        // A gray photo with a small red logo in the corner.
+        let photo = Pixmap::from_fn(100, 100, |x, y| {
+            if x < 10 && y < 10 {
+                Pixel::new(200, 20, 20)
+            } else {
+                let v = (x + y) as u8;
+                Pixel::new(v, v, v.saturating_add(3))
+            }
+        });
+        let mut photo = LoadedImage::Rgb(photo);
+        assert_eq!(classify_page(&photo, &params), PageClass::Color);
+        if let LoadedImage::Rgb(p) = &mut photo {
+            for y in 0..10 {
+                for x in 0..10 {
+                    p.put_pixel(x, y, Pixel::new(90, 90, 90));
+                }
+            }
+        }
+        assert_eq!(classify_page(&photo, &params), PageClass::Grayscale);
+
+        let gray = Bitmap::from_vec(2, 2, [0, 255, 20, 240].map(GrayPixel::new).to_vec());
+        assert_eq!(
+            classify_page(&LoadedImage::Gray(gray), &params),
+            PageClass::Bitonal
+        );
+    }
+}
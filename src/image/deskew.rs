@@ -0,0 +1,192 @@
+//! Straightening skewed scans.
+//!
+//! Pages fed through a scanner come out rotated by a degree or two, and JB2
+//! symbol matching suffers badly from it: the same letter lands on the pixel
+//! grid differently along a slanted line, so fewer shapes match. The skew is
+//! found from projection profiles: the black pixels are summed along lines
+//! of each candidate angle, and the angle at which text lines line up gives
+//! the sharpest profile (the largest sum of squared differences between
+//! neighbouring bins). The page is then rotated back about its center.
+
+use crate::encode::jb2::symbol_dict::BitImage;
+use crate::image::image_formats::{Pixel, Pixmap};
+
+/// Settings for [`estimate_skew`] and the import path's deskewing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct DeskewParams {
+    /// Largest skew looked for, in degrees either way.
+    pub max_angle: f32,
+    /// Spacing of the candidate angles, in degrees.
+    pub step: f32,
+    /// Pages skewed by less than this, in degrees, are left alone.
+    pub min_angle: f32,
+}
+
+impl Default for DeskewParams {
+    fn default() -> Self {
+        Self {
+            max_angle: 3.0,
+            step: 0.1,
+            min_angle: 0.2,
+        }
+    }
+}
+
+/// Estimates the skew of the lines of `image`, in degrees. Positive angles
+/// are lines rising to the right.
+pub fn estimate_skew(image: &BitImage, params: &DeskewParams) -> f32 {
+    let step = params.step.max(0.01);
+    let steps = (params.max_angle.max(0.0) / step).round() as i32;
+    let mut best = (0.0f32, f64::MIN);
+    for i in -steps..=steps {
+        let angle = i as f32 * step;
+        let score = profile_sharpness(image, angle);
+        // On ties, prefer the angle nearest zero.
+        if score > best.1 || (score == best.1 && angle.abs() < best.0.abs()) {
+            best = (angle, score);
+        }
+    }
+    best.0
+}
+
+/// Sum of squared differences between neighbouring bins of the projection
+/// of the black pixels of `image` along lines at `angle` degrees.
+fn profile_sharpness(image: &BitImage, angle: f32) -> f64 {
+    let tan = (angle as f64).to_radians().tan();
+    let shift = (image.width as f64 * tan.abs()).ceil() as usize;
+    let last = image.height + shift;
+    let mut bins = vec![0u32; last + 1];
+    let base = if tan > 0.0 { 0.0 } else { shift as f64 };
+    for y in 0..image.height {
+        for (start, end) in image.row_runs(y) {
+            for x in start..=end {
+                let bin = (y as f64 + base + x as f64 * tan).round() as usize;
+                bins[bin.min(last)] += 1;
+            }
+        }
+    }
+    bins.windows(2)
+        .map(|w| {
+            let d = w[1] as f64 - w[0] as f64;
+            d * d
+        })
+        .sum()
+}
+
+/// Where the pixel at (`x`, `y`) of an image straightened by `angle`
+/// degrees comes from in the skewed one.
+fn source_point(x: u32, y: u32, (cx, cy): (f64, f64), (sin, cos): (f64, f64)) -> (f64, f64) {
+    let (dx, dy) = (x as f64 + 0.5 - cx, y as f64 + 0.5 - cy);
+    (
+        cx + dx * cos + dy * sin - 0.5,
+        cy - dx * sin + dy * cos - 0.5,
+    )
+}
+
+/// Rotates `image` about its center to undo a skew of `angle` degrees (as
+/// returned by [`estimate_skew`]), keeping its size. Pixels rotated in from
+/// outside the page are white.
+pub fn deskew_bits(image: &BitImage, angle: f32) -> BitImage {
+    let mut out = BitImage::new(image.width as u32, image.height as u32)
+        .expect("same size as an existing image");
+    let center = (image.width as f64 / 2.0, image.height as f64 / 2.0);
+    let rotation = (angle as f64).to_radians().sin_cos();
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let (sx, sy) = source_point(x as u32, y as u32, center, rotation);
+            let (sx, sy) = (sx.round(), sy.round());
+            if sx >= 0.0
+                && sy >= 0.0
+                && (sx as usize) < image.width
+                && (sy as usize) < image.height
+                && image.get_pixel_unchecked(sx as usize, sy as usize)
+            {
+                out.set_usize(x, y, true);
+            }
+        }
+    }
+    out
+}
+
+/// Rotates `image` about its center to undo a skew of `angle` degrees, with
+/// bilinear resampling, keeping its size. Pixels rotated in from outside the
+/// page are white.
+pub fn deskew_pixmap(image: &Pixmap, angle: f32) -> Pixmap {
+    let (w, h) = image.dimensions();
+    let center = (w as f64 / 2.0, h as f64 / 2.0);
+    let rotation = (angle as f64).to_radians().sin_cos();
+    let sample = |x: i64, y: i64| -> [f64; 3] {
+        if x < 0 || y < 0 || x >= w as i64 || y >= h as i64 {
+            [255.0; 3]
+        } else {
+            let p = image.get_pixel(x as u32, y as u32);
+            [p.r as f64, p.g as f64, p.b as f64]
+        }
+    };
+    Pixmap::from_fn(w, h, |x, y| {
+        let (sx, sy) = source_point(x, y, center, rotation);
+        let (x0, y0) = (sx.floor(), sy.floor());
+        let (fx, fy) = (sx - x0, sy - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let (a, b) = (sample(x0, y0), sample(x0 + 1, y0));
+        let (c, d) = (sample(x0, y0 + 1), sample(x0 + 1, y0 + 1));
+        let mix = |i: usize| {
+            let top = a[i] + (b[i] - a[i]) * fx;
+            let bottom = c[i] + (d[i] - c[i]) * fx;
+            (top + (bottom - top) * fy).round().clamp(0.0, 255.0) as u8
+        };
+        Pixel::new(mix(0), mix(1), mix(2))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dashed "text lines" rising to the right by `angle` degrees.
+    fn skewed_lines(angle: f32) -> BitImage {
+        let (w, h) = (400usize, 300usize);
+        let tan = (angle as f64).to_radians().tan();
+        let mut image = BitImage::new(w as u32, h as u32).unwrap();
+        for line in 0..10 {
+            let y0 = 40.0 + line as f64 * 24.0;
+            for x in 20..380 {
+                if x % 12 < 8 {
+                    let y = (y0 - (x as f64 - 200.0) * tan).round() as usize;
+                    for t in 0..6 {
+                        image.set_usize(x, y + t, true);
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn test_estimate_and_undo_skew() {
+        let params = DeskewParams::default();
+        assert_eq!(estimate_skew(&skewed_lines(0.0), &params), 0.0);
+        for angle in [1.5f32, -0.8, 2.0] {
+            let image = skewed_lines(angle);
+            let found = estimate_skew(&image, &params);
+            assert!((found - angle).abs() <= 0.15, "{angle}: found {found}");
+
+            let straight = deskew_bits(&image, found);
+            assert!(estimate_skew(&straight, &params).abs() <= 0.15);
+        }
+
+        let page = Pixmap::from_fn(40, 40, |x, _| {
+            if x < 20 {
+                Pixel::black()
+            } else {
+                Pixel::white()
+            }
+        });
+        let turned = deskew_pixmap(&page, 1.0);
+        assert_eq!(turned.dimensions(), (40, 40));
+        assert_eq!(turned.get_pixel(5, 20), Pixel::black());
+        assert_eq!(deskew_pixmap(&page, 0.0).pixels(), page.pixels());
+    }
+}
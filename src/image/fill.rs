@@ -0,0 +1,229 @@
+//! Filling the holes the foreground leaves in the background.
+//!
+//! The pixels of a background under the JB2 shapes are never seen, but the
+//! wavelet coder still has to code something there, and a sharp edge around
+//! each letter rings into the visible pixels next to it. What goes into the
+//! holes decides how much ringing there is and what it costs:
+//!
+//! - [`HoleFill::Wavelet`] leaves it to IW44's own masking, which solves for
+//!   coefficients that only fit the visible pixels, like DjVuLibre.
+//! - [`HoleFill::Nearest`] copies the nearest visible pixel: cheap, but
+//!   leaves steps where fills from different sides meet.
+//! - [`HoleFill::Diffusion`] smooths the nearest fill by repeatedly
+//!   averaging neighbors, which removes the steps.
+//! - [`HoleFill::Inpaint`] fills from the edge inwards, each pixel a
+//!   weighted average of the filled or visible pixels around it, after
+//!   Telea's fast marching inpainting. It keeps gradients running into the
+//!   hole best.
+//!
+//! The pixel fills code the filled picture as is, without IW44 masking.
+
+use crate::image::image_formats::{Bitmap, Pixmap};
+use std::collections::VecDeque;
+
+/// How masked background pixels are filled before wavelet coding; see the
+/// [module documentation](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HoleFill {
+    /// IW44 masking in the wavelet domain.
+    #[default]
+    Wavelet,
+    /// The color of the nearest visible pixel.
+    Nearest,
+    /// Nearest fill smoothed by iterated neighbor averaging.
+    Diffusion,
+    /// Telea-style fast marching inpainting.
+    Inpaint,
+}
+
+/// Most averaging passes of [`HoleFill::Diffusion`].
+const DIFFUSION_PASSES: usize = 200;
+/// Radius of the neighborhood [`HoleFill::Inpaint`] averages over.
+const INPAINT_RADIUS: i32 = 4;
+
+/// Fills the pixels of `image` that are set (non-zero) in `mask`, which has
+/// the size of `image`, with `method`. [`HoleFill::Wavelet`] and masks
+/// hiding everything leave `image` as it is.
+pub fn fill_holes(image: &mut Pixmap, mask: &Bitmap, method: HoleFill) {
+    let (w, h) = image.dimensions();
+    let (w, h) = (w as usize, h as usize);
+    let hole: Vec<bool> = (0..w * h)
+        .map(|i| mask.get_pixel((i % w) as u32, (i / w) as u32).y != 0)
+        .collect();
+    if method == HoleFill::Wavelet || hole.iter().all(|&m| m) || !hole.contains(&true) {
+        return;
+    }
+
+    let mut planes: [Vec<f32>; 3] = std::array::from_fn(|c| {
+        image
+            .as_raw()
+            .iter()
+            .skip(c)
+            .step_by(3)
+            .map(|&v| v as f32)
+            .collect()
+    });
+    let distance = nearest_fill(&mut planes, &hole, w, h);
+    match method {
+        HoleFill::Diffusion => diffuse(&mut planes, &hole, w, h),
+        HoleFill::Inpaint => inpaint(&mut planes, &hole, &distance, w, h),
+        _ => {}
+    }
+
+    for (i, pixel) in image.as_raw_mut().chunks_exact_mut(3).enumerate() {
+        if hole[i] {
+            for (c, value) in pixel.iter_mut().enumerate() {
+                *value = planes[c][i].round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+/// The 4-neighbors of pixel `i` in a `w` x `h` image.
+fn neighbors(i: usize, w: usize, h: usize) -> impl Iterator<Item = usize> {
+    let (x, y) = (i % w, i / w);
+    [
+        (x > 0).then(|| i - 1),
+        (x + 1 < w).then(|| i + 1),
+        (y > 0).then(|| i - w),
+        (y + 1 < h).then(|| i + w),
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Gives every hole pixel the value of a nearest visible one, spreading
+/// outwards from the edges of the holes, and returns each pixel's distance
+/// in steps from the visible pixels.
+fn nearest_fill(planes: &mut [Vec<f32>; 3], hole: &[bool], w: usize, h: usize) -> Vec<u32> {
+    let mut distance: Vec<u32> = hole.iter().map(|&m| if m { u32::MAX } else { 0 }).collect();
+    let mut queue: VecDeque<usize> = (0..w * h).filter(|&i| !hole[i]).collect();
+    while let Some(i) = queue.pop_front() {
+        for j in neighbors(i, w, h) {
+            if distance[j] == u32::MAX {
+                distance[j] = distance[i] + 1;
+                for plane in planes.iter_mut() {
+                    plane[j] = plane[i];
+                }
+                queue.push_back(j);
+            }
+        }
+    }
+    distance
+}
+
+/// Replaces hole pixels by the mean of their neighbors until the largest
+/// change falls under half a level.
+fn diffuse(planes: &mut [Vec<f32>; 3], hole: &[bool], w: usize, h: usize) {
+    let holes: Vec<usize> = (0..w * h).filter(|&i| hole[i]).collect();
+    for plane in planes.iter_mut() {
+        for _ in 0..DIFFUSION_PASSES {
+            let mut change = 0f32;
+            for &i in &holes {
+                let (sum, count) =
+                    neighbors(i, w, h).fold((0.0, 0.0), |(s, n), j| (s + plane[j], n + 1.0));
+                let value = sum / count;
+                change = change.max((value - plane[i]).abs());
+                plane[i] = value;
+            }
+            if change < 0.5 {
+                break;
+            }
+        }
+    }
+}
+
+/// Fills hole pixels in order of their distance to the visible pixels, each
+/// from the known pixels within [`INPAINT_RADIUS`], weighted up when they are
+/// close and when they lie on about the same distance contour.
+fn inpaint(planes: &mut [Vec<f32>; 3], hole: &[bool], distance: &[u32], w: usize, h: usize) {
+    let mut order: Vec<usize> = (0..w * h).filter(|&i| hole[i]).collect();
+    order.sort_by_key(|&i| distance[i]);
+    let mut known: Vec<bool> = hole.iter().map(|&m| !m).collect();
+    for i in order {
+        let (x, y) = ((i % w) as i32, (i / w) as i32);
+        let mut sum = [0f32; 3];
+        let mut total = 0f32;
+        for dy in -INPAINT_RADIUS..=INPAINT_RADIUS {
+            for dx in -INPAINT_RADIUS..=INPAINT_RADIUS {
+                let (nx, ny) = (x + dx, y + dy);
+                let d2 = dx * dx + dy * dy;
+                if d2 == 0
+                    || d2 > INPAINT_RADIUS * INPAINT_RADIUS
+                    || nx < 0
+                    || ny < 0
+                    || nx >= w as i32
+                    || ny >= h as i32
+                {
+                    continue;
+                }
+                let j = ny as usize * w + nx as usize;
+                if !known[j] {
+                    continue;
+                }
+                let level = 1.0 / (1.0 + distance[i].abs_diff(distance[j]) as f32);
+                let weight = level / d2 as f32;
+                for (c, plane) in planes.iter().enumerate() {
+                    sum[c] += weight * plane[j];
+                }
+                total += weight;
+            }
+        }
+        // The nearest fill stays when nothing is known in reach.
+        if total > 0.0 {
+            for (c, plane) in planes.iter_mut().enumerate() {
+                plane[i] = sum[c] / total;
+            }
+        }
+        known[i] = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::image_formats::{GrayPixel, Pixel};
+
+    #[test]
+    fn test_fill_holes() {
+        // A left-to-right ramp with a band across it hidden.
+        let ramp = Pixmap::from_fn(20, 9, |x, _| Pixel::new(x as u8 * 10, 50, 50));
+        let mask = (0..9 * 20)
+            .map(|i| GrayPixel::new(u8::from((5..15).contains(&(i % 20)))))
+            .collect();
+        let mask = Bitmap::from_vec(20, 9, mask);
+
+        let mut wavelet = ramp.clone();
+        fill_holes(&mut wavelet, &mask, HoleFill::Wavelet);
+        assert_eq!(wavelet.as_raw(), ramp.as_raw());
+
+        let error = |method| {
+            let mut filled = Pixmap::from_fn(20, 9, |x, y| {
+                if mask.get_pixel(x, y).y != 0 {
+                    Pixel::black()
+                } else {
+                    ramp.get_pixel(x, y)
+                }
+            });
+            fill_holes(&mut filled, &mask, method);
+            for y in 0..9 {
+                for x in 0..20 {
+                    if mask.get_pixel(x, y).y == 0 {
+                        assert_eq!(filled.get_pixel(x, y), ramp.get_pixel(x, y));
+                    }
+                }
+            }
+            // Mean distance from the ramp inside the hole.
+            let diff: u32 = (0..9)
+                .flat_map(|y| (0..20).map(move |x| (x, y)))
+                .map(|(x, y)| filled.get_pixel(x, y).r.abs_diff(ramp.get_pixel(x, y).r) as u32)
+                .sum();
+            diff / 90
+        };
+        let nearest = error(HoleFill::Nearest);
+        assert!(nearest > 0);
+        assert!(error(HoleFill::Diffusion) < nearest);
+        assert!(error(HoleFill::Inpaint) < nearest);
+    }
+}
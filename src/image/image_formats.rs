@@ -8,9 +8,20 @@
 //!
 //! An extension trait, `DjvuImageExt`, adds DjVu-specific rendering operations
 //! like `stencil`, `attenuate`, and `blit_solid`.
+//!
+//! Loader helpers read PBM/PGM/PPM (binary and ASCII) directly into these
+//! types; multi-page TIFF and PNG are available behind the `tiff` and `png`
+//! features.
 
+use crate::encode::jb2::symbol_dict::BitImage;
 use crate::image::geom::Rect;
+use crate::utils::error::{DjvuError, Result};
 use bytemuck::{Pod, Zeroable};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+#[cfg(feature = "tiff")]
+use std::io::{Read, Seek};
+use std::path::Path;
 
 // --- Pixel Type Definitions ---
 
@@ -206,6 +217,39 @@ impl Pixmap {
             data,
         }
     }
+
+    /// Reduces the pixmap by `factor` in each direction, averaging each
+    /// `factor`×`factor` block (partial blocks at the edges included).
+    ///
+    /// The result is `ceil(width / factor)` by `ceil(height / factor)`, the
+    /// size DjVu viewers expect for a background subsampled by `factor`.
+    pub fn subsample(&self, factor: u32) -> Pixmap {
+        let factor = factor.max(1);
+        if factor == 1 {
+            return self.clone();
+        }
+        let (w, h) = (self.width.div_ceil(factor), self.height.div_ceil(factor));
+        Pixmap::from_fn(w, h, |x, y| {
+            let (x0, y0) = (x * factor, y * factor);
+            let (x1, y1) = (
+                (x0 + factor).min(self.width),
+                (y0 + factor).min(self.height),
+            );
+            let mut sum = [0u32; 3];
+            for sy in y0..y1 {
+                for p in
+                    &self.data[(sy * self.width + x0) as usize..(sy * self.width + x1) as usize]
+                {
+                    sum[0] += p.r as u32;
+                    sum[1] += p.g as u32;
+                    sum[2] += p.b as u32;
+                }
+            }
+            let n = (x1 - x0) * (y1 - y0);
+            let avg = |s: u32| ((s + n / 2) / n) as u8;
+            Pixel::new(avg(sum[0]), avg(sum[1]), avg(sum[2]))
+        })
+    }
 }
 
 // --- Bitmap Type (Grayscale Image Buffer) ---
@@ -451,3 +495,558 @@ impl DjvuImageExt for Pixmap {
         }
     }
 }
+
+// --- File Loaders ---
+
+/// An image read from disk, in whichever in-memory form matches its source.
+///
+/// Bilevel sources (PBM, 1-bit TIFF) come back as a [`BitImage`] ready for
+/// JB2; grayscale as a [`Bitmap`]; color as a [`Pixmap`].
+#[derive(Clone, Debug)]
+pub enum LoadedImage {
+    Bilevel(BitImage),
+    Gray(Bitmap),
+    Rgb(Pixmap),
+}
+
+impl LoadedImage {
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            LoadedImage::Bilevel(b) => (b.width as u32, b.height as u32),
+            LoadedImage::Gray(g) => g.dimensions(),
+            LoadedImage::Rgb(p) => p.dimensions(),
+        }
+    }
+
+    /// Converts to RGB, expanding gray and bilevel (black = set) sources.
+    pub fn into_pixmap(self) -> Pixmap {
+        match self {
+            LoadedImage::Rgb(p) => p,
+            LoadedImage::Gray(g) => Pixmap::from_fn(g.width, g.height, |x, y| {
+                let v = g.get_pixel(x, y).y;
+                Pixel::new(v, v, v)
+            }),
+            LoadedImage::Bilevel(b) => Pixmap::from_fn(b.width as u32, b.height as u32, |x, y| {
+                if b.get_pixel_unchecked(x as usize, y as usize) {
+                    Pixel::black()
+                } else {
+                    Pixel::white()
+                }
+            }),
+        }
+    }
+}
+
+fn pnm_error(msg: impl Into<String>) -> DjvuError {
+    DjvuError::InvalidArg(format!("PNM: {}", msg.into()))
+}
+
+/// Reads the next whitespace-delimited header token, skipping `#` comments.
+fn pnm_token<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut token = String::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            break;
+        }
+        match byte[0] {
+            b'#' => {
+                let mut comment = Vec::new();
+                reader.read_until(b'\n', &mut comment)?;
+                if !token.is_empty() {
+                    break;
+                }
+            }
+            c if c.is_ascii_whitespace() => {
+                if !token.is_empty() {
+                    break;
+                }
+            }
+            c => token.push(c as char),
+        }
+    }
+    if token.is_empty() {
+        return Err(pnm_error("truncated header"));
+    }
+    Ok(token)
+}
+
+fn pnm_number<R: BufRead>(reader: &mut R) -> Result<u32> {
+    let token = pnm_token(reader)?;
+    token
+        .parse()
+        .map_err(|_| pnm_error(format!("expected a number, found {token:?}")))
+}
+
+/// Reads `count` samples of up to 16 bits and scales them to 8 bits.
+fn pnm_samples<R: BufRead>(
+    reader: &mut R,
+    count: usize,
+    maxval: u32,
+    ascii: bool,
+) -> Result<Vec<u8>> {
+    let scale = |v: u32| -> u8 { ((v.min(maxval) * 255 + maxval / 2) / maxval) as u8 };
+    if ascii {
+        return (0..count).map(|_| pnm_number(reader).map(scale)).collect();
+    }
+    if maxval < 256 {
+        let mut raw = vec![0u8; count];
+        reader.read_exact(&mut raw)?;
+        if maxval != 255 {
+            raw.iter_mut().for_each(|v| *v = scale(*v as u32));
+        }
+        Ok(raw)
+    } else {
+        let mut raw = vec![0u8; count * 2];
+        reader.read_exact(&mut raw)?;
+        Ok(raw
+            .chunks_exact(2)
+            .map(|b| scale(u16::from_be_bytes([b[0], b[1]]) as u32))
+            .collect())
+    }
+}
+
+/// Reads a PBM, PGM or PPM image, in either binary (`P4`–`P6`) or ASCII
+/// (`P1`–`P3`) form.
+pub fn read_pnm<R: BufRead>(reader: &mut R) -> Result<LoadedImage> {
+    let magic = pnm_token(reader)?;
+    let (kind, ascii) = match magic.as_str() {
+        "P1" => (1, true),
+        "P2" => (2, true),
+        "P3" => (3, true),
+        "P4" => (1, false),
+        "P5" => (2, false),
+        "P6" => (3, false),
+        other => return Err(pnm_error(format!("unsupported magic {other:?}"))),
+    };
+    let width = pnm_number(reader)?;
+    let height = pnm_number(reader)?;
+    if width == 0 || height == 0 {
+        return Err(pnm_error(format!("invalid dimensions {width}x{height}")));
+    }
+    let (w, h) = (width as usize, height as usize);
+
+    if kind == 1 {
+        let mut image = BitImage::new(width, height).map_err(|e| pnm_error(e.to_string()))?;
+        if ascii {
+            for y in 0..h {
+                for x in 0..w {
+                    // P1 allows samples without separating whitespace.
+                    let mut byte = [0u8; 1];
+                    loop {
+                        reader.read_exact(&mut byte)?;
+                        match byte[0] {
+                            b'0' | b'1' => break,
+                            b'#' => {
+                                reader.read_until(b'\n', &mut Vec::new())?;
+                            }
+                            c if c.is_ascii_whitespace() => {}
+                            c => return Err(pnm_error(format!("bad P1 sample {:?}", c as char))),
+                        }
+                    }
+                    image.set_usize(x, y, byte[0] == b'1');
+                }
+            }
+        } else {
+            let row_bytes = w.div_ceil(8);
+            let mut row = vec![0u8; row_bytes];
+            for y in 0..h {
+                reader.read_exact(&mut row)?;
+                for x in 0..w {
+                    if row[x / 8] & (0x80 >> (x % 8)) != 0 {
+                        image.set_usize(x, y, true);
+                    }
+                }
+            }
+        }
+        return Ok(LoadedImage::Bilevel(image));
+    }
+
+    let maxval = pnm_number(reader)?;
+    if maxval == 0 || maxval > 65535 {
+        return Err(pnm_error(format!("invalid maxval {maxval}")));
+    }
+    if kind == 2 {
+        let samples = pnm_samples(reader, w * h, maxval, ascii)?;
+        let data = samples.into_iter().map(GrayPixel::new).collect();
+        Ok(LoadedImage::Gray(Bitmap::from_vec(width, height, data)))
+    } else {
+        let samples = pnm_samples(reader, w * h * 3, maxval, ascii)?;
+        let data = samples
+            .chunks_exact(3)
+            .map(|c| Pixel::new(c[0], c[1], c[2]))
+            .collect();
+        Ok(LoadedImage::Rgb(Pixmap::from_vec(width, height, data)))
+    }
+}
+
+/// Opens and reads a PBM/PGM/PPM file.
+pub fn load_pnm<P: AsRef<Path>>(path: P) -> Result<LoadedImage> {
+    let mut reader = BufReader::new(File::open(path)?);
+    read_pnm(&mut reader)
+}
+
+/// Opens a PBM file as a bilevel image.
+pub fn load_pbm<P: AsRef<Path>>(path: P) -> Result<BitImage> {
+    match load_pnm(path)? {
+        LoadedImage::Bilevel(image) => Ok(image),
+        _ => Err(pnm_error("expected a bilevel (P1/P4) image")),
+    }
+}
+
+/// Opens a PGM file as a grayscale image.
+pub fn load_pgm<P: AsRef<Path>>(path: P) -> Result<Bitmap> {
+    match load_pnm(path)? {
+        LoadedImage::Gray(image) => Ok(image),
+        _ => Err(pnm_error("expected a grayscale (P2/P5) image")),
+    }
+}
+
+/// Opens a PPM file (or any PNM, converted) as an RGB image.
+pub fn load_ppm<P: AsRef<Path>>(path: P) -> Result<Pixmap> {
+    load_pnm(path).map(LoadedImage::into_pixmap)
+}
+
+/// Writes a bilevel image as binary PBM (`P4`).
+pub fn write_pbm<W: Write>(writer: &mut W, image: &BitImage) -> Result<()> {
+    write!(writer, "P4\n{} {}\n", image.width, image.height)?;
+    let mut row = vec![0u8; image.width.div_ceil(8)];
+    for y in 0..image.height {
+        row.fill(0);
+        for x in 0..image.width {
+            if image.get_pixel_unchecked(x, y) {
+                row[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        writer.write_all(&row)?;
+    }
+    Ok(())
+}
+
+/// Writes an image as binary PBM, PGM or PPM (`P4`–`P6`), whichever fits.
+pub fn write_pnm<W: Write>(writer: &mut W, image: &LoadedImage) -> Result<()> {
+    let (magic, data) = match image {
+        LoadedImage::Bilevel(image) => return write_pbm(writer, image),
+        LoadedImage::Gray(image) => ("P5", image.as_raw()),
+        LoadedImage::Rgb(image) => ("P6", image.as_raw()),
+    };
+    let (width, height) = image.dimensions();
+    write!(writer, "{magic}\n{width} {height}\n255\n")?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+/// Iterator over the pages (IFDs) of a TIFF file.
+#[cfg(feature = "tiff")]
+pub struct TiffPages<R: Read + Seek> {
+    decoder: Option<tiff::decoder::Decoder<R>>,
+    first: bool,
+}
+
+#[cfg(feature = "tiff")]
+impl TiffPages<BufReader<File>> {
+    /// Opens a (possibly multi-page) TIFF file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(feature = "tiff")]
+impl<R: Read + Seek> TiffPages<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        let decoder = tiff::decoder::Decoder::new(reader).map_err(tiff_error)?;
+        Ok(Self {
+            decoder: Some(decoder),
+            first: true,
+        })
+    }
+
+    fn decode_current(decoder: &mut tiff::decoder::Decoder<R>) -> Result<LoadedImage> {
+        use tiff::ColorType;
+        use tiff::decoder::DecodingResult;
+
+        let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+        let color = decoder.colortype().map_err(tiff_error)?;
+        let data = match decoder.read_image().map_err(tiff_error)? {
+            DecodingResult::U8(data) => data,
+            DecodingResult::U16(data) => data.into_iter().map(|v| (v >> 8) as u8).collect(),
+            _ => {
+                return Err(DjvuError::InvalidArg(format!(
+                    "TIFF: unsupported sample format for {color:?}"
+                )));
+            }
+        };
+        let (w, h) = (width as usize, height as usize);
+        match color {
+            // Packed rows, already normalized so that 0 is black.
+            ColorType::Gray(1) => {
+                let mut image = BitImage::new(width, height)
+                    .map_err(|e| DjvuError::InvalidArg(e.to_string()))?;
+                let row_bytes = w.div_ceil(8);
+                for y in 0..h {
+                    for x in 0..w {
+                        if data[y * row_bytes + x / 8] & (0x80 >> (x % 8)) == 0 {
+                            image.set_usize(x, y, true);
+                        }
+                    }
+                }
+                Ok(LoadedImage::Bilevel(image))
+            }
+            ColorType::Gray(8 | 16) => Ok(LoadedImage::Gray(Bitmap::from_vec(
+                width,
+                height,
+                data.into_iter().map(GrayPixel::new).collect(),
+            ))),
+            ColorType::RGB(8 | 16) | ColorType::RGBA(8 | 16) => {
+                let step = if matches!(color, ColorType::RGB(_)) {
+                    3
+                } else {
+                    4
+                };
+                let pixels = data
+                    .chunks_exact(step)
+                    .map(|c| Pixel::new(c[0], c[1], c[2]))
+                    .collect();
+                Ok(LoadedImage::Rgb(Pixmap::from_vec(width, height, pixels)))
+            }
+            other => Err(DjvuError::InvalidArg(format!(
+                "TIFF: unsupported color type {other:?}"
+            ))),
+        }
+    }
+}
+
+#[cfg(feature = "tiff")]
+impl<R: Read + Seek> Iterator for TiffPages<R> {
+    type Item = Result<LoadedImage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let decoder = self.decoder.as_mut()?;
+        if !self.first {
+            if !decoder.more_images() {
+                self.decoder = None;
+                return None;
+            }
+            if let Err(e) = decoder.next_image() {
+                self.decoder = None;
+                return Some(Err(tiff_error(e)));
+            }
+        }
+        self.first = false;
+        let page = Self::decode_current(decoder);
+        if page.is_err() {
+            self.decoder = None;
+        }
+        Some(page)
+    }
+}
+
+#[cfg(feature = "tiff")]
+fn tiff_error(e: tiff::TiffError) -> DjvuError {
+    DjvuError::InvalidArg(format!("TIFF: {e}"))
+}
+
+/// Opens a PNG file, expanding palettes and reducing 16-bit samples to 8.
+#[cfg(feature = "png")]
+pub fn load_png<P: AsRef<Path>>(path: P) -> Result<LoadedImage> {
+    let png_error = |e: png::DecodingError| DjvuError::InvalidArg(format!("PNG: {e}"));
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(png_error)?;
+    let size = reader
+        .output_buffer_size()
+        .ok_or_else(|| DjvuError::InvalidArg("PNG: image too large".to_string()))?;
+    let mut buf = vec![0u8; size];
+    let info = reader.next_frame(&mut buf).map_err(png_error)?;
+    buf.truncate(info.buffer_size());
+
+    let (width, height) = (info.width, info.height);
+    let channels = info.color_type.samples();
+    if channels <= 2 {
+        let data = buf
+            .chunks_exact(channels)
+            .map(|c| GrayPixel::new(c[0]))
+            .collect();
+        Ok(LoadedImage::Gray(Bitmap::from_vec(width, height, data)))
+    } else {
+        let data = buf
+            .chunks_exact(channels)
+            .map(|c| Pixel::new(c[0], c[1], c[2]))
+            .collect();
+        Ok(LoadedImage::Rgb(Pixmap::from_vec(width, height, data)))
+    }
+}
+
+/// Writes an image as 8-bit PNG, bilevel images in black and white.
+#[cfg(feature = "png")]
+pub fn write_png<W: Write>(writer: W, image: &LoadedImage) -> Result<()> {
+    let png_error = |e: png::EncodingError| DjvuError::EncodingError(format!("PNG: {e}"));
+    let (width, height) = image.dimensions();
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_depth(png::BitDepth::Eight);
+    let expanded;
+    let data = match image {
+        LoadedImage::Bilevel(image) => {
+            encoder.set_color(png::ColorType::Grayscale);
+            expanded = (0..image.height)
+                .flat_map(|y| (0..image.width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    if image.get_pixel_unchecked(x, y) {
+                        0
+                    } else {
+                        255
+                    }
+                })
+                .collect::<Vec<u8>>();
+            &expanded
+        }
+        LoadedImage::Gray(image) => {
+            encoder.set_color(png::ColorType::Grayscale);
+            image.as_raw()
+        }
+        LoadedImage::Rgb(image) => {
+            encoder.set_color(png::ColorType::Rgb);
+            image.as_raw()
+        }
+    };
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(data).map_err(png_error)?;
+    writer.finish().map_err(png_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_pnm_ascii_and_binary_agree() {
+        let ascii = b"P1\n# comment\n3 2\n1 0 1\n010\n";
+        let mut binary = b"P4\n3 2\n".to_vec();
+        binary.extend_from_slice(&[0b1010_0000, 0b0100_0000]);
+
+        let a = match read_pnm(&mut Cursor::new(&ascii[..])).unwrap() {
+            LoadedImage::Bilevel(img) => img,
+            other => panic!("expected bilevel, got {other:?}"),
+        };
+        let b = match read_pnm(&mut Cursor::new(binary)).unwrap() {
+            LoadedImage::Bilevel(img) => img,
+            other => panic!("expected bilevel, got {other:?}"),
+        };
+        assert_eq!(a, b);
+        assert!(a.get_pixel_unchecked(0, 0) && !a.get_pixel_unchecked(1, 0));
+
+        let mut round_trip = Vec::new();
+        write_pbm(&mut round_trip, &a).unwrap();
+        match read_pnm(&mut Cursor::new(round_trip)).unwrap() {
+            LoadedImage::Bilevel(img) => assert_eq!(img, a),
+            other => panic!("expected bilevel, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_read_pnm_scales_maxval() {
+        let pgm = b"P2 2 1 15\n0 15\n";
+        match read_pnm(&mut Cursor::new(&pgm[..])).unwrap() {
+            LoadedImage::Gray(g) => {
+                assert_eq!(g.get_pixel(0, 0).y, 0);
+                assert_eq!(g.get_pixel(1, 0).y, 255);
+            }
+            other => panic!("expected gray, got {other:?}"),
+        }
+
+        let mut ppm = b"P6\n1 1\n65535\n".to_vec();
+        ppm.extend_from_slice(&[0xff, 0xff, 0x00, 0x00, 0x80, 0x00]);
+        match read_pnm(&mut Cursor::new(ppm)).unwrap() {
+            LoadedImage::Rgb(p) => assert_eq!(p.get_pixel(0, 0), Pixel::new(255, 0, 128)),
+            other => panic!("expected rgb, got {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "tiff")]
+    #[test]
+    fn test_tiff_pages_iterates_all_pages() {
+        use image::ImageEncoder;
+        use image::codecs::tiff::TiffEncoder;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("page.tif");
+        let gray = [0u8, 64, 128, 255];
+        TiffEncoder::new(File::create(&path).unwrap())
+            .write_image(&gray, 2, 2, image::ExtendedColorType::L8)
+            .unwrap();
+
+        let pages: Vec<_> = TiffPages::open(&path)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(pages.len(), 1);
+        match &pages[0] {
+            LoadedImage::Gray(g) => assert_eq!(g.get_pixel(1, 1).y, 255),
+            other => panic!("expected gray, got {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "tiff")]
+    #[test]
+    fn test_tiff_pages_reads_multipage_fixture() {
+        // Three pages of different sizes and kinds: PackBits bilevel (black
+        // where (x + 2y) % 7 == 0, and the whole last row), gray, and RGB.
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/three_pages.tif");
+        let pages: Vec<_> = TiffPages::open(&path)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(pages.len(), 3);
+        match &pages[0] {
+            LoadedImage::Bilevel(b) => {
+                assert_eq!((b.width, b.height), (20, 6));
+                for y in 0..6 {
+                    for x in 0..20 {
+                        let black = y == 5 || (x + 2 * y) % 7 == 0;
+                        assert_eq!(b.get_pixel_unchecked(x, y), black, "({x}, {y})");
+                    }
+                }
+            }
+            other => panic!("expected bilevel, got {other:?}"),
+        }
+        match &pages[1] {
+            LoadedImage::Gray(g) => {
+                assert_eq!(g.dimensions(), (5, 4));
+                assert_eq!(g.get_pixel(4, 3).y, 203);
+            }
+            other => panic!("expected gray, got {other:?}"),
+        }
+        match &pages[2] {
+            LoadedImage::Rgb(p) => {
+                assert_eq!(p.dimensions(), (3, 2));
+                assert_eq!(p.get_pixel(2, 1), Pixel::new(200, 200, 7));
+            }
+            other => panic!("expected rgb, got {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn test_load_png_rgb() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("page.png");
+        image::RgbImage::from_pixel(3, 2, image::Rgb([10, 20, 30]))
+            .save(&path)
+            .unwrap();
+
+        let loaded = load_png(&path).unwrap();
+        assert_eq!(loaded.dimensions(), (3, 2));
+        assert_eq!(loaded.into_pixmap().get_pixel(2, 1), Pixel::new(10, 20, 30));
+
+        let mut bilevel = BitImage::new(3, 2).unwrap();
+        bilevel.set_usize(1, 0, true);
+        let mut png = Vec::new();
+        write_png(&mut png, &LoadedImage::Bilevel(bilevel)).unwrap();
+        std::fs::write(&path, png).unwrap();
+        let written = load_png(&path).unwrap().into_pixmap();
+        assert_eq!(written.get_pixel(1, 0), Pixel::black());
+        assert_eq!(written.get_pixel(2, 1), Pixel::white());
+    }
+}
@@ -1,3 +1,9 @@
+pub mod classify;
+pub mod deskew;
+pub mod fill;
+pub mod filter;
 pub mod geom;
 pub mod image_formats;
 pub mod palette;
+pub mod solid;
+pub mod view;
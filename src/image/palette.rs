@@ -8,6 +8,7 @@
 //!
 //! Your custom NeuQuant implementation is provided as the default `Quantizer`.
 
+use crate::iff::bs_byte_stream::{bzz_compress, bzz_decompress};
 use crate::image::image_formats::{Pixel, Pixmap};
 use crate::utils::error::{DjvuError, Result};
 use bytemuck::{Pod, Zeroable, cast_slice};
@@ -264,16 +265,20 @@ impl Palette {
             }
             U24Helper::write_u24(writer, data_size as u32)?;
 
-            // Write each u16 index in BigEndian
+            // The u16 indices follow in BigEndian, BZZ-compressed like
+            // DjVuPalette does.
+            let mut index_bytes = Vec::with_capacity(data_size * 2);
             for &index in &self.color_indices {
-                writer.write_u16::<BigEndian>(index)?;
+                index_bytes.write_u16::<BigEndian>(index)?;
             }
+            writer.write_all(&bzz_compress(&index_bytes, 50)?)?;
         }
 
         Ok(())
     }
 
-    /// Decodes a palette from the DjVu `FGbz` chunk format. (For completeness)
+    /// Decodes a palette from the DjVu `FGbz` chunk format, with its color
+    /// indices if it has any.
     pub fn decode<R: Read>(reader: &mut R) -> Result<Self> {
         let version = reader.read_u8()?;
         if (version & 0x7F) != 0 {
@@ -289,24 +294,24 @@ impl Palette {
         let bgr_colors: &[BgrColor] = cast_slice(&bgr_bytes);
         let colors: Vec<Pixel> = bgr_colors.iter().map(|&bgr| bgr.into()).collect();
 
-        let mut color_indices = Vec::new();
+        let mut palette = Palette {
+            colors,
+            color_indices: Vec::new(),
+        };
         if (version & 0x80) != 0 {
             let data_size = U24Helper::read_u24(reader)? as usize;
-
-            // Read the byte slice and parse as BigEndian u16
-            let mut index_bytes = vec![0u8; data_size * 2];
-            reader.read_exact(&mut index_bytes)?;
-            let mut cursor = Cursor::new(&index_bytes);
-            for _ in 0..data_size {
-                let index = cursor.read_u16::<BigEndian>()?;
-                color_indices.push(index);
+            let mut compressed = Vec::new();
+            reader.read_to_end(&mut compressed)?;
+            let bytes = bzz_decompress(&compressed)?;
+            if bytes.len() < data_size * 2 {
+                return Err(DjvuError::Stream(format!(
+                    "DjVuPalette holds {} of {data_size} color indices",
+                    bytes.len() / 2
+                )));
             }
+            palette.set_color_indices_from_bytes(&bytes[..data_size * 2])?;
         }
-
-        Ok(Palette {
-            colors,
-            color_indices,
-        })
+        Ok(palette)
     }
 }
 
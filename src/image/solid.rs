@@ -0,0 +1,107 @@
+//! Detecting backgrounds of one flat color.
+//!
+//! A scanned text page often has nothing in its background but the paper.
+//! Wavelet coding such a picture at full resolution still costs a few bytes
+//! per block; [`solid_color`] spots it so the page encoder can write a tiny
+//! solid `BG44` instead.
+
+use crate::encode::jb2::symbol_dict::BitImage;
+use crate::image::image_formats::{Pixel, Pixmap};
+
+/// Tolerances for [`solid_color`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SolidColorParams {
+    /// Largest difference, per channel, between a pixel and the mean color
+    /// for the pixel to count as background.
+    pub tolerance: u8,
+    /// Fraction of pixels allowed outside the tolerance (specks, dust).
+    pub max_outliers: f32,
+}
+
+impl Default for SolidColorParams {
+    fn default() -> Self {
+        Self {
+            tolerance: 8,
+            max_outliers: 0.001,
+        }
+    }
+}
+
+/// The color of `image` if it is essentially uniform by `params`.
+///
+/// Pixels set in `hidden` (a page-sized JB2 mask) are covered by the
+/// foreground and not looked at. Returns the mean of the visible pixels.
+pub fn solid_color(
+    image: &Pixmap,
+    hidden: Option<&BitImage>,
+    params: &SolidColorParams,
+) -> Option<Pixel> {
+    let (w, h) = image.dimensions();
+    let visible = |x: u32, y: u32| {
+        hidden.is_none_or(|mask| {
+            (x as usize) >= mask.width
+                || (y as usize) >= mask.height
+                || !mask.get_pixel_unchecked(x as usize, y as usize)
+        })
+    };
+
+    let (mut sum, mut count) = ([0u64; 3], 0u64);
+    for y in 0..h {
+        for x in (0..w).filter(|&x| visible(x, y)) {
+            let p = image.get_pixel(x, y);
+            sum[0] += p.r as u64;
+            sum[1] += p.g as u64;
+            sum[2] += p.b as u64;
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return None;
+    }
+    let [r, g, b] = sum.map(|c| ((c + count / 2) / count) as u8);
+    let mean = Pixel::new(r, g, b);
+
+    let tolerance = params.tolerance;
+    let mut outliers = 0u64;
+    for y in 0..h {
+        for x in (0..w).filter(|&x| visible(x, y)) {
+            let p = image.get_pixel(x, y);
+            if p.r.abs_diff(mean.r) > tolerance
+                || p.g.abs_diff(mean.g) > tolerance
+                || p.b.abs_diff(mean.b) > tolerance
+            {
+                outliers += 1;
+            }
+        }
+    }
+    (outliers as f64 <= params.max_outliers as f64 * count as f64).then_some(mean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solid_color() {
+        let params = SolidColorParams::default();
+        let mut paper = Pixmap::from_fn(100, 100, |x, y| {
+            let grain = ((x * 7 + y * 13) % 5) as u8;
+            Pixel::new(240 + grain, 236, 228 - grain)
+        });
+        let tint = solid_color(&paper, None, &params).unwrap();
+        assert!(tint.r.abs_diff(242) <= 1 && tint.g == 236 && tint.b.abs_diff(226) <= 1);
+
+        // A photo in the corner is too much to ignore, unless it is hidden.
+        let mut mask = BitImage::new(100, 100).unwrap();
+        for y in 0..20 {
+            for x in 0..20 {
+                paper.put_pixel(x, y, Pixel::new(30, 90, 160));
+            }
+            mask.fill_run(y as usize, 0, 19);
+        }
+        assert_eq!(solid_color(&paper, None, &params), None);
+        assert!(solid_color(&paper, Some(&mask), &params).is_some());
+    }
+}
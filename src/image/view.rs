@@ -0,0 +1,171 @@
+//! Borrowed views of pixel buffers owned by someone else.
+//!
+//! Video decoders and scanner SDKs hand out frames in their own buffers,
+//! usually with padding at the end of each row and often in BGR or with an
+//! alpha channel. An [`ImageView`] describes such a buffer without copying
+//! it, so a frame of a few hundred megabytes can go to
+//! [`IWEncoder::from_view`](crate::encode::iw44::IWEncoder::from_view) as is.
+
+use crate::utils::error::{DjvuError, Result};
+
+/// Layout of the pixels in an [`ImageView`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// One byte of luminance per pixel.
+    Gray8,
+    /// Red, green, blue.
+    Rgb8,
+    /// Red, green, blue, then a byte that is ignored (alpha or padding).
+    Rgba8,
+    /// Blue, green, red.
+    Bgr8,
+    /// Blue, green, red, then a byte that is ignored.
+    Bgra8,
+}
+
+impl PixelFormat {
+    /// Bytes per pixel.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Gray8 => 1,
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 => 3,
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+        }
+    }
+
+    /// Whether the format carries color.
+    pub fn is_color(self) -> bool {
+        self != PixelFormat::Gray8
+    }
+}
+
+/// A borrowed image: `height` rows of `width` pixels, `stride` bytes apart,
+/// top row first.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageView<'a> {
+    data: &'a [u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    format: PixelFormat,
+}
+
+impl<'a> ImageView<'a> {
+    /// Wraps `data`, checking that the rows fit in it. The last row need not
+    /// be padded to a full `stride`.
+    pub fn new(
+        data: &'a [u8],
+        width: u32,
+        height: u32,
+        stride: usize,
+        format: PixelFormat,
+    ) -> Result<Self> {
+        let row_len = width as usize * format.bytes_per_pixel();
+        if stride < row_len {
+            return Err(DjvuError::InvalidArg(format!(
+                "Stride {stride} is shorter than a row of {width} {format:?} pixels"
+            )));
+        }
+        let needed = match height {
+            0 => 0,
+            h => (h as usize - 1) * stride + row_len,
+        };
+        if data.len() < needed {
+            return Err(DjvuError::InvalidArg(format!(
+                "A {width}x{height} {format:?} image with stride {stride} needs {needed} bytes, got {}",
+                data.len()
+            )));
+        }
+        Ok(Self {
+            data,
+            width,
+            height,
+            stride,
+            format,
+        })
+    }
+
+    /// A view of a tightly packed buffer.
+    pub fn packed(data: &'a [u8], width: u32, height: u32, format: PixelFormat) -> Result<Self> {
+        Self::new(
+            data,
+            width,
+            height,
+            width as usize * format.bytes_per_pixel(),
+            format,
+        )
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Row `y`, without the padding after it.
+    pub fn row(&self, y: u32) -> &'a [u8] {
+        let start = y as usize * self.stride;
+        &self.data[start..start + self.width as usize * self.format.bytes_per_pixel()]
+    }
+
+    /// The rows, top first, without padding.
+    pub fn rows(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        (0..self.height).map(|y| self.row(y))
+    }
+
+    /// Writes `row` (a row of this view) into `out` as gray bytes, if the
+    /// format is [`PixelFormat::Gray8`], or as RGB triples otherwise.
+    pub fn unpack_row(&self, row: &[u8], out: &mut Vec<u8>) {
+        out.clear();
+        let bpp = self.format.bytes_per_pixel();
+        match self.format {
+            PixelFormat::Gray8 | PixelFormat::Rgb8 => out.extend_from_slice(row),
+            PixelFormat::Rgba8 => {
+                for px in row.chunks_exact(bpp) {
+                    out.extend_from_slice(&px[..3]);
+                }
+            }
+            PixelFormat::Bgr8 | PixelFormat::Bgra8 => {
+                for px in row.chunks_exact(bpp) {
+                    out.extend_from_slice(&[px[2], px[1], px[0]]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strided_view() {
+        // Two rows of two BGRA pixels, each row padded to 12 bytes; the
+        // last row is cut short after its pixels.
+        let data = [
+            1, 2, 3, 255, 4, 5, 6, 255, 0, 0, 0, 0, //
+            7, 8, 9, 255, 10, 11, 12, 255,
+        ];
+        let view = ImageView::new(&data, 2, 2, 12, PixelFormat::Bgra8).unwrap();
+        assert_eq!(view.rows().count(), 2);
+        assert_eq!(view.row(1), &data[12..20]);
+
+        let mut rgb = Vec::new();
+        view.unpack_row(view.row(1), &mut rgb);
+        assert_eq!(rgb, [9, 8, 7, 12, 11, 10]);
+
+        assert!(ImageView::new(&data, 2, 2, 7, PixelFormat::Bgra8).is_err());
+        assert!(ImageView::new(&data, 2, 3, 12, PixelFormat::Bgra8).is_err());
+        assert!(ImageView::packed(&data, 5, 1, PixelFormat::Rgb8).is_ok());
+    }
+}
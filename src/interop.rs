@@ -0,0 +1,303 @@
+//! Round-trip checks against the DjVuLibre command-line tools.
+//!
+//! When `djvused` and `ddjvu` are installed, [`check_document`] hands a
+//! document to them (page count and directory listing through `djvused`, a
+//! full decode of every page through `ddjvu`) and collects what they print
+//! on stderr as [`Diagnostic`]s. Tests use it to catch output the reference
+//! decoder rejects; applications can run it on their own documents.
+//!
+//! The tools are looked up in `DJVULIBRE_BIN` if set, then on `PATH`. When
+//! either is missing, [`check_document`] returns `Ok(None)` so callers can
+//! skip instead of fail; [`dump_document`] does the same for `djvudump`.
+
+use crate::{DjvuError, Result};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Locations of the DjVuLibre tools.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tools {
+    pub djvused: PathBuf,
+    pub ddjvu: PathBuf,
+}
+
+impl Tools {
+    /// Finds the tools, or returns `None` if either is missing.
+    pub fn detect() -> Option<Self> {
+        Some(Self {
+            djvused: find_tool("djvused")?,
+            ddjvu: find_tool("ddjvu")?,
+        })
+    }
+}
+
+/// Looks a DjVuLibre tool up in `DJVULIBRE_BIN`, then on `PATH`.
+fn find_tool(name: &str) -> Option<PathBuf> {
+    let file = format!("{name}{}", std::env::consts::EXE_SUFFIX);
+    std::env::var_os("DJVULIBRE_BIN")
+        .into_iter()
+        .map(PathBuf::from)
+        .chain(
+            std::env::var_os("PATH")
+                .iter()
+                .flat_map(std::env::split_paths),
+        )
+        .map(|d| d.join(&file))
+        .find(|p| p.is_file())
+}
+
+/// How serious a tool message is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The tool failed, or reported a DjVuLibre exception (`*** ...`).
+    Error,
+    /// Any other message on stderr.
+    Warning,
+}
+
+/// One message printed by a DjVuLibre tool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// `"djvused"`, `"ddjvu"` or `"djvudump"`.
+    pub tool: &'static str,
+    pub severity: Severity,
+    /// Zero-based page the message came from, for per-page runs.
+    pub page: Option<usize>,
+    /// The message, without the tool name and exception markers.
+    pub message: String,
+}
+
+/// Result of a round trip through the tools.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InteropReport {
+    /// Page count reported by `djvused -e n`.
+    pub pages: Option<usize>,
+    /// Output of `djvused -e ls`, one line per component file.
+    pub listing: Vec<String>,
+    /// Pages `ddjvu` decoded without an error.
+    pub decoded_pages: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl InteropReport {
+    /// True if no tool reported an error.
+    pub fn is_ok(&self) -> bool {
+        !self
+            .diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+
+    /// Iterates over the error diagnostics.
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+    }
+}
+
+/// Checks `data` with the tools found by [`Tools::detect`].
+///
+/// Returns `Ok(None)` if the tools are not installed.
+pub fn check_document(data: &[u8]) -> Result<Option<InteropReport>> {
+    match Tools::detect() {
+        Some(tools) => check_document_with(&tools, data).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Checks `data` with the given tools.
+pub fn check_document_with(tools: &Tools, data: &[u8]) -> Result<InteropReport> {
+    let dir = ScratchDir::new()?;
+    let input = dir.path().join("input.djvu");
+    std::fs::write(&input, data)?;
+
+    let mut report = InteropReport::default();
+
+    let out = run(Command::new(&tools.djvused).arg("-e").arg("n").arg(&input))?;
+    report
+        .diagnostics
+        .extend(parse_diagnostics("djvused", None, &out));
+    report.pages = String::from_utf8_lossy(&out.stdout).trim().parse().ok();
+
+    let out = run(Command::new(&tools.djvused).arg("-e").arg("ls").arg(&input))?;
+    report
+        .diagnostics
+        .extend(parse_diagnostics("djvused", None, &out));
+    report.listing = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect();
+
+    let output = dir.path().join("page.pnm");
+    for page in 0..report.pages.unwrap_or(0) {
+        let out = run(Command::new(&tools.ddjvu)
+            .arg("-format=pnm")
+            .arg(format!("-page={}", page + 1))
+            .arg(&input)
+            .arg(&output))?;
+        let diagnostics = parse_diagnostics("ddjvu", Some(page), &out);
+        if !diagnostics.iter().any(|d| d.severity == Severity::Error) {
+            report.decoded_pages += 1;
+        }
+        report.diagnostics.extend(diagnostics);
+    }
+    Ok(report)
+}
+
+/// Returns the chunk listing `djvudump` prints for `data`, or `Ok(None)` if
+/// it is not installed.
+pub fn dump_document(data: &[u8]) -> Result<Option<String>> {
+    let Some(djvudump) = find_tool("djvudump") else {
+        return Ok(None);
+    };
+    let dir = ScratchDir::new()?;
+    let input = dir.path().join("input.djvu");
+    std::fs::write(&input, data)?;
+    let out = run(Command::new(djvudump).arg(&input))?;
+    if let Some(error) = parse_diagnostics("djvudump", None, &out)
+        .into_iter()
+        .find(|d| d.severity == Severity::Error)
+    {
+        return Err(DjvuError::ValidationError(error.message));
+    }
+    Ok(Some(String::from_utf8_lossy(&out.stdout).into_owned()))
+}
+
+fn run(command: &mut Command) -> Result<Output> {
+    command
+        .output()
+        .map_err(|e| DjvuError::Custom(format!("Failed to run {:?}: {e}", command.get_program())))
+}
+
+/// Turns a tool's stderr into diagnostics.
+///
+/// DjVuLibre prints exceptions as `*** [1-11711] message` and other messages
+/// prefixed with the tool name. A failed run with nothing on stderr still
+/// yields an error.
+pub fn parse_diagnostics(tool: &'static str, page: Option<usize>, out: &Output) -> Vec<Diagnostic> {
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let mut diagnostics: Vec<Diagnostic> = stderr
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (severity, rest) = match line.strip_prefix("***") {
+                Some(rest) => (Severity::Error, rest.trim_start()),
+                None => (Severity::Warning, line),
+            };
+            let rest = rest
+                .strip_prefix(tool)
+                .and_then(|r| r.strip_prefix(':'))
+                .unwrap_or(rest)
+                .trim_start();
+            // Drop the `[1-11711]` source location DjVuLibre adds.
+            let message = match rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                Some((_, msg)) => msg.trim_start(),
+                None => rest,
+            };
+            Diagnostic {
+                tool,
+                severity,
+                page,
+                message: message.to_string(),
+            }
+        })
+        .collect();
+
+    if !out.status.success() {
+        match diagnostics.last_mut() {
+            Some(last) => last.severity = Severity::Error,
+            None => diagnostics.push(Diagnostic {
+                tool,
+                severity: Severity::Error,
+                page,
+                message: format!("exited with {}", out.status),
+            }),
+        }
+    }
+    diagnostics
+}
+
+/// A temporary directory removed on drop.
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn new() -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "djvu_interop_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    fn output(code: i32, stderr: &str) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+        Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_diagnostics() {
+        let out = output(
+            1,
+            "ddjvu: Corrupted chunk\n*** [1-11711] Unexpected End Of File.\n",
+        );
+        let diags = parse_diagnostics("ddjvu", Some(2), &out);
+        assert_eq!(diags.len(), 2);
+        assert_eq!(diags[0].severity, Severity::Warning);
+        assert_eq!(diags[0].message, "Corrupted chunk");
+        assert_eq!(diags[1].severity, Severity::Error);
+        assert_eq!(diags[1].message, "Unexpected End Of File.");
+        assert_eq!(diags[1].page, Some(2));
+
+        let silent = parse_diagnostics("djvused", None, &output(10, ""));
+        assert_eq!(silent[0].severity, Severity::Error);
+        assert!(parse_diagnostics("djvused", None, &output(0, "")).is_empty());
+    }
+
+    #[test]
+    fn test_round_trip_when_tools_installed() -> Result<()> {
+        use crate::{DjvuBuilder, PageBuilder, Pixel, Pixmap};
+
+        let doc = DjvuBuilder::new(2).build();
+        for i in 0..2 {
+            doc.add_page(
+                PageBuilder::new(i, 64, 64)
+                    .with_background(Pixmap::from_pixel(64, 64, Pixel::white()))?
+                    .build()?,
+            )?;
+        }
+        let Some(report) = check_document(&doc.finalize()?)? else {
+            return Ok(());
+        };
+        assert!(report.is_ok(), "{:?}", report.diagnostics);
+        assert_eq!(report.pages, Some(2));
+        assert_eq!(report.decoded_pages, 2);
+        Ok(())
+    }
+}
@@ -1,11 +1,14 @@
 // portable_simd feature - only enable when the feature flag is set
 #![cfg_attr(feature = "portable_simd", feature(portable_simd))]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! A Rust library for encoding DjVu documents.
 //!
 //! This crate provides a high-level builder API for creating multi-page DjVu documents
 //! from image data with coordinate-based layer positioning.
 //!
+//! The supported types are gathered in [`prelude`].
+//!
 //! # Quick Start
 //!
 //! ```ignore
@@ -38,30 +41,55 @@
 //! - **Thread-safe**: Safe to use from multiple threads
 //! - **Automatic masking**: Handles JB2/IW44 layer overlaps
 //! - **Optional parallelism**: Enable `rayon` feature for parallel encoding
+//! - **`no_std` codec core**: With `default-features = false` only the ZP
+//!   coder (`encode::zc`), the JB2 encoder (`encode::jb2::JB2Encoder`)
+//!   and the IW44 wavelet transform are built, on `core` and `alloc`, for
+//!   producing page data on devices without an operating system
 //!
 //! # Image Formats
 //!
 //! - **Pixmap (RGB/grayscale)**: For IW44 background layers (photos, scans)
 //! - **Bitmap (bilevel)**: For JB2 foreground layers (text, graphics)
 
+extern crate alloc;
+
 // Core modules
+#[cfg(feature = "std")]
 pub mod annotations;
+#[cfg(feature = "std")]
 pub mod doc;
+// Codec internals, public for the tests, benchmarks and examples; the
+// supported surface is the prelude.
+#[doc(hidden)]
 pub mod encode;
+#[cfg(feature = "std")]
+#[doc(hidden)]
 pub mod iff;
+#[cfg(feature = "std")]
+#[doc(hidden)]
 pub mod image;
 pub mod utils;
 
+#[cfg(feature = "interop")]
+pub mod interop;
+
+#[cfg(feature = "std")]
+pub mod prelude;
+
 // Public builder API
+#[cfg(feature = "std")]
 pub use doc::{DjvuBuilder, DjvuDocument, ImageLayer, LayerData, Page, PageBuilder};
 
 // Advanced types (for custom encoding workflows)
+#[cfg(feature = "std")]
 pub use doc::{PageComponents, PageEncodeParams};
 
 // Image types
+#[cfg(feature = "std")]
 pub use image::image_formats::{Bitmap, GrayPixel, Pixel, Pixmap};
 
 // Error types
+#[cfg(feature = "std")]
 pub use utils::error::{DjvuError, Result};
 
 // Constants
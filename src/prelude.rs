@@ -0,0 +1,25 @@
+//! The supported API in one import.
+//!
+//! ```ignore
+//! use djvu_encoder::prelude::*;
+//! ```
+//!
+//! brings in what building, encoding and editing documents needs. Items
+//! here follow semantic versioning: they are not removed or changed
+//! incompatibly before the next major version. The module tree behind them,
+//! and in particular the codec internals under `encode`, `iff` and `image`,
+//! are hidden from the documentation and may be reorganized between minor
+//! versions.
+
+pub use crate::annotations::{Annotations, HiddenText, Hyperlink};
+pub use crate::doc::{
+    Bookmark, DjVmNav, DjVuDocEditor, DjvuBuilder, DjvuDocument, EncodeStats, Page, PageBuilder,
+    PageComponents, PageEncodeParams, PageRotation, Profile,
+};
+pub use crate::encode::jb2::BitImage;
+#[cfg(feature = "tiff")]
+pub use crate::image::image_formats::TiffPages;
+#[cfg(feature = "png")]
+pub use crate::image::image_formats::load_png;
+pub use crate::image::image_formats::{Bitmap, GrayPixel, LoadedImage, Pixel, Pixmap, load_pnm};
+pub use crate::utils::error::{DjvuError, Result};
@@ -1,5 +1,6 @@
 // Color checker utility for verifying DjVu encoding/decoding accuracy
 
+use crate::utils::log::info;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read};
@@ -280,9 +281,10 @@ pub fn check_solid_color<P: AsRef<Path>>(
     let ppm_data = read_ppm(ppm_path)?;
     let analysis = analyze_colors(&ppm_data);
 
-    println!("Image dimensions: {}x{}", ppm_data.width, ppm_data.height);
-    println!("Total pixels: {}", analysis.total_pixels);
-    println!("Unique colors: {}", analysis.unique_colors);
+    info!(
+        "Image dimensions: {}x{}, {} pixels, {} unique colors",
+        ppm_data.width, ppm_data.height, analysis.total_pixels, analysis.unique_colors
+    );
 
     let result = analysis.check_expected_color(&expected_color, tolerance);
     result.print_result();
@@ -0,0 +1,243 @@
+//! Process-wide configuration for the encoder.
+//!
+//! Most settings in this crate are passed explicitly (see `DjvuBuilder` and
+//! `PageEncodeParams`). A handful of knobs, however, are properties of the
+//! host process rather than of any one document: how much memory the encoder
+//! may hold on to, where scratch files go, how many worker threads to use.
+//! Those live here.
+//!
+//! Configuration is optional. Without a call to [`init`], [`config`] returns
+//! [`GlobalConfig::default`].
+//!
+//! ```
+//! use djvu_encoder::utils::djvu_global::{self, GlobalConfig};
+//!
+//! let config = GlobalConfig::builder()
+//!     .with_memory_limit(256 * 1024 * 1024)
+//!     .with_default_dpi(600)
+//!     .build()
+//!     .unwrap();
+//! djvu_global::init(config).unwrap();
+//! assert_eq!(djvu_global::config().default_dpi(), 600);
+//! ```
+
+use crate::utils::error::{DjvuError, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// Controls how encoded pages are retained until the document is finalized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// Maximum number of encoded pages kept in memory, or `None` for no limit.
+    pub max_cached_pages: Option<usize>,
+    /// When a limit is reached, move encoded pages to a temporary file
+    /// instead of failing.
+    pub spill_to_disk: bool,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self {
+            max_cached_pages: None,
+            spill_to_disk: true,
+        }
+    }
+}
+
+/// Process-wide encoder settings. Build one with [`GlobalConfig::builder`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalConfig {
+    memory_limit: Option<usize>,
+    default_dpi: u32,
+    default_gamma: f32,
+    temp_dir: Option<PathBuf>,
+    thread_count: Option<usize>,
+    cache_policy: CachePolicy,
+}
+
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        Self {
+            memory_limit: None,
+            default_dpi: 300,
+            default_gamma: 2.2,
+            temp_dir: None,
+            thread_count: None,
+            cache_policy: CachePolicy::default(),
+        }
+    }
+}
+
+impl GlobalConfig {
+    /// Starts a builder initialized with the default settings.
+    pub fn builder() -> GlobalConfigBuilder {
+        GlobalConfigBuilder {
+            config: GlobalConfig::default(),
+        }
+    }
+
+    /// Approximate upper bound, in bytes, on memory held by the encoder.
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// DPI used by `DjvuBuilder` when none is given.
+    pub fn default_dpi(&self) -> u32 {
+        self.default_dpi
+    }
+
+    /// Gamma used by `DjvuBuilder` when none is given.
+    pub fn default_gamma(&self) -> f32 {
+        self.default_gamma
+    }
+
+    /// Directory for scratch files; falls back to the system temp directory.
+    pub fn temp_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Requested number of worker threads, or `None` to let the runtime decide.
+    pub fn thread_count(&self) -> Option<usize> {
+        self.thread_count
+    }
+
+    /// Retention policy for encoded pages.
+    pub fn cache_policy(&self) -> &CachePolicy {
+        &self.cache_policy
+    }
+}
+
+/// Builder for [`GlobalConfig`].
+#[derive(Debug, Clone)]
+pub struct GlobalConfigBuilder {
+    config: GlobalConfig,
+}
+
+impl GlobalConfigBuilder {
+    /// Caps the memory, in bytes, the encoder should hold at once.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.config.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Sets the DPI new documents start with.
+    pub fn with_default_dpi(mut self, dpi: u32) -> Self {
+        self.config.default_dpi = dpi;
+        self
+    }
+
+    /// Sets the gamma new documents start with.
+    pub fn with_default_gamma(mut self, gamma: f32) -> Self {
+        self.config.default_gamma = gamma;
+        self
+    }
+
+    /// Sets the directory used for scratch files.
+    pub fn with_temp_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.config.temp_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the number of worker threads (only meaningful with `rayon`).
+    pub fn with_thread_count(mut self, threads: usize) -> Self {
+        self.config.thread_count = Some(threads);
+        self
+    }
+
+    /// Sets the retention policy for encoded pages.
+    pub fn with_cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.config.cache_policy = policy;
+        self
+    }
+
+    /// Validates the settings and returns the finished configuration.
+    pub fn build(self) -> Result<GlobalConfig> {
+        let c = &self.config;
+        // The INFO chunk stores DPI as a u16 and gamma as a byte in tenths.
+        if c.default_dpi == 0 || c.default_dpi > u16::MAX as u32 {
+            return Err(DjvuError::InvalidArg(format!(
+                "default DPI {} out of range 1..=65535",
+                c.default_dpi
+            )));
+        }
+        if !(0.3..=25.5).contains(&c.default_gamma) {
+            return Err(DjvuError::InvalidArg(format!(
+                "default gamma {} out of range 0.3..=25.5",
+                c.default_gamma
+            )));
+        }
+        if c.thread_count == Some(0) {
+            return Err(DjvuError::InvalidArg(
+                "thread count must be at least 1".to_string(),
+            ));
+        }
+        if c.memory_limit == Some(0) {
+            return Err(DjvuError::InvalidArg(
+                "memory limit must be non-zero".to_string(),
+            ));
+        }
+        Ok(self.config)
+    }
+}
+
+fn global() -> &'static RwLock<Arc<GlobalConfig>> {
+    static GLOBAL: OnceLock<RwLock<Arc<GlobalConfig>>> = OnceLock::new();
+    GLOBAL.get_or_init(|| RwLock::new(Arc::new(GlobalConfig::default())))
+}
+
+/// Installs `config` as the process-wide configuration.
+///
+/// May be called more than once; later calls replace earlier ones, except
+/// for the thread count, which the `rayon` global pool only accepts once.
+pub fn init(config: GlobalConfig) -> Result<()> {
+    #[cfg(feature = "rayon")]
+    if let Some(threads) = config.thread_count {
+        // The pool may already have been started; that is not an error here.
+        let _ = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global();
+    }
+
+    let mut guard = global()
+        .write()
+        .map_err(|_| DjvuError::InvalidOperation("global config lock poisoned".to_string()))?;
+    *guard = Arc::new(config);
+    Ok(())
+}
+
+/// Returns the current process-wide configuration.
+pub fn config() -> Arc<GlobalConfig> {
+    match global().read() {
+        Ok(guard) => Arc::clone(&guard),
+        Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_rejects_invalid_values() {
+        assert!(GlobalConfig::builder().with_default_dpi(0).build().is_err());
+        assert!(
+            GlobalConfig::builder()
+                .with_default_gamma(30.0)
+                .build()
+                .is_err()
+        );
+        assert!(
+            GlobalConfig::builder()
+                .with_thread_count(0)
+                .build()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_builder_defaults() {
+        let config = GlobalConfig::builder().build().unwrap();
+        assert_eq!(config, GlobalConfig::default());
+        assert_eq!(config.temp_dir(), std::env::temp_dir());
+    }
+}
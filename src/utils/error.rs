@@ -19,6 +19,8 @@ pub enum DjvuError {
     Custom(String),
     /// An encoding/decoding error occurred
     EncodingError(String),
+    /// A JB2 symbol or blit lies outside the coordinates JB2 can code
+    SymbolOutOfRange(String),
 }
 
 impl fmt::Display for DjvuError {
@@ -31,6 +33,7 @@ impl fmt::Display for DjvuError {
             DjvuError::Stream(msg) => write!(f, "Stream error: {}", msg),
             DjvuError::Custom(msg) => write!(f, "Error: {}", msg),
             DjvuError::EncodingError(msg) => write!(f, "Encoding error: {}", msg),
+            DjvuError::SymbolOutOfRange(msg) => write!(f, "Symbol out of range: {}", msg),
         }
     }
 }
@@ -52,7 +55,12 @@ impl From<io::Error> for DjvuError {
 
 impl From<crate::encode::jb2::error::Jb2Error> for DjvuError {
     fn from(err: crate::encode::jb2::error::Jb2Error) -> Self {
-        DjvuError::EncodingError(err.to_string())
+        match err {
+            crate::encode::jb2::error::Jb2Error::SymbolOutOfRange(msg) => {
+                DjvuError::SymbolOutOfRange(msg)
+            }
+            err => DjvuError::EncodingError(err.to_string()),
+        }
     }
 }
 
@@ -65,6 +73,18 @@ impl From<crate::encode::zc::ZCodecError> for DjvuError {
 /// A specialized `Result` type for DjVu encoding operations.
 pub type Result<T> = std::result::Result<T, DjvuError>;
 
+/// Converts an I/O result into a [`Result`], naming what the failed
+/// operation was working on (usually a file path) in the error message.
+pub(crate) trait IoContext<T> {
+    fn context(self, what: impl fmt::Display) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn context(self, what: impl fmt::Display) -> Result<T> {
+        self.map_err(|err| DjvuError::Io(io::Error::new(err.kind(), format!("{what}: {err}"))))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -102,4 +122,16 @@ mod tests {
             "Error: test"
         );
     }
+
+    #[test]
+    fn test_io_context() {
+        let result: io::Result<()> = Err(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        match result.context("scans/p1.pbm") {
+            Err(DjvuError::Io(err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::NotFound);
+                assert_eq!(err.to_string(), "scans/p1.pbm: missing");
+            }
+            other => panic!("expected an I/O error, got {other:?}"),
+        }
+    }
 }
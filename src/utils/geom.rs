@@ -0,0 +1,43 @@
+//! Page rectangles shared by the document layer and the codecs.
+
+/// A rectangle of whole pixels on a page, `x` and `y` being its corner
+/// nearest the origin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn from_dimensions(width: u32, height: u32) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// The part of this rect inside a `width`x`height` page, with a zero
+    /// size when it lies outside.
+    pub fn clipped_to(&self, width: u32, height: u32) -> Self {
+        let (x, y) = (self.x.min(width), self.y.min(height));
+        Self {
+            x,
+            y,
+            width: self.x.saturating_add(self.width).min(width) - x,
+            height: self.y.saturating_add(self.height).min(height) - y,
+        }
+    }
+}
@@ -1,33 +1,155 @@
-//! Simple logging utilities for the DjVu library.
+//! Logging utilities for the DjVu library.
 //!
-//! Re-exports the `log` crate for structured logging.
+//! The library logs through the `tracing` crate. Each codec subsystem emits
+//! under its own target (see [`target`]) so that output can be narrowed down
+//! to, say, only the JB2 encoder while debugging.
 //!
 //! # Usage
 //!
-//! Use the standard log macros throughout the code:
-//! `trace!`, `debug!`, `info!`, `warn!`, `error!`.
+//! Inside the crate, use the tracing macros with a subsystem target:
 //!
-//! Example:
 //! ```
-//! use log::debug;
+//! use djvu_encoder::utils::log::{debug, target};
 //!
 //! fn my_function(arg: i32) {
-//!     debug!("Starting computation with argument: {}", arg);
-//!     // ...
+//!     debug!(target: target::IW44, "Starting computation with argument: {}", arg);
 //! }
 //! ```
+//!
+//! Applications that already install a subscriber need do nothing else;
+//! [`expand_aliases`] turns the short subsystem names into filter
+//! directives they can use. Applications that don't can opt in to a stderr
+//! subscriber with [`init_logging`] (feature `logging`), which reads its
+//! filter from the `DJVU_LOG` environment variable (falling back to
+//! `RUST_LOG`):
+//!
+//! ```text
+//! DJVU_LOG=warn,jb2=debug,iw44=trace
+//! ```
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub use tracing::level_filters::LevelFilter;
+pub use tracing::{Level, debug, error, info, trace, warn};
+
+/// Log targets used by the individual subsystems.
+///
+/// In filter directives these may be referred to either by their full name
+/// or by the short alias after the `::` (e.g. `jb2=debug`).
+pub mod target {
+    /// IW44 wavelet encoder (BG44/FG44/PM44/BM44).
+    pub const IW44: &str = "djvu_encoder::iw44";
+    /// JB2 bilevel encoder and connected-component analysis.
+    pub const JB2: &str = "djvu_encoder::jb2";
+    /// ZP arithmetic coder.
+    pub const ZP: &str = "djvu_encoder::zp";
+    /// BZZ (BWT + ZP) general-purpose compressor.
+    pub const BZZ: &str = "djvu_encoder::bzz";
+    /// Document assembly: pages, directories, IFF output.
+    pub const DOC: &str = "djvu_encoder::doc";
+
+    pub(crate) const ALL: [&str; 5] = [IW44, JB2, ZP, BZZ, DOC];
+}
+
+/// Environment variable consulted by [`init_logging`].
+pub const LOG_ENV_VAR: &str = "DJVU_LOG";
+
+/// Rewrites the subsystem aliases (`iw44`, `jb2`, `zp`, `bzz`, `doc`) in a
+/// comma-separated filter to their full targets, so `jb2=debug` becomes
+/// `djvu_encoder::jb2=debug`. Other directives are kept as they are.
+pub fn expand_aliases(spec: &str) -> String {
+    let expand = |name: &str| {
+        target::ALL
+            .iter()
+            .find(|t| t.rsplit("::").next() == Some(name))
+            .copied()
+    };
+    spec.split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|directive| {
+            let (name, level) = match directive.split_once('=') {
+                Some((name, level)) => (name.trim(), Some(level.trim())),
+                None => (directive, None),
+            };
+            match (expand(name), level) {
+                (Some(full), Some(level)) => alloc::format!("{full}={level}"),
+                (Some(full), None) => full.into(),
+                (None, _) => directive.into(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
 
-pub use log::{Level, debug, error, info, trace, warn};
+/// Builds a filter from `spec` after [`expand_aliases`], showing warnings
+/// from targets it does not name.
+#[cfg(feature = "logging")]
+pub fn env_filter(spec: &str) -> tracing_subscriber::EnvFilter {
+    tracing_subscriber::EnvFilter::builder()
+        .with_default_directive(LevelFilter::WARN.into())
+        .parse_lossy(expand_aliases(spec))
+}
 
-/// Initialize logging (no-op, applications should set up their own logger).
+/// Installs a stderr subscriber with the given filter.
 ///
-/// Applications using this library should initialize their own logging backend.
-/// For example, with `env_logger`:
+/// Fails if another subscriber has already been installed for this process.
+#[cfg(feature = "logging")]
+pub fn init_with_filter(
+    filter: tracing_subscriber::EnvFilter,
+) -> Result<(), tracing_subscriber::util::TryInitError> {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .try_init()
+}
+
+/// Installs a stderr subscriber, configured from `DJVU_LOG`/`RUST_LOG`.
+#[cfg(feature = "logging")]
+pub fn init_logging() -> Result<(), tracing_subscriber::util::TryInitError> {
+    let spec = std::env::var(LOG_ENV_VAR)
+        .or_else(|_| std::env::var("RUST_LOG"))
+        .unwrap_or_default();
+    init_with_filter(env_filter(&spec))
+}
+
+/// Installs a stderr subscriber at `max_level` for every target.
 ///
-/// ```ignore
-/// env_logger::init();
-/// ```
-pub fn init_subscriber(_max_level: Level) {
-    // No-op - applications should initialize their own logging backend
-    // This function is kept for API compatibility
+/// An explicit `DJVU_LOG` filter in the environment takes precedence. Does
+/// nothing if the application already set up its own subscriber, or
+/// without the `logging` feature.
+pub fn init_subscriber(max_level: Level) {
+    #[cfg(feature = "logging")]
+    {
+        let spec = std::env::var(LOG_ENV_VAR).unwrap_or_else(|_| max_level.as_str().into());
+        let _ = init_with_filter(env_filter(&spec));
+    }
+    #[cfg(not(feature = "logging"))]
+    let _ = max_level;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_aliases() {
+        assert_eq!(
+            expand_aliases("info, jb2=trace,djvu_encoder::zp=off,doc"),
+            "info,djvu_encoder::jb2=trace,djvu_encoder::zp=off,djvu_encoder::doc"
+        );
+        assert_eq!(expand_aliases("docx=debug"), "docx=debug");
+    }
+
+    #[cfg(feature = "logging")]
+    #[test]
+    fn test_env_filter_levels() {
+        let filter = env_filter("info,jb2=trace,zp=off");
+        assert_eq!(filter.max_level_hint(), Some(LevelFilter::TRACE));
+        assert_eq!(env_filter("").max_level_hint(), Some(LevelFilter::WARN));
+    }
 }
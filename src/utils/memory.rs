@@ -0,0 +1,163 @@
+//! Approximate memory accounting for the encoder.
+//!
+//! The numbers tracked here are estimates, not allocator statistics: large
+//! consumers (coefficient maps, encoded page buffers waiting for assembly)
+//! report what they hold, and a [`MemoryTracker`] decides whether a new
+//! reservation fits under the cap configured in
+//! [`GlobalConfig`](crate::utils::djvu_global::GlobalConfig).
+
+use crate::utils::djvu_global;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Shared counter of approximate bytes in use, with an optional cap.
+#[derive(Debug, Default)]
+pub struct MemoryTracker {
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    limit: Option<usize>,
+}
+
+impl MemoryTracker {
+    /// Creates a tracker with the given cap (`None` for unlimited).
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            limit,
+        }
+    }
+
+    /// Creates a tracker using the cap from the global configuration.
+    pub fn from_global() -> Self {
+        Self::new(djvu_global::config().memory_limit())
+    }
+
+    /// The configured cap, if any.
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Bytes currently accounted for.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Highest value `used` has reached.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Reserves `bytes` only if doing so keeps usage under the cap.
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        let mut current = self.used.load(Ordering::Relaxed);
+        loop {
+            let next = current.saturating_add(bytes);
+            if self.limit.is_some_and(|limit| next > limit) {
+                return false;
+            }
+            match self.used.compare_exchange_weak(
+                current,
+                next,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.peak.fetch_max(next, Ordering::Relaxed);
+                    return true;
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Reserves `bytes` regardless of the cap.
+    pub fn reserve(&self, bytes: usize) {
+        let next = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(next, Ordering::Relaxed);
+    }
+
+    /// Returns `bytes` previously reserved.
+    pub fn release(&self, bytes: usize) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |u| {
+                Some(u.saturating_sub(bytes))
+            });
+    }
+
+    /// Reserves `bytes` for the lifetime of the returned guard.
+    pub fn scoped(self: &Arc<Self>, bytes: usize) -> MemoryReservation {
+        self.reserve(bytes);
+        MemoryReservation {
+            tracker: Arc::clone(self),
+            bytes,
+        }
+    }
+}
+
+/// A reservation that is released when dropped.
+#[derive(Debug)]
+pub struct MemoryReservation {
+    tracker: Arc<MemoryTracker>,
+    bytes: usize,
+}
+
+impl MemoryReservation {
+    /// Size of this reservation in bytes.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.tracker.release(self.bytes);
+    }
+}
+
+/// Approximate working set of an IW44 encoder for a `width`×`height` image.
+///
+/// Each plane keeps an `i16` coefficient map padded to 32×32 blocks, plus a
+/// bucket-state byte per coefficient in the codec.
+pub fn iw44_encoder_bytes(width: u32, height: u32, color: bool) -> usize {
+    let bw = (width as usize).div_ceil(32) * 32;
+    let bh = (height as usize).div_ceil(32) * 32;
+    let per_plane = bw * bh * (std::mem::size_of::<i16>() + 1);
+    if color { per_plane * 3 } else { per_plane }
+}
+
+/// Approximate working set of JB2 analysis for a `width`×`height` bitmap.
+///
+/// The packed input plus the run list, which in practice stays within a
+/// small multiple of the packed size.
+pub fn jb2_encoder_bytes(width: u32, height: u32) -> usize {
+    let packed = (width as usize).div_ceil(8) * height as usize;
+    packed * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_reserve_respects_limit() {
+        let tracker = MemoryTracker::new(Some(100));
+        assert!(tracker.try_reserve(60));
+        assert!(!tracker.try_reserve(60));
+        tracker.release(60);
+        assert!(tracker.try_reserve(100));
+        assert_eq!(tracker.peak(), 100);
+    }
+
+    #[test]
+    fn test_scoped_reservation_releases_on_drop() {
+        let tracker = Arc::new(MemoryTracker::new(None));
+        {
+            let _guard = tracker.scoped(1024);
+            assert_eq!(tracker.used(), 1024);
+        }
+        assert_eq!(tracker.used(), 0);
+        assert_eq!(tracker.peak(), 1024);
+    }
+}
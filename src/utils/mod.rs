@@ -1,11 +1,30 @@
 //! General-purpose utility modules.
 
+#[cfg(feature = "std")]
+#[doc(hidden)]
 pub mod color_checker;
+#[cfg(feature = "std")]
+pub mod djvu_global;
+#[cfg(feature = "std")]
 pub mod error;
+#[cfg(feature = "std")]
+#[doc(hidden)]
 pub mod file_path;
+pub mod geom;
 pub mod log;
+#[cfg(feature = "std")]
+#[doc(hidden)]
+pub mod memory;
+#[cfg(feature = "std")]
 pub mod progress;
+#[cfg(feature = "std")]
+pub mod string;
+#[cfg(feature = "std")]
+#[doc(hidden)]
 pub mod write_ext;
+#[cfg(feature = "std")]
+pub(crate) mod zip;
 
 // Re-export commonly used items
+#[cfg(feature = "std")]
 pub use error::{DjvuError, Result};
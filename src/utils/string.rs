@@ -0,0 +1,74 @@
+//! Cleaning user text before it is written into a chunk.
+//!
+//! Hidden text, annotations, bookmarks and directory titles all end up as
+//! UTF-8 inside binary or LISP-like chunks. Viewers are strict about them:
+//! DjVuLibre treats the control characters `\x0B`, `\x1D` and `\x1F` in
+//! `TXTz` as zone separators, stops reading `DIRM` titles at the first NUL,
+//! and some viewers refuse a whole `ANTz` chunk over one stray control
+//! byte. Text from OCR engines and PDF metadata routinely carries all of
+//! these, and decomposed accents that search then fails to match.
+//!
+//! [`sanitize`] normalizes to NFC and replaces control characters, and is
+//! applied by the encoders themselves; [`sanitize_bytes`] first decodes raw
+//! bytes under a [`Utf8Policy`].
+
+use crate::{DjvuError, Result};
+use std::borrow::Cow;
+use unicode_normalization::{IsNormalized, UnicodeNormalization, is_nfc_quick};
+
+/// What to do with bytes that are not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Utf8Policy {
+    /// Fail with [`DjvuError::InvalidArg`].
+    #[default]
+    Reject,
+    /// Replace each invalid sequence with U+FFFD.
+    Lossy,
+}
+
+/// Returns `text` in NFC with every control character removed, except
+/// that tabs and line breaks become a space so the words on either side
+/// stay apart. Borrows when there is nothing to change.
+pub fn sanitize(text: &str) -> Cow<'_, str> {
+    let clean = text.chars().all(|c| !c.is_control());
+    let normal = is_nfc_quick(text.chars()) == IsNormalized::Yes;
+    if clean && normal {
+        return Cow::Borrowed(text);
+    }
+    let stripped = text.chars().filter_map(|c| match c {
+        '\t' | '\n' | '\r' | '\x0B' | '\x0C' => Some(' '),
+        c if c.is_control() => None,
+        c => Some(c),
+    });
+    Cow::Owned(stripped.nfc().collect())
+}
+
+/// Decodes `bytes` as UTF-8 under `policy`, then [`sanitize`]s the result.
+pub fn sanitize_bytes(bytes: &[u8], policy: Utf8Policy) -> Result<String> {
+    let text = match policy {
+        Utf8Policy::Reject => Cow::Borrowed(std::str::from_utf8(bytes).map_err(|e| {
+            DjvuError::InvalidArg(format!("Text is not UTF-8 at byte {}", e.valid_up_to()))
+        })?),
+        Utf8Policy::Lossy => String::from_utf8_lossy(bytes),
+    };
+    Ok(sanitize(&text).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        assert!(matches!(sanitize("plain wörds"), Cow::Borrowed(_)));
+        // "e" + combining acute becomes the precomposed "é".
+        assert_eq!(sanitize("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(sanitize("a\x1Fb\0c\td\u{85}"), "abc d");
+
+        assert!(sanitize_bytes(b"ok\xff", Utf8Policy::Reject).is_err());
+        assert_eq!(
+            sanitize_bytes(b"ok\xff\n", Utf8Policy::Lossy).unwrap(),
+            "ok\u{fffd} "
+        );
+    }
+}
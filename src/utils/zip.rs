@@ -0,0 +1,172 @@
+//! A minimal ZIP writer, for delivering several files as one.
+//!
+//! Entries are stored without compression: DjVu chunks are compressed
+//! already, and stored entries can be read straight out of the archive.
+//! Every entry gets the same timestamp (1980-01-01 00:00), so the same
+//! files always give the same archive. ZIP64 is not written, which limits
+//! an archive to 4 GiB and 65 535 entries.
+
+use crate::doc::integrity::crc32;
+use crate::{DjvuError, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::Write;
+
+/// General purpose flag marking names as UTF-8.
+const UTF8_NAMES: u16 = 1 << 11;
+/// 1980-01-01 in MS-DOS date format.
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+/// Writes `entries`, each a path and its contents, as a ZIP archive.
+///
+/// Paths are relative and `/`-separated. Ones that would unpack outside the
+/// destination directory (absolute, with a `..` component or a backslash)
+/// are rejected.
+pub(crate) fn write_stored_zip<W: Write>(entries: &[(&str, &[u8])], mut writer: W) -> Result<()> {
+    let too_big = || DjvuError::InvalidArg("Archive exceeds the ZIP limits".to_string());
+    let count = u16::try_from(entries.len()).map_err(|_| too_big())?;
+    for &(name, _) in entries {
+        check_entry_name(name)?;
+    }
+
+    let mut central = Vec::new();
+    let mut offset = 0u32;
+    for &(name, data) in entries {
+        let size = u32::try_from(data.len()).map_err(|_| too_big())?;
+        let name_len = u16::try_from(name.len()).map_err(|_| too_big())?;
+        let crc = crc32(data);
+
+        // Local file header, then the data.
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.write_u32::<LittleEndian>(0x0403_4b50)?;
+        header.write_u16::<LittleEndian>(10)?; // version needed: 1.0
+        header.write_u16::<LittleEndian>(UTF8_NAMES)?;
+        header.write_u16::<LittleEndian>(0)?; // stored
+        header.write_u16::<LittleEndian>(0)?; // time
+        header.write_u16::<LittleEndian>(DOS_DATE)?;
+        header.write_u32::<LittleEndian>(crc)?;
+        header.write_u32::<LittleEndian>(size)?; // compressed
+        header.write_u32::<LittleEndian>(size)?; // uncompressed
+        header.write_u16::<LittleEndian>(name_len)?;
+        header.write_u16::<LittleEndian>(0)?; // extra field
+        header.write_all(name.as_bytes())?;
+        writer.write_all(&header)?;
+        writer.write_all(data)?;
+
+        // Central directory record.
+        central.write_u32::<LittleEndian>(0x0201_4b50)?;
+        central.write_u16::<LittleEndian>(20)?; // made by: 2.0, MS-DOS
+        central.write_u16::<LittleEndian>(10)?;
+        central.write_u16::<LittleEndian>(UTF8_NAMES)?;
+        central.write_u16::<LittleEndian>(0)?;
+        central.write_u16::<LittleEndian>(0)?;
+        central.write_u16::<LittleEndian>(DOS_DATE)?;
+        central.write_u32::<LittleEndian>(crc)?;
+        central.write_u32::<LittleEndian>(size)?;
+        central.write_u32::<LittleEndian>(size)?;
+        central.write_u16::<LittleEndian>(name_len)?;
+        central.write_u16::<LittleEndian>(0)?; // extra field
+        central.write_u16::<LittleEndian>(0)?; // comment
+        central.write_u16::<LittleEndian>(0)?; // disk
+        central.write_u16::<LittleEndian>(0)?; // internal attributes
+        central.write_u32::<LittleEndian>(0)?; // external attributes
+        central.write_u32::<LittleEndian>(offset)?;
+        central.write_all(name.as_bytes())?;
+
+        offset = u32::try_from(header.len())
+            .ok()
+            .and_then(|len| offset.checked_add(len)?.checked_add(size))
+            .ok_or_else(too_big)?;
+    }
+
+    // End of central directory.
+    let central_size = u32::try_from(central.len()).map_err(|_| too_big())?;
+    writer.write_all(&central)?;
+    writer.write_u32::<LittleEndian>(0x0605_4b50)?;
+    writer.write_u16::<LittleEndian>(0)?; // this disk
+    writer.write_u16::<LittleEndian>(0)?; // disk with the directory
+    writer.write_u16::<LittleEndian>(count)?;
+    writer.write_u16::<LittleEndian>(count)?;
+    writer.write_u32::<LittleEndian>(central_size)?;
+    writer.write_u32::<LittleEndian>(offset)?;
+    writer.write_u16::<LittleEndian>(0)?; // comment
+    Ok(())
+}
+
+/// Checks that `name` stays inside the directory the archive unpacks to.
+fn check_entry_name(name: &str) -> Result<()> {
+    let escapes = name.is_empty()
+        || name.starts_with('/')
+        || name.contains(['\\', '\0'])
+        || name
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..")
+        || name
+            .split('/')
+            .next()
+            .is_some_and(|first| first.contains(':'));
+    if escapes {
+        return Err(DjvuError::InvalidArg(format!(
+            "{name:?} cannot be used as a ZIP entry name"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Reads the entries of an archive written by [`write_stored_zip`]
+    /// through its central directory, checking every CRC.
+    pub(crate) fn read_stored_zip(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let u16_at = |pos: usize| u16::from_le_bytes([zip[pos], zip[pos + 1]]) as usize;
+        let u32_at =
+            |pos: usize| u32::from_le_bytes(zip[pos..pos + 4].try_into().unwrap()) as usize;
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(end), 0x0605_4b50);
+        let mut pos = u32_at(end + 16);
+        let mut entries = Vec::new();
+        for _ in 0..u16_at(end + 10) {
+            assert_eq!(u32_at(pos), 0x0201_4b50);
+            let (crc, size, name_len) = (u32_at(pos + 16), u32_at(pos + 24), u16_at(pos + 28));
+            let name = String::from_utf8(zip[pos + 46..pos + 46 + name_len].to_vec()).unwrap();
+            let local = u32_at(pos + 42);
+            assert_eq!(u32_at(local), 0x0403_4b50);
+            let start = local + 30 + u16_at(local + 26) + u16_at(local + 28);
+            let data = zip[start..start + size].to_vec();
+            assert_eq!(crc32(&data) as usize, crc);
+            entries.push((name, data));
+            pos += 46 + name_len;
+        }
+        entries
+    }
+
+    #[test]
+    fn test_write_stored_zip() -> Result<()> {
+        let mut zip = Vec::new();
+        write_stored_zip(&[("index.djvu", b"AT&T"), ("pages/é.djvu", b"")], &mut zip)?;
+        assert!(zip.starts_with(b"PK\x03\x04"));
+        assert_eq!(
+            read_stored_zip(&zip),
+            [
+                ("index.djvu".to_string(), b"AT&T".to_vec()),
+                ("pages/é.djvu".to_string(), Vec::new()),
+            ]
+        );
+
+        for name in [
+            "../up.djvu",
+            "pages/../../up.djvu",
+            "/etc/x",
+            "a\\..\\b",
+            "C:x",
+            "",
+        ] {
+            assert!(
+                write_stored_zip(&[(name, b"")], Vec::new()).is_err(),
+                "{name}"
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+//! Compare `Profile::Cjb2Clean` with DjVuLibre's `cjb2 -clean`.
+//!
+//! Encodes a small corpus of synthetic text pages and checks that the
+//! `Sjbz` chunks stay within a few percent of cjb2's, whose sizes are kept
+//! in `tests/data/cjb2_clean_sizes.txt` so the comparison runs without
+//! DjVuLibre. The corpus is generated from fixed seeds; after changing it,
+//! record cjb2's sizes again with `cjb2` on the `PATH` and
+//! `cargo test --test cjb2_parity_test -- --ignored`. A corpus page without
+//! a recorded size fails the comparison.
+
+use djvu_encoder::doc::chunk_stats::DocumentStats;
+use djvu_encoder::doc::{PageComponents, Profile};
+use djvu_encoder::encode::jb2::BitImage;
+use djvu_encoder::image::image_formats::{LoadedImage, write_pnm};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Largest difference allowed between the two `Sjbz` sizes, relative to
+/// cjb2's.
+const TOLERANCE: f64 = 0.10;
+
+/// Where cjb2's `Sjbz` sizes for the corpus are recorded.
+const SIZES: &str = "tests/data/cjb2_clean_sizes.txt";
+
+/// A page of the corpus.
+struct CorpusPage {
+    name: &'static str,
+    width: usize,
+    height: usize,
+    dpi: u32,
+    glyph_size: usize,
+    /// Number of single-pixel specks.
+    specks: usize,
+    /// Seed of the glyphs, their placement and the specks.
+    seed: u32,
+}
+
+impl CorpusPage {
+    const fn new(
+        name: &'static str,
+        (width, height): (usize, usize),
+        dpi: u32,
+        glyph_size: usize,
+        specks: usize,
+        seed: u32,
+    ) -> Self {
+        Self {
+            name,
+            width,
+            height,
+            dpi,
+            glyph_size,
+            specks,
+            seed,
+        }
+    }
+
+    fn render(&self) -> BitImage {
+        text_page(
+            self.width,
+            self.height,
+            self.glyph_size,
+            self.specks,
+            self.seed,
+        )
+    }
+}
+
+const CORPUS: [CorpusPage; 4] = [
+    CorpusPage::new("letter-300", (2550, 3300), 300, 18, 0, 1),
+    CorpusPage::new("letter-300-specks", (2550, 3300), 300, 18, 2000, 2),
+    CorpusPage::new("letter-600", (5100, 6600), 600, 36, 500, 3),
+    CorpusPage::new("small-print-400", (1700, 2200), 400, 12, 200, 4),
+];
+
+/// A page of text lines set from a small alphabet of glyphs, each a few
+/// solid strokes, with `specks` random single-pixel specks for the
+/// cleaning to remove.
+fn text_page(width: usize, height: usize, glyph_size: usize, specks: usize, seed: u32) -> BitImage {
+    let mut state = seed.max(1);
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as usize
+    };
+    // Strokes as (x, y, width, height) inside a glyph_size x 1.5 glyph_size box.
+    let stroke = (glyph_size / 8).max(1);
+    let glyphs: Vec<Vec<(usize, usize, usize, usize)>> = (0..40)
+        .map(|_| {
+            (0..2 + next() % 3)
+                .map(|_| {
+                    if next().is_multiple_of(2) {
+                        let w = glyph_size / 2 + next() % (glyph_size / 2);
+                        (
+                            next() % (glyph_size - w + 1),
+                            next() % glyph_size,
+                            w,
+                            stroke,
+                        )
+                    } else {
+                        let h = glyph_size / 2 + next() % glyph_size;
+                        (
+                            next() % (glyph_size - stroke),
+                            next() % (glyph_size / 2),
+                            stroke,
+                            h,
+                        )
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut page = BitImage::new(width as u32, height as u32).unwrap();
+    let (advance, leading) = (glyph_size + glyph_size / 4, glyph_size * 5 / 2);
+    let mut y = glyph_size * 3;
+    while y + leading < height - glyph_size * 3 {
+        let mut x = glyph_size * 3;
+        while x + advance < width - glyph_size * 3 {
+            if !next().is_multiple_of(6) {
+                for &(sx, sy, w, h) in &glyphs[next() % glyphs.len()] {
+                    for row in y + sy..y + sy + h {
+                        page.fill_run(row, x + sx, x + sx + w - 1);
+                    }
+                }
+            }
+            x += advance;
+        }
+        y += leading;
+    }
+    for _ in 0..specks {
+        page.set_usize(next() % width, next() % height, true);
+    }
+    page
+}
+
+fn sjbz_bytes(document: &[u8]) -> u64 {
+    let stats = DocumentStats::from_document(document).unwrap();
+    stats.pages().map(|page| page.chunk_bytes(*b"Sjbz")).sum()
+}
+
+/// Size of our `Sjbz` for a corpus page.
+fn our_sjbz_bytes(page: &BitImage, dpi: u32) -> u64 {
+    let mut params = Profile::Cjb2Clean.params();
+    params.dpi = dpi;
+    let ours = PageComponents::new()
+        .with_foreground(page.clone())
+        .unwrap()
+        .encode(&params, 1, dpi, 1, None)
+        .unwrap();
+    sjbz_bytes(&ours)
+}
+
+/// The recorded cjb2 sizes, as `(name, bytes)`.
+fn recorded_sizes() -> Vec<(String, u64)> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SIZES);
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+        .map(|line| {
+            let (name, bytes) = line.split_once(' ').expect("`name bytes` lines");
+            (name.to_string(), bytes.trim().parse().unwrap())
+        })
+        .collect()
+}
+
+fn check_parity(name: &str, reference: u64, ours: u64) {
+    let difference = (ours as f64 - reference as f64) / reference as f64;
+    println!(
+        "{name}: cjb2 {reference} bytes, ours {ours} bytes ({:+.1}%)",
+        difference * 100.0
+    );
+    assert!(
+        difference.abs() <= TOLERANCE,
+        "{name}: Sjbz differs from cjb2's by {:.1}%",
+        difference * 100.0
+    );
+}
+
+#[test]
+fn test_cjb2_clean_parity() {
+    let recorded = recorded_sizes();
+    for corpus in &CORPUS {
+        // Record them as the module documentation describes.
+        let &(_, reference) = recorded
+            .iter()
+            .find(|(n, _)| n == corpus.name)
+            .unwrap_or_else(|| panic!("{}: no cjb2 size recorded in {SIZES}", corpus.name));
+        let ours = our_sjbz_bytes(&corpus.render(), corpus.dpi);
+        check_parity(corpus.name, reference, ours);
+    }
+}
+
+/// Encodes the corpus with cjb2, checks parity and records cjb2's sizes.
+#[test]
+#[ignore = "needs DjVuLibre's cjb2 on the PATH"]
+fn test_record_cjb2_clean_sizes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut results = Vec::new();
+    let mut sizes = String::from(
+        "# Sjbz bytes of `cjb2 -clean` for the corpus of cjb2_parity_test.rs,\n\
+         # written by its ignored test_record_cjb2_clean_sizes.\n",
+    );
+
+    for corpus in &CORPUS {
+        let (name, dpi) = (corpus.name, corpus.dpi);
+        let page = corpus.render();
+        let pbm = dir.path().join(format!("{name}.pbm"));
+        let djvu = dir.path().join(format!("{name}.djvu"));
+        let mut data = Vec::new();
+        write_pnm(&mut data, &LoadedImage::Bilevel(page.clone())).unwrap();
+        fs::write(&pbm, data).unwrap();
+
+        let status = Command::new("cjb2")
+            .arg("-clean")
+            .arg("-dpi")
+            .arg(dpi.to_string())
+            .arg(&pbm)
+            .arg(&djvu)
+            .status()
+            .expect("Failed to run cjb2");
+        assert!(status.success(), "cjb2 failed on {name}");
+        let reference = sjbz_bytes(&fs::read(&djvu).unwrap());
+        sizes.push_str(&format!("{name} {reference}\n"));
+        results.push((name, reference, our_sjbz_bytes(&page, dpi)));
+    }
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SIZES);
+    fs::write(path, sizes).unwrap();
+    for (name, reference, ours) in results {
+        check_parity(name, reference, ours);
+    }
+}
@@ -0,0 +1,133 @@
+//! Golden SHA-256 digests of encoder output.
+//!
+//! Every codec here is specified down to the bit, so the same input must
+//! give the same bytes on every target: little or big endian, x86_64 or
+//! aarch64, with or without rayon. A SIMD path or a byte-order shortcut that
+//! changes a single rounding shows up as a digest mismatch long before
+//! anyone notices a visual difference.
+//!
+//! Run with `cargo test --features determinism-vectors`. When an output
+//! change is intended, update the digest from the failure message and say
+//! why in the commit.
+
+#![cfg(feature = "determinism-vectors")]
+
+#[path = "../benches/common/mod.rs"]
+mod common;
+
+use common::XorShift;
+use djvu_encoder::doc::page_encoder::{PageComponents, PageEncodeParams};
+use djvu_encoder::encode::iw44::encoder::{EncoderParams, IWEncoder};
+use djvu_encoder::encode::jb2::{JB2Encoder, analyze_page, shapes_to_encoder_format};
+use djvu_encoder::encode::zc::{ZEncoder, ZpContext};
+use djvu_encoder::iff::bs_byte_stream::bzz_compress;
+use sha2::{Digest, Sha256};
+
+fn check(name: &str, output: &[u8], expected: &str) {
+    let digest: String = Sha256::digest(output)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    assert_eq!(
+        digest,
+        expected,
+        "{name}: {} bytes of output hash differently on this target",
+        output.len()
+    );
+}
+
+fn iw44_chunks(mut encoder: IWEncoder) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let (chunk, more) = encoder.encode_chunk(10).unwrap();
+        out.extend_from_slice(&chunk);
+        if !more {
+            break;
+        }
+    }
+    out
+}
+
+#[test]
+fn zp_vector() {
+    let mut rng = XorShift::new(7);
+    let mut zp = ZEncoder::new(Vec::new(), true).unwrap();
+    let mut ctx = [ZpContext::new(); 4];
+    for i in 0..1usize << 16 {
+        let bit = rng.next_u32().is_multiple_of(8);
+        if i.is_multiple_of(5) {
+            zp.encode_raw(bit).unwrap();
+        } else {
+            zp.encode(bit, &mut ctx[i & 3]).unwrap();
+        }
+    }
+    check(
+        "zp",
+        &zp.finish().unwrap(),
+        "722958cb057d6d1111e6f35a93a3347cbc636560fff93ae0f6b409cceb52b96a",
+    );
+}
+
+#[test]
+fn bzz_vector() {
+    let data = common::mixed_bytes(16 * 1024);
+    check(
+        "bzz",
+        &bzz_compress(&data, 100).unwrap(),
+        "59494cb66748610e4a66f49c3d5cf17da7c21629e106949a530be0848aa0c43d",
+    );
+}
+
+#[test]
+fn jb2_vector() {
+    let (w, h) = (600, 400);
+    let page = common::text_page(w, h);
+    let shapes = analyze_page(&page, 300, 1).extract_shapes();
+    let (bitmaps, parents, blits) = shapes_to_encoder_format(shapes, h as i32);
+    let mut encoder = JB2Encoder::new(Vec::new());
+    let out = encoder
+        .encode_page_with_shapes(w, h, &bitmaps, &parents, &blits, 0, None)
+        .unwrap();
+    check(
+        "jb2",
+        &out,
+        "7e76a1abdd12ed6e65ebf04c4635a806802851e829dc8e62a3f2c00b6bd95f70",
+    );
+}
+
+#[test]
+fn iw44_vectors() {
+    let rgb = common::photo_rgb(256, 192);
+    let encoder = IWEncoder::from_rgb(&rgb, None, EncoderParams::default()).unwrap();
+    check(
+        "iw44 color",
+        &iw44_chunks(encoder),
+        "82a5ed76151d3795198c3499a38a0cc5b67f8858c5d513c6c854cbafcfc7b04e",
+    );
+
+    let gray = common::photo_gray(256, 192);
+    let encoder = IWEncoder::from_gray(&gray, None, EncoderParams::default()).unwrap();
+    check(
+        "iw44 gray",
+        &iw44_chunks(encoder),
+        "e3c326bd06fa7dc65b5a1a1ab0c35c63602a0ae9674926a51b83963eea693d60",
+    );
+}
+
+#[test]
+fn compound_page_vector() {
+    let (w, h) = (320, 240);
+    let page = PageComponents::new_with_dimensions(w, h)
+        .with_background(common::photo_rgb(w, h))
+        .unwrap()
+        .with_jb2_auto_extract(common::text_page(w, h))
+        .unwrap();
+    let out = page
+        .encode(&PageEncodeParams::default(), 1, 300, 1, Some(2.2))
+        .unwrap();
+    check(
+        "compound page",
+        &out,
+        "fdda74abed43e9f7c602052a949a6c37f7824c9395ecf6179c669b542c098925",
+    );
+}
@@ -0,0 +1,104 @@
+//! Decode JB2 pages the encoder codes in unusual ways with DjVuLibre's `ddjvu`.
+//!
+//! Dense pages make the encoder free the number trees with
+//! `REQUIRED_DICT_OR_RESET` records, and pages with more shapes than the
+//! library holds ([`LIBRARY_LIMIT`]) blit the rest as `NEW_MARK_IMAGE_ONLY`.
+//! Both only stay readable if the encoder mirrors the decoder's state
+//! exactly, so each page is decoded and compared pixel for pixel with
+//! [`render_page`]. Run with `ddjvu` on the `PATH` and
+//! `cargo test --test jb2_ddjvu_roundtrip_test -- --ignored`.
+
+use djvu_encoder::doc::page_encoder::{PageComponents, PageEncodeParams};
+use djvu_encoder::doc::{RenderMode, render_page};
+use djvu_encoder::encode::jb2::encoder::LIBRARY_LIMIT;
+use djvu_encoder::encode::jb2::symbol_dict::BitImage;
+use djvu_encoder::image::image_formats::{Pixel, load_pbm};
+use std::process::Command;
+
+/// Encodes `page`, decodes its mask with `ddjvu` and checks every pixel.
+fn assert_ddjvu_decodes(page: &PageComponents) {
+    let dir = tempfile::tempdir().unwrap();
+    let (input, output) = (dir.path().join("page.djvu"), dir.path().join("page.pbm"));
+    let data = page
+        .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+        .unwrap();
+    std::fs::write(&input, data).unwrap();
+
+    let out = Command::new("ddjvu")
+        .args(["-format=pbm", "-mode=mask"])
+        .arg(&input)
+        .arg(&output)
+        .output()
+        .expect("ddjvu should be on the PATH");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(out.status.success() && stderr.trim().is_empty(), "{stderr}");
+
+    let decoded = load_pbm(&output).unwrap();
+    let expected = render_page(page, 1.0, RenderMode::Bitonal).unwrap();
+    let (width, height) = expected.dimensions();
+    assert_eq!(
+        (decoded.width as u32, decoded.height as u32),
+        (width, height)
+    );
+    for y in 0..height {
+        for x in 0..width {
+            assert_eq!(
+                decoded.get_pixel_unchecked(x as usize, y as usize),
+                expected.get_pixel(x, y) == Pixel::black(),
+                "pixel ({x}, {y})"
+            );
+        }
+    }
+}
+
+/// A 3x3 shape drawn from the low nine bits of `n`, never blank.
+fn pattern(n: usize) -> BitImage {
+    let bits = n % 511 + 1;
+    let mut shape = BitImage::new(3, 3).unwrap();
+    for i in 0..9 {
+        shape.set_usize(i % 3, i / 3, (bits >> i) & 1 == 1);
+    }
+    shape
+}
+
+#[test]
+#[ignore = "needs DjVuLibre's ddjvu on the PATH"]
+fn test_ddjvu_decodes_context_resets() {
+    // Thousands of marks scattered over a large page give every location
+    // offset its own tree path, so the number contexts are reset often.
+    let shapes: Vec<_> = (0..8).map(pattern).collect();
+    let mut state = 1u32;
+    let blits: Vec<_> = (0..20_000)
+        .map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let (x, y) = (state % 1_999, state / 1_999 % 1_999);
+            (x as i32 * 5, y as i32 * 5, i % shapes.len())
+        })
+        .collect();
+    let page = PageComponents::new_with_dimensions(10_000, 10_000).with_jb2_manual(shapes, blits);
+    assert_ddjvu_decodes(&page);
+}
+
+#[test]
+#[ignore = "needs DjVuLibre's ddjvu on the PATH"]
+fn test_ddjvu_decodes_full_library() {
+    // One cell of a 4-pixel grid per shape, then a few more blitting shapes
+    // that came after the library filled up, so they are coded again.
+    let count = LIBRARY_LIMIT + 300;
+    let extra = 100;
+    let columns = ((count + extra) as f64).sqrt().ceil() as usize;
+    let shapes: Vec<_> = (0..count).map(pattern).collect();
+    let blits: Vec<_> = (0..count)
+        .chain(count - extra..count)
+        .enumerate()
+        .map(|(cell, shapeno)| {
+            let (x, y) = (cell % columns * 4, cell / columns * 4);
+            (x as i32, y as i32, shapeno)
+        })
+        .collect();
+    let size = columns as u32 * 4;
+    let page = PageComponents::new_with_dimensions(size, size).with_jb2_manual(shapes, blits);
+    assert_ddjvu_decodes(&page);
+}
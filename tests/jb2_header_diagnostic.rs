@@ -258,8 +258,8 @@ fn test_minimal_jb2_stream() {
     // Create the absolute minimal JB2 stream:
     // START_OF_DATA(width=10, height=10, no_refine) + END_OF_DATA
 
-    use djvu_encoder::encode::jb2::num_coder::NumCoder;
-    use djvu_encoder::encode::zc::ZEncoder;
+    use djvu_encoder::encode::jb2::num_coder::{Jb2Context, NumCoder};
+    use djvu_encoder::encode::zc::{ZEncoder, ZpContext};
 
     println!("=== Testing minimal JB2 stream encoding ===");
 
@@ -268,9 +268,9 @@ fn test_minimal_jb2_stream() {
     let mut num_coder = NumCoder::new();
 
     // Allocate contexts
-    let mut dist_record_type: u32 = 0;
-    let mut image_size_dist: u32 = 0;
-    let mut dist_refinement_flag: u8 = 0;
+    let mut dist_record_type = Jb2Context::default();
+    let mut image_size_dist = Jb2Context::default();
+    let mut dist_refinement_flag = ZpContext::new();
 
     // Constants
     const START_OF_DATA: i32 = 0;
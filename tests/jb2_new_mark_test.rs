@@ -233,9 +233,9 @@ fn test_reference_with_cjb2() {
 
 #[test]
 fn test_encode_single_new_mark_manually() {
-    use djvu_encoder::encode::jb2::num_coder::{BIG_POSITIVE, NumCoder};
+    use djvu_encoder::encode::jb2::num_coder::{BIG_POSITIVE, Jb2Context, NumCoder};
     use djvu_encoder::encode::jb2::symbol_dict::BitImage;
-    use djvu_encoder::encode::zc::ZEncoder;
+    use djvu_encoder::encode::zc::{ContextBank, ZEncoder, ZpContext};
 
     println!("=== Manually encoding a single NEW_MARK record ===\n");
 
@@ -259,14 +259,14 @@ fn test_encode_single_new_mark_manually() {
     let mut num_coder = NumCoder::new();
 
     // Allocate contexts
-    let mut dist_record_type: u32 = 0;
-    let mut image_size_dist: u32 = 0;
-    let mut abs_size_x: u32 = 0;
-    let mut abs_size_y: u32 = 0;
-    let mut abs_loc_x: u32 = 0;
-    let mut abs_loc_y: u32 = 0;
-    let mut dist_refinement_flag: u8 = 0;
-    let mut bitdist = [0u8; 1024];
+    let mut dist_record_type = Jb2Context::default();
+    let mut image_size_dist = Jb2Context::default();
+    let mut abs_size_x = Jb2Context::default();
+    let mut abs_size_y = Jb2Context::default();
+    let mut abs_loc_x = Jb2Context::default();
+    let mut abs_loc_y = Jb2Context::default();
+    let mut dist_refinement_flag = ZpContext::new();
+    let mut bitdist = ContextBank::new(1024);
 
     // START_OF_DATA
     println!("Encoding START_OF_DATA...");
@@ -49,8 +49,8 @@ fn create_djvu_from_jb2(jb2_data: &[u8], width: u16, height: u16) -> Vec<u8> {
 
 #[test]
 fn test_minimal_without_reset() {
-    use djvu_encoder::encode::jb2::num_coder::{BIG_POSITIVE, NumCoder};
-    use djvu_encoder::encode::zc::ZEncoder;
+    use djvu_encoder::encode::jb2::num_coder::{BIG_POSITIVE, Jb2Context, NumCoder};
+    use djvu_encoder::encode::zc::{ZEncoder, ZpContext};
 
     println!("=== Testing WITHOUT reset ===\n");
 
@@ -63,9 +63,9 @@ fn test_minimal_without_reset() {
 
     // NO RESET
 
-    let mut dist_record_type: u32 = 0;
-    let mut image_size_dist: u32 = 0;
-    let mut dist_refinement_flag: u8 = 0;
+    let mut dist_record_type = Jb2Context::default();
+    let mut image_size_dist = Jb2Context::default();
+    let mut dist_refinement_flag = ZpContext::new();
 
     // START_OF_DATA
     num_coder
@@ -128,8 +128,8 @@ fn test_minimal_without_reset() {
 
 #[test]
 fn test_minimal_with_reset() {
-    use djvu_encoder::encode::jb2::num_coder::{BIG_POSITIVE, NumCoder};
-    use djvu_encoder::encode::zc::ZEncoder;
+    use djvu_encoder::encode::jb2::num_coder::{BIG_POSITIVE, Jb2Context, NumCoder};
+    use djvu_encoder::encode::zc::{ZEncoder, ZpContext};
 
     println!("=== Testing WITH reset ===\n");
 
@@ -143,9 +143,9 @@ fn test_minimal_with_reset() {
     // RESET CALLED
     num_coder.reset();
 
-    let mut dist_record_type: u32 = 0;
-    let mut image_size_dist: u32 = 0;
-    let mut dist_refinement_flag: u8 = 0;
+    let mut dist_record_type = Jb2Context::default();
+    let mut image_size_dist = Jb2Context::default();
+    let mut dist_refinement_flag = ZpContext::new();
 
     // START_OF_DATA
     num_coder
@@ -208,8 +208,8 @@ fn test_minimal_with_reset() {
 
 #[test]
 fn test_minimal_with_fresh_contexts() {
-    use djvu_encoder::encode::jb2::num_coder::{BIG_POSITIVE, NumCoder};
-    use djvu_encoder::encode::zc::ZEncoder;
+    use djvu_encoder::encode::jb2::num_coder::{BIG_POSITIVE, Jb2Context, NumCoder};
+    use djvu_encoder::encode::zc::{ZEncoder, ZpContext};
 
     println!("=== Testing with fresh NumContext variables (but NumCoder reset) ===\n");
 
@@ -224,9 +224,9 @@ fn test_minimal_with_fresh_contexts() {
     num_coder.reset();
 
     // Fresh contexts initialized to 0
-    let mut dist_record_type: u32 = 0;
-    let mut image_size_dist: u32 = 0;
-    let mut dist_refinement_flag: u8 = 0;
+    let mut dist_record_type = Jb2Context::default();
+    let mut image_size_dist = Jb2Context::default();
+    let mut dist_refinement_flag = ZpContext::new();
 
     // START_OF_DATA
     num_coder
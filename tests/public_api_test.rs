@@ -0,0 +1,62 @@
+//! Checks the supported API surface in `djvu_encoder::prelude`.
+//!
+//! Every item of the prelude and the signatures of its entry points are
+//! named here, so removing or changing one incompatibly fails to compile.
+//! Changes that make this file fail need a major version bump.
+
+use djvu_encoder::prelude::*;
+
+/// The entry points, as function pointers: a changed signature no longer
+/// coerces.
+#[allow(clippy::type_complexity)]
+#[test]
+fn test_prelude_signatures() {
+    let _: fn(usize) -> DjvuBuilder = DjvuBuilder::new;
+    let _: fn(DjvuBuilder, Profile) -> DjvuBuilder = DjvuBuilder::with_profile;
+    let _: fn(DjvuBuilder) -> DjvuDocument = DjvuBuilder::build;
+    let _: fn(&DjvuDocument, Page) -> Result<()> = DjvuDocument::add_page;
+    let _: fn(&DjvuDocument, DjVmNav) -> Result<()> = DjvuDocument::set_bookmarks;
+    let _: fn(&DjvuDocument) -> Result<Vec<u8>> = DjvuDocument::finalize;
+    let _: fn(&DjvuDocument) -> Result<EncodeStats> = DjvuDocument::encode_stats;
+
+    let _: fn(usize, u32, u32) -> PageBuilder = PageBuilder::new;
+    let _: fn(PageBuilder, Pixmap) -> Result<PageBuilder> = PageBuilder::with_background;
+    let _: fn(PageBuilder, Bitmap, u32, u32) -> PageBuilder = PageBuilder::with_foreground;
+    let _: fn(PageBuilder, HiddenText) -> PageBuilder = PageBuilder::with_hidden_text;
+    let _: fn(PageBuilder) -> Result<Page> = PageBuilder::build;
+
+    let _: fn(u32, u32) -> PageComponents = PageComponents::new_with_dimensions;
+    let _: fn(PageComponents, Pixmap) -> Result<PageComponents> = PageComponents::with_background;
+    let _: fn(PageComponents, BitImage) -> Result<PageComponents> = PageComponents::with_foreground;
+    let _: fn(PageComponents, PageRotation) -> PageComponents = PageComponents::with_rotation;
+    let _: fn(&PageComponents, &PageEncodeParams, u32, u32, u8, Option<f32>) -> Result<Vec<u8>> =
+        PageComponents::encode;
+    let _: fn(Profile) -> PageEncodeParams = Profile::params;
+
+    let _: fn(&[u8]) -> Result<DjVuDocEditor> = DjVuDocEditor::from_bytes;
+    let _: fn(&mut DjVuDocEditor, DjVuDocEditor) -> Result<()> = DjVuDocEditor::append_document;
+    let _: fn(&DjVuDocEditor) -> Result<Vec<u8>> = DjVuDocEditor::to_bytes;
+
+    let _: fn(&str) -> Result<DjVmNav> = DjVmNav::from_outline;
+    let _ = |b: Bookmark| (b.title, b.dest, b.children);
+    let _ = |a: Annotations| a.hyperlinks.into_iter().map(|h: Hyperlink| h.url);
+    let _ = |p: Pixel, g: GrayPixel| (p.r, g);
+    let _ = |e: DjvuError| e.to_string();
+}
+
+#[test]
+fn test_prelude_encodes_a_document() -> Result<()> {
+    let doc = DjvuBuilder::new(1)
+        .with_profile(Profile::PhotoArchive)
+        .build();
+    let page = PageBuilder::new(0, 16, 16)
+        .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))?
+        .build()?;
+    doc.add_page(page)?;
+    let data = doc.finalize()?;
+    assert!(data.starts_with(b"AT&TFORM"));
+
+    let editor = DjVuDocEditor::from_bytes(&data)?;
+    assert_eq!(editor.page_count(), 1);
+    Ok(())
+}
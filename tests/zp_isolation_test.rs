@@ -4,14 +4,14 @@ use std::io::Cursor;
 
 #[test]
 fn test_zp_encoder_basic() {
-    use djvu_encoder::encode::zc::zcodec::ZEncoder;
+    use djvu_encoder::encode::zc::{ZpContext, zcodec::ZEncoder};
 
     // Create encoder
     let buffer = Vec::new();
     let mut encoder =
         ZEncoder::new(Cursor::new(buffer), true).expect("Failed to create ZP encoder");
 
-    let mut ctx: u8 = 0;
+    let mut ctx = ZpContext::new();
 
     // Encode a simple pattern
     for i in 0..100 {
@@ -55,13 +55,13 @@ fn test_zp_encoder_iwencoder() {
 
 #[test]
 fn test_zp_encoder_larger_data() {
-    use djvu_encoder::encode::zc::zcodec::ZEncoder;
+    use djvu_encoder::encode::zc::{ZpContext, zcodec::ZEncoder};
 
     let buffer = Vec::new();
     let mut encoder =
         ZEncoder::new(Cursor::new(buffer), true).expect("Failed to create ZP encoder");
 
-    let mut ctx: u8 = 0;
+    let mut ctx = ZpContext::new();
 
     // Encode a larger pattern (simulating actual image data)
     for i in 0..10000 {